@@ -0,0 +1,643 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::binary;
+use crate::commands::{COMMAND_TABLE, lookup};
+use crate::crypto;
+use crate::pattern::Pattern;
+use crate::storage::now_ms;
+
+/// ACL categories, as listed by ACL CAT. Which commands belong to each is
+/// described by the command table.
+const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "bloom",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+/// Names of every ACL category.
+pub fn categories() -> Vec<&'static str> {
+    CATEGORIES.to_vec()
+}
+
+/// Commands belonging to a category, or None if the category is unknown.
+pub fn category_commands(category: &str) -> Option<Vec<&'static str>> {
+    let category = category.to_lowercase();
+    if category != "all" && !CATEGORIES.contains(&category.as_str()) {
+        return None;
+    }
+    Some(
+        COMMAND_TABLE
+            .iter()
+            .filter(|spec| category == "all" || spec.acl_categories().contains(&category.as_str()))
+            .map(|spec| spec.name)
+            .collect(),
+    )
+}
+
+fn in_category(category: &str, command: &str) -> bool {
+    lookup(command).is_some_and(|spec| spec.acl_categories().contains(&category))
+}
+
+/// An ACL user: whether it may log in, with which passwords, and what it may
+/// run and touch once authenticated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    pub nopass: bool,
+    /// SHA-256 hex digests; plain passwords are never stored.
+    passwords: Vec<String>,
+    /// Command rules in the order given, such as `+@read` or `-flushall`.
+    /// The first rule is always `+@all` or `-@all`; the last match wins.
+    commands: Vec<String>,
+    keys: Vec<String>,
+    channels: Vec<String>,
+}
+
+impl User {
+    /// A new user as created by ACL SETUSER: disabled, no passwords and no
+    /// permissions.
+    pub fn new(name: &str) -> Self {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            commands: vec!["-@all".to_string()],
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// Applies one ACL SETUSER rule.
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_string()],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec!["*".to_string()],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.commands = vec!["+@all".to_string()],
+            "nocommands" => self.commands = vec!["-@all".to_string()],
+            "reset" => *self = User::new(&self.name),
+            _ => return self.apply_with_argument(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_with_argument(&mut self, rule: &str) -> Result<(), String> {
+        let syntax_error = || "Syntax error".to_string();
+        let (op, arg) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+        match op {
+            ">" => {
+                let hash = sha256_hex(&binary::encode(arg));
+                if !self.passwords.contains(&hash) {
+                    self.passwords.push(hash);
+                }
+                self.nopass = false;
+            }
+            "<" => {
+                let hash = sha256_hex(&binary::encode(arg));
+                if !self.passwords.contains(&hash) {
+                    return Err(
+                        "The password you are trying to remove from the user does not exist"
+                            .to_string(),
+                    );
+                }
+                self.passwords.retain(|h| *h != hash);
+            }
+            "#" | "!" => {
+                let hash = arg.to_lowercase();
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+                }
+                if op == "#" {
+                    if !self.passwords.contains(&hash) {
+                        self.passwords.push(hash);
+                    }
+                    self.nopass = false;
+                } else {
+                    if !self.passwords.contains(&hash) {
+                        return Err(
+                            "The password you are trying to remove from the user does not exist"
+                                .to_string(),
+                        );
+                    }
+                    self.passwords.retain(|h| *h != hash);
+                }
+            }
+            "~" => {
+                if self.keys.iter().any(|k| k == "*") {
+                    return Err("Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns".to_string());
+                }
+                if !self.keys.iter().any(|k| k == arg) {
+                    self.keys.push(arg.to_string());
+                }
+            }
+            "&" => {
+                if self.channels.iter().any(|c| c == "*") {
+                    return Err("Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels".to_string());
+                }
+                if !self.channels.iter().any(|c| c == arg) {
+                    self.channels.push(arg.to_string());
+                }
+            }
+            "+" | "-" => {
+                let name = arg.to_lowercase();
+                let known = match name.strip_prefix('@') {
+                    Some(category) => category_commands(category).is_some(),
+                    None => lookup(&name).is_some(),
+                };
+                if !known {
+                    return Err("Unknown command or category name in ACL".to_string());
+                }
+                if name == "@all" {
+                    self.commands = vec![format!("{}@all", op)];
+                } else {
+                    self.commands.retain(|r| r[1..] != name);
+                    self.commands.push(format!("{}{}", op, name));
+                }
+            }
+            _ => return Err(syntax_error()),
+        }
+        Ok(())
+    }
+
+    /// Whether the user may run a command, judged by the last matching rule.
+    pub fn can_run(&self, command: &str) -> bool {
+        let command = command.to_uppercase();
+        let mut allowed = false;
+        for rule in &self.commands {
+            let (op, name) = rule.split_at(1);
+            let matches = match name.strip_prefix('@') {
+                Some(category) => category == "all" || in_category(category, &command),
+                None => name.eq_ignore_ascii_case(&command),
+            };
+            if matches {
+                allowed = op == "+";
+            }
+        }
+        allowed
+    }
+
+    /// Whether the user may access a key.
+    pub fn can_access_key(&self, key: &str) -> bool {
+        self.keys.iter().any(|p| Pattern::new(p).matches(key))
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        if self.nopass {
+            return true;
+        }
+        let hash = sha256_hex(&binary::encode(password));
+        self.passwords.iter().any(|h| secure_eq(h, &hash))
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn commands_rule(&self) -> String {
+        self.commands.join(" ")
+    }
+
+    pub fn keys_rule(&self) -> String {
+        self.keys
+            .iter()
+            .map(|k| format!("~{}", k))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn channels_rule(&self) -> String {
+        self.channels
+            .iter()
+            .map(|c| format!("&{}", c))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The user's rules in the form printed by ACL LIST.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("user {}", self.name)];
+        parts.extend(self.flags().iter().map(|f| f.to_string()));
+        parts.extend(self.passwords.iter().map(|h| format!("#{}", h)));
+        if self.keys.is_empty() {
+            parts.push("resetkeys".to_string());
+        } else {
+            parts.push(self.keys_rule());
+        }
+        if self.channels.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.channels_rule());
+        }
+        parts.push(self.commands_rule());
+        parts.join(" ")
+    }
+
+    pub fn flag_list(&self) -> Vec<String> {
+        self.flags().iter().map(|f| f.to_string()).collect()
+    }
+
+    pub fn password_hashes(&self) -> &[String] {
+        &self.passwords
+    }
+}
+
+/// Why an ACL LOG entry was recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclLogReason {
+    Command,
+    Key,
+    Auth,
+}
+
+impl AclLogReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AclLogReason::Command => "command",
+            AclLogReason::Key => "key",
+            AclLogReason::Auth => "auth",
+        }
+    }
+}
+
+/// A denied command or failed login, as reported by ACL LOG.
+#[derive(Debug, Clone)]
+pub struct AclLogEntry {
+    pub count: u64,
+    pub reason: AclLogReason,
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub entry_id: u64,
+    pub created_ms: u64,
+    pub updated_ms: u64,
+}
+
+/// Repeats of the same event within this window bump the existing entry's
+/// count instead of adding a new one.
+const ACL_LOG_GROUPING_MS: u64 = 60_000;
+
+#[derive(Debug, Default)]
+struct AclLog {
+    /// Newest entry first.
+    entries: VecDeque<AclLogEntry>,
+    next_id: u64,
+}
+
+/// The server's users, shared by every connection.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    users: Arc<RwLock<BTreeMap<String, User>>>,
+    log: Arc<Mutex<AclLog>>,
+}
+
+impl Acl {
+    /// Starts with only the `default` user, which can do anything without a
+    /// password.
+    pub fn new() -> Self {
+        let mut default = User::new("default");
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            let _ = default.apply(rule);
+        }
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), default);
+        Acl {
+            users: Arc::new(RwLock::new(users)),
+            log: Arc::new(Mutex::new(AclLog::default())),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<User> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<User> {
+        self.users.read().unwrap().values().cloned().collect()
+    }
+
+    /// Creates or modifies a user. Either every rule applies or none does.
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Removes users, returning how many existed. `default` can't be removed.
+    pub fn del_users(&self, names: &[String]) -> Result<usize, String> {
+        if names.iter().any(|n| n == "default") {
+            return Err("The 'default' user cannot be removed".to_string());
+        }
+        let mut users = self.users.write().unwrap();
+        Ok(names.iter().filter(|n| users.remove(*n).is_some()).count())
+    }
+
+    /// Checks a username/password pair against an enabled user.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        self.users
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|user| user.enabled && user.check_password(password))
+    }
+
+    /// Whether new connections are logged in as `default` without AUTH.
+    pub fn default_nopass(&self) -> bool {
+        self.get("default")
+            .is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    /// Mirrors the `requirepass` directive onto the default user.
+    pub fn set_requirepass(&self, password: &str) {
+        let mut users = self.users.write().unwrap();
+        if let Some(user) = users.get_mut("default") {
+            let _ = user.apply("resetpass");
+            let rule = if password.is_empty() {
+                "nopass".to_string()
+            } else {
+                format!(">{}", password)
+            };
+            let _ = user.apply(&rule);
+        }
+    }
+}
+
+impl Acl {
+    /// Records a security event, keeping at most `max_len` entries.
+    pub fn log_event(
+        &self,
+        reason: AclLogReason,
+        object: &str,
+        username: &str,
+        client_info: &str,
+        max_len: usize,
+    ) {
+        let now = now_ms();
+        let mut log = self.log.lock().unwrap();
+        if let Some(entry) = log.entries.iter_mut().find(|e| {
+            e.reason == reason
+                && e.object == object
+                && e.username == username
+                && now.saturating_sub(e.updated_ms) < ACL_LOG_GROUPING_MS
+        }) {
+            entry.count += 1;
+            entry.updated_ms = now;
+            entry.client_info = client_info.to_string();
+            return;
+        }
+
+        let entry_id = log.next_id;
+        log.next_id += 1;
+        log.entries.push_front(AclLogEntry {
+            count: 1,
+            reason,
+            object: object.to_string(),
+            username: username.to_string(),
+            client_info: client_info.to_string(),
+            entry_id,
+            created_ms: now,
+            updated_ms: now,
+        });
+        log.entries.truncate(max_len);
+    }
+
+    /// The `count` most recent log entries, newest first.
+    pub fn log_entries(&self, count: usize) -> Vec<AclLogEntry> {
+        let log = self.log.lock().unwrap();
+        log.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset_log(&self) {
+        self.log.lock().unwrap().entries.clear();
+    }
+}
+
+/// A random password of `bits` bits (rounded up to a multiple of 4), as hex.
+pub fn genpass(bits: usize) -> std::io::Result<String> {
+    let chars = bits.div_ceil(4);
+    let mut bytes = vec![0u8; chars.div_ceil(2)];
+    crypto::fill_random(&mut bytes)?;
+    let mut hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.truncate(chars);
+    Ok(hex)
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compares secrets without bailing out at the first differing byte.
+fn secure_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// SHA-256 of `data` as lowercase hex, the form ACL stores passwords in.
+pub fn sha256_hex(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_setuser_and_permissions() {
+        let acl = Acl::new();
+        acl.set_user(
+            "alice",
+            &rules(&["on", ">secret", "~cache:*", "+@read", "-keys"]),
+        )
+        .unwrap();
+
+        assert!(acl.authenticate("alice", "secret"));
+        assert!(!acl.authenticate("alice", "wrong"));
+
+        let alice = acl.get("alice").unwrap();
+        assert!(alice.can_run("get"));
+        assert!(!alice.can_run("KEYS"));
+        assert!(!alice.can_run("SET"));
+        assert!(alice.can_access_key("cache:1"));
+        assert!(!alice.can_access_key("session:1"));
+        assert_eq!(
+            alice.describe(),
+            format!(
+                "user alice on #{} ~cache:* resetchannels -@all +@read -keys",
+                sha256_hex(b"secret")
+            )
+        );
+    }
+
+    #[test]
+    fn test_setuser_is_atomic() {
+        let acl = Acl::new();
+        assert!(acl.set_user("bob", &rules(&["on", "+@nosuch"])).is_err());
+        assert!(acl.get("bob").is_none());
+        assert!(acl.del_users(&rules(&["default"])).is_err());
+    }
+
+    #[test]
+    fn test_log_groups_repeats() {
+        let acl = Acl::new();
+        acl.log_event(AclLogReason::Auth, "AUTH", "bob", "id=1", 2);
+        acl.log_event(AclLogReason::Auth, "AUTH", "bob", "id=2", 2);
+        acl.log_event(AclLogReason::Command, "keys", "bob", "id=1", 2);
+        acl.log_event(AclLogReason::Key, "secret", "bob", "id=1", 2);
+
+        let entries = acl.log_entries(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].object, "secret");
+        assert_eq!(entries[1].object, "keys");
+
+        acl.reset_log();
+        acl.log_event(AclLogReason::Auth, "AUTH", "bob", "id=1", 10);
+        acl.log_event(AclLogReason::Auth, "AUTH", "bob", "id=1", 10);
+        assert_eq!(acl.log_entries(10)[0].count, 2);
+    }
+
+    #[test]
+    fn test_genpass() {
+        let pass = genpass(256).unwrap();
+        assert_eq!(pass.len(), 64);
+        assert!(pass.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(genpass(5).unwrap().len(), 2);
+        assert_ne!(genpass(128).unwrap(), genpass(128).unwrap());
+    }
+
+    #[test]
+    fn test_requirepass_updates_default_user() {
+        let acl = Acl::new();
+        assert!(acl.default_nopass());
+        acl.set_requirepass("pw");
+        assert!(!acl.default_nopass());
+        assert!(acl.authenticate("default", "pw"));
+        acl.set_requirepass("");
+        assert!(acl.default_nopass());
+    }
+}
@@ -0,0 +1,1335 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::binary;
+use crate::commands::{self, Command};
+use crate::connection::ConnectionContext;
+use crate::crypto::{self, Key, Keyring};
+use crate::parser::{Resp, parse_request};
+use crate::rdb;
+use crate::server::Server;
+use crate::storage::{DbSnapshot, Storage, Value};
+use crate::stream::{Group, StreamId};
+use crate::zset;
+
+/// When the append-only file is flushed to disk, as redis.conf's
+/// `appendfsync`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    /// After every write command, before it is acknowledged.
+    Always,
+    /// Once a second from the cron; a crash loses at most a second of writes.
+    EverySec,
+    /// Whenever the operating system decides to.
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(name: &str) -> Option<AppendFsync> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// Commands taking a relative expiration, see `pinned_expiry`.
+const RELATIVE_EXPIRY: &[&str] = &["set", "setex", "psetex", "expire", "pexpire", "restore"];
+
+/// Commands that delete their key when given an expiration in the past.
+const EXPIRE_FAMILY: &[&str] = &["expire", "pexpire", "expireat", "pexpireat"];
+
+/// Collection elements per command when writing out a whole dataset.
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// Largest record a dataset is split into when written encrypted.
+const DATASET_RECORD_LEN: usize = 1 << 20;
+
+/// Length of the random id an encrypted file has after its magic.
+const FILE_ID_LEN: usize = 16;
+
+/// What an encrypted file starts with: the magic and the id.
+const ENCRYPTED_HEADER_LEN: usize = crypto::MAGIC.len() + FILE_ID_LEN;
+
+/// The open append-only file.
+#[derive(Debug)]
+struct AofWriter {
+    file: File,
+    fsync: AppendFsync,
+    /// Database of the last command written, so SELECT is only logged when
+    /// it changes.
+    db: Option<usize>,
+    /// Something was written since the last fsync.
+    unsynced: bool,
+    size: u64,
+    /// Each write is sealed as a record when set.
+    sealer: Option<RecordSealer>,
+}
+
+impl AofWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let record;
+        let data = match &mut self.sealer {
+            Some(sealer) => {
+                record = sealer.seal(data)?;
+                &record[..]
+            }
+            None => data,
+        };
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        if self.fsync == AppendFsync::Always {
+            self.file.sync_data()?;
+        } else {
+            self.unsynced = true;
+        }
+        Ok(())
+    }
+}
+
+/// Append-only file state shared by every connection.
+#[derive(Debug)]
+pub struct Aof {
+    on: AtomicBool,
+    writer: Mutex<Option<AofWriter>>,
+    last_write_ok: AtomicBool,
+    /// Set while a background fsync runs.
+    syncing: Arc<AtomicBool>,
+}
+
+/// Exclusive access to the append-only file while a write command runs.
+pub struct AofGuard<'a> {
+    aof: &'a Aof,
+    writer: MutexGuard<'a, Option<AofWriter>>,
+}
+
+impl Aof {
+    pub fn new() -> Self {
+        Aof {
+            on: AtomicBool::new(false),
+            writer: Mutex::new(None),
+            last_write_ok: AtomicBool::new(true),
+            syncing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Locks the file for a write command, or returns None when the AOF is
+    /// off. Holding the guard while the command runs makes writes execute
+    /// one at a time, so the file records them in the order they happened.
+    pub fn lock(&self) -> Option<AofGuard<'_>> {
+        if !self.is_on() {
+            return None;
+        }
+        let writer = self.writer.lock().unwrap();
+        writer.is_some().then_some(AofGuard { aof: self, writer })
+    }
+
+    pub fn last_write_ok(&self) -> bool {
+        self.last_write_ok.load(Ordering::Relaxed)
+    }
+
+    /// Size of the file in bytes, when it is open.
+    pub fn size(&self) -> Option<u64> {
+        self.writer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|writer| writer.size)
+    }
+
+    pub fn set_fsync(&self, fsync: AppendFsync) {
+        if let Some(writer) = self.writer.lock().unwrap().as_mut() {
+            writer.fsync = fsync;
+        }
+    }
+
+    /// Flushes pending writes to disk, for `appendfsync everysec`. The sync
+    /// runs on tokio's blocking pool, like Redis's bio thread, so neither
+    /// writers nor the cron's worker wait for the disk. While one is still
+    /// running the writes stay pending for the next call.
+    pub fn fsync(&self) {
+        if self.syncing.load(Ordering::Acquire) {
+            return;
+        }
+        let file = {
+            let mut writer = self.writer.lock().unwrap();
+            match writer.as_mut() {
+                Some(writer) if writer.fsync == AppendFsync::EverySec && writer.unsynced => {
+                    writer.unsynced = false;
+                    writer.file.try_clone()
+                }
+                _ => return,
+            }
+        };
+        let syncing = self.syncing.clone();
+        syncing.store(true, Ordering::Release);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = file.and_then(|file| file.sync_data()) {
+                tracing::warn!("Error syncing the AOF file: {}", e);
+            }
+            syncing.store(false, Ordering::Release);
+        });
+    }
+
+    /// Opens `path` for appending, truncated to `len` bytes when given, to
+    /// drop an incomplete transaction at its end. With a keyring, the file
+    /// must be empty or already encrypted.
+    pub fn open(
+        &self,
+        path: &Path,
+        fsync: AppendFsync,
+        len: Option<u64>,
+        keyring: Option<&Keyring>,
+    ) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if let Some(len) = len {
+            file.set_len(len)?;
+        }
+        let sealer = match keyring.map(|keyring| keyring.current().clone()) {
+            Some(key) if file.metadata()?.len() == 0 => {
+                let sealer = RecordSealer::new(key)?;
+                file.write_all(&sealer.header())?;
+                Some(sealer)
+            }
+            Some(key) => Some(RecordSealer::resume(key, &fs::read(path)?)?),
+            None => None,
+        };
+        let size = file.metadata()?.len();
+        *self.writer.lock().unwrap() = Some(AofWriter {
+            file,
+            fsync,
+            db: None,
+            unsynced: false,
+            size,
+            sealer,
+        });
+        self.on.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Starts logging to a new file at `path` holding the current dataset,
+    /// as turning `appendonly` on does. The file is encrypted with the
+    /// keyring's current key when there is one.
+    pub fn start(
+        &self,
+        path: &Path,
+        fsync: AppendFsync,
+        storage: &Storage,
+        keyring: Option<&Keyring>,
+    ) -> io::Result<()> {
+        let key = keyring.map(|keyring| keyring.current().clone());
+        // Writers queue up behind the lock while the dataset is written out
+        let mut writer = self.writer.lock().unwrap();
+        self.on.store(true, Ordering::Relaxed);
+        let sealer = match write_dataset(path, &storage.snapshot(), key) {
+            Ok(sealer) => sealer,
+            Err(e) => {
+                self.on.store(false, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        let file = OpenOptions::new().append(true).open(path)?;
+        *writer = Some(AofWriter {
+            size: file.metadata()?.len(),
+            file,
+            fsync,
+            db: None,
+            unsynced: false,
+            sealer,
+        });
+        Ok(())
+    }
+
+    /// Stops logging, flushing what was written so far.
+    pub fn stop(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        self.on.store(false, Ordering::Relaxed);
+        if let Some(writer) = writer.take()
+            && let Err(e) = writer.file.sync_data()
+        {
+            tracing::warn!("Error syncing the AOF file: {}", e);
+        }
+    }
+}
+
+impl Default for Aof {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AofGuard<'_> {
+    /// Appends one command run against database `db`.
+    pub fn feed(&mut self, db: usize, args: &[&str]) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let mut buf = Vec::new();
+        if writer.db != Some(db) {
+            encode_command(&mut buf, &["select", &db.to_string()]);
+        }
+        encode_command(&mut buf, args);
+        match writer.write(&buf) {
+            Ok(()) => {
+                writer.db = Some(db);
+                self.aof.last_write_ok.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                // Leave the SELECT to be written again with the next command
+                writer.db = None;
+                if self.aof.last_write_ok.swap(false, Ordering::Relaxed) {
+                    tracing::warn!("Error writing to the AOF file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The absolute time, in milliseconds, a write with a relative expiration
+/// set its key to expire at. Writes are propagated followed by a PEXPIREAT
+/// to it, so replaying them later doesn't extend the key's life.
+pub fn pinned_expiry(storage: &Storage, db: usize, name: &str, args: &[String]) -> Option<String> {
+    if !RELATIVE_EXPIRY.contains(&name) {
+        return None;
+    }
+    storage
+        .db(db)
+        .expire_time(args.first()?)
+        .map(|at| at.to_string())
+}
+
+/// Whether a write of the EXPIRE family deleted its key, having been given
+/// an expiration in the past. It's propagated as a DEL instead, which means
+/// the same whenever it's replayed.
+pub fn expired_by_write(
+    storage: &Storage,
+    db: usize,
+    name: &str,
+    args: &[String],
+    reply: &Resp,
+) -> bool {
+    EXPIRE_FAMILY.contains(&name)
+        && *reply == Resp::Integer(1)
+        && storage.db(db).exists(&args[..1]) == 0
+}
+
+/// Appends a command as a RESP array of bulk strings, of the bytes each
+/// argument was decoded from.
+pub fn encode_command(out: &mut Vec<u8>, args: &[&str]) {
+    let args: Vec<_> = args.iter().map(|arg| binary::encode(arg)).collect();
+    let args: Vec<&[u8]> = args.iter().map(|arg| &**arg).collect();
+    encode_bytes_command(out, &args);
+}
+
+/// Appends a command whose arguments needn't be text.
+fn encode_bytes_command(out: &mut Vec<u8>, args: &[&[u8]]) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// The commands that recreate a dataset, with large collections split
+/// across several commands.
+fn dataset_commands(dbs: &[DbSnapshot]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (index, keys) in dbs.iter().enumerate().filter(|(_, keys)| !keys.is_empty()) {
+        encode_command(&mut out, &["select", &index.to_string()]);
+        for (key, value, expires_at) in keys {
+            match &**value {
+                Value::String(s) => encode_command(&mut out, &["set", key, s]),
+                Value::Int(n) => encode_command(&mut out, &["set", key, &n.to_string()]),
+                Value::List(list) => {
+                    let items: Vec<&str> = list.iter().collect();
+                    for chunk in items.chunks(ITEMS_PER_COMMAND) {
+                        let mut args = vec!["rpush", key.as_str()];
+                        args.extend_from_slice(chunk);
+                        encode_command(&mut out, &args);
+                    }
+                }
+                Value::Set(set) => {
+                    let members: Vec<&str> = set.iter().map(String::as_str).collect();
+                    for chunk in members.chunks(ITEMS_PER_COMMAND) {
+                        let mut args = vec!["sadd", key.as_str()];
+                        args.extend_from_slice(chunk);
+                        encode_command(&mut out, &args);
+                    }
+                }
+                Value::Hash(hash) => {
+                    let pairs: Vec<(&str, &str)> = hash.iter().collect();
+                    for chunk in pairs.chunks(ITEMS_PER_COMMAND) {
+                        let mut args = vec!["hset", key.as_str()];
+                        for (field, value) in chunk {
+                            args.push(field);
+                            args.push(value);
+                        }
+                        encode_command(&mut out, &args);
+                    }
+                }
+                Value::ZSet(zset) => {
+                    let members: Vec<(String, &str)> = zset
+                        .iter()
+                        .map(|(member, score)| (zset::format_score(score), member))
+                        .collect();
+                    for chunk in members.chunks(ITEMS_PER_COMMAND) {
+                        let mut args = vec!["zadd", key.as_str()];
+                        for (score, member) in chunk {
+                            args.push(score);
+                            args.push(member);
+                        }
+                        encode_command(&mut out, &args);
+                    }
+                }
+                // One XADD per entry, under the ID it has. An empty stream
+                // gets one trimmed away at once, under the last ID it gave
+                // out; one that never gave any is left to its groups'
+                // XGROUP CREATE MKSTREAM
+                Value::Stream(stream) => {
+                    if stream.is_empty() && stream.last_id() > StreamId::MIN {
+                        let last_id = stream.last_id().to_string();
+                        encode_command(&mut out, &["xadd", key, "MAXLEN", "0", &last_id, "x", "y"]);
+                    }
+                    for (id, fields) in stream.iter() {
+                        let id = id.to_string();
+                        let mut args = vec!["xadd", key.as_str(), &id];
+                        for (field, value) in fields {
+                            args.push(field);
+                            args.push(value);
+                        }
+                        encode_command(&mut out, &args);
+                    }
+                    for (name, group) in stream.groups() {
+                        group_commands(&mut out, key, name, group);
+                    }
+                }
+                // No command sets a filter's bits, so it's restored whole
+                Value::Bloom(_) => {
+                    let payload = rdb::dump(value, false);
+                    encode_bytes_command(
+                        &mut out,
+                        &[b"restore", &binary::encode(key), b"0", &payload],
+                    );
+                }
+            }
+            if let Some(at) = expires_at {
+                encode_command(&mut out, &["pexpireat", key, &at.to_string()]);
+            }
+        }
+    }
+    out
+}
+
+/// The commands that recreate a stream's consumer group: its creation at
+/// its last delivered ID, an XCLAIM for each entry pending, with when it
+/// was delivered and how many times, and the consumers without any.
+fn group_commands(out: &mut Vec<u8>, key: &str, name: &str, group: &Group) {
+    let last_id = group.last_id().to_string();
+    let mut create = vec!["xgroup", "create", key, name, &last_id, "MKSTREAM"];
+    let entries_read = group.entries_read().map(|read| read.to_string());
+    if let Some(read) = &entries_read {
+        create.extend(["ENTRIESREAD", read]);
+    }
+    encode_command(out, &create);
+    for (id, pending) in group.pending() {
+        encode_command(
+            out,
+            &[
+                "xclaim",
+                key,
+                name,
+                &pending.consumer,
+                "0",
+                &id.to_string(),
+                "TIME",
+                &pending.delivery_time.to_string(),
+                "RETRYCOUNT",
+                &pending.delivery_count.to_string(),
+                "FORCE",
+                "JUSTID",
+            ],
+        );
+    }
+    for (consumer, state) in group.consumers() {
+        if state.pending.is_empty() {
+            encode_command(out, &["xgroup", "createconsumer", key, name, consumer]);
+        }
+    }
+}
+
+/// Writes a dataset as commands to a temporary file and renames it over
+/// `path`. Encrypted, the file is new, so it gets a new id; what seals the
+/// records after the dataset's is returned.
+fn write_dataset(
+    path: &Path,
+    dbs: &[DbSnapshot],
+    key: Option<Key>,
+) -> io::Result<Option<RecordSealer>> {
+    let temp = path.with_file_name(format!("temp-appendonly-{}.aof", std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        let commands = dataset_commands(dbs);
+        let sealer = match key {
+            Some(key) => {
+                let mut sealer = RecordSealer::new(key)?;
+                file.write_all(&sealer.header())?;
+                for chunk in commands.chunks(DATASET_RECORD_LEN) {
+                    file.write_all(&sealer.seal(chunk)?)?;
+                }
+                Some(sealer)
+            }
+            None => {
+                file.write_all(&commands)?;
+                None
+            }
+        };
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(sealer)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Seals the records of an encrypted file, which starts with the magic and
+/// a random id. Each record is authenticated along with that id and its
+/// sequence number in the file, so one dropped from the middle, moved,
+/// repeated or copied from another file fails to decrypt. Records cut from
+/// the end can't be told from a file that ends there, as after a crash.
+#[derive(Debug)]
+struct RecordSealer {
+    key: Key,
+    file_id: [u8; FILE_ID_LEN],
+    /// Sequence number of the next record.
+    seq: u64,
+}
+
+impl RecordSealer {
+    /// Seals the records of a new file.
+    fn new(key: Key) -> io::Result<Self> {
+        Ok(RecordSealer {
+            key,
+            file_id: crypto::random_bytes()?,
+            seq: 0,
+        })
+    }
+
+    /// Seals records appended to an existing file, whose records are all
+    /// complete.
+    fn resume(key: Key, data: &[u8]) -> io::Result<Self> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let file_id = file_id(data).ok_or_else(|| invalid("bad encrypted file header"))?;
+        let mut seq = 0;
+        let mut pos = ENCRYPTED_HEADER_LEN;
+        while pos < data.len() {
+            let len = data
+                .get(pos..pos + 4)
+                .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                .ok_or_else(|| invalid("incomplete encrypted record"))?;
+            pos += 4 + len;
+            seq += 1;
+        }
+        if pos != data.len() {
+            return Err(invalid("incomplete encrypted record"));
+        }
+        Ok(RecordSealer { key, file_id, seq })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        [&crypto::MAGIC[..], &self.file_id].concat()
+    }
+
+    /// Frames `data` as the next record: the length of the sealed data,
+    /// then the sealed data.
+    fn seal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let sealed = self.key.seal(&record_aad(&self.file_id, self.seq), data)?;
+        self.seq += 1;
+        let mut record = Vec::with_capacity(4 + sealed.len());
+        record.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+}
+
+/// The id of an encrypted file, which follows its magic.
+fn file_id(data: &[u8]) -> Option<[u8; FILE_ID_LEN]> {
+    data.get(crypto::MAGIC.len()..ENCRYPTED_HEADER_LEN)?
+        .try_into()
+        .ok()
+}
+
+/// What a record is authenticated with besides its contents.
+fn record_aad(file_id: &[u8; FILE_ID_LEN], seq: u64) -> Vec<u8> {
+    [&crypto::MAGIC[..], file_id, &seq.to_be_bytes()].concat()
+}
+
+/// The commands of an encrypted file: its magic followed by records.
+#[derive(Debug)]
+struct Decrypted {
+    plain: Vec<u8>,
+    /// Where each record's commands start, in `plain` and in the file.
+    records: Vec<(usize, usize)>,
+    /// Length of the records that could be decrypted.
+    valid_len: usize,
+    /// Why decryption stopped before the end of the file.
+    error: Option<String>,
+    /// It stopped at a record cut off by the end of the file.
+    truncated: bool,
+}
+
+impl Decrypted {
+    /// Where the record holding offset `plain` of the commands starts in
+    /// the file, so cutting the commands there means cutting the file there.
+    fn file_offset(&self, plain: usize) -> usize {
+        if plain >= self.plain.len() {
+            return self.valid_len;
+        }
+        let index = self.records.partition_point(|&(start, _)| start <= plain);
+        self.records[index - 1].1
+    }
+}
+
+fn decrypt(data: &[u8], keyring: Option<&Keyring>) -> Result<Decrypted, String> {
+    let keyring =
+        keyring.ok_or("the append only file is encrypted but no encryption key is configured")?;
+    let mut decrypted = Decrypted {
+        plain: Vec::new(),
+        records: Vec::new(),
+        valid_len: ENCRYPTED_HEADER_LEN,
+        error: None,
+        truncated: false,
+    };
+    let Some(file_id) = file_id(data) else {
+        decrypted.valid_len = 0;
+        decrypted.truncated = true;
+        decrypted.error = Some("Unexpected end of file in the header".to_string());
+        return Ok(decrypted);
+    };
+    let mut pos = ENCRYPTED_HEADER_LEN;
+    while pos < data.len() {
+        let len = data
+            .get(pos..pos + 4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize);
+        let Some(sealed) = len.and_then(|len| data.get(pos + 4..pos + 4 + len)) else {
+            decrypted.truncated = true;
+            decrypted.error = Some(format!("Unexpected end of file at offset {}", pos));
+            break;
+        };
+        let aad = record_aad(&file_id, decrypted.records.len() as u64);
+        match keyring.open(&aad, sealed) {
+            Ok(plain) => {
+                decrypted.records.push((decrypted.plain.len(), pos));
+                decrypted.plain.extend_from_slice(&plain);
+                pos += 4 + sealed.len();
+                decrypted.valid_len = pos;
+            }
+            Err(e) => {
+                decrypted.error = Some(format!("Record at offset {} is {}", pos, e));
+                break;
+            }
+        }
+    }
+    Ok(decrypted)
+}
+
+/// What replaying a file did.
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadStats {
+    pub commands: usize,
+    /// Length of the part of the file that was applied. Shorter than the
+    /// file when it ends inside a MULTI block or a command, which is left
+    /// out.
+    pub valid_len: u64,
+    /// The file ended in the middle of a command.
+    pub truncated: bool,
+}
+
+/// The next entry of an append-only file.
+enum Next<'a> {
+    Command(Vec<&'a [u8]>, usize),
+    /// The file ends in the middle of a command.
+    Truncated,
+    Invalid(String),
+}
+
+fn next_command(data: &[u8], pos: usize) -> Next<'_> {
+    if data[pos] != b'*' {
+        return Next::Invalid(format!("Bad file format at offset {}", pos));
+    }
+    match parse_request(&data[pos..]) {
+        Ok(Some((parts, _))) if parts.is_empty() => {
+            Next::Invalid(format!("Empty command at offset {}", pos))
+        }
+        Ok(Some((parts, used))) => Next::Command(parts, used),
+        Ok(None) => Next::Truncated,
+        Err(e) => Next::Invalid(format!("Bad file format at offset {}: {}", pos, e)),
+    }
+}
+
+/// Replays an append-only file into the server. Commands are executed
+/// under their original names, bypassing ACLs and renames. A file cut off
+/// in the middle of a command, as a crash leaves it, is loaded up to there
+/// when `load_truncated` is set. Encrypted files are decrypted with the
+/// server's keyring.
+pub fn load(data: &[u8], server: &Server, load_truncated: bool) -> Result<LoadStats, String> {
+    if !crypto::is_encrypted(data) {
+        return load_commands(data, server, load_truncated);
+    }
+    let decrypted = decrypt(data, server.keyring.as_ref())?;
+    match &decrypted.error {
+        Some(_) if decrypted.truncated && load_truncated => tracing::warn!(
+            "!!! Warning: short read while loading the AOF file !!! AOF loaded anyway because aof-load-truncated is enabled"
+        ),
+        Some(e) => {
+            return Err(format!(
+                "{} reading the append only file. Make a backup of your AOF file, then use reredis --check-aof --fix <filename>",
+                e
+            ));
+        }
+        None => {}
+    }
+    let mut stats = load_commands(&decrypted.plain, server, load_truncated)?;
+    stats.valid_len = decrypted.file_offset(stats.valid_len as usize) as u64;
+    stats.truncated |= decrypted.truncated;
+    Ok(stats)
+}
+
+fn load_commands(data: &[u8], server: &Server, load_truncated: bool) -> Result<LoadStats, String> {
+    let mut ctx = ConnectionContext::new(0);
+    ctx.deny_blocking = true;
+    let mut stats = LoadStats::default();
+    // Commands of an open MULTI block, and where the block started
+    let mut multi: Option<(Vec<Command>, usize)> = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let (parts, used) = match next_command(data, pos) {
+            Next::Command(parts, used) => (parts, used),
+            Next::Truncated if load_truncated => {
+                tracing::warn!(
+                    "!!! Warning: short read while loading the AOF file !!! AOF loaded anyway because aof-load-truncated is enabled"
+                );
+                stats.truncated = true;
+                break;
+            }
+            Next::Truncated => {
+                return Err(format!(
+                    "Unexpected end of file reading the append only file at offset {}. You can: 1) Make a backup of your AOF file, then use reredis --check-aof --fix <filename>. 2) Alternatively you can set the 'aof-load-truncated' configuration option to yes and restart the server",
+                    pos
+                ));
+            }
+            Next::Invalid(e) => {
+                return Err(format!(
+                    "{} reading the append only file. Make a backup of your AOF file, then use reredis --check-aof --fix <filename>",
+                    e
+                ));
+            }
+        };
+        let (name, args) = parts.split_first().unwrap();
+        let cmd = Command {
+            name: String::from_utf8_lossy(name).to_lowercase(),
+            args: args.iter().map(|arg| binary::decode(arg)).collect(),
+        };
+        let start = pos;
+        pos += used;
+
+        match (cmd.name.as_str(), &mut multi) {
+            ("multi", None) => multi = Some((Vec::new(), start)),
+            ("exec", Some(_)) => {
+                let (queued, _) = multi.take().unwrap();
+                for cmd in &queued {
+                    replay(cmd, server, &mut ctx)?;
+                }
+                stats.commands += queued.len();
+            }
+            (_, Some((queued, _))) => queued.push(cmd),
+            _ => {
+                replay(&cmd, server, &mut ctx)?;
+                stats.commands += 1;
+            }
+        }
+    }
+
+    stats.valid_len = match multi {
+        Some((_, start)) => {
+            tracing::warn!("Revert incomplete MULTI/EXEC transaction in AOF file");
+            start as u64
+        }
+        None => pos.min(data.len()) as u64,
+    };
+    Ok(stats)
+}
+
+/// The outcome of checking a file with `--check-aof`.
+#[derive(Debug, PartialEq)]
+pub struct CheckReport {
+    /// Length of the valid prefix of the file.
+    pub ok_up_to: u64,
+    /// What is wrong past `ok_up_to`, if anything.
+    pub error: Option<String>,
+}
+
+/// Walks an append-only file without running it, finding the longest
+/// prefix that loads cleanly: whole commands and closed transactions.
+pub fn check(data: &[u8]) -> CheckReport {
+    let mut multi_start: Option<usize> = None;
+    let mut pos = 0;
+    let mut error = None;
+    while pos < data.len() {
+        match next_command(data, pos) {
+            Next::Command(parts, used) => {
+                let name = String::from_utf8_lossy(parts[0]).to_lowercase();
+                match (name.as_str(), multi_start) {
+                    ("multi", Some(_)) => {
+                        error = Some(format!("Unexpected MULTI at offset {}", pos));
+                        break;
+                    }
+                    ("multi", None) => multi_start = Some(pos),
+                    ("exec", None) => {
+                        error = Some(format!("Unexpected EXEC at offset {}", pos));
+                        break;
+                    }
+                    ("exec", Some(_)) => multi_start = None,
+                    _ => {}
+                }
+                pos += used;
+            }
+            Next::Truncated => {
+                error = Some(format!("Unexpected end of file at offset {}", pos));
+                break;
+            }
+            Next::Invalid(e) => {
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    if error.is_none() && multi_start.is_some() {
+        error = Some("Reached EOF before reading EXEC for MULTI".to_string());
+    }
+    CheckReport {
+        ok_up_to: multi_start.unwrap_or(pos) as u64,
+        error,
+    }
+}
+
+/// `reredis --check-aof [--fix] <file>`, like redis-check-aof: reports
+/// whether the file is valid and, with `--fix`, truncates it to its valid
+/// prefix after asking for confirmation. Returns the exit code.
+pub fn check_tool(args: &[String]) -> i32 {
+    let (fix, path) = match args {
+        [path] => (false, path),
+        [flag, path] if flag == "--fix" => (true, path),
+        _ => {
+            eprintln!("Usage: reredis --check-aof [--fix] <file.aof>");
+            return 1;
+        }
+    };
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Cannot open file {}: {}", path, e);
+            return 1;
+        }
+    };
+    let report = if crypto::is_encrypted(&data) {
+        let decrypted = match Keyring::load("").and_then(|keyring| decrypt(&data, keyring.as_ref()))
+        {
+            Ok(decrypted) => decrypted,
+            Err(e) => {
+                eprintln!("Cannot decrypt {}: {} (set {})", path, e, crypto::KEY_ENV);
+                return 1;
+            }
+        };
+        let report = check(&decrypted.plain);
+        CheckReport {
+            ok_up_to: decrypted.file_offset(report.ok_up_to as usize) as u64,
+            error: report.error.or(decrypted.error),
+        }
+    } else {
+        check(&data)
+    };
+    let size = data.len() as u64;
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, diff={}",
+        path,
+        size,
+        report.ok_up_to,
+        size - report.ok_up_to
+    );
+    let Some(error) = report.error else {
+        println!("AOF {} is valid", path);
+        return 0;
+    };
+    println!("{}", error);
+    if !fix {
+        println!(
+            "AOF {} is not valid. Use the --fix option to try fixing it.",
+            path
+        );
+        return 1;
+    }
+
+    println!(
+        "This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes",
+        path,
+        size,
+        size - report.ok_up_to,
+        report.ok_up_to
+    );
+    print!("Continue? [y/N]: ");
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+        println!("Aborting...");
+        return 1;
+    }
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(report.ok_up_to).and_then(|()| file.sync_all()));
+    match truncated {
+        Ok(()) => {
+            println!("Successfully truncated AOF {}", path);
+            0
+        }
+        Err(e) => {
+            println!("Failed to truncate AOF {}: {}", path, e);
+            1
+        }
+    }
+}
+
+fn replay(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Result<(), String> {
+    match commands::replay(cmd, server, ctx) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "Unknown command '{}' reading the append only file",
+            cmd.name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("reredis-{}-{}.aof", name, std::process::id()))
+    }
+
+    fn run(server: &Server, ctx: &mut ConnectionContext, args: &[&str]) -> Resp {
+        let cmd = Command {
+            name: args[0].to_string(),
+            args: args[1..].iter().map(|s| s.to_string()).collect(),
+        };
+        commands::execute(&cmd, server, ctx)
+    }
+
+    #[test]
+    fn test_writes_are_logged_and_replayed() {
+        let path = temp_path("log");
+        let server = Server::default();
+        server
+            .aof
+            .open(&path, AppendFsync::Always, Some(0), None)
+            .unwrap();
+        let mut ctx = ConnectionContext::new(1);
+        run(&server, &mut ctx, &["SET", "k", "v", "EX", "100"]);
+        run(&server, &mut ctx, &["GET", "k"]);
+        run(&server, &mut ctx, &["SELECT", "2"]);
+        run(&server, &mut ctx, &["RPUSH", "l", "a", "b"]);
+        run(&server, &mut ctx, &["MULTI"]);
+        run(&server, &mut ctx, &["INCR", "n"]);
+        run(&server, &mut ctx, &["EXEC"]);
+        // Failed writes aren't logged
+        run(&server, &mut ctx, &["INCR", "l"]);
+        server.aof.stop();
+
+        let data = fs::read(&path).unwrap();
+        let text = String::from_utf8_lossy(&data);
+        assert!(text.starts_with("*2\r\n$6\r\nselect\r\n$1\r\n0\r\n*5\r\n$3\r\nset\r\n"));
+        assert!(text.contains("pexpireat"));
+        assert!(!text.contains("get"));
+        assert!(text.contains("$5\r\nmulti\r\n*2\r\n$4\r\nincr\r\n$1\r\nn\r\n*1\r\n$4\r\nexec"));
+        assert_eq!(text.matches("incr").count(), 1);
+
+        let loaded = Server::default();
+        let stats = load(&data, &loaded, false).unwrap();
+        assert_eq!(stats.valid_len, data.len() as u64);
+        assert_eq!(loaded.storage.db(0).get("k"), Some("v".to_string()));
+        assert!(loaded.storage.db(0).ttl("k") > 90_000);
+        assert_eq!(loaded.storage.db(2).llen("l").unwrap(), 2);
+        assert_eq!(loaded.storage.db(2).get("n"), Some("1".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_consumer_groups_are_replayed() {
+        let path = temp_path("groups");
+        let server = Server::default();
+        server
+            .aof
+            .open(&path, AppendFsync::Always, Some(0), None)
+            .unwrap();
+        let mut ctx = ConnectionContext::new(1);
+        for id in ["1-0", "2-0", "3-0", "4-0"] {
+            run(&server, &mut ctx, &["XADD", "s", id, "f", "v"]);
+        }
+        run(&server, &mut ctx, &["XGROUP", "CREATE", "s", "g", "0"]);
+        run(&server, &mut ctx, &["XGROUP", "CREATE", "s", "idle", "$"]);
+        let read = [
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "alice",
+            "COUNT",
+            "3",
+            "STREAMS",
+            "s",
+            ">",
+        ];
+        run(&server, &mut ctx, &read);
+        run(&server, &mut ctx, &["XACK", "s", "g", "1-0"]);
+        run(&server, &mut ctx, &["XCLAIM", "s", "g", "bob", "0", "2-0"]);
+        run(
+            &server,
+            &mut ctx,
+            &["XGROUP", "CREATECONSUMER", "s", "g", "carol"],
+        );
+        server.aof.stop();
+
+        let data = fs::read(&path).unwrap();
+        let text = String::from_utf8_lossy(&data);
+        assert!(!text.contains("xreadgroup"));
+        let loaded = Server::default();
+        load(&data, &loaded, false).unwrap();
+        assert_eq!(
+            digest::dataset_digest(&loaded.storage),
+            digest::dataset_digest(&server.storage)
+        );
+        let counts = |server: &Server| {
+            server.storage.db(0).with_stream("s", |stream| {
+                let group = stream.group("g").unwrap();
+                let pending = group.pending().values();
+                (
+                    group.entries_read(),
+                    pending.map(|p| p.delivery_count).collect::<Vec<_>>(),
+                )
+            })
+        };
+        assert_eq!(counts(&loaded), Ok(Some((Some(3), vec![2, 1]))));
+        assert_eq!(counts(&loaded), counts(&server));
+
+        // Rewritten the same
+        server
+            .aof
+            .start(&path, AppendFsync::EverySec, &server.storage, None)
+            .unwrap();
+        server.aof.stop();
+        let rewritten = Server::default();
+        load(&fs::read(&path).unwrap(), &rewritten, false).unwrap();
+        assert_eq!(
+            digest::dataset_digest(&rewritten.storage),
+            digest::dataset_digest(&server.storage)
+        );
+        assert_eq!(counts(&rewritten), counts(&server));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trimmed_streams_are_replayed() {
+        let path = temp_path("trimmed");
+        let server = Server::default();
+        server
+            .aof
+            .open(&path, AppendFsync::Always, Some(0), None)
+            .unwrap();
+        let mut ctx = ConnectionContext::new(1);
+        for ms in 1..=150 {
+            let id = ms.to_string();
+            run(
+                &server,
+                &mut ctx,
+                &["XADD", "s", "MAXLEN", "~", "10", &id, "f", "v"],
+            );
+        }
+        run(&server, &mut ctx, &["XTRIM", "s", "MINID", "~", "120"]);
+        run(
+            &server,
+            &mut ctx,
+            &["XADD", "e", "MAXLEN", "0", "7-0", "f", "v"],
+        );
+        server.aof.stop();
+
+        let ends = |server: &Server| {
+            ["s", "e"].map(|key| {
+                let stream = server.storage.db(0).with_stream(key, |stream| {
+                    (stream.len(), stream.first_id(), stream.last_id())
+                });
+                stream.unwrap().unwrap()
+            })
+        };
+        let expected = [
+            (50, StreamId::new(101, 0), StreamId::new(150, 0)),
+            (0, StreamId::MIN, StreamId::new(7, 0)),
+        ];
+        assert_eq!(ends(&server), expected);
+        let loaded = Server::default();
+        load(&fs::read(&path).unwrap(), &loaded, false).unwrap();
+        assert_eq!(ends(&loaded), expected);
+
+        // An emptied stream is rewritten with its last ID
+        server
+            .aof
+            .start(&path, AppendFsync::EverySec, &server.storage, None)
+            .unwrap();
+        server.aof.stop();
+        let rewritten = Server::default();
+        load(&fs::read(&path).unwrap(), &rewritten, false).unwrap();
+        assert_eq!(ends(&rewritten), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors_and_incomplete_transactions() {
+        let server = Server::default();
+        let mut data = Vec::new();
+        encode_command(&mut data, &["set", "a", "1"]);
+        let complete = data.len() as u64;
+        encode_command(&mut data, &["multi"]);
+        encode_command(&mut data, &["set", "b", "2"]);
+        let stats = load(&data, &server, false).unwrap();
+        assert_eq!(stats.commands, 1);
+        assert_eq!(stats.valid_len, complete);
+        assert_eq!(server.storage.db(0).get("b"), None);
+
+        let value = binary::decode(b"\x80\xff");
+        let mut data = Vec::new();
+        encode_command(&mut data, &["set", "a", &value]);
+        assert_eq!(data, b"*3\r\n$3\r\nset\r\n$1\r\na\r\n$2\r\n\x80\xff\r\n");
+        let server = Server::default();
+        load(&data, &server, false).unwrap();
+        assert_eq!(server.storage.db(0).get("a"), Some(value));
+
+        assert!(load(b"*2\r\n$3\r\nset\r\n", &Server::default(), false).is_err());
+        assert!(load(b"set a 1\r\n", &Server::default(), false).is_err());
+        assert!(load(b"*1\r\n$5\r\nbogus\r\n", &Server::default(), false).is_err());
+    }
+
+    #[test]
+    fn test_load_truncated() {
+        let mut data = Vec::new();
+        encode_command(&mut data, &["set", "a", "1"]);
+        let complete = data.len() as u64;
+        encode_command(&mut data, &["set", "b", "2"]);
+        data.truncate(data.len() - 3);
+
+        let server = Server::default();
+        let stats = load(&data, &server, true).unwrap();
+        assert!(stats.truncated);
+        assert_eq!(stats.valid_len, complete);
+        assert_eq!(server.storage.db(0).get("a"), Some("1".to_string()));
+        assert!(load(&data, &Server::default(), false).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut data = Vec::new();
+        encode_command(&mut data, &["set", "a", "1"]);
+        encode_command(&mut data, &["multi"]);
+        encode_command(&mut data, &["set", "b", "2"]);
+        encode_command(&mut data, &["exec"]);
+        let valid = data.len() as u64;
+        assert_eq!(
+            check(&data),
+            CheckReport {
+                ok_up_to: valid,
+                error: None
+            }
+        );
+
+        // An open transaction is cut back to its MULTI
+        let mut open = data.clone();
+        encode_command(&mut open, &["multi"]);
+        encode_command(&mut open, &["set", "c", "3"]);
+        let report = check(&open);
+        assert_eq!(report.ok_up_to, valid);
+        assert!(report.error.is_some());
+
+        let mut cut = data.clone();
+        encode_command(&mut cut, &["set", "c", "3"]);
+        cut.pop();
+        assert_eq!(check(&cut).ok_up_to, valid);
+
+        let mut garbage = data.clone();
+        garbage.extend_from_slice(b"junk");
+        assert_eq!(check(&garbage).ok_up_to, valid);
+
+        let mut exec = data;
+        encode_command(&mut exec, &["exec"]);
+        assert_eq!(check(&exec).ok_up_to, valid);
+    }
+
+    #[test]
+    fn test_encrypted_file() {
+        let path = temp_path("encrypted");
+        let server = Server {
+            keyring: Some(Keyring::parse(&"ab".repeat(32)).unwrap()),
+            ..Default::default()
+        };
+        server.storage.db(0).set("old".to_string(), "1".to_string());
+        let keyring = server.keyring.as_ref();
+        server
+            .aof
+            .start(&path, AppendFsync::Always, &server.storage, keyring)
+            .unwrap();
+        let mut ctx = ConnectionContext::new(1);
+        run(&server, &mut ctx, &["SET", "secret", "value"]);
+        let complete = server.aof.size().unwrap();
+        run(&server, &mut ctx, &["RPUSH", "l", "a"]);
+        server.aof.stop();
+
+        let data = fs::read(&path).unwrap();
+        assert!(crypto::is_encrypted(&data));
+        assert!(!String::from_utf8_lossy(&data).contains("secret"));
+        assert!(load(&data, &Server::default(), true).is_err());
+
+        // A record cut short is dropped whole
+        let loaded = Server {
+            keyring: server.keyring.clone(),
+            ..Default::default()
+        };
+        let cut = &data[..data.len() - 5];
+        let stats = load(cut, &loaded, true).unwrap();
+        assert!(stats.truncated);
+        assert_eq!(stats.valid_len, complete);
+        assert_eq!(
+            loaded.storage.db(0).get("secret"),
+            Some("value".to_string())
+        );
+        assert_eq!(loaded.storage.db(0).get("old"), Some("1".to_string()));
+        assert!(load(cut, &loaded, false).is_err());
+
+        let decrypted = decrypt(&data, loaded.keyring.as_ref()).unwrap();
+        let report = check(&decrypted.plain);
+        assert_eq!(decrypted.file_offset(report.ok_up_to as usize), data.len());
+
+        // Records dropped, reordered, repeated or taken from another file
+        // of the same key fail to decrypt
+        let [_, set, rpush] = decrypted.records[..] else {
+            panic!("expected the dataset and two commands");
+        };
+        let (head, set, rpush) = (&data[..set.1], &data[set.1..rpush.1], &data[rpush.1..]);
+        let other = Server {
+            keyring: server.keyring.clone(),
+            ..Default::default()
+        };
+        let other_path = temp_path("encrypted-other");
+        other
+            .aof
+            .start(&other_path, AppendFsync::Always, &other.storage, keyring)
+            .unwrap();
+        run(&other, &mut ctx, &["SET", "secret", "forged"]);
+        other.aof.stop();
+        let other_data = fs::read(&other_path).unwrap();
+        let forged = &other_data[decrypt(&other_data, keyring)
+            .unwrap()
+            .records
+            .last()
+            .unwrap()
+            .1..];
+        for tampered in [
+            [head, rpush].concat(),
+            [head, rpush, set].concat(),
+            [head, set, set, rpush].concat(),
+            [head, forged, rpush].concat(),
+        ] {
+            let decrypted = decrypt(&tampered, keyring).unwrap();
+            assert!(!decrypted.truncated);
+            assert!(decrypted.error.unwrap().contains("failed authentication"));
+            assert!(
+                load(
+                    &tampered,
+                    &Server {
+                        keyring: server.keyring.clone(),
+                        ..Default::default()
+                    },
+                    true
+                )
+                .is_err()
+            );
+        }
+        fs::remove_file(&other_path).unwrap();
+
+        // Reopened, the file goes on where its records left off
+        server
+            .aof
+            .open(&path, AppendFsync::Always, None, keyring)
+            .unwrap();
+        run(&server, &mut ctx, &["SET", "more", "1"]);
+        server.aof.stop();
+        let reloaded = Server {
+            keyring: server.keyring.clone(),
+            ..Default::default()
+        };
+        let data = fs::read(&path).unwrap();
+        let stats = load(&data, &reloaded, false).unwrap();
+        assert_eq!(stats.valid_len, data.len() as u64);
+        assert_eq!(reloaded.storage.db(0).get("more"), Some("1".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_start_writes_the_dataset() {
+        let path = temp_path("start");
+        let server = Server::default();
+        let db = server.storage.db(1);
+        db.set("s".to_string(), "x".to_string());
+        db.sadd("set", (0..100).map(|i| i.to_string()).collect())
+            .unwrap();
+        db.hset("h", "f".to_string(), "v".to_string()).unwrap();
+        db.expire("h", 60_000);
+        // Restored whole, from a payload that isn't UTF-8
+        let items: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        db.bf_add("bloom", &items).unwrap();
+        server
+            .aof
+            .start(&path, AppendFsync::EverySec, &server.storage, None)
+            .unwrap();
+        assert!(server.aof.is_on());
+        server.aof.stop();
+
+        let loaded = Server::default();
+        load(&fs::read(&path).unwrap(), &loaded, false).unwrap();
+        assert_eq!(
+            digest::dataset_digest(&loaded.storage),
+            digest::dataset_digest(&server.storage)
+        );
+        assert_eq!(
+            loaded.storage.db(1).bf_exists("bloom", &items),
+            Ok(vec![true; 300])
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
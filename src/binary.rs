@@ -0,0 +1,110 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+/// Escapes are this plus the byte they stand for: U+10FF80 for 0x80, up
+/// to U+10FFFF for 0xFF, the last chars of the private use area.
+const ESCAPES: u32 = 0x10FF00;
+
+/// The first byte of the escapes in UTF-8, which text without them lacks.
+const ESCAPE_LEAD: u8 = 0xF4;
+
+/// Decodes bytes a client sent into a string, which values are kept as.
+/// UTF-8 text decodes to itself, and each byte that isn't part of it to an
+/// escape, as do the bytes of escapes a client sends, so that `encode`
+/// gives back exactly the bytes. Requests are decoded once, when they're
+/// parsed, and encoded wherever they leave the server: in replies, the
+/// append-only file, the replication stream, RDB files and DUMP payloads,
+/// and the hashes taken of them.
+pub fn decode(bytes: &[u8]) -> String {
+    if let Ok(s) = std::str::from_utf8(bytes)
+        && !bytes.contains(&ESCAPE_LEAD)
+    {
+        return s.to_string();
+    }
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if unescape(c).is_some() {
+                out.extend(c.encode_utf8(&mut [0; 4]).bytes().map(escape));
+            } else {
+                out.push(c);
+            }
+        }
+        out.extend(chunk.invalid().iter().copied().map(escape));
+    }
+    out
+}
+
+/// The bytes `s` was decoded from.
+pub fn encode(s: &str) -> Cow<'_, [u8]> {
+    if !s.as_bytes().contains(&ESCAPE_LEAD) {
+        return Cow::Borrowed(s.as_bytes());
+    }
+    let mut out = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match unescape(c) {
+            Some(byte) => out.push(byte),
+            None => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// The length of `s` in the bytes it was decoded from, as STRLEN counts.
+pub fn len(s: &str) -> usize {
+    encode(s).len()
+}
+
+/// Orders strings by the bytes they were decoded from, as Redis compares
+/// members and lexicographical ranges. Escapes would otherwise sort after
+/// all text, where their bytes sort before the UTF-8 of U+00C0 and up.
+pub fn cmp(a: &str, b: &str) -> Ordering {
+    encode(a).cmp(&encode(b))
+}
+
+fn escape(byte: u8) -> char {
+    char::from_u32(ESCAPES + byte as u32).expect("escapes are chars")
+}
+
+fn unescape(c: char) -> Option<u8> {
+    (c as u32)
+        .checked_sub(ESCAPES)
+        .filter(|&byte| byte >= 0x80)
+        .map(|byte| byte as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for bytes in [
+            &b"plain"[..],
+            "caf\u{e9}".as_bytes(),
+            b"\x80\xff\x00 \xc3",
+            b"caf\xe9 \xc3\xa9",
+            // The escapes themselves, sent as UTF-8
+            "\u{10ff80}\u{10ffff}\u{10fffd}".as_bytes(),
+            b"\xf4\x8f\xbe",
+        ] {
+            let s = decode(bytes);
+            assert_eq!(&*encode(&s), bytes, "{:?}", s);
+            assert_eq!(len(&s), bytes.len());
+        }
+        assert_eq!(decode("caf\u{e9}".as_bytes()), "caf\u{e9}");
+        assert_ne!(decode(b"caf\xe9"), "caf\u{e9}");
+        assert!(matches!(encode("caf\u{e9}"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_byte_order() {
+        let mut members: Vec<String> = [&b"\xc3\xa9"[..], b"\x80", b"\xc3", b"z", b"\xff"]
+            .into_iter()
+            .map(decode)
+            .collect();
+        members.sort_by(|a, b| cmp(a, b));
+        let bytes: Vec<Vec<u8>> = members.iter().map(|s| encode(s).into_owned()).collect();
+        assert_eq!(bytes, [&b"z"[..], b"\x80", b"\xc3", b"\xc3\xa9", b"\xff"]);
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::aof::AofGuard;
+use crate::commands::{group_changes, propagate};
+use crate::notify::{self, KeyspaceEvent};
+use crate::parser::Resp;
+use crate::replication::FeedGuard;
+use crate::server::Server;
+use crate::stats::Stats;
+use crate::storage::{Db, End, ScoreEnd, ZAddOptions};
+use crate::stream::{Fields, GroupRead, StreamId};
+
+/// What a pop takes from: an end of a list, or of a sorted set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    List(End),
+    ZSet(ScoreEnd),
+}
+
+impl Source {
+    /// The pop command for this source, which blocking pops are propagated
+    /// as so they replay without blocking.
+    pub fn pop_command(self) -> &'static str {
+        match self {
+            Source::List(end) => end.pop_command(),
+            Source::ZSet(end) => end.pop_command(),
+        }
+    }
+}
+
+/// What a pop took: list elements, or sorted set members with their
+/// scores. For a blocked XREAD or XREADGROUP, the stream entries it read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Elements {
+    List(Vec<String>),
+    ZSet(Vec<(String, f64)>),
+    Stream(Vec<(StreamId, Fields)>),
+}
+
+impl Elements {
+    pub fn len(&self) -> usize {
+        match self {
+            Elements::List(values) => values.len(),
+            Elements::ZSet(members) => members.len(),
+            Elements::Stream(entries) => entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A key and the elements popped from it for a blocked client.
+pub type Popped = (String, Elements);
+
+/// Pops `count` elements from `source` at `key`, as many as there are if
+/// fewer.
+pub fn pop(db: &Db, key: &str, source: Source, count: usize) -> Result<Elements, String> {
+    match source {
+        Source::List(end) => db.pop(key, end, count).map(Elements::List),
+        Source::ZSet(end) => db.zpop(key, end, count).map(Elements::ZSet),
+    }
+}
+
+/// Left by a blocking command that found nothing to pop or read, for the
+/// connection to wait on before replying.
+#[derive(Debug)]
+pub struct Blocked {
+    /// How long to wait, or None for as long as it takes.
+    pub timeout: Option<Duration>,
+    pub popped: oneshot::Receiver<Popped>,
+    /// The command's reply once it's served.
+    pub reply: fn(Popped) -> Resp,
+}
+
+/// What a client waits for.
+#[derive(Debug)]
+enum Wait {
+    /// To pop from `source`, `count` elements or, if None, one, propagated
+    /// without a count as BLPOP, BRPOP, BZPOPMIN and BZPOPMAX are.
+    Pop {
+        source: Source,
+        count: Option<usize>,
+    },
+    /// To read the entries of a stream added after the ID at its key's
+    /// position, up to `count` of them if given.
+    Read {
+        after: Vec<StreamId>,
+        count: Option<usize>,
+    },
+    /// To read as `consumer` of `group` the entries of a stream never
+    /// delivered to the group, up to `count` of them if given, making
+    /// them pending for it unless `noack`.
+    ReadGroup {
+        group: String,
+        consumer: String,
+        count: Option<usize>,
+        noack: bool,
+    },
+}
+
+#[derive(Debug)]
+struct Waiter {
+    db: usize,
+    keys: Vec<String>,
+    wait: Wait,
+    sender: oneshot::Sender<Popped>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// The clients waiting on each key of each database, oldest first.
+    queues: HashMap<(usize, String), VecDeque<u64>>,
+    waiters: HashMap<u64, Waiter>,
+    /// Keys with waiters written since they were last served, in the
+    /// order they were.
+    ready: Vec<(usize, String)>,
+}
+
+impl Registry {
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            let queue_key = (waiter.db, key.clone());
+            if let Some(queue) = self.queues.get_mut(&queue_key) {
+                queue.retain(|&waiting| waiting != id);
+                if queue.is_empty() {
+                    self.queues.remove(&queue_key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+/// Clients blocked in BLPOP, BRPOP, BLMPOP, BZPOPMIN, BZPOPMAX, BZMPOP,
+/// XREAD and XREADGROUP, by the keys they wait on. Writers signal the keys
+/// they change and serve the waiters on them before their command returns,
+/// popping for the oldest first, giving every reader the entries added
+/// past its ID, and consumers of a group in turn those the group hasn't
+/// been delivered.
+#[derive(Debug, Default)]
+pub struct BlockingKeys {
+    registry: Mutex<Registry>,
+    /// Whether anyone waits, so writes skip the lock when no one does.
+    any: AtomicBool,
+}
+
+impl BlockingKeys {
+    /// Registers client `id` as waiting on `keys` of database `db`, behind
+    /// the clients already waiting on each, to pop `count` elements from
+    /// `source`, or one if None. The receiver gets them once they're
+    /// popped.
+    pub fn block(
+        &self,
+        id: u64,
+        db: usize,
+        keys: &[String],
+        source: Source,
+        count: Option<usize>,
+    ) -> oneshot::Receiver<Popped> {
+        self.register(id, db, keys, Wait::Pop { source, count })
+    }
+
+    /// Registers client `id` as reading the streams at `keys` of database
+    /// `db`, for the entries added to one of them after the ID at the same
+    /// position in `after`, up to `count` of them if given. The receiver
+    /// gets the first stream's entries found.
+    pub fn block_read(
+        &self,
+        id: u64,
+        db: usize,
+        keys: &[String],
+        after: Vec<StreamId>,
+        count: Option<usize>,
+    ) -> oneshot::Receiver<Popped> {
+        self.register(id, db, keys, Wait::Read { after, count })
+    }
+
+    /// Registers client `id` as reading the streams at `keys` of database
+    /// `db` as a group and consumer, for up to `count` entries never
+    /// delivered to the group, made pending for the consumer unless
+    /// `noack`. The receiver gets the first stream's entries found.
+    pub fn block_read_group(
+        &self,
+        id: u64,
+        db: usize,
+        keys: &[String],
+        (group, consumer): (&str, &str),
+        count: Option<usize>,
+        noack: bool,
+    ) -> oneshot::Receiver<Popped> {
+        let wait = Wait::ReadGroup {
+            group: group.to_string(),
+            consumer: consumer.to_string(),
+            count,
+            noack,
+        };
+        self.register(id, db, keys, wait)
+    }
+
+    fn register(
+        &self,
+        id: u64,
+        db: usize,
+        keys: &[String],
+        wait: Wait,
+    ) -> oneshot::Receiver<Popped> {
+        let (sender, receiver) = oneshot::channel();
+        let mut registry = self.registry.lock().unwrap();
+        registry.remove(id);
+        for key in keys {
+            let queue = registry.queues.entry((db, key.clone())).or_default();
+            if !queue.contains(&id) {
+                queue.push_back(id);
+            }
+        }
+        let waiter = Waiter {
+            db,
+            keys: keys.to_vec(),
+            wait,
+            sender,
+        };
+        registry.waiters.insert(id, waiter);
+        self.any.store(true, Ordering::Relaxed);
+        receiver
+    }
+
+    /// Stops client `id` waiting, if it still is.
+    pub fn unblock(&self, id: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.remove(id);
+        self.any
+            .store(!registry.waiters.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Marks `key` of database `db` as written, to serve the clients
+    /// waiting on it, if any, once the command is done.
+    pub fn signal(&self, db: usize, key: &str) {
+        if !self.any.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut registry = self.registry.lock().unwrap();
+        let ready = (db, key.to_string());
+        if registry.queues.contains_key(&ready) && !registry.ready.contains(&ready) {
+            registry.ready.push(ready);
+        }
+    }
+
+    /// Clients waiting in a blocking command.
+    pub fn waiting(&self) -> usize {
+        self.registry.lock().unwrap().waiters.len()
+    }
+}
+
+/// Serves the clients waiting on the keys signaled since the last call:
+/// every reader of a stream added to past its ID and the consumers of its
+/// groups, then the oldest first of those popping, for each while their
+/// list or sorted set has elements. Called by call() after every write,
+/// with the append-only file and replication stream it holds, each pop
+/// being propagated as the LPOP, RPOP, ZPOPMIN or ZPOPMAX it is, and each
+/// group read as the changes it made.
+pub fn serve_ready(server: &Server, aof: &mut Option<AofGuard>, replicas: &mut Option<FeedGuard>) {
+    let blocking = &server.blocking;
+    if !blocking.any.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut registry = blocking.registry.lock().unwrap();
+    for (db, key) in std::mem::take(&mut registry.ready) {
+        let storage = server.storage.db(db);
+        serve_readers(server, &mut registry, aof, replicas, db, &key);
+        while let Some(id) = registry.queues.get(&(db, key.clone())).and_then(|queue| {
+            queue
+                .iter()
+                .copied()
+                .find(|id| matches!(registry.waiters[id].wait, Wait::Pop { .. }))
+        }) {
+            let waiter = &registry.waiters[&id];
+            let Wait::Pop { source, count } = waiter.wait else {
+                unreachable!("only waiters popping were looked for")
+            };
+            let closed = waiter.sender.is_closed();
+            // Gone without unblocking, the connection having ended
+            if closed {
+                registry.remove(id);
+                continue;
+            }
+            let elements = match pop(storage, &key, source, count.unwrap_or(1)) {
+                Ok(elements) if !elements.is_empty() => elements,
+                _ => break,
+            };
+            let popped = elements.len().to_string();
+            let waiter = registry.remove(id).expect("the waiter was just looked up");
+            if let Err((_, elements)) = waiter.sender.send((key.clone(), elements)) {
+                // Closed since, put the elements back where they were
+                restore(storage, &key, source, elements);
+                continue;
+            }
+            Stats::add(&server.stats.dirty, 1);
+            server.storage.touch_key(db, &key);
+            let event = if storage.get_type(&key).is_some() {
+                KeyspaceEvent::Set
+            } else {
+                KeyspaceEvent::Deleted
+            };
+            notify::notify(server, event, db, &key);
+            if count.is_some() {
+                propagate(aof, replicas, db, &[source.pop_command(), &key, &popped]);
+            } else {
+                propagate(aof, replicas, db, &[source.pop_command(), &key]);
+            }
+        }
+    }
+    blocking
+        .any
+        .store(!registry.waiters.is_empty(), Ordering::Relaxed);
+}
+
+/// Gives the clients reading the stream at `key` the entries added past
+/// their IDs, if it has any. All are served, as reading takes nothing
+/// away. Consumers of a group are given the entries it wasn't delivered
+/// in turn, oldest first, until there are none left.
+fn serve_readers(
+    server: &Server,
+    registry: &mut Registry,
+    aof: &mut Option<AofGuard>,
+    replicas: &mut Option<FeedGuard>,
+    db: usize,
+    key: &str,
+) {
+    let Some(queue) = registry.queues.get(&(db, key.to_string())) else {
+        return;
+    };
+    let storage = server.storage.db(db);
+    let readers: Vec<u64> = queue
+        .iter()
+        .copied()
+        .filter(|id| !matches!(registry.waiters[id].wait, Wait::Pop { .. }))
+        .collect();
+    for id in readers {
+        let waiter = &registry.waiters[&id];
+        // Gone without unblocking, the connection having ended
+        if waiter.sender.is_closed() {
+            registry.remove(id);
+            continue;
+        }
+        let entries = match &waiter.wait {
+            Wait::Read { after, count } => {
+                let position = waiter
+                    .keys
+                    .iter()
+                    .position(|waited| waited == key)
+                    .expect("the waiter is queued on the key");
+                let Some(start) = after[position].next() else {
+                    continue;
+                };
+                match storage.xrange(key, start, StreamId::MAX, false, *count) {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                }
+            }
+            Wait::ReadGroup {
+                group,
+                consumer,
+                count,
+                noack,
+            } => {
+                let read = storage.xreadgroup(key, group, consumer, GroupRead::New, *count, *noack);
+                let Ok(Some((entries, changes))) = read else {
+                    continue;
+                };
+                let commands = group_changes(key, group, consumer, &changes);
+                if !commands.is_empty() {
+                    Stats::add(&server.stats.dirty, 1);
+                    server.storage.touch_key(db, key);
+                    for parts in &commands {
+                        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+                        propagate(aof, replicas, db, &parts);
+                    }
+                }
+                // New entries, all still in the stream
+                entries
+                    .into_iter()
+                    .filter_map(|(id, fields)| Some((id, fields?)))
+                    .collect()
+            }
+            Wait::Pop { .. } => unreachable!("waiters popping were left out"),
+        };
+        if entries.is_empty() {
+            continue;
+        }
+        let waiter = registry.remove(id).expect("the waiter was just looked up");
+        // Nothing to put back if it's gone since, a group's entries staying
+        // pending for the consumer
+        let _ = waiter
+            .sender
+            .send((key.to_string(), Elements::Stream(entries)));
+    }
+}
+
+/// Puts back elements popped from `source` at `key` for a client gone
+/// before it got them.
+fn restore(db: &Db, key: &str, source: Source, elements: Elements) {
+    let restored = match (source, elements) {
+        (Source::List(end), Elements::List(mut values)) => {
+            values.reverse();
+            match end {
+                End::Left => db.lpush(key, values),
+                End::Right => db.rpush(key, values),
+            }
+        }
+        (Source::ZSet(_), Elements::ZSet(members)) => db.zadd(
+            key,
+            members
+                .into_iter()
+                .map(|(member, score)| (score, member))
+                .collect(),
+            ZAddOptions::default(),
+        ),
+        _ => unreachable!("pops take elements of their source's type"),
+    };
+    restored.expect("the key held the same type a moment ago");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::NewId;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn popped(key: &str, values: &[&str]) -> Popped {
+        (key.to_string(), Elements::List(keys(values)))
+    }
+
+    #[test]
+    fn test_waiters_served_oldest_first() {
+        let server = Server::default();
+        let db = server.storage.db(0);
+        let blocking = &server.blocking;
+        let mut first = blocking.block(1, 0, &keys(&["a", "b"]), Source::List(End::Left), None);
+        let mut second = blocking.block(2, 0, &keys(&["b"]), Source::List(End::Right), Some(2));
+        let mut other_db = blocking.block(3, 1, &keys(&["b"]), Source::List(End::Left), None);
+        let mut last = blocking.block(4, 0, &keys(&["b"]), Source::List(End::Left), None);
+        assert_eq!(blocking.waiting(), 4);
+
+        db.rpush("b", keys(&["w", "x", "y", "z"])).unwrap();
+        blocking.signal(0, "b");
+        serve_ready(&server, &mut None, &mut None);
+        assert_eq!(first.try_recv(), Ok(popped("b", &["w"])));
+        assert_eq!(second.try_recv(), Ok(popped("b", &["z", "y"])));
+        assert_eq!(last.try_recv(), Ok(popped("b", &["x"])));
+        assert!(other_db.try_recv().is_err());
+        assert_eq!(db.llen("b"), Ok(0));
+        assert_eq!(blocking.waiting(), 1);
+
+        // Served once, client 1 no longer waits on "a"
+        db.rpush("a", keys(&["x"])).unwrap();
+        blocking.signal(0, "a");
+        serve_ready(&server, &mut None, &mut None);
+        assert_eq!(db.llen("a"), Ok(1));
+
+        blocking.unblock(3);
+        assert_eq!(blocking.waiting(), 0);
+        server.storage.db(1).rpush("b", keys(&["x"])).unwrap();
+        blocking.signal(1, "b");
+        serve_ready(&server, &mut None, &mut None);
+        assert_eq!(server.storage.db(1).llen("b"), Ok(1));
+    }
+
+    #[test]
+    fn test_sorted_set_waiters() {
+        let server = Server::default();
+        let db = server.storage.db(0);
+        let blocking = &server.blocking;
+        let mut min = blocking.block(1, 0, &keys(&["z"]), Source::ZSet(ScoreEnd::Min), None);
+        let mut max = blocking.block(2, 0, &keys(&["z"]), Source::ZSet(ScoreEnd::Max), Some(5));
+
+        let members = vec![
+            (1.0, "a".to_string()),
+            (2.0, "b".to_string()),
+            (3.0, "c".to_string()),
+        ];
+        db.zadd("z", members, ZAddOptions::default()).unwrap();
+        blocking.signal(0, "z");
+        serve_ready(&server, &mut None, &mut None);
+        let scored = |members: &[(&str, f64)]| {
+            Elements::ZSet(members.iter().map(|&(m, s)| (m.to_string(), s)).collect())
+        };
+        assert_eq!(min.try_recv(), Ok(("z".to_string(), scored(&[("a", 1.0)]))));
+        assert_eq!(
+            max.try_recv(),
+            Ok(("z".to_string(), scored(&[("c", 3.0), ("b", 2.0)])))
+        );
+        assert_eq!(db.get_type("z"), None);
+        assert_eq!(blocking.waiting(), 0);
+    }
+
+    #[test]
+    fn test_closed_waiters_are_skipped() {
+        let server = Server::default();
+        let db = server.storage.db(0);
+        drop(
+            server
+                .blocking
+                .block(1, 0, &keys(&["k"]), Source::List(End::Left), None),
+        );
+        let mut open = server
+            .blocking
+            .block(2, 0, &keys(&["k"]), Source::List(End::Left), None);
+
+        db.rpush("k", keys(&["x"])).unwrap();
+        server.blocking.signal(0, "k");
+        serve_ready(&server, &mut None, &mut None);
+        assert_eq!(open.try_recv(), Ok(popped("k", &["x"])));
+        assert_eq!(server.blocking.waiting(), 0);
+    }
+
+    #[test]
+    fn test_stream_readers() {
+        let server = Server::default();
+        let db = server.storage.db(0);
+        let blocking = &server.blocking;
+        let add = |ms: u64| {
+            let fields = vec![("n".to_string(), ms.to_string())];
+            db.xadd(
+                "s",
+                NewId::Explicit(StreamId::new(ms, 0)),
+                fields,
+                false,
+                None,
+            )
+            .unwrap();
+        };
+        add(1);
+        let mut all =
+            blocking.block_read(1, 0, &keys(&["other", "s"]), vec![StreamId::MIN; 2], None);
+        let mut new = blocking.block_read(2, 0, &keys(&["s"]), vec![StreamId::new(1, 0)], Some(1));
+        let mut popper = blocking.block(3, 0, &keys(&["s"]), Source::List(End::Left), None);
+
+        add(2);
+        add(3);
+        blocking.signal(0, "s");
+        serve_ready(&server, &mut None, &mut None);
+        let entries = |ids: &[u64]| {
+            let entries = ids
+                .iter()
+                .map(|&ms| {
+                    (
+                        StreamId::new(ms, 0),
+                        vec![("n".to_string(), ms.to_string())],
+                    )
+                })
+                .collect();
+            ("s".to_string(), Elements::Stream(entries))
+        };
+        // Both readers are served, neither taking the entries away
+        assert_eq!(all.try_recv(), Ok(entries(&[1, 2, 3])));
+        assert_eq!(new.try_recv(), Ok(entries(&[2])));
+        assert!(popper.try_recv().is_err());
+        assert_eq!(db.xlen("s"), Ok(3));
+        assert_eq!(blocking.waiting(), 1);
+
+        // A reader past every entry waits on
+        let mut late = blocking.block_read(4, 0, &keys(&["s"]), vec![StreamId::new(3, 0)], None);
+        blocking.signal(0, "s");
+        serve_ready(&server, &mut None, &mut None);
+        assert!(late.try_recv().is_err());
+        assert_eq!(blocking.waiting(), 2);
+    }
+}
@@ -0,0 +1,280 @@
+use std::f64::consts::LN_2;
+
+use crate::binary;
+
+/// What a bloom filter BF.ADD or BF.MADD creates starts with, unless the
+/// `bf-*` parameters say otherwise: RedisBloom's defaults.
+pub const DEFAULT_ERROR_RATE: f64 = 0.01;
+pub const DEFAULT_CAPACITY: u64 = 100;
+pub const DEFAULT_EXPANSION: u32 = 2;
+
+/// The filter added to a full chain has this times the error rate of the
+/// one before, so that the chain's stays within the rate it was created
+/// with however many it grows to.
+const ERROR_TIGHTENING_RATIO: f64 = 0.5;
+
+/// RedisBloom's chain options, stored with it. Filters are sized to whole
+/// 64-bit words rather than a power of two bits (NOROUND), and hashed with
+/// 64-bit MurmurHash2 (FORCE64); NO_SCALING chains take no more items once
+/// their only filter is full.
+pub const OPT_NOROUND: u64 = 1;
+pub const OPT_FORCE64: u64 = 4;
+pub const OPT_NO_SCALING: u64 = 8;
+
+/// The seed of an item's first hash, whose value seeds the second.
+const HASH_SEED: u64 = 0xc6a4a7935bd1e995;
+
+/// One bloom filter of a chain, for up to `capacity` items with
+/// `error_rate` false positives, each item setting `hashes` of its bits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub capacity: u64,
+    pub error_rate: f64,
+    pub hashes: u32,
+    /// Bits per item, from which `hashes` and the bits are sized.
+    pub bpe: f64,
+    /// The bits' count as a power of two, if RedisBloom rounded it to
+    /// one, or 0.
+    pub n2: u64,
+    /// How many of the bits of `data` are used.
+    pub bits: u64,
+    pub data: Vec<u8>,
+    /// Items added to this filter.
+    pub size: u64,
+}
+
+impl Filter {
+    /// An empty filter, or None if its bits can't be allocated.
+    fn new(capacity: u64, error_rate: f64) -> Option<Filter> {
+        let bpe = -error_rate.ln() / (LN_2 * LN_2);
+        let bits = capacity as f64 * bpe;
+        if bits >= (u64::MAX / 2) as f64 {
+            return None;
+        }
+        let bytes = usize::try_from((bits as u64).div_ceil(64) * 8).ok()?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(bytes).ok()?;
+        data.resize(bytes, 0);
+        Some(Filter {
+            capacity,
+            error_rate,
+            hashes: (LN_2 * bpe).ceil() as u32,
+            bpe,
+            n2: 0,
+            bits: bytes as u64 * 8,
+            data,
+            size: 0,
+        })
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        (0..self.hashes as u64).all(|i| {
+            let bit = bit(hash, i, self.bits);
+            self.data[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        for i in 0..self.hashes as u64 {
+            let bit = bit(hash, i, self.bits);
+            self.data[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+        self.size += 1;
+    }
+}
+
+/// A scalable bloom filter, as RedisBloom keeps one: a chain of filters,
+/// each taking items once those before it are full. A new filter holds
+/// `expansion` times as many items as the last, at a tighter error rate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bloom {
+    filters: Vec<Filter>,
+    options: u64,
+    expansion: u32,
+}
+
+impl Bloom {
+    /// An empty chain whose first filter holds `capacity` items, growing by
+    /// `expansion` or, if None, not at all. None if the filter's bits can't
+    /// be allocated.
+    pub fn new(capacity: u64, error_rate: f64, expansion: Option<u32>) -> Option<Bloom> {
+        let mut options = OPT_NOROUND | OPT_FORCE64;
+        if expansion.is_none() {
+            options |= OPT_NO_SCALING;
+        }
+        Some(Bloom {
+            filters: vec![Filter::new(capacity, error_rate)?],
+            options,
+            expansion: expansion.unwrap_or(DEFAULT_EXPANSION),
+        })
+    }
+
+    /// A chain as loaded from an RDB file, which has at least one filter.
+    pub fn from_parts(filters: Vec<Filter>, options: u64, expansion: u32) -> Bloom {
+        Bloom {
+            filters,
+            options,
+            expansion,
+        }
+    }
+
+    /// The filters, oldest first.
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+
+    pub fn options(&self) -> u64 {
+        self.options
+    }
+
+    pub fn expansion(&self) -> u32 {
+        self.expansion
+    }
+
+    pub fn scaling(&self) -> bool {
+        self.options & OPT_NO_SCALING == 0
+    }
+
+    /// Items added to the chain.
+    pub fn size(&self) -> u64 {
+        self.filters.iter().map(|filter| filter.size).sum()
+    }
+
+    /// Bytes the filters' bits take.
+    pub fn bytes(&self) -> usize {
+        self.filters.iter().map(|filter| filter.data.len()).sum()
+    }
+
+    /// Whether `item` may have been added: false only if it never was.
+    pub fn contains(&self, item: &str) -> bool {
+        let hash = hash(&binary::encode(item));
+        self.filters.iter().rev().any(|filter| filter.contains(hash))
+    }
+
+    /// Adds `item`, and returns whether it's new, false if it may have been
+    /// added already. Once the last filter is full a larger one is added
+    /// for it, or an error returned if the chain doesn't scale or can't.
+    pub fn add(&mut self, item: &str) -> Result<bool, String> {
+        let hash = hash(&binary::encode(item));
+        if self.filters.iter().rev().any(|filter| filter.contains(hash)) {
+            return Ok(false);
+        }
+        let last = self.filters.last().expect("a chain has a filter");
+        if last.size >= last.capacity {
+            if !self.scaling() {
+                return Err("ERR non scaling filter is full".to_string());
+            }
+            let filter = last
+                .capacity
+                .checked_mul(self.expansion as u64)
+                .and_then(|capacity| {
+                    Filter::new(capacity, last.error_rate * ERROR_TIGHTENING_RATIO)
+                })
+                .ok_or("ERR problem inserting into filter")?;
+            self.filters.push(filter);
+        }
+        let last = self.filters.last_mut().expect("a chain has a filter");
+        last.insert(hash);
+        Ok(true)
+    }
+}
+
+/// An item's two hashes: 64-bit MurmurHash2 of it with a fixed seed, and
+/// with that as the seed.
+fn hash(item: &[u8]) -> (u64, u64) {
+    let a = murmur_hash64a(item, HASH_SEED);
+    (a, murmur_hash64a(item, a))
+}
+
+/// The `i`th of the `bits` an item's hashes pick: its first hash, and `i`
+/// times its second past it.
+fn bit((a, b): (u64, u64), i: u64, bits: u64) -> u64 {
+    a.wrapping_add(i.wrapping_mul(b)) % bits
+}
+
+/// Austin Appleby's MurmurHash64A.
+fn murmur_hash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let words = key.chunks_exact(8);
+    let tail = words.remainder();
+    for word in words {
+        let mut k = u64::from_le_bytes(word.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur_hash() {
+        assert_eq!(murmur_hash64a(b"", 0), 0);
+        assert_eq!(murmur_hash64a(b"", 1), 0xc6a4a7935bd064dc);
+        // Whole words and a tail hash differently from the same bytes cut
+        // short
+        assert_ne!(
+            murmur_hash64a(b"abcdefghi", 0),
+            murmur_hash64a(b"abcdefgh", 0)
+        );
+    }
+
+    #[test]
+    fn test_sizing() {
+        let bloom = Bloom::new(100, 0.01, Some(2)).unwrap();
+        let filter = &bloom.filters()[0];
+        // 9.585 bits per item, rounded up to whole words
+        assert_eq!(filter.bits, 960);
+        assert_eq!(filter.data.len(), 120);
+        assert_eq!(filter.hashes, 7);
+        assert_eq!(bloom.options(), OPT_NOROUND | OPT_FORCE64);
+        assert!(Bloom::new(u64::MAX, 0.01, None).is_none());
+    }
+
+    #[test]
+    fn test_add_and_scale() {
+        let mut bloom = Bloom::new(10, 0.01, Some(4)).unwrap();
+        assert_eq!(bloom.add("a"), Ok(true));
+        assert_eq!(bloom.add("a"), Ok(false));
+        assert!(bloom.contains("a"));
+        assert!(!bloom.contains("b"));
+
+        for n in 0..100 {
+            bloom.add(&n.to_string()).unwrap();
+        }
+        assert!((0..100).all(|n| bloom.contains(&n.to_string())));
+        // 10, then 40 and 160 items at half the error rate of the last
+        let filters = bloom.filters();
+        let capacities: Vec<u64> = filters.iter().map(|f| f.capacity).collect();
+        assert_eq!(capacities, [10, 40, 160]);
+        assert_eq!(filters[2].error_rate, 0.01 * 0.25);
+        assert_eq!(filters[0].size, 10);
+        assert!(bloom.size() <= 101 && bloom.size() > 90);
+
+        let mut fixed = Bloom::new(2, 0.01, None).unwrap();
+        fixed.add("a").unwrap();
+        fixed.add("b").unwrap();
+        assert_eq!(fixed.add("a"), Ok(false));
+        assert_eq!(
+            fixed.add("c"),
+            Err("ERR non scaling filter is full".to_string())
+        );
+        assert!(!fixed.scaling());
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::reply::Protocol;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PauseMode {
+    Write,
+    All,
+}
+
+/// How a blocked client was woken by CLIENT UNBLOCK.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnblockReason {
+    Timeout,
+    Error,
+}
+
+/// What a connection is to the server, as CLIENT LIST and CLIENT KILL TYPE
+/// tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientKind {
+    Normal,
+    /// A replica, from when it sent SYNC or PSYNC.
+    Replica,
+    /// This server's link to its primary.
+    Primary,
+}
+
+/// The client type a CLIENT LIST or CLIENT KILL TYPE argument names, with
+/// `slave` for `replica`.
+pub fn parse_client_type(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "normal" => Some("normal"),
+        "master" => Some("master"),
+        "replica" | "slave" => Some("replica"),
+        "pubsub" => Some("pubsub"),
+        _ => None,
+    }
+}
+
+/// Bytes a connection's query and output buffers take, updated by the
+/// connection itself.
+#[derive(Debug, Default)]
+pub struct ClientMemory {
+    qbuf: AtomicUsize,
+    omem: AtomicUsize,
+}
+
+impl ClientMemory {
+    pub fn qbuf(&self) -> usize {
+        self.qbuf.load(Ordering::Relaxed)
+    }
+
+    pub fn omem(&self) -> usize {
+        self.omem.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        self.qbuf() + self.omem()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub laddr: SocketAddr,
+    pub fd: i64,
+    pub name: Option<String>,
+    pub lib_name: Option<String>,
+    pub lib_ver: Option<String>,
+    pub kind: ClientKind,
+    pub db: usize,
+    pub user: String,
+    pub protocol: Protocol,
+    pub created_at: Instant,
+    pub last_interaction: Instant,
+    pub last_cmd: String,
+    pub sub: usize,
+    pub psub: usize,
+    pub kill: Arc<Notify>,
+    pub blocked: bool,
+    pub unblock: Arc<Notify>,
+    unblocked_with: Option<UnblockReason>,
+    pub memory: Arc<ClientMemory>,
+    /// Set with CLIENT NO-EVICT: never disconnected by maxmemory-clients.
+    pub no_evict: bool,
+    /// Set by MONITOR: the connection is sent every command others run.
+    pub monitor: bool,
+    /// Commands queued since MULTI, or None outside a transaction.
+    pub multi: Option<usize>,
+}
+
+impl ClientInfo {
+    /// Renders the client the way Redis does in CLIENT LIST / CLIENT INFO.
+    pub fn to_line(&self) -> String {
+        let now = Instant::now();
+        format!(
+            "id={} addr={} laddr={} fd={} name={} age={} idle={} flags={} db={} sub={} psub={} multi={} qbuf={} omem={} tot-mem={} cmd={} user={} resp={} lib-name={} lib-ver={}",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.fd,
+            self.name.as_deref().unwrap_or(""),
+            now.duration_since(self.created_at).as_secs(),
+            now.duration_since(self.last_interaction).as_secs(),
+            self.flags(),
+            self.db,
+            self.sub,
+            self.psub,
+            self.multi.map_or(-1, |queued| queued as i64),
+            self.memory.qbuf(),
+            self.memory.omem(),
+            self.memory.total(),
+            if self.last_cmd.is_empty() {
+                "NULL"
+            } else {
+                &self.last_cmd
+            },
+            self.user,
+            self.protocol.version(),
+            self.lib_name.as_deref().unwrap_or(""),
+            self.lib_ver.as_deref().unwrap_or(""),
+        )
+    }
+
+    /// The type CLIENT LIST and CLIENT KILL filter on.
+    pub fn client_type(&self) -> &'static str {
+        match self.kind {
+            ClientKind::Primary => "master",
+            ClientKind::Replica => "replica",
+            ClientKind::Normal if self.sub + self.psub > 0 => "pubsub",
+            ClientKind::Normal => "normal",
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        match self.kind {
+            ClientKind::Replica => flags.push('S'),
+            ClientKind::Primary => flags.push('M'),
+            ClientKind::Normal => {}
+        }
+        if self.blocked {
+            flags.push('b');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if self.monitor {
+            flags.push('O');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientRegistry {
+    next_id: Arc<AtomicU64>,
+    clients: Arc<RwLock<HashMap<u64, ClientInfo>>>,
+    pause: Arc<RwLock<Option<(PauseMode, Instant)>>>,
+    unpaused: Arc<Notify>,
+    /// The sum of every registered client's `memory`.
+    memory: Arc<AtomicUsize>,
+    /// Where the commands clients run are sent for the MONITOR connections.
+    monitors: Arc<RwLock<HashMap<u64, UnboundedSender<String>>>>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        ClientRegistry {
+            next_id: Arc::new(AtomicU64::new(1)),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            pause: Arc::new(RwLock::new(None)),
+            unpaused: Arc::new(Notify::new()),
+            memory: Arc::new(AtomicUsize::new(0)),
+            monitors: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn register(&self, addr: SocketAddr, laddr: SocketAddr, fd: i64) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let info = ClientInfo {
+            id,
+            addr,
+            laddr,
+            fd,
+            name: None,
+            lib_name: None,
+            lib_ver: None,
+            kind: ClientKind::Normal,
+            db: 0,
+            user: "default".to_string(),
+            protocol: Protocol::Resp2,
+            created_at: now,
+            last_interaction: now,
+            last_cmd: String::new(),
+            sub: 0,
+            psub: 0,
+            kill: Arc::new(Notify::new()),
+            blocked: false,
+            unblock: Arc::new(Notify::new()),
+            unblocked_with: None,
+            memory: Arc::new(ClientMemory::default()),
+            no_evict: false,
+            monitor: false,
+            multi: None,
+        };
+        self.clients.write().unwrap().insert(id, info);
+        id
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.remove(&mut self.clients.write().unwrap(), id);
+    }
+
+    /// Removes a client under the lock, taking its buffers off the total.
+    fn remove(&self, clients: &mut HashMap<u64, ClientInfo>, id: u64) -> Option<ClientInfo> {
+        let client = clients.remove(&id)?;
+        if client.monitor {
+            self.monitors.write().unwrap().remove(&id);
+        }
+        self.memory
+            .fetch_sub(client.memory.total(), Ordering::Relaxed);
+        Some(client)
+    }
+
+    /// Records the command a client is about to run, refreshing its idle time.
+    pub fn touch(&self, id: u64, cmd_name: &str) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.last_interaction = Instant::now();
+            client.last_cmd = cmd_name.to_string();
+        }
+    }
+
+    /// Removes a client from the registry and wakes its connection task so it
+    /// closes the socket. Returns false if no such client is connected.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.remove(&mut self.clients.write().unwrap(), id) {
+            Some(client) => {
+                client.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the connection name; an empty name clears it.
+    pub fn set_name(&self, id: u64, name: &str) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.name = (!name.is_empty()).then(|| name.to_string());
+        }
+    }
+
+    pub fn set_kind(&self, id: u64, kind: ClientKind) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.kind = kind;
+        }
+    }
+
+    pub fn set_user(&self, id: u64, user: &str) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.user = user.to_string();
+        }
+    }
+
+    pub fn set_protocol(&self, id: u64, protocol: Protocol) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.protocol = protocol;
+        }
+    }
+
+    pub fn set_db(&self, id: u64, db: usize) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.db = db;
+        }
+    }
+
+    /// Records the client library reported through CLIENT SETINFO.
+    pub fn set_lib_info(&self, id: u64, lib_name: Option<&str>, lib_ver: Option<&str>) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            if let Some(name) = lib_name {
+                client.lib_name = (!name.is_empty()).then(|| name.to_string());
+            }
+            if let Some(ver) = lib_ver {
+                client.lib_ver = (!ver.is_empty()).then(|| ver.to_string());
+            }
+        }
+    }
+
+    /// Records how many commands are queued in the client's transaction.
+    pub fn set_multi(&self, id: u64, multi: Option<usize>) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.multi = multi;
+        }
+    }
+
+    pub fn set_no_evict(&self, id: u64, no_evict: bool) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.no_evict = no_evict;
+        }
+    }
+
+    /// Records how many bytes a client's query and output buffers take.
+    pub fn set_memory(&self, id: u64, qbuf: usize, omem: usize) {
+        // Under the lock, so a client being removed isn't counted again
+        let clients = self.clients.read().unwrap();
+        if let Some(client) = clients.get(&id) {
+            let old = client.memory.qbuf.swap(qbuf, Ordering::Relaxed)
+                + client.memory.omem.swap(omem, Ordering::Relaxed);
+            self.memory.fetch_add(qbuf + omem, Ordering::Relaxed);
+            self.memory.fetch_sub(old, Ordering::Relaxed);
+        }
+    }
+
+    /// Bytes the buffers of every client take together.
+    pub fn memory(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+
+    /// Disconnects the clients whose buffers take the most memory, except
+    /// CLIENT NO-EVICT ones, until all of them take `limit` bytes or less.
+    /// Returns the ids of those disconnected.
+    pub fn evict(&self, limit: usize) -> Vec<u64> {
+        let mut clients = self.clients.write().unwrap();
+        let mut evicted = Vec::new();
+        while self.memory() > limit {
+            let largest = clients
+                .values()
+                .filter(|client| !client.no_evict && client.memory.total() > 0)
+                .max_by_key(|client| client.memory.total())
+                .map(|client| client.id);
+            let Some(client) = largest.and_then(|id| self.remove(&mut clients, id)) else {
+                break;
+            };
+            client.kill.notify_one();
+            evicted.push(client.id);
+        }
+        evicted
+    }
+
+    /// Turns a client into a MONITOR connection, returning where the lines
+    /// for the commands others run arrive.
+    pub fn monitor(&self, id: u64) -> UnboundedReceiver<String> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.monitor = true;
+            self.monitors.write().unwrap().insert(id, sender);
+        }
+        receiver
+    }
+
+    pub fn has_monitors(&self) -> bool {
+        !self.monitors.read().unwrap().is_empty()
+    }
+
+    /// Sends a line to every MONITOR connection.
+    pub fn feed_monitors(&self, line: &str) {
+        for sender in self.monitors.read().unwrap().values() {
+            let _ = sender.send(line.to_string());
+        }
+    }
+
+    /// Marks a client as waiting inside a blocking command.
+    pub fn set_blocked(&self, id: u64, blocked: bool) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(&id) {
+            client.blocked = blocked;
+            if blocked {
+                client.unblocked_with = None;
+            }
+        }
+    }
+
+    /// Wakes a client blocked in a blocking command. Returns false if the
+    /// client does not exist or is not blocked.
+    pub fn unblock(&self, id: u64, reason: UnblockReason) -> bool {
+        match self.clients.write().unwrap().get_mut(&id) {
+            Some(client) if client.blocked => {
+                client.blocked = false;
+                client.unblocked_with = Some(reason);
+                client.unblock.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns why a client was unblocked, clearing the record.
+    pub fn take_unblock_reason(&self, id: u64) -> Option<UnblockReason> {
+        self.clients
+            .write()
+            .unwrap()
+            .get_mut(&id)
+            .and_then(|client| client.unblocked_with.take())
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.clients.read().unwrap().contains_key(&id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientInfo> {
+        self.clients.read().unwrap().get(&id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let clients = self.clients.read().unwrap();
+        let mut list: Vec<ClientInfo> = clients.values().cloned().collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    /// Pauses command processing for `duration`. An existing pause is only
+    /// ever extended: the later deadline and the stricter mode win.
+    pub fn pause(&self, mode: PauseMode, duration: Duration) {
+        let mut pause = self.pause.write().unwrap();
+        let mut deadline = Instant::now() + duration;
+        let mut mode = mode;
+        if let Some((current_mode, current_deadline)) = *pause
+            && current_deadline > Instant::now()
+        {
+            deadline = deadline.max(current_deadline);
+            if current_mode == PauseMode::All {
+                mode = PauseMode::All;
+            }
+        }
+        *pause = Some((mode, deadline));
+    }
+
+    pub fn unpause(&self) {
+        *self.pause.write().unwrap() = None;
+        self.unpaused.notify_waiters();
+    }
+
+    /// Returns the deadline of the pause affecting a command, if any.
+    fn paused_until(&self, is_write: bool) -> Option<Instant> {
+        match *self.pause.read().unwrap() {
+            Some((mode, deadline)) if deadline > Instant::now() => {
+                if mode == PauseMode::All || is_write {
+                    Some(deadline)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_paused(&self, is_write: bool) -> bool {
+        self.paused_until(is_write).is_some()
+    }
+
+    /// Waits until a command of the given kind is allowed to run.
+    pub async fn wait_while_paused(&self, is_write: bool) {
+        loop {
+            let unpaused = self.unpaused.notified();
+            let Some(deadline) = self.paused_until(is_write) else {
+                return;
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline.into()) => {}
+                _ = unpaused => {}
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for ClientRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_register_and_list() {
+        let registry = ClientRegistry::new();
+        let a = registry.register(addr(5000), addr(6379), 7);
+        let b = registry.register(addr(5001), addr(6379), 8);
+        assert!(b > a);
+        assert_eq!(registry.len(), 2);
+
+        registry.unregister(a);
+        let list = registry.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, b);
+    }
+
+    #[test]
+    fn test_touch_updates_last_cmd() {
+        let registry = ClientRegistry::new();
+        let id = registry.register(addr(5000), addr(6379), 7);
+        registry.touch(id, "get");
+        let line = registry.get(id).unwrap().to_line();
+        assert!(line.starts_with(&format!("id={} addr=127.0.0.1:5000", id)));
+        assert!(line.contains("cmd=get"));
+    }
+
+    #[test]
+    fn test_name_and_lib_info() {
+        let registry = ClientRegistry::new();
+        let id = registry.register(addr(5000), addr(6379), 7);
+        registry.set_name(id, "worker-1");
+        registry.set_lib_info(id, Some("redis-py"), None);
+        registry.set_lib_info(id, None, Some("5.0.1"));
+
+        let line = registry.get(id).unwrap().to_line();
+        assert!(line.contains(" name=worker-1 "));
+        assert!(line.ends_with("lib-name=redis-py lib-ver=5.0.1"));
+
+        registry.set_name(id, "");
+        assert_eq!(registry.get(id).unwrap().name, None);
+    }
+
+    #[test]
+    fn test_kill_removes_client() {
+        let registry = ClientRegistry::new();
+        let id = registry.register(addr(5000), addr(6379), 7);
+        let signal = registry.get(id).unwrap().kill;
+        assert!(registry.kill(id));
+        assert!(!registry.contains(id));
+        assert!(!registry.kill(id));
+        // The permit is stored, so a task that starts waiting later still wakes.
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(signal.notified());
+    }
+
+    #[test]
+    fn test_unblock_only_wakes_blocked_clients() {
+        let registry = ClientRegistry::new();
+        let id = registry.register(addr(5000), addr(6379), 7);
+        assert!(!registry.unblock(id, UnblockReason::Timeout));
+
+        registry.set_blocked(id, true);
+        assert!(registry.get(id).unwrap().to_line().contains("flags=b"));
+        assert!(registry.unblock(id, UnblockReason::Error));
+        assert_eq!(registry.take_unblock_reason(id), Some(UnblockReason::Error));
+        assert_eq!(registry.take_unblock_reason(id), None);
+    }
+
+    #[test]
+    fn test_pause_modes() {
+        let registry = ClientRegistry::new();
+        registry.pause(PauseMode::Write, Duration::from_secs(60));
+        assert!(registry.paused_until(true).is_some());
+        assert!(registry.paused_until(false).is_none());
+
+        // A shorter ALL pause upgrades the mode but keeps the later deadline.
+        let deadline = registry.paused_until(true).unwrap();
+        registry.pause(PauseMode::All, Duration::from_millis(1));
+        assert_eq!(registry.paused_until(false), Some(deadline));
+
+        registry.unpause();
+        assert!(registry.paused_until(true).is_none());
+    }
+
+    #[test]
+    fn test_evict_largest_clients() {
+        let registry = ClientRegistry::new();
+        let small = registry.register(addr(5000), addr(6379), 7);
+        let large = registry.register(addr(5001), addr(6379), 8);
+        let exempt = registry.register(addr(5002), addr(6379), 9);
+        registry.set_memory(small, 1000, 0);
+        registry.set_memory(large, 1000, 5000);
+        registry.set_memory(exempt, 10_000, 0);
+        registry.set_no_evict(exempt, true);
+        registry.set_memory(large, 1000, 4000);
+        assert_eq!(registry.memory(), 16_000);
+        let line = registry.get(large).unwrap().to_line();
+        assert!(line.contains(" qbuf=1000 omem=4000 tot-mem=5000 "));
+        assert!(registry.get(exempt).unwrap().to_line().contains("flags=e"));
+
+        assert!(registry.evict(20_000).is_empty());
+        assert_eq!(registry.evict(11_000), vec![large]);
+        assert!(!registry.contains(large));
+        assert_eq!(registry.memory(), 11_000);
+        // Only the exempt client is left over the limit
+        assert_eq!(registry.evict(5_000), vec![small]);
+        assert_eq!(registry.evict(5_000), Vec::<u64>::new());
+        registry.unregister(exempt);
+        assert_eq!(registry.memory(), 0);
+        // Updates from a client already gone don't count
+        registry.set_memory(small, 1000, 0);
+        assert_eq!(registry.memory(), 0);
+    }
+}
@@ -0,0 +1,74 @@
+/// Number of hash slots keys are spread over, as in Redis Cluster.
+pub const SLOTS: u16 = 16384;
+
+/// CRC-16/XMODEM lookup table (polynomial 0x1021), as used by Redis Cluster.
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC16_TABLE[(((crc >> 8) as u8) ^ byte) as usize]
+    })
+}
+
+/// The part of `key` that decides its slot: what's between the first `{`
+/// and the next `}` when that's not empty, or the whole key. Keys sharing
+/// a hash tag, like `{user:1}:cart` and `{user:1}:profile`, share a slot.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|&b| b == b'{')
+        && let Some(len) = key[open + 1..].iter().position(|&b| b == b'}')
+        && len > 0
+    {
+        return &key[open + 1..open + 1 + len];
+    }
+    key
+}
+
+/// The hash slot `key` belongs to.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) & (SLOTS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16() {
+        // The check value of CRC-16/XMODEM
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"somekey"), 11058);
+        assert_eq!(
+            key_hash_slot(b"{user:1}:cart"),
+            key_hash_slot(b"{user:1}:profile")
+        );
+        assert_eq!(key_hash_slot(b"{user:1}:cart"), key_hash_slot(b"user:1"));
+        assert_eq!(hash_tag(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_tag(b"foo{{bar}}zap"), b"{bar");
+        assert_eq!(hash_tag(b"foo{bar"), b"foo{bar");
+    }
+}
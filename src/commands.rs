@@ -1,13 +1,59 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::acl::{self, AclLogReason};
+use crate::aof::{self, AofGuard};
+use crate::binary;
+use crate::blocking::{self, Blocked, Elements, Popped, Source};
+use crate::clients::{ClientKind, ClientRegistry, PauseMode, UnblockReason, parse_client_type};
+use crate::cluster;
+use crate::connection::{ConnectionContext, ReplyMode};
+use crate::digest;
+use crate::docs::{self, COMMAND_DOCS};
+use crate::evict;
+use crate::geo::{self, GeoSearch, Origin, Shape, Sort};
+use crate::info;
+use crate::logging;
+use crate::memory;
+use crate::notify::{self, KeyspaceEvent};
 use crate::parser::Resp;
-use crate::storage::Storage;
-
-#[derive(Debug)]
+use crate::pattern::Pattern;
+use crate::rdb;
+use crate::replication::{self, FailoverState, FeedGuard, LinkState, SyncRequest};
+use crate::reply::Protocol;
+use crate::server::Server;
+use crate::stats::Stats;
+use crate::storage::{
+    self, Aggregate, Db, End, ExpireOptions, ScoreEnd, SetOp, SetOptions, Storage, ZAddOptions,
+};
+use crate::stream::{
+    self, ClaimOptions, Fields, Group, GroupChanges, GroupEntries, GroupRead, NewId, Stream,
+    StreamId, Trim, TrimTo,
+};
+use crate::trace;
+use crate::zset::{self, LexBound, LexRange, RangeBy, RangeQuery, ScoreBound, ScoreRange};
+
+#[derive(Debug, Clone)]
 pub struct Command {
     pub name: String,
     pub args: Vec<String>,
 }
 
 impl Command {
+    /// Builds a command from request parts borrowed out of the read buffer,
+    /// allocating each argument exactly once.
+    pub fn from_parts(parts: &[&[u8]]) -> Result<Command, String> {
+        let Some((name, args)) = parts.split_first() else {
+            return Err("ERR empty command".to_string());
+        };
+
+        let name = String::from_utf8_lossy(name).to_uppercase();
+        let args = args.iter().map(|arg| binary::decode(arg)).collect();
+        Ok(Command { name, args })
+    }
+
     pub fn from_resp(resp: &Resp) -> Result<Command, String> {
         match resp {
             Resp::Array(Some(items)) => {
@@ -41,1048 +87,8595 @@ impl Command {
     }
 }
 
-pub fn execute(cmd: &Command, storage: &Storage) -> Resp {
-    match cmd.name.as_str() {
-        "PING" => cmd_ping(cmd),
-        "ECHO" => cmd_echo(cmd),
-        "QUIT" => cmd_quit(),
-        "COMMAND" => cmd_command(cmd),
-        "CONFIG" => cmd_config(cmd),
-        "CLIENT" => cmd_client(cmd),
-        "INFO" => cmd_info(cmd, storage),
-        "DBSIZE" => cmd_dbsize(storage),
-
-        "SET" => cmd_set(cmd, storage),
-        "GET" => cmd_get(cmd, storage),
-        "SETNX" => cmd_setnx(cmd, storage),
-        "SETEX" => cmd_setex(cmd, storage),
-        "PSETEX" => cmd_psetex(cmd, storage),
-        "GETSET" => cmd_getset(cmd, storage),
-        "MSET" => cmd_mset(cmd, storage),
-        "MGET" => cmd_mget(cmd, storage),
-        "INCR" => cmd_incr(cmd, storage),
-        "INCRBY" => cmd_incrby(cmd, storage),
-        "DECR" => cmd_decr(cmd, storage),
-        "DECRBY" => cmd_decrby(cmd, storage),
-        "APPEND" => cmd_append(cmd, storage),
-        "STRLEN" => cmd_strlen(cmd, storage),
-
-        "DEL" => cmd_del(cmd, storage),
-        "EXISTS" => cmd_exists(cmd, storage),
-        "EXPIRE" => cmd_expire(cmd, storage),
-        "PEXPIRE" => cmd_pexpire(cmd, storage),
-        "TTL" => cmd_ttl(cmd, storage),
-        "PTTL" => cmd_pttl(cmd, storage),
-        "PERSIST" => cmd_persist(cmd, storage),
-        "KEYS" => cmd_keys(cmd, storage),
-        "TYPE" => cmd_type(cmd, storage),
-        "RENAME" => cmd_rename(cmd, storage),
-        "RENAMENX" => cmd_renamenx(cmd, storage),
-        "FLUSHDB" => cmd_flushdb(storage),
-        "FLUSHALL" => cmd_flushdb(storage),
-
-        "LPUSH" => cmd_lpush(cmd, storage),
-        "RPUSH" => cmd_rpush(cmd, storage),
-        "LPOP" => cmd_lpop(cmd, storage),
-        "RPOP" => cmd_rpop(cmd, storage),
-        "LLEN" => cmd_llen(cmd, storage),
-        "LRANGE" => cmd_lrange(cmd, storage),
-        "LINDEX" => cmd_lindex(cmd, storage),
-        "LSET" => cmd_lset(cmd, storage),
-
-        "SADD" => cmd_sadd(cmd, storage),
-        "SREM" => cmd_srem(cmd, storage),
-        "SMEMBERS" => cmd_smembers(cmd, storage),
-        "SISMEMBER" => cmd_sismember(cmd, storage),
-        "SCARD" => cmd_scard(cmd, storage),
-
-        "HSET" => cmd_hset(cmd, storage),
-        "HGET" => cmd_hget(cmd, storage),
-        "HMSET" => cmd_hmset(cmd, storage),
-        "HMGET" => cmd_hmget(cmd, storage),
-        "HGETALL" => cmd_hgetall(cmd, storage),
-        "HDEL" => cmd_hdel(cmd, storage),
-        "HEXISTS" => cmd_hexists(cmd, storage),
-        "HLEN" => cmd_hlen(cmd, storage),
-        "HKEYS" => cmd_hkeys(cmd, storage),
-        "HVALS" => cmd_hvals(cmd, storage),
-        "HINCRBY" => cmd_hincrby(cmd, storage),
-
-        _ => Resp::Error(format!("ERR unknown command '{}'", cmd.name)),
-    }
+type Handler = fn(&Command, &Server, &mut ConnectionContext) -> Resp;
+
+/// Static description of a command: how many arguments it takes (counting
+/// the name, negative meaning "at least"), its flags, where its keys are,
+/// which ACL categories it belongs to besides those implied by its flags and
+/// the type its keys must hold.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    pub flags: &'static [&'static str],
+    pub first_key: i32,
+    pub last_key: i32,
+    pub step: i32,
+    pub categories: &'static [&'static str],
+    /// The type, as TYPE names it, every key of the command must hold if it
+    /// exists. Commands that overwrite keys of any type have none.
+    pub key_type: Option<&'static str>,
+    /// For `movablekeys` commands, the position of the argument counting
+    /// their keys, which follow it.
+    pub numkeys: Option<usize>,
+    /// For `movablekeys` commands whose keys follow a keyword, as XREAD's
+    /// follow STREAMS, that keyword. The keys are the first half of the
+    /// arguments after it.
+    pub keys_after: Option<&'static str>,
+    handler: Handler,
 }
 
-fn cmd_ping(cmd: &Command) -> Resp {
-    if cmd.args.is_empty() {
-        Resp::Simple("PONG".to_string())
-    } else {
-        Resp::Bulk(Some(cmd.args[0].clone()))
+impl CommandSpec {
+    /// The command only works on keys holding `type_name`: call() replies
+    /// WRONGTYPE to it for other keys before the handler runs.
+    const fn key_type(mut self, type_name: &'static str) -> Self {
+        self.key_type = Some(type_name);
+        self
     }
-}
 
-fn cmd_echo(cmd: &Command) -> Resp {
-    if cmd.args.is_empty() {
-        Resp::Error("ERR wrong number of arguments for 'echo' command".to_string())
-    } else {
-        Resp::Bulk(Some(cmd.args[0].clone()))
+    /// The command's keys are counted by the argument at `position`, 1
+    /// being the first after the name, and follow it.
+    const fn numkeys(mut self, position: usize) -> Self {
+        self.numkeys = Some(position);
+        self
     }
-}
 
-fn cmd_quit() -> Resp {
-    Resp::Simple("OK".to_string())
-}
+    /// The command's keys follow the first `keyword` among its arguments,
+    /// each matched by an argument in the second half of what follows.
+    const fn keys_after(mut self, keyword: &'static str) -> Self {
+        self.keys_after = Some(keyword);
+        self
+    }
 
-fn cmd_command(cmd: &Command) -> Resp {
-    if cmd.args.is_empty() || cmd.args[0].to_uppercase() == "DOCS" {
-        Resp::Array(Some(vec![]))
-    } else if cmd.args[0].to_uppercase() == "COUNT" {
-        Resp::Integer(40)
-    } else {
-        Resp::Array(Some(vec![]))
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
     }
-}
 
-fn cmd_config(cmd: &Command) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'config' command".to_string());
+    /// Whether a call with `argc` arguments (not counting the name) matches
+    /// the command's arity.
+    pub fn accepts(&self, argc: usize) -> bool {
+        let total = argc as i32 + 1;
+        if self.arity >= 0 {
+            total == self.arity
+        } else {
+            total >= -self.arity
+        }
     }
 
-    match cmd.args[0].to_uppercase().as_str() {
-        "GET" => {
-            if cmd.args.len() < 2 {
-                return Resp::Error(
-                    "ERR wrong number of arguments for 'config|get' command".to_string(),
-                );
+    /// ACL categories of the command, including `@read`, `@write`, `@admin`,
+    /// `@dangerous`, `@fast` and `@slow` derived from its flags.
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        if self.has_flag("write") {
+            categories.push("write");
+        }
+        if self.has_flag("readonly") {
+            categories.push("read");
+        }
+        if self.has_flag("admin") {
+            categories.extend(["admin", "dangerous"]);
+        }
+        for category in self.categories {
+            if !categories.contains(category) {
+                categories.push(category);
             }
+        }
+        categories.push(if self.has_flag("fast") {
+            "fast"
+        } else {
+            "slow"
+        });
+        categories
+    }
 
-            let pattern = &cmd.args[1];
-            if pattern == "save" || pattern == "*" {
-                Resp::Array(Some(vec![
-                    Resp::Bulk(Some("save".to_string())),
-                    Resp::Bulk(Some("".to_string())),
-                ]))
+    /// The key arguments in `args` (which excludes the command name).
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a str> {
+        if let Some(keyword) = self.keys_after {
+            let Some(position) = args
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case(keyword))
+            else {
+                return Vec::new();
+            };
+            let rest = &args[position + 1..];
+            return rest[..rest.len() / 2].iter().map(String::as_str).collect();
+        }
+        if let Some(position) = self.numkeys {
+            // Only as many as there are, the handler rejects the rest
+            let count = args
+                .get(position - 1)
+                .and_then(|numkeys| numkeys.parse::<usize>().ok())
+                .unwrap_or(0);
+            // After any before the count, as the STORE commands' destination
+            let fixed = if self.first_key > 0 {
+                &args[self.first_key as usize - 1..(self.last_key as usize).min(args.len())]
             } else {
-                Resp::Array(Some(vec![]))
-            }
+                &[]
+            };
+            return fixed
+                .iter()
+                .chain(args[position.min(args.len())..].iter().take(count))
+                .map(String::as_str)
+                .collect();
         }
-        "SET" => Resp::Simple("OK".to_string()),
-        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", cmd.args[0])),
+        if self.first_key <= 0 || self.step <= 0 {
+            return Vec::new();
+        }
+        let argv_len = args.len() as i32 + 1;
+        let last = if self.last_key < 0 {
+            argv_len + self.last_key
+        } else {
+            self.last_key.min(argv_len - 1)
+        };
+        (self.first_key..=last)
+            .step_by(self.step as usize)
+            .map(|i| args[i as usize - 1].as_str())
+            .collect()
     }
 }
 
-fn cmd_client(cmd: &Command) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'client' command".to_string());
-    }
+/// Runs a handler that only needs the connection's selected database.
+macro_rules! with_db {
+    ($handler:ident) => {
+        |cmd, server, ctx| $handler(cmd, server.storage.db(ctx.db))
+    };
+}
 
-    match cmd.args[0].to_uppercase().as_str() {
-        "SETINFO" => Resp::Simple("OK".to_string()),
-        "SETNAME" => Resp::Simple("OK".to_string()),
-        "GETNAME" => Resp::Bulk(None),
-        "LIST" => Resp::Bulk(Some("id=1 addr=127.0.0.1:0 fd=1 name= db=0\n".to_string())),
-        "ID" => Resp::Integer(1),
-        _ => Resp::Simple("OK".to_string()),
+const fn spec(
+    name: &'static str,
+    arity: i32,
+    flags: &'static [&'static str],
+    (first_key, last_key, step): (i32, i32, i32),
+    categories: &'static [&'static str],
+    handler: Handler,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+        categories,
+        key_type: None,
+        numkeys: None,
+        keys_after: None,
+        handler,
     }
 }
 
-fn cmd_info(cmd: &Command, storage: &Storage) -> Resp {
-    let section = cmd.args.get(0).map(|s| s.to_uppercase());
+const NO_KEYS: (i32, i32, i32) = (0, 0, 0);
+const ONE_KEY: (i32, i32, i32) = (1, 1, 1);
+const ALL_KEYS: (i32, i32, i32) = (1, -1, 1);
+
+/// Every command the server implements.
+#[rustfmt::skip]
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    // Connection and server
+    spec("auth", -2, &["noscript", "loading", "stale", "fast", "no_auth"], NO_KEYS, &["connection"], cmd_auth),
+    spec("hello", -1, &["noscript", "loading", "stale", "fast", "no_auth"], NO_KEYS, &["connection"], cmd_hello),
+    spec("ping", -1, &["fast"], NO_KEYS, &["connection"], |cmd, _, _| cmd_ping(cmd)),
+    spec("echo", 2, &["fast"], NO_KEYS, &["connection"], |cmd, _, _| cmd_echo(cmd)),
+    spec("quit", -1, &["noscript", "loading", "stale", "fast", "no_auth"], NO_KEYS, &["connection"], |_, _, ctx| cmd_quit(ctx)),
+    spec("command", -1, &["loading", "stale"], NO_KEYS, &["connection"], |cmd, _, _| cmd_command(cmd)),
+    spec("config", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, &[], |cmd, server, _| cmd_config(cmd, server)),
+    spec("acl", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, &[], |cmd, server, ctx| cmd_acl(cmd, server, ctx)),
+    spec("client", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, &["connection"], |cmd, server, ctx| cmd_client(cmd, &server.clients, ctx)),
+    spec("cluster", -2, &["loading", "stale"], NO_KEYS, &[], |cmd, _, _| cmd_cluster(cmd)),
+    spec("memory", -2, &["readonly"], (2, 2, 1), &[], |cmd, server, ctx| cmd_memory(cmd, server.storage.db(ctx.db))),
+    spec("latency", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, &[], |cmd, server, _| cmd_latency(cmd, server)),
+    spec("debug", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, &[], |cmd, server, ctx| cmd_debug(cmd, server, ctx)),
+    spec("monitor", 1, &["admin", "noscript", "loading", "stale"], NO_KEYS, &["dangerous"], |_, server, ctx| cmd_monitor(&server.clients, ctx)),
+    spec("info", -1, &["loading", "stale"], NO_KEYS, &["dangerous"], |cmd, server, _| cmd_info(cmd, server)),
+    spec("time", 1, &["loading", "stale", "fast"], NO_KEYS, &[], |_, _, _| cmd_time()),
+    spec("lastsave", 1, &["loading", "stale", "fast"], NO_KEYS, &["admin", "dangerous"], |_, server, _| cmd_lastsave(server)),
+    spec("save", 1, &["admin", "noscript", "no_multi"], NO_KEYS, &["dangerous"], |_, server, _| cmd_save(server)),
+    spec("bgsave", -1, &["admin", "noscript"], NO_KEYS, &["dangerous"], |cmd, server, _| cmd_bgsave(cmd, server)),
+    spec("replicaof", 3, &["admin", "noscript", "stale"], NO_KEYS, &["dangerous"], cmd_replicaof),
+    spec("slaveof", 3, &["admin", "noscript", "stale"], NO_KEYS, &["dangerous"], cmd_replicaof),
+    spec("sync", 1, &["admin", "noscript", "no_multi"], NO_KEYS, &["dangerous"], |_, server, ctx| cmd_sync(server, ctx, SyncRequest::Sync)),
+    spec("psync", -3, &["admin", "noscript", "no_multi"], NO_KEYS, &["dangerous"], cmd_psync),
+    spec("replconf", -1, &["admin", "noscript", "loading", "stale"], NO_KEYS, &["dangerous"], |cmd, _, ctx| cmd_replconf(cmd, ctx)),
+    spec("failover", -1, &["admin", "noscript", "stale"], NO_KEYS, &["dangerous"], |cmd, server, _| cmd_failover(cmd, server)),
+    spec("role", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, &["admin", "dangerous"], |_, server, _| cmd_role(server)),
+    spec("dbsize", 1, &["readonly", "fast"], NO_KEYS, &["keyspace"], |_, server, ctx| cmd_dbsize(server.storage.db(ctx.db))),
+    // Strings
+    spec("set", -3, &["write", "denyoom"], ONE_KEY, &["string"], with_db!(cmd_set)),
+    spec("get", 2, &["readonly", "fast"], ONE_KEY, &["string"], with_db!(cmd_get)).key_type("string"),
+    spec("setnx", 3, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_setnx)),
+    spec("setex", 4, &["write", "denyoom"], ONE_KEY, &["string"], with_db!(cmd_setex)),
+    spec("psetex", 4, &["write", "denyoom"], ONE_KEY, &["string"], with_db!(cmd_psetex)),
+    spec("getset", 3, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_getset)).key_type("string"),
+    spec("mset", -3, &["write", "denyoom"], (1, -1, 2), &["string"], with_db!(cmd_mset)),
+    spec("mget", -2, &["readonly", "fast"], ALL_KEYS, &["string"], with_db!(cmd_mget)),
+    spec("incr", 2, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_incr)).key_type("string"),
+    spec("incrby", 3, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_incrby)).key_type("string"),
+    spec("decr", 2, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_decr)).key_type("string"),
+    spec("decrby", 3, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_decrby)).key_type("string"),
+    spec("append", 3, &["write", "denyoom", "fast"], ONE_KEY, &["string"], with_db!(cmd_append)).key_type("string"),
+    spec("strlen", 2, &["readonly", "fast"], ONE_KEY, &["string"], with_db!(cmd_strlen)).key_type("string"),
+    // Bitmaps
+    spec("bitcount", -2, &["readonly"], ONE_KEY, &["bitmap"], with_db!(cmd_bitcount)).key_type("string"),
+    // Keyspace
+    spec("del", -2, &["write"], ALL_KEYS, &["keyspace"], with_db!(cmd_del)),
+    spec("exists", -2, &["readonly", "fast"], ALL_KEYS, &["keyspace"], with_db!(cmd_exists)),
+    spec("touch", -2, &["readonly", "fast"], ALL_KEYS, &["keyspace"], with_db!(cmd_touch)),
+    spec("expire", -3, &["write", "fast"], ONE_KEY, &["keyspace"], |cmd, server, ctx| cmd_expire(cmd, server, ctx.db)),
+    spec("pexpire", -3, &["write", "fast"], ONE_KEY, &["keyspace"], |cmd, server, ctx| cmd_pexpire(cmd, server, ctx.db)),
+    spec("expireat", -3, &["write", "fast"], ONE_KEY, &["keyspace"], |cmd, server, ctx| cmd_expireat(cmd, server, ctx.db)),
+    spec("pexpireat", -3, &["write", "fast"], ONE_KEY, &["keyspace"], |cmd, server, ctx| cmd_pexpireat(cmd, server, ctx.db)),
+    spec("ttl", 2, &["readonly", "fast"], ONE_KEY, &["keyspace"], with_db!(cmd_ttl)),
+    spec("pttl", 2, &["readonly", "fast"], ONE_KEY, &["keyspace"], with_db!(cmd_pttl)),
+    spec("persist", 2, &["write", "fast"], ONE_KEY, &["keyspace"], with_db!(cmd_persist)),
+    spec("keys", 2, &["readonly"], NO_KEYS, &["keyspace", "dangerous"], with_db!(cmd_keys)),
+    spec("scan", -2, &["readonly"], NO_KEYS, &["keyspace"], with_db!(cmd_scan)),
+    spec("dump", 2, &["readonly"], ONE_KEY, &["keyspace"], |cmd, server, ctx| cmd_dump(cmd, server, ctx.db)),
+    spec("restore", -4, &["write", "denyoom"], ONE_KEY, &["keyspace", "dangerous"], with_db!(cmd_restore)),
+    spec("type", 2, &["readonly", "fast"], ONE_KEY, &["keyspace"], with_db!(cmd_type)),
+    spec("object", -2, &["readonly"], (2, 2, 1), &["keyspace"], with_db!(cmd_object)),
+    spec("rename", 3, &["write"], (1, 2, 1), &["keyspace"], with_db!(cmd_rename)),
+    spec("copy", -3, &["write", "denyoom"], (1, 2, 1), &["keyspace"], cmd_copy),
+    spec("move", 3, &["write", "fast"], ONE_KEY, &["keyspace"], cmd_move),
+    spec("renamenx", 3, &["write", "fast"], (1, 2, 1), &["keyspace"], with_db!(cmd_renamenx)),
+    spec("flushdb", -1, &["write"], NO_KEYS, &["keyspace", "dangerous"], |_, server, ctx| cmd_flushdb(&server.storage, ctx.db)),
+    spec("flushall", -1, &["write"], NO_KEYS, &["keyspace", "dangerous"], |_, server, _| cmd_flushall(&server.storage)),
+    spec("select", 2, &["loading", "stale", "fast"], NO_KEYS, &["connection"], cmd_select),
+    spec("swapdb", 3, &["write", "fast"], NO_KEYS, &["keyspace", "dangerous"], |cmd, server, _| cmd_swapdb(cmd, &server.storage)),
+    // Transactions
+    spec("multi", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, &["transaction"], cmd_multi),
+    spec("exec", 1, &["noscript", "loading", "stale"], NO_KEYS, &["transaction"], cmd_exec),
+    spec("discard", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, &["transaction"], cmd_discard),
+    spec("watch", -2, &["noscript", "loading", "stale", "fast"], ALL_KEYS, &["transaction"], cmd_watch),
+    spec("unwatch", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, &["transaction"], cmd_unwatch),
+    // Lists
+    spec("lpush", -3, &["write", "denyoom", "fast"], ONE_KEY, &["list"], with_db!(cmd_lpush)).key_type("list"),
+    spec("rpush", -3, &["write", "denyoom", "fast"], ONE_KEY, &["list"], with_db!(cmd_rpush)).key_type("list"),
+    spec("lpop", -2, &["write", "fast"], ONE_KEY, &["list"], with_db!(cmd_lpop)).key_type("list"),
+    spec("rpop", -2, &["write", "fast"], ONE_KEY, &["list"], with_db!(cmd_rpop)).key_type("list"),
+    spec("blpop", -3, &["write", "blocking"], (1, -2, 1), &["list", "blocking"], |cmd, server, ctx| cmd_blocking_pop(cmd, server, ctx, Source::List(End::Left))).key_type("list"),
+    spec("brpop", -3, &["write", "blocking"], (1, -2, 1), &["list", "blocking"], |cmd, server, ctx| cmd_blocking_pop(cmd, server, ctx, Source::List(End::Right))).key_type("list"),
+    spec("lmpop", -4, &["write", "movablekeys"], NO_KEYS, &["list"], |cmd, server, ctx| cmd_mpop(cmd, server, ctx, list_source)).key_type("list").numkeys(1),
+    spec("blmpop", -5, &["write", "blocking", "movablekeys"], NO_KEYS, &["list", "blocking"], |cmd, server, ctx| cmd_blocking_mpop(cmd, server, ctx, list_source)).key_type("list").numkeys(2),
+    spec("llen", 2, &["readonly", "fast"], ONE_KEY, &["list"], with_db!(cmd_llen)).key_type("list"),
+    spec("lrange", 4, &["readonly"], ONE_KEY, &["list"], with_db!(cmd_lrange)).key_type("list"),
+    spec("lindex", 3, &["readonly"], ONE_KEY, &["list"], with_db!(cmd_lindex)).key_type("list"),
+    spec("lset", 4, &["write", "denyoom"], ONE_KEY, &["list"], with_db!(cmd_lset)).key_type("list"),
+    spec("ltrim", 4, &["write"], ONE_KEY, &["list"], with_db!(cmd_ltrim)).key_type("list"),
+    // Sets
+    spec("sadd", -3, &["write", "denyoom", "fast"], ONE_KEY, &["set"], with_db!(cmd_sadd)).key_type("set"),
+    spec("srem", -3, &["write", "fast"], ONE_KEY, &["set"], with_db!(cmd_srem)).key_type("set"),
+    spec("smembers", 2, &["readonly"], ONE_KEY, &["set"], with_db!(cmd_smembers)).key_type("set"),
+    spec("sismember", 3, &["readonly", "fast"], ONE_KEY, &["set"], with_db!(cmd_sismember)).key_type("set"),
+    spec("scard", 2, &["readonly", "fast"], ONE_KEY, &["set"], with_db!(cmd_scard)).key_type("set"),
+    spec("sinter", -2, &["readonly"], ALL_KEYS, &["set"], |cmd, server, ctx| cmd_combine_sets(cmd, server, ctx, SetOp::Inter)).key_type("set"),
+    spec("sunion", -2, &["readonly"], ALL_KEYS, &["set"], |cmd, server, ctx| cmd_combine_sets(cmd, server, ctx, SetOp::Union)).key_type("set"),
+    spec("sdiff", -2, &["readonly"], ALL_KEYS, &["set"], |cmd, server, ctx| cmd_combine_sets(cmd, server, ctx, SetOp::Diff)).key_type("set"),
+    // The destination is overwritten whatever its type, so no key_type
+    spec("sinterstore", -3, &["write", "denyoom"], ALL_KEYS, &["set"], |cmd, server, ctx| cmd_combine_sets_store(cmd, server, ctx, SetOp::Inter)),
+    spec("sunionstore", -3, &["write", "denyoom"], ALL_KEYS, &["set"], |cmd, server, ctx| cmd_combine_sets_store(cmd, server, ctx, SetOp::Union)),
+    spec("sdiffstore", -3, &["write", "denyoom"], ALL_KEYS, &["set"], |cmd, server, ctx| cmd_combine_sets_store(cmd, server, ctx, SetOp::Diff)),
+    spec("srandmember", -2, &["readonly"], ONE_KEY, &["set"], with_db!(cmd_srandmember)).key_type("set"),
+    // Hashes
+    spec("hset", -4, &["write", "denyoom", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hset)).key_type("hash"),
+    spec("hget", 3, &["readonly", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hget)).key_type("hash"),
+    spec("hmset", -4, &["write", "denyoom", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hmset)).key_type("hash"),
+    spec("hmget", -3, &["readonly", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hmget)).key_type("hash"),
+    spec("hgetall", 2, &["readonly"], ONE_KEY, &["hash"], with_db!(cmd_hgetall)).key_type("hash"),
+    spec("hdel", -3, &["write", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hdel)).key_type("hash"),
+    spec("hexists", 3, &["readonly", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hexists)).key_type("hash"),
+    spec("hlen", 2, &["readonly", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hlen)).key_type("hash"),
+    spec("hkeys", 2, &["readonly"], ONE_KEY, &["hash"], with_db!(cmd_hkeys)).key_type("hash"),
+    spec("hvals", 2, &["readonly"], ONE_KEY, &["hash"], with_db!(cmd_hvals)).key_type("hash"),
+    spec("hincrby", 4, &["write", "denyoom", "fast"], ONE_KEY, &["hash"], with_db!(cmd_hincrby)).key_type("hash"),
+    // Sorted sets
+    spec("zadd", -4, &["write", "denyoom", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zadd)).key_type("zset"),
+    spec("zincrby", 4, &["write", "denyoom", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zincrby)).key_type("zset"),
+    spec("zscore", 3, &["readonly", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zscore)).key_type("zset"),
+    spec("zmscore", -3, &["readonly", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zmscore)).key_type("zset"),
+    spec("zrem", -3, &["write", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zrem)).key_type("zset"),
+    spec("zcard", 2, &["readonly", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zcard)).key_type("zset"),
+    spec("zcount", 4, &["readonly", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zcount)).key_type("zset"),
+    spec("zlexcount", 4, &["readonly", "fast"], ONE_KEY, &["sortedset"], with_db!(cmd_zlexcount)).key_type("zset"),
+    spec("zpopmin", -2, &["write", "fast"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zpop(cmd, server.storage.db(ctx.db), ScoreEnd::Min)).key_type("zset"),
+    spec("zpopmax", -2, &["write", "fast"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zpop(cmd, server.storage.db(ctx.db), ScoreEnd::Max)).key_type("zset"),
+    spec("zmpop", -4, &["write", "movablekeys"], NO_KEYS, &["sortedset"], |cmd, server, ctx| cmd_mpop(cmd, server, ctx, zset_source)).key_type("zset").numkeys(1),
+    spec("bzpopmin", -3, &["write", "fast", "blocking"], (1, -2, 1), &["sortedset", "blocking"], |cmd, server, ctx| cmd_blocking_pop(cmd, server, ctx, Source::ZSet(ScoreEnd::Min))).key_type("zset"),
+    spec("bzpopmax", -3, &["write", "fast", "blocking"], (1, -2, 1), &["sortedset", "blocking"], |cmd, server, ctx| cmd_blocking_pop(cmd, server, ctx, Source::ZSet(ScoreEnd::Max))).key_type("zset"),
+    spec("bzmpop", -5, &["write", "blocking", "movablekeys"], NO_KEYS, &["sortedset", "blocking"], |cmd, server, ctx| cmd_blocking_mpop(cmd, server, ctx, zset_source)).key_type("zset").numkeys(2),
+    spec("zunion", -3, &["readonly", "movablekeys"], NO_KEYS, &["sortedset"], |cmd, server, ctx| cmd_combine_zsets(cmd, server.storage.db(ctx.db), SetOp::Union)).numkeys(1),
+    spec("zinter", -3, &["readonly", "movablekeys"], NO_KEYS, &["sortedset"], |cmd, server, ctx| cmd_combine_zsets(cmd, server.storage.db(ctx.db), SetOp::Inter)).numkeys(1),
+    spec("zdiff", -3, &["readonly", "movablekeys"], NO_KEYS, &["sortedset"], |cmd, server, ctx| cmd_combine_zsets(cmd, server.storage.db(ctx.db), SetOp::Diff)).numkeys(1),
+    spec("zunionstore", -4, &["write", "denyoom", "movablekeys"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_combine_zsets_store(cmd, server.storage.db(ctx.db), SetOp::Union)).numkeys(2),
+    spec("zinterstore", -4, &["write", "denyoom", "movablekeys"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_combine_zsets_store(cmd, server.storage.db(ctx.db), SetOp::Inter)).numkeys(2),
+    spec("zdiffstore", -4, &["write", "denyoom", "movablekeys"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_combine_zsets_store(cmd, server.storage.db(ctx.db), SetOp::Diff)).numkeys(2),
+    spec("zrank", -3, &["readonly", "fast"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrank(cmd, server.storage.db(ctx.db), false)).key_type("zset"),
+    spec("zrevrank", -3, &["readonly", "fast"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrank(cmd, server.storage.db(ctx.db), true)).key_type("zset"),
+    spec("zrange", -4, &["readonly"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrange(cmd, server.storage.db(ctx.db), RangeType::Auto, false)).key_type("zset"),
+    spec("zrangestore", -5, &["write", "denyoom"], (1, 2, 1), &["sortedset"], with_db!(cmd_zrangestore)),
+    spec("zrevrange", -4, &["readonly"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrange(cmd, server.storage.db(ctx.db), RangeType::Index, true)).key_type("zset"),
+    spec("zrangebyscore", -4, &["readonly"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrange(cmd, server.storage.db(ctx.db), RangeType::Score, false)).key_type("zset"),
+    spec("zrevrangebyscore", -4, &["readonly"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrange(cmd, server.storage.db(ctx.db), RangeType::Score, true)).key_type("zset"),
+    spec("zrangebylex", -4, &["readonly"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrange(cmd, server.storage.db(ctx.db), RangeType::Lex, false)).key_type("zset"),
+    spec("zrevrangebylex", -4, &["readonly"], ONE_KEY, &["sortedset"], |cmd, server, ctx| cmd_zrange(cmd, server.storage.db(ctx.db), RangeType::Lex, true)).key_type("zset"),
+    // Geo, on sorted sets scored by geohash
+    spec("geoadd", -5, &["write", "denyoom"], ONE_KEY, &["geo"], with_db!(cmd_geoadd)).key_type("zset"),
+    spec("geopos", -2, &["readonly"], ONE_KEY, &["geo"], with_db!(cmd_geopos)).key_type("zset"),
+    spec("geodist", -4, &["readonly"], ONE_KEY, &["geo"], with_db!(cmd_geodist)).key_type("zset"),
+    spec("geosearch", -7, &["readonly"], ONE_KEY, &["geo"], with_db!(cmd_geosearch)).key_type("zset"),
+    spec("geosearchstore", -8, &["write", "denyoom"], (1, 2, 1), &["geo"], with_db!(cmd_geosearchstore)),
+    // Streams
+    spec("xack", -4, &["write", "fast"], ONE_KEY, &["stream"], with_db!(cmd_xack)).key_type("stream"),
+    spec("xadd", -5, &["write", "denyoom", "fast"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xadd(cmd, server.storage.db(ctx.db), ctx)).key_type("stream"),
+    spec("xautoclaim", -6, &["write", "fast"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xautoclaim(cmd, server.storage.db(ctx.db), ctx)).key_type("stream"),
+    spec("xclaim", -6, &["write", "fast"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xclaim(cmd, server.storage.db(ctx.db), ctx)).key_type("stream"),
+    spec("xgroup", -2, &["write", "denyoom"], (2, 2, 1), &["stream"], with_db!(cmd_xgroup)).key_type("stream"),
+    spec("xlen", 2, &["readonly", "fast"], ONE_KEY, &["stream"], with_db!(cmd_xlen)).key_type("stream"),
+    spec("xpending", -3, &["readonly"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xpending(cmd, server.storage.db(ctx.db), ctx)).key_type("stream"),
+    spec("xrange", -4, &["readonly"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xrange(cmd, server.storage.db(ctx.db), false)).key_type("stream"),
+    spec("xread", -4, &["readonly", "blocking", "movablekeys"], NO_KEYS, &["stream", "blocking"], cmd_xread).key_type("stream").keys_after("STREAMS"),
+    spec("xreadgroup", -7, &["write", "blocking", "movablekeys"], NO_KEYS, &["stream", "blocking"], cmd_xreadgroup).key_type("stream").keys_after("STREAMS"),
+    spec("xrevrange", -4, &["readonly"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xrange(cmd, server.storage.db(ctx.db), true)).key_type("stream"),
+    spec("xtrim", -4, &["write"], ONE_KEY, &["stream"], |cmd, server, ctx| cmd_xtrim(cmd, server.storage.db(ctx.db), ctx)).key_type("stream"),
+    // Bloom filters
+    spec("bf.add", 3, &["write", "denyoom", "fast"], ONE_KEY, &["bloom"], |cmd, server, ctx| cmd_bf_add(cmd, server.storage.db(ctx.db), false)).key_type("MBbloom--"),
+    spec("bf.exists", 3, &["readonly", "fast"], ONE_KEY, &["bloom"], |cmd, server, ctx| cmd_bf_exists(cmd, server.storage.db(ctx.db), false)).key_type("MBbloom--"),
+    spec("bf.madd", -3, &["write", "denyoom"], ONE_KEY, &["bloom"], |cmd, server, ctx| cmd_bf_add(cmd, server.storage.db(ctx.db), true)).key_type("MBbloom--"),
+    spec("bf.mexists", -3, &["readonly", "fast"], ONE_KEY, &["bloom"], |cmd, server, ctx| cmd_bf_exists(cmd, server.storage.db(ctx.db), true)).key_type("MBbloom--"),
+    spec("bf.reserve", -4, &["write", "denyoom"], ONE_KEY, &["bloom"], with_db!(cmd_bf_reserve)),
+];
+
+static COMMANDS_BY_NAME: LazyLock<HashMap<String, &'static CommandSpec>> = LazyLock::new(|| {
+    COMMAND_TABLE
+        .iter()
+        .map(|spec| (spec.name.to_uppercase(), spec))
+        .collect()
+});
+
+/// Finds a command by name, in any case.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    match COMMANDS_BY_NAME.get(name) {
+        Some(spec) => Some(spec),
+        None => COMMANDS_BY_NAME.get(&name.to_uppercase()).copied(),
+    }
+}
 
-    let mut info = String::new();
+/// Command names as clients see them, after `rename-command` directives.
+#[derive(Debug, Default)]
+pub struct CommandRenames {
+    /// New name to original name.
+    aliases: HashMap<String, String>,
+    /// Original names that were renamed or disabled.
+    hidden: HashSet<String>,
+}
 
-    if section.is_none()
-        || section.as_deref() == Some("SERVER")
-        || section.as_deref() == Some("ALL")
-    {
-        info.push_str("# Server\r\n");
-        info.push_str("redis_version:7.0.0-reredis\r\n");
-        info.push_str("redis_mode:standalone\r\n");
-        info.push_str("os:Linux\r\n");
-        info.push_str("arch_bits:64\r\n");
-        info.push_str("tcp_port:6379\r\n");
-        info.push_str("\r\n");
-    }
-
-    if section.is_none()
-        || section.as_deref() == Some("KEYSPACE")
-        || section.as_deref() == Some("ALL")
-    {
-        info.push_str("# Keyspace\r\n");
-        let db_size = storage.dbsize();
-        if db_size > 0 {
-            info.push_str(&format!("db0:keys={},expires=0,avg_ttl=0\r\n", db_size));
+impl CommandRenames {
+    /// Builds the mapping from `(original, new)` pairs; an empty new name
+    /// disables the command.
+    pub fn new(renames: &[(String, String)]) -> Self {
+        let mut mapping = CommandRenames::default();
+        for (original, new) in renames {
+            let original = original.to_uppercase();
+            if !new.is_empty() {
+                mapping.aliases.insert(new.to_uppercase(), original.clone());
+            }
+            mapping.hidden.insert(original);
         }
+        mapping
     }
 
-    Resp::Bulk(Some(info))
+    /// The original name of the command a client asked for, or None if that
+    /// name is not available.
+    pub fn resolve<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        match self.aliases.get(name) {
+            Some(original) => Some(original),
+            None if self.hidden.contains(name) => None,
+            None => Some(name),
+        }
+    }
 }
 
-fn cmd_dbsize(storage: &Storage) -> Resp {
-    Resp::Integer(storage.dbsize() as i64)
-}
+pub fn execute(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let renamed;
+    let cmd = match server.renames.resolve(&cmd.name) {
+        None => return Resp::Error(format!("ERR unknown command '{}'", cmd.name)),
+        Some(name) if name != cmd.name => {
+            renamed = Command {
+                name: name.to_string(),
+                args: cmd.args.clone(),
+            };
+            &renamed
+        }
+        Some(_) => cmd,
+    };
 
-fn cmd_set(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'set' command".to_string());
+    let spec = match check_command(cmd, server, ctx) {
+        Ok(spec) => spec,
+        Err(e) => {
+            // A command rejected while queuing dooms the whole transaction
+            if ctx.multi.is_some() {
+                ctx.exec_aborted = true;
+            }
+            return e;
+        }
+    };
+
+    if let Some(queue) = &mut ctx.multi
+        && !matches!(spec.name, "multi" | "exec" | "discard" | "watch" | "quit")
+    {
+        queue.push(cmd.clone());
+        server.clients.set_multi(ctx.id, Some(queue.len()));
+        return Resp::Simple("QUEUED".to_string());
     }
 
-    let key = cmd.args[0].clone();
-    let value = cmd.args[1].clone();
+    // EXEC takes the storage lock exclusively itself
+    let _guard = (spec.name != "exec").then(|| server.storage.shared());
+    call(spec, cmd, server, ctx)
+}
 
-    let mut expiry_ms: Option<u64> = None;
-    let mut nx = false;
-    let mut xx = false;
-    let mut get = false;
+/// Runs a command read back from the append-only file, by its original
+/// name and without ACL checks. Returns None for unknown commands.
+pub fn replay(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Option<Resp> {
+    let spec = lookup(&cmd.name)?;
+    if !spec.accepts(cmd.args.len()) {
+        return Some(Resp::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            spec.name
+        )));
+    }
+    Some(call(spec, cmd, server, ctx))
+}
 
-    let mut i = 2;
-    while i < cmd.args.len() {
-        match cmd.args[i].to_uppercase().as_str() {
-            "EX" => {
-                if i + 1 >= cmd.args.len() {
-                    return Resp::Error("ERR syntax error".to_string());
-                }
-                match cmd.args[i + 1].parse::<u64>() {
-                    Ok(secs) => expiry_ms = Some(secs * 1000),
-                    Err(_) => {
-                        return Resp::Error(
-                            "ERR value is not an integer or out of range".to_string(),
-                        );
-                    }
-                }
-                i += 2;
-            }
-            "PX" => {
-                if i + 1 >= cmd.args.len() {
-                    return Resp::Error("ERR syntax error".to_string());
-                }
-                match cmd.args[i + 1].parse::<u64>() {
-                    Ok(ms) => expiry_ms = Some(ms),
-                    Err(_) => {
-                        return Resp::Error(
-                            "ERR value is not an integer or out of range".to_string(),
-                        );
-                    }
-                }
-                i += 2;
-            }
-            "NX" => {
-                nx = true;
-                i += 1;
-            }
-            "XX" => {
-                xx = true;
-                i += 1;
+/// Runs a command that passed its checks, telling WATCH about the keys it
+/// modified and logging writes to the append-only file.
+fn call(spec: &CommandSpec, cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let db = ctx.db;
+    // Read up front: CONFIG SET takes the config lock before the AOF lock
+    let (threshold, tracking, maxmemory, lfu) = {
+        let config = server.config.read().unwrap();
+        (
+            config.latency_monitor_threshold,
+            config.latency_tracking,
+            config.maxmemory,
+            config
+                .maxmemory_policy
+                .is_lfu()
+                .then_some((config.lfu_log_factor, config.lfu_decay_time)),
+        )
+    };
+    let write = spec.has_flag("write");
+    let mut aof = if write { server.aof.lock() } else { None };
+    let mut replicas = if write {
+        server.replication.lock()
+    } else {
+        None
+    };
+    // Replicas wait for their primary to delete expired and evicted keys
+    // instead
+    if !server.replication.is_replica() {
+        remove_expired_keys(spec, cmd, server, db, &mut aof, &mut replicas);
+        if maxmemory > 0 && memory::used_memory() > maxmemory {
+            if !write && aof.is_none() && replicas.is_none() {
+                aof = server.aof.lock();
+                replicas = server.replication.lock();
             }
-            "GET" => {
-                get = true;
-                i += 1;
+            if !evict::perform_evictions(server, maxmemory, &mut aof, &mut replicas)
+                && spec.has_flag("denyoom")
+            {
+                return Resp::Error(
+                    "OOM command not allowed when used memory > 'maxmemory'.".to_string(),
+                );
             }
-            "KEEPTTL" => {
-                i += 1;
+        }
+    }
+    let storage = server.storage.db(db);
+    if let Some(error) = check_key_types(spec, cmd, storage) {
+        return error;
+    }
+    // Which keys exist, to tell the key hooks about those the command deletes
+    let existed: Vec<bool> = if write && server.storage.has_key_hooks() {
+        let keys = spec.keys(&cmd.args);
+        keys.iter()
+            .map(|key| storage.get_type(key).is_some())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    for key in spec.keys(&cmd.args) {
+        storage.record_access(key, lfu);
+    }
+    let span = tracing::info_span!(
+        "command",
+        client = ctx.id,
+        command = spec.name,
+        keys = spec.keys(&cmd.args).len(),
+        db,
+        status = tracing::field::Empty
+    );
+    let start = Instant::now();
+    let reply = span.in_scope(|| (spec.handler)(cmd, server, ctx));
+    let elapsed = start.elapsed();
+    span.record(
+        "status",
+        if matches!(reply, Resp::Error(_)) {
+            "error"
+        } else {
+            "ok"
+        },
+    );
+    drop(span);
+    // After running, so EXEC follows the commands it ran
+    if server.clients.has_monitors() {
+        feed_monitors(spec, cmd, server, ctx.id, db);
+    }
+    let propagate_as = ctx.propagate_as.take();
+    Stats::add(&server.stats.commands_processed, 1);
+    let event = if spec.has_flag("fast") {
+        "fast-command"
+    } else {
+        "command"
+    };
+    server.latency.record(event, elapsed, threshold);
+    if tracking {
+        server.command_latency.record(spec.name, elapsed);
+    }
+    // A blocking command that popped nothing changed nothing, unless it
+    // says what else it changed, as XREADGROUP creating its consumer
+    let blocked = spec.has_flag("blocking")
+        && reply == Resp::Array(None)
+        && propagate_as.as_ref().is_none_or(Vec::is_empty);
+    if write && !blocked && !matches!(reply, Resp::Error(_)) {
+        let keys = spec.keys(&cmd.args);
+        Stats::add(&server.stats.dirty, keys.len().max(1) as u64);
+        for &key in &keys {
+            server.storage.touch_key(db, key);
+            server.blocking.signal(db, key);
+        }
+        for (key, existed) in keys.into_iter().zip(existed) {
+            let event = if storage.get_type(key).is_some() {
+                KeyspaceEvent::Set
+            } else if existed {
+                KeyspaceEvent::Deleted
+            } else {
+                continue;
+            };
+            notify::notify(server, event, db, key);
+        }
+        if aof::expired_by_write(&server.storage, db, spec.name, &cmd.args, &reply) {
+            propagate(&mut aof, &mut replicas, db, &["del", &cmd.args[0]]);
+        } else if let Some(commands) = &propagate_as {
+            for parts in commands {
+                let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+                propagate(&mut aof, &mut replicas, db, &parts);
             }
-            _ => {
-                return Resp::Error("ERR syntax error".to_string());
+        } else if aof.is_some() || replicas.is_some() {
+            let at = aof::pinned_expiry(&server.storage, db, spec.name, &cmd.args);
+            let mut parts = vec![spec.name];
+            parts.extend(cmd.args.iter().map(String::as_str));
+            propagate(&mut aof, &mut replicas, db, &parts);
+            if let Some(at) = &at {
+                propagate(
+                    &mut aof,
+                    &mut replicas,
+                    db,
+                    &["pexpireat", &cmd.args[0], at],
+                );
             }
         }
     }
-
-    let exists = storage.get(&key).is_some();
-    if nx && exists {
-        return if get {
-            match storage.get(&key) {
-                Some(v) => Resp::Bulk(Some(v)),
-                None => Resp::Bulk(None),
-            }
-        } else {
-            Resp::Bulk(None)
-        };
+    if write && !ctx.in_exec {
+        blocking::serve_ready(server, &mut aof, &mut replicas);
     }
-    if xx && !exists {
-        return if get {
-            Resp::Bulk(None)
+    reply
+}
+
+/// Shows a command to the MONITOR connections as `<unix time> [<db>
+/// <client address> name=.. lib-name=.. lib-ver=..] "name" "arg" ...`, with
+/// the passwords of AUTH and HELLO redacted.
+fn feed_monitors(spec: &CommandSpec, cmd: &Command, server: &Server, client_id: u64, db: usize) {
+    // Replaying the append-only file isn't a client
+    let Some(client) = server.clients.get(client_id) else {
+        return;
+    };
+    let redacted = match spec.name {
+        "auth" => 0..cmd.args.len(),
+        "hello" => match cmd
+            .args
+            .iter()
+            .position(|arg| arg.eq_ignore_ascii_case("auth"))
+        {
+            Some(i) => i + 1..(i + 3).min(cmd.args.len()),
+            None => 0..0,
+        },
+        _ => 0..0,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!(
+        "{}.{:06} [{} {} name={} lib-name={} lib-ver={}] \"{}\"",
+        now.as_secs(),
+        now.subsec_micros(),
+        db,
+        client.addr,
+        client.name.as_deref().unwrap_or(""),
+        client.lib_name.as_deref().unwrap_or(""),
+        client.lib_ver.as_deref().unwrap_or(""),
+        spec.name
+    );
+    for (i, arg) in cmd.args.iter().enumerate() {
+        line.push(' ');
+        if redacted.contains(&i) {
+            line.push_str("\"(redacted)\"");
         } else {
-            Resp::Bulk(None)
-        };
+            line.push_str(&quote(arg));
+        }
     }
+    server.clients.feed_monitors(&line);
+}
 
-    let old_value = if get { storage.get(&key) } else { None };
-
-    match expiry_ms {
-        Some(ms) => storage.set_with_expiry(key, value, ms),
-        None => storage.set(key, value),
+/// A value in double quotes, escaping quotes, backslashes and bytes that
+/// aren't printable.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for &byte in binary::encode(value).iter() {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            0x20..=0x7e => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
     }
+    quoted.push('"');
+    quoted
+}
 
-    if get {
-        match old_value {
-            Some(v) => Resp::Bulk(Some(v)),
-            None => Resp::Bulk(None),
+/// WRONGTYPE if a key of a command that only works on one type holds
+/// another, so the command fails before touching anything.
+fn check_key_types(spec: &CommandSpec, cmd: &Command, db: &Db) -> Option<Resp> {
+    let expected = spec.key_type?;
+    spec.keys(&cmd.args)
+        .into_iter()
+        .any(|key| db.get_type(key).is_some_and(|actual| actual != expected))
+        .then(|| {
+            Resp::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            )
+        })
+}
+
+/// Removes the expired keys a command is about to access, propagating a
+/// DEL for each ahead of the command itself, so the append-only file and
+/// replicas drop them at the same point in the stream.
+fn remove_expired_keys<'a>(
+    spec: &CommandSpec,
+    cmd: &Command,
+    server: &'a Server,
+    db: usize,
+    aof: &mut Option<AofGuard<'a>>,
+    replicas: &mut Option<FeedGuard<'a>>,
+) {
+    let storage = server.storage.db(db);
+    for key in spec.keys(&cmd.args) {
+        // Paused writes include deletions, the key stays hidden until then
+        if !storage.is_expired(key) || server.clients.is_paused(true) {
+            continue;
+        }
+        // Write commands already hold these, reads lock them now
+        if !spec.has_flag("write") && aof.is_none() && replicas.is_none() {
+            *aof = server.aof.lock();
+            *replicas = server.replication.lock();
+        }
+        if storage.remove_expired(key) {
+            propagate(aof, replicas, db, &["del", key]);
+            server.storage.touch_key(db, key);
+            notify::notify(server, KeyspaceEvent::Expired, db, key);
         }
-    } else {
-        Resp::Simple("OK".to_string())
     }
 }
 
-fn cmd_get(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'get' command".to_string());
+/// Logs a command to the append-only file and sends it to the replicas.
+pub fn propagate(
+    aof: &mut Option<AofGuard>,
+    replicas: &mut Option<FeedGuard>,
+    db: usize,
+    args: &[&str],
+) {
+    if let Some(aof) = aof {
+        aof.feed(db, args);
     }
-
-    match storage.get(&cmd.args[0]) {
-        Some(value) => Resp::Bulk(Some(value)),
-        None => Resp::Bulk(None),
+    if let Some(replicas) = replicas {
+        replicas.feed(db, args);
     }
 }
 
-fn cmd_setnx(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'setnx' command".to_string());
-    }
+/// Finds the command and checks it may run: arity, authentication and ACL.
+fn check_command(
+    cmd: &Command,
+    server: &Server,
+    ctx: &ConnectionContext,
+) -> Result<&'static CommandSpec, Resp> {
+    let Some(spec) = lookup(&cmd.name) else {
+        return Err(Resp::Error(format!("ERR unknown command '{}'", cmd.name)));
+    };
 
-    let key = cmd.args[0].clone();
-    let value = cmd.args[1].clone();
+    if !spec.accepts(cmd.args.len()) {
+        return Err(Resp::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            spec.name
+        )));
+    }
 
-    if storage.setnx(key, value) {
-        Resp::Integer(1)
-    } else {
-        Resp::Integer(0)
+    if !ctx.authenticated && !spec.has_flag("no_auth") {
+        return Err(Resp::Error("NOAUTH Authentication required.".to_string()));
     }
-}
 
-fn cmd_setex(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 3 {
-        return Resp::Error("ERR wrong number of arguments for 'setex' command".to_string());
+    if !spec.has_flag("no_auth") {
+        check_permissions(cmd, server, ctx)?;
     }
 
-    let key = cmd.args[0].clone();
-    let seconds: u64 = match cmd.args[1].parse() {
-        Ok(s) => s,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
-    };
-    let value = cmd.args[2].clone();
+    // Writes from the primary are applied through `replay`, past these
+    if server.replication.is_replica()
+        && let Some(link) = server.replication.link_state()
+    {
+        let (read_only, serve_stale) = {
+            let config = server.config.read().unwrap();
+            (config.replica_read_only, config.replica_serve_stale_data)
+        };
+        if read_only && spec.has_flag("write") {
+            return Err(Resp::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            ));
+        }
+        if !serve_stale && link != LinkState::Connected && !spec.has_flag("stale") {
+            return Err(Resp::Error(
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(spec)
+}
 
-    storage.set_with_expiry(key, value, seconds * 1000);
-    Resp::Simple("OK".to_string())
+pub fn is_write_command(name: &str) -> bool {
+    lookup(name).is_some_and(|spec| spec.has_flag("write"))
 }
 
-fn cmd_psetex(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 3 {
-        return Resp::Error("ERR wrong number of arguments for 'psetex' command".to_string());
-    }
+/// Keys a command reads or writes, located through its command table entry.
+pub fn command_keys(cmd: &Command) -> Vec<&str> {
+    lookup(&cmd.name).map_or_else(Vec::new, |spec| spec.keys(&cmd.args))
+}
 
-    let key = cmd.args[0].clone();
-    let ms: u64 = match cmd.args[1].parse() {
-        Ok(m) => m,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+/// Checks the connection's user may run a command on the keys it names.
+fn check_permissions(cmd: &Command, server: &Server, ctx: &ConnectionContext) -> Result<(), Resp> {
+    let user = server.acl.get(&ctx.user);
+    let denied_key = user.as_ref().and_then(|user| {
+        command_keys(cmd)
+            .into_iter()
+            .find(|key| !user.can_access_key(key))
+    });
+    let (reason, object, error) = match user {
+        Some(user) if user.can_run(&cmd.name) => match denied_key {
+            None => return Ok(()),
+            Some(key) => (
+                AclLogReason::Key,
+                key.to_string(),
+                "NOPERM No permissions to access a key".to_string(),
+            ),
+        },
+        _ => (
+            AclLogReason::Command,
+            cmd.name.to_lowercase(),
+            format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                ctx.user,
+                cmd.name.to_lowercase()
+            ),
+        ),
     };
-    let value = cmd.args[2].clone();
+    log_acl_event(server, ctx, reason, &object, &ctx.user);
+    Err(Resp::Error(error))
+}
 
-    storage.set_with_expiry(key, value, ms);
-    Resp::Simple("OK".to_string())
+fn log_acl_event(
+    server: &Server,
+    ctx: &ConnectionContext,
+    reason: AclLogReason,
+    object: &str,
+    username: &str,
+) {
+    let client_info = server
+        .clients
+        .get(ctx.id)
+        .map(|client| client.to_line())
+        .unwrap_or_default();
+    let max_len = server.config.read().unwrap().acl_log_max_len;
+    server
+        .acl
+        .log_event(reason, object, username, &client_info, max_len);
 }
 
-fn cmd_getset(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'getset' command".to_string());
+/// Logs the connection in as `username` if the password matches.
+fn authenticate(
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    username: &str,
+    password: &str,
+) -> Result<(), Resp> {
+    if server.acl.authenticate(username, password) {
+        ctx.user = username.to_string();
+        ctx.authenticated = true;
+        server.clients.set_user(ctx.id, username);
+        Ok(())
+    } else {
+        log_acl_event(server, ctx, AclLogReason::Auth, "AUTH", username);
+        Err(Resp::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+        ))
     }
+}
 
-    let key = cmd.args[0].clone();
-    let value = cmd.args[1].clone();
+fn cmd_auth(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let (username, password) = match cmd.args.as_slice() {
+        [password] => ("default", password),
+        [username, password] => (username.as_str(), password),
+        _ => return Resp::Error("ERR wrong number of arguments for 'auth' command".to_string()),
+    };
 
-    match storage.getset(key, value) {
-        Some(old) => Resp::Bulk(Some(old)),
-        None => Resp::Bulk(None),
+    if cmd.args.len() == 1 && server.acl.default_nopass() {
+        return Resp::Error(
+            "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string(),
+        );
+    }
+
+    match authenticate(server, ctx, username, password) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => e,
     }
 }
 
-fn cmd_mset(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() || cmd.args.len() % 2 != 0 {
-        return Resp::Error("ERR wrong number of arguments for 'mset' command".to_string());
+fn cmd_hello(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let mut i = 0;
+    let mut protocol = ctx.protocol;
+    if let Some(version) = cmd.args.first() {
+        match version.parse::<i64>() {
+            Ok(2) => protocol = Protocol::Resp2,
+            Ok(3) => protocol = Protocol::Resp3,
+            Ok(_) => return Resp::Error("NOPROTO unsupported protocol version".to_string()),
+            Err(_) => {
+                return Resp::Error(
+                    "ERR Protocol version is not an integer or out of range".to_string(),
+                );
+            }
+        }
+        i = 1;
     }
 
-    let pairs: Vec<(String, String)> = cmd
-        .args
-        .chunks(2)
-        .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
-        .collect();
+    let mut setname = None;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            "AUTH" if i + 2 < cmd.args.len() => {
+                if let Err(e) = authenticate(server, ctx, &cmd.args[i + 1], &cmd.args[i + 2]) {
+                    return e;
+                }
+                i += 3;
+            }
+            "SETNAME" if i + 1 < cmd.args.len() => {
+                setname = Some(cmd.args[i + 1].clone());
+                i += 2;
+            }
+            _ => {
+                return Resp::Error(format!(
+                    "ERR Syntax error in HELLO option '{}'",
+                    cmd.args[i]
+                ));
+            }
+        }
+    }
 
-    storage.mset(pairs);
-    Resp::Simple("OK".to_string())
-}
+    if !ctx.authenticated {
+        return Resp::Error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".to_string());
+    }
 
-fn cmd_mget(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'mget' command".to_string());
+    if let Some(name) = setname {
+        let setname = Command {
+            name: "CLIENT".to_string(),
+            args: vec!["SETNAME".to_string(), name],
+        };
+        if let err @ Resp::Error(_) = cmd_client(&setname, &server.clients, ctx) {
+            return err;
+        }
     }
 
-    let values = storage.mget(&cmd.args);
-    let resp_values: Vec<Resp> = values
-        .into_iter()
-        .map(|v| match v {
-            Some(s) => Resp::Bulk(Some(s)),
-            None => Resp::Bulk(None),
-        })
-        .collect();
+    ctx.protocol = protocol;
+    server.clients.set_protocol(ctx.id, protocol);
 
-    Resp::Array(Some(resp_values))
+    let field = |name: &str| Resp::Bulk(Some(name.to_string()));
+    let role = if server.replication.is_replica() {
+        "replica"
+    } else {
+        "master"
+    };
+    let port = server.config.read().unwrap().port;
+    Resp::Map(vec![
+        (field("server"), field("redis")),
+        (field("version"), field("7.0.0")),
+        (field("proto"), Resp::Integer(protocol.version())),
+        (field("id"), Resp::Integer(ctx.id as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field(role)),
+        (field("port"), Resp::Integer(port as i64)),
+        (field("modules"), Resp::Array(Some(vec![]))),
+    ])
 }
 
-fn cmd_incr(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'incr' command".to_string());
+fn cmd_multi(_cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    if ctx.multi.is_some() {
+        return Resp::Error("ERR MULTI calls can not be nested".to_string());
     }
+    ctx.multi = Some(Vec::new());
+    server.clients.set_multi(ctx.id, Some(0));
+    ctx.exec_aborted = false;
+    Resp::Simple("OK".to_string())
+}
 
-    match storage.incr(&cmd.args[0]) {
-        Ok(n) => Resp::Integer(n),
-        Err(e) => Resp::Error(e),
+fn cmd_exec(_cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let Some(queue) = ctx.multi.take() else {
+        return Resp::Error("ERR EXEC without MULTI".to_string());
+    };
+    server.clients.set_multi(ctx.id, None);
+    let _guard = server.storage.exclusive();
+    let watched = std::mem::take(&mut ctx.watched);
+    // Expired since WATCH, though nothing has removed it yet
+    let expired = watched
+        .iter()
+        .any(|(db, key)| server.storage.db(*db).is_expired(key));
+    let dirty = server.storage.unwatch(ctx.id, &watched) || expired;
+    if std::mem::take(&mut ctx.exec_aborted) {
+        return Resp::Error(
+            "EXECABORT Transaction discarded because of previous errors.".to_string(),
+        );
+    }
+    // A watched key changed: abort with a null reply
+    if dirty {
+        return Resp::Array(None);
     }
+
+    run_transaction(&queue, server, ctx)
 }
 
-fn cmd_incrby(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'incrby' command".to_string());
-    }
-
-    let delta: i64 = match cmd.args[1].parse() {
-        Ok(d) => d,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
-    };
-
-    match storage.incr_by(&cmd.args[0], delta) {
-        Ok(n) => Resp::Integer(n),
-        Err(e) => Resp::Error(e),
-    }
+/// Runs a transaction received from the primary, while the caller holds
+/// the storage lock exclusively.
+pub fn replay_transaction(queue: &[Command], server: &Server, ctx: &mut ConnectionContext) {
+    run_transaction(queue, server, ctx);
 }
 
-fn cmd_decr(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'decr' command".to_string());
+fn run_transaction(queue: &[Command], server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    // Writes are propagated inside MULTI/EXEC so they are replayed as one
+    let logged = queue.iter().any(|cmd| is_write_command(&cmd.name));
+    if logged {
+        propagate(
+            &mut server.aof.lock(),
+            &mut server.replication.lock(),
+            ctx.db,
+            &["multi"],
+        );
     }
-
-    match storage.decr(&cmd.args[0]) {
-        Ok(n) => Resp::Integer(n),
-        Err(e) => Resp::Error(e),
+    // Blocking commands can't wait in the middle of a transaction
+    let deny_blocking = std::mem::replace(&mut ctx.deny_blocking, true);
+    ctx.in_exec = true;
+    let replies = queue
+        .iter()
+        .map(|cmd| match lookup(&cmd.name) {
+            Some(spec) => call(spec, cmd, server, ctx),
+            None => Resp::Error(format!("ERR unknown command '{}'", cmd.name)),
+        })
+        .collect();
+    ctx.deny_blocking = deny_blocking;
+    ctx.in_exec = false;
+    if logged {
+        let (mut aof, mut replicas) = (server.aof.lock(), server.replication.lock());
+        propagate(&mut aof, &mut replicas, ctx.db, &["exec"]);
+        // Waiters see the transaction's writes all at once
+        blocking::serve_ready(server, &mut aof, &mut replicas);
     }
+    Resp::Array(Some(replies))
 }
 
-fn cmd_decrby(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'decrby' command".to_string());
-    }
-
-    let delta: i64 = match cmd.args[1].parse() {
-        Ok(d) => d,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
-    };
-
-    match storage.incr_by(&cmd.args[0], -delta) {
-        Ok(n) => Resp::Integer(n),
-        Err(e) => Resp::Error(e),
+fn cmd_discard(_cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    if ctx.multi.take().is_none() {
+        return Resp::Error("ERR DISCARD without MULTI".to_string());
     }
+    server.clients.set_multi(ctx.id, None);
+    ctx.exec_aborted = false;
+    server
+        .storage
+        .unwatch(ctx.id, &std::mem::take(&mut ctx.watched));
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_append(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'append' command".to_string());
+fn cmd_watch(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    if ctx.multi.is_some() {
+        ctx.exec_aborted = true;
+        return Resp::Error("ERR WATCH inside MULTI is not allowed".to_string());
     }
-
-    match storage.append(&cmd.args[0], &cmd.args[1]) {
-        Ok(len) => Resp::Integer(len as i64),
-        Err(e) => Resp::Error(e),
+    for key in &cmd.args {
+        let watched = (ctx.db, key.clone());
+        if !ctx.watched.contains(&watched) {
+            server.storage.watch(ctx.id, ctx.db, key);
+            ctx.watched.push(watched);
+        }
     }
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_strlen(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'strlen' command".to_string());
-    }
-
-    match storage.strlen(&cmd.args[0]) {
-        Ok(len) => Resp::Integer(len as i64),
-        Err(e) => Resp::Error(e),
-    }
+fn cmd_unwatch(_cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    server
+        .storage
+        .unwatch(ctx.id, &std::mem::take(&mut ctx.watched));
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_del(cmd: &Command, storage: &Storage) -> Resp {
+fn cmd_ping(cmd: &Command) -> Resp {
     if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'del' command".to_string());
+        Resp::Simple("PONG".to_string())
+    } else {
+        Resp::Bulk(Some(cmd.args[0].clone()))
     }
-
-    let count = storage.del(&cmd.args);
-    Resp::Integer(count as i64)
 }
 
-fn cmd_exists(cmd: &Command, storage: &Storage) -> Resp {
+fn cmd_echo(cmd: &Command) -> Resp {
     if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'exists' command".to_string());
+        Resp::Error("ERR wrong number of arguments for 'echo' command".to_string())
+    } else {
+        Resp::Bulk(Some(cmd.args[0].clone()))
     }
+}
 
-    let count = storage.exists(&cmd.args);
-    Resp::Integer(count as i64)
+fn cmd_time() -> Resp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(now.as_secs().to_string())),
+        Resp::Bulk(Some(now.subsec_micros().to_string())),
+    ]))
 }
 
-fn cmd_expire(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'expire' command".to_string());
+fn cmd_lastsave(server: &Server) -> Resp {
+    Resp::Integer(server.lastsave.load(Ordering::Relaxed) as i64)
+}
+
+fn cmd_save(server: &Server) -> Resp {
+    match rdb::save(server) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => Resp::Error(e),
     }
+}
 
-    let seconds: u64 = match cmd.args[1].parse() {
-        Ok(s) => s,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+fn cmd_bgsave(cmd: &Command, server: &Server) -> Resp {
+    let schedule = match cmd.args.as_slice() {
+        [] => false,
+        [arg] if arg.eq_ignore_ascii_case("schedule") => true,
+        _ => return Resp::Error("ERR syntax error".to_string()),
     };
-
-    if storage.expire(&cmd.args[0], seconds * 1000) {
-        Resp::Integer(1)
-    } else {
-        Resp::Integer(0)
+    // With SCHEDULE a save already running isn't an error: the cron starts
+    // another one once it is done
+    if schedule && server.rdb.bgsave_in_progress() {
+        server.rdb.schedule_bgsave();
+        return Resp::Simple("Background saving scheduled".to_string());
+    }
+    match rdb::bgsave(server) {
+        Ok(()) => Resp::Simple("Background saving started".to_string()),
+        Err(e) => Resp::Error(e),
     }
 }
 
-fn cmd_pexpire(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'pexpire' command".to_string());
+fn cmd_replicaof(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let [host, port] = cmd.args.as_slice() else {
+        return Resp::Error("ERR syntax error".to_string());
+    };
+    if server.replication.failover_state() != FailoverState::None {
+        return Resp::Error("ERR REPLICAOF not allowed while failing over.".to_string());
     }
-
-    let ms: u64 = match cmd.args[1].parse() {
-        Ok(m) => m,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    let client = server
+        .clients
+        .get(ctx.id)
+        .map(|client| client.to_line())
+        .unwrap_or_default();
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        if server.replication.set_primary(None) {
+            tracing::info!("PRIMARY MODE enabled (user request from '{}')", client);
+        }
+        server.config.write().unwrap().replicaof = None;
+        return Resp::Simple("OK".to_string());
+    }
+    let Ok(port) = port.parse::<u16>() else {
+        return Resp::Error("ERR Invalid master port".to_string());
     };
+    let primary = Some((host.clone(), port));
+    if !server.replication.set_primary(primary.clone()) {
+        return Resp::Simple("OK Already connected to specified master".to_string());
+    }
+    server.config.write().unwrap().replicaof = primary;
+    tracing::info!(
+        "REPLICAOF {}:{} enabled (user request from '{}')",
+        host,
+        port,
+        client
+    );
+    Resp::Simple("OK".to_string())
+}
 
-    if storage.expire(&cmd.args[0], ms) {
-        Resp::Integer(1)
-    } else {
-        Resp::Integer(0)
+/// SYNC and PSYNC turn the connection into a replica. Its replies are
+/// written by `replication::serve_replica`, which the connection is handed
+/// to once this returns, so the one returned here is dropped.
+fn cmd_sync(server: &Server, ctx: &mut ConnectionContext, request: SyncRequest) -> Resp {
+    // A replica can only pass on a dataset it has
+    if server
+        .replication
+        .primary()
+        .is_some_and(|primary| primary.state != LinkState::Connected)
+    {
+        return Resp::Error(
+            "NOMASTERLINK Can't SYNC while not connected with my master".to_string(),
+        );
     }
+    server.clients.set_kind(ctx.id, ClientKind::Replica);
+    ctx.sync = Some(request);
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_ttl(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'ttl' command".to_string());
+/// FAILOVER [TO host port [FORCE]] [ABORT] [TIMEOUT milliseconds]
+fn cmd_failover(cmd: &Command, server: &Server) -> Resp {
+    let mut target = None;
+    let mut force = false;
+    let mut abort = false;
+    let mut timeout = None;
+    let mut args = cmd.args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_uppercase().as_str() {
+            "TO" if target.is_none() => {
+                let (Some(host), Some(port)) = (args.next(), args.next()) else {
+                    return Resp::Error("ERR syntax error".to_string());
+                };
+                let Ok(port) = port.parse::<u16>() else {
+                    return Resp::Error("ERR Invalid target port".to_string());
+                };
+                target = Some((host.clone(), port));
+            }
+            "FORCE" if !force => force = true,
+            "ABORT" if !abort => abort = true,
+            "TIMEOUT" if timeout.is_none() => {
+                let ms = args.next().and_then(|ms| ms.parse::<i64>().ok());
+                match ms {
+                    Some(ms) if ms > 0 => timeout = Some(Duration::from_millis(ms as u64)),
+                    _ => {
+                        return Resp::Error(
+                            "ERR FAILOVER timeout must be greater than 0".to_string(),
+                        );
+                    }
+                }
+            }
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
     }
 
-    let ttl_ms = storage.ttl(&cmd.args[0]);
-    if ttl_ms == -2 || ttl_ms == -1 {
-        Resp::Integer(ttl_ms)
-    } else {
-        Resp::Integer(ttl_ms / 1000)
+    if abort {
+        if target.is_some() || force || timeout.is_some() {
+            return Resp::Error(
+                "ERR FAILOVER ABORT can't be combined with other options".to_string(),
+            );
+        }
+        if !replication::abort_failover(server, "Failover manually aborted") {
+            return Resp::Error("ERR No failover in progress.".to_string());
+        }
+        return Resp::Simple("OK".to_string());
+    }
+    if force && (target.is_none() || timeout.is_none()) {
+        return Resp::Error(
+            "ERR FAILOVER with force option requires both a timeout and target HOST and PORT."
+                .to_string(),
+        );
+    }
+    match replication::start_failover(server, target, timeout, force) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => Resp::Error(e),
     }
 }
 
-fn cmd_pttl(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'pttl' command".to_string());
+fn cmd_psync(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    // A primary failing over to this replica asks it to take over first
+    match cmd.args.get(2) {
+        None => {}
+        Some(option) if option.eq_ignore_ascii_case("failover") && cmd.args.len() == 3 => {
+            if cmd.args[0] != server.replication.replid() {
+                return Resp::Error("ERR PSYNC FAILOVER replid must match my replid.".to_string());
+            }
+            if server.replication.set_primary(None) {
+                server.config.write().unwrap().replicaof = None;
+                tracing::info!(
+                    "Failover request received for replid {}, taking over as primary",
+                    cmd.args[0]
+                );
+            }
+        }
+        Some(_) => return Resp::Error("ERR syntax error".to_string()),
     }
-
-    Resp::Integer(storage.ttl(&cmd.args[0]))
+    // "-1" asks for a full resync, as does any offset we can't continue from
+    let offset = cmd.args[1].parse::<i64>().unwrap_or(-1);
+    let request = SyncRequest::Psync {
+        replid: cmd.args[0].clone(),
+        offset,
+    };
+    cmd_sync(server, ctx, request)
 }
 
-fn cmd_persist(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'persist' command".to_string());
+fn cmd_replconf(cmd: &Command, ctx: &mut ConnectionContext) -> Resp {
+    if !cmd.args.len().is_multiple_of(2) {
+        return Resp::Error("ERR syntax error".to_string());
     }
-
-    if storage.persist(&cmd.args[0]) {
-        Resp::Integer(1)
-    } else {
-        Resp::Integer(0)
+    for pair in cmd.args.chunks(2) {
+        match pair[0].to_lowercase().as_str() {
+            "listening-port" => match pair[1].parse() {
+                Ok(port) => ctx.replica_port = Some(port),
+                Err(_) => {
+                    return Resp::Error("ERR value is not an integer or out of range".to_string());
+                }
+            },
+            // Replicas announce their capabilities; full syncs need none
+            "capa" | "ip-address" => {}
+            option => {
+                return Resp::Error(format!("ERR Unrecognized REPLCONF option: {}", option));
+            }
+        }
     }
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_keys(cmd: &Command, storage: &Storage) -> Resp {
-    let pattern = cmd.args.get(0).map(|s| s.as_str()).unwrap_or("*");
-    let keys = storage.keys(pattern);
-    let resp_keys: Vec<Resp> = keys.into_iter().map(|k| Resp::Bulk(Some(k))).collect();
-    Resp::Array(Some(resp_keys))
+/// ROLE: `master` with the replication offset and every replica's address
+/// and acknowledged offset, or `slave` with the primary and the link state.
+fn cmd_role(server: &Server) -> Resp {
+    let replication = &server.replication;
+    let offset = replication.offset() as i64;
+    let Some(primary) = replication.primary() else {
+        let replicas = replication
+            .replicas()
+            .into_iter()
+            .filter(|replica| replica.online)
+            .map(|replica| {
+                Resp::Array(Some(vec![
+                    Resp::Bulk(Some(replica.ip.to_string())),
+                    Resp::Bulk(Some(replica.port.to_string())),
+                    Resp::Bulk(Some(replica.ack_offset.to_string())),
+                ]))
+            })
+            .collect();
+        return Resp::Array(Some(vec![
+            Resp::Bulk(Some("master".to_string())),
+            Resp::Integer(offset),
+            Resp::Array(Some(replicas)),
+        ]));
+    };
+    // The offset is only meaningful once the dataset has been received
+    let offset = if primary.state == LinkState::Connected {
+        offset
+    } else {
+        -1
+    };
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some("slave".to_string())),
+        Resp::Bulk(Some(primary.host)),
+        Resp::Integer(primary.port as i64),
+        Resp::Bulk(Some(primary.state.name().to_string())),
+        Resp::Integer(offset),
+    ]))
 }
 
-fn cmd_type(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'type' command".to_string());
-    }
-
-    match storage.get_type(&cmd.args[0]) {
-        Some(t) => Resp::Simple(t.to_string()),
-        None => Resp::Simple("none".to_string()),
-    }
+fn cmd_quit(ctx: &mut ConnectionContext) -> Resp {
+    ctx.closing = true;
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_rename(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'rename' command".to_string());
-    }
-
-    match storage.rename(&cmd.args[0], &cmd.args[1]) {
-        Ok(()) => Resp::Simple("OK".to_string()),
-        Err(e) => Resp::Error(e),
+/// MONITOR: the commands every client runs are sent to this connection
+/// from now on.
+fn cmd_monitor(clients: &ClientRegistry, ctx: &mut ConnectionContext) -> Resp {
+    if ctx.monitor.is_none() {
+        ctx.monitor = Some(clients.monitor(ctx.id));
     }
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_renamenx(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'renamenx' command".to_string());
-    }
+fn cmd_command(cmd: &Command) -> Resp {
+    let Some(sub) = cmd.args.first() else {
+        return Resp::Array(Some(COMMAND_TABLE.iter().map(command_info).collect()));
+    };
 
-    match storage.renamenx(&cmd.args[0], &cmd.args[1]) {
-        Ok(true) => Resp::Integer(1),
-        Ok(false) => Resp::Integer(0),
-        Err(e) => Resp::Error(e),
+    match sub.to_uppercase().as_str() {
+        "COUNT" => Resp::Integer(COMMAND_TABLE.len() as i64),
+        "INFO" if cmd.args.len() == 1 => {
+            Resp::Array(Some(COMMAND_TABLE.iter().map(command_info).collect()))
+        }
+        "INFO" => Resp::Array(Some(
+            cmd.args[1..]
+                .iter()
+                .map(|name| lookup(name).map_or(Resp::Array(None), command_info))
+                .collect(),
+        )),
+        "LIST" => cmd_command_list(cmd),
+        "DOCS" => {
+            let docs: Vec<_> = if cmd.args.len() == 1 {
+                COMMAND_DOCS.iter().collect()
+            } else {
+                cmd.args[1..]
+                    .iter()
+                    .filter_map(|name| docs::find(name))
+                    .collect()
+            };
+            Resp::Map(docs.iter().map(|doc| doc.to_resp()).collect())
+        }
+        "GETKEYS" => cmd_command_getkeys(cmd),
+        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", sub)),
     }
 }
 
-fn cmd_flushdb(storage: &Storage) -> Resp {
-    storage.flushdb();
-    Resp::Simple("OK".to_string())
+/// A command's entry in COMMAND and COMMAND INFO replies.
+fn command_info(spec: &CommandSpec) -> Resp {
+    let simple = |s: &str| Resp::Simple(s.to_string());
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(spec.name.to_string())),
+        Resp::Integer(spec.arity as i64),
+        Resp::Array(Some(spec.flags.iter().map(|f| simple(f)).collect())),
+        Resp::Integer(spec.first_key as i64),
+        Resp::Integer(spec.last_key as i64),
+        Resp::Integer(spec.step as i64),
+        Resp::Array(Some(
+            spec.acl_categories()
+                .iter()
+                .map(|c| simple(&format!("@{}", c)))
+                .collect(),
+        )),
+        // Tips, key specifications and subcommands aren't described yet.
+        Resp::Array(Some(vec![])),
+        Resp::Array(Some(vec![])),
+        Resp::Array(Some(vec![])),
+    ]))
 }
 
-fn cmd_lpush(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'lpush' command".to_string());
+fn cmd_command_getkeys(cmd: &Command) -> Resp {
+    let Some(name) = cmd.args.get(1) else {
+        return Resp::Error(
+            "ERR wrong number of arguments for 'command|getkeys' command".to_string(),
+        );
+    };
+    let Some(spec) = lookup(name) else {
+        return Resp::Error("ERR Invalid command specified".to_string());
+    };
+    let args = &cmd.args[2..];
+    if !spec.accepts(args.len()) {
+        return Resp::Error("ERR Invalid number of arguments specified for command".to_string());
     }
+    let keys = spec.keys(args);
+    if keys.is_empty() {
+        return Resp::Error("ERR The command has no key arguments".to_string());
+    }
+    Resp::Array(Some(
+        keys.into_iter()
+            .map(|key| Resp::Bulk(Some(key.to_string())))
+            .collect(),
+    ))
+}
 
-    let key = &cmd.args[0];
-    let values: Vec<String> = cmd.args[1..].to_vec();
+fn cmd_command_list(cmd: &Command) -> Resp {
+    let filter: Box<dyn Fn(&CommandSpec) -> bool> = match &cmd.args[1..] {
+        [] => Box::new(|_| true),
+        [filterby, kind, value] if filterby.eq_ignore_ascii_case("FILTERBY") => {
+            let value = value.clone();
+            match kind.to_uppercase().as_str() {
+                // There are no modules, so no command comes from one.
+                "MODULE" => Box::new(|_| false),
+                "ACLCAT" => {
+                    let category = value.to_lowercase();
+                    Box::new(move |spec| spec.acl_categories().contains(&category.as_str()))
+                }
+                "PATTERN" => {
+                    let pattern = Pattern::new(&value);
+                    Box::new(move |spec| pattern.matches(spec.name))
+                }
+                _ => return Resp::Error("ERR syntax error".to_string()),
+            }
+        }
+        _ => return Resp::Error("ERR syntax error".to_string()),
+    };
 
-    match storage.lpush(key, values) {
-        Ok(len) => Resp::Integer(len as i64),
-        Err(e) => Resp::Error(e),
-    }
+    Resp::Array(Some(
+        COMMAND_TABLE
+            .iter()
+            .filter(|spec| filter(spec))
+            .map(|spec| Resp::Bulk(Some(spec.name.to_string())))
+            .collect(),
+    ))
 }
 
-fn cmd_rpush(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'rpush' command".to_string());
+fn cmd_config(cmd: &Command, server: &Server) -> Resp {
+    let config = &server.config;
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'config' command".to_string());
     }
 
-    let key = &cmd.args[0];
-    let values: Vec<String> = cmd.args[1..].to_vec();
+    match cmd.args[0].to_uppercase().as_str() {
+        "GET" => {
+            if cmd.args.len() < 2 {
+                return Resp::Error(
+                    "ERR wrong number of arguments for 'config|get' command".to_string(),
+                );
+            }
 
-    match storage.rpush(key, values) {
-        Ok(len) => Resp::Integer(len as i64),
-        Err(e) => Resp::Error(e),
+            let config = config.read().unwrap();
+            let mut seen = Vec::new();
+            let mut result = Vec::new();
+            for pattern in &cmd.args[1..] {
+                for (name, value) in config.get(pattern) {
+                    if !seen.contains(&name) {
+                        seen.push(name);
+                        result.push((Resp::Bulk(Some(name.to_string())), Resp::Bulk(Some(value))));
+                    }
+                }
+            }
+            Resp::Map(result)
+        }
+        "SET" => {
+            if cmd.args.len() < 3 || !(cmd.args.len() - 1).is_multiple_of(2) {
+                return Resp::Error(
+                    "ERR wrong number of arguments for 'config|set' command".to_string(),
+                );
+            }
+
+            // Apply all parameters or none of them
+            let mut config = config.write().unwrap();
+            let mut updated = config.clone();
+            for pair in cmd.args[1..].chunks(2) {
+                if let Err(e) = updated.set(&pair[0], &pair[1]) {
+                    return Resp::Error(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                        pair[0], e
+                    ));
+                }
+            }
+            if updated.appendonly != config.appendonly {
+                if updated.appendonly {
+                    if let Err(e) = server.aof.start(
+                        &updated.aof_path(),
+                        updated.appendfsync,
+                        &server.storage,
+                        server.keyring.as_ref(),
+                    ) {
+                        tracing::warn!("Can't open the append-only file: {}", e);
+                        return Resp::Error(
+                            "ERR CONFIG SET failed (possibly related to argument 'appendonly') - Can't open the append-only file".to_string(),
+                        );
+                    }
+                    tracing::info!("Append only file enabled, dataset written out");
+                } else {
+                    server.aof.stop();
+                }
+            }
+            server.aof.set_fsync(updated.appendfsync);
+            server
+                .replication
+                .set_backlog_size(updated.repl_backlog_size);
+            server
+                .storage
+                .set_list_max_listpack_size(updated.list_max_listpack_size);
+            server.storage.set_hash_max_listpack(
+                updated.hash_max_listpack_entries,
+                updated.hash_max_listpack_value,
+            );
+            server.storage.set_bf_defaults(
+                updated.bf_error_rate,
+                updated.bf_initial_size,
+                updated.bf_expansion_factor,
+            );
+            if updated.requirepass != config.requirepass {
+                server.acl.set_requirepass(&updated.requirepass);
+            }
+            logging::set_options(updated.loglevel, updated.log_format);
+            trace::set_log_spans(updated.command_tracing);
+            *config = updated;
+            Resp::Simple("OK".to_string())
+        }
+        "RESETSTAT" => {
+            server.command_latency.reset();
+            server.stats.reset();
+            Resp::Simple("OK".to_string())
+        }
+        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", cmd.args[0])),
     }
 }
 
-fn cmd_lpop(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'lpop' command".to_string());
+fn cmd_debug(cmd: &Command, server: &Server, ctx: &ConnectionContext) -> Resp {
+    let sub = cmd.args[0].to_uppercase();
+    match sub.as_str() {
+        "DIGEST" if cmd.args.len() == 1 => {
+            Resp::Simple(digest::to_hex(&digest::dataset_digest(&server.storage)))
+        }
+        "DIGEST-VALUE" => {
+            let db = server.storage.db(ctx.db);
+            Resp::Array(Some(
+                cmd.args[1..]
+                    .iter()
+                    .map(|key| {
+                        let value = db
+                            .with_value(key, digest::value_digest)
+                            .unwrap_or(digest::EMPTY_DIGEST);
+                        Resp::Simple(digest::to_hex(&value))
+                    })
+                    .collect(),
+            ))
+        }
+        _ => Resp::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'",
+            cmd.args[0]
+        )),
     }
+}
 
-    match storage.lpop(&cmd.args[0]) {
-        Ok(Some(v)) => Resp::Bulk(Some(v)),
-        Ok(None) => Resp::Bulk(None),
-        Err(e) => Resp::Error(e),
+/// CLUSTER: only KEYSLOT, for clients that route by slot; there's no
+/// cluster mode.
+fn cmd_cluster(cmd: &Command) -> Resp {
+    match cmd.args[0].to_uppercase().as_str() {
+        "KEYSLOT" if cmd.args.len() == 2 => {
+            Resp::Integer(cluster::key_hash_slot(&binary::encode(&cmd.args[1])) as i64)
+        }
+        "KEYSLOT" => {
+            Resp::Error("ERR wrong number of arguments for 'cluster|keyslot' command".to_string())
+        }
+        _ => Resp::Error("ERR This instance has cluster support disabled".to_string()),
     }
 }
 
-fn cmd_rpop(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'rpop' command".to_string());
+/// MEMORY USAGE key [SAMPLES count]: the bytes a key and its value take,
+/// with collections estimated from `count` elements (5 by default, 0 for
+/// all of them).
+fn cmd_memory(cmd: &Command, db: &Db) -> Resp {
+    match cmd.args[0].to_uppercase().as_str() {
+        "USAGE" if cmd.args.len() == 2 || cmd.args.len() == 4 => {
+            let mut samples = 5;
+            if cmd.args.len() == 4 {
+                if !cmd.args[2].eq_ignore_ascii_case("samples") {
+                    return Resp::Error("ERR syntax error".to_string());
+                }
+                match cmd.args[3].parse::<usize>() {
+                    Ok(count) => samples = count,
+                    Err(_) => {
+                        return Resp::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        );
+                    }
+                }
+            }
+            match db.memory_usage(&cmd.args[1], samples) {
+                Some(bytes) => Resp::Integer(bytes as i64),
+                None => Resp::Bulk(None),
+            }
+        }
+        "USAGE" => Resp::Error("ERR syntax error".to_string()),
+        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", cmd.args[0])),
     }
+}
 
-    match storage.rpop(&cmd.args[0]) {
-        Ok(Some(v)) => Resp::Bulk(Some(v)),
-        Ok(None) => Resp::Bulk(None),
-        Err(e) => Resp::Error(e),
+fn cmd_latency(cmd: &Command, server: &Server) -> Resp {
+    let sub = cmd.args[0].to_uppercase();
+    match sub.as_str() {
+        "LATEST" => Resp::Array(Some(
+            server
+                .latency
+                .latest()
+                .into_iter()
+                .map(|spike| {
+                    Resp::Array(Some(vec![
+                        Resp::Bulk(Some(spike.event)),
+                        Resp::Integer(spike.time as i64),
+                        Resp::Integer(spike.latency_ms as i64),
+                        Resp::Integer(spike.max_ms as i64),
+                    ]))
+                })
+                .collect(),
+        )),
+        "HISTORY" if cmd.args.len() == 2 => Resp::Array(Some(
+            server
+                .latency
+                .history(&cmd.args[1])
+                .into_iter()
+                .map(|sample| {
+                    Resp::Array(Some(vec![
+                        Resp::Integer(sample.time as i64),
+                        Resp::Integer(sample.latency_ms as i64),
+                    ]))
+                })
+                .collect(),
+        )),
+        "RESET" => Resp::Integer(server.latency.reset(&cmd.args[1..]) as i64),
+        "DOCTOR" => {
+            let threshold = server.config.read().unwrap().latency_monitor_threshold;
+            Resp::Bulk(Some(server.latency.doctor(threshold)))
+        }
+        "HISTORY" => {
+            Resp::Error("ERR wrong number of arguments for 'latency|history' command".to_string())
+        }
+        "HISTOGRAM" => {
+            let histograms = if cmd.args.len() == 1 {
+                server.command_latency.called()
+            } else {
+                cmd.args[1..]
+                    .iter()
+                    .filter_map(|name| lookup(name))
+                    .filter_map(|spec| Some((spec.name, server.command_latency.get(spec.name)?)))
+                    .filter(|(_, histogram)| histogram.total() > 0)
+                    .collect()
+            };
+            let mut reply = Vec::new();
+            for (name, histogram) in histograms {
+                let buckets = histogram
+                    .power_of_two_buckets()
+                    .into_iter()
+                    .map(|(bound, count)| {
+                        (Resp::Integer(bound as i64), Resp::Integer(count as i64))
+                    })
+                    .collect();
+                let fields = vec![
+                    (
+                        Resp::Bulk(Some("calls".to_string())),
+                        Resp::Integer(histogram.total() as i64),
+                    ),
+                    (
+                        Resp::Bulk(Some("histogram_usec".to_string())),
+                        Resp::Map(buckets),
+                    ),
+                ];
+                reply.push((Resp::Bulk(Some(name.to_string())), Resp::Map(fields)));
+            }
+            Resp::Map(reply)
+        }
+        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", cmd.args[0])),
     }
 }
 
-fn cmd_llen(cmd: &Command, storage: &Storage) -> Resp {
+fn cmd_acl(cmd: &Command, server: &Server, ctx: &ConnectionContext) -> Resp {
     if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'llen' command".to_string());
+        return Resp::Error("ERR wrong number of arguments for 'acl' command".to_string());
     }
 
-    match storage.llen(&cmd.args[0]) {
-        Ok(len) => Resp::Integer(len as i64),
-        Err(e) => Resp::Error(e),
-    }
-}
-
-fn cmd_lrange(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 3 {
-        return Resp::Error("ERR wrong number of arguments for 'lrange' command".to_string());
-    }
-
-    let key = &cmd.args[0];
-    let start: i64 = match cmd.args[1].parse() {
-        Ok(s) => s,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    let sub = cmd.args[0].to_uppercase();
+    let arity_error = || {
+        Resp::Error(format!(
+            "ERR wrong number of arguments for 'acl|{}' command",
+            sub.to_lowercase()
+        ))
     };
-    let stop: i64 = match cmd.args[2].parse() {
-        Ok(s) => s,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    let bulks = |items: Vec<String>| {
+        Resp::Array(Some(
+            items.into_iter().map(|s| Resp::Bulk(Some(s))).collect(),
+        ))
     };
 
-    match storage.lrange(key, start, stop) {
-        Ok(values) => {
-            let resp_values: Vec<Resp> = values.into_iter().map(|v| Resp::Bulk(Some(v))).collect();
-            Resp::Array(Some(resp_values))
+    match sub.as_str() {
+        "SETUSER" => {
+            if cmd.args.len() < 2 {
+                return arity_error();
+            }
+            match server.acl.set_user(&cmd.args[1], &cmd.args[2..]) {
+                Ok(()) => Resp::Simple("OK".to_string()),
+                Err(e) => Resp::Error(format!("ERR {}", e)),
+            }
         }
-        Err(e) => Resp::Error(e),
+        "GETUSER" => {
+            if cmd.args.len() != 2 {
+                return arity_error();
+            }
+            let Some(user) = server.acl.get(&cmd.args[1]) else {
+                return Resp::Bulk(None);
+            };
+            let field = |name: &str| Resp::Bulk(Some(name.to_string()));
+            Resp::Map(vec![
+                (field("flags"), bulks(user.flag_list())),
+                (field("passwords"), bulks(user.password_hashes().to_vec())),
+                (field("commands"), Resp::Bulk(Some(user.commands_rule()))),
+                (field("keys"), Resp::Bulk(Some(user.keys_rule()))),
+                (field("channels"), Resp::Bulk(Some(user.channels_rule()))),
+                (field("selectors"), Resp::Array(Some(vec![]))),
+            ])
+        }
+        "DELUSER" => {
+            if cmd.args.len() < 2 {
+                return arity_error();
+            }
+            let names = &cmd.args[1..];
+            match server.acl.del_users(names) {
+                Ok(deleted) => {
+                    // Connections authenticated as a deleted user are dropped.
+                    for client in server.clients.list() {
+                        if names.contains(&client.user) {
+                            server.clients.kill(client.id);
+                        }
+                    }
+                    Resp::Integer(deleted as i64)
+                }
+                Err(e) => Resp::Error(format!("ERR {}", e)),
+            }
+        }
+        "LIST" => bulks(server.acl.list().iter().map(|u| u.describe()).collect()),
+        "USERS" => bulks(server.acl.list().into_iter().map(|u| u.name).collect()),
+        "WHOAMI" => Resp::Bulk(Some(ctx.user.clone())),
+        "LOG" => cmd_acl_log(cmd, server),
+        "GENPASS" => {
+            let bits = match cmd.args.get(1).map(|b| b.parse::<usize>()) {
+                None => 256,
+                Some(Ok(bits)) if (1..=4096).contains(&bits) => bits,
+                Some(_) => {
+                    return Resp::Error(
+                        "ERR ACL GENPASS argument must be the number of bits for the output password, a positive number up to 4096".to_string(),
+                    );
+                }
+            };
+            match acl::genpass(bits) {
+                Ok(pass) => Resp::Bulk(Some(pass)),
+                Err(e) => Resp::Error(format!("ERR Failed to generate password: {}", e)),
+            }
+        }
+        "CAT" => match cmd.args.get(1) {
+            None => bulks(acl::categories().iter().map(|c| c.to_string()).collect()),
+            Some(category) if cmd.args.len() == 2 => match acl::category_commands(category) {
+                Some(commands) => bulks(commands.iter().map(|c| c.to_string()).collect()),
+                None => Resp::Error(format!("ERR Unknown category '{}'", category)),
+            },
+            Some(_) => arity_error(),
+        },
+        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", cmd.args[0])),
     }
 }
 
-fn cmd_lindex(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'lindex' command".to_string());
-    }
-
-    let key = &cmd.args[0];
-    let index: i64 = match cmd.args[1].parse() {
-        Ok(i) => i,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+fn cmd_acl_log(cmd: &Command, server: &Server) -> Resp {
+    let count = match cmd.args.get(1) {
+        None => 10,
+        Some(arg) if arg.eq_ignore_ascii_case("RESET") => {
+            server.acl.reset_log();
+            return Resp::Simple("OK".to_string());
+        }
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                return Resp::Error("ERR value is out of range, must be positive".to_string());
+            }
+        },
     };
 
-    match storage.lindex(key, index) {
-        Ok(Some(v)) => Resp::Bulk(Some(v)),
-        Ok(None) => Resp::Bulk(None),
-        Err(e) => Resp::Error(e),
-    }
+    let now = storage::now_ms();
+    let field = |name: &str| Resp::Bulk(Some(name.to_string()));
+    let entries = server
+        .acl
+        .log_entries(count)
+        .into_iter()
+        .map(|entry| {
+            let age = now.saturating_sub(entry.updated_ms) as f64 / 1000.0;
+            Resp::Map(vec![
+                (field("count"), Resp::Integer(entry.count as i64)),
+                (field("reason"), field(entry.reason.as_str())),
+                (field("context"), field("toplevel")),
+                (field("object"), Resp::Bulk(Some(entry.object))),
+                (field("username"), Resp::Bulk(Some(entry.username))),
+                (
+                    field("age-seconds"),
+                    Resp::Bulk(Some(format!("{:.3}", age))),
+                ),
+                (field("client-info"), Resp::Bulk(Some(entry.client_info))),
+                (field("entry-id"), Resp::Integer(entry.entry_id as i64)),
+                (
+                    field("timestamp-created"),
+                    Resp::Integer(entry.created_ms as i64),
+                ),
+                (
+                    field("timestamp-last-updated"),
+                    Resp::Integer(entry.updated_ms as i64),
+                ),
+            ])
+        })
+        .collect();
+    Resp::Array(Some(entries))
 }
 
-fn cmd_lset(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 3 {
-        return Resp::Error("ERR wrong number of arguments for 'lset' command".to_string());
+fn cmd_client(cmd: &Command, clients: &ClientRegistry, ctx: &mut ConnectionContext) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'client' command".to_string());
     }
 
-    let key = &cmd.args[0];
-    let index: i64 = match cmd.args[1].parse() {
-        Ok(i) => i,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
-    };
-    let value = cmd.args[2].clone();
-
-    match storage.lset(key, index, value) {
-        Ok(()) => Resp::Simple("OK".to_string()),
-        Err(e) => Resp::Error(e),
+    match cmd.args[0].to_uppercase().as_str() {
+        "SETINFO" => cmd_client_setinfo(cmd, clients, ctx.id),
+        "SETNAME" => cmd_client_setname(cmd, clients, ctx),
+        "GETNAME" => Resp::Bulk(ctx.name.clone()),
+        "LIST" => cmd_client_list(cmd, clients),
+        "INFO" => match clients.get(ctx.id) {
+            Some(client) => Resp::Bulk(Some(format!("{}\n", client.to_line()))),
+            None => Resp::Bulk(None),
+        },
+        "ID" => Resp::Integer(ctx.id as i64),
+        "KILL" => cmd_client_kill(cmd, clients, ctx.id),
+        "REPLY" => cmd_client_reply(cmd, ctx),
+        "NO-EVICT" => cmd_client_no_evict(cmd, clients, ctx.id),
+        "PAUSE" => cmd_client_pause(cmd, clients),
+        "UNBLOCK" => cmd_client_unblock(cmd, clients),
+        "UNPAUSE" => {
+            clients.unpause();
+            Resp::Simple("OK".to_string())
+        }
+        _ => Resp::Simple("OK".to_string()),
     }
 }
 
-fn cmd_sadd(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'sadd' command".to_string());
+fn cmd_client_no_evict(cmd: &Command, clients: &ClientRegistry, client_id: u64) -> Resp {
+    if cmd.args.len() != 2 {
+        return Resp::Error(
+            "ERR wrong number of arguments for 'client|no-evict' command".to_string(),
+        );
     }
-
-    let key = &cmd.args[0];
-    let members: Vec<String> = cmd.args[1..].to_vec();
-
-    match storage.sadd(key, members) {
-        Ok(added) => Resp::Integer(added as i64),
-        Err(e) => Resp::Error(e),
+    match cmd.args[1].to_uppercase().as_str() {
+        "ON" => clients.set_no_evict(client_id, true),
+        "OFF" => clients.set_no_evict(client_id, false),
+        _ => return Resp::Error("ERR syntax error".to_string()),
     }
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_srem(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'srem' command".to_string());
-    }
-
-    let key = &cmd.args[0];
-    let members: Vec<String> = cmd.args[1..].to_vec();
-
-    match storage.srem(key, members) {
-        Ok(removed) => Resp::Integer(removed as i64),
-        Err(e) => Resp::Error(e),
-    }
+/// Client names and library info end up in space-separated CLIENT LIST
+/// output, so they must be printable and free of spaces.
+fn is_valid_client_attr(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_graphic())
 }
 
-fn cmd_smembers(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'smembers' command".to_string());
+fn cmd_client_setname(
+    cmd: &Command,
+    clients: &ClientRegistry,
+    ctx: &mut ConnectionContext,
+) -> Resp {
+    if cmd.args.len() != 2 {
+        return Resp::Error(
+            "ERR wrong number of arguments for 'client|setname' command".to_string(),
+        );
     }
 
-    match storage.smembers(&cmd.args[0]) {
-        Ok(members) => {
-            let resp_members: Vec<Resp> =
-                members.into_iter().map(|m| Resp::Bulk(Some(m))).collect();
-            Resp::Array(Some(resp_members))
-        }
-        Err(e) => Resp::Error(e),
+    if !is_valid_client_attr(&cmd.args[1]) {
+        return Resp::Error(
+            "ERR Client names cannot contain spaces, newlines or special characters.".to_string(),
+        );
     }
+
+    ctx.name = (!cmd.args[1].is_empty()).then(|| cmd.args[1].clone());
+    clients.set_name(ctx.id, &cmd.args[1]);
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_sismember(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'sismember' command".to_string());
+fn cmd_client_reply(cmd: &Command, ctx: &mut ConnectionContext) -> Resp {
+    if cmd.args.len() != 2 {
+        return Resp::Error("ERR wrong number of arguments for 'client|reply' command".to_string());
     }
 
-    match storage.sismember(&cmd.args[0], &cmd.args[1]) {
-        Ok(true) => Resp::Integer(1),
-        Ok(false) => Resp::Integer(0),
-        Err(e) => Resp::Error(e),
+    match cmd.args[1].to_uppercase().as_str() {
+        "ON" => ctx.reply_mode = ReplyMode::On,
+        "OFF" => ctx.reply_mode = ReplyMode::Off,
+        // Covers this command's own reply plus the next one
+        "SKIP" => ctx.reply_mode = ReplyMode::Skip(2),
+        _ => return Resp::Error("ERR syntax error".to_string()),
     }
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_scard(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'scard' command".to_string());
+fn cmd_client_setinfo(cmd: &Command, clients: &ClientRegistry, client_id: u64) -> Resp {
+    if cmd.args.len() != 3 {
+        return Resp::Error(
+            "ERR wrong number of arguments for 'client|setinfo' command".to_string(),
+        );
     }
 
-    match storage.scard(&cmd.args[0]) {
-        Ok(card) => Resp::Integer(card as i64),
-        Err(e) => Resp::Error(e),
+    let value = cmd.args[2].as_str();
+    if !is_valid_client_attr(value) {
+        return Resp::Error(format!(
+            "ERR {} cannot contain spaces, newlines or special characters.",
+            cmd.args[1]
+        ));
+    }
+
+    match cmd.args[1].to_uppercase().as_str() {
+        "LIB-NAME" => clients.set_lib_info(client_id, Some(value), None),
+        "LIB-VER" => clients.set_lib_info(client_id, None, Some(value)),
+        _ => return Resp::Error(format!("ERR Unrecognized option '{}'", cmd.args[1])),
     }
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_hset(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 3 || (cmd.args.len() - 1) % 2 != 0 {
-        return Resp::Error("ERR wrong number of arguments for 'hset' command".to_string());
+fn cmd_client_unblock(cmd: &Command, clients: &ClientRegistry) -> Resp {
+    if cmd.args.len() < 2 || cmd.args.len() > 3 {
+        return Resp::Error(
+            "ERR wrong number of arguments for 'client|unblock' command".to_string(),
+        );
     }
 
-    let key = &cmd.args[0];
-    let mut added = 0;
+    let id: u64 = match cmd.args[1].parse() {
+        Ok(id) => id,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
 
-    for chunk in cmd.args[1..].chunks(2) {
-        let field = chunk[0].clone();
-        let value = chunk[1].clone();
-        match storage.hset(key, field, value) {
-            Ok(is_new) => {
-                if is_new {
-                    added += 1;
-                }
-            }
-            Err(e) => return Resp::Error(e),
+    let reason = match cmd.args.get(2).map(|r| r.to_uppercase()).as_deref() {
+        None | Some("TIMEOUT") => UnblockReason::Timeout,
+        Some("ERROR") => UnblockReason::Error,
+        Some(_) => {
+            return Resp::Error("ERR CLIENT UNBLOCK reason should be TIMEOUT or ERROR".to_string());
         }
-    }
+    };
 
-    Resp::Integer(added)
+    Resp::Integer(clients.unblock(id, reason) as i64)
 }
 
-fn cmd_hget(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'hget' command".to_string());
+fn cmd_client_pause(cmd: &Command, clients: &ClientRegistry) -> Resp {
+    if cmd.args.len() < 2 || cmd.args.len() > 3 {
+        return Resp::Error("ERR wrong number of arguments for 'client|pause' command".to_string());
     }
 
-    match storage.hget(&cmd.args[0], &cmd.args[1]) {
-        Ok(Some(v)) => Resp::Bulk(Some(v)),
-        Ok(None) => Resp::Bulk(None),
-        Err(e) => Resp::Error(e),
-    }
+    let ms: u64 = match cmd.args[1].parse() {
+        Ok(ms) => ms,
+        Err(_) => return Resp::Error("ERR timeout is not an integer or out of range".to_string()),
+    };
+
+    let mode = match cmd.args.get(2).map(|m| m.to_uppercase()).as_deref() {
+        None | Some("ALL") => PauseMode::All,
+        Some("WRITE") => PauseMode::Write,
+        Some(_) => return Resp::Error("ERR syntax error".to_string()),
+    };
+
+    clients.pause(mode, Duration::from_millis(ms));
+    Resp::Simple("OK".to_string())
 }
 
-fn cmd_hmset(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 3 || (cmd.args.len() - 1) % 2 != 0 {
-        return Resp::Error("ERR wrong number of arguments for 'hmset' command".to_string());
+fn cmd_client_kill(cmd: &Command, clients: &ClientRegistry, client_id: u64) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'client|kill' command".to_string());
     }
 
-    let key = &cmd.args[0];
-    let pairs: Vec<(String, String)> = cmd.args[1..]
-        .chunks(2)
-        .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
-        .collect();
-
-    match storage.hmset(key, pairs) {
-        Ok(()) => Resp::Simple("OK".to_string()),
-        Err(e) => Resp::Error(e),
+    // Old form: CLIENT KILL ip:port
+    if cmd.args.len() == 2 {
+        let target = &cmd.args[1];
+        return match clients
+            .list()
+            .iter()
+            .find(|c| c.addr.to_string() == *target)
+        {
+            Some(client) => {
+                clients.kill(client.id);
+                Resp::Simple("OK".to_string())
+            }
+            None => Resp::Error("ERR No such client".to_string()),
+        };
     }
-}
 
-fn cmd_hmget(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'hmget' command".to_string());
+    if !(cmd.args.len() - 1).is_multiple_of(2) {
+        return Resp::Error("ERR syntax error".to_string());
     }
 
-    let key = &cmd.args[0];
-    let fields: Vec<String> = cmd.args[1..].to_vec();
+    let mut id: Option<u64> = None;
+    let mut addr: Option<&str> = None;
+    let mut laddr: Option<&str> = None;
+    let mut user: Option<&str> = None;
+    let mut max_age: Option<u64> = None;
+    let mut client_type: Option<&str> = None;
+    let mut skip_me = true;
+
+    for pair in cmd.args[1..].chunks(2) {
+        let value = pair[1].as_str();
+        match pair[0].to_uppercase().as_str() {
+            "ID" => match value.parse::<u64>() {
+                Ok(n) if n > 0 => id = Some(n),
+                _ => return Resp::Error("ERR client-id should be greater than 0".to_string()),
+            },
+            "ADDR" => addr = Some(value),
+            "LADDR" => laddr = Some(value),
+            "USER" => user = Some(value),
+            "MAXAGE" => match value.parse::<u64>() {
+                Ok(n) => max_age = Some(n),
+                Err(_) => {
+                    return Resp::Error("ERR value is not an integer or out of range".to_string());
+                }
+            },
+            "TYPE" => match parse_client_type(value) {
+                Some(name) => client_type = Some(name),
+                None => return Resp::Error(format!("ERR Unknown client type '{}'", value)),
+            },
+            "SKIPME" => match value.to_lowercase().as_str() {
+                "yes" => skip_me = true,
+                "no" => skip_me = false,
+                _ => return Resp::Error("ERR syntax error".to_string()),
+            },
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
+    }
 
-    match storage.hmget(key, &fields) {
-        Ok(values) => {
-            let resp_values: Vec<Resp> = values
-                .into_iter()
-                .map(|v| match v {
-                    Some(s) => Resp::Bulk(Some(s)),
-                    None => Resp::Bulk(None),
-                })
-                .collect();
-            Resp::Array(Some(resp_values))
+    let mut killed = 0;
+    for client in clients.list() {
+        if id.is_some_and(|id| id != client.id)
+            || addr.is_some_and(|a| a != client.addr.to_string())
+            || laddr.is_some_and(|a| a != client.laddr.to_string())
+            || user.is_some_and(|u| u != client.user)
+            || max_age.is_some_and(|age| client.created_at.elapsed().as_secs() < age)
+            || client_type.is_some_and(|name| name != client.client_type())
+            || (skip_me && client.id == client_id)
+        {
+            continue;
+        }
+        if clients.kill(client.id) {
+            killed += 1;
         }
-        Err(e) => Resp::Error(e),
     }
+    Resp::Integer(killed)
 }
 
-fn cmd_hgetall(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'hgetall' command".to_string());
-    }
+fn cmd_client_list(cmd: &Command, clients: &ClientRegistry) -> Resp {
+    let mut ids: Option<Vec<u64>> = None;
+    let mut client_type: Option<&str> = None;
 
-    match storage.hgetall(&cmd.args[0]) {
-        Ok(pairs) => {
-            let mut resp_values: Vec<Resp> = Vec::with_capacity(pairs.len() * 2);
-            for (k, v) in pairs {
-                resp_values.push(Resp::Bulk(Some(k)));
-                resp_values.push(Resp::Bulk(Some(v)));
+    let mut i = 1;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            "TYPE" => {
+                if i + 1 >= cmd.args.len() {
+                    return Resp::Error("ERR syntax error".to_string());
+                }
+                match parse_client_type(&cmd.args[i + 1]) {
+                    Some(name) => client_type = Some(name),
+                    None => {
+                        return Resp::Error(format!(
+                            "ERR Unknown client type '{}'",
+                            cmd.args[i + 1]
+                        ));
+                    }
+                }
+                i += 2;
             }
-            Resp::Array(Some(resp_values))
+            "ID" => {
+                if i + 1 >= cmd.args.len() {
+                    return Resp::Error("ERR syntax error".to_string());
+                }
+                let mut wanted = Vec::new();
+                for arg in &cmd.args[i + 1..] {
+                    match arg.parse::<u64>() {
+                        Ok(id) if id > 0 => wanted.push(id),
+                        _ => return Resp::Error("ERR Invalid client ID".to_string()),
+                    }
+                }
+                ids = Some(wanted);
+                i = cmd.args.len();
+            }
+            _ => return Resp::Error("ERR syntax error".to_string()),
         }
-        Err(e) => Resp::Error(e),
     }
-}
 
-fn cmd_hdel(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'hdel' command".to_string());
+    let mut out = String::new();
+    for client in clients.list() {
+        if ids.as_ref().is_some_and(|ids| !ids.contains(&client.id))
+            || client_type.is_some_and(|name| name != client.client_type())
+        {
+            continue;
+        }
+        out.push_str(&client.to_line());
+        out.push('\n');
     }
+    Resp::Bulk(Some(out))
+}
 
-    let key = &cmd.args[0];
-    let fields: Vec<String> = cmd.args[1..].to_vec();
+fn cmd_info(cmd: &Command, server: &Server) -> Resp {
+    Resp::Bulk(Some(info::info(server, &cmd.args)))
+}
 
-    match storage.hdel(key, fields) {
-        Ok(removed) => Resp::Integer(removed as i64),
-        Err(e) => Resp::Error(e),
-    }
+fn cmd_dbsize(db: &Db) -> Resp {
+    Resp::Integer(db.dbsize() as i64)
 }
 
-fn cmd_hexists(cmd: &Command, storage: &Storage) -> Resp {
+/// SET key value [NX|XX] [GET] [EX seconds|PX milliseconds|KEEPTTL]
+fn cmd_set(cmd: &Command, db: &Db) -> Resp {
     if cmd.args.len() < 2 {
-        return Resp::Error("ERR wrong number of arguments for 'hexists' command".to_string());
+        return Resp::Error("ERR wrong number of arguments for 'set' command".to_string());
     }
 
-    match storage.hexists(&cmd.args[0], &cmd.args[1]) {
-        Ok(true) => Resp::Integer(1),
-        Ok(false) => Resp::Integer(0),
-        Err(e) => Resp::Error(e),
-    }
-}
+    let key = cmd.args[0].clone();
+    let value = cmd.args[1].clone();
 
-fn cmd_hlen(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'hlen' command".to_string());
+    let mut options = SetOptions::default();
+    let mut i = 2;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            option @ ("EX" | "PX") => {
+                if i + 1 >= cmd.args.len() || options.keep_ttl || options.expires_at.is_some() {
+                    return Resp::Error("ERR syntax error".to_string());
+                }
+                let unit_ms = if option == "EX" { 1000 } else { 1 };
+                match parse_ttl(&cmd.args[i + 1], unit_ms, "set") {
+                    Ok(ms) => options.expires_at = Some(storage::now_ms().saturating_add(ms)),
+                    Err(e) => return Resp::Error(e),
+                }
+                i += 1;
+            }
+            "NX" if !options.xx => options.nx = true,
+            "XX" if !options.nx => options.xx = true,
+            "GET" => options.get = true,
+            "KEEPTTL" if options.expires_at.is_none() => options.keep_ttl = true,
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
+        i += 1;
     }
 
-    match storage.hlen(&cmd.args[0]) {
-        Ok(len) => Resp::Integer(len as i64),
+    match db.set_with_options(key, value, options) {
+        Ok((_, old)) if options.get => Resp::Bulk(old),
+        Ok((true, _)) => Resp::Simple("OK".to_string()),
+        Ok((false, _)) => Resp::Bulk(None),
         Err(e) => Resp::Error(e),
     }
 }
 
-fn cmd_hkeys(cmd: &Command, storage: &Storage) -> Resp {
+fn cmd_get(cmd: &Command, db: &Db) -> Resp {
     if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'hkeys' command".to_string());
+        return Resp::Error("ERR wrong number of arguments for 'get' command".to_string());
     }
 
-    match storage.hkeys(&cmd.args[0]) {
-        Ok(keys) => {
-            let resp_keys: Vec<Resp> = keys.into_iter().map(|k| Resp::Bulk(Some(k))).collect();
-            Resp::Array(Some(resp_keys))
-        }
-        Err(e) => Resp::Error(e),
+    match db.get(&cmd.args[0]) {
+        Some(value) => Resp::Bulk(Some(value)),
+        None => Resp::Bulk(None),
     }
 }
 
-fn cmd_hvals(cmd: &Command, storage: &Storage) -> Resp {
-    if cmd.args.is_empty() {
-        return Resp::Error("ERR wrong number of arguments for 'hvals' command".to_string());
+fn cmd_setnx(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'setnx' command".to_string());
     }
 
-    match storage.hvals(&cmd.args[0]) {
-        Ok(vals) => {
-            let resp_vals: Vec<Resp> = vals.into_iter().map(|v| Resp::Bulk(Some(v))).collect();
-            Resp::Array(Some(resp_vals))
-        }
+    let key = cmd.args[0].clone();
+    let value = cmd.args[1].clone();
+
+    let options = SetOptions {
+        nx: true,
+        ..SetOptions::default()
+    };
+    match db.set_with_options(key, value, options) {
+        Ok((written, _)) => Resp::Integer(written as i64),
         Err(e) => Resp::Error(e),
     }
 }
 
-fn cmd_hincrby(cmd: &Command, storage: &Storage) -> Resp {
+fn cmd_setex(cmd: &Command, db: &Db) -> Resp {
     if cmd.args.len() < 3 {
-        return Resp::Error("ERR wrong number of arguments for 'hincrby' command".to_string());
+        return Resp::Error("ERR wrong number of arguments for 'setex' command".to_string());
     }
 
-    let key = &cmd.args[0];
-    let field = &cmd.args[1];
-    let delta: i64 = match cmd.args[2].parse() {
-        Ok(d) => d,
-        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    let key = cmd.args[0].clone();
+    let ms = match parse_ttl(&cmd.args[1], 1000, "setex") {
+        Ok(ms) => ms,
+        Err(e) => return Resp::Error(e),
     };
+    let value = cmd.args[2].clone();
 
-    match storage.hincrby(key, field, delta) {
-        Ok(n) => Resp::Integer(n),
+    db.set_with_expiry(key, value, ms);
+    Resp::Simple("OK".to_string())
+}
+
+fn cmd_psetex(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'psetex' command".to_string());
+    }
+
+    let key = cmd.args[0].clone();
+    let ms = match parse_ttl(&cmd.args[1], 1, "psetex") {
+        Ok(ms) => ms,
+        Err(e) => return Resp::Error(e),
+    };
+    let value = cmd.args[2].clone();
+
+    db.set_with_expiry(key, value, ms);
+    Resp::Simple("OK".to_string())
+}
+
+/// A TTL argument of the SET family, counted in units of `unit_ms`, in
+/// milliseconds. It must be positive, unlike EXPIRE's.
+fn parse_ttl(arg: &str, unit_ms: i64, command: &str) -> Result<u64, String> {
+    let ttl: i64 = arg
+        .parse()
+        .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+    match ttl.checked_mul(unit_ms) {
+        Some(ms) if ms > 0 => Ok(ms as u64),
+        _ => Err(format!("ERR invalid expire time in '{}' command", command)),
+    }
+}
+
+fn cmd_getset(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'getset' command".to_string());
+    }
+
+    let key = cmd.args[0].clone();
+    let value = cmd.args[1].clone();
+
+    let options = SetOptions {
+        get: true,
+        ..SetOptions::default()
+    };
+    match db.set_with_options(key, value, options) {
+        Ok((_, old)) => Resp::Bulk(old),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_mset(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() || !cmd.args.len().is_multiple_of(2) {
+        return Resp::Error("ERR wrong number of arguments for 'mset' command".to_string());
+    }
+
+    let pairs: Vec<(String, String)> = cmd
+        .args
+        .chunks(2)
+        .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
+        .collect();
+
+    db.mset(pairs);
+    Resp::Simple("OK".to_string())
+}
+
+fn cmd_mget(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'mget' command".to_string());
+    }
+
+    let values = db.mget(&cmd.args);
+    let resp_values: Vec<Resp> = values
+        .into_iter()
+        .map(|v| match v {
+            Some(s) => Resp::Bulk(Some(s)),
+            None => Resp::Bulk(None),
+        })
+        .collect();
+
+    Resp::Array(Some(resp_values))
+}
+
+fn cmd_incr(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'incr' command".to_string());
+    }
+
+    match db.incr(&cmd.args[0]) {
+        Ok(n) => Resp::Integer(n),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_incrby(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'incrby' command".to_string());
+    }
+
+    let delta: i64 = match cmd.args[1].parse() {
+        Ok(d) => d,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+
+    match db.incr_by(&cmd.args[0], delta) {
+        Ok(n) => Resp::Integer(n),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_decr(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'decr' command".to_string());
+    }
+
+    match db.decr(&cmd.args[0]) {
+        Ok(n) => Resp::Integer(n),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_decrby(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'decrby' command".to_string());
+    }
+
+    let delta: i64 = match cmd.args[1].parse() {
+        Ok(d) => d,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+
+    match db.incr_by(&cmd.args[0], -delta) {
+        Ok(n) => Resp::Integer(n),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_append(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'append' command".to_string());
+    }
+
+    match db.append(&cmd.args[0], &cmd.args[1]) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_strlen(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'strlen' command".to_string());
+    }
+
+    match db.strlen(&cmd.args[0]) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// BITCOUNT key [start end [BYTE|BIT]]
+fn cmd_bitcount(cmd: &Command, db: &Db) -> Resp {
+    let (range, bits) = match &cmd.args[1..] {
+        [] => (None, false),
+        [start, end, unit @ ..] if unit.len() <= 1 => {
+            let bits = match unit.first().map(|u| u.to_uppercase()) {
+                None => false,
+                Some(u) if u == "BYTE" => false,
+                Some(u) if u == "BIT" => true,
+                Some(_) => return Resp::Error("ERR syntax error".to_string()),
+            };
+            let (Ok(start), Ok(end)) = (start.parse::<i64>(), end.parse::<i64>()) else {
+                return Resp::Error("ERR value is not an integer or out of range".to_string());
+            };
+            (Some((start, end)), bits)
+        }
+        _ => return Resp::Error("ERR syntax error".to_string()),
+    };
+
+    match db.bitcount(&cmd.args[0], range, bits) {
+        Ok(count) => Resp::Integer(count as i64),
         Err(e) => Resp::Error(e),
     }
-}
+}
+
+fn cmd_del(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'del' command".to_string());
+    }
+
+    let count = db.del(&cmd.args);
+    Resp::Integer(count as i64)
+}
+
+fn cmd_exists(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'exists' command".to_string());
+    }
+
+    let count = db.exists(&cmd.args);
+    Resp::Integer(count as i64)
+}
+
+/// Counts the keys that exist, like EXISTS. Their access time and LFU
+/// counter are updated by `call`, as for every command's keys.
+fn cmd_touch(cmd: &Command, db: &Db) -> Resp {
+    Resp::Integer(db.exists(&cmd.args) as i64)
+}
+
+fn cmd_expire(cmd: &Command, server: &Server, db: usize) -> Resp {
+    expire_generic(cmd, server, db, 1000, true)
+}
+
+fn cmd_pexpire(cmd: &Command, server: &Server, db: usize) -> Resp {
+    expire_generic(cmd, server, db, 1, true)
+}
+
+fn cmd_expireat(cmd: &Command, server: &Server, db: usize) -> Resp {
+    expire_generic(cmd, server, db, 1000, false)
+}
+
+fn cmd_pexpireat(cmd: &Command, server: &Server, db: usize) -> Resp {
+    expire_generic(cmd, server, db, 1, false)
+}
+
+/// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT: `unit_ms` is the length of a
+/// unit of the time argument, which is a TTL when `relative` and a Unix
+/// time otherwise.
+///
+/// A primary deletes the key right away when the deadline is in the past,
+/// as with a TTL of zero or less, and propagates that as a DEL. A replica
+/// keeps it, expired, until its primary deletes it: the deadline may only
+/// have passed while the command was on its way.
+fn expire_generic(cmd: &Command, server: &Server, db: usize, unit_ms: i64, relative: bool) -> Resp {
+    let time: i64 = match cmd.args[1].parse() {
+        Ok(time) => time,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+    let options = match parse_expire_options(&cmd.args[2..]) {
+        Ok(options) => options,
+        Err(e) => return Resp::Error(e),
+    };
+    let key = &cmd.args[0];
+    let mut at_ms = time.checked_mul(unit_ms);
+    if relative {
+        at_ms = at_ms.and_then(|ms| ms.checked_add(storage::now_ms() as i64));
+    }
+    let Some(at_ms) = at_ms else {
+        let name = cmd.name.to_lowercase();
+        return Resp::Error(format!("ERR invalid expire time in '{}' command", name));
+    };
+    // Any time before 1970 is as past as 1970
+    let at_ms = at_ms.max(0) as u64;
+    let db = server.storage.db(db);
+    if !db.expire_at(key, at_ms, options) {
+        return Resp::Integer(0);
+    }
+    if at_ms <= storage::now_ms() && !server.replication.is_replica() {
+        db.del(std::slice::from_ref(key));
+    }
+    Resp::Integer(1)
+}
+
+/// The NX, XX, GT and LT options of the EXPIRE family.
+fn parse_expire_options(args: &[String]) -> Result<ExpireOptions, String> {
+    let mut options = ExpireOptions::default();
+    for arg in args {
+        match arg.to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "GT" => options.gt = true,
+            "LT" => options.lt = true,
+            _ => return Err(format!("ERR Unsupported option {}", arg)),
+        }
+    }
+    if options.nx && (options.xx || options.gt || options.lt) {
+        return Err(
+            "ERR NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        );
+    }
+    if options.gt && options.lt {
+        return Err("ERR GT and LT options at the same time are not compatible".to_string());
+    }
+    Ok(options)
+}
+
+fn cmd_ttl(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'ttl' command".to_string());
+    }
+
+    let ttl_ms = db.ttl(&cmd.args[0]);
+    if ttl_ms == -2 || ttl_ms == -1 {
+        Resp::Integer(ttl_ms)
+    } else {
+        Resp::Integer(ttl_ms / 1000)
+    }
+}
+
+fn cmd_pttl(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'pttl' command".to_string());
+    }
+
+    Resp::Integer(db.ttl(&cmd.args[0]))
+}
+
+fn cmd_persist(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'persist' command".to_string());
+    }
+
+    if db.persist(&cmd.args[0]) {
+        Resp::Integer(1)
+    } else {
+        Resp::Integer(0)
+    }
+}
+
+fn cmd_keys(cmd: &Command, db: &Db) -> Resp {
+    let pattern = cmd.args.first().map(|s| s.as_str()).unwrap_or("*");
+    let keys = db.keys(&Pattern::new(pattern));
+    let resp_keys: Vec<Resp> = keys.into_iter().map(|k| Resp::Bulk(Some(k))).collect();
+    Resp::Array(Some(resp_keys))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]. COUNT is how
+/// many keys to look at, so a step may return fewer, or none, that match.
+fn cmd_scan(cmd: &Command, db: &Db) -> Resp {
+    let Ok(cursor) = cmd.args[0].parse::<u64>() else {
+        return Resp::Error("ERR invalid cursor".to_string());
+    };
+    let mut pattern = "*";
+    let mut count = 10;
+    let mut type_name = None;
+    let mut i = 1;
+    while i < cmd.args.len() {
+        let Some(value) = cmd.args.get(i + 1) else {
+            return Resp::Error("ERR syntax error".to_string());
+        };
+        match cmd.args[i].to_uppercase().as_str() {
+            "MATCH" => pattern = value,
+            "COUNT" => match value.parse::<i64>() {
+                Ok(n) if n >= 1 => count = n as usize,
+                Ok(_) => return Resp::Error("ERR syntax error".to_string()),
+                Err(_) => {
+                    return Resp::Error("ERR value is not an integer or out of range".to_string());
+                }
+            },
+            "TYPE" => type_name = Some(value.to_lowercase()),
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
+        i += 2;
+    }
+
+    let pattern = Pattern::new(pattern);
+    let (next, keys) = db.scan(cursor, count, &pattern, type_name.as_deref());
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(next.to_string())),
+        Resp::Array(Some(
+            keys.into_iter().map(|k| Resp::Bulk(Some(k))).collect(),
+        )),
+    ]))
+}
+
+fn cmd_object(cmd: &Command, db: &Db) -> Resp {
+    match cmd.args[0].to_uppercase().as_str() {
+        "ENCODING" if cmd.args.len() == 2 => match db.encoding(&cmd.args[1]) {
+            Some(encoding) => Resp::Bulk(Some(encoding.to_string())),
+            None => Resp::Bulk(None),
+        },
+        "ENCODING" => Resp::Error("ERR syntax error".to_string()),
+        _ => Resp::Error(format!("ERR Unknown subcommand '{}'", cmd.args[0])),
+    }
+}
+
+fn cmd_type(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'type' command".to_string());
+    }
+
+    match db.get_type(&cmd.args[0]) {
+        Some(t) => Resp::Simple(t.to_string()),
+        None => Resp::Simple("none".to_string()),
+    }
+}
+
+fn cmd_dump(cmd: &Command, server: &Server, index: usize) -> Resp {
+    let compression = server.config.read().unwrap().rdbcompression;
+    let payload = server
+        .storage
+        .db(index)
+        .with_value(&cmd.args[0], |value, _| rdb::dump(value, compression));
+    match payload {
+        Some(payload) => Resp::Bytes(payload),
+        None => Resp::Bulk(None),
+    }
+}
+
+/// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency]. IDLETIME and FREQ set what the LRU and LFU eviction
+/// policies go by.
+fn cmd_restore(cmd: &Command, db: &Db) -> Resp {
+    let key = &cmd.args[0];
+    let mut replace = false;
+    let mut absttl = false;
+    let mut idle = None;
+    let mut freq = None;
+    let mut i = 3;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absttl = true,
+            "IDLETIME" if i + 1 < cmd.args.len() => {
+                i += 1;
+                match cmd.args[i].parse::<i64>() {
+                    Ok(seconds) if seconds >= 0 => idle = Some(seconds as u64),
+                    Ok(_) => {
+                        return Resp::Error("ERR Invalid IDLETIME value, must be >= 0".to_string());
+                    }
+                    Err(_) => {
+                        return Resp::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        );
+                    }
+                }
+            }
+            "FREQ" if i + 1 < cmd.args.len() => {
+                i += 1;
+                match cmd.args[i].parse::<i64>() {
+                    Ok(counter) if (0..=255).contains(&counter) => freq = Some(counter as u8),
+                    Ok(_) => {
+                        return Resp::Error(
+                            "ERR Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                        );
+                    }
+                    Err(_) => {
+                        return Resp::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        );
+                    }
+                }
+            }
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
+        i += 1;
+    }
+
+    let ttl: i64 = match cmd.args[1].parse() {
+        Ok(ttl) if ttl >= 0 => ttl,
+        Ok(_) => return Resp::Error("ERR Invalid TTL value, must be >= 0".to_string()),
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+    if !replace && db.exists(std::slice::from_ref(key)) > 0 {
+        return Resp::Error("BUSYKEY Target key name already exists.".to_string());
+    }
+
+    let value = match rdb::undump(&binary::encode(&cmd.args[2])) {
+        Ok(value) => value,
+        Err(e) => return Resp::Error(e),
+    };
+
+    let now = storage::now_ms();
+    let expires_at = match (ttl as u64, absttl) {
+        (0, _) => None,
+        (at, true) => Some(at),
+        (ttl, false) => Some(now.saturating_add(ttl)),
+    };
+    if expires_at.is_some_and(|at| at <= now) {
+        // Already expired: the key is only replaced by nothing
+        db.del(std::slice::from_ref(key));
+    } else {
+        db.restore(key.clone(), value, expires_at);
+        db.set_access(key, idle, freq);
+    }
+    Resp::Simple("OK".to_string())
+}
+
+fn cmd_rename(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'rename' command".to_string());
+    }
+
+    match db.rename(&cmd.args[0], &cmd.args[1]) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_renamenx(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'renamenx' command".to_string());
+    }
+
+    match db.renamenx(&cmd.args[0], &cmd.args[1]) {
+        Ok(true) => Resp::Integer(1),
+        Ok(false) => Resp::Integer(0),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// COPY source destination [DB destination-db] [REPLACE]
+fn cmd_copy(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let (src, dst) = (&cmd.args[0], &cmd.args[1]);
+    let mut dst_db = ctx.db;
+    let mut replace = false;
+    let mut i = 2;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "DB" if i + 1 < cmd.args.len() => {
+                i += 1;
+                dst_db = match parse_db_index(&cmd.args[i], &server.storage) {
+                    Ok(index) => index,
+                    Err(e) => return e,
+                };
+            }
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
+        i += 1;
+    }
+    if src == dst && dst_db == ctx.db {
+        return Resp::Error("ERR source and destination objects are the same".to_string());
+    }
+
+    if !server.storage.copy(ctx.db, src, dst_db, dst, replace) {
+        return Resp::Integer(0);
+    }
+    // call() only knows about keys of the selected database
+    if dst_db != ctx.db {
+        server.storage.touch_key(dst_db, dst);
+        server.blocking.signal(dst_db, dst);
+        notify::notify(server, KeyspaceEvent::Set, dst_db, dst);
+    }
+    Resp::Integer(1)
+}
+
+fn cmd_move(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let key = &cmd.args[0];
+    let dst_db = match parse_db_index(&cmd.args[1], &server.storage) {
+        Ok(index) => index,
+        Err(e) => return e,
+    };
+    if dst_db == ctx.db {
+        return Resp::Error("ERR source and destination objects are the same".to_string());
+    }
+
+    if !server.storage.move_key(key, ctx.db, dst_db) {
+        return Resp::Integer(0);
+    }
+    server.storage.touch_key(dst_db, key);
+    server.blocking.signal(dst_db, key);
+    notify::notify(server, KeyspaceEvent::Set, dst_db, key);
+    Resp::Integer(1)
+}
+
+fn cmd_flushdb(storage: &Storage, index: usize) -> Resp {
+    storage.flushdb(index);
+    Resp::Simple("OK".to_string())
+}
+
+fn cmd_flushall(storage: &Storage) -> Resp {
+    storage.flushall();
+    Resp::Simple("OK".to_string())
+}
+
+/// Parses a database index argument, checking it against the configured
+/// number of databases.
+fn parse_db_index(arg: &str, storage: &Storage) -> Result<usize, Resp> {
+    let index: i64 = arg
+        .parse()
+        .map_err(|_| Resp::Error("ERR value is not an integer or out of range".to_string()))?;
+    if index < 0 || index as usize >= storage.len() {
+        return Err(Resp::Error("ERR DB index is out of range".to_string()));
+    }
+    Ok(index as usize)
+}
+
+fn cmd_select(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    if cmd.args.len() != 1 {
+        return Resp::Error("ERR wrong number of arguments for 'select' command".to_string());
+    }
+
+    match parse_db_index(&cmd.args[0], &server.storage) {
+        Ok(index) => {
+            ctx.db = index;
+            server.clients.set_db(ctx.id, index);
+            Resp::Simple("OK".to_string())
+        }
+        Err(e) => e,
+    }
+}
+
+fn cmd_swapdb(cmd: &Command, storage: &Storage) -> Resp {
+    if cmd.args.len() != 2 {
+        return Resp::Error("ERR wrong number of arguments for 'swapdb' command".to_string());
+    }
+
+    let a = match parse_db_index(&cmd.args[0], storage) {
+        Ok(index) => index,
+        Err(_) => return Resp::Error("ERR invalid first DB index".to_string()),
+    };
+    let b = match parse_db_index(&cmd.args[1], storage) {
+        Ok(index) => index,
+        Err(_) => return Resp::Error("ERR invalid second DB index".to_string()),
+    };
+
+    storage.swap_db(a, b);
+    Resp::Simple("OK".to_string())
+}
+
+fn cmd_lpush(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'lpush' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let values: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.lpush(key, values) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_rpush(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'rpush' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let values: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.rpush(key, values) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_lpop(cmd: &Command, db: &Db) -> Resp {
+    cmd_pop(cmd, db, End::Left)
+}
+
+fn cmd_rpop(cmd: &Command, db: &Db) -> Resp {
+    cmd_pop(cmd, db, End::Right)
+}
+
+/// LPOP and RPOP: one element, or with a count an array of up to that
+/// many.
+fn cmd_pop(cmd: &Command, db: &Db, end: End) -> Resp {
+    let key = &cmd.args[0];
+    let count = match &cmd.args[1..] {
+        [] => None,
+        [count] => match count.parse::<usize>() {
+            Ok(count) => Some(count),
+            Err(_) => {
+                return Resp::Error("ERR value is out of range, must be positive".to_string());
+            }
+        },
+        _ => {
+            return Resp::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                end.pop_command()
+            ));
+        }
+    };
+
+    // Popping the last elements removes the key, so whether it was there
+    // is asked first
+    let exists = db.get_type(key).is_some();
+    match db.pop(key, end, count.unwrap_or(1)) {
+        Ok(values) if count.is_none() => Resp::Bulk(values.into_iter().next()),
+        Ok(_) if !exists => Resp::Array(None),
+        Ok(values) => Resp::Array(Some(
+            values.into_iter().map(|v| Resp::Bulk(Some(v))).collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// BLPOP, BRPOP, BZPOPMIN and BZPOPMAX: pops from the first of the keys
+/// holding a list or sorted set that isn't empty or, if none does, leaves
+/// the connection blocked on all of them until one is written to or the
+/// timeout runs out.
+fn cmd_blocking_pop(
+    cmd: &Command,
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    source: Source,
+) -> Resp {
+    let (timeout, keys) = cmd.args.split_last().expect("arity is checked");
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return e,
+    };
+
+    match pop_first(server, ctx, keys, source, None) {
+        Ok(Some(popped)) => element(popped),
+        Ok(None) => block(server, ctx, keys, source, None, timeout, element),
+        Err(e) => e,
+    }
+}
+
+/// LMPOP and ZMPOP, their end read by `parse_source`.
+fn cmd_mpop(
+    cmd: &Command,
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    parse_source: fn(&str) -> Option<Source>,
+) -> Resp {
+    let (keys, source, count) = match parse_mpop(&cmd.args, parse_source) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+    match pop_first(server, ctx, keys, source, Some(count)) {
+        Ok(Some(popped)) => elements(popped),
+        Ok(None) => Resp::Array(None),
+        Err(e) => e,
+    }
+}
+
+/// BLMPOP and BZMPOP, their end read by `parse_source`.
+fn cmd_blocking_mpop(
+    cmd: &Command,
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    parse_source: fn(&str) -> Option<Source>,
+) -> Resp {
+    let timeout = match parse_timeout(&cmd.args[0]) {
+        Ok(timeout) => timeout,
+        Err(e) => return e,
+    };
+    let (keys, source, count) = match parse_mpop(&cmd.args[1..], parse_source) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+    match pop_first(server, ctx, keys, source, Some(count)) {
+        Ok(Some(popped)) => elements(popped),
+        Ok(None) => block(server, ctx, keys, source, Some(count), timeout, elements),
+        Err(e) => e,
+    }
+}
+
+/// A popped sorted set member and its score, as replies have them.
+fn scored(member: String, score: f64) -> [Resp; 2] {
+    [
+        Resp::Bulk(Some(member)),
+        Resp::Bulk(Some(zset::format_score(score))),
+    ]
+}
+
+/// The reply of BLPOP, BRPOP, BZPOPMIN and BZPOPMAX: the key and the
+/// element popped from it, a sorted set member followed by its score.
+fn element((key, elements): Popped) -> Resp {
+    let mut reply = vec![Resp::Bulk(Some(key))];
+    match elements {
+        Elements::List(values) => reply.extend(values.into_iter().map(|v| Resp::Bulk(Some(v)))),
+        Elements::ZSet(members) => reply.extend(
+            members
+                .into_iter()
+                .flat_map(|(member, score)| scored(member, score)),
+        ),
+        Elements::Stream(_) => unreachable!("stream entries are read, not popped"),
+    }
+    Resp::Array(Some(reply))
+}
+
+/// The reply of LMPOP, BLMPOP, ZMPOP and BZMPOP: the key and the elements
+/// popped from it, sorted set members each paired with its score. Also
+/// a stream's part of the XREAD reply, its key and the entries read.
+fn elements((key, elements): Popped) -> Resp {
+    let elements = match elements {
+        Elements::List(values) => values.into_iter().map(|v| Resp::Bulk(Some(v))).collect(),
+        Elements::ZSet(members) => members
+            .into_iter()
+            .map(|(member, score)| Resp::Array(Some(scored(member, score).into())))
+            .collect(),
+        Elements::Stream(entries) => entries
+            .into_iter()
+            .map(|(id, fields)| stream_entry(id, fields))
+            .collect(),
+    };
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(key)),
+        Resp::Array(Some(elements)),
+    ]))
+}
+
+/// The list end LEFT or RIGHT names.
+fn list_source(arg: &str) -> Option<Source> {
+    match arg.to_uppercase().as_str() {
+        "LEFT" => Some(Source::List(End::Left)),
+        "RIGHT" => Some(Source::List(End::Right)),
+        _ => None,
+    }
+}
+
+/// The sorted set end MIN or MAX names.
+fn zset_source(arg: &str) -> Option<Source> {
+    match arg.to_uppercase().as_str() {
+        "MIN" => Some(Source::ZSet(ScoreEnd::Min)),
+        "MAX" => Some(Source::ZSet(ScoreEnd::Max)),
+        _ => None,
+    }
+}
+
+/// Parses `numkeys key [key ...] where [COUNT count]`, the arguments of
+/// LMPOP and ZMPOP and of their blocking forms after the timeout, the end
+/// to pop from read by `parse_source`.
+fn parse_mpop(
+    args: &[String],
+    parse_source: fn(&str) -> Option<Source>,
+) -> Result<(&[String], Source, usize), Resp> {
+    let numkeys = args[0]
+        .parse::<usize>()
+        .ok()
+        .filter(|&numkeys| numkeys > 0)
+        .ok_or_else(|| Resp::Error("ERR numkeys should be greater than 0".to_string()))?;
+    let syntax_error = || Resp::Error("ERR syntax error".to_string());
+    if numkeys >= args.len() - 1 {
+        return Err(syntax_error());
+    }
+    let keys = &args[1..=numkeys];
+    let source = parse_source(&args[numkeys + 1]).ok_or_else(syntax_error)?;
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [option, count] if option.eq_ignore_ascii_case("COUNT") => count
+            .parse::<usize>()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(|| Resp::Error("ERR count should be greater than 0".to_string()))?,
+        _ => return Err(syntax_error()),
+    };
+    Ok((keys, source, count))
+}
+
+/// Pops from the first of `keys` holding a list or sorted set, as `source`
+/// says, that isn't empty, `count` elements or, if None, one, and has the
+/// command propagated as that pop.
+fn pop_first(
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    keys: &[String],
+    source: Source,
+    count: Option<usize>,
+) -> Result<Option<Popped>, Resp> {
+    let db = server.storage.db(ctx.db);
+    for key in keys {
+        let elements = blocking::pop(db, key, source, count.unwrap_or(1)).map_err(Resp::Error)?;
+        if elements.is_empty() {
+            continue;
+        }
+        let mut pop = vec![source.pop_command().to_string(), key.clone()];
+        if count.is_some() {
+            pop.push(elements.len().to_string());
+        }
+        ctx.propagate_as = Some(vec![pop]);
+        return Ok(Some((key.clone(), elements)));
+    }
+    Ok(None)
+}
+
+/// Leaves the connection blocked on `keys` until one is written to or the
+/// timeout runs out, unless it can't block, replying a null array for now.
+fn block(
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    keys: &[String],
+    source: Source,
+    count: Option<usize>,
+    timeout: Option<Duration>,
+    reply: fn(Popped) -> Resp,
+) -> Resp {
+    if ctx.deny_blocking {
+        return Resp::Array(None);
+    }
+    let popped = server.blocking.block(ctx.id, ctx.db, keys, source, count);
+    // Written to since they were popped from, before anyone was waiting to
+    // be told
+    let db = server.storage.db(ctx.db);
+    for key in keys {
+        let len = match source {
+            Source::List(_) => db.llen(key),
+            Source::ZSet(_) => db.zcard(key),
+        };
+        if len.is_ok_and(|len| len > 0) {
+            server.blocking.signal(ctx.db, key);
+        }
+    }
+    ctx.blocked = Some(Blocked {
+        timeout,
+        popped,
+        reply,
+    });
+    Resp::Array(None)
+}
+
+/// Parses a blocking command's timeout, in seconds with fractions allowed,
+/// 0 being no timeout.
+fn parse_timeout(arg: &str) -> Result<Option<Duration>, Resp> {
+    let secs: f64 = arg
+        .parse()
+        .map_err(|_| Resp::Error("ERR timeout is not a float or out of range".to_string()))?;
+    if secs < 0.0 {
+        return Err(Resp::Error("ERR timeout is negative".to_string()));
+    }
+    let timeout = Duration::try_from_secs_f64(secs)
+        .map_err(|_| Resp::Error("ERR timeout is not a float or out of range".to_string()))?;
+    Ok((!timeout.is_zero()).then_some(timeout))
+}
+
+fn cmd_llen(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'llen' command".to_string());
+    }
+
+    match db.llen(&cmd.args[0]) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_lrange(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'lrange' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let start: i64 = match cmd.args[1].parse() {
+        Ok(s) => s,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+    let stop: i64 = match cmd.args[2].parse() {
+        Ok(s) => s,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+
+    match db.lrange(key, start, stop) {
+        Ok(values) => {
+            let resp_values: Vec<Resp> = values.into_iter().map(|v| Resp::Bulk(Some(v))).collect();
+            Resp::Array(Some(resp_values))
+        }
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_lindex(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'lindex' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let index: i64 = match cmd.args[1].parse() {
+        Ok(i) => i,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+
+    match db.lindex(key, index) {
+        Ok(Some(v)) => Resp::Bulk(Some(v)),
+        Ok(None) => Resp::Bulk(None),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_lset(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'lset' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let index: i64 = match cmd.args[1].parse() {
+        Ok(i) => i,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+    let value = cmd.args[2].clone();
+
+    match db.lset(key, index, value) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_ltrim(cmd: &Command, db: &Db) -> Resp {
+    let (Ok(start), Ok(stop)) = (cmd.args[1].parse::<i64>(), cmd.args[2].parse::<i64>()) else {
+        return Resp::Error("ERR value is not an integer or out of range".to_string());
+    };
+
+    match db.ltrim(&cmd.args[0], start, stop) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_sadd(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'sadd' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let members: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.sadd(key, members) {
+        Ok(added) => Resp::Integer(added as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_srem(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'srem' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let members: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.srem(key, members) {
+        Ok(removed) => Resp::Integer(removed as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_smembers(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'smembers' command".to_string());
+    }
+
+    match db.smembers(&cmd.args[0]) {
+        Ok(members) => {
+            let resp_members: Vec<Resp> =
+                members.into_iter().map(|m| Resp::Bulk(Some(m))).collect();
+            Resp::Array(Some(resp_members))
+        }
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_sismember(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'sismember' command".to_string());
+    }
+
+    match db.sismember(&cmd.args[0], &cmd.args[1]) {
+        Ok(true) => Resp::Integer(1),
+        Ok(false) => Resp::Integer(0),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_scard(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'scard' command".to_string());
+    }
+
+    match db.scard(&cmd.args[0]) {
+        Ok(card) => Resp::Integer(card as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// SINTER, SUNION and SDIFF: the members of the given sets combined.
+fn cmd_combine_sets(
+    cmd: &Command,
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    op: SetOp,
+) -> Resp {
+    match server.storage.db(ctx.db).combine_sets(op, &cmd.args) {
+        Ok(members) => Resp::Array(Some(
+            members.into_iter().map(|m| Resp::Bulk(Some(m))).collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// SINTERSTORE, SUNIONSTORE and SDIFFSTORE: the members of the sets after
+/// the destination combined and stored in it.
+fn cmd_combine_sets_store(
+    cmd: &Command,
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    op: SetOp,
+) -> Resp {
+    let db = server.storage.db(ctx.db);
+    match db.combine_sets_store(op, &cmd.args[0], &cmd.args[1..]) {
+        Ok(stored) => Resp::Integer(stored as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// SRANDMEMBER key [count]: one random member, or with a count that many
+/// distinct ones, or for a negative count its magnitude with repeats.
+fn cmd_srandmember(cmd: &Command, db: &Db) -> Resp {
+    let key = &cmd.args[0];
+    let count = match &cmd.args[1..] {
+        [] => None,
+        [count] => match count.parse::<i64>() {
+            // Redis refuses counts this large rather than allocate for them
+            Ok(count) if count < -(i64::MAX / 2) => {
+                return Resp::Error("ERR value is out of range".to_string());
+            }
+            Ok(count) => Some(count),
+            Err(_) => {
+                return Resp::Error("ERR value is not an integer or out of range".to_string());
+            }
+        },
+        _ => return Resp::Error("ERR syntax error".to_string()),
+    };
+
+    let picked = match count {
+        None => db.srandmember(key, 1, false),
+        Some(count) => db.srandmember(key, count.unsigned_abs() as usize, count < 0),
+    };
+    match picked {
+        Ok(members) if count.is_none() => Resp::Bulk(members.into_iter().next()),
+        Ok(members) => Resp::Array(Some(
+            members.into_iter().map(|m| Resp::Bulk(Some(m))).collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hset(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 || !(cmd.args.len() - 1).is_multiple_of(2) {
+        return Resp::Error("ERR wrong number of arguments for 'hset' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let mut added = 0;
+
+    for chunk in cmd.args[1..].chunks(2) {
+        let field = chunk[0].clone();
+        let value = chunk[1].clone();
+        match db.hset(key, field, value) {
+            Ok(is_new) => {
+                if is_new {
+                    added += 1;
+                }
+            }
+            Err(e) => return Resp::Error(e),
+        }
+    }
+
+    Resp::Integer(added)
+}
+
+fn cmd_hget(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'hget' command".to_string());
+    }
+
+    match db.hget(&cmd.args[0], &cmd.args[1]) {
+        Ok(Some(v)) => Resp::Bulk(Some(v)),
+        Ok(None) => Resp::Bulk(None),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hmset(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 || !(cmd.args.len() - 1).is_multiple_of(2) {
+        return Resp::Error("ERR wrong number of arguments for 'hmset' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let pairs: Vec<(String, String)> = cmd.args[1..]
+        .chunks(2)
+        .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
+        .collect();
+
+    match db.hmset(key, pairs) {
+        Ok(()) => Resp::Simple("OK".to_string()),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hmget(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'hmget' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let fields: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.hmget(key, &fields) {
+        Ok(values) => {
+            let resp_values: Vec<Resp> = values
+                .into_iter()
+                .map(|v| match v {
+                    Some(s) => Resp::Bulk(Some(s)),
+                    None => Resp::Bulk(None),
+                })
+                .collect();
+            Resp::Array(Some(resp_values))
+        }
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hgetall(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'hgetall' command".to_string());
+    }
+
+    match db.hgetall(&cmd.args[0]) {
+        Ok(pairs) => Resp::Map(
+            pairs
+                .into_iter()
+                .map(|(k, v)| (Resp::Bulk(Some(k)), Resp::Bulk(Some(v))))
+                .collect(),
+        ),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hdel(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'hdel' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let fields: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.hdel(key, fields) {
+        Ok(removed) => Resp::Integer(removed as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hexists(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'hexists' command".to_string());
+    }
+
+    match db.hexists(&cmd.args[0], &cmd.args[1]) {
+        Ok(true) => Resp::Integer(1),
+        Ok(false) => Resp::Integer(0),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hlen(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'hlen' command".to_string());
+    }
+
+    match db.hlen(&cmd.args[0]) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hkeys(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'hkeys' command".to_string());
+    }
+
+    match db.hkeys(&cmd.args[0]) {
+        Ok(keys) => {
+            let resp_keys: Vec<Resp> = keys.into_iter().map(|k| Resp::Bulk(Some(k))).collect();
+            Resp::Array(Some(resp_keys))
+        }
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hvals(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'hvals' command".to_string());
+    }
+
+    match db.hvals(&cmd.args[0]) {
+        Ok(vals) => {
+            let resp_vals: Vec<Resp> = vals.into_iter().map(|v| Resp::Bulk(Some(v))).collect();
+            Resp::Array(Some(resp_vals))
+        }
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_hincrby(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'hincrby' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let field = &cmd.args[1];
+    let delta: i64 = match cmd.args[2].parse() {
+        Ok(d) => d,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+
+    match db.hincrby(key, field, delta) {
+        Ok(n) => Resp::Integer(n),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]
+fn cmd_zadd(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'zadd' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let mut options = ZAddOptions::default();
+    let mut i = 1;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "GT" => options.gt = true,
+            "LT" => options.lt = true,
+            "CH" => options.ch = true,
+            "INCR" => options.incr = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let pairs = &cmd.args[i..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Resp::Error("ERR syntax error".to_string());
+    }
+    if options.nx && options.xx {
+        return Resp::Error(
+            "ERR XX and NX options at the same time are not compatible".to_string(),
+        );
+    }
+    if [options.nx, options.gt, options.lt]
+        .iter()
+        .filter(|&&set| set)
+        .count()
+        > 1
+    {
+        return Resp::Error(
+            "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        );
+    }
+    if options.incr && pairs.len() > 2 {
+        return Resp::Error("ERR INCR option supports a single increment-element pair".to_string());
+    }
+
+    let mut members = Vec::with_capacity(pairs.len() / 2);
+    for pair in pairs.chunks(2) {
+        match zset::parse_score(&pair[0]) {
+            Some(score) => members.push((score, pair[1].clone())),
+            None => return Resp::Error("ERR value is not a valid float".to_string()),
+        }
+    }
+
+    // INCR replies the new score, or nil if the options didn't allow it
+    if options.incr {
+        let (delta, member) = &members[0];
+        return match db.zincrby(key, member, *delta, options) {
+            Ok(score) => Resp::Bulk(score.map(zset::format_score)),
+            Err(e) => Resp::Error(e),
+        };
+    }
+    match db.zadd(key, members, options) {
+        Ok(changed) => Resp::Integer(changed as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zincrby(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'zincrby' command".to_string());
+    }
+
+    let delta = match zset::parse_score(&cmd.args[1]) {
+        Some(delta) => delta,
+        None => return Resp::Error("ERR value is not a valid float".to_string()),
+    };
+
+    match db.zincrby(&cmd.args[0], &cmd.args[2], delta, ZAddOptions::default()) {
+        Ok(score) => Resp::Bulk(score.map(zset::format_score)),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zscore(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'zscore' command".to_string());
+    }
+
+    match db.zscore(&cmd.args[0], &cmd.args[1]) {
+        Ok(score) => Resp::Bulk(score.map(zset::format_score)),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zmscore(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'zmscore' command".to_string());
+    }
+
+    match db.zmscore(&cmd.args[0], &cmd.args[1..]) {
+        Ok(scores) => Resp::Array(Some(
+            scores
+                .into_iter()
+                .map(|score| Resp::Bulk(score.map(zset::format_score)))
+                .collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zrem(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error("ERR wrong number of arguments for 'zrem' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let members: Vec<String> = cmd.args[1..].to_vec();
+
+    match db.zrem(key, members) {
+        Ok(removed) => Resp::Integer(removed as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zcard(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'zcard' command".to_string());
+    }
+
+    match db.zcard(&cmd.args[0]) {
+        Ok(card) => Resp::Integer(card as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zcount(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'zcount' command".to_string());
+    }
+
+    let range = match (
+        ScoreBound::parse(&cmd.args[1]),
+        ScoreBound::parse(&cmd.args[2]),
+    ) {
+        (Some(min), Some(max)) => ScoreRange { min, max },
+        _ => return Resp::Error("ERR min or max is not a float".to_string()),
+    };
+    match db.zcount(&cmd.args[0], RangeBy::Score(range)) {
+        Ok(count) => Resp::Integer(count as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_zlexcount(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'zlexcount' command".to_string());
+    }
+
+    let range = match (LexBound::parse(&cmd.args[1]), LexBound::parse(&cmd.args[2])) {
+        (Some(min), Some(max)) => LexRange { min, max },
+        _ => return Resp::Error("ERR min or max not valid string range item".to_string()),
+    };
+    match db.zcount(&cmd.args[0], RangeBy::Lex(range)) {
+        Ok(count) => Resp::Integer(count as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// ZPOPMIN and ZPOPMAX: the member with the lowest or highest score, or
+/// with a count up to that many, each followed by its score.
+fn cmd_zpop(cmd: &Command, db: &Db, end: ScoreEnd) -> Resp {
+    let count = match &cmd.args[1..] {
+        [] => 1,
+        [count] => match count.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                return Resp::Error("ERR value is out of range, must be positive".to_string());
+            }
+        },
+        _ => return Resp::Error("ERR syntax error".to_string()),
+    };
+
+    match db.zpop(&cmd.args[0], end, count) {
+        Ok(members) => Resp::Array(Some(
+            members
+                .into_iter()
+                .flat_map(|(member, score)| scored(member, score))
+                .collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// The keys ZUNION, ZINTER and ZDIFF combine, their weights, how their
+/// scores aggregate, and whether WITHSCORES was given.
+type CombineZSets<'a> = (&'a [String], Vec<f64>, Aggregate, bool);
+
+/// Parses `numkeys key [key ...]` and the options after, the arguments of
+/// ZUNION, ZINTER and ZDIFF and of their STORE forms after the
+/// destination, into the keys, their weights, how to aggregate their
+/// scores and whether to reply with them. ZDIFF takes no WEIGHTS or
+/// AGGREGATE, and the STORE forms no WITHSCORES.
+fn parse_combine_zsets<'a>(
+    cmd: &Command,
+    args: &'a [String],
+    op: SetOp,
+    store: bool,
+) -> Result<CombineZSets<'a>, Resp> {
+    let syntax_error = || Resp::Error("ERR syntax error".to_string());
+    let numkeys = args[0]
+        .parse::<usize>()
+        .map_err(|_| Resp::Error("ERR value is not an integer or out of range".to_string()))?;
+    if numkeys == 0 {
+        return Err(Resp::Error(format!(
+            "ERR at least 1 input key is needed for '{}' command",
+            cmd.name.to_lowercase()
+        )));
+    }
+    if numkeys >= args.len() {
+        return Err(syntax_error());
+    }
+    let keys = &args[1..=numkeys];
+
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut withscores = false;
+    let mut i = numkeys + 1;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "WEIGHTS" if op != SetOp::Diff && i + numkeys < args.len() => {
+                for (weight, arg) in weights.iter_mut().zip(&args[i + 1..]) {
+                    *weight = zset::parse_score(arg).ok_or_else(|| {
+                        Resp::Error("ERR weight value is not a float".to_string())
+                    })?;
+                }
+                i += numkeys;
+            }
+            "AGGREGATE" if op != SetOp::Diff && i + 1 < args.len() => {
+                aggregate = match args[i + 1].to_uppercase().as_str() {
+                    "SUM" => Aggregate::Sum,
+                    "MIN" => Aggregate::Min,
+                    "MAX" => Aggregate::Max,
+                    _ => return Err(syntax_error()),
+                };
+                i += 1;
+            }
+            "WITHSCORES" if !store => withscores = true,
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+    Ok((keys, weights, aggregate, withscores))
+}
+
+/// ZUNION, ZINTER and ZDIFF: the members of the sorted sets, or sets,
+/// combined, lowest score first.
+fn cmd_combine_zsets(cmd: &Command, db: &Db, op: SetOp) -> Resp {
+    let (keys, weights, aggregate, withscores) =
+        match parse_combine_zsets(cmd, &cmd.args, op, false) {
+            Ok(parsed) => parsed,
+            Err(e) => return e,
+        };
+    match db.combine_zsets(op, keys, &weights, aggregate) {
+        Ok(members) => Resp::Array(Some(
+            members
+                .into_iter()
+                .flat_map(|(member, score)| {
+                    let [member, score] = scored(member, score);
+                    std::iter::once(member).chain(withscores.then_some(score))
+                })
+                .collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// ZUNIONSTORE, ZINTERSTORE and ZDIFFSTORE: the sorted sets, or sets,
+/// after the destination combined and stored in it.
+fn cmd_combine_zsets_store(cmd: &Command, db: &Db, op: SetOp) -> Resp {
+    let (keys, weights, aggregate, _) = match parse_combine_zsets(cmd, &cmd.args[1..], op, true) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+    match db.combine_zsets_store(op, &cmd.args[0], keys, &weights, aggregate) {
+        Ok(stored) => Resp::Integer(stored as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// ZRANK and ZREVRANK key member [WITHSCORE]: where the member ranks from
+/// the lowest score or the highest, and with WITHSCORE its score too.
+fn cmd_zrank(cmd: &Command, db: &Db, rev: bool) -> Resp {
+    if cmd.args.len() < 2 {
+        return Resp::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            cmd.name.to_lowercase()
+        ));
+    }
+    let withscore = match &cmd.args[2..] {
+        [] => false,
+        [option] if option.eq_ignore_ascii_case("WITHSCORE") => true,
+        _ => return Resp::Error("ERR syntax error".to_string()),
+    };
+
+    match db.zrank(&cmd.args[0], &cmd.args[1], rev) {
+        Ok(Some((rank, score))) if withscore => Resp::Array(Some(vec![
+            Resp::Integer(rank as i64),
+            Resp::Bulk(Some(zset::format_score(score))),
+        ])),
+        Ok(Some((rank, _))) => Resp::Integer(rank as i64),
+        Ok(None) if withscore => Resp::Array(None),
+        Ok(None) => Resp::Bulk(None),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// What a ZRANGE-family command ranges over: ZRANGE and ZRANGESTORE take
+/// it from their BYSCORE and BYLEX options, the commands they replaced
+/// from their names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeType {
+    Auto,
+    Index,
+    Score,
+    Lex,
+}
+
+/// Parses the arguments after the key of a ZRANGE-family command, the
+/// range's two ends and then its options, into the query they make and
+/// whether to reply with scores. `rev` commands take their ends highest
+/// first; ZRANGESTORE, `store`, takes no WITHSCORES.
+fn parse_zrange(
+    args: &[String],
+    mut range_type: RangeType,
+    mut rev: bool,
+    store: bool,
+) -> Result<(RangeQuery, bool), String> {
+    let syntax_error = || "ERR syntax error".to_string();
+    let auto = range_type == RangeType::Auto;
+    let mut withscores = false;
+    let mut limit = None;
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "WITHSCORES" if !store => withscores = true,
+            "LIMIT" if i + 2 < args.len() => {
+                match (args[i + 1].parse::<i64>(), args[i + 2].parse::<i64>()) {
+                    (Ok(offset), Ok(count)) => limit = Some((offset, count)),
+                    _ => return Err("ERR value is not an integer or out of range".to_string()),
+                }
+                i += 2;
+            }
+            "REV" if auto => rev = true,
+            "BYSCORE" if range_type == RangeType::Auto => range_type = RangeType::Score,
+            "BYLEX" if range_type == RangeType::Auto => range_type = RangeType::Lex,
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    if limit.is_some() && matches!(range_type, RangeType::Auto | RangeType::Index) {
+        return Err(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .to_string(),
+        );
+    }
+    if withscores && range_type == RangeType::Lex {
+        return Err(
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+        );
+    }
+
+    // Ranges of scores and members run from the highest end when reversed
+    let (min, max) = match range_type {
+        RangeType::Score | RangeType::Lex if rev => (&args[1], &args[0]),
+        _ => (&args[0], &args[1]),
+    };
+    let by = match range_type {
+        RangeType::Auto | RangeType::Index => match (min.parse(), max.parse()) {
+            (Ok(start), Ok(stop)) => RangeBy::Index(start, stop),
+            _ => return Err("ERR value is not an integer or out of range".to_string()),
+        },
+        RangeType::Score => match (ScoreBound::parse(min), ScoreBound::parse(max)) {
+            (Some(min), Some(max)) => RangeBy::Score(ScoreRange { min, max }),
+            _ => return Err("ERR min or max is not a float".to_string()),
+        },
+        RangeType::Lex => match (LexBound::parse(min), LexBound::parse(max)) {
+            (Some(min), Some(max)) => RangeBy::Lex(LexRange { min, max }),
+            _ => return Err("ERR min or max not valid string range item".to_string()),
+        },
+    };
+    Ok((RangeQuery { by, rev, limit }, withscores))
+}
+
+/// ZRANGE, and ZREVRANGE, ZRANGEBYSCORE, ZREVRANGEBYSCORE, ZRANGEBYLEX and
+/// ZREVRANGEBYLEX, which it replaced: the members in a range, each
+/// followed by its score WITHSCORES.
+fn cmd_zrange(cmd: &Command, db: &Db, range_type: RangeType, rev: bool) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            cmd.name.to_lowercase()
+        ));
+    }
+
+    let (query, withscores) = match parse_zrange(&cmd.args[1..], range_type, rev, false) {
+        Ok(parsed) => parsed,
+        Err(e) => return Resp::Error(e),
+    };
+    match db.zrange(&cmd.args[0], &query) {
+        Ok(members) => {
+            let mut items = Vec::with_capacity(members.len() * if withscores { 2 } else { 1 });
+            for (member, score) in members {
+                items.push(Resp::Bulk(Some(member)));
+                if withscores {
+                    items.push(Resp::Bulk(Some(zset::format_score(score))));
+                }
+            }
+            Resp::Array(Some(items))
+        }
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// ZRANGESTORE dst src min max [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+fn cmd_zrangestore(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 4 {
+        return Resp::Error("ERR wrong number of arguments for 'zrangestore' command".to_string());
+    }
+
+    let (query, _) = match parse_zrange(&cmd.args[2..], RangeType::Auto, false, true) {
+        Ok(parsed) => parsed,
+        Err(e) => return Resp::Error(e),
+    };
+    match db.zrangestore(&cmd.args[0], &cmd.args[1], &query) {
+        Ok(stored) => Resp::Integer(stored as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// Parses a longitude and latitude, refusing those out of the range a
+/// geohash covers.
+fn parse_position(lon: &str, lat: &str) -> Result<(f64, f64), String> {
+    let (Ok(lon), Ok(lat)) = (lon.parse::<f64>(), lat.parse::<f64>()) else {
+        return Err("ERR value is not a valid float".to_string());
+    };
+    if !geo::valid(lon, lat) {
+        return Err(format!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            lon, lat
+        ));
+    }
+    Ok((lon, lat))
+}
+
+/// A position as GEOPOS and WITHCOORD reply with it.
+fn position(lon: f64, lat: f64) -> Resp {
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(geo::format_coordinate(lon))),
+        Resp::Bulk(Some(geo::format_coordinate(lat))),
+    ]))
+}
+
+/// GEOADD key [NX | XX] [CH] longitude latitude member [longitude latitude
+/// member ...]: ZADD with the positions' geohashes as scores.
+fn cmd_geoadd(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 4 {
+        return Resp::Error("ERR wrong number of arguments for 'geoadd' command".to_string());
+    }
+
+    let key = &cmd.args[0];
+    let mut options = ZAddOptions::default();
+    let mut i = 1;
+    while i < cmd.args.len() {
+        match cmd.args[i].to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "CH" => options.ch = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let triples = &cmd.args[i..];
+    if triples.is_empty() || !triples.len().is_multiple_of(3) {
+        return Resp::Error("ERR syntax error".to_string());
+    }
+    if options.nx && options.xx {
+        return Resp::Error(
+            "ERR XX and NX options at the same time are not compatible".to_string(),
+        );
+    }
+
+    let mut members = Vec::with_capacity(triples.len() / 3);
+    for triple in triples.chunks(3) {
+        match parse_position(&triple[0], &triple[1]) {
+            Ok((lon, lat)) => members.push((geo::encode(lon, lat) as f64, triple[2].clone())),
+            Err(e) => return Resp::Error(e),
+        }
+    }
+    match db.zadd(key, members, options) {
+        Ok(changed) => Resp::Integer(changed as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// GEOPOS key [member ...]: each member's position, or nil if it isn't
+/// there.
+fn cmd_geopos(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.is_empty() {
+        return Resp::Error("ERR wrong number of arguments for 'geopos' command".to_string());
+    }
+
+    match db.zmscore(&cmd.args[0], &cmd.args[1..]) {
+        Ok(scores) => Resp::Array(Some(
+            scores
+                .into_iter()
+                .map(|score| match score {
+                    Some(score) => {
+                        let (lon, lat) = geo::decode(score as u64);
+                        position(lon, lat)
+                    }
+                    None => Resp::Array(None),
+                })
+                .collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// GEODIST key member1 member2 [M | KM | FT | MI]: the distance between
+/// two members, or nil if either isn't there.
+fn cmd_geodist(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 3 {
+        return Resp::Error("ERR wrong number of arguments for 'geodist' command".to_string());
+    }
+
+    let unit = match &cmd.args[3..] {
+        [] => 1.0,
+        [unit] => match geo::parse_unit(unit) {
+            Ok(unit) => unit,
+            Err(e) => return Resp::Error(e),
+        },
+        _ => return Resp::Error("ERR syntax error".to_string()),
+    };
+    match db.zmscore(&cmd.args[0], &cmd.args[1..3]) {
+        Ok(scores) => match scores[..] {
+            [Some(first), Some(second)] => {
+                let (lon1, lat1) = geo::decode(first as u64);
+                let (lon2, lat2) = geo::decode(second as u64);
+                let distance = geo::distance(lon1, lat1, lon2, lat2) / unit;
+                Resp::Bulk(Some(geo::format_distance(distance)))
+            }
+            _ => Resp::Bulk(None),
+        },
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// What GEOSEARCH replies with for each member besides its name, and
+/// whether GEOSEARCHSTORE stores distances rather than geohashes.
+#[derive(Debug, Clone, Copy, Default)]
+struct GeoSearchReply {
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+/// Parses the arguments of GEOSEARCH after the key, or of GEOSEARCHSTORE
+/// after the source:
+///
+/// FROMMEMBER member | FROMLONLAT longitude latitude
+/// BYRADIUS radius unit | BYBOX width height unit
+/// [ASC | DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+///
+/// with STOREDIST in place of the WITH options for GEOSEARCHSTORE.
+fn parse_geosearch(
+    cmd: &Command,
+    args: &[String],
+    store: bool,
+) -> Result<(GeoSearch, GeoSearchReply), String> {
+    let mut member = None;
+    let mut lon_lat = None;
+    let mut radius = None;
+    let mut dimensions = None;
+    let mut unit = 1.0;
+    let mut sort = None;
+    let mut count = None;
+    let mut any = false;
+    let mut reply = GeoSearchReply::default();
+
+    let mut i = 0;
+    while i < args.len() {
+        let remaining = args.len() - i - 1;
+        match args[i].to_uppercase().as_str() {
+            "FROMMEMBER" if remaining >= 1 && member.is_none() => {
+                member = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "FROMLONLAT" if remaining >= 2 && lon_lat.is_none() => {
+                lon_lat = Some(parse_position(&args[i + 1], &args[i + 2])?);
+                i += 2;
+            }
+            "BYRADIUS" if remaining >= 2 && radius.is_none() => {
+                let value = args[i + 1]
+                    .parse::<f64>()
+                    .map_err(|_| "ERR need numeric radius".to_string())?;
+                if value < 0.0 {
+                    return Err("ERR radius cannot be negative".to_string());
+                }
+                unit = geo::parse_unit(&args[i + 2])?;
+                radius = Some(value * unit);
+                i += 2;
+            }
+            "BYBOX" if remaining >= 3 && dimensions.is_none() => {
+                let width = args[i + 1]
+                    .parse::<f64>()
+                    .map_err(|_| "ERR need numeric width".to_string())?;
+                let height = args[i + 2]
+                    .parse::<f64>()
+                    .map_err(|_| "ERR need numeric height".to_string())?;
+                if width < 0.0 || height < 0.0 {
+                    return Err("ERR height or width cannot be negative".to_string());
+                }
+                unit = geo::parse_unit(&args[i + 3])?;
+                dimensions = Some((width * unit, height * unit));
+                i += 3;
+            }
+            "ASC" => sort = Some(Sort::Asc),
+            "DESC" => sort = Some(Sort::Desc),
+            "COUNT" if remaining >= 1 => {
+                let value = args[i + 1]
+                    .parse::<i64>()
+                    .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+                if value <= 0 {
+                    return Err("ERR COUNT must be > 0".to_string());
+                }
+                count = Some(value as usize);
+                i += 1;
+            }
+            "ANY" => any = true,
+            "WITHCOORD" => reply.with_coord = true,
+            "WITHDIST" => reply.with_dist = true,
+            "WITHHASH" => reply.with_hash = true,
+            "STOREDIST" if store => reply.store_dist = true,
+            _ => return Err("ERR syntax error".to_string()),
+        }
+        i += 1;
+    }
+
+    let name = cmd.name.to_uppercase();
+    if store && (reply.with_coord || reply.with_dist || reply.with_hash) {
+        return Err(format!(
+            "ERR {} is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+            name
+        ));
+    }
+    let origin = match (member, lon_lat) {
+        (Some(member), None) => Origin::Member(member),
+        (None, Some((lon, lat))) => Origin::Position(lon, lat),
+        _ => {
+            return Err(format!(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                name
+            ));
+        }
+    };
+    let shape = match (radius, dimensions) {
+        (Some(radius), None) => Shape::Radius(radius),
+        (None, Some((width, height))) => Shape::Box { width, height },
+        _ => {
+            return Err(format!(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+                name
+            ));
+        }
+    };
+    if any && count.is_none() {
+        return Err("ERR the ANY argument requires COUNT argument".to_string());
+    }
+    // The nearest are the ones kept unless any will do
+    if count.is_some() && !any && sort.is_none() {
+        sort = Some(Sort::Asc);
+    }
+
+    let search = GeoSearch {
+        origin,
+        shape,
+        unit,
+        sort,
+        count,
+        any,
+    };
+    Ok((search, reply))
+}
+
+/// GEOSEARCH key <origin> <shape> [options]: the members in the shape,
+/// each alone or, with any WITH option, in an array with its distance,
+/// geohash and position after it, in that order.
+fn cmd_geosearch(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 6 {
+        return Resp::Error("ERR wrong number of arguments for 'geosearch' command".to_string());
+    }
+
+    let (search, reply) = match parse_geosearch(cmd, &cmd.args[1..], false) {
+        Ok(parsed) => parsed,
+        Err(e) => return Resp::Error(e),
+    };
+    let found = match db.geosearch(&cmd.args[0], &search) {
+        Ok(found) => found,
+        Err(e) => return Resp::Error(e),
+    };
+    let bare = !(reply.with_coord || reply.with_dist || reply.with_hash);
+    Resp::Array(Some(
+        found
+            .into_iter()
+            .map(|place| {
+                let member = Resp::Bulk(Some(place.member));
+                if bare {
+                    return member;
+                }
+                let mut item = vec![member];
+                if reply.with_dist {
+                    item.push(Resp::Bulk(Some(geo::format_distance(place.distance))));
+                }
+                if reply.with_hash {
+                    item.push(Resp::Integer(place.score as i64));
+                }
+                if reply.with_coord {
+                    item.push(position(place.lon, place.lat));
+                }
+                Resp::Array(Some(item))
+            })
+            .collect(),
+    ))
+}
+
+/// GEOSEARCHSTORE destination source <origin> <shape> [options]
+/// [STOREDIST]: stores the members GEOSEARCH would find in the
+/// destination, with their geohashes or distances as scores.
+fn cmd_geosearchstore(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() < 7 {
+        return Resp::Error(
+            "ERR wrong number of arguments for 'geosearchstore' command".to_string(),
+        );
+    }
+
+    let (search, reply) = match parse_geosearch(cmd, &cmd.args[2..], true) {
+        Ok(parsed) => parsed,
+        Err(e) => return Resp::Error(e),
+    };
+    match db.geosearch_store(&cmd.args[0], &cmd.args[1], &search, reply.store_dist) {
+        Ok(stored) => Resp::Integer(stored as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+const INVALID_STREAM_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+
+/// A stream entry as replied: its ID, then its fields and values.
+fn stream_entry(id: StreamId, fields: Fields) -> Resp {
+    let flat = fields
+        .into_iter()
+        .flat_map(|(field, value)| [Resp::Bulk(Some(field)), Resp::Bulk(Some(value))])
+        .collect();
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(id.to_string())),
+        Resp::Array(Some(flat)),
+    ]))
+}
+
+/// Parses the options of XTRIM, or those of XADD before the ID with
+/// `xadd` set: [NOMKSTREAM] [<MAXLEN | MINID> [= | ~] threshold [LIMIT
+/// count]], NOMKSTREAM only for XADD. Returns the trim asked for, whether
+/// NOMKSTREAM was, and where the options end. `~` trims at most LIMIT
+/// entries, 100 nodes' worth if not given and as many as it takes if 0.
+fn parse_trim_options(args: &[String], xadd: bool) -> Result<(Option<Trim>, bool, usize), Resp> {
+    let error = |e: &str| Resp::Error(e.to_string());
+    let mut to = None;
+    let mut approx = false;
+    let mut limit = None;
+    let mut nomkstream = false;
+    let mut pos = 1;
+    while pos < args.len() {
+        let option = args[pos].to_uppercase();
+        let more = pos + 1 < args.len();
+        match option.as_str() {
+            "MAXLEN" | "MINID" if more => {
+                let maxlen = option == "MAXLEN";
+                if to.is_some_and(|to| matches!(to, TrimTo::MaxLen(_)) != maxlen) {
+                    return Err(error(
+                        "ERR syntax error, MAXLEN and MINID options at the same time are not compatible",
+                    ));
+                }
+                pos += 1;
+                if (args[pos] == "~" || args[pos] == "=") && pos + 1 < args.len() {
+                    approx = args[pos] == "~";
+                    pos += 1;
+                }
+                to = Some(if maxlen {
+                    match args[pos].parse::<i64>() {
+                        Ok(len) if len < 0 => {
+                            return Err(error("ERR The MAXLEN argument must be >= 0."));
+                        }
+                        Ok(len) => TrimTo::MaxLen(len as u64),
+                        Err(_) => return Err(error("ERR value is not an integer or out of range")),
+                    }
+                } else {
+                    match StreamId::parse(&args[pos], 0) {
+                        Some(id) => TrimTo::MinId(id),
+                        None => return Err(error(INVALID_STREAM_ID)),
+                    }
+                });
+            }
+            "LIMIT" if more => {
+                pos += 1;
+                limit = match args[pos].parse::<i64>() {
+                    Ok(n) if n < 0 => return Err(error("ERR The LIMIT argument must be >= 0.")),
+                    Ok(n) => Some(n as usize),
+                    Err(_) => return Err(error("ERR value is not an integer or out of range")),
+                };
+            }
+            "NOMKSTREAM" if xadd => nomkstream = true,
+            // The ID
+            _ if xadd => break,
+            _ => return Err(error("ERR syntax error")),
+        }
+        pos += 1;
+    }
+    if limit.is_some() && to.is_none() {
+        return Err(error(
+            "ERR syntax error, LIMIT cannot be used without specifying a trimming strategy",
+        ));
+    }
+    if limit.is_some() && !approx {
+        return Err(error(
+            "ERR syntax error, LIMIT cannot be used without the special ~ option",
+        ));
+    }
+    let limit = match limit {
+        Some(0) => None,
+        Some(limit) => Some(limit),
+        None if approx => Some(100 * stream::NODE_ENTRIES),
+        None => None,
+    };
+    let trim = to.map(|to| Trim { to, approx, limit });
+    Ok((trim, nomkstream, pos))
+}
+
+/// The arguments of an exact trim, as XADD and XTRIM are propagated with.
+fn trim_args(trim: Trim) -> [String; 3] {
+    let (strategy, threshold) = match trim.to {
+        TrimTo::MaxLen(len) => ("MAXLEN", len.to_string()),
+        TrimTo::MinId(id) => ("MINID", id.to_string()),
+    };
+    debug_assert!(!trim.approx && trim.limit.is_none());
+    [strategy.to_string(), "=".to_string(), threshold]
+}
+
+/// XADD key [NOMKSTREAM] [<MAXLEN | MINID> [= | ~] threshold [LIMIT
+/// count]] <* | id> field value [field value ...]: appends an entry and
+/// replies its ID, or nil if NOMKSTREAM found no stream, then trims the
+/// stream as XTRIM does. The command is propagated with the ID the entry
+/// got, and the exact trim it made.
+fn cmd_xadd(cmd: &Command, db: &Db, ctx: &mut ConnectionContext) -> Resp {
+    let args = &cmd.args;
+    let (trim, nomkstream, pos) = match parse_trim_options(args, true) {
+        Ok(options) => options,
+        Err(e) => return e,
+    };
+    let fields = args.get(pos + 1..).unwrap_or_default();
+    if fields.is_empty() || !fields.len().is_multiple_of(2) {
+        return Resp::Error("ERR wrong number of arguments for 'xadd' command".to_string());
+    }
+    let id = match NewId::parse(&args[pos]) {
+        Some(NewId::Explicit(StreamId::MIN)) => {
+            return Resp::Error(
+                "ERR The ID specified in XADD must be greater than 0-0".to_string(),
+            );
+        }
+        Some(id) => id,
+        None => return Resp::Error(INVALID_STREAM_ID.to_string()),
+    };
+    let fields: Fields = fields
+        .chunks_exact(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+
+    match db.xadd(&args[0], id, fields, nomkstream, trim) {
+        Ok(Some((id, trimmed))) => {
+            let mut propagate = vec!["xadd".to_string(), args[0].clone()];
+            if nomkstream {
+                propagate.push("NOMKSTREAM".to_string());
+            }
+            if let Some(trimmed) = trimmed {
+                propagate.extend(trim_args(trimmed));
+            }
+            propagate.push(id.to_string());
+            propagate.extend(args[pos + 1..].iter().cloned());
+            ctx.propagate_as = Some(vec![propagate]);
+            Resp::Bulk(Some(id.to_string()))
+        }
+        Ok(None) => Resp::Bulk(None),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// XTRIM key <MAXLEN | MINID> [= | ~] threshold [LIMIT count]: removes the
+/// first entries of a stream down to `threshold` entries, or those with
+/// IDs below it, and replies how many. With `~`, only whole nodes of 100
+/// entries are removed, up to LIMIT. Propagated as the exact trim it made.
+fn cmd_xtrim(cmd: &Command, db: &Db, ctx: &mut ConnectionContext) -> Resp {
+    let trim = match parse_trim_options(&cmd.args, false) {
+        Ok((Some(trim), _, _)) => trim,
+        Ok((None, _, _)) => {
+            return Resp::Error(
+                "ERR syntax error, XTRIM must be called with a trimming strategy".to_string(),
+            );
+        }
+        Err(e) => return e,
+    };
+
+    match db.with_stream_mut(&cmd.args[0], |stream| stream.trim(trim)) {
+        Ok(Some((removed, exact))) => {
+            let mut propagate = vec!["xtrim".to_string(), cmd.args[0].clone()];
+            propagate.extend(trim_args(exact));
+            ctx.propagate_as = Some(vec![propagate]);
+            Resp::Integer(removed as i64)
+        }
+        Ok(None) => Resp::Integer(0),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+fn cmd_xlen(cmd: &Command, db: &Db) -> Resp {
+    if cmd.args.len() != 1 {
+        return Resp::Error("ERR wrong number of arguments for 'xlen' command".to_string());
+    }
+
+    match db.xlen(&cmd.args[0]) {
+        Ok(len) => Resp::Integer(len as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// Parses one end of an XRANGE interval: `-`, `+`, an ID, or a bare
+/// millisecond time taking `missing_seq` as its sequence number. The last
+/// two may follow `(` to leave that ID out, which says true.
+fn parse_interval_id(arg: &str, missing_seq: u64) -> Result<(StreamId, bool), Resp> {
+    let invalid = || Resp::Error(INVALID_STREAM_ID.to_string());
+    match arg {
+        "-" => Ok((StreamId::MIN, false)),
+        "+" => Ok((StreamId::MAX, false)),
+        _ => match arg.strip_prefix('(') {
+            Some(id) => Ok((StreamId::parse(id, missing_seq).ok_or_else(invalid)?, true)),
+            None => Ok((
+                StreamId::parse(arg, missing_seq).ok_or_else(invalid)?,
+                false,
+            )),
+        },
+    }
+}
+
+/// XRANGE key start end [COUNT count] and XREVRANGE key end start [COUNT
+/// count]: the entries between two IDs, inclusive unless prefixed by `(`,
+/// in order or, with `rev`, from the end down.
+fn cmd_xrange(cmd: &Command, db: &Db, rev: bool) -> Resp {
+    let name = if rev { "xrevrange" } else { "xrange" };
+    if cmd.args.len() < 3 {
+        return Resp::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+
+    let (start, end) = if rev {
+        (&cmd.args[2], &cmd.args[1])
+    } else {
+        (&cmd.args[1], &cmd.args[2])
+    };
+    let start = match parse_interval_id(start, 0) {
+        Ok((id, false)) => id,
+        Ok((id, true)) => match id.next() {
+            Some(id) => id,
+            None => return Resp::Error("ERR invalid start ID for the interval".to_string()),
+        },
+        Err(e) => return e,
+    };
+    let end = match parse_interval_id(end, u64::MAX) {
+        Ok((id, false)) => id,
+        Ok((id, true)) => match id.prev() {
+            Some(id) => id,
+            None => return Resp::Error("ERR invalid end ID for the interval".to_string()),
+        },
+        Err(e) => return e,
+    };
+    let mut count = None;
+    let mut options = cmd.args[3..].iter();
+    while let Some(option) = options.next() {
+        match options.next() {
+            Some(value) if option.eq_ignore_ascii_case("COUNT") => match value.parse::<i64>() {
+                Ok(n) => count = Some(n.max(0) as usize),
+                Err(_) => {
+                    return Resp::Error("ERR value is not an integer or out of range".to_string());
+                }
+            },
+            _ => return Resp::Error("ERR syntax error".to_string()),
+        }
+    }
+    if count == Some(0) {
+        return Resp::Array(None);
+    }
+
+    match db.xrange(&cmd.args[0], start, end, rev, count) {
+        Ok(entries) => Resp::Array(Some(
+            entries
+                .into_iter()
+                .map(|(id, fields)| stream_entry(id, fields))
+                .collect(),
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// What XREAD and XREADGROUP are asked for before their streams.
+struct ReadOptions<'a> {
+    /// The group and consumer XREADGROUP reads as.
+    group: Option<(&'a str, &'a str)>,
+    count: Option<usize>,
+    /// With BLOCK, how long to wait, None for as long as it takes.
+    timeout: Option<Option<Duration>>,
+    noack: bool,
+    keys: &'a [String],
+    ids: &'a [String],
+}
+
+/// Parses [GROUP group consumer] [COUNT count] [BLOCK milliseconds]
+/// [NOACK] STREAMS key [key ...] id [id ...], GROUP and NOACK only for
+/// XREADGROUP.
+fn parse_read_options(args: &[String], xreadgroup: bool) -> Result<ReadOptions<'_>, Resp> {
+    let syntax_error = || Resp::Error("ERR syntax error".to_string());
+    let mut options = ReadOptions {
+        group: None,
+        count: None,
+        timeout: None,
+        noack: false,
+        keys: &[],
+        ids: &[],
+    };
+    let mut pos = 0;
+    loop {
+        let Some(option) = args.get(pos) else {
+            return Err(syntax_error());
+        };
+        if option.eq_ignore_ascii_case("STREAMS") {
+            pos += 1;
+            break;
+        }
+        if xreadgroup && option.eq_ignore_ascii_case("NOACK") {
+            options.noack = true;
+            pos += 1;
+            continue;
+        }
+        let Some(value) = args.get(pos + 1) else {
+            return Err(syntax_error());
+        };
+        if option.eq_ignore_ascii_case("COUNT") {
+            match value.parse::<i64>() {
+                // 0 takes them all, as no COUNT does
+                Ok(n) => options.count = (n > 0).then_some(n as usize),
+                Err(_) => {
+                    return Err(Resp::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    ));
+                }
+            }
+        } else if option.eq_ignore_ascii_case("BLOCK") {
+            match value.parse::<i64>() {
+                Ok(ms) if ms < 0 => return Err(Resp::Error("ERR timeout is negative".to_string())),
+                Ok(ms) => {
+                    options.timeout = Some((ms > 0).then(|| Duration::from_millis(ms as u64)))
+                }
+                Err(_) => {
+                    return Err(Resp::Error(
+                        "ERR timeout is not an integer or out of range".to_string(),
+                    ));
+                }
+            }
+        } else if xreadgroup && option.eq_ignore_ascii_case("GROUP") {
+            let Some(consumer) = args.get(pos + 2) else {
+                return Err(syntax_error());
+            };
+            options.group = Some((value, consumer));
+            pos += 1;
+        } else {
+            return Err(syntax_error());
+        }
+        pos += 2;
+    }
+    let streams = &args[pos..];
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        let name = if xreadgroup { "xreadgroup" } else { "xread" };
+        return Err(Resp::Error(format!(
+            "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+            name
+        )));
+    }
+    (options.keys, options.ids) = streams.split_at(streams.len() / 2);
+    Ok(options)
+}
+
+/// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id
+/// [id ...]: the entries of each stream added after its ID, `$` standing
+/// for the last one it gave out, or nil if none has any. With BLOCK, waits
+/// for an XADD to one of them instead, 0 waiting forever.
+fn cmd_xread(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let ReadOptions {
+        count,
+        timeout,
+        keys,
+        ids,
+        ..
+    } = match parse_read_options(&cmd.args, false) {
+        Ok(options) => options,
+        Err(e) => return e,
+    };
+    let db = server.storage.db(ctx.db);
+    let mut after = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        let id = match id.as_str() {
+            "$" => db.xlast_id(key),
+            ">" => Err("ERR The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option.".to_string()),
+            _ => StreamId::parse(id, 0).ok_or_else(|| INVALID_STREAM_ID.to_string()),
+        };
+        match id {
+            Ok(id) => after.push(id),
+            Err(e) => return Resp::Error(e),
+        }
+    }
+    match read_streams(db, keys, &after, count) {
+        Ok(read) if !read.is_empty() => return Resp::Array(Some(read)),
+        Ok(_) => {}
+        Err(e) => return Resp::Error(e),
+    }
+    let Some(timeout) = timeout.filter(|_| !ctx.deny_blocking) else {
+        return Resp::Array(None);
+    };
+
+    let served = server
+        .blocking
+        .block_read(ctx.id, ctx.db, keys, after.clone(), count);
+    // Added to since they were read, before anyone was waiting to be told
+    if let Ok(read) = read_streams(db, keys, &after, count)
+        && !read.is_empty()
+    {
+        server.blocking.unblock(ctx.id);
+        return Resp::Array(Some(read));
+    }
+    ctx.blocked = Some(Blocked {
+        timeout,
+        popped: served,
+        reply: |read| Resp::Array(Some(vec![elements(read)])),
+    });
+    Resp::Array(None)
+}
+
+/// The XREAD reply for each of the streams at `keys` with entries added
+/// after the ID at the same position in `after`: the key and up to `count`
+/// of them.
+fn read_streams(
+    db: &Db,
+    keys: &[String],
+    after: &[StreamId],
+    count: Option<usize>,
+) -> Result<Vec<Resp>, String> {
+    let mut read = Vec::new();
+    for (key, after) in keys.iter().zip(after) {
+        let Some(start) = after.next() else {
+            continue;
+        };
+        let entries = db.xrange(key, start, StreamId::MAX, false, count)?;
+        if !entries.is_empty() {
+            read.push(elements((key.clone(), Elements::Stream(entries))));
+        }
+    }
+    Ok(read)
+}
+
+/// The commands making the changes a consumer group command made besides
+/// its reply, which it's propagated as so they replay the same: XGROUP
+/// CREATECONSUMER for a new consumer, XCLAIM for each entry delivered or
+/// claimed, XACK for those dropped, and XGROUP SETID if the group's last
+/// delivered ID moved.
+pub fn group_changes(
+    key: &str,
+    group: &str,
+    consumer: &str,
+    changes: &GroupChanges,
+) -> Vec<Vec<String>> {
+    let strings = |parts: &[&str]| parts.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let mut commands = Vec::new();
+    if changes.created_consumer {
+        commands.push(strings(&["xgroup", "createconsumer", key, group, consumer]));
+    }
+    for (id, pending) in &changes.claimed {
+        commands.push(strings(&[
+            "xclaim",
+            key,
+            group,
+            &pending.consumer,
+            "0",
+            &id.to_string(),
+            "TIME",
+            &pending.delivery_time.to_string(),
+            "RETRYCOUNT",
+            &pending.delivery_count.to_string(),
+            "FORCE",
+            "JUSTID",
+        ]));
+    }
+    if !changes.dropped.is_empty() {
+        let mut xack = strings(&["xack", key, group]);
+        xack.extend(changes.dropped.iter().map(StreamId::to_string));
+        commands.push(xack);
+    }
+    if let Some((id, entries_read)) = changes.last_id {
+        let mut setid = strings(&["xgroup", "setid", key, group, &id.to_string()]);
+        if let Some(read) = entries_read {
+            setid.extend(strings(&["ENTRIESREAD", &read.to_string()]));
+        }
+        commands.push(setid);
+    }
+    commands
+}
+
+/// A stream ID, or `$` for the stream's last one as None.
+fn parse_group_id(arg: &str) -> Result<Option<StreamId>, Resp> {
+    match arg {
+        "$" => Ok(None),
+        _ => StreamId::parse(arg, 0)
+            .map(Some)
+            .ok_or_else(|| Resp::Error(INVALID_STREAM_ID.to_string())),
+    }
+}
+
+/// XGROUP's [MKSTREAM] [ENTRIESREAD entries-read], MKSTREAM only for
+/// CREATE: whether to create the stream, and the entries read, -1 for
+/// not known.
+fn parse_group_options(options: &[String], create: bool) -> Result<(bool, Option<u64>), Resp> {
+    let mut mkstream = false;
+    let mut entries_read = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if create && option.eq_ignore_ascii_case("MKSTREAM") {
+            mkstream = true;
+        } else if option.eq_ignore_ascii_case("ENTRIESREAD")
+            && let Some(value) = options.next()
+        {
+            entries_read = match value.parse::<i64>() {
+                Ok(-1) => None,
+                Ok(n) if n >= 0 => Some(n as u64),
+                Ok(_) => {
+                    return Err(Resp::Error(
+                        "ERR value for ENTRIESREAD must be positive or -1".to_string(),
+                    ));
+                }
+                Err(_) => {
+                    return Err(Resp::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    ));
+                }
+            };
+        } else {
+            return Err(Resp::Error("ERR syntax error".to_string()));
+        }
+    }
+    Ok((mkstream, entries_read))
+}
+
+/// XGROUP CREATE key group <id | $> [MKSTREAM] [ENTRIESREAD n], SETID key
+/// group <id | $> [ENTRIESREAD n], DESTROY key group and CREATECONSUMER
+/// key group consumer: manages a stream's consumer groups.
+fn cmd_xgroup(cmd: &Command, db: &Db) -> Resp {
+    let args = &cmd.args;
+    let subcommand = args[0].to_uppercase();
+    let arity = match subcommand.as_str() {
+        "CREATE" | "SETID" => 4..=usize::MAX,
+        "DESTROY" => 3..=3,
+        "CREATECONSUMER" => 4..=4,
+        _ => return Resp::Error(format!("ERR Unknown subcommand '{}'", args[0])),
+    };
+    if !arity.contains(&args.len()) {
+        return Resp::Error(format!(
+            "ERR wrong number of arguments for 'xgroup|{}' command",
+            subcommand.to_lowercase()
+        ));
+    }
+    let (key, group) = (&args[1], &args[2]);
+    let no_key = || {
+        Resp::Error("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string())
+    };
+    let no_group = || {
+        Resp::Error(format!(
+            "NOGROUP No such consumer group '{}' for key name '{}'",
+            group, key
+        ))
+    };
+
+    let result = match subcommand.as_str() {
+        "CREATE" => {
+            let id = match parse_group_id(&args[3]) {
+                Ok(id) => id,
+                Err(e) => return e,
+            };
+            let (mkstream, entries_read) = match parse_group_options(&args[4..], true) {
+                Ok(options) => options,
+                Err(e) => return e,
+            };
+            match db.xgroup_create(key, group, id, entries_read, mkstream) {
+                Ok(Some(true)) => return Resp::Simple("OK".to_string()),
+                Ok(Some(false)) => {
+                    return Resp::Error("BUSYGROUP Consumer Group name already exists".to_string());
+                }
+                Ok(None) => return no_key(),
+                Err(e) => return Resp::Error(e),
+            }
+        }
+        "SETID" => {
+            let id = match parse_group_id(&args[3]) {
+                Ok(id) => id,
+                Err(e) => return e,
+            };
+            let entries_read = match parse_group_options(&args[4..], false) {
+                Ok((_, entries_read)) => entries_read,
+                Err(e) => return e,
+            };
+            db.with_stream_mut(key, |stream| {
+                let id = id.unwrap_or(stream.last_id());
+                if stream.set_group_id(group, id, entries_read) {
+                    Resp::Simple("OK".to_string())
+                } else {
+                    no_group()
+                }
+            })
+        }
+        "DESTROY" => db.with_stream_mut(key, |stream| {
+            Resp::Integer(stream.destroy_group(group) as i64)
+        }),
+        _ => db.with_stream_mut(key, |stream| {
+            match stream.create_consumer(group, &args[3], storage::now_ms()) {
+                Some(created) => Resp::Integer(created as i64),
+                None => no_group(),
+            }
+        }),
+    };
+    match result {
+        Ok(Some(reply)) => reply,
+        Ok(None) => no_key(),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// A stream's part of the XREADGROUP reply: its key and the entries read,
+/// those gone from the stream since they were delivered with nil fields.
+fn group_entries(key: &str, entries: GroupEntries) -> Resp {
+    let entries = entries
+        .into_iter()
+        .map(|(id, fields)| match fields {
+            Some(fields) => stream_entry(id, fields),
+            None => Resp::Array(Some(vec![
+                Resp::Bulk(Some(id.to_string())),
+                Resp::Array(None),
+            ])),
+        })
+        .collect();
+    Resp::Array(Some(vec![
+        Resp::Bulk(Some(key.to_string())),
+        Resp::Array(Some(entries)),
+    ]))
+}
+
+/// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds]
+/// [NOACK] STREAMS key [key ...] id [id ...]: reads each stream as a
+/// consumer of its group, `>` taking entries never delivered to the group
+/// and making them pending for the consumer unless NOACK, and an ID the
+/// consumer's pending entries after it, delivered again. Nil if only `>`
+/// was asked for and there are no new entries. With BLOCK, waits for some
+/// instead. The command is propagated as the XCLAIMs and XGROUP SETIDs
+/// making the same changes.
+fn cmd_xreadgroup(cmd: &Command, server: &Server, ctx: &mut ConnectionContext) -> Resp {
+    let options = match parse_read_options(&cmd.args, true) {
+        Ok(options) => options,
+        Err(e) => return e,
+    };
+    let Some((group, consumer)) = options.group else {
+        return Resp::Error("ERR Missing GROUP option for XREADGROUP".to_string());
+    };
+    let db = server.storage.db(ctx.db);
+    let mut from = Vec::with_capacity(options.keys.len());
+    for (key, id) in options.keys.iter().zip(options.ids) {
+        match db.with_stream(key, |stream| stream.group(group).is_some()) {
+            Ok(Some(true)) => {}
+            Ok(_) => {
+                return Resp::Error(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    key, group
+                ));
+            }
+            Err(e) => return Resp::Error(e),
+        }
+        from.push(match id.as_str() {
+            ">" => GroupRead::New,
+            "$" => {
+                return Resp::Error("ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.".to_string());
+            }
+            _ => match StreamId::parse(id, 0) {
+                Some(id) => GroupRead::Pending(id),
+                None => return Resp::Error(INVALID_STREAM_ID.to_string()),
+            },
+        });
+    }
+
+    let mut read = Vec::new();
+    let mut propagate = Vec::new();
+    for (key, &from) in options.keys.iter().zip(&from) {
+        let read_group = db.xreadgroup(key, group, consumer, from, options.count, options.noack);
+        let (entries, changes) = match read_group {
+            Ok(Some(read)) => read,
+            Ok(None) => continue,
+            Err(e) => return Resp::Error(e),
+        };
+        propagate.extend(group_changes(key, group, consumer, &changes));
+        // Histories are replied even when empty, new entries only if any
+        if from != GroupRead::New || !entries.is_empty() {
+            read.push(group_entries(key, entries));
+        }
+    }
+    ctx.propagate_as = Some(propagate);
+    if !read.is_empty() {
+        return Resp::Array(Some(read));
+    }
+    let Some(timeout) = options.timeout.filter(|_| !ctx.deny_blocking) else {
+        return Resp::Array(None);
+    };
+
+    let served = server.blocking.block_read_group(
+        ctx.id,
+        ctx.db,
+        options.keys,
+        (group, consumer),
+        options.count,
+        options.noack,
+    );
+    ctx.blocked = Some(Blocked {
+        timeout,
+        popped: served,
+        reply: |read| Resp::Array(Some(vec![elements(read)])),
+    });
+    Resp::Array(None)
+}
+
+/// XACK key group id [id ...]: acknowledges entries delivered to the
+/// group, replying how many were pending.
+fn cmd_xack(cmd: &Command, db: &Db) -> Resp {
+    let mut ids = Vec::with_capacity(cmd.args.len() - 2);
+    for id in &cmd.args[2..] {
+        match StreamId::parse(id, 0) {
+            Some(id) => ids.push(id),
+            None => return Resp::Error(INVALID_STREAM_ID.to_string()),
+        }
+    }
+
+    match db.with_stream_mut(&cmd.args[0], |stream| stream.ack(&cmd.args[1], &ids)) {
+        Ok(acked) => Resp::Integer(acked.flatten().unwrap_or(0) as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]:
+/// the group's pending entries, counted with the lowest and highest IDs
+/// and how many each consumer has, or listed with whom they're pending
+/// for, the milliseconds since they were delivered and how many times
+/// they were.
+fn cmd_xpending(cmd: &Command, db: &Db, ctx: &ConnectionContext) -> Resp {
+    let args = &cmd.args;
+    let (key, group) = (&args[0], &args[1]);
+    let mut pos = 2;
+    let mut min_idle = 0;
+    if args.len() > 2 && args[2].eq_ignore_ascii_case("IDLE") && args.len() > 3 {
+        match args[3].parse::<i64>() {
+            Ok(idle) => min_idle = idle.max(0) as u64,
+            Err(_) => {
+                return Resp::Error("ERR value is not an integer or out of range".to_string());
+            }
+        }
+        pos = 4;
+    }
+    let detail = &args[pos..];
+    if !(args.len() == 2 || (3..=4).contains(&detail.len())) {
+        return Resp::Error("ERR syntax error".to_string());
+    }
+    if args.len() == 2 {
+        let summary = db.with_stream(key, |stream| {
+            stream
+                .group(group)
+                .map(|group| pending_summary(group, ctx.protocol))
+        });
+        return match summary {
+            Ok(Some(Some(summary))) => summary,
+            Ok(_) => Resp::Error(format!(
+                "NOGROUP No such key '{}' or consumer group '{}'",
+                key, group
+            )),
+            Err(e) => Resp::Error(e),
+        };
+    }
+
+    let start = match parse_interval_id(&detail[0], 0) {
+        Ok((id, false)) => Some(id),
+        Ok((id, true)) => id.next(),
+        Err(e) => return e,
+    };
+    let end = match parse_interval_id(&detail[1], u64::MAX) {
+        Ok((id, false)) => Some(id),
+        Ok((id, true)) => id.prev(),
+        Err(e) => return e,
+    };
+    let count = match detail[2].parse::<i64>() {
+        Ok(count) => count.max(0) as usize,
+        Err(_) => return Resp::Error("ERR value is not an integer or out of range".to_string()),
+    };
+    let consumer = detail.get(3);
+    let now = storage::now_ms();
+    let pending = db.with_stream(key, |stream| {
+        let group = stream.group(group)?;
+        let (Some(start), Some(end)) = (start, end) else {
+            return Some(Vec::new());
+        };
+        if start > end {
+            return Some(Vec::new());
+        }
+        let pending = group
+            .pending()
+            .range(start..=end)
+            .filter(|(_, pending)| consumer.is_none_or(|consumer| &pending.consumer == consumer))
+            .filter(|(_, pending)| now.saturating_sub(pending.delivery_time) >= min_idle)
+            .take(count)
+            .map(|(id, pending)| {
+                Resp::Array(Some(vec![
+                    Resp::Bulk(Some(id.to_string())),
+                    Resp::Bulk(Some(pending.consumer.clone())),
+                    Resp::Integer(now.saturating_sub(pending.delivery_time) as i64),
+                    Resp::Integer(pending.delivery_count as i64),
+                ]))
+            })
+            .collect();
+        Some(pending)
+    });
+    match pending {
+        Ok(Some(Some(pending))) => Resp::Array(Some(pending)),
+        Ok(_) => Resp::Error(format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            key, group
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// XPENDING's summary of a group: how many entries are pending, the lowest
+/// and highest of their IDs, and how many each consumer has, as a map over
+/// RESP3.
+fn pending_summary(group: &Group, protocol: Protocol) -> Resp {
+    let pending = group.pending();
+    let (Some((first, _)), Some((last, _))) = (pending.first_key_value(), pending.last_key_value())
+    else {
+        return Resp::Array(Some(vec![
+            Resp::Integer(0),
+            Resp::Bulk(None),
+            Resp::Bulk(None),
+            Resp::Array(None),
+        ]));
+    };
+    let consumers = group
+        .consumers()
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending.is_empty())
+        .map(|(name, consumer)| {
+            (
+                Resp::Bulk(Some(name.clone())),
+                Resp::Bulk(Some(consumer.pending.len().to_string())),
+            )
+        });
+    let consumers = match protocol {
+        Protocol::Resp3 => Resp::Map(consumers.collect()),
+        Protocol::Resp2 => Resp::Array(Some(
+            consumers
+                .map(|(name, count)| Resp::Array(Some(vec![name, count])))
+                .collect(),
+        )),
+    };
+    Resp::Array(Some(vec![
+        Resp::Integer(pending.len() as i64),
+        Resp::Bulk(Some(first.to_string())),
+        Resp::Bulk(Some(last.to_string())),
+        consumers,
+    ]))
+}
+
+/// The entries claimed by XCLAIM or XAUTOCLAIM as replied, only their IDs
+/// with JUSTID.
+fn claimed_entries(stream: &Stream, ids: Vec<StreamId>, justid: bool) -> Vec<Resp> {
+    ids.into_iter()
+        .map(|id| match stream.get(id) {
+            Some(fields) if !justid => stream_entry(id, fields.clone()),
+            _ => Resp::Bulk(Some(id.to_string())),
+        })
+        .collect()
+}
+
+/// XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME
+/// unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID
+/// id]: makes the entries pending for another consumer of the group
+/// pending for `consumer` if they weren't delivered for `min-idle-time`
+/// milliseconds, and replies them. The command is propagated as the
+/// changes it made.
+fn cmd_xclaim(cmd: &Command, db: &Db, ctx: &mut ConnectionContext) -> Resp {
+    let args = &cmd.args;
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let mut options = ClaimOptions::default();
+    match args[3].parse::<i64>() {
+        Ok(idle) => options.min_idle = idle.max(0) as u64,
+        Err(_) => {
+            return Resp::Error("ERR Invalid min-idle-time argument for XCLAIM".to_string());
+        }
+    }
+    let mut pos = 4;
+    let mut ids = Vec::new();
+    while let Some(id) = args.get(pos).and_then(|id| StreamId::parse(id, 0)) {
+        ids.push(id);
+        pos += 1;
+    }
+    let now = storage::now_ms();
+    let mut delivery_time = None;
+    let mut options_args = args[pos..].iter();
+    while let Some(option) = options_args.next() {
+        let upper = option.to_uppercase();
+        match upper.as_str() {
+            "FORCE" => options.force = true,
+            "JUSTID" => options.justid = true,
+            "IDLE" | "TIME" | "RETRYCOUNT" if options_args.len() > 0 => {
+                let Ok(value) = options_args.next().unwrap().parse::<i64>() else {
+                    return Resp::Error(format!(
+                        "ERR Invalid {} option argument for XCLAIM",
+                        upper
+                    ));
+                };
+                match upper.as_str() {
+                    "IDLE" => delivery_time = Some(now as i64 - value),
+                    "TIME" => delivery_time = Some(value),
+                    _ => options.retry_count = Some(value.max(0) as u64),
+                }
+            }
+            "LASTID" if options_args.len() > 0 => {
+                match StreamId::parse(options_args.next().unwrap(), 0) {
+                    Some(id) => options.last_id = Some(id),
+                    None => return Resp::Error(INVALID_STREAM_ID.to_string()),
+                }
+            }
+            _ => return Resp::Error(format!("ERR Unrecognized XCLAIM option '{}'", option)),
+        }
+    }
+    // Not in the past or future, as Redis clamps it
+    options.delivery_time = delivery_time
+        .filter(|&time| time >= 0 && time as u64 <= now)
+        .map(|time| time as u64);
+
+    let claimed = db.with_stream_mut(key, |stream| {
+        let (ids, changes) = stream.claim(group, consumer, &ids, &options, now)?;
+        Some((claimed_entries(stream, ids, options.justid), changes))
+    });
+    match claimed {
+        Ok(Some(Some((claimed, changes)))) => {
+            ctx.propagate_as = Some(group_changes(key, group, consumer, &changes));
+            Resp::Array(Some(claimed))
+        }
+        Ok(_) => Resp::Error(format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            key, group
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// XAUTOCLAIM key group consumer min-idle-time start [COUNT count]
+/// [JUSTID]: claims for `consumer` up to `count` entries, 100 by default,
+/// pending from `start` on and undelivered for `min-idle-time`
+/// milliseconds. Replies the ID to scan on from, 0-0 once done, the
+/// entries claimed, and the IDs of those dropped, gone from the stream.
+/// The command is propagated as the changes it made.
+fn cmd_xautoclaim(cmd: &Command, db: &Db, ctx: &mut ConnectionContext) -> Resp {
+    let args = &cmd.args;
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let min_idle = match args[3].parse::<i64>() {
+        Ok(idle) => idle.max(0) as u64,
+        Err(_) => {
+            return Resp::Error("ERR Invalid min-idle-time argument for XAUTOCLAIM".to_string());
+        }
+    };
+    let start = match parse_interval_id(&args[4], 0) {
+        Ok((id, false)) => id,
+        Ok((id, true)) => match id.next() {
+            Some(id) => id,
+            None => return Resp::Error("ERR invalid start ID for the interval".to_string()),
+        },
+        Err(e) => return e,
+    };
+    let mut count = 100;
+    let mut justid = false;
+    let mut options = args[5..].iter();
+    while let Some(option) = options.next() {
+        if option.eq_ignore_ascii_case("JUSTID") {
+            justid = true;
+        } else if option.eq_ignore_ascii_case("COUNT")
+            && let Some(value) = options.next()
+        {
+            count = match value.parse::<i64>() {
+                // Kept to what ten times as many attempts can count
+                Ok(n) if (1..=i64::MAX / 10).contains(&n) => n as usize,
+                _ => return Resp::Error("ERR COUNT must be > 0".to_string()),
+            };
+        } else {
+            return Resp::Error("ERR syntax error".to_string());
+        }
+    }
+
+    let options = ClaimOptions {
+        min_idle,
+        justid,
+        ..ClaimOptions::default()
+    };
+    let now = storage::now_ms();
+    let claimed = db.with_stream_mut(key, |stream| {
+        let (next, ids, changes) =
+            stream.autoclaim(group, consumer, start, count, &options, now)?;
+        Some((next, claimed_entries(stream, ids, justid), changes))
+    });
+    match claimed {
+        Ok(Some(Some((next, claimed, changes)))) => {
+            let deleted = changes
+                .dropped
+                .iter()
+                .map(|id| Resp::Bulk(Some(id.to_string())))
+                .collect();
+            ctx.propagate_as = Some(group_changes(key, group, consumer, &changes));
+            Resp::Array(Some(vec![
+                Resp::Bulk(Some(next.to_string())),
+                Resp::Array(Some(claimed)),
+                Resp::Array(Some(deleted)),
+            ]))
+        }
+        Ok(_) => Resp::Error(format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            key, group
+        )),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// BF.RESERVE key error_rate capacity [EXPANSION expansion] [NONSCALING]:
+/// creates an empty bloom filter for `capacity` items with `error_rate`
+/// false positives, which adds a filter EXPANSION times larger
+/// (`bf-expansion-factor` by default) each time it fills up, or with
+/// NONSCALING takes no more items.
+fn cmd_bf_reserve(cmd: &Command, db: &Db) -> Resp {
+    let args = &cmd.args;
+    let error = |e: &str| Resp::Error(e.to_string());
+    let error_rate = match args[1].parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => rate,
+        Ok(_) => return error("ERR (0 < error rate range < 1)"),
+        Err(_) => return error("ERR bad error rate"),
+    };
+    let capacity = match args[2].parse::<i64>() {
+        Ok(capacity) if capacity > 0 => capacity as u64,
+        Ok(_) => return error("ERR (capacity should be larger than 0)"),
+        Err(_) => return error("ERR bad capacity"),
+    };
+    let mut expansion = None;
+    let mut scaling = true;
+    let mut pos = 3;
+    while pos < args.len() {
+        match args[pos].to_uppercase().as_str() {
+            "NONSCALING" => scaling = false,
+            "EXPANSION" if pos + 1 < args.len() => {
+                pos += 1;
+                expansion = match args[pos].parse::<u32>() {
+                    Ok(0) => return error("ERR expansion should be greater or equal to 1"),
+                    Ok(expansion) => Some(expansion),
+                    Err(_) => return error("ERR bad expansion"),
+                };
+            }
+            _ => return error("ERR syntax error"),
+        }
+        pos += 1;
+    }
+    if !scaling && expansion.is_some() {
+        return error("ERR nonscaling filters cannot expand");
+    }
+
+    match db.bf_reserve(&args[0], capacity, error_rate, expansion, scaling) {
+        Ok(true) => Resp::Simple("OK".to_string()),
+        Ok(false) => error("ERR item exists"),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// BF.ADD key item, or BF.MADD key item [item ...] with `multiple` set:
+/// adds items to the bloom filter at `key`, created as the `bf-*`
+/// parameters say if there's none, and replies 1 for each that's new, 0
+/// for one that may have been added already.
+fn cmd_bf_add(cmd: &Command, db: &Db, multiple: bool) -> Resp {
+    let reply = |added: Result<bool, String>| match added {
+        Ok(new) => Resp::Integer(new as i64),
+        Err(e) => Resp::Error(e),
+    };
+    match db.bf_add(&cmd.args[0], &cmd.args[1..]) {
+        Ok(added) if multiple => Resp::Array(Some(added.into_iter().map(reply).collect())),
+        Ok(mut added) => reply(added.remove(0)),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// BF.EXISTS key item, or BF.MEXISTS key item [item ...] with `multiple`
+/// set: replies 1 for each item that may have been added to the bloom
+/// filter at `key`, 0 for one that certainly wasn't.
+fn cmd_bf_exists(cmd: &Command, db: &Db, multiple: bool) -> Resp {
+    match db.bf_exists(&cmd.args[0], &cmd.args[1..]) {
+        Ok(found) if multiple => Resp::Array(Some(
+            found
+                .into_iter()
+                .map(|found| Resp::Integer(found as i64))
+                .collect(),
+        )),
+        Ok(found) => Resp::Integer(found[0] as i64),
+        Err(e) => Resp::Error(e),
+    }
+}
+
+/// Encodes `resp` as `protocol` has it, in one buffer.
+pub fn encode_resp(resp: &Resp, protocol: Protocol) -> Vec<u8> {
+    match resp {
+        Resp::Bulk(None) | Resp::Array(None) if protocol == Protocol::Resp3 => b"_\r\n".to_vec(),
+        Resp::Simple(s) => [b"+", &*binary::encode(s), b"\r\n"].concat(),
+        Resp::Error(e) => [b"-", &*binary::encode(e), b"\r\n"].concat(),
+        Resp::Integer(i) => format!(":{}\r\n", i).into_bytes(),
+        Resp::Bulk(None) => b"$-1\r\n".to_vec(),
+        Resp::Bulk(Some(s)) => {
+            let bytes = binary::encode(s);
+            let mut result = format!("${}\r\n", bytes.len()).into_bytes();
+            result.extend(&*bytes);
+            result.extend(b"\r\n");
+            result
+        }
+        Resp::Bytes(bytes) => {
+            let mut result = format!("${}\r\n", bytes.len()).into_bytes();
+            result.extend(bytes);
+            result.extend(b"\r\n");
+            result
+        }
+        Resp::Array(None) => b"*-1\r\n".to_vec(),
+        Resp::Array(Some(items)) => {
+            let mut result = format!("*{}\r\n", items.len()).into_bytes();
+            for item in items {
+                result.extend(encode_resp(item, protocol));
+            }
+            result
+        }
+        Resp::Map(pairs) => {
+            let mut result = match protocol {
+                Protocol::Resp2 => format!("*{}\r\n", pairs.len() * 2),
+                Protocol::Resp3 => format!("%{}\r\n", pairs.len()),
+            }
+            .into_bytes();
+            for (field, value) in pairs {
+                result.extend(encode_resp(field, protocol));
+                result.extend(encode_resp(value, protocol));
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// A command as a client sends it, its name first.
+    fn cmd(args: &[&str]) -> Command {
+        Command {
+            name: args[0].to_string(),
+            args: args[1..].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Runs commands on `ctx`'s connection: `run(&["SET", "k", "v"])`.
+    fn runner<'a>(
+        server: &'a Server,
+        ctx: &'a mut ConnectionContext,
+    ) -> impl FnMut(&[&str]) -> Resp + 'a {
+        |args| execute(&cmd(args), server, ctx)
+    }
+
+    fn bulk(s: &str) -> Resp {
+        Resp::Bulk(Some(s.to_string()))
+    }
+
+    #[test]
+    fn test_ping() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        assert_eq!(
+            execute(&cmd(&["PING"]), &server, &mut ctx),
+            Resp::Simple("PONG".to_string())
+        );
+    }
+
+    #[test]
+    fn test_ping_with_message() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        assert_eq!(
+            execute(&cmd(&["PING", "hello"]), &server, &mut ctx),
+            bulk("hello")
+        );
+    }
+
+    #[test]
+    fn test_time_and_lastsave() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let Resp::Array(Some(time)) = execute(&cmd(&["TIME"]), &server, &mut ctx) else {
+            panic!("TIME should reply with an array");
+        };
+        let [Resp::Bulk(Some(secs)), Resp::Bulk(Some(micros))] = time.as_slice() else {
+            panic!("TIME should reply with two bulk strings");
+        };
+        let secs: i64 = secs.parse().unwrap();
+        assert!(micros.parse::<u32>().unwrap() < 1_000_000);
+
+        let Resp::Integer(lastsave) = execute(&cmd(&["LASTSAVE"]), &server, &mut ctx) else {
+            panic!("LASTSAVE should reply with an integer");
+        };
+        assert!(lastsave > 0 && lastsave <= secs);
+    }
+
+    #[test]
+    fn test_debug_digest() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let zeros = Resp::Simple("0".repeat(40));
+
+        assert_eq!(
+            execute(&cmd(&["DEBUG", "DIGEST"]), &server, &mut ctx),
+            zeros
+        );
+        execute(&cmd(&["SET", "k", "v"]), &server, &mut ctx);
+        let digest = execute(&cmd(&["DEBUG", "digest"]), &server, &mut ctx);
+        assert_ne!(digest, zeros);
+
+        let Resp::Array(Some(values)) = execute(
+            &cmd(&["DEBUG", "DIGEST-VALUE", "k", "missing"]),
+            &server,
+            &mut ctx,
+        ) else {
+            panic!("DIGEST-VALUE should reply with an array");
+        };
+        assert_ne!(values[0], zeros);
+        assert_eq!(values[1], zeros);
+
+        // Rewriting the same value leaves the digest alone
+        execute(&cmd(&["SET", "k", "v"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["DEBUG", "DIGEST"]), &server, &mut ctx),
+            digest
+        );
+        assert!(matches!(
+            execute(&cmd(&["DEBUG", "NOPE"]), &server, &mut ctx),
+            Resp::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_set_get() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        assert_eq!(
+            execute(&cmd(&["SET", "key", "value"]), &server, &mut ctx),
+            Resp::Simple("OK".to_string())
+        );
+
+        assert_eq!(
+            execute(&cmd(&["GET", "key"]), &server, &mut ctx),
+            bulk("value")
+        );
+    }
+
+    #[test]
+    fn test_client_kill_filters() {
+        let server = Server::default();
+        let clients = &server.clients;
+        let local = "127.0.0.1:6379".parse().unwrap();
+        let me = clients.register("127.0.0.1:5000".parse().unwrap(), local, 7);
+        let other = clients.register("127.0.0.1:5001".parse().unwrap(), local, 8);
+        let mut ctx = ConnectionContext::new(me);
+
+        let kill = |args: &[&str]| cmd(&[&["CLIENT", "KILL"], args].concat());
+
+        // SKIPME defaults to yes, so only the other connection is killed.
+        let cmd = kill(&["LADDR", "127.0.0.1:6379"]);
+        assert_eq!(execute(&cmd, &server, &mut ctx), Resp::Integer(1));
+        assert!(clients.contains(me));
+        assert!(!clients.contains(other));
+
+        let cmd = kill(&["127.0.0.1:5001"]);
+        assert_eq!(
+            execute(&cmd, &server, &mut ctx),
+            Resp::Error("ERR No such client".to_string())
+        );
+
+        let cmd = kill(&["ID", &me.to_string(), "SKIPME", "no"]);
+        assert_eq!(execute(&cmd, &server, &mut ctx), Resp::Integer(1));
+        assert!(clients.is_empty());
+    }
+
+    #[test]
+    fn test_client_types() {
+        let server = Server::default();
+        let clients = &server.clients;
+        let local = "127.0.0.1:6379".parse().unwrap();
+        let me = clients.register("127.0.0.1:5000".parse().unwrap(), local, 7);
+        let replica = clients.register("127.0.0.1:5001".parse().unwrap(), local, 8);
+        let primary = clients.register("127.0.0.1:6380".parse().unwrap(), local, 9);
+        clients.set_kind(primary, ClientKind::Primary);
+        let mut ctx = ConnectionContext::new(me);
+        let mut link = ConnectionContext::new(replica);
+        execute(&cmd(&["SYNC"]), &server, &mut link);
+
+        let list = |client_type: &str| {
+            let Resp::Bulk(Some(list)) = execute(
+                &cmd(&["CLIENT", "LIST", "TYPE", client_type]),
+                &server,
+                &mut ConnectionContext::new(me),
+            ) else {
+                panic!("expected the client list");
+            };
+            list.lines()
+                .map(|line| {
+                    line.split(' ')
+                        .find(|field| field.starts_with("flags="))
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(list("normal"), vec!["flags=N"]);
+        assert_eq!(list("slave"), vec!["flags=S"]);
+        assert_eq!(list("master"), vec!["flags=M"]);
+        assert!(list("pubsub").is_empty());
+
+        // Replication links aren't normal clients
+        let kill = |args: &[&str]| cmd(&[&["CLIENT", "KILL", "SKIPME", "no"], args].concat());
+        assert_eq!(
+            execute(&kill(&["TYPE", "normal"]), &server, &mut ctx),
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&kill(&["TYPE", "replica"]), &server, &mut ctx),
+            Resp::Integer(1)
+        );
+        assert!(!clients.contains(replica));
+        assert_eq!(
+            execute(&kill(&["TYPE", "master"]), &server, &mut ctx),
+            Resp::Integer(1)
+        );
+        assert!(clients.is_empty());
+        assert_eq!(
+            execute(&kill(&["TYPE", "other"]), &server, &mut ctx),
+            Resp::Error("ERR Unknown client type 'other'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_monitor() {
+        let server = Server::default();
+        let clients = &server.clients;
+        let local = "127.0.0.1:6379".parse().unwrap();
+        let watcher = clients.register("127.0.0.1:5000".parse().unwrap(), local, 7);
+        let me = clients.register("127.0.0.1:5001".parse().unwrap(), local, 8);
+        let mut monitor = ConnectionContext::new(watcher);
+        let mut ctx = ConnectionContext::new(me);
+
+        // Nothing is formatted until someone listens
+        execute(&cmd(&["SET", "before", "1"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["MONITOR"]), &server, &mut monitor),
+            Resp::Simple("OK".to_string())
+        );
+        assert!(
+            clients
+                .get(watcher)
+                .unwrap()
+                .to_line()
+                .contains(" flags=O ")
+        );
+        let mut lines = monitor.monitor.take().unwrap();
+
+        execute(&cmd(&["SELECT", "2"]), &server, &mut ctx);
+        execute(
+            &cmd(&["SET", "k", "a \"b\"\n\u{10FF80}"]),
+            &server,
+            &mut ctx,
+        );
+        execute(&cmd(&["AUTH", "secret"]), &server, &mut ctx);
+        execute(
+            &cmd(&["HELLO", "2", "AUTH", "default", "secret"]),
+            &server,
+            &mut ctx,
+        );
+        // Commands that fail their checks aren't shown
+        execute(&cmd(&["GET"]), &server, &mut ctx);
+        execute(&cmd(&["CLIENT", "SETNAME", "app"]), &server, &mut ctx);
+        execute(
+            &cmd(&["CLIENT", "SETINFO", "LIB-NAME", "redis-py"]),
+            &server,
+            &mut ctx,
+        );
+        execute(&cmd(&["CONFIG", "GET", "maxmemory"]), &server, &mut ctx);
+
+        let mut shown = Vec::new();
+        while let Ok(line) = lines.try_recv() {
+            let (time, rest) = line.split_once(' ').unwrap();
+            assert!(time.parse::<f64>().is_ok(), "{}", line);
+            shown.push(rest.to_string());
+        }
+        assert_eq!(
+            shown,
+            vec![
+                r#"[0 127.0.0.1:5000 name= lib-name= lib-ver=] "monitor""#,
+                r#"[0 127.0.0.1:5001 name= lib-name= lib-ver=] "select" "2""#,
+                r#"[2 127.0.0.1:5001 name= lib-name= lib-ver=] "set" "k" "a \"b\"\n\x80""#,
+                r#"[2 127.0.0.1:5001 name= lib-name= lib-ver=] "auth" "(redacted)""#,
+                r#"[2 127.0.0.1:5001 name= lib-name= lib-ver=] "hello" "2" "AUTH" "(redacted)" "(redacted)""#,
+                r#"[2 127.0.0.1:5001 name=app lib-name= lib-ver=] "client" "SETNAME" "app""#,
+                r#"[2 127.0.0.1:5001 name=app lib-name=redis-py lib-ver=] "client" "SETINFO" "LIB-NAME" "redis-py""#,
+                r#"[2 127.0.0.1:5001 name=app lib-name=redis-py lib-ver=] "config" "GET" "maxmemory""#,
+            ]
+        );
+
+        // A disconnected monitor is forgotten
+        clients.unregister(watcher);
+        assert!(!clients.has_monitors());
+    }
+
+    #[test]
+    fn test_select_and_swapdb() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+
+        execute(&cmd(&["SET", "k", "v0"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["SELECT", "16"]), &server, &mut ctx),
+            Resp::Error("ERR DB index is out of range".to_string())
+        );
+        execute(&cmd(&["SELECT", "5"]), &server, &mut ctx);
+        assert_eq!(ctx.db, 5);
+        assert_eq!(
+            execute(&cmd(&["GET", "k"]), &server, &mut ctx),
+            Resp::Bulk(None)
+        );
+        assert_eq!(
+            execute(&cmd(&["DBSIZE"]), &server, &mut ctx),
+            Resp::Integer(0)
+        );
+
+        execute(&cmd(&["SWAPDB", "0", "5"]), &server, &mut ctx);
+        assert_eq!(execute(&cmd(&["GET", "k"]), &server, &mut ctx), bulk("v0"));
+
+        execute(&cmd(&["FLUSHALL"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["DBSIZE"]), &server, &mut ctx),
+            Resp::Integer(0)
+        );
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+
+        execute(&cmd(&["RPUSH", "l", "a", "b"]), &server, &mut ctx);
+        let Resp::Bytes(payload) = execute(&cmd(&["DUMP", "l"]), &server, &mut ctx) else {
+            panic!("DUMP didn't return a payload");
+        };
+        assert_eq!(
+            execute(&cmd(&["DUMP", "missing"]), &server, &mut ctx),
+            Resp::Bulk(None)
+        );
+
+        // The payload isn't UTF-8, so it's sent as the bytes DUMP replied
+        let restore = |args: &[&str]| {
+            let mut parts: Vec<&[u8]> = vec![b"RESTORE"];
+            parts.extend(args.iter().map(|arg| arg.as_bytes()));
+            parts.insert(3, &payload);
+            Command::from_parts(&parts).unwrap()
+        };
+        assert_eq!(
+            execute(&restore(&["l", "0"]), &server, &mut ctx),
+            Resp::Error("BUSYKEY Target key name already exists.".to_string())
+        );
+        assert_eq!(
+            execute(&restore(&["copy", "-1"]), &server, &mut ctx),
+            Resp::Error("ERR Invalid TTL value, must be >= 0".to_string())
+        );
+        assert_eq!(
+            execute(
+                &restore(&["copy", "5000", "IDLETIME", "10"]),
+                &server,
+                &mut ctx
+            ),
+            Resp::Simple("OK".to_string())
+        );
+        assert_eq!(
+            execute(&cmd(&["LRANGE", "copy", "0", "-1"]), &server, &mut ctx),
+            execute(&cmd(&["LRANGE", "l", "0", "-1"]), &server, &mut ctx)
+        );
+        assert!(server.storage.db(0).ttl("copy") > 4000);
+
+        // An absolute TTL in the past deletes the key it replaces
+        assert_eq!(
+            execute(
+                &restore(&["l", "1", "REPLACE", "ABSTTL"]),
+                &server,
+                &mut ctx
+            ),
+            Resp::Simple("OK".to_string())
+        );
+        assert_eq!(
+            execute(&cmd(&["EXISTS", "l"]), &server, &mut ctx),
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&cmd(&["RESTORE", "x", "0", "garbage"]), &server, &mut ctx),
+            Resp::Error("ERR DUMP payload version or checksum are wrong".to_string())
+        );
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = |parts: &[&[u8]]| {
+            let reply = execute(&Command::from_parts(parts).unwrap(), &server, &mut ctx);
+            encode_resp(&reply, Protocol::Resp2)
+        };
+
+        run(&[b"SET", b"k\xff", b"\x80\xff"]);
+        assert_eq!(run(&[b"GET", b"k\xff"]), b"$2\r\n\x80\xff\r\n");
+        assert_eq!(run(&[b"STRLEN", b"k\xff"]), b":2\r\n");
+        // Bytes that look like the escapes they're kept as stay themselves
+        assert_eq!(
+            run(&[b"APPEND", b"k\xff", "\u{10ff80}".as_bytes()]),
+            b":6\r\n"
+        );
+        assert_eq!(
+            run(&[b"GET", b"k\xff"]),
+            [&b"$6\r\n\x80\xff"[..], "\u{10ff80}".as_bytes(), b"\r\n"].concat()
+        );
+        assert_eq!(run(&[b"KEYS", b"*"]), b"*1\r\n$2\r\nk\xff\r\n");
+
+        run(&[b"HSET", b"h", b"\xc3", b"caf\xe9"]);
+        assert_eq!(run(&[b"HGET", b"h", b"\xc3"]), b"$4\r\ncaf\xe9\r\n");
+        assert_eq!(run(&[b"HGET", b"h", "\u{c3}".as_bytes()]), b"$-1\r\n");
+        assert_eq!(
+            run(&[b"HGETALL", b"h"]),
+            b"*2\r\n$1\r\n\xc3\r\n$4\r\ncaf\xe9\r\n"
+        );
+        // Members sort by their bytes, not by how they're kept
+        run(&[
+            b"ZADD",
+            b"z",
+            b"0",
+            b"\x80",
+            b"0",
+            b"\xc3",
+            b"0",
+            b"\xc3\xa9",
+        ]);
+        assert_eq!(
+            run(&[b"ZRANGE", b"z", b"0", b"-1"]),
+            b"*3\r\n$1\r\n\x80\r\n$1\r\n\xc3\r\n$2\r\n\xc3\xa9\r\n"
+        );
+        assert_eq!(
+            run(&[b"ZRANGEBYLEX", b"z", b"-", b"(\xc3\xa9"]),
+            b"*2\r\n$1\r\n\x80\r\n$1\r\n\xc3\r\n"
+        );
+    }
+
+    #[test]
+    fn test_requirepass() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        execute(
+            &cmd(&["CONFIG", "SET", "requirepass", "secret"]),
+            &server,
+            &mut ctx,
+        );
+        ctx.authenticated = false;
+
+        assert_eq!(
+            execute(&cmd(&["GET", "k"]), &server, &mut ctx),
+            Resp::Error("NOAUTH Authentication required.".to_string())
+        );
+        assert!(matches!(
+            execute(&cmd(&["AUTH", "wrong"]), &server, &mut ctx),
+            Resp::Error(e) if e.starts_with("WRONGPASS")
+        ));
+        assert_eq!(
+            execute(&cmd(&["AUTH", "default", "secret"]), &server, &mut ctx),
+            Resp::Simple("OK".to_string())
+        );
+        assert_eq!(
+            execute(&cmd(&["GET", "k"]), &server, &mut ctx),
+            Resp::Bulk(None)
+        );
+    }
+
+    #[test]
+    fn test_acl_permissions() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+
+        let setuser = cmd(&[
+            "ACL", "SETUSER", "reader", "on", ">pw", "~cache:*", "+@read",
+        ]);
+        assert_eq!(
+            execute(&setuser, &server, &mut ctx),
+            Resp::Simple("OK".to_string())
+        );
+        execute(&cmd(&["SET", "cache:1", "v"]), &server, &mut ctx);
+        execute(&cmd(&["AUTH", "reader", "pw"]), &server, &mut ctx);
+
+        assert_eq!(
+            execute(&cmd(&["ACL", "WHOAMI"]), &server, &mut ctx),
+            Resp::Error(
+                "NOPERM User reader has no permissions to run the 'acl' command".to_string()
+            )
+        );
+        assert_eq!(
+            execute(&cmd(&["GET", "cache:1"]), &server, &mut ctx),
+            bulk("v")
+        );
+        assert_eq!(
+            execute(&cmd(&["GET", "other"]), &server, &mut ctx),
+            Resp::Error("NOPERM No permissions to access a key".to_string())
+        );
+        assert!(matches!(
+            execute(&cmd(&["SET", "cache:1", "w"]), &server, &mut ctx),
+            Resp::Error(e) if e.starts_with("NOPERM")
+        ));
+    }
+
+    #[test]
+    fn test_rename_command() {
+        let mut config = Config::default();
+        config
+            .load_str("rename-command FLUSHALL \"\"\nrename-command CONFIG ops-config\n")
+            .unwrap();
+        let server = Server::new(config);
+        let mut ctx = ConnectionContext::new(1);
+
+        assert_eq!(
+            execute(&cmd(&["FLUSHALL"]), &server, &mut ctx),
+            Resp::Error("ERR unknown command 'FLUSHALL'".to_string())
+        );
+        assert!(matches!(
+            execute(&cmd(&["CONFIG", "GET", "port"]), &server, &mut ctx),
+            Resp::Error(_)
+        ));
+        assert_eq!(
+            execute(&cmd(&["OPS-CONFIG", "GET", "port"]), &server, &mut ctx),
+            Resp::Map(vec![(bulk("port"), bulk("6379"),)])
+        );
+    }
+
+    #[test]
+    fn test_command_table() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+
+        assert_eq!(
+            execute(&cmd(&["GET", "a", "b"]), &server, &mut ctx),
+            Resp::Error("ERR wrong number of arguments for 'get' command".to_string())
+        );
+        assert_eq!(
+            execute(&cmd(&["COMMAND", "COUNT"]), &server, &mut ctx),
+            Resp::Integer(COMMAND_TABLE.len() as i64)
+        );
+        assert_eq!(
+            execute(
+                &cmd(&["COMMAND", "LIST", "FILTERBY", "PATTERN", "h*get*"]),
+                &server,
+                &mut ctx
+            ),
+            Resp::Array(Some(vec![bulk("hget"), bulk("hmget"), bulk("hgetall"),]))
+        );
+
+        assert_eq!(
+            execute(
+                &cmd(&["COMMAND", "GETKEYS", "RENAME", "a", "b"]),
+                &server,
+                &mut ctx
+            ),
+            Resp::Array(Some(vec![bulk("a"), bulk("b"),]))
+        );
+        assert_eq!(
+            execute(&cmd(&["COMMAND", "GETKEYS", "PING"]), &server, &mut ctx),
+            Resp::Error("ERR The command has no key arguments".to_string())
+        );
+
+        let mset = cmd(&["MSET", "k1", "v1", "k2", "v2"]);
+        assert_eq!(command_keys(&mset), vec!["k1", "k2"]);
+        assert!(is_write_command("MSET"));
+        assert!(!is_write_command("GET"));
+        assert_eq!(
+            lookup("get").unwrap().acl_categories(),
+            vec!["read", "string", "fast"]
+        );
+    }
+
+    #[test]
+    fn test_replica_read_only_and_stale_data() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        run(&["REPLICAOF", "127.0.0.1", "1"]);
+
+        assert_eq!(
+            run(&["SET", "k", "v"]),
+            Resp::Error("READONLY You can't write against a read only replica.".to_string())
+        );
+        assert_eq!(run(&["GET", "k"]), Resp::Bulk(None));
+        run(&["CONFIG", "SET", "replica-read-only", "no"]);
+        assert_eq!(run(&["SET", "k", "v"]), Resp::Simple("OK".to_string()));
+
+        // The link is down, so only commands flagged stale still run
+        run(&["CONFIG", "SET", "replica-serve-stale-data", "no"]);
+        assert!(matches!(
+            run(&["GET", "k"]),
+            Resp::Error(e) if e.starts_with("MASTERDOWN")
+        ));
+        assert!(matches!(run(&["INFO", "server"]), Resp::Bulk(Some(_))));
+        run(&["REPLICAOF", "NO", "ONE"]);
+        assert_eq!(run(&["GET", "k"]), bulk("v"));
+    }
+
+    #[test]
+    fn test_role() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        assert_eq!(
+            execute(&cmd(&["ROLE"]), &server, &mut ctx),
+            Resp::Array(Some(vec![
+                bulk("master"),
+                Resp::Integer(0),
+                Resp::Array(Some(vec![])),
+            ]))
+        );
+        execute(&cmd(&["REPLICAOF", "127.0.0.1", "1"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["ROLE"]), &server, &mut ctx),
+            Resp::Array(Some(vec![
+                bulk("slave"),
+                bulk("127.0.0.1"),
+                Resp::Integer(1),
+                bulk("connect"),
+                Resp::Integer(-1),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_maxmemory() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        execute(&cmd(&["SET", "a", "1"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "b", "1"]), &server, &mut ctx);
+        // Always exceeded, whatever else the process allocated
+        execute(
+            &cmd(&["CONFIG", "SET", "maxmemory", "1"]),
+            &server,
+            &mut ctx,
+        );
+        let oom =
+            Resp::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string());
+        assert_eq!(execute(&cmd(&["SET", "c", "1"]), &server, &mut ctx), oom);
+        assert_eq!(execute(&cmd(&["GET", "a"]), &server, &mut ctx), bulk("1"));
+        assert_eq!(
+            execute(&cmd(&["DEL", "a"]), &server, &mut ctx),
+            Resp::Integer(1)
+        );
+
+        execute(
+            &cmd(&["CONFIG", "SET", "maxmemory-policy", "allkeys-lru"]),
+            &server,
+            &mut ctx,
+        );
+        assert_eq!(execute(&cmd(&["SET", "c", "1"]), &server, &mut ctx), oom);
+        assert_eq!(server.storage.db(0).dbsize(), 0);
+        assert_eq!(Stats::get(&server.stats.evicted_keys), 1);
+        assert!(matches!(
+            execute(
+                &cmd(&["CONFIG", "SET", "maxmemory-policy", "lru"]),
+                &server,
+                &mut ctx
+            ),
+            Resp::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_expire_options() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        run(&["SET", "k", "v"]);
+        assert_eq!(run(&["EXPIRE", "k", "100", "XX"]), Resp::Integer(0));
+        assert_eq!(run(&["EXPIRE", "k", "100", "GT"]), Resp::Integer(0));
+        assert_eq!(run(&["EXPIRE", "k", "100", "LT"]), Resp::Integer(1));
+        assert_eq!(run(&["EXPIRE", "k", "200", "nx"]), Resp::Integer(0));
+        assert_eq!(run(&["EXPIRE", "k", "50", "GT"]), Resp::Integer(0));
+        let before = storage::now_ms();
+        assert_eq!(
+            run(&["PEXPIRE", "k", "200000", "XX", "GT"]),
+            Resp::Integer(1)
+        );
+        let at = server.storage.db(0).expire_time("k").unwrap();
+        assert!((before + 200000..=storage::now_ms() + 200000).contains(&at));
+        assert_eq!(run(&["EXPIRE", "k", "300", "LT"]), Resp::Integer(0));
+        let at = storage::now_ms() / 1000 + 100;
+        assert_eq!(
+            run(&["EXPIREAT", "k", &at.to_string(), "LT"]),
+            Resp::Integer(1)
+        );
+        assert_eq!(server.storage.db(0).expire_time("k"), Some(at * 1000));
+        assert_eq!(run(&["PERSIST", "k"]), Resp::Integer(1));
+        assert_eq!(
+            run(&["PEXPIREAT", "k", "99999999999999", "NX"]),
+            Resp::Integer(1)
+        );
+        assert_eq!(run(&["EXPIRE", "missing", "100", "NX"]), Resp::Integer(0));
+
+        let error = |message: &str| Resp::Error(message.to_string());
+        assert_eq!(
+            run(&["EXPIRE", "k", "100", "NX", "XX"]),
+            error("ERR NX and XX, GT or LT options at the same time are not compatible")
+        );
+        assert_eq!(
+            run(&["EXPIRE", "k", "100", "GT", "LT"]),
+            error("ERR GT and LT options at the same time are not compatible")
+        );
+        assert_eq!(
+            run(&["EXPIRE", "k", "100", "YY"]),
+            error("ERR Unsupported option YY")
+        );
+    }
+
+    #[test]
+    fn test_non_positive_expirations() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        for expire in [
+            &["EXPIRE", "k", "0"][..],
+            &["PEXPIRE", "k", "-100"],
+            &["EXPIREAT", "k", "1"],
+            &["PEXPIREAT", "k", "-1"],
+        ] {
+            run(&["SET", "k", "v"]);
+            assert_eq!(run(expire), Resp::Integer(1));
+            assert_eq!(run(&["EXISTS", "k"]), Resp::Integer(0));
+            assert_eq!(server.storage.db(0).dbsize(), 0);
+        }
+        assert_eq!(
+            run(&["EXPIRE", "k", "9223372036854775807"]),
+            Resp::Error("ERR invalid expire time in 'expire' command".to_string())
+        );
+
+        for args in [
+            &["SET", "k", "v", "EX", "0"][..],
+            &["SET", "k", "v", "PX", "-5"],
+            &["SETEX", "k", "0", "v"],
+            &["PSETEX", "k", "-1", "v"],
+        ] {
+            let error = format!(
+                "ERR invalid expire time in '{}' command",
+                args[0].to_lowercase()
+            );
+            assert_eq!(run(args), Resp::Error(error));
+        }
+        assert_eq!(run(&["EXISTS", "k"]), Resp::Integer(0));
+    }
+
+    #[test]
+    fn test_touch() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        run(&["SET", "a", "1"]);
+        run(&["RPUSH", "b", "1"]);
+        let db = server.storage.db(0);
+        std::thread::sleep(Duration::from_millis(30));
+        let idle_ms = |key: &str| {
+            let (_, idle) = db
+                .sample(64, false, |entry| entry.idle_ms())
+                .into_iter()
+                .find(|(sampled, _)| sampled == key)
+                .unwrap();
+            idle
+        };
+        assert!(idle_ms("a") >= 30);
+
+        assert_eq!(run(&["TOUCH", "a", "missing", "b", "a"]), Resp::Integer(3));
+        assert!(idle_ms("a") < 30);
+        assert!(idle_ms("b") < 30);
+        assert_eq!(run(&["GET", "a"]), bulk("1"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = |args: &[&str]| execute(&cmd(&[&["SCAN"], args].concat()), &server, &mut ctx);
+        let db = server.storage.db(0);
+        for i in 0..25 {
+            db.set(format!("user:{}", i), "v".to_string());
+        }
+        db.rpush("queue", vec!["job".to_string()]).unwrap();
+
+        let mut scan_all = |options: &[&str]| {
+            let mut keys = Vec::new();
+            let mut cursor = "0".to_string();
+            loop {
+                let mut args = vec![cursor.as_str()];
+                args.extend_from_slice(options);
+                let Resp::Array(Some(reply)) = run(&args) else {
+                    panic!("unexpected reply");
+                };
+                let [Resp::Bulk(Some(next)), Resp::Array(Some(batch))] = &reply[..] else {
+                    panic!("unexpected reply {:?}", reply);
+                };
+                keys.extend(batch.iter().map(|key| match key {
+                    Resp::Bulk(Some(key)) => key.clone(),
+                    key => panic!("unexpected key {:?}", key),
+                }));
+                if next == "0" {
+                    break;
+                }
+                cursor = next.clone();
+            }
+            keys.sort();
+            keys
+        };
+        assert_eq!(scan_all(&[]).len(), 26);
+        assert_eq!(scan_all(&["MATCH", "user:1?", "COUNT", "3"]).len(), 10);
+        assert_eq!(scan_all(&["TYPE", "LIST"]), vec!["queue".to_string()]);
+        assert!(scan_all(&["TYPE", "zset"]).is_empty());
+
+        let error = |reply| match reply {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(error(run(&["-1"])), "ERR invalid cursor");
+        assert_eq!(error(run(&["0", "COUNT", "0"])), "ERR syntax error");
+        assert_eq!(
+            error(run(&["0", "COUNT", "x"])),
+            "ERR value is not an integer or out of range"
+        );
+        assert_eq!(error(run(&["0", "MATCH"])), "ERR syntax error");
+        assert_eq!(error(run(&["0", "LIMIT", "1"])), "ERR syntax error");
+    }
+
+    #[test]
+    fn test_copy() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        run(&["RPUSH", "list", "a", "b"]);
+        run(&["EXPIRE", "list", "100"]);
+        run(&["SET", "taken", "v"]);
+
+        assert_eq!(run(&["COPY", "list", "copy"]), Resp::Integer(1));
+        run(&["RPUSH", "copy", "c"]);
+        assert_eq!(run(&["LLEN", "list"]), Resp::Integer(2));
+        assert_eq!(run(&["LLEN", "copy"]), Resp::Integer(3));
+        assert!(matches!(run(&["TTL", "copy"]), Resp::Integer(99..=100)));
+
+        assert_eq!(run(&["COPY", "list", "taken"]), Resp::Integer(0));
+        assert_eq!(run(&["TYPE", "taken"]), Resp::Simple("string".to_string()));
+        assert_eq!(run(&["COPY", "list", "taken", "REPLACE"]), Resp::Integer(1));
+        assert_eq!(run(&["TYPE", "taken"]), Resp::Simple("list".to_string()));
+        assert_eq!(run(&["COPY", "missing", "other"]), Resp::Integer(0));
+
+        assert_eq!(run(&["COPY", "list", "list", "DB", "3"]), Resp::Integer(1));
+        assert_eq!(server.storage.db(3).llen("list"), Ok(2));
+        assert_eq!(run(&["COPY", "list", "list", "DB", "3"]), Resp::Integer(0));
+
+        let error = |reply| match reply {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(
+            error(run(&["COPY", "list", "list"])),
+            "ERR source and destination objects are the same"
+        );
+        assert_eq!(
+            error(run(&["COPY", "list", "x", "DB", "16"])),
+            "ERR DB index is out of range"
+        );
+        assert_eq!(error(run(&["COPY", "list", "x", "DB"])), "ERR syntax error");
+    }
+
+    #[test]
+    fn test_move() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        run(&["SADD", "set", "a", "b"]);
+        run(&["EXPIRE", "set", "100"]);
+        run(&["SET", "taken", "here"]);
+        server
+            .storage
+            .db(1)
+            .set("taken".to_string(), "there".to_string());
+
+        assert_eq!(run(&["MOVE", "set", "1"]), Resp::Integer(1));
+        assert_eq!(run(&["EXISTS", "set"]), Resp::Integer(0));
+        assert_eq!(server.storage.db(1).scard("set"), Ok(2));
+        assert!(server.storage.db(1).ttl("set") > 99_000);
+
+        assert_eq!(run(&["MOVE", "taken", "1"]), Resp::Integer(0));
+        assert_eq!(run(&["GET", "taken"]), bulk("here"));
+        assert_eq!(run(&["MOVE", "missing", "1"]), Resp::Integer(0));
+
+        let error = |reply| match reply {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(
+            error(run(&["MOVE", "taken", "0"])),
+            "ERR source and destination objects are the same"
+        );
+        assert_eq!(
+            error(run(&["MOVE", "taken", "16"])),
+            "ERR DB index is out of range"
+        );
+    }
+
+    #[test]
+    fn test_key_types() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let wrongtype = || {
+            Resp::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            )
+        };
+        run(&["SET", "string", "v"]);
+        run(&["RPUSH", "list", "a"]);
+        let dirty = Stats::get(&server.stats.dirty);
+
+        assert_eq!(run(&["LPUSH", "string", "x"]), wrongtype());
+        assert_eq!(run(&["GET", "list"]), wrongtype());
+        assert_eq!(run(&["SADD", "list", "x"]), wrongtype());
+        assert_eq!(run(&["HGET", "string", "f"]), wrongtype());
+        assert_eq!(Stats::get(&server.stats.dirty), dirty);
+        assert_eq!(run(&["GET", "string"]), bulk("v"));
+
+        // Missing keys, and commands that overwrite any type, aren't checked
+        assert_eq!(run(&["LPUSH", "new", "x"]), Resp::Integer(1));
+        assert_eq!(run(&["SET", "list", "v"]), Resp::Simple("OK".to_string()));
+
+        // Queued in a transaction, the error comes from EXEC
+        run(&["MULTI"]);
+        assert_eq!(run(&["INCR", "new"]), Resp::Simple("QUEUED".to_string()));
+        assert_eq!(run(&["EXEC"]), Resp::Array(Some(vec![wrongtype()])));
+
+        for spec in COMMAND_TABLE.iter() {
+            if let Some(key_type) = spec.key_type {
+                assert!(
+                    [
+                        "string",
+                        "list",
+                        "set",
+                        "hash",
+                        "zset",
+                        "stream",
+                        "MBbloom--"
+                    ]
+                    .contains(&key_type)
+                );
+                assert!(
+                    spec.first_key > 0 || spec.numkeys.is_some() || spec.keys_after.is_some(),
+                    "{} has no keys to check",
+                    spec.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_options() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let ok = || Resp::Simple("OK".to_string());
+
+        assert_eq!(run(&["SET", "k", "1", "EX", "100"]), ok());
+        assert_eq!(run(&["SET", "k", "2", "KEEPTTL"]), ok());
+        assert!(matches!(run(&["TTL", "k"]), Resp::Integer(99..=100)));
+        assert_eq!(run(&["SET", "k", "3", "XX", "GET", "KEEPTTL"]), bulk("2"));
+        assert!(matches!(run(&["TTL", "k"]), Resp::Integer(99..=100)));
+        assert_eq!(run(&["SET", "k", "4"]), ok());
+        assert_eq!(run(&["TTL", "k"]), Resp::Integer(-1));
+
+        assert_eq!(run(&["SET", "k", "5", "NX"]), Resp::Bulk(None));
+        assert_eq!(run(&["SET", "k", "5", "NX", "GET"]), bulk("4"));
+        assert_eq!(run(&["SET", "new", "5", "XX", "GET"]), Resp::Bulk(None));
+        assert_eq!(run(&["EXISTS", "new"]), Resp::Integer(0));
+        assert_eq!(run(&["SET", "new", "5", "NX", "GET"]), Resp::Bulk(None));
+        assert_eq!(run(&["GET", "new"]), bulk("5"));
+
+        run(&["RPUSH", "list", "a"]);
+        assert_eq!(
+            run(&["SET", "list", "v", "GET"]),
+            Resp::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+            )
+        );
+        assert_eq!(run(&["LLEN", "list"]), Resp::Integer(1));
+
+        for args in [
+            &["SET", "k", "v", "NX", "XX"][..],
+            &["SET", "k", "v", "EX", "10", "KEEPTTL"],
+            &["SET", "k", "v", "KEEPTTL", "PX", "10"],
+            &["SET", "k", "v", "EX", "10", "PX", "10"],
+            &["SET", "k", "v", "EX"],
+        ] {
+            assert_eq!(
+                run(args),
+                Resp::Error("ERR syntax error".to_string()),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_nx_is_atomic() {
+        let server = Server::default();
+        for round in 0..20 {
+            let key = format!("lock:{}", round);
+            let winners: usize = std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..8)
+                    .map(|id| {
+                        let (server, key) = (&server, &key);
+                        scope.spawn(move || {
+                            let mut ctx = ConnectionContext::new(id);
+                            let set = cmd(&["SET", key.as_str(), &id.to_string(), "NX"]);
+                            execute(&set, server, &mut ctx) == Resp::Simple("OK".to_string())
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap() as usize)
+                    .sum()
+            });
+            assert_eq!(winners, 1, "round {}", round);
+        }
+
+        let mut ctx = ConnectionContext::new(100);
+        let mut run = runner(&server, &mut ctx);
+        assert_eq!(run(&["SETNX", "lock:0", "x"]), Resp::Integer(0));
+        assert_eq!(run(&["SETNX", "free", "x"]), Resp::Integer(1));
+        assert_eq!(run(&["GETSET", "free", "y"]), bulk("x"));
+        assert_eq!(run(&["GETSET", "other", "y"]), Resp::Bulk(None));
+        assert_eq!(run(&["GET", "free"]), bulk("y"));
+    }
+
+    #[test]
+    fn test_bitcount() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run =
+            |args: &[&str]| execute(&cmd(&[&["BITCOUNT"], args].concat()), &server, &mut ctx);
+        // "foobar": 4 + 6 + 6 + 3 + 3 + 4 bits set
+        server
+            .storage
+            .db(0)
+            .set("k".to_string(), "foobar".to_string());
+        let long = "foobar".repeat(100);
+        server.storage.db(0).set("long".to_string(), long);
+
+        assert_eq!(run(&["k"]), Resp::Integer(26));
+        assert_eq!(run(&["long"]), Resp::Integer(2600));
+        assert_eq!(run(&["missing"]), Resp::Integer(0));
+        assert_eq!(run(&["k", "0", "0"]), Resp::Integer(4));
+        assert_eq!(run(&["k", "1", "1", "byte"]), Resp::Integer(6));
+        assert_eq!(run(&["k", "-2", "-1"]), Resp::Integer(7));
+        assert_eq!(run(&["k", "0", "100"]), Resp::Integer(26));
+        assert_eq!(run(&["k", "3", "1"]), Resp::Integer(0));
+        assert_eq!(run(&["k", "-1", "-2"]), Resp::Integer(0));
+
+        // 'f' is 0b01100110 and 'o' 0b01101111
+        assert_eq!(run(&["k", "5", "30", "BIT"]), Resp::Integer(17));
+        assert_eq!(run(&["k", "1", "2", "BIT"]), Resp::Integer(2));
+        assert_eq!(run(&["k", "3", "4", "BIT"]), Resp::Integer(0));
+        assert_eq!(run(&["k", "6", "9", "BIT"]), Resp::Integer(2));
+        assert_eq!(run(&["k", "-8", "-1", "BIT"]), Resp::Integer(4));
+        assert_eq!(run(&["k", "0", "-1", "BIT"]), Resp::Integer(26));
+
+        let error = |reply| match reply {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(error(run(&["k", "0"])), "ERR syntax error");
+        assert_eq!(error(run(&["k", "0", "1", "WORD"])), "ERR syntax error");
+        assert_eq!(error(run(&["k", "0", "1", "BIT", "x"])), "ERR syntax error");
+        assert_eq!(
+            error(run(&["k", "a", "1"])),
+            "ERR value is not an integer or out of range"
+        );
+    }
+    #[test]
+    fn test_native_integers() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let encoding = |encoding: &str| Resp::Bulk(Some(encoding.to_string()));
+
+        run(&["SET", "n", "41"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "n"]), encoding("int"));
+        assert_eq!(run(&["INCR", "n"]), Resp::Integer(42));
+        assert_eq!(run(&["OBJECT", "encoding", "n"]), encoding("int"));
+        assert_eq!(run(&["GET", "n"]), bulk("42"));
+        assert_eq!(run(&["STRLEN", "n"]), Resp::Integer(2));
+        assert_eq!(run(&["BITCOUNT", "n"]), Resp::Integer(6));
+        assert_eq!(run(&["APPEND", "n", "0"]), Resp::Integer(3));
+        assert_eq!(run(&["OBJECT", "ENCODING", "n"]), encoding("embstr"));
+        assert_eq!(run(&["INCRBY", "n", "-420"]), Resp::Integer(0));
+        assert_eq!(run(&["OBJECT", "ENCODING", "n"]), encoding("int"));
+
+        // Only strings that read back the same are kept as integers
+        for value in ["007", "+1", "-0", " 1", "99999999999999999999"] {
+            run(&["SET", "s", value]);
+            assert_eq!(run(&["OBJECT", "ENCODING", "s"]), encoding("embstr"));
+            assert_eq!(run(&["GET", "s"]), Resp::Bulk(Some(value.to_string())));
+        }
+        run(&["SET", "s", &"x".repeat(45)]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "s"]), encoding("raw"));
+        run(&["MSET", "m", "-9223372036854775808"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "m"]), encoding("int"));
+        assert_eq!(run(&["GETSET", "m", "1"]), bulk("-9223372036854775808"));
+
+        run(&["RPUSH", "l", "1"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "l"]), encoding("listpack"));
+        run(&["CONFIG", "SET", "list-max-listpack-size", "2"]);
+        run(&["RPUSH", "l", "2"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "l"]), encoding("listpack"));
+        run(&["LPUSH", "l", "0"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "l"]), encoding("quicklist"));
+        assert_eq!(
+            run(&["LRANGE", "l", "0", "-1"]),
+            Resp::Array(Some(
+                ["0", "1", "2"]
+                    .iter()
+                    .map(|item| Resp::Bulk(Some(item.to_string())))
+                    .collect()
+            ))
+        );
+
+        run(&["HSET", "h", "f", "v"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "h"]), encoding("listpack"));
+        run(&["CONFIG", "SET", "hash-max-listpack-value", "3"]);
+        run(&["HSET", "h", "g", "long"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "h"]), encoding("hashtable"));
+        assert_eq!(run(&["HGET", "h", "g"]), bulk("long"));
+        assert_eq!(run(&["OBJECT", "ENCODING", "missing"]), Resp::Bulk(None));
+        assert!(matches!(run(&["OBJECT", "FREQ", "n"]), Resp::Error(_)));
+    }
+
+    #[test]
+    fn test_string_updates_keep_ttl() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let live = |reply| matches!(reply, Resp::Integer(99..=100));
+
+        run(&["SET", "n", "1", "EX", "100"]);
+        assert_eq!(run(&["INCR", "n"]), Resp::Integer(2));
+        assert_eq!(run(&["DECRBY", "n", "5"]), Resp::Integer(-3));
+        assert!(live(run(&["TTL", "n"])));
+        assert_eq!(run(&["APPEND", "n", "x"]), Resp::Integer(3));
+        assert!(live(run(&["TTL", "n"])));
+        assert_eq!(run(&["GET", "n"]), bulk("-3x"));
+
+        run(&["SET", "s", "a", "PX", "100000"]);
+        assert_eq!(run(&["APPEND", "s", "bc"]), Resp::Integer(3));
+        assert!(live(run(&["TTL", "s"])));
+        run(&["SET", "s", "abc"]);
+        assert_eq!(run(&["TTL", "s"]), Resp::Integer(-1));
+
+        // An expired key is replaced, not updated
+        run(&["SET", "gone", "5", "PX", "1"]);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(run(&["INCR", "gone"]), Resp::Integer(1));
+        assert_eq!(run(&["TTL", "gone"]), Resp::Integer(-1));
+    }
+
+    #[test]
+    fn test_blocking_pop() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let popped = |key: &str, value: &str| {
+            Resp::Array(Some(vec![
+                Resp::Bulk(Some(key.to_string())),
+                Resp::Bulk(Some(value.to_string())),
+            ]))
+        };
+
+        run(&["RPUSH", "b", "x", "y"]);
+        assert_eq!(run(&["BLPOP", "a", "b", "0"]), popped("b", "x"));
+        assert_eq!(run(&["BRPOP", "a", "b", "0.5"]), popped("b", "y"));
+
+        // Inside a transaction nothing waits
+        run(&["MULTI"]);
+        run(&["BLPOP", "a", "1"]);
+        assert_eq!(run(&["EXEC"]), Resp::Array(Some(vec![Resp::Array(None)])));
+
+        let error = |reply| match reply {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(error(run(&["BLPOP", "a", "-1"])), "ERR timeout is negative");
+        assert_eq!(
+            error(run(&["BLPOP", "a", "soon"])),
+            "ERR timeout is not a float or out of range"
+        );
+        assert_eq!(
+            error(run(&["BLPOP", "a", "1e300"])),
+            "ERR timeout is not a float or out of range"
+        );
+        run(&["SET", "s", "v"]);
+        assert!(error(run(&["BLPOP", "a", "s", "0"])).starts_with("WRONGTYPE"));
+    }
+
+    #[test]
+    fn test_blocking_pop_waits_for_push() {
+        let server = Server::default();
+        let (mut first, mut second) = (ConnectionContext::new(1), ConnectionContext::new(2));
+        let mut pusher = ConnectionContext::new(3);
+
+        let reply = execute(&cmd(&["BLPOP", "q", "0"]), &server, &mut first);
+        assert_eq!(reply, Resp::Array(None));
+        assert_eq!(first.blocked.as_ref().unwrap().timeout, None);
+        execute(&cmd(&["BRPOP", "other", "q", "2.5"]), &server, &mut second);
+        assert_eq!(
+            second.blocked.as_ref().unwrap().timeout,
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(server.blocking.waiting(), 2);
+        assert_eq!(server.stats.dirty.load(Ordering::Relaxed), 0);
+
+        // The push serves both waiters, oldest first, before returning
+        let reply = execute(&cmd(&["RPUSH", "q", "a", "b", "c"]), &server, &mut pusher);
+        assert_eq!(reply, Resp::Integer(3));
+        let served = |ctx: &mut ConnectionContext| {
+            let mut blocked = ctx.blocked.take().unwrap();
+            (blocked.reply)(blocked.popped.try_recv().unwrap())
+        };
+        assert_eq!(
+            served(&mut first),
+            Resp::Array(Some(vec![bulk("q"), bulk("a")]))
+        );
+        assert_eq!(
+            served(&mut second),
+            Resp::Array(Some(vec![bulk("q"), bulk("c")]))
+        );
+        assert_eq!(server.blocking.waiting(), 0);
+        let reply = execute(&cmd(&["LRANGE", "q", "0", "-1"]), &server, &mut pusher);
+        assert_eq!(reply, Resp::Array(Some(vec![bulk("b")])));
+
+        // A transaction's writes are served after EXEC, so a waiter never
+        // sees what the transaction takes back
+        execute(&cmd(&["LPOP", "q"]), &server, &mut pusher);
+        execute(&cmd(&["BLPOP", "q", "0"]), &server, &mut first);
+        for args in [&["MULTI"][..], &["RPUSH", "q", "a", "b"], &["LPOP", "q"]] {
+            execute(&cmd(args), &server, &mut pusher);
+        }
+        assert_eq!(server.blocking.waiting(), 1);
+        let reply = execute(&cmd(&["EXEC"]), &server, &mut pusher);
+        assert_eq!(reply, Resp::Array(Some(vec![Resp::Integer(2), bulk("a")])));
+        assert_eq!(
+            served(&mut first),
+            Resp::Array(Some(vec![bulk("q"), bulk("b")]))
+        );
+    }
+
+    #[test]
+    fn test_blocking_zpop() {
+        let server = Server::default();
+        let array = |items: Vec<Resp>| Resp::Array(Some(items));
+        let (mut worker, mut other) = (ConnectionContext::new(1), ConnectionContext::new(2));
+        let mut scheduler = ConnectionContext::new(3);
+
+        execute(
+            &cmd(&["ZADD", "jobs", "5", "a", "7", "b"]),
+            &server,
+            &mut scheduler,
+        );
+        let reply = execute(
+            &cmd(&["BZPOPMIN", "none", "jobs", "0"]),
+            &server,
+            &mut worker,
+        );
+        assert_eq!(reply, array(vec![bulk("jobs"), bulk("a"), bulk("5")]));
+        let reply = execute(
+            &cmd(&["BZMPOP", "1", "1", "jobs", "MAX", "COUNT", "3"]),
+            &server,
+            &mut worker,
+        );
+        assert_eq!(
+            reply,
+            array(vec![
+                bulk("jobs"),
+                array(vec![array(vec![bulk("b"), bulk("7")])])
+            ])
+        );
+
+        // Waiting on the emptied key until a ZADD serves both, oldest first
+        let reply = execute(&cmd(&["BZPOPMAX", "jobs", "0"]), &server, &mut worker);
+        assert_eq!(reply, Resp::Array(None));
+        execute(
+            &cmd(&["BZMPOP", "0", "1", "jobs", "MIN"]),
+            &server,
+            &mut other,
+        );
+        assert_eq!(server.blocking.waiting(), 2);
+        let reply = execute(
+            &cmd(&["ZADD", "jobs", "1", "x", "2", "y", "3", "z"]),
+            &server,
+            &mut scheduler,
+        );
+        assert_eq!(reply, Resp::Integer(3));
+        let served = |ctx: &mut ConnectionContext| {
+            let mut blocked = ctx.blocked.take().unwrap();
+            (blocked.reply)(blocked.popped.try_recv().unwrap())
+        };
+        assert_eq!(
+            served(&mut worker),
+            array(vec![bulk("jobs"), bulk("z"), bulk("3")])
+        );
+        assert_eq!(
+            served(&mut other),
+            array(vec![
+                bulk("jobs"),
+                array(vec![array(vec![bulk("x"), bulk("1")])])
+            ])
+        );
+        let reply = execute(&cmd(&["ZCARD", "jobs"]), &server, &mut scheduler);
+        assert_eq!(reply, Resp::Integer(1));
+
+        // A list pushed to serves no sorted set waiter
+        execute(&cmd(&["BZPOPMIN", "q", "0"]), &server, &mut worker);
+        let reply = execute(&cmd(&["RPUSH", "q", "v"]), &server, &mut scheduler);
+        assert_eq!(reply, Resp::Integer(1));
+        assert_eq!(server.blocking.waiting(), 1);
+        let reply = execute(&cmd(&["LLEN", "q"]), &server, &mut scheduler);
+        assert_eq!(reply, Resp::Integer(1));
+        let reply = execute(&cmd(&["BZPOPMIN", "q", "0"]), &server, &mut other);
+        assert!(matches!(reply, Resp::Error(e) if e.starts_with("WRONGTYPE")));
+    }
+
+    #[test]
+    fn test_multi_pop() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let array = |items: Vec<Resp>| Resp::Array(Some(items));
+
+        run(&["RPUSH", "l", "a", "b", "c", "d", "e"]);
+        assert_eq!(run(&["LPOP", "l", "2"]), array(vec![bulk("a"), bulk("b")]));
+        assert_eq!(run(&["RPOP", "l", "1"]), array(vec![bulk("e")]));
+        assert_eq!(run(&["LPOP", "l", "0"]), array(vec![]));
+        assert_eq!(run(&["LPOP", "missing", "2"]), Resp::Array(None));
+        assert_eq!(run(&["LPOP", "missing"]), Resp::Bulk(None));
+        run(&["RPUSH", "short", "x", "y"]);
+        assert_eq!(
+            run(&["RPOP", "short", "5"]),
+            array(vec![bulk("y"), bulk("x")])
+        );
+        run(&["RPUSH", "short", "x", "y"]);
+        assert_eq!(
+            run(&["LPOP", "short", "2"]),
+            array(vec![bulk("x"), bulk("y")])
+        );
+        assert_eq!(run(&["EXISTS", "short"]), Resp::Integer(0));
+
+        assert_eq!(
+            run(&["LMPOP", "2", "missing", "l", "RIGHT", "COUNT", "5"]),
+            array(vec![bulk("l"), array(vec![bulk("d"), bulk("c")])])
+        );
+        assert_eq!(run(&["LMPOP", "1", "l", "left"]), Resp::Array(None));
+        run(&["RPUSH", "l", "x"]);
+        assert_eq!(
+            run(&["BLMPOP", "0", "1", "l", "LEFT"]),
+            array(vec![bulk("l"), array(vec![bulk("x")])])
+        );
+        run(&["MULTI"]);
+        run(&["BLMPOP", "0", "1", "l", "LEFT"]);
+        assert_eq!(run(&["EXEC"]), array(vec![Resp::Array(None)]));
+
+        let error = |reply| match reply {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(
+            error(run(&["LPOP", "l", "-1"])),
+            "ERR value is out of range, must be positive"
+        );
+        for args in [
+            &["LMPOP", "0", "l", "LEFT"][..],
+            &["LMPOP", "x", "l", "LEFT"],
+        ] {
+            assert_eq!(error(run(args)), "ERR numkeys should be greater than 0");
+        }
+        for args in [
+            &["LMPOP", "2", "l", "LEFT"][..],
+            &["LMPOP", "1", "l", "UP"],
+            &["LMPOP", "1", "l", "LEFT", "COUNT"],
+            &["LMPOP", "1", "l", "LEFT", "LIMIT", "1"],
+        ] {
+            assert_eq!(error(run(args)), "ERR syntax error", "{:?}", args);
+        }
+        assert_eq!(
+            error(run(&["LMPOP", "1", "l", "LEFT", "COUNT", "0"])),
+            "ERR count should be greater than 0"
+        );
+        run(&["SET", "s", "v"]);
+        assert!(error(run(&["LMPOP", "2", "l", "s", "LEFT"])).starts_with("WRONGTYPE"));
+
+        let spec = lookup("blmpop").unwrap();
+        let args: Vec<String> = ["0", "2", "a", "b", "LEFT"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(spec.keys(&args), vec!["a", "b"]);
+        assert_eq!(spec.keys(&args[..3]), vec!["a"]);
+        // A destination before the count comes first
+        let spec = lookup("zunionstore").unwrap();
+        let args: Vec<String> = ["d", "2", "a", "b"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(spec.keys(&args), vec!["d", "a", "b"]);
+    }
+
+    #[test]
+    fn test_failover_options() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let failover = |args: &[&str]| cmd(&[&["FAILOVER"], args].concat());
+        let mut error = |args| match execute(&failover(args), &server, &mut ctx) {
+            Resp::Error(e) => e,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(error(&["TO", "h"]), "ERR syntax error");
+        assert_eq!(
+            error(&["TIMEOUT", "0"]),
+            "ERR FAILOVER timeout must be greater than 0"
+        );
+        assert!(error(&["TO", "h", "1", "FORCE"]).contains("requires both a timeout"));
+        assert!(error(&["ABORT", "TIMEOUT", "5"]).contains("ABORT"));
+        assert_eq!(error(&["ABORT"]), "ERR No failover in progress.");
+        assert_eq!(error(&[]), "ERR FAILOVER requires connected replicas.");
+    }
+
+    #[test]
+    fn test_multi_exec() {
+        let server = Server::default();
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let id = server.clients.register(addr, addr, 7);
+        let mut ctx = ConnectionContext::new(id);
+        let queued = Resp::Simple("QUEUED".to_string());
+        let multi = || {
+            let line = server.clients.get(id).unwrap().to_line();
+            line.split(' ')
+                .find_map(|field| field.strip_prefix("multi="))
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(multi(), "-1");
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        assert_eq!(multi(), "0");
+        assert_eq!(execute(&cmd(&["SET", "k", "1"]), &server, &mut ctx), queued);
+        assert_eq!(execute(&cmd(&["INCR", "k"]), &server, &mut ctx), queued);
+        assert_eq!(execute(&cmd(&["GET", "k"]), &server, &mut ctx), queued);
+        assert_eq!(multi(), "3");
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Array(Some(vec![
+                Resp::Simple("OK".to_string()),
+                Resp::Integer(2),
+                bulk("2"),
+            ]))
+        );
+        assert_eq!(multi(), "-1");
+
+        // A queuing error aborts the transaction
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "k", "3"]), &server, &mut ctx);
+        assert!(matches!(
+            execute(&cmd(&["GET"]), &server, &mut ctx),
+            Resp::Error(_)
+        ));
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+        );
+        assert_eq!(execute(&cmd(&["GET", "k"]), &server, &mut ctx), bulk("2"));
+
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        execute(&cmd(&["DEL", "k"]), &server, &mut ctx);
+        assert_eq!(multi(), "1");
+        assert_eq!(
+            execute(&cmd(&["DISCARD"]), &server, &mut ctx),
+            Resp::Simple("OK".to_string())
+        );
+        assert_eq!(multi(), "-1");
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Error("ERR EXEC without MULTI".to_string())
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut other = ConnectionContext::new(2);
+
+        // Untouched watched keys let EXEC through
+        execute(&cmd(&["WATCH", "k"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "other", "1"]), &server, &mut other);
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "k", "mine"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Array(Some(vec![Resp::Simple("OK".to_string())]))
+        );
+
+        // A write from another client aborts it
+        execute(&cmd(&["WATCH", "k"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "k", "theirs"]), &server, &mut other);
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "k", "mine"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Array(None)
+        );
+        assert_eq!(
+            execute(&cmd(&["GET", "k"]), &server, &mut ctx),
+            bulk("theirs")
+        );
+
+        // So does flushing the database, but not after UNWATCH
+        execute(&cmd(&["WATCH", "k"]), &server, &mut ctx);
+        execute(&cmd(&["FLUSHDB"]), &server, &mut other);
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Array(None)
+        );
+
+        execute(&cmd(&["WATCH", "k"]), &server, &mut ctx);
+        execute(&cmd(&["UNWATCH"]), &server, &mut ctx);
+        execute(&cmd(&["SET", "k", "x"]), &server, &mut other);
+        execute(&cmd(&["MULTI"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut ctx),
+            Resp::Array(Some(vec![]))
+        );
+
+        // And so does the key expiring: whether it's removed on access, by
+        // the expire cycle, or not yet at all
+        let expire = |key: &str| {
+            let db = server.storage.db(0);
+            assert!(db.expire_at(key, 1, storage::ExpireOptions::default()));
+        };
+        let expire_cycle = crate::cron::CRON_TASKS
+            .iter()
+            .find(|task| task.name == "expire-cycle")
+            .unwrap();
+        for removal in ["access", "cycle", "none"] {
+            execute(&cmd(&["SET", "k", "v"]), &server, &mut other);
+            execute(&cmd(&["WATCH", "k"]), &server, &mut ctx);
+            expire("k");
+            match removal {
+                "access" => {
+                    execute(&cmd(&["GET", "k"]), &server, &mut other);
+                }
+                "cycle" => {
+                    (expire_cycle.run)(&server, Duration::from_secs(1));
+                }
+                _ => {}
+            }
+            execute(&cmd(&["MULTI"]), &server, &mut ctx);
+            assert_eq!(
+                execute(&cmd(&["EXEC"]), &server, &mut ctx),
+                Resp::Array(None),
+                "expired key removed on {}",
+                removal
+            );
+        }
+    }
+
+    #[test]
+    fn test_latency_histograms() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+
+        execute(&cmd(&["SET", "k", "v"]), &server, &mut ctx);
+        execute(&cmd(&["GET", "k"]), &server, &mut ctx);
+        execute(&cmd(&["GET", "k"]), &server, &mut ctx);
+
+        let Resp::Bulk(Some(info)) = execute(&cmd(&["INFO", "latencystats"]), &server, &mut ctx)
+        else {
+            panic!("INFO should reply with a bulk string");
+        };
+        assert!(info.contains("latency_percentiles_usec_get:p50="));
+        assert!(info.contains(",p99.9="));
+        assert!(!info.contains("latency_percentiles_usec_del"));
+
+        let Resp::Map(reply) = execute(
+            &cmd(&["LATENCY", "HISTOGRAM", "get", "del", "nosuch"]),
+            &server,
+            &mut ctx,
+        ) else {
+            panic!("LATENCY HISTOGRAM should reply with a map");
+        };
+        assert_eq!(reply.len(), 1);
+        assert_eq!(reply[0].0, bulk("get"));
+        let Resp::Map(details) = &reply[0].1 else {
+            panic!("expected histogram details");
+        };
+        assert_eq!(details[0].1, Resp::Integer(2));
+
+        execute(&cmd(&["CONFIG", "RESETSTAT"]), &server, &mut ctx);
+        assert_eq!(
+            execute(&cmd(&["LATENCY", "HISTOGRAM", "get"]), &server, &mut ctx),
+            Resp::Map(vec![])
+        );
+    }
+
+    #[test]
+    fn test_quit_and_client_reply() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+
+        execute(&cmd(&["CLIENT", "REPLY", "SKIP"]), &server, &mut ctx);
+        assert!(!ctx.take_reply());
+        execute(&cmd(&["PING"]), &server, &mut ctx);
+        assert!(!ctx.take_reply());
+        execute(&cmd(&["PING"]), &server, &mut ctx);
+        assert!(ctx.take_reply());
+
+        assert!(!ctx.closing);
+        execute(&cmd(&["QUIT"]), &server, &mut ctx);
+        assert!(ctx.closing);
+    }
+
+    #[test]
+    fn test_encode_resp() {
+        assert_eq!(
+            encode_resp(&Resp::Simple("OK".to_string()), Protocol::Resp2),
+            b"+OK\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_resp(&Resp::Error("ERR".to_string()), Protocol::Resp2),
+            b"-ERR\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_resp(&Resp::Integer(42), Protocol::Resp2),
+            b":42\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_resp(&Resp::Bulk(None), Protocol::Resp2),
+            b"$-1\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_resp(&bulk("hello"), Protocol::Resp2),
+            b"$5\r\nhello\r\n".to_vec()
+        );
+
+        let map = Resp::Map(vec![(bulk("a"), Resp::Bulk(None))]);
+        assert_eq!(
+            encode_resp(&map, Protocol::Resp2),
+            b"*2\r\n$1\r\na\r\n$-1\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_resp(&map, Protocol::Resp3),
+            b"%1\r\n$1\r\na\r\n_\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_hello_switches_to_resp3() {
+        let server = Server::default();
+        let local = "127.0.0.1:6379".parse().unwrap();
+        let id = server
+            .clients
+            .register("127.0.0.1:5000".parse().unwrap(), local, 7);
+        let mut ctx = ConnectionContext::new(id);
+        let mut run = runner(&server, &mut ctx);
+        let proto = |reply: Resp| {
+            let Resp::Map(fields) = reply else {
+                panic!("HELLO should reply with a map");
+            };
+            fields
+                .into_iter()
+                .find(|(field, _)| *field == bulk("proto"))
+                .map(|(_, value)| value)
+        };
+
+        assert_eq!(proto(run(&["HELLO"])), Some(Resp::Integer(2)));
+        assert_eq!(proto(run(&["HELLO", "3"])), Some(Resp::Integer(3)));
+        assert!(matches!(run(&["HELLO", "4"]), Resp::Error(e) if e.starts_with("NOPROTO")));
+        // Without a version, and after a failed switch, the protocol stays
+        assert_eq!(proto(run(&["HELLO"])), Some(Resp::Integer(3)));
+        let Resp::Bulk(Some(info)) = run(&["CLIENT", "INFO"]) else {
+            panic!("CLIENT INFO should reply with a bulk string");
+        };
+        assert!(info.contains(" resp=3 "));
+
+        run(&["HSET", "h", "f", "v"]);
+        let reply = run(&["HGETALL", "h"]);
+        assert_eq!(
+            encode_resp(&reply, Protocol::Resp3),
+            b"%1\r\n$1\r\nf\r\n$1\r\nv\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_resp(&reply, Protocol::Resp2),
+            b"*2\r\n$1\r\nf\r\n$1\r\nv\r\n".to_vec()
+        );
+        drop(run);
+        assert_eq!(ctx.protocol, Protocol::Resp3);
+    }
+
+    #[test]
+    fn test_srandmember() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let members = |reply: Resp| match reply {
+            Resp::Array(Some(items)) => items,
+            other => panic!("expected an array, got {:?}", other),
+        };
+
+        run(&["SADD", "s", "a", "b", "c"]);
+        assert!(matches!(run(&["SRANDMEMBER", "s"]), Resp::Bulk(Some(_))));
+        let distinct = members(run(&["SRANDMEMBER", "s", "2"]));
+        assert_eq!(distinct.len(), 2);
+        assert_ne!(distinct[0], distinct[1]);
+        assert_eq!(members(run(&["SRANDMEMBER", "s", "10"])).len(), 3);
+        assert_eq!(members(run(&["SRANDMEMBER", "s", "-10"])).len(), 10);
+        assert_eq!(run(&["SRANDMEMBER", "s", "0"]), Resp::Array(Some(vec![])));
+        assert_eq!(run(&["SCARD", "s"]), Resp::Integer(3));
+
+        assert_eq!(run(&["SRANDMEMBER", "missing"]), Resp::Bulk(None));
+        assert_eq!(
+            run(&["SRANDMEMBER", "missing", "-3"]),
+            Resp::Array(Some(vec![]))
+        );
+        assert!(matches!(run(&["SRANDMEMBER", "s", "x"]), Resp::Error(_)));
+        assert!(matches!(
+            run(&["SRANDMEMBER", "s", "-9223372036854775807"]),
+            Resp::Error(_)
+        ));
+        assert!(matches!(
+            run(&["SRANDMEMBER", "s", "1", "2"]),
+            Resp::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let sorted = |reply: Resp| match reply {
+            Resp::Array(Some(mut items)) => {
+                items.sort_by_key(|item| format!("{:?}", item));
+                items
+            }
+            other => panic!("expected an array, got {:?}", other),
+        };
+        let bulks = |items: &[&str]| -> Vec<Resp> {
+            items
+                .iter()
+                .map(|s| Resp::Bulk(Some(s.to_string())))
+                .collect()
+        };
+
+        run(&["SADD", "a", "1", "2", "3"]);
+        run(&["SADD", "b", "2", "3", "4"]);
+        run(&["SADD", "c", "3"]);
+        assert_eq!(sorted(run(&["SINTER", "a", "b", "c"])), bulks(&["3"]));
+        assert_eq!(
+            sorted(run(&["SUNION", "a", "b"])),
+            bulks(&["1", "2", "3", "4"])
+        );
+        assert_eq!(sorted(run(&["SDIFF", "a", "b"])), bulks(&["1"]));
+        assert_eq!(sorted(run(&["SINTER", "a", "missing"])), bulks(&[]));
+
+        run(&["SET", "dest", "v", "EX", "100"]);
+        assert_eq!(run(&["SUNIONSTORE", "dest", "a", "b"]), Resp::Integer(4));
+        assert_eq!(run(&["TYPE", "dest"]), Resp::Simple("set".to_string()));
+        assert_eq!(run(&["TTL", "dest"]), Resp::Integer(-1));
+        assert_eq!(run(&["SDIFFSTORE", "dest", "dest", "a"]), Resp::Integer(1));
+        assert_eq!(sorted(run(&["SMEMBERS", "dest"])), bulks(&["4"]));
+        assert_eq!(run(&["SINTERSTORE", "dest", "a", "c"]), Resp::Integer(1));
+        assert_eq!(
+            run(&["SINTERSTORE", "dest", "a", "missing"]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["EXISTS", "dest"]), Resp::Integer(0));
+
+        run(&["SET", "s", "v"]);
+        for cmd in ["SINTER", "SUNION", "SDIFF"] {
+            assert!(matches!(run(&[cmd, "a", "s"]), Resp::Error(e) if e.starts_with("WRONGTYPE")));
+        }
+        assert!(matches!(
+            run(&["SUNIONSTORE", "dest", "a", "s"]),
+            Resp::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+        assert_eq!(run(&["EXISTS", "dest"]), Resp::Integer(0));
+    }
+
+    #[test]
+    fn test_sorted_set_basics() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+
+        assert_eq!(
+            run(&["ZADD", "z", "1", "a", "2.5", "b", "-inf", "c"]),
+            Resp::Integer(3)
+        );
+        // Updating a score adds nothing
+        assert_eq!(run(&["ZADD", "z", "3", "a", "4", "d"]), Resp::Integer(1));
+        assert_eq!(run(&["ZCARD", "z"]), Resp::Integer(4));
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("3"));
+        assert_eq!(run(&["ZSCORE", "z", "b"]), bulk("2.5"));
+        assert_eq!(
+            run(&["ZMSCORE", "z", "a", "missing", "c"]),
+            Resp::Array(Some(vec![bulk("3"), Resp::Bulk(None), bulk("-inf")]))
+        );
+        assert_eq!(
+            run(&["ZMSCORE", "missing", "a"]),
+            Resp::Array(Some(vec![Resp::Bulk(None)]))
+        );
+        assert_eq!(run(&["ZSCORE", "z", "c"]), bulk("-inf"));
+        assert_eq!(run(&["ZSCORE", "z", "x"]), Resp::Bulk(None));
+        assert_eq!(run(&["ZSCORE", "missing", "a"]), Resp::Bulk(None));
+        assert_eq!(run(&["TYPE", "z"]), Resp::Simple("zset".to_string()));
+        assert_eq!(run(&["OBJECT", "ENCODING", "z"]), bulk("skiplist"));
+
+        assert_eq!(
+            run(&["ZADD", "z", "x", "a"]),
+            Resp::Error("ERR value is not a valid float".to_string())
+        );
+        assert_eq!(
+            run(&["ZADD", "z", "nan", "a"]),
+            Resp::Error("ERR value is not a valid float".to_string())
+        );
+        assert_eq!(
+            run(&["ZADD", "z", "1e400", "a"]),
+            Resp::Error("ERR value is not a valid float".to_string())
+        );
+        assert_eq!(
+            run(&["ZADD", "z", "1", "a", "2"]),
+            Resp::Error("ERR syntax error".to_string())
+        );
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("3"));
+
+        assert_eq!(run(&["ZINCRBY", "z", "0.5", "a"]), bulk("3.5"));
+        assert_eq!(run(&["ZINCRBY", "z", "-1e20", "e"]), bulk("-1e+20"));
+        assert_eq!(run(&["ZRANK", "z", "e"]), Resp::Integer(1));
+        assert_eq!(run(&["ZINCRBY", "z", "1", "a"]), bulk("4.5"));
+        assert_eq!(
+            run(&["ZINCRBY", "z", "+inf", "c"]),
+            Resp::Error("ERR resulting score is not a number (NaN)".to_string())
+        );
+        assert_eq!(run(&["ZSCORE", "z", "c"]), bulk("-inf"));
+        assert_eq!(
+            run(&["ZINCRBY", "z", "x", "a"]),
+            Resp::Error("ERR value is not a valid float".to_string())
+        );
+        assert_eq!(run(&["ZINCRBY", "new", "2", "m"]), bulk("2"));
+        assert_eq!(run(&["ZREM", "z", "e"]), Resp::Integer(1));
+        assert_eq!(run(&["ZADD", "z", "3", "a"]), Resp::Integer(0));
+
+        assert_eq!(run(&["ZREM", "z", "a", "x", "b"]), Resp::Integer(2));
+        assert_eq!(run(&["ZCARD", "z"]), Resp::Integer(2));
+        assert_eq!(run(&["ZREM", "z", "c", "d"]), Resp::Integer(2));
+        assert_eq!(run(&["EXISTS", "z"]), Resp::Integer(0));
+        assert_eq!(run(&["ZCARD", "z"]), Resp::Integer(0));
+        assert_eq!(run(&["ZREM", "z", "a"]), Resp::Integer(0));
+
+        run(&["SET", "s", "v"]);
+        for args in [
+            &["ZADD", "s", "1", "a"][..],
+            &["ZSCORE", "s", "a"],
+            &["ZMSCORE", "s", "a"],
+            &["ZCARD", "s"],
+        ] {
+            assert!(matches!(run(args), Resp::Error(e) if e.starts_with("WRONGTYPE")));
+        }
+    }
+
+    #[test]
+    fn test_zadd_options() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let error = |e: &str| Resp::Error(e.to_string());
+
+        // XX on a missing key neither adds nor creates it
+        assert_eq!(run(&["ZADD", "z", "XX", "1", "a"]), Resp::Integer(0));
+        assert_eq!(run(&["EXISTS", "z"]), Resp::Integer(0));
+        assert_eq!(
+            run(&["ZADD", "z", "XX", "INCR", "1", "a"]),
+            Resp::Bulk(None)
+        );
+        assert_eq!(run(&["EXISTS", "z"]), Resp::Integer(0));
+
+        run(&["ZADD", "z", "1", "a", "2", "b"]);
+        assert_eq!(
+            run(&["ZADD", "z", "NX", "5", "a", "3", "c"]),
+            Resp::Integer(1)
+        );
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("1"));
+        assert_eq!(
+            run(&["ZADD", "z", "XX", "5", "a", "4", "d"]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("5"));
+        assert_eq!(run(&["ZSCORE", "z", "d"]), Resp::Bulk(None));
+
+        // GT and LT hold existing members to the comparison, but still add
+        assert_eq!(
+            run(&["ZADD", "z", "GT", "CH", "4", "a", "3", "b", "1", "e"]),
+            Resp::Integer(2)
+        );
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("5"));
+        assert_eq!(run(&["ZSCORE", "z", "b"]), bulk("3"));
+        assert_eq!(
+            run(&["ZADD", "z", "LT", "CH", "4", "a", "3", "b"]),
+            Resp::Integer(1)
+        );
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("4"));
+
+        // INCR is ZINCRBY, nil when blocked
+        assert_eq!(run(&["ZADD", "z", "INCR", "1.5", "a"]), bulk("5.5"));
+        assert_eq!(
+            run(&["ZADD", "z", "incr", "GT", "-1", "a"]),
+            Resp::Bulk(None)
+        );
+        assert_eq!(
+            run(&["ZADD", "z", "NX", "INCR", "1", "a"]),
+            Resp::Bulk(None)
+        );
+        assert_eq!(run(&["ZADD", "z", "NX", "INCR", "1", "f"]), bulk("1"));
+        assert_eq!(run(&["ZSCORE", "z", "a"]), bulk("5.5"));
+
+        assert_eq!(
+            run(&["ZADD", "z", "NX", "XX", "1", "a"]),
+            error("ERR XX and NX options at the same time are not compatible")
+        );
+        for args in [["NX", "GT"], ["GT", "LT"], ["NX", "LT"]] {
+            assert_eq!(
+                run(&["ZADD", "z", args[0], args[1], "1", "a"]),
+                error("ERR GT, LT, and/or NX options at the same time are not compatible")
+            );
+        }
+        assert_eq!(
+            run(&["ZADD", "z", "INCR", "1", "a", "2", "b"]),
+            error("ERR INCR option supports a single increment-element pair")
+        );
+        assert_eq!(run(&["ZADD", "z", "CH", "1"]), error("ERR syntax error"));
+        assert_eq!(
+            run(&["ZADD", "z", "1", "a", "2"]),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&["ZADD", "z", "XX", "x", "a"]),
+            error("ERR value is not a valid float")
+        );
+    }
+
+    #[test]
+    fn test_zpop() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let array = |items: Vec<Resp>| Resp::Array(Some(items));
+        let error = |e: &str| Resp::Error(e.to_string());
+
+        run(&[
+            "ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e",
+        ]);
+        assert_eq!(run(&["ZPOPMIN", "z"]), array(vec![bulk("a"), bulk("1")]));
+        assert_eq!(
+            run(&["ZPOPMAX", "z", "2"]),
+            array(vec![bulk("e"), bulk("5"), bulk("d"), bulk("4")])
+        );
+        assert_eq!(run(&["ZPOPMIN", "z", "0"]), array(vec![]));
+        assert_eq!(run(&["ZPOPMIN", "missing"]), array(vec![]));
+        assert_eq!(
+            run(&["ZPOPMIN", "z", "-1"]),
+            error("ERR value is out of range, must be positive")
+        );
+        assert_eq!(run(&["ZPOPMIN", "z", "1", "2"]), error("ERR syntax error"));
+
+        assert_eq!(
+            run(&["ZMPOP", "2", "missing", "z", "MIN", "COUNT", "5"]),
+            array(vec![
+                bulk("z"),
+                array(vec![
+                    array(vec![bulk("b"), bulk("2")]),
+                    array(vec![bulk("c"), bulk("3")]),
+                ]),
+            ])
+        );
+        assert_eq!(run(&["EXISTS", "z"]), Resp::Integer(0));
+        assert_eq!(run(&["ZMPOP", "1", "z", "max"]), Resp::Array(None));
+        run(&["ZADD", "z", "1", "a"]);
+        assert_eq!(
+            run(&["ZMPOP", "1", "z", "MAX"]),
+            array(vec![
+                bulk("z"),
+                array(vec![array(vec![bulk("a"), bulk("1")])])
+            ])
+        );
+        assert_eq!(run(&["ZMPOP", "1", "z", "LEFT"]), error("ERR syntax error"));
+        assert_eq!(
+            run(&["ZMPOP", "0", "z", "MIN"]),
+            error("ERR numkeys should be greater than 0")
+        );
+        assert_eq!(
+            run(&["ZMPOP", "1", "z", "MIN", "COUNT", "0"]),
+            error("ERR count should be greater than 0")
+        );
+        run(&["SET", "s", "v"]);
+        assert!(matches!(
+            run(&["ZMPOP", "2", "z", "s", "MIN"]),
+            Resp::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[test]
+    fn test_combine_zsets() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let bulks = |items: &[&str]| {
+            Resp::Array(Some(
+                items
+                    .iter()
+                    .map(|s| Resp::Bulk(Some(s.to_string())))
+                    .collect(),
+            ))
+        };
+        let error = |e: &str| Resp::Error(e.to_string());
+
+        run(&["ZADD", "z1", "1", "a", "2", "b", "3", "c"]);
+        run(&["ZADD", "z2", "10", "b", "20", "c", "30", "d"]);
+        // A set's members all score 1
+        run(&["SADD", "s", "a", "d"]);
+
+        assert_eq!(
+            run(&["ZUNION", "2", "z1", "z2", "WITHSCORES"]),
+            bulks(&["a", "1", "b", "12", "c", "23", "d", "30"])
+        );
+        assert_eq!(
+            run(&["ZUNION", "3", "z1", "z2", "s", "WEIGHTS", "2", "1", "3"]),
+            bulks(&["a", "b", "c", "d"])
+        );
+        assert_eq!(
+            run(&["ZINTER", "2", "z1", "z2", "AGGREGATE", "max", "WITHSCORES"]),
+            bulks(&["b", "10", "c", "20"])
+        );
+        assert_eq!(
+            run(&["ZINTER", "2", "z1", "s", "WEIGHTS", "-1", "5", "WITHSCORES"]),
+            bulks(&["a", "4"])
+        );
+        assert_eq!(run(&["ZINTER", "2", "z1", "missing"]), bulks(&[]));
+        assert_eq!(
+            run(&["ZDIFF", "3", "z1", "s", "missing", "WITHSCORES"]),
+            bulks(&["b", "2", "c", "3"])
+        );
+
+        // Stored replacing what the destination held, or deleting it
+        run(&["SET", "dest", "v"]);
+        assert_eq!(
+            run(&["ZUNIONSTORE", "dest", "2", "z1", "s", "AGGREGATE", "MIN"]),
+            Resp::Integer(4)
+        );
+        assert_eq!(
+            run(&["ZRANGE", "dest", "0", "-1", "WITHSCORES"]),
+            bulks(&["a", "1", "d", "1", "b", "2", "c", "3"])
+        );
+        assert_eq!(
+            run(&["ZINTERSTORE", "dest", "2", "z1", "z2"]),
+            Resp::Integer(2)
+        );
+        assert_eq!(run(&["ZSCORE", "dest", "c"]), bulk("23"));
+        assert_eq!(
+            run(&["ZDIFFSTORE", "dest", "2", "z1", "z1"]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["EXISTS", "dest"]), Resp::Integer(0));
+
+        assert_eq!(
+            run(&["ZUNION", "0", "z1"]),
+            error("ERR at least 1 input key is needed for 'zunion' command")
+        );
+        assert_eq!(
+            run(&["ZUNIONSTORE", "dest", "0", "z1"]),
+            error("ERR at least 1 input key is needed for 'zunionstore' command")
+        );
+        assert_eq!(run(&["ZUNION", "3", "z1", "z2"]), error("ERR syntax error"));
+        assert_eq!(
+            run(&["ZUNION", "2", "z1", "z2", "WEIGHTS", "1"]),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&["ZUNION", "1", "z1", "WEIGHTS", "x"]),
+            error("ERR weight value is not a float")
+        );
+        assert_eq!(
+            run(&["ZDIFF", "1", "z1", "WEIGHTS", "1"]),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&["ZINTERSTORE", "dest", "1", "z1", "WITHSCORES"]),
+            error("ERR syntax error")
+        );
+        run(&["SET", "str", "v"]);
+        assert!(matches!(
+            run(&["ZUNION", "2", "z1", "str"]),
+            Resp::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[test]
+    fn test_geo() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let array = |items: Vec<Resp>| Resp::Array(Some(items));
+        let error = |e: &str| Resp::Error(e.to_string());
+
+        assert_eq!(
+            run(&[
+                "GEOADD",
+                "Sicily",
+                "13.361389",
+                "38.115556",
+                "Palermo",
+                "15.087269",
+                "37.502669",
+                "Catania",
+            ]),
+            Resp::Integer(2)
+        );
+        // Stored as a sorted set scored by geohash
+        assert_eq!(
+            run(&["ZSCORE", "Sicily", "Palermo"]),
+            bulk("3479099956230698")
+        );
+        assert_eq!(
+            run(&["GEOPOS", "Sicily", "Palermo", "missing"]),
+            array(vec![
+                array(vec![
+                    bulk("13.36138933897018433"),
+                    bulk("38.11555639549629859")
+                ]),
+                Resp::Array(None),
+            ])
+        );
+        assert_eq!(
+            run(&["GEODIST", "Sicily", "Palermo", "Catania"]),
+            bulk("166274.1516")
+        );
+        assert_eq!(
+            run(&["GEODIST", "Sicily", "Palermo", "Catania", "km"]),
+            bulk("166.2742")
+        );
+        assert_eq!(
+            run(&["GEODIST", "Sicily", "Palermo", "missing"]),
+            Resp::Bulk(None)
+        );
+
+        run(&[
+            "GEOADD",
+            "Sicily",
+            "12.758489",
+            "38.788135",
+            "edge1",
+            "17.241510",
+            "38.788135",
+            "edge2",
+        ]);
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYRADIUS",
+                "200",
+                "km",
+                "ASC"
+            ]),
+            array(vec![bulk("Catania"), bulk("Palermo")])
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYBOX",
+                "400",
+                "400",
+                "km",
+                "DESC",
+                "WITHDIST",
+            ]),
+            array(vec![
+                array(vec![bulk("edge1"), bulk("279.7405")]),
+                array(vec![bulk("edge2"), bulk("279.7403")]),
+                array(vec![bulk("Palermo"), bulk("190.4424")]),
+                array(vec![bulk("Catania"), bulk("56.4413")]),
+            ])
+        );
+        // COUNT keeps the nearest
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMMEMBER",
+                "Palermo",
+                "BYRADIUS",
+                "500",
+                "km",
+                "COUNT",
+                "2",
+                "WITHHASH",
+            ]),
+            array(vec![
+                array(vec![bulk("Palermo"), Resp::Integer(3479099956230698)]),
+                array(vec![bulk("edge1"), Resp::Integer(3479273021651468)]),
+            ])
+        );
+
+        assert_eq!(
+            run(&[
+                "GEOSEARCHSTORE",
+                "near",
+                "Sicily",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYRADIUS",
+                "100",
+                "km",
+                "STOREDIST",
+            ]),
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            run(&["ZRANGE", "near", "0", "-1"]),
+            array(vec![bulk("Catania")])
+        );
+        assert!(matches!(
+            run(&["ZSCORE", "near", "Catania"]),
+            Resp::Bulk(Some(score)) if score.starts_with("56.441")
+        ));
+        assert_eq!(
+            run(&[
+                "GEOSEARCHSTORE",
+                "near",
+                "Sicily",
+                "FROMLONLAT",
+                "0",
+                "0",
+                "BYRADIUS",
+                "1",
+                "m",
+            ]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["EXISTS", "near"]), Resp::Integer(0));
+
+        assert_eq!(
+            run(&["GEOADD", "Sicily", "181", "0", "far"]),
+            error("ERR invalid longitude,latitude pair 181.000000,0.000000")
+        );
+        assert_eq!(
+            run(&["GEOADD", "Sicily", "XX", "NX", "0", "0", "m"]),
+            error("ERR XX and NX options at the same time are not compatible")
+        );
+        assert_eq!(
+            run(&["GEODIST", "Sicily", "Palermo", "Catania", "yd"]),
+            error("ERR unsupported unit provided. please use M, KM, FT, MI")
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "BYRADIUS",
+                "1",
+                "km",
+                "ASC",
+                "WITHDIST"
+            ]),
+            error("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH")
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMMEMBER",
+                "Palermo",
+                "ASC",
+                "COUNT",
+                "1"
+            ]),
+            error("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH")
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMMEMBER",
+                "Palermo",
+                "BYRADIUS",
+                "1",
+                "km",
+                "ANY"
+            ]),
+            error("ERR the ANY argument requires COUNT argument")
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMMEMBER",
+                "x",
+                "BYRADIUS",
+                "1",
+                "km"
+            ]),
+            error("ERR could not decode requested zset member")
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCHSTORE",
+                "near",
+                "Sicily",
+                "FROMMEMBER",
+                "Palermo",
+                "BYRADIUS",
+                "1",
+                "km",
+                "WITHDIST",
+            ]),
+            error(
+                "ERR GEOSEARCHSTORE is not compatible with WITHDIST, WITHHASH and WITHCOORD options"
+            )
+        );
+        assert_eq!(
+            run(&[
+                "GEOSEARCH",
+                "Sicily",
+                "FROMMEMBER",
+                "Palermo",
+                "BYRADIUS",
+                "-1",
+                "km"
+            ]),
+            error("ERR radius cannot be negative")
+        );
+    }
+
+    #[test]
+    fn test_streams() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let error = |e: &str| Resp::Error(e.to_string());
+        let entry = |id: &str, fields: &[&str]| {
+            Resp::Array(Some(vec![
+                bulk(id),
+                Resp::Array(Some(fields.iter().map(|s| bulk(s)).collect())),
+            ]))
+        };
+
+        assert_eq!(run(&["XADD", "s", "1-1", "a", "1"]), bulk("1-1"));
+        assert_eq!(run(&["XADD", "s", "1-*", "b", "2"]), bulk("1-2"));
+        assert_eq!(run(&["XADD", "s", "3", "c", "3", "d", "4"]), bulk("3-0"));
+        assert_eq!(run(&["XLEN", "s"]), Resp::Integer(3));
+        assert_eq!(run(&["TYPE", "s"]), Resp::Simple("stream".to_string()));
+
+        assert_eq!(
+            run(&["XADD", "s", "2-0", "a", "1"]),
+            error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+        assert_eq!(
+            run(&["XADD", "t", "0-0", "a", "1"]),
+            error("ERR The ID specified in XADD must be greater than 0-0")
+        );
+        assert_eq!(
+            run(&["XADD", "t", "x-1", "a", "1"]),
+            error("ERR Invalid stream ID specified as stream command argument")
+        );
+        assert_eq!(
+            run(&["XADD", "t", "*", "a", "1", "b"]),
+            error("ERR wrong number of arguments for 'xadd' command")
+        );
+        assert_eq!(
+            run(&["XADD", "t", "NOMKSTREAM", "*", "a", "1"]),
+            Resp::Bulk(None)
+        );
+        assert_eq!(run(&["EXISTS", "t"]), Resp::Integer(0));
+
+        assert_eq!(
+            run(&["XRANGE", "s", "-", "+"]),
+            Resp::Array(Some(vec![
+                entry("1-1", &["a", "1"]),
+                entry("1-2", &["b", "2"]),
+                entry("3-0", &["c", "3", "d", "4"]),
+            ]))
+        );
+        // A bare time covers the whole millisecond; ( leaves an ID out
+        assert_eq!(
+            run(&["XRANGE", "s", "1", "1", "COUNT", "1"]),
+            Resp::Array(Some(vec![entry("1-1", &["a", "1"])]))
+        );
+        assert_eq!(
+            run(&["XRANGE", "s", "(1-1", "(3-0"]),
+            Resp::Array(Some(vec![entry("1-2", &["b", "2"])]))
+        );
+        assert_eq!(
+            run(&["XREVRANGE", "s", "+", "-", "COUNT", "2"]),
+            Resp::Array(Some(vec![
+                entry("3-0", &["c", "3", "d", "4"]),
+                entry("1-2", &["b", "2"]),
+            ]))
+        );
+        assert_eq!(
+            run(&["XRANGE", "s", "-", "+", "COUNT", "0"]),
+            Resp::Array(None)
+        );
+        assert_eq!(run(&["XRANGE", "s", "3", "1"]), Resp::Array(Some(vec![])));
+        assert_eq!(
+            run(&["XRANGE", "missing", "-", "+"]),
+            Resp::Array(Some(vec![]))
+        );
+        assert_eq!(
+            run(&[
+                "XRANGE",
+                "s",
+                "(18446744073709551615-18446744073709551615",
+                "+"
+            ]),
+            error("ERR invalid start ID for the interval")
+        );
+        assert_eq!(
+            run(&["XRANGE", "s", "(-", "+"]),
+            error("ERR Invalid stream ID specified as stream command argument")
+        );
+        assert_eq!(
+            run(&["XRANGE", "s", "-", "+", "LIMIT", "1"]),
+            error("ERR syntax error")
+        );
+
+        run(&["SET", "str", "v"]);
+        assert_eq!(
+            run(&["XADD", "str", "*", "a", "1"]),
+            error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+        assert_eq!(
+            run(&["XLEN", "str"]),
+            error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+
+        // Propagated with the ID the entry got
+        drop(run);
+        let xadd = cmd(&["XADD", "s", "NOMKSTREAM", "4-*", "e", "5"]);
+        assert_eq!(cmd_xadd(&xadd, server.storage.db(0), &mut ctx), bulk("4-0"));
+        assert_eq!(
+            ctx.propagate_as,
+            Some(vec![
+                ["xadd", "s", "NOMKSTREAM", "4-0", "e", "5"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            ])
+        );
+    }
 
-pub fn encode_resp(resp: &Resp) -> Vec<u8> {
-    match resp {
-        Resp::Simple(s) => format!("+{}\r\n", s).into_bytes(),
-        Resp::Error(e) => format!("-{}\r\n", e).into_bytes(),
-        Resp::Integer(i) => format!(":{}\r\n", i).into_bytes(),
-        Resp::Bulk(None) => b"$-1\r\n".to_vec(),
-        Resp::Bulk(Some(s)) => {
-            let mut result = format!("${}\r\n", s.len()).into_bytes();
-            result.extend(s.as_bytes());
-            result.extend(b"\r\n");
-            result
+    #[test]
+    fn test_stream_trimming() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let error = |e: &str| Resp::Error(e.to_string());
+        let strings = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        for ms in 1..=250 {
+            run(&["XADD", "s", &ms.to_string(), "n", "v"]);
         }
-        Resp::Array(None) => b"*-1\r\n".to_vec(),
-        Resp::Array(Some(items)) => {
-            let mut result = format!("*{}\r\n", items.len()).into_bytes();
-            for item in items {
-                result.extend(encode_resp(item));
-            }
-            result
+        assert_eq!(
+            run(&["XTRIM", "s", "MAXLEN", "~", "120"]),
+            Resp::Integer(100)
+        );
+        assert_eq!(run(&["XTRIM", "s", "MAXLEN", "~", "120"]), Resp::Integer(0));
+        assert_eq!(
+            run(&["XTRIM", "s", "MINID", "~", "240", "LIMIT", "50"]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["XTRIM", "s", "MINID", "201"]), Resp::Integer(100));
+        assert_eq!(run(&["XTRIM", "s", "MAXLEN", "=", "10"]), Resp::Integer(40));
+        assert_eq!(run(&["XLEN", "s"]), Resp::Integer(10));
+        assert_eq!(run(&["XTRIM", "missing", "MAXLEN", "0"]), Resp::Integer(0));
+        assert_eq!(run(&["EXISTS", "missing"]), Resp::Integer(0));
+
+        assert!(matches!(
+            run(&["XADD", "s", "MAXLEN", "2", "*", "n", "v"]),
+            Resp::Bulk(Some(_))
+        ));
+        assert_eq!(run(&["XLEN", "s"]), Resp::Integer(2));
+        assert_eq!(
+            run(&["XADD", "t", "NOMKSTREAM", "MINID", "5", "*", "n", "v"]),
+            Resp::Bulk(None)
+        );
+        // Trimming every entry away keeps the stream, and its last ID
+        run(&["XADD", "t", "MAXLEN", "0", "7-0", "n", "v"]);
+        assert_eq!(run(&["XLEN", "t"]), Resp::Integer(0));
+        assert_eq!(
+            run(&["XADD", "t", "7-0", "n", "v"]),
+            error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+
+        assert_eq!(
+            run(&["XTRIM", "s"]),
+            error("ERR wrong number of arguments for 'xtrim' command")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "LIMIT", "5"]),
+            error("ERR syntax error, LIMIT cannot be used without specifying a trimming strategy")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "MAXLEN", "5", "LIMIT", "5"]),
+            error("ERR syntax error, LIMIT cannot be used without the special ~ option")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "MAXLEN", "5", "MINID", "5"]),
+            error("ERR syntax error, MAXLEN and MINID options at the same time are not compatible")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "MAXLEN", "-1"]),
+            error("ERR The MAXLEN argument must be >= 0.")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "MAXLEN", "~", "1", "LIMIT", "-1"]),
+            error("ERR The LIMIT argument must be >= 0.")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "MINID", "x"]),
+            error("ERR Invalid stream ID specified as stream command argument")
+        );
+        assert_eq!(
+            run(&["XTRIM", "s", "MAXLEN", "1", "NOMKSTREAM"]),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&["XADD", "s", "MAXLEN", "5", "LIMIT", "5", "*", "n", "v"]),
+            error("ERR syntax error, LIMIT cannot be used without the special ~ option")
+        );
+
+        // Both are propagated with the exact trim they made
+        let db = server.storage.db(0);
+        for ms in 10..=310 {
+            db.xadd(
+                "u",
+                NewId::Explicit(StreamId::new(ms, 0)),
+                vec![],
+                false,
+                None,
+            )
+            .unwrap();
         }
+        let mut ctx = ConnectionContext::new(2);
+        assert_eq!(
+            cmd_xadd(
+                &cmd(&["XADD", "u", "MAXLEN", "~", "0", "311", "n", "v"]),
+                db,
+                &mut ctx
+            ),
+            bulk("311-0")
+        );
+        assert_eq!(
+            ctx.propagate_as,
+            Some(vec![strings(&[
+                "xadd", "u", "MAXLEN", "=", "2", "311-0", "n", "v"
+            ])])
+        );
+        assert_eq!(
+            cmd_xtrim(&cmd(&["XTRIM", "u", "MINID", "~", "1000"]), db, &mut ctx),
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            ctx.propagate_as,
+            Some(vec![strings(&["xtrim", "u", "MINID", "=", "310-0"])])
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn test_ping() {
-        let storage = Storage::new();
-        let cmd = Command {
-            name: "PING".to_string(),
-            args: vec![],
-        };
-        assert_eq!(execute(&cmd, &storage), Resp::Simple("PONG".to_string()));
+    fn test_xread() {
+        let server = Server::default();
+        let (mut reader, mut writer) = (ConnectionContext::new(1), ConnectionContext::new(2));
+        let array = |items: Vec<Resp>| Resp::Array(Some(items));
+        let error = |e: &str| Resp::Error(e.to_string());
+        let entry =
+            |id: &str, value: &str| array(vec![bulk(id), array(vec![bulk("f"), bulk(value)])]);
+
+        for (key, id) in [("a", "1-0"), ("a", "2-0"), ("b", "5-0")] {
+            execute(&cmd(&["XADD", key, id, "f", id]), &server, &mut writer);
+        }
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "STREAMS", "a", "b", "1", "0"]),
+                &server,
+                &mut reader
+            ),
+            array(vec![
+                array(vec![bulk("a"), array(vec![entry("2-0", "2-0")])]),
+                array(vec![bulk("b"), array(vec![entry("5-0", "5-0")])]),
+            ])
+        );
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "COUNT", "1", "STREAMS", "a", "0-0"]),
+                &server,
+                &mut reader
+            ),
+            array(vec![array(vec![
+                bulk("a"),
+                array(vec![entry("1-0", "1-0")])
+            ])])
+        );
+        // Nothing past the last IDs, and not waiting without BLOCK
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "STREAMS", "a", "missing", "$", "$"]),
+                &server,
+                &mut reader
+            ),
+            Resp::Array(None)
+        );
+        assert!(reader.blocked.is_none());
+        assert_eq!(
+            lookup("xread")
+                .unwrap()
+                .keys(&cmd(&["XREAD", "COUNT", "2", "STREAMS", "a", "b", "0", "0"]).args),
+            ["a", "b"]
+        );
+
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "STREAMS", "a", "b", "0"]),
+                &server,
+                &mut reader
+            ),
+            error(
+                "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+            )
+        );
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "COUNT", "1", "a", "0"]),
+                &server,
+                &mut reader
+            ),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "BLOCK", "-1", "STREAMS", "a", "0"]),
+                &server,
+                &mut reader
+            ),
+            error("ERR timeout is negative")
+        );
+        assert_eq!(
+            execute(&cmd(&["XREAD", "STREAMS", "a", "x"]), &server, &mut reader),
+            error("ERR Invalid stream ID specified as stream command argument")
+        );
+        execute(&cmd(&["SET", "str", "v"]), &server, &mut writer);
+        assert_eq!(
+            execute(
+                &cmd(&["XREAD", "STREAMS", "a", "str", "0", "0"]),
+                &server,
+                &mut reader
+            ),
+            error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+
+        // Blocked on the streams' ends until an XADD to one of them
+        let reply = execute(
+            &cmd(&["XREAD", "BLOCK", "1500", "STREAMS", "a", "new", "$", "$"]),
+            &server,
+            &mut reader,
+        );
+        assert_eq!(reply, Resp::Array(None));
+        assert_eq!(
+            reader.blocked.as_ref().unwrap().timeout,
+            Some(Duration::from_millis(1500))
+        );
+        execute(
+            &cmd(&["XADD", "new", "7-0", "f", "7-0"]),
+            &server,
+            &mut writer,
+        );
+        let mut blocked = reader.blocked.take().unwrap();
+        assert_eq!(
+            (blocked.reply)(blocked.popped.try_recv().unwrap()),
+            array(vec![array(vec![
+                bulk("new"),
+                array(vec![entry("7-0", "7-0")])
+            ])])
+        );
+        assert_eq!(server.blocking.waiting(), 0);
+
+        // BLOCK 0 waits forever, but not inside a transaction
+        execute(
+            &cmd(&["XREAD", "BLOCK", "0", "STREAMS", "a", "$"]),
+            &server,
+            &mut reader,
+        );
+        assert_eq!(reader.blocked.take().unwrap().timeout, None);
+        server.blocking.unblock(reader.id);
+        execute(&cmd(&["MULTI"]), &server, &mut reader);
+        execute(
+            &cmd(&["XREAD", "BLOCK", "0", "STREAMS", "a", "$"]),
+            &server,
+            &mut reader,
+        );
+        assert_eq!(
+            execute(&cmd(&["EXEC"]), &server, &mut reader),
+            array(vec![Resp::Array(None)])
+        );
+        assert!(reader.blocked.is_none());
     }
 
     #[test]
-    fn test_ping_with_message() {
-        let storage = Storage::new();
-        let cmd = Command {
-            name: "PING".to_string(),
-            args: vec!["hello".to_string()],
+    fn test_consumer_groups() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let array = |items: Vec<Resp>| Resp::Array(Some(items));
+        let error = |e: &str| Resp::Error(e.to_string());
+        let entry = |id: &str| array(vec![bulk(id), array(vec![bulk("f"), bulk(id)])]);
+        let ok = Resp::Simple("OK".to_string());
+
+        assert_eq!(
+            run(&["XGROUP", "CREATE", "s", "g", "$"]),
+            error(
+                "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+            )
+        );
+        assert_eq!(run(&["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]), ok);
+        assert_eq!(
+            run(&["XGROUP", "CREATE", "s", "g", "0"]),
+            error("BUSYGROUP Consumer Group name already exists")
+        );
+        for id in ["1-0", "2-0", "3-0"] {
+            run(&["XADD", "s", id, "f", id]);
+        }
+
+        // Only what's added after the group was created is new to it
+        assert_eq!(
+            run(&[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "alice",
+                "COUNT",
+                "2",
+                "STREAMS",
+                "s",
+                ">"
+            ]),
+            array(vec![array(vec![
+                bulk("s"),
+                array(vec![entry("1-0"), entry("2-0")])
+            ])])
+        );
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]),
+            array(vec![array(vec![bulk("s"), array(vec![entry("3-0")])])])
+        );
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]),
+            Resp::Array(None)
+        );
+        // History, even when there's none
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "1-0"]),
+            array(vec![array(vec![bulk("s"), array(vec![entry("2-0")])])])
+        );
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "g", "carol", "STREAMS", "s", "0"]),
+            array(vec![array(vec![bulk("s"), array(vec![])])])
+        );
+
+        assert_eq!(
+            run(&["XPENDING", "s", "g"]),
+            array(vec![
+                Resp::Integer(3),
+                bulk("1-0"),
+                bulk("3-0"),
+                array(vec![
+                    array(vec![bulk("alice"), bulk("2")]),
+                    array(vec![bulk("bob"), bulk("1")]),
+                ]),
+            ])
+        );
+        let detail = run(&["XPENDING", "s", "g", "-", "+", "10", "alice"]);
+        let Resp::Array(Some(pending)) = detail else {
+            panic!("expected the pending entries, got {:?}", detail);
+        };
+        assert_eq!(pending.len(), 2);
+        let Resp::Array(Some(first)) = &pending[1] else {
+            panic!("expected a pending entry");
+        };
+        assert_eq!(
+            (&first[0], &first[1], &first[3]),
+            (&bulk("2-0"), &bulk("alice"), &Resp::Integer(2))
+        );
+        assert_eq!(
+            run(&["XPENDING", "s", "g", "IDLE", "60000", "-", "+", "10"]),
+            array(vec![])
+        );
+
+        assert_eq!(
+            run(&["XACK", "s", "g", "1-0", "1-0", "9-0"]),
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            run(&["XCLAIM", "s", "g", "bob", "0", "2-0", "JUSTID"]),
+            array(vec![bulk("2-0")])
+        );
+        assert_eq!(
+            run(&["XAUTOCLAIM", "s", "g", "alice", "0", "0", "COUNT", "1"]),
+            array(vec![bulk("3-0"), array(vec![entry("2-0")]), array(vec![])])
+        );
+        assert_eq!(
+            run(&["XAUTOCLAIM", "s", "g", "alice", "0", "3-0", "JUSTID"]),
+            array(vec![bulk("0-0"), array(vec![bulk("3-0")]), array(vec![])])
+        );
+        assert_eq!(
+            run(&["XPENDING", "s", "g"]),
+            array(vec![
+                Resp::Integer(2),
+                bulk("2-0"),
+                bulk("3-0"),
+                array(vec![array(vec![bulk("alice"), bulk("2")])]),
+            ])
+        );
+        assert_eq!(
+            run(&["XGROUP", "CREATECONSUMER", "s", "g", "dave"]),
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            run(&["XGROUP", "CREATECONSUMER", "s", "g", "dave"]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["XGROUP", "SETID", "s", "g", "0"]), ok);
+        assert_eq!(run(&["XGROUP", "DESTROY", "s", "g"]), Resp::Integer(1));
+        assert_eq!(run(&["XGROUP", "DESTROY", "s", "g"]), Resp::Integer(0));
+
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]),
+            error("NOGROUP No such key 's' or consumer group 'g' in XREADGROUP with GROUP option")
+        );
+        assert_eq!(
+            run(&["XGROUP", "SETID", "s", "g", "0"]),
+            error("NOGROUP No such consumer group 'g' for key name 's'")
+        );
+        assert_eq!(
+            run(&["XPENDING", "s", "g"]),
+            error("NOGROUP No such key 's' or consumer group 'g'")
+        );
+        assert_eq!(
+            run(&["XREAD", "STREAMS", "s", ">"]),
+            error(
+                "ERR The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option."
+            )
+        );
+        assert_eq!(
+            run(&["XREADGROUP", "COUNT", "1", "NOACK", "STREAMS", "s", ">"]),
+            error("ERR Missing GROUP option for XREADGROUP")
+        );
+        run(&["XGROUP", "CREATE", "s", "g", "0"]);
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", "$"]),
+            error(
+                "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set."
+            )
+        );
+        assert_eq!(
+            run(&["XCLAIM", "s", "g", "c", "x", "1-0"]),
+            error("ERR Invalid min-idle-time argument for XCLAIM")
+        );
+        assert_eq!(
+            run(&["XCLAIM", "s", "g", "c", "0", "1-0", "BOGUS"]),
+            error("ERR Unrecognized XCLAIM option 'BOGUS'")
+        );
+        assert_eq!(
+            run(&["XAUTOCLAIM", "s", "g", "c", "0", "0", "COUNT", "0"]),
+            error("ERR COUNT must be > 0")
+        );
+        assert_eq!(
+            lookup("xreadgroup").unwrap().keys(
+                &cmd(&[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    "c",
+                    "STREAMS",
+                    "a",
+                    "b",
+                    ">",
+                    ">"
+                ])
+                .args
+            ),
+            ["a", "b"]
+        );
+
+        // Propagated as the changes it made, so replicas don't read
+        let mut ctx = ConnectionContext::new(2);
+        let read = cmd(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "erin",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "s",
+            ">",
+        ]);
+        cmd_xreadgroup(&read, &server, &mut ctx);
+        let propagated: Vec<Vec<String>> = ctx.propagate_as.take().unwrap();
+        let names: Vec<String> = propagated
+            .iter()
+            .map(|parts| parts[..2].join(" "))
+            .collect();
+        assert_eq!(names, ["xgroup createconsumer", "xclaim s", "xgroup setid"]);
+        assert_eq!(
+            propagated[2],
+            ["xgroup", "setid", "s", "g", "1-0", "ENTRIESREAD", "1"]
+        );
+
+        // Blocked until an XADD brings an entry new to the group
+        let block = cmd(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "erin",
+            "BLOCK",
+            "0",
+            "STREAMS",
+            "s",
+            ">",
+        ]);
+        execute(
+            &cmd(&["XREADGROUP", "GROUP", "g", "erin", "STREAMS", "s", ">"]),
+            &server,
+            &mut ctx,
+        );
+        let nothing_new = cmd(&["XREADGROUP", "GROUP", "g", "frank", "STREAMS", "s", ">"]);
+        assert_eq!(
+            cmd_xreadgroup(&nothing_new, &server, &mut ctx),
+            Resp::Array(None)
+        );
+        assert_eq!(
+            ctx.propagate_as.take().unwrap(),
+            [["xgroup", "createconsumer", "s", "g", "frank"]]
+        );
+        assert_eq!(execute(&block, &server, &mut ctx), Resp::Array(None));
+        execute(
+            &cmd(&["XADD", "s", "4-0", "f", "4-0"]),
+            &server,
+            &mut ConnectionContext::new(3),
+        );
+        let mut blocked = ctx.blocked.take().unwrap();
+        assert_eq!(
+            (blocked.reply)(blocked.popped.try_recv().unwrap()),
+            array(vec![array(vec![bulk("s"), array(vec![entry("4-0")])])])
+        );
+        let pending = execute(
+            &cmd(&["XPENDING", "s", "g", "4-0", "4-0", "1"]),
+            &server,
+            &mut ctx,
+        );
+        let Resp::Array(Some(pending)) = pending else {
+            panic!("expected the pending entries, got {:?}", pending);
+        };
+        let [Resp::Array(Some(entry))] = &pending[..] else {
+            panic!("expected one pending entry, got {:?}", pending);
         };
+        let [id, consumer, Resp::Integer(idle), Resp::Integer(deliveries)] = &entry[..] else {
+            panic!("expected a pending entry, got {:?}", entry);
+        };
+        // Delivered just now, however long the test took to get here
+        assert!((0..10_000).contains(idle));
         assert_eq!(
-            execute(&cmd, &storage),
-            Resp::Bulk(Some("hello".to_string()))
+            (id, consumer, *deliveries),
+            (&bulk("4-0"), &bulk("erin"), 1)
         );
     }
 
     #[test]
-    fn test_set_get() {
-        let storage = Storage::new();
-        let set_cmd = Command {
-            name: "SET".to_string(),
-            args: vec!["key".to_string(), "value".to_string()],
-        };
-        assert_eq!(execute(&set_cmd, &storage), Resp::Simple("OK".to_string()));
+    fn test_bloom_filters() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let ok = || Resp::Simple("OK".to_string());
+        let error = |e: &str| Resp::Error(e.to_string());
+        let ints = |ns: &[i64]| Resp::Array(Some(ns.iter().map(|&n| Resp::Integer(n)).collect()));
+
+        assert_eq!(run(&["BF.ADD", "b", "a"]), Resp::Integer(1));
+        assert_eq!(run(&["BF.ADD", "b", "a"]), Resp::Integer(0));
+        assert_eq!(run(&["BF.MADD", "b", "a", "x", "y"]), ints(&[0, 1, 1]));
+        assert_eq!(run(&["BF.EXISTS", "b", "x"]), Resp::Integer(1));
+        assert_eq!(run(&["BF.EXISTS", "b", "z"]), Resp::Integer(0));
+        assert_eq!(run(&["BF.MEXISTS", "b", "y", "z"]), ints(&[1, 0]));
+        assert_eq!(run(&["BF.MEXISTS", "missing", "y"]), ints(&[0]));
+        assert_eq!(run(&["EXISTS", "missing"]), Resp::Integer(0));
+        assert_eq!(run(&["TYPE", "b"]), Resp::Simple("MBbloom--".to_string()));
+
+        // Created from the bf-* parameters, and grown past its capacity
+        assert_eq!(run(&["CONFIG", "SET", "bf-initial-size", "10"]), ok());
+        let items: Vec<String> = (0..30).map(|i| i.to_string()).collect();
+        let mut madd = vec!["BF.MADD", "grown"];
+        madd.extend(items.iter().map(String::as_str));
+        run(&madd);
+        let db = server.storage.db(0);
+        assert_eq!(db.bf_exists("grown", &items), Ok(vec![true; 30]));
+        let capacities = db.with_value("grown", |value, _| match value {
+            storage::Value::Bloom(bloom) => bloom.filters().iter().map(|f| f.capacity).collect(),
+            _ => vec![],
+        });
+        assert_eq!(capacities, Some(vec![10, 20]));
 
-        let get_cmd = Command {
-            name: "GET".to_string(),
-            args: vec!["key".to_string()],
-        };
         assert_eq!(
-            execute(&get_cmd, &storage),
-            Resp::Bulk(Some("value".to_string()))
+            run(&["BF.RESERVE", "fixed", "0.01", "2", "NONSCALING"]),
+            ok()
+        );
+        assert_eq!(
+            run(&["BF.MADD", "fixed", "a", "b", "c"]),
+            Resp::Array(Some(vec![
+                Resp::Integer(1),
+                Resp::Integer(1),
+                error("ERR non scaling filter is full"),
+            ]))
+        );
+        assert_eq!(
+            run(&["BF.RESERVE", "fixed", "0.01", "2"]),
+            error("ERR item exists")
+        );
+        assert_eq!(
+            run(&["BF.RESERVE", "r", "0.1", "100", "EXPANSION", "4"]),
+            ok()
+        );
+        let reserve_errors: [(&[&str], &str); 7] = [
+            (&["0", "10"], "ERR (0 < error rate range < 1)"),
+            (&["x", "10"], "ERR bad error rate"),
+            (&["0.1", "0"], "ERR (capacity should be larger than 0)"),
+            (&["0.1", "1.5"], "ERR bad capacity"),
+            (
+                &["0.1", "10", "EXPANSION", "0"],
+                "ERR expansion should be greater or equal to 1",
+            ),
+            (&["0.1", "10", "EXPANSION", "x"], "ERR bad expansion"),
+            (&["0.1", "10", "NONSCALING", "FOO"], "ERR syntax error"),
+        ];
+        for (args, e) in reserve_errors {
+            let mut reserve = vec!["BF.RESERVE", "new"];
+            reserve.extend_from_slice(args);
+            assert_eq!(run(&reserve), error(e));
+        }
+        assert_eq!(
+            run(&[
+                "BF.RESERVE",
+                "new",
+                "0.1",
+                "10",
+                "EXPANSION",
+                "2",
+                "NONSCALING"
+            ]),
+            error("ERR nonscaling filters cannot expand")
+        );
+        assert_eq!(run(&["EXISTS", "new"]), Resp::Integer(0));
+
+        run(&["SET", "str", "v"]);
+        assert_eq!(
+            run(&["BF.ADD", "str", "a"]),
+            error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+        assert_eq!(
+            run(&["BF.MEXISTS", "str", "a"]),
+            error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+        assert_eq!(
+            run(&["BF.RESERVE", "str", "0.1", "10"]),
+            error("ERR item exists")
         );
     }
 
     #[test]
-    fn test_encode_resp() {
+    fn test_zrank() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+
+        run(&["ZADD", "z", "10", "a", "20", "b", "20", "c", "30.5", "d"]);
+        assert_eq!(run(&["ZRANK", "z", "a"]), Resp::Integer(0));
+        assert_eq!(run(&["ZRANK", "z", "c"]), Resp::Integer(2));
+        assert_eq!(run(&["ZREVRANK", "z", "d"]), Resp::Integer(0));
+        assert_eq!(run(&["ZREVRANK", "z", "a"]), Resp::Integer(3));
         assert_eq!(
-            encode_resp(&Resp::Simple("OK".to_string())),
-            b"+OK\r\n".to_vec()
+            run(&["ZREVRANK", "z", "d", "withscore"]),
+            Resp::Array(Some(vec![Resp::Integer(0), bulk("30.5")]))
         );
+        assert_eq!(run(&["ZRANK", "z", "x"]), Resp::Bulk(None));
+        assert_eq!(run(&["ZRANK", "z", "x", "WITHSCORE"]), Resp::Array(None));
+        assert_eq!(run(&["ZRANK", "missing", "a"]), Resp::Bulk(None));
         assert_eq!(
-            encode_resp(&Resp::Error("ERR".to_string())),
-            b"-ERR\r\n".to_vec()
+            run(&["ZRANK", "z", "a", "WITHSCORES"]),
+            Resp::Error("ERR syntax error".to_string())
         );
-        assert_eq!(encode_resp(&Resp::Integer(42)), b":42\r\n".to_vec());
-        assert_eq!(encode_resp(&Resp::Bulk(None)), b"$-1\r\n".to_vec());
+        run(&["ZREM", "z", "a"]);
+        assert_eq!(run(&["ZRANK", "z", "b"]), Resp::Integer(0));
+    }
+
+    #[test]
+    fn test_zrange() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = runner(&server, &mut ctx);
+        let bulks = |items: &[&str]| {
+            Resp::Array(Some(
+                items
+                    .iter()
+                    .map(|s| Resp::Bulk(Some(s.to_string())))
+                    .collect(),
+            ))
+        };
+        let error = |e: &str| Resp::Error(e.to_string());
+
+        run(&["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"]);
         assert_eq!(
-            encode_resp(&Resp::Bulk(Some("hello".to_string()))),
-            b"$5\r\nhello\r\n".to_vec()
+            run(&["ZRANGE", "z", "0", "-1"]),
+            bulks(&["a", "b", "c", "d"])
+        );
+        assert_eq!(
+            run(&["ZRANGE", "z", "0", "1", "REV", "WITHSCORES"]),
+            bulks(&["d", "4", "c", "3"])
+        );
+        assert_eq!(run(&["ZREVRANGE", "z", "-2", "-1"]), bulks(&["b", "a"]));
+        assert_eq!(
+            run(&["ZRANGE", "z", "(1", "3", "BYSCORE", "WITHSCORES"]),
+            bulks(&["b", "2", "c", "3"])
+        );
+        assert_eq!(
+            run(&[
+                "ZRANGE", "z", "+inf", "-inf", "BYSCORE", "REV", "LIMIT", "1", "2"
+            ]),
+            bulks(&["c", "b"])
+        );
+        assert_eq!(run(&["ZRANGEBYSCORE", "z", "2", "(4"]), bulks(&["b", "c"]));
+        assert_eq!(
+            run(&["ZREVRANGEBYSCORE", "z", "4", "-inf", "LIMIT", "0", "1"]),
+            bulks(&["d"])
+        );
+        assert_eq!(run(&["ZRANGE", "missing", "0", "-1"]), bulks(&[]));
+        assert_eq!(run(&["ZCOUNT", "z", "(1", "3"]), Resp::Integer(2));
+        assert_eq!(run(&["ZCOUNT", "z", "-inf", "+inf"]), Resp::Integer(4));
+        assert_eq!(run(&["ZCOUNT", "z", "3", "1"]), Resp::Integer(0));
+        assert_eq!(run(&["ZCOUNT", "missing", "0", "1"]), Resp::Integer(0));
+        assert_eq!(
+            run(&["ZCOUNT", "z", "0", "1x"]),
+            error("ERR min or max is not a float")
+        );
+
+        run(&["ZADD", "lex", "0", "a", "0", "b", "0", "c", "0", "d"]);
+        assert_eq!(run(&["ZRANGEBYLEX", "lex", "[b", "(d"]), bulks(&["b", "c"]));
+        assert_eq!(
+            run(&["ZREVRANGEBYLEX", "lex", "+", "(b"]),
+            bulks(&["d", "c"])
+        );
+        assert_eq!(run(&["ZLEXCOUNT", "lex", "(a", "[c"]), Resp::Integer(2));
+        assert_eq!(run(&["ZLEXCOUNT", "lex", "-", "+"]), Resp::Integer(4));
+        assert_eq!(
+            run(&["ZLEXCOUNT", "lex", "b", "+"]),
+            error("ERR min or max not valid string range item")
+        );
+        assert_eq!(
+            run(&["ZRANGE", "lex", "-", "+", "BYLEX", "LIMIT", "2", "-1"]),
+            bulks(&["c", "d"])
+        );
+
+        assert_eq!(
+            run(&["ZRANGE", "z", "0", "-1", "LIMIT", "0", "1"]),
+            error(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+            )
+        );
+        assert_eq!(
+            run(&["ZRANGE", "lex", "-", "+", "BYLEX", "WITHSCORES"]),
+            error("ERR syntax error, WITHSCORES not supported in combination with BYLEX")
+        );
+        assert_eq!(
+            run(&["ZRANGEBYSCORE", "z", "0", "1", "REV"]),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&["ZRANGE", "z", "0", "1", "BYSCORE", "BYLEX"]),
+            error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&["ZRANGEBYSCORE", "z", "x", "1"]),
+            error("ERR min or max is not a float")
+        );
+        assert_eq!(
+            run(&["ZRANGEBYLEX", "lex", "a", "+"]),
+            error("ERR min or max not valid string range item")
+        );
+        assert_eq!(
+            run(&["ZRANGE", "z", "0", "x"]),
+            error("ERR value is not an integer or out of range")
+        );
+
+        run(&["SET", "dst", "v", "EX", "100"]);
+        assert_eq!(
+            run(&["ZRANGESTORE", "dst", "z", "1", "2"]),
+            Resp::Integer(2)
+        );
+        assert_eq!(run(&["TTL", "dst"]), Resp::Integer(-1));
+        assert_eq!(
+            run(&["ZRANGE", "dst", "0", "-1", "WITHSCORES"]),
+            bulks(&["b", "2", "c", "3"])
+        );
+        assert_eq!(
+            run(&["ZRANGESTORE", "dst", "z", "5", "+inf", "BYSCORE"]),
+            Resp::Integer(0)
+        );
+        assert_eq!(run(&["EXISTS", "dst"]), Resp::Integer(0));
+        assert_eq!(
+            run(&["ZRANGESTORE", "dst", "z", "0", "-1", "WITHSCORES"]),
+            error("ERR syntax error")
         );
+        run(&["SET", "s", "v"]);
+        assert!(matches!(
+            run(&["ZRANGESTORE", "dst", "s", "0", "-1"]),
+            Resp::Error(e) if e.starts_with("WRONGTYPE")
+        ));
     }
 }
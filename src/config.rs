@@ -0,0 +1,968 @@
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+use crate::aof::AppendFsync;
+use crate::bloom;
+use crate::evict::MaxmemoryPolicy;
+use crate::engine;
+use crate::hash;
+use crate::list;
+use crate::logging::{Level, LogFormat};
+use crate::pattern::Pattern;
+
+/// Configuration shared between connections so CONFIG SET takes effect live.
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+/// What happens to a command sent faster than `client-rate-limit-commands`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitAction {
+    Reject,
+    Delay,
+}
+
+/// Directives that can only be given at startup.
+const IMMUTABLE: &[&str] = &[
+    "bind",
+    "port",
+    "io-acceptors",
+    "databases",
+    "storage-engine",
+    "logfile",
+    "daemonize",
+    "pidfile",
+    "appendfilename",
+    "encryption-key-file",
+    "replicaof",
+];
+
+/// Server settings, read from an optional config file followed by
+/// `--directive value` overrides on the command line, like redis-server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub io_acceptors: usize,
+    pub databases: usize,
+    /// Name of the `StorageEngine` every database is kept by.
+    pub storage_engine: String,
+    pub requirepass: String,
+    pub acl_log_max_len: usize,
+    /// `(original, new)` command names; an empty new name disables it.
+    pub rename_commands: Vec<(String, String)>,
+    pub client_rate_limit_commands: u64,
+    pub client_rate_limit_bytes: u64,
+    pub client_rate_limit_action: RateLimitAction,
+    pub client_query_buffer_limit: usize,
+    /// Operations taking at least this many milliseconds are recorded by the
+    /// latency monitor; 0 disables it.
+    pub latency_monitor_threshold: u64,
+    /// Whether per-command latency histograms are kept.
+    pub latency_tracking: bool,
+    /// Percentiles reported by `INFO latencystats`.
+    pub latency_tracking_info_percentiles: Vec<f64>,
+    /// Emit a span for every command and connection.
+    pub command_tracing: bool,
+    pub loglevel: Level,
+    /// Log file path; empty logs to standard output.
+    pub logfile: String,
+    pub log_format: LogFormat,
+    /// Detach from the terminal at startup (unix only).
+    pub daemonize: bool,
+    /// Where to write the process id; empty means none unless daemonized.
+    pub pidfile: String,
+    /// How many times a second the server cron runs.
+    pub hz: u32,
+    /// Close clients idle for this many seconds; 0 never does.
+    pub timeout: u64,
+    /// Directory holding the RDB file.
+    pub dir: String,
+    pub dbfilename: String,
+    /// LZF-compress long strings in RDB files.
+    pub rdbcompression: bool,
+    /// Write a CRC64 at the end of RDB files and verify it when loading.
+    pub rdbchecksum: bool,
+    /// `save <seconds> <changes>` rules: BGSAVE once at least `changes`
+    /// writes happened and `seconds` passed since the last save.
+    pub save_rules: Vec<(u64, u64)>,
+    /// Log every write to the append-only file, and load it at startup
+    /// instead of the RDB file.
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    /// Load an append-only file cut off mid-command up to the cut instead
+    /// of refusing to start.
+    pub aof_load_truncated: bool,
+    /// File of hex AES-256 keys to encrypt RDB and AOF files with, newest
+    /// first. Empty falls back to the REREDIS_ENCRYPTION_KEY variable.
+    pub encryption_key_file: String,
+    /// The primary to replicate from at startup. REPLICAOF changes it at
+    /// runtime.
+    pub replicaof: Option<(String, u16)>,
+    /// Password, and user when not `default`, to authenticate to the
+    /// primary with.
+    pub masterauth: String,
+    pub masteruser: String,
+    /// Whether a replica rejects writes from its clients.
+    pub replica_read_only: bool,
+    /// Whether a replica answers queries while its link to the primary is
+    /// down, with possibly outdated data.
+    pub replica_serve_stale_data: bool,
+    /// Bytes of recent replication stream kept for replicas to continue
+    /// from after losing their link.
+    pub repl_backlog_size: usize,
+    /// Seconds without traffic after which either end of a replication
+    /// link gives up on it.
+    pub repl_timeout: u64,
+    /// Seconds between the PINGs a primary sends its replicas.
+    pub repl_ping_replica_period: u64,
+    /// Bytes of memory to stay under by evicting keys, 0 for no limit.
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    /// Bytes all client buffers may take together before the largest
+    /// clients are disconnected, 0 for no limit.
+    pub maxmemory_clients: usize,
+    /// Keys sampled per database for each eviction.
+    pub maxmemory_samples: usize,
+    /// How slowly the LFU counter grows, and the minutes it takes to
+    /// decay by one.
+    pub lfu_log_factor: u64,
+    pub lfu_decay_time: u64,
+    /// Whether the cron reallocates values to give fragmented memory
+    /// back, once the allocator wastes both this many bytes and this
+    /// percentage of what's allocated.
+    pub activedefrag: bool,
+    pub active_defrag_ignore_bytes: usize,
+    pub active_defrag_threshold_lower: u64,
+    /// How large a list may grow before it's converted from a listpack
+    /// to a deque: positive, in elements, and -1 to -5 in bytes, from 4kb
+    /// to 64kb.
+    pub list_max_listpack_size: i64,
+    /// The most fields a hash holds, and the longest field or value,
+    /// before it's converted from a listpack to a table.
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    /// The error rate, capacity and expansion of the bloom filters BF.ADD
+    /// and BF.MADD create.
+    pub bf_error_rate: f64,
+    pub bf_initial_size: u64,
+    pub bf_expansion_factor: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            io_acceptors: 1,
+            databases: 16,
+            storage_engine: "memory".to_string(),
+            requirepass: String::new(),
+            acl_log_max_len: 128,
+            rename_commands: Vec::new(),
+            client_rate_limit_commands: 0,
+            client_rate_limit_bytes: 0,
+            client_rate_limit_action: RateLimitAction::Reject,
+            client_query_buffer_limit: 1024 * 1024 * 1024,
+            latency_monitor_threshold: 0,
+            latency_tracking: true,
+            latency_tracking_info_percentiles: vec![50.0, 99.0, 99.9],
+            command_tracing: false,
+            loglevel: Level::Notice,
+            logfile: String::new(),
+            log_format: LogFormat::Plain,
+            daemonize: false,
+            pidfile: String::new(),
+            hz: 10,
+            timeout: 0,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            rdbcompression: true,
+            rdbchecksum: true,
+            save_rules: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            aof_load_truncated: true,
+            encryption_key_file: String::new(),
+            replicaof: None,
+            masterauth: String::new(),
+            masteruser: String::new(),
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            repl_backlog_size: 1024 * 1024,
+            repl_timeout: 60,
+            repl_ping_replica_period: 10,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_clients: 0,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            activedefrag: false,
+            active_defrag_ignore_bytes: 100 * 1024 * 1024,
+            active_defrag_threshold_lower: 10,
+            list_max_listpack_size: list::DEFAULT_MAX_LISTPACK_SIZE,
+            hash_max_listpack_entries: hash::DEFAULT_MAX_LISTPACK_ENTRIES,
+            hash_max_listpack_value: hash::DEFAULT_MAX_LISTPACK_VALUE,
+            bf_error_rate: bloom::DEFAULT_ERROR_RATE,
+            bf_initial_size: bloom::DEFAULT_CAPACITY,
+            bf_expansion_factor: bloom::DEFAULT_EXPANSION,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from process arguments (without argv[0]).
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args;
+
+        if let Some(path) = args.first()
+            && !path.starts_with("--")
+        {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Can't open config file '{}': {}", path, e))?;
+            config.load_str(&contents)?;
+            args = &args[1..];
+        }
+
+        let mut saw_save = false;
+        let mut i = 0;
+        while i < args.len() {
+            let Some(name) = args[i].strip_prefix("--") else {
+                return Err(format!("Unexpected argument '{}'", args[i]));
+            };
+            let mut values = Vec::new();
+            i += 1;
+            while i < args.len() && !args[i].starts_with("--") {
+                values.push(args[i].clone());
+                i += 1;
+            }
+            // `--daemonize` on its own switches it on
+            if values.is_empty() && name == "daemonize" {
+                values.push("yes".to_string());
+            }
+            // `--replicaof "host port"` as one argument, as redis-server takes it
+            if matches!(name, "replicaof" | "slaveof")
+                && let [value] = &values[..]
+                && value.contains(char::is_whitespace)
+            {
+                values = value.split_whitespace().map(str::to_string).collect();
+            }
+            // The first save directive replaces the default rules
+            if name == "save" && !std::mem::replace(&mut saw_save, true) {
+                config.save_rules.clear();
+            }
+            config.apply(name, &values)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Applies every directive of a config file, one per line.
+    pub fn load_str(&mut self, contents: &str) -> Result<(), String> {
+        let mut saw_save = false;
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = split_line(line)
+                .map_err(|e| format!("line {}: {}", lineno + 1, e))?
+                .into_iter();
+            let name = parts.next().unwrap_or_default();
+            let values: Vec<String> = parts.collect();
+            if name.eq_ignore_ascii_case("save") && !std::mem::replace(&mut saw_save, true) {
+                self.save_rules.clear();
+            }
+            self.apply(&name, &values)
+                .map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Applies a single directive.
+    pub fn apply(&mut self, name: &str, values: &[String]) -> Result<(), String> {
+        let name = name.to_lowercase();
+        if name == "rename-command" {
+            let [original, new] = values else {
+                return Err(format!("wrong number of arguments for '{}'", name));
+            };
+            self.rename_commands.push((original.clone(), new.clone()));
+            return Ok(());
+        }
+        // Adds `seconds changes` pairs, given as one quoted value or several;
+        // `save ""` removes every rule
+        if name == "save" {
+            let numbers = values
+                .iter()
+                .flat_map(|v| v.split_whitespace())
+                .map(|v| parse_number::<u64>(&name, v))
+                .collect::<Result<Vec<_>, _>>()?;
+            if numbers.is_empty() {
+                self.save_rules.clear();
+                return Ok(());
+            }
+            if numbers.len() % 2 != 0 {
+                return Err(format!("Invalid save parameters for '{}'", name));
+            }
+            self.save_rules
+                .extend(numbers.chunks(2).map(|pair| (pair[0], pair[1])));
+            return Ok(());
+        }
+        // `replicaof <host> <port>`; `replicaof no one` or "" for none
+        if name == "replicaof" || name == "slaveof" {
+            self.replicaof = match values {
+                [none] if none.is_empty() => None,
+                [host, port]
+                    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") =>
+                {
+                    None
+                }
+                [host, port] => Some((host.clone(), parse_number(&name, port)?)),
+                _ => return Err(format!("wrong number of arguments for '{}'", name)),
+            };
+            return Ok(());
+        }
+        // A space-separated list, given as one quoted value or several
+        if name == "latency-tracking-info-percentiles" {
+            let mut percentiles = Vec::new();
+            for value in values.iter().flat_map(|v| v.split_whitespace()) {
+                let percentile: f64 = parse_number(&name, value)?;
+                if !(0.0..=100.0).contains(&percentile) {
+                    return Err(format!("percentile '{}' must be between 0 and 100", value));
+                }
+                percentiles.push(percentile);
+            }
+            self.latency_tracking_info_percentiles = percentiles;
+            return Ok(());
+        }
+
+        let value = match values {
+            [value] => value.as_str(),
+            _ => return Err(format!("wrong number of arguments for '{}'", name)),
+        };
+
+        match name.as_str() {
+            "bind" => self.bind = value.to_string(),
+            "port" => self.port = parse_number(&name, value)?,
+            "io-acceptors" => {
+                self.io_acceptors = parse_number(&name, value)?;
+                if self.io_acceptors == 0 {
+                    return Err("io-acceptors must be at least 1".to_string());
+                }
+            }
+            "databases" => {
+                self.databases = parse_number(&name, value)?;
+                if self.databases == 0 {
+                    return Err("databases must be at least 1".to_string());
+                }
+            }
+            "storage-engine" => {
+                if engine::find(value).is_none() {
+                    let names: Vec<&str> = engine::ENGINES.iter().map(|(name, _)| *name).collect();
+                    return Err(format!(
+                        "unknown storage engine '{}', available: {}",
+                        value,
+                        names.join(", ")
+                    ));
+                }
+                self.storage_engine = value.to_lowercase();
+            }
+            "requirepass" => self.requirepass = value.to_string(),
+            "acl-log-max-len" => self.acl_log_max_len = parse_number(&name, value)?,
+            "client-rate-limit-commands" => {
+                self.client_rate_limit_commands = parse_number(&name, value)?
+            }
+            "client-rate-limit-bytes" => self.client_rate_limit_bytes = parse_number(&name, value)?,
+            "client-rate-limit-action" => {
+                self.client_rate_limit_action = match value.to_lowercase().as_str() {
+                    "reject" => RateLimitAction::Reject,
+                    "delay" => RateLimitAction::Delay,
+                    _ => {
+                        return Err(format!(
+                            "argument must be 'reject' or 'delay' for '{}'",
+                            name
+                        ));
+                    }
+                }
+            }
+            "client-query-buffer-limit" => {
+                self.client_query_buffer_limit = parse_memory(&name, value)?;
+                if self.client_query_buffer_limit < 1024 * 1024 {
+                    return Err("client-query-buffer-limit must be at least 1mb".to_string());
+                }
+            }
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold = parse_number(&name, value)?
+            }
+            "latency-tracking" => self.latency_tracking = parse_bool(&name, value)?,
+            "command-tracing" => self.command_tracing = parse_bool(&name, value)?,
+            "loglevel" => {
+                self.loglevel = Level::parse(value).ok_or_else(|| {
+                    format!(
+                        "argument must be one of debug, verbose, notice or warning for '{}'",
+                        name
+                    )
+                })?
+            }
+            "logfile" => self.logfile = value.to_string(),
+            "daemonize" => self.daemonize = parse_bool(&name, value)?,
+            "pidfile" => self.pidfile = value.to_string(),
+            "hz" => {
+                // Out of range values are clamped, as Redis does
+                self.hz = parse_number::<u32>(&name, value)?.clamp(1, 500);
+            }
+            "timeout" => self.timeout = parse_number(&name, value)?,
+            "dir" => self.dir = value.to_string(),
+            "dbfilename" => {
+                if value.contains('/') {
+                    return Err("dbfilename can't be a path, just a filename".to_string());
+                }
+                self.dbfilename = value.to_string();
+            }
+            "appendonly" => self.appendonly = parse_bool(&name, value)?,
+            "appendfilename" => {
+                if value.contains('/') {
+                    return Err("appendfilename can't be a path, just a filename".to_string());
+                }
+                self.appendfilename = value.to_string();
+            }
+            "appendfsync" => {
+                self.appendfsync = AppendFsync::parse(value).ok_or_else(|| {
+                    format!(
+                        "argument must be one of always, everysec or no for '{}'",
+                        name
+                    )
+                })?
+            }
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(&name, value)?,
+            "encryption-key-file" => self.encryption_key_file = value.to_string(),
+            "masterauth" => self.masterauth = value.to_string(),
+            "masteruser" => self.masteruser = value.to_string(),
+            "replica-read-only" | "slave-read-only" => {
+                self.replica_read_only = parse_bool(&name, value)?
+            }
+            "replica-serve-stale-data" | "slave-serve-stale-data" => {
+                self.replica_serve_stale_data = parse_bool(&name, value)?
+            }
+            "repl-backlog-size" => {
+                self.repl_backlog_size = parse_memory(&name, value)?;
+                if self.repl_backlog_size < 16 * 1024 {
+                    return Err("repl-backlog-size must be at least 16kb".to_string());
+                }
+            }
+            "repl-timeout" => {
+                self.repl_timeout = parse_number(&name, value)?;
+                if self.repl_timeout == 0 {
+                    return Err("repl-timeout must be positive".to_string());
+                }
+            }
+            "maxmemory" => self.maxmemory = parse_memory(&name, value)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = MaxmemoryPolicy::parse(value)
+                    .ok_or_else(|| format!("invalid maxmemory-policy '{}'", value))?
+            }
+            "maxmemory-clients" => self.maxmemory_clients = parse_memory(&name, value)?,
+            "maxmemory-samples" => {
+                self.maxmemory_samples = parse_number(&name, value)?;
+                if !(1..=64).contains(&self.maxmemory_samples) {
+                    return Err("maxmemory-samples must be between 1 and 64".to_string());
+                }
+            }
+            "lfu-log-factor" => self.lfu_log_factor = parse_number(&name, value)?,
+            "lfu-decay-time" => self.lfu_decay_time = parse_number(&name, value)?,
+            "activedefrag" => self.activedefrag = parse_bool(&name, value)?,
+            "active-defrag-ignore-bytes" => {
+                self.active_defrag_ignore_bytes = parse_memory(&name, value)?
+            }
+            "active-defrag-threshold-lower" => {
+                self.active_defrag_threshold_lower = parse_number(&name, value)?;
+                if self.active_defrag_threshold_lower > 1000 {
+                    return Err(
+                        "active-defrag-threshold-lower must be between 0 and 1000".to_string()
+                    );
+                }
+            }
+            "list-max-listpack-size" | "list-max-ziplist-size" => {
+                self.list_max_listpack_size = parse_number(&name, value)?;
+                if self.list_max_listpack_size < -5 {
+                    return Err("list-max-listpack-size must be at least -5".to_string());
+                }
+            }
+            "hash-max-listpack-entries" | "hash-max-ziplist-entries" => {
+                self.hash_max_listpack_entries = parse_number(&name, value)?;
+            }
+            "hash-max-listpack-value" | "hash-max-ziplist-value" => {
+                self.hash_max_listpack_value = parse_number(&name, value)?;
+            }
+            "bf-error-rate" => {
+                let error_rate: f64 = parse_number(&name, value)?;
+                if !(error_rate > 0.0 && error_rate < 1.0) {
+                    return Err("bf-error-rate must be between 0 and 1, exclusive".to_string());
+                }
+                self.bf_error_rate = error_rate;
+            }
+            "bf-initial-size" => {
+                let size = parse_number(&name, value)?;
+                if !(1..=1048576).contains(&size) {
+                    return Err("bf-initial-size must be between 1 and 1048576".to_string());
+                }
+                self.bf_initial_size = size;
+            }
+            "bf-expansion-factor" => {
+                let factor = parse_number(&name, value)?;
+                if !(1..=32768).contains(&factor) {
+                    return Err("bf-expansion-factor must be between 1 and 32768".to_string());
+                }
+                self.bf_expansion_factor = factor;
+            }
+            "repl-ping-replica-period" | "repl-ping-slave-period" => {
+                self.repl_ping_replica_period = parse_number(&name, value)?;
+                if self.repl_ping_replica_period == 0 {
+                    return Err("repl-ping-replica-period must be positive".to_string());
+                }
+            }
+            "rdbcompression" => self.rdbcompression = parse_bool(&name, value)?,
+            "rdbchecksum" => self.rdbchecksum = parse_bool(&name, value)?,
+            "log-format" => {
+                self.log_format = match value.to_lowercase().as_str() {
+                    "plain" => LogFormat::Plain,
+                    "json" => LogFormat::Json,
+                    _ => {
+                        return Err(format!("argument must be 'plain' or 'json' for '{}'", name));
+                    }
+                }
+            }
+            _ => return Err(format!("Bad directive '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// Every parameter with its current value, as reported by CONFIG GET.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("bind", self.bind.clone()),
+            ("port", self.port.to_string()),
+            ("io-acceptors", self.io_acceptors.to_string()),
+            ("databases", self.databases.to_string()),
+            ("storage-engine", self.storage_engine.clone()),
+            ("requirepass", self.requirepass.clone()),
+            ("acl-log-max-len", self.acl_log_max_len.to_string()),
+            (
+                "client-rate-limit-commands",
+                self.client_rate_limit_commands.to_string(),
+            ),
+            (
+                "client-rate-limit-bytes",
+                self.client_rate_limit_bytes.to_string(),
+            ),
+            (
+                "client-rate-limit-action",
+                match self.client_rate_limit_action {
+                    RateLimitAction::Reject => "reject",
+                    RateLimitAction::Delay => "delay",
+                }
+                .to_string(),
+            ),
+            (
+                "client-query-buffer-limit",
+                self.client_query_buffer_limit.to_string(),
+            ),
+            (
+                "latency-monitor-threshold",
+                self.latency_monitor_threshold.to_string(),
+            ),
+            (
+                "latency-tracking",
+                if self.latency_tracking { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "latency-tracking-info-percentiles",
+                self.latency_tracking_info_percentiles
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            (
+                "command-tracing",
+                if self.command_tracing { "yes" } else { "no" }.to_string(),
+            ),
+            ("loglevel", self.loglevel.name().to_string()),
+            ("logfile", self.logfile.clone()),
+            (
+                "daemonize",
+                if self.daemonize { "yes" } else { "no" }.to_string(),
+            ),
+            ("pidfile", self.pidfile.clone()),
+            ("hz", self.hz.to_string()),
+            ("timeout", self.timeout.to_string()),
+            ("dir", self.dir.clone()),
+            ("dbfilename", self.dbfilename.clone()),
+            (
+                "appendonly",
+                if self.appendonly { "yes" } else { "no" }.to_string(),
+            ),
+            ("appendfilename", self.appendfilename.clone()),
+            ("appendfsync", self.appendfsync.name().to_string()),
+            ("encryption-key-file", self.encryption_key_file.clone()),
+            (
+                "replicaof",
+                self.replicaof
+                    .as_ref()
+                    .map(|(host, port)| format!("{} {}", host, port))
+                    .unwrap_or_default(),
+            ),
+            ("masterauth", self.masterauth.clone()),
+            ("masteruser", self.masteruser.clone()),
+            (
+                "replica-read-only",
+                if self.replica_read_only { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "replica-serve-stale-data",
+                if self.replica_serve_stale_data {
+                    "yes"
+                } else {
+                    "no"
+                }
+                .to_string(),
+            ),
+            ("repl-backlog-size", self.repl_backlog_size.to_string()),
+            ("repl-timeout", self.repl_timeout.to_string()),
+            ("maxmemory", self.maxmemory.to_string()),
+            ("maxmemory-policy", self.maxmemory_policy.name().to_string()),
+            ("maxmemory-clients", self.maxmemory_clients.to_string()),
+            ("maxmemory-samples", self.maxmemory_samples.to_string()),
+            ("lfu-log-factor", self.lfu_log_factor.to_string()),
+            ("lfu-decay-time", self.lfu_decay_time.to_string()),
+            (
+                "activedefrag",
+                if self.activedefrag { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "active-defrag-ignore-bytes",
+                self.active_defrag_ignore_bytes.to_string(),
+            ),
+            (
+                "active-defrag-threshold-lower",
+                self.active_defrag_threshold_lower.to_string(),
+            ),
+            (
+                "list-max-listpack-size",
+                self.list_max_listpack_size.to_string(),
+            ),
+            (
+                "hash-max-listpack-entries",
+                self.hash_max_listpack_entries.to_string(),
+            ),
+            (
+                "hash-max-listpack-value",
+                self.hash_max_listpack_value.to_string(),
+            ),
+            ("bf-error-rate", self.bf_error_rate.to_string()),
+            ("bf-initial-size", self.bf_initial_size.to_string()),
+            ("bf-expansion-factor", self.bf_expansion_factor.to_string()),
+            (
+                "repl-ping-replica-period",
+                self.repl_ping_replica_period.to_string(),
+            ),
+            (
+                "aof-load-truncated",
+                if self.aof_load_truncated { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "rdbcompression",
+                if self.rdbcompression { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "rdbchecksum",
+                if self.rdbchecksum { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "log-format",
+                match self.log_format {
+                    LogFormat::Plain => "plain",
+                    LogFormat::Json => "json",
+                }
+                .to_string(),
+            ),
+            (
+                "save",
+                self.save_rules
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+        ]
+    }
+
+    /// Path of the RDB file, `dir/dbfilename`.
+    pub fn rdb_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    /// Path of the append-only file, `dir/appendfilename`.
+    pub fn aof_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.dir).join(&self.appendfilename)
+    }
+
+    /// Parameters whose name matches a glob pattern.
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = Pattern::new(&pattern.to_lowercase());
+        self.entries()
+            .into_iter()
+            .filter(|(name, _)| pattern.matches(name))
+            .collect()
+    }
+
+    /// Changes a parameter at runtime, as CONFIG SET does.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name = name.to_lowercase();
+        if IMMUTABLE.contains(&name.as_str()) {
+            return Err(format!("can't set immutable config '{}'", name));
+        }
+        if !self.entries().iter().any(|(known, _)| *known == name) {
+            return Err(format!("Unknown option or number of arguments '{}'", name));
+        }
+        // At runtime the given rules replace the current ones
+        if name == "save" {
+            let previous = std::mem::take(&mut self.save_rules);
+            return self.apply(&name, &[value.to_string()]).inspect_err(|_| {
+                self.save_rules = previous;
+            });
+        }
+        self.apply(&name, &[value.to_string()])
+    }
+}
+
+/// Splits a config file line into arguments, honouring single and double
+/// quotes so `rename-command FLUSHALL ""` yields an empty argument.
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some(c) if c == first => break,
+                    Some('\\') if first == '"' => match chars.next() {
+                        Some(c) => arg.push(c),
+                        None => return Err("Unbalanced quotes in configuration line".to_string()),
+                    },
+                    Some(c) => arg.push(c),
+                    None => return Err("Unbalanced quotes in configuration line".to_string()),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err("Unbalanced quotes in configuration line".to_string());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("argument '{}' for '{}' is not a valid number", value, name))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("argument must be 'yes' or 'no' for '{}'", name)),
+    }
+}
+
+/// Parses a memory size with an optional Redis-style unit (`1gb`, `512mb`,
+/// `64k`, ...). `k`/`m`/`g` are powers of 1000, `kb`/`mb`/`gb` powers of 1024.
+pub fn parse_memory(name: &str, value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(format!(
+                "argument '{}' for '{}' is not a valid memory size",
+                value, name
+            ));
+        }
+    };
+    parse_number::<usize>(name, digits)?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("argument '{}' for '{}' is too large", value, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_command_line_overrides() {
+        let config = Config::from_args(&args(&["--port", "7000", "--io-acceptors", "4"])).unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.io_acceptors, 4);
+        assert_eq!(config.bind, "127.0.0.1");
+
+        assert!(Config::from_args(&args(&["--io-acceptors", "0"])).is_err());
+        assert!(Config::from_args(&args(&["--nonsense", "1"])).is_err());
+
+        let config = Config::from_args(&args(&["--daemonize", "--pidfile", "/tmp/r.pid"])).unwrap();
+        assert!(config.daemonize);
+        assert_eq!(config.pidfile, "/tmp/r.pid");
+        assert!(
+            !Config::from_args(&args(&["--daemonize", "no"]))
+                .unwrap()
+                .daemonize
+        );
+
+        let primary = Some(("127.0.0.1".to_string(), 7778));
+        let config = Config::from_args(&args(&["--replicaof", "127.0.0.1 7778"])).unwrap();
+        assert_eq!(config.replicaof, primary);
+        let config = Config::from_args(&args(&["--slaveof", "127.0.0.1", "7778"])).unwrap();
+        assert_eq!(config.replicaof, primary);
+    }
+
+    #[test]
+    fn test_load_str() {
+        let mut config = Config::default();
+        config
+            .load_str("# comment\n\nbind 0.0.0.0\nport 6380\n")
+            .unwrap();
+        assert_eq!(config.bind, "0.0.0.0");
+        assert_eq!(config.port, 6380);
+        assert!(config.load_str("port abc").is_err());
+    }
+
+    #[test]
+    fn test_rename_command() {
+        let mut config = Config::default();
+        config
+            .load_str("rename-command FLUSHALL \"\"\nrename-command 'CONFIG' cfg\n")
+            .unwrap();
+        assert_eq!(
+            config.rename_commands,
+            vec![
+                ("FLUSHALL".to_string(), String::new()),
+                ("CONFIG".to_string(), "cfg".to_string()),
+            ]
+        );
+        assert!(config.load_str("rename-command FLUSHALL").is_err());
+        assert!(config.load_str("requirepass \"unterminated").is_err());
+        assert!(config.set("rename-command", "x").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("x", "1gb"), Ok(1 << 30));
+        assert_eq!(parse_memory("x", "2K"), Ok(2000));
+        assert_eq!(parse_memory("x", "123"), Ok(123));
+        assert!(parse_memory("x", "12xb").is_err());
+        assert!(parse_memory("x", "mb").is_err());
+    }
+
+    #[test]
+    fn test_runtime_get_set() {
+        let mut config = Config::default();
+        config.set("client-rate-limit-commands", "100").unwrap();
+        assert_eq!(
+            config.get("client-rate-limit-*")[0],
+            ("client-rate-limit-commands", "100".to_string())
+        );
+        assert!(config.set("port", "7000").is_err());
+        assert!(config.set("client-rate-limit-action", "drop").is_err());
+
+        config.set("list-max-listpack-size", "-3").unwrap();
+        assert_eq!(config.list_max_listpack_size, -3);
+        assert!(config.set("list-max-listpack-size", "-6").is_err());
+        config.load_str("list-max-ziplist-size 128\n").unwrap();
+        assert_eq!(config.list_max_listpack_size, 128);
+
+        config.set("hash-max-listpack-entries", "256").unwrap();
+        assert_eq!(config.hash_max_listpack_entries, 256);
+        assert!(config.set("hash-max-listpack-value", "-1").is_err());
+        config.load_str("hash-max-ziplist-value 32\n").unwrap();
+        assert_eq!(config.hash_max_listpack_value, 32);
+
+        config.set("bf-error-rate", "0.001").unwrap();
+        assert_eq!(config.get("bf-error-rate")[0].1, "0.001");
+        assert!(config.set("bf-error-rate", "1").is_err());
+        assert!(config.set("bf-initial-size", "0").is_err());
+        config.load_str("bf-expansion-factor 4\n").unwrap();
+        assert_eq!(config.bf_expansion_factor, 4);
+    }
+
+    #[test]
+    fn test_latency_tracking_percentiles() {
+        let mut config = Config::default();
+        config
+            .load_str("latency-tracking-info-percentiles 50 99.99\nlatency-tracking no\n")
+            .unwrap();
+        assert_eq!(config.latency_tracking_info_percentiles, vec![50.0, 99.99]);
+        assert!(!config.latency_tracking);
+
+        config.set("latency-tracking-info-percentiles", "").unwrap();
+        assert!(config.latency_tracking_info_percentiles.is_empty());
+        config
+            .set("latency-tracking-info-percentiles", "90 99")
+            .unwrap();
+        assert_eq!(
+            config.get("latency-tracking-info-percentiles")[0].1,
+            "90 99"
+        );
+        assert!(
+            config
+                .set("latency-tracking-info-percentiles", "101")
+                .is_err()
+        );
+        assert!(config.set("latency-tracking", "maybe").is_err());
+    }
+
+    #[test]
+    fn test_save_rules() {
+        let mut config = Config::default();
+        assert_eq!(config.get("save")[0].1, "3600 1 300 100 60 10000");
+        // Lines in a file add up, replacing the defaults
+        config.load_str("save 900 1\nsave 300 10\n").unwrap();
+        assert_eq!(config.save_rules, vec![(900, 1), (300, 10)]);
+        config.load_str("save \"\"\n").unwrap();
+        assert!(config.save_rules.is_empty());
+        assert!(config.load_str("save 900").is_err());
+
+        // CONFIG SET replaces them, and leaves them alone on error
+        config.set("save", "60 5 10 100").unwrap();
+        assert_eq!(config.save_rules, vec![(60, 5), (10, 100)]);
+        assert!(config.set("save", "60 x").is_err());
+        assert_eq!(config.save_rules, vec![(60, 5), (10, 100)]);
+
+        let config = Config::from_args(&args(&["--save", "30", "2"])).unwrap();
+        assert_eq!(config.save_rules, vec![(30, 2)]);
+    }
+}
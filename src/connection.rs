@@ -0,0 +1,475 @@
+use bytes::{Buf, BytesMut};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::Instrument;
+
+use crate::blocking::Blocked;
+use crate::clients::UnblockReason;
+use crate::commands::{Command, execute, is_write_command};
+use crate::config::RateLimitAction;
+use crate::parser::{Resp, parse_request};
+use crate::ratelimit::RateLimiter;
+use crate::replication::{self, SyncRequest};
+use crate::reply::{Protocol, VectoredEncoder, encoded_len};
+use crate::server::Server;
+use crate::stats::Stats;
+
+/// What CLIENT REPLY asked us to do with replies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    /// Suppress this many more replies, then switch back to `On`.
+    Skip(u8),
+}
+
+/// Per-connection state threaded through command execution.
+#[derive(Debug)]
+pub struct ConnectionContext {
+    pub id: u64,
+    pub db: usize,
+    pub name: Option<String>,
+    pub user: String,
+    pub authenticated: bool,
+    /// Commands queued since MULTI, or None outside a transaction.
+    pub multi: Option<Vec<Command>>,
+    /// A command was rejected while queuing, so EXEC must fail.
+    pub exec_aborted: bool,
+    /// Keys watched with WATCH, as (database, key).
+    pub watched: Vec<(usize, String)>,
+    pub subscriptions: HashSet<String>,
+    pub psubscriptions: HashSet<String>,
+    pub reply_mode: ReplyMode,
+    /// The protocol replies are encoded in, switched with HELLO.
+    pub protocol: Protocol,
+    /// Set by commands such as QUIT that end the connection after replying.
+    pub closing: bool,
+    /// The port a replica listens on, from REPLCONF listening-port.
+    pub replica_port: Option<u16>,
+    /// Set by SYNC and PSYNC: the connection is served as a replica from
+    /// now on.
+    pub sync: Option<SyncRequest>,
+    /// Set by a blocking command that found nothing: the connection waits
+    /// for it to be served before replying.
+    pub blocked: Option<Blocked>,
+    /// Blocking commands return at once instead, as inside a transaction
+    /// or when replaying writes.
+    pub deny_blocking: bool,
+    /// Set while EXEC runs the queued commands: clients blocked on the
+    /// keys they write are served once it's done, not in between.
+    pub in_exec: bool,
+    /// Set by a command to be propagated as others, such as a blocking
+    /// pop as the pop it did.
+    pub propagate_as: Option<Vec<Vec<String>>>,
+    /// Set by MONITOR: the commands other clients run, to send along with
+    /// the replies.
+    pub monitor: Option<UnboundedReceiver<String>>,
+}
+
+impl ConnectionContext {
+    pub fn new(id: u64) -> Self {
+        ConnectionContext {
+            id,
+            db: 0,
+            name: None,
+            user: "default".to_string(),
+            authenticated: true,
+            multi: None,
+            exec_aborted: false,
+            watched: Vec::new(),
+            subscriptions: HashSet::new(),
+            psubscriptions: HashSet::new(),
+            reply_mode: ReplyMode::On,
+            protocol: Protocol::Resp2,
+            closing: false,
+            replica_port: None,
+            sync: None,
+            blocked: None,
+            deny_blocking: false,
+            in_exec: false,
+            propagate_as: None,
+            monitor: None,
+        }
+    }
+
+    /// Whether the reply to the command just executed should be sent.
+    pub fn take_reply(&mut self) -> bool {
+        match self.reply_mode {
+            ReplyMode::On => true,
+            ReplyMode::Off => false,
+            ReplyMode::Skip(n) => {
+                self.reply_mode = if n <= 1 {
+                    ReplyMode::On
+                } else {
+                    ReplyMode::Skip(n - 1)
+                };
+                false
+            }
+        }
+    }
+}
+
+pub async fn handle_client(mut stream: TcpStream, server: Arc<Server>) {
+    let (Ok(addr), Ok(laddr)) = (stream.peer_addr(), stream.local_addr()) else {
+        return;
+    };
+    let client_id = server.clients.register(addr, laddr, raw_fd(&stream));
+    Stats::add(&server.stats.connections_received, 1);
+    let mut ctx = ConnectionContext::new(client_id);
+    ctx.authenticated = server.acl.default_nopass();
+    // The span is current while the client's commands run, so theirs are
+    // its children
+    let span = tracing::info_span!("connection", addr = %addr, client = client_id);
+    serve_client(&mut stream, &server, &mut ctx)
+        .instrument(span)
+        .await;
+    server.storage.unwatch(client_id, &ctx.watched);
+    server.clients.unregister(client_id);
+}
+
+#[cfg(unix)]
+pub fn raw_fd(stream: &TcpStream) -> i64 {
+    use std::os::unix::io::AsRawFd;
+    stream.as_raw_fd() as i64
+}
+
+#[cfg(not(unix))]
+pub fn raw_fd(_stream: &TcpStream) -> i64 {
+    -1
+}
+
+/// Initial size of the per-connection read buffer.
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Replies are batched per read and flushed once this many bytes are pending.
+const OUTPUT_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// Replies produced since the last flush, each with the protocol it's
+/// encoded in, as HELLO can switch it halfway through a pipeline.
+#[derive(Default)]
+struct Output {
+    replies: Vec<(Resp, Protocol)>,
+    bytes: usize,
+}
+
+impl Output {
+    fn push(&mut self, reply: Resp, protocol: Protocol) {
+        self.bytes += encoded_len(&reply, protocol);
+        self.replies.push((reply, protocol));
+    }
+
+    fn is_full(&self) -> bool {
+        self.bytes >= OUTPUT_FLUSH_THRESHOLD
+    }
+}
+
+async fn flush(stream: &mut TcpStream, server: &Server, output: &mut Output) -> bool {
+    if output.replies.is_empty() {
+        return true;
+    }
+    Stats::add(&server.stats.net_output_bytes, output.bytes as u64);
+    let mut encoder = VectoredEncoder::new();
+    for (reply, protocol) in &output.replies {
+        encoder.push(reply, *protocol);
+    }
+    let result = encoder.write_to(stream).await;
+    output.replies.clear();
+    output.bytes = 0;
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::debug!("Failed to write response: {}", e);
+            false
+        }
+    }
+}
+
+/// The next line for a MONITOR connection; never ready for the others.
+async fn monitored(monitor: &mut Option<UnboundedReceiver<String>>) -> Option<String> {
+    match monitor {
+        Some(lines) => lines.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Disconnects the clients taking the most memory until all of them fit in
+/// `limit`, returning false if that included `client_id`.
+fn evict_clients(server: &Server, client_id: u64, limit: usize) -> bool {
+    let evicted = server.clients.evict(limit);
+    for id in &evicted {
+        tracing::debug!("Evicting client {} over maxmemory-clients", id);
+    }
+    Stats::add(&server.stats.evicted_clients, evicted.len() as u64);
+    !evicted.contains(&client_id)
+}
+
+/// Waits until a blocking command is served an element, times out or is
+/// woken by CLIENT UNBLOCK, and returns its reply, or None if the client
+/// went away meanwhile. The socket is still read, into `accumulated`, to
+/// notice that.
+async fn wait_blocked(
+    stream: &mut TcpStream,
+    server: &Server,
+    client_id: u64,
+    blocked: Blocked,
+    accumulated: &mut BytesMut,
+) -> Option<Resp> {
+    let clients = &server.clients;
+    let Some(client) = clients.get(client_id) else {
+        server.blocking.unblock(client_id);
+        return None;
+    };
+    let Blocked {
+        timeout,
+        mut popped,
+        reply,
+    } = blocked;
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    clients.set_blocked(client_id, true);
+    let served = loop {
+        tokio::select! {
+            served = &mut popped => break served.ok(),
+            _ = &mut deadline => break None,
+            _ = client.unblock.notified() => break None,
+            _ = client.kill.notified() => break None,
+            read = stream.read_buf(accumulated) => match read {
+                Ok(n) if n > 0 => Stats::add(&server.stats.net_input_bytes, n as u64),
+                _ => {
+                    server.blocking.unblock(client_id);
+                    return None;
+                }
+            },
+        }
+    };
+    server.blocking.unblock(client_id);
+    clients.set_blocked(client_id, false);
+    if !clients.contains(client_id) {
+        return None;
+    }
+
+    // Served just as it timed out or was unblocked
+    match served.or_else(|| popped.try_recv().ok()) {
+        Some(served) => Some(reply(served)),
+        None => match clients.take_unblock_reason(client_id) {
+            Some(UnblockReason::Error) => Some(Resp::Error(
+                "UNBLOCKED client unblocked via CLIENT UNBLOCK".to_string(),
+            )),
+            _ => Some(Resp::Array(None)),
+        },
+    }
+}
+
+async fn serve_client(stream: &mut TcpStream, server: &Server, ctx: &mut ConnectionContext) {
+    let clients = &server.clients;
+    let client_id = ctx.id;
+    let Some(killed) = clients.get(client_id).map(|c| c.kill) else {
+        return;
+    };
+    // Parsed requests are skipped with `advance`, which only moves the start
+    // of the buffer; the leftover tail is compacted lazily when `read_buf`
+    // needs room again.
+    let mut accumulated = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    let mut output = Output::default();
+    let mut limiter = RateLimiter::default();
+
+    loop {
+        let read = tokio::select! {
+            read = stream.read_buf(&mut accumulated) => read,
+            Some(line) = monitored(&mut ctx.monitor) => {
+                output.push(Resp::Simple(line), ctx.protocol);
+                // Whatever else arrived meanwhile goes out in the same write
+                while !output.is_full()
+                    && let Some(line) = ctx.monitor.as_mut().and_then(|lines| lines.try_recv().ok())
+                {
+                    output.push(Resp::Simple(line), ctx.protocol);
+                }
+                if !flush(stream, server, &mut output).await {
+                    return;
+                }
+                continue;
+            }
+            _ = killed.notified() => break,
+        };
+
+        match read {
+            Ok(0) => {
+                // Connection closed
+                break;
+            }
+            Ok(n) => {
+                Stats::add(&server.stats.net_input_bytes, n as u64);
+                let (action, query_buffer_limit, maxmemory_clients) = {
+                    let config = server.config.read().unwrap();
+                    limiter.configure(
+                        config.client_rate_limit_commands,
+                        config.client_rate_limit_bytes,
+                    );
+                    (
+                        config.client_rate_limit_action,
+                        config.client_query_buffer_limit,
+                        config.maxmemory_clients,
+                    )
+                };
+
+                // Process all complete commands in the buffer
+                loop {
+                    if accumulated.is_empty() {
+                        break;
+                    }
+
+                    let (cmd, consumed) = match parse_request(&accumulated) {
+                        Ok(Some((parts, consumed))) if parts.is_empty() => {
+                            // Blank inline line, nothing to run
+                            accumulated.advance(consumed);
+                            continue;
+                        }
+                        Ok(Some((parts, consumed))) => (Command::from_parts(&parts), consumed),
+                        Ok(None) => {
+                            // Incomplete data, wait for more
+                            break;
+                        }
+                        Err(e) => {
+                            output.push(
+                                Resp::Error(format!("ERR Protocol error: {}", e)),
+                                ctx.protocol,
+                            );
+                            flush(stream, server, &mut output).await;
+                            return;
+                        }
+                    };
+
+                    // Remove consumed bytes from buffer
+                    accumulated.advance(consumed);
+
+                    // Execute the command
+                    let mut response = match cmd {
+                        Ok(cmd) => {
+                            // CLIENT commands stay available so a pause can be lifted
+                            let name = server.renames.resolve(&cmd.name).unwrap_or_default();
+                            let is_write = is_write_command(name)
+                                || (name == "EXEC"
+                                    && ctx
+                                        .multi
+                                        .iter()
+                                        .flatten()
+                                        .any(|c| is_write_command(&c.name)));
+                            if name != "CLIENT" && clients.is_paused(is_write) {
+                                // Don't hold earlier replies back while we wait
+                                if !flush(stream, server, &mut output).await {
+                                    return;
+                                }
+                                clients.wait_while_paused(is_write).await;
+                            }
+                            clients.touch(client_id, &cmd.name.to_lowercase());
+
+                            if !limiter.allow_command() {
+                                if action == RateLimitAction::Reject {
+                                    output.push(
+                                        Resp::Error("ERR rate limit exceeded".to_string()),
+                                        ctx.protocol,
+                                    );
+                                    continue;
+                                }
+                                if !flush(stream, server, &mut output).await {
+                                    return;
+                                }
+                                tokio::time::sleep(limiter.command_delay()).await;
+                            }
+
+                            execute(&cmd, server, ctx)
+                        }
+                        Err(e) => Resp::Error(e),
+                    };
+
+                    // The replication link writes everything from here on
+                    if let Some(request) = ctx.sync.take() {
+                        // Replicas are never evicted for their buffers
+                        clients.set_memory(client_id, 0, 0);
+                        if flush(stream, server, &mut output).await {
+                            replication::serve_replica(stream, server, ctx, request).await;
+                        }
+                        return;
+                    }
+
+                    if let Some(blocked) = ctx.blocked.take() {
+                        // Earlier replies go out before waiting
+                        if !flush(stream, server, &mut output).await {
+                            server.blocking.unblock(client_id);
+                            return;
+                        }
+                        match wait_blocked(stream, server, client_id, blocked, &mut accumulated)
+                            .await
+                        {
+                            Some(reply) => response = reply,
+                            None => return,
+                        }
+                    }
+
+                    if ctx.take_reply() {
+                        output.push(response, ctx.protocol);
+                    }
+
+                    // QUIT, or CLIENT KILL targeting this connection
+                    if ctx.closing || !clients.contains(client_id) {
+                        flush(stream, server, &mut output).await;
+                        return;
+                    }
+
+                    if output.is_full() && !flush(stream, server, &mut output).await {
+                        return;
+                    }
+                }
+
+                // Whatever is left is an incomplete command; don't let a client
+                // grow it without bound
+                if accumulated.len() > query_buffer_limit {
+                    tracing::warn!(
+                        "Closing client {} that reached max query buffer length ({} bytes)",
+                        client_id,
+                        accumulated.len()
+                    );
+                    output.push(
+                        Resp::Error("ERR max query buffer length exceeded".to_string()),
+                        ctx.protocol,
+                    );
+                    flush(stream, server, &mut output).await;
+                    return;
+                }
+
+                clients.set_memory(client_id, accumulated.len(), output.bytes);
+                if maxmemory_clients > 0
+                    && clients.memory() > maxmemory_clients
+                    && !evict_clients(server, client_id, maxmemory_clients)
+                {
+                    return;
+                }
+
+                // One write for every reply produced by this read
+                if !flush(stream, server, &mut output).await {
+                    return;
+                }
+                clients.set_memory(client_id, accumulated.len(), 0);
+
+                // Over the bytes/sec budget: stop reading until it refills
+                let delay = limiter.read_delay(n);
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Error reading from socket: {}", e);
+                break;
+            }
+        }
+    }
+}
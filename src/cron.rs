@@ -0,0 +1,364 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::commands;
+use crate::defrag;
+use crate::memory;
+use crate::notify::{self, KeyspaceEvent};
+use crate::rdb;
+use crate::replication;
+use crate::server::Server;
+use crate::stats::Stats;
+use crate::storage;
+
+/// A piece of periodic background work run by the server cron.
+pub struct CronTask {
+    pub name: &'static str,
+    /// How often the task runs; never more often than once per tick.
+    pub period: Duration,
+    /// Share of a tick the task may use, in percent. Runs that exceed it
+    /// are counted as overruns.
+    pub budget_percent: u32,
+    /// Does a slice of the work, taking at most the given time, and returns
+    /// whether work is left. It's called again, after yielding to the other
+    /// tasks on the worker, until it's done or the budget is spent.
+    pub run: fn(&Server, Duration) -> bool,
+}
+
+/// The most a task runs before the cron yields to the connections sharing
+/// its worker.
+const SLICE: Duration = Duration::from_millis(1);
+
+/// Everything the cron does, in the order it does it each tick.
+pub static CRON_TASKS: &[CronTask] = &[
+    CronTask {
+        name: "expire-cycle",
+        period: Duration::ZERO,
+        budget_percent: 25,
+        run: expire_cycle,
+    },
+    CronTask {
+        name: "active-defrag",
+        period: Duration::ZERO,
+        budget_percent: 10,
+        run: |server, budget| {
+            defrag::cycle(server, budget);
+            false
+        },
+    },
+    CronTask {
+        name: "failover",
+        period: Duration::ZERO,
+        budget_percent: 5,
+        run: |server, _| {
+            replication::update_failover(server);
+            false
+        },
+    },
+    CronTask {
+        name: "replication",
+        period: Duration::from_secs(1),
+        budget_percent: 5,
+        run: |server, _| {
+            replication::heartbeat(server);
+            false
+        },
+    },
+    CronTask {
+        name: "bgsave-done",
+        period: Duration::ZERO,
+        budget_percent: 5,
+        run: |server, _| {
+            rdb::check_bgsave_done(server);
+            false
+        },
+    },
+    CronTask {
+        name: "auto-save",
+        period: Duration::ZERO,
+        budget_percent: 5,
+        run: auto_save,
+    },
+    CronTask {
+        name: "aof-fsync",
+        period: Duration::from_secs(1),
+        budget_percent: 5,
+        run: |server, _| {
+            server.aof.fsync();
+            false
+        },
+    },
+    CronTask {
+        name: "stats",
+        period: Duration::from_millis(100),
+        budget_percent: 5,
+        run: sample_stats,
+    },
+    CronTask {
+        name: "clients-timeout",
+        period: Duration::from_secs(1),
+        budget_percent: 10,
+        run: close_idle_clients,
+    },
+];
+
+/// Per-task counters reported by `INFO cron`.
+#[derive(Debug, Default)]
+pub struct TaskStats {
+    pub runs: AtomicU64,
+    pub usec: AtomicU64,
+    pub max_usec: AtomicU64,
+    pub overruns: AtomicU64,
+}
+
+/// Run statistics for every entry of `CRON_TASKS`, by index.
+#[derive(Debug)]
+pub struct CronStats {
+    tasks: Vec<TaskStats>,
+}
+
+impl CronStats {
+    pub fn new() -> Self {
+        CronStats {
+            tasks: CRON_TASKS.iter().map(|_| TaskStats::default()).collect(),
+        }
+    }
+
+    fn record(&self, index: usize, elapsed: Duration, budget: Duration) {
+        let stats = &self.tasks[index];
+        let usec = elapsed.as_micros() as u64;
+        stats.runs.fetch_add(1, Ordering::Relaxed);
+        stats.usec.fetch_add(usec, Ordering::Relaxed);
+        stats.max_usec.fetch_max(usec, Ordering::Relaxed);
+        if elapsed > budget {
+            stats.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// One `cron_task_<name>:runs=..,usec=..,...` line per task.
+    pub fn info_lines(&self) -> String {
+        let mut out = String::new();
+        for (task, stats) in CRON_TASKS.iter().zip(&self.tasks) {
+            let runs = stats.runs.load(Ordering::Relaxed);
+            let usec = stats.usec.load(Ordering::Relaxed);
+            let _ = write!(
+                out,
+                "cron_task_{}:runs={},usec={},usec_per_run={:.2},max_usec={},overruns={}\r\n",
+                task.name.replace('-', "_"),
+                runs,
+                usec,
+                if runs == 0 {
+                    0.0
+                } else {
+                    usec as f64 / runs as f64
+                },
+                stats.max_usec.load(Ordering::Relaxed),
+                stats.overruns.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+impl Default for CronStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs every task that is due. `last_runs` holds when each task last ran.
+pub async fn tick(server: &Server, interval: Duration, last_runs: &mut [Option<Instant>]) {
+    for (index, task) in CRON_TASKS.iter().enumerate() {
+        let now = Instant::now();
+        // Half a tick of slack keeps timer jitter from skipping a whole tick
+        if let Some(last) = last_runs[index]
+            && now.duration_since(last) < task.period.saturating_sub(interval / 2)
+        {
+            continue;
+        }
+        last_runs[index] = Some(now);
+        let budget = interval * task.budget_percent / 100;
+        while (task.run)(server, budget.saturating_sub(now.elapsed()).min(SLICE))
+            && now.elapsed() < budget
+        {
+            tokio::task::yield_now().await;
+        }
+        server.cron.record(index, now.elapsed(), budget);
+    }
+}
+
+/// The server cron: wakes up `hz` times a second and runs the due tasks.
+/// `hz` is re-read every tick so CONFIG SET takes effect immediately.
+pub async fn run(server: Arc<Server>) {
+    let mut last_runs = vec![None; CRON_TASKS.len()];
+    loop {
+        let interval = Duration::from_secs(1) / server.config.read().unwrap().hz;
+        tokio::time::sleep(interval).await;
+        tick(&server, interval, &mut last_runs).await;
+    }
+}
+
+/// Deletes expired keys for up to `budget`, returning whether some are left.
+fn expire_cycle(server: &Server, budget: Duration) -> bool {
+    // Replicas keep expired keys until their primary deletes them, and
+    // paused writes include deletions
+    if server.replication.is_replica() || server.clients.is_paused(true) {
+        return false;
+    }
+    let due = || {
+        server
+            .storage
+            .next_expiry()
+            .is_some_and(|at| at <= storage::now_ms())
+    };
+    // Nothing to do before the nearest deadline
+    if !due() {
+        return false;
+    }
+    let start = Instant::now();
+    {
+        // Locked as for a write command, so each DEL is propagated in the
+        // order it happened among other writes
+        let _guard = server.storage.shared();
+        let mut aof = server.aof.lock();
+        let mut replicas = server.replication.lock();
+        for (db, key) in server.storage.run_expiry_cleanup(budget) {
+            commands::propagate(&mut aof, &mut replicas, db, &["del", &key]);
+            server.storage.touch_key(db, &key);
+            notify::notify(server, KeyspaceEvent::Expired, db, &key);
+        }
+    }
+    let threshold = server.config.read().unwrap().latency_monitor_threshold;
+    server
+        .latency
+        .record("expire-cycle", start.elapsed(), threshold);
+    due()
+}
+
+/// Starts a BGSAVE when one was scheduled or a `save` rule matches, backing
+/// off for a few seconds after a failed one.
+fn auto_save(server: &Server, _budget: Duration) -> bool {
+    let now = storage::now_ms() / 1000;
+    if server.rdb.bgsave_in_progress() || !server.rdb.may_start_bgsave(now) {
+        return false;
+    }
+    if server.rdb.bgsave_scheduled() {
+        tracing::info!("Starting the scheduled background save");
+    } else {
+        let since_save = now.saturating_sub(server.lastsave.load(Ordering::Relaxed));
+        let dirty = Stats::get(&server.stats.dirty);
+        let rules = server.config.read().unwrap().save_rules.clone();
+        let Some((seconds, changes)) = due_save_rule(&rules, dirty, since_save) else {
+            return false;
+        };
+        tracing::info!("{} changes in {} seconds. Saving...", changes, seconds);
+    }
+    if let Err(e) = rdb::bgsave(server) {
+        tracing::warn!("{}", e.trim_start_matches("ERR "));
+    }
+    false
+}
+
+/// The first `(seconds, changes)` rule satisfied by `dirty` changes made
+/// over `since_save` seconds.
+fn due_save_rule(rules: &[(u64, u64)], dirty: u64, since_save: u64) -> Option<(u64, u64)> {
+    rules
+        .iter()
+        .copied()
+        .find(|&(seconds, changes)| dirty >= changes && since_save > seconds)
+}
+
+fn sample_stats(server: &Server, _budget: Duration) -> bool {
+    server.stats.track_instantaneous_metrics();
+    memory::update_peak();
+    false
+}
+
+/// Disconnects clients idle for longer than `timeout` seconds. Blocked and
+/// subscribed clients are left alone, as in Redis.
+fn close_idle_clients(server: &Server, _budget: Duration) -> bool {
+    let timeout = server.config.read().unwrap().timeout;
+    if timeout == 0 {
+        return false;
+    }
+    let timeout = Duration::from_secs(timeout);
+    for client in server.clients.list() {
+        if !client.blocked
+            && client.sub + client.psub == 0
+            && client.last_interaction.elapsed() > timeout
+        {
+            tracing::debug!("Closing idle client {}", client.id);
+            server.clients.kill(client.id);
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_runs_due_tasks() {
+        let server = Server::default();
+        let mut last_runs = vec![None; CRON_TASKS.len()];
+        let interval = Duration::from_millis(100);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        rt.block_on(tick(&server, interval, &mut last_runs));
+        rt.block_on(tick(&server, interval, &mut last_runs));
+        let runs = |name: &str| {
+            let index = CRON_TASKS.iter().position(|t| t.name == name).unwrap();
+            server.cron.tasks[index].runs.load(Ordering::Relaxed)
+        };
+        // Every-tick tasks ran twice, slower ones only once
+        assert_eq!(runs("expire-cycle"), 2);
+        assert_eq!(runs("clients-timeout"), 1);
+        assert!(
+            server
+                .cron
+                .info_lines()
+                .contains("cron_task_expire_cycle:runs=2,")
+        );
+    }
+
+    #[test]
+    fn test_expire_cycle_yields_between_slices() {
+        let server = Server::default();
+        let db = server.storage.db(0);
+        for i in 0..1000 {
+            db.restore(
+                format!("k:{}", i),
+                storage::Value::String("v".into()),
+                Some(1),
+            );
+        }
+        // A spent budget still deletes a batch, then reports what's left
+        assert!(expire_cycle(&server, Duration::ZERO));
+        assert!(db.expires_count() < 1000);
+
+        // A tick keeps going, slice by slice, until all are gone
+        let mut last_runs = vec![None; CRON_TASKS.len()];
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(tick(&server, Duration::from_secs(1), &mut last_runs));
+        assert_eq!(db.expires_count(), 0);
+        assert!(!expire_cycle(&server, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_due_save_rule() {
+        let rules = [(3600, 1), (300, 100), (60, 10000)];
+        assert_eq!(due_save_rule(&rules, 0, 100_000), None);
+        assert_eq!(due_save_rule(&rules, 5, 3601), Some((3600, 1)));
+        assert_eq!(due_save_rule(&rules, 5, 3600), None);
+        assert_eq!(due_save_rule(&rules, 150, 301), Some((300, 100)));
+        assert_eq!(due_save_rule(&rules, 20000, 61), Some((60, 10000)));
+        assert_eq!(due_save_rule(&[], 20000, 100_000), None);
+    }
+}
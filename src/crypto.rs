@@ -0,0 +1,288 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+
+use crate::digest::sha1;
+
+/// Start of every encrypted persistence file. Plain RDB files start with
+/// "REDIS" and plain AOFs with '*', so the two can't be confused.
+pub const MAGIC: &[u8; 8] = b"RRCRYPT1";
+
+/// Environment variable holding the keys when `encryption-key-file` isn't
+/// set, in the same format as the file.
+pub const KEY_ENV: &str = "REREDIS_ENCRYPTION_KEY";
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_ID_LEN: usize = 4;
+
+/// Overhead of a sealed message: key id, nonce and tag.
+pub const SEAL_OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+/// An AES-256-GCM key.
+#[derive(Clone)]
+pub struct Key {
+    /// First bytes of the key's SHA-1, recorded with everything it seals
+    /// so the right key can be found again after a rotation.
+    id: [u8; KEY_ID_LEN],
+    cipher: Aes256Gcm,
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key({:02x?})", self.id)
+    }
+}
+
+impl Key {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&sha1(key)[..KEY_ID_LEN]);
+        Key {
+            id,
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        self.cipher
+            .encrypt(nonce.into(), payload)
+            .expect("files are far below GCM's length limit")
+    }
+
+    fn decrypt(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let payload = Payload { msg: sealed, aad };
+        self.cipher.decrypt(nonce.into(), payload).ok()
+    }
+
+    /// Encrypts `plaintext` under a fresh random nonce, as the key id, the
+    /// nonce, the ciphertext and the tag. `aad` is authenticated but not
+    /// included, so opening it takes the same `aad` again.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = random_bytes()?;
+        let mut out = Vec::with_capacity(plaintext.len() + SEAL_OVERHEAD);
+        out.extend_from_slice(&self.id);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&self.encrypt(&nonce, aad, plaintext));
+        Ok(out)
+    }
+}
+
+/// Fills `bytes` from the operating system's random number generator, for
+/// nonces, file ids and passwords.
+pub fn fill_random(bytes: &mut [u8]) -> io::Result<()> {
+    OsRng
+        .try_fill_bytes(bytes)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+pub fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    fill_random(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The keys persistence files are encrypted with: the first one encrypts
+/// everything written from now on, and all of them can decrypt, so a key
+/// can be rotated by putting a new one first and keeping the old ones until
+/// every file has been rewritten.
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    /// Parses hex-encoded 256-bit keys, one per line or separated by
+    /// commas. Blank lines and `#` comments are ignored.
+    pub fn parse(text: &str) -> Result<Keyring, String> {
+        let mut keys = Vec::new();
+        for line in text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or(""))
+        {
+            for hex in line.split(',').map(str::trim).filter(|hex| !hex.is_empty()) {
+                keys.push(Key::new(&parse_hex_key(hex)?));
+            }
+        }
+        if keys.is_empty() {
+            return Err("no encryption keys found".to_string());
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// The keys named by `encryption-key-file`, or else by the
+    /// REREDIS_ENCRYPTION_KEY environment variable. None when neither is
+    /// set, meaning files are written in the clear.
+    pub fn load(key_file: &str) -> Result<Option<Keyring>, String> {
+        if !key_file.is_empty() {
+            let text = fs::read_to_string(key_file)
+                .map_err(|e| format!("can't read encryption-key-file '{}': {}", key_file, e))?;
+            return Keyring::parse(&text)
+                .map(Some)
+                .map_err(|e| format!("encryption-key-file '{}': {}", key_file, e));
+        }
+        match std::env::var(KEY_ENV) {
+            Ok(text) => Keyring::parse(&text)
+                .map(Some)
+                .map_err(|e| format!("{}: {}", KEY_ENV, e)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The key new data is encrypted with.
+    pub fn current(&self) -> &Key {
+        &self.keys[0]
+    }
+
+    /// Decrypts something made by `Key::seal` with any of the keys, given
+    /// the `aad` it was sealed with.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err("encrypted data is truncated".to_string());
+        }
+        let (id, rest) = sealed.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or_else(|| format!("encrypted with an unknown key ({:02x?})", id))?;
+        key.decrypt(nonce.try_into().unwrap(), aad, ciphertext)
+            .ok_or_else(|| "encrypted data failed authentication (corrupt file?)".to_string())
+    }
+
+    /// Encrypts a whole file: the magic followed by the sealed contents.
+    pub fn seal_file(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&self.current().seal(MAGIC, data)?);
+        Ok(out)
+    }
+}
+
+fn parse_hex_key(hex: &str) -> Result<[u8; 32], String> {
+    let invalid = || "encryption keys must be 64 hex digits (256 bits)".to_string();
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or_else(invalid)?, 16)
+            .map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// The contents of a file written by `Keyring::seal_file`, or the file
+/// itself when it isn't encrypted.
+pub fn open_file<'a>(data: &'a [u8], keyring: Option<&Keyring>) -> Result<Cow<'a, [u8]>, String> {
+    if !is_encrypted(data) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let keyring = keyring.ok_or("the file is encrypted but no encryption key is configured")?;
+    keyring.open(MAGIC, &data[MAGIC.len()..]).map(Cow::Owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_gcm_vectors() {
+        // Test cases 14 and 15 of the original GCM specification
+        let key = Key::new(&[0; 32]);
+        let sealed = key.encrypt(&[0; 12], b"", &[0; 16]);
+        assert_eq!(
+            sealed,
+            hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")
+        );
+
+        let key = Key::new(
+            &hex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308")
+                .try_into()
+                .unwrap(),
+        );
+        let nonce = hex("cafebabefacedbaddecaf888").try_into().unwrap();
+        let plaintext = hex(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255",
+        );
+        let sealed = key.encrypt(&nonce, b"", &plaintext);
+        assert_eq!(
+            sealed,
+            hex(
+                "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015adb094dac5d93471bdec1a502270e3cc6c"
+            )
+        );
+        assert_eq!(key.decrypt(&nonce, b"", &sealed), Some(plaintext));
+
+        // Test case 16: the same with additional data, authenticated but
+        // not encrypted
+        let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let plaintext = hex(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        );
+        let sealed = key.encrypt(&nonce, &aad, &plaintext);
+        assert_eq!(
+            sealed,
+            hex(
+                "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f66276fc6ece0f4e1768cddf8853bb2d551b"
+            )
+        );
+        assert_eq!(key.decrypt(&nonce, &aad, &sealed), Some(plaintext));
+        assert_eq!(key.decrypt(&nonce, b"", &sealed), None);
+    }
+
+    #[test]
+    fn test_keyring_rotation() {
+        let old = "11".repeat(32);
+        let new = "22".repeat(32);
+        let before = Keyring::parse(&old).unwrap();
+        let sealed = before.seal_file(b"REDIS0010 data").unwrap();
+        assert!(is_encrypted(&sealed));
+
+        // The new key writes, the old one still reads
+        let after = Keyring::parse(&format!("# rotated\n{}\n{}\n", new, old)).unwrap();
+        assert_eq!(
+            open_file(&sealed, Some(&after)).unwrap().as_ref(),
+            b"REDIS0010 data"
+        );
+        let resealed = after.seal_file(b"REDIS0010 data").unwrap();
+        assert!(open_file(&resealed, Some(&before)).is_err());
+        assert!(open_file(&sealed, None).is_err());
+
+        // Sealed for one purpose, it doesn't open for another
+        let record = after.current().seal(b"record 1", b"SET a 1").unwrap();
+        assert_eq!(after.open(b"record 1", &record).unwrap(), b"SET a 1");
+        assert!(after.open(b"record 2", &record).is_err());
+
+        let mut corrupt = sealed.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(open_file(&corrupt, Some(&after)).is_err());
+        assert_eq!(
+            open_file(b"REDIS0010", None).unwrap().as_ref(),
+            b"REDIS0010"
+        );
+
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("abcd").is_err());
+        assert!(Keyring::parse(&"zz".repeat(32)).is_err());
+    }
+}
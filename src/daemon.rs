@@ -0,0 +1,73 @@
+use std::fs;
+use std::io;
+
+/// Pidfile written when daemonizing without an explicit `pidfile`, as Redis
+/// does.
+pub const DEFAULT_PIDFILE: &str = "/var/run/reredis.pid";
+
+/// Detaches from the controlling terminal: forks, lets the parent exit,
+/// starts a new session and points the standard streams at /dev/null.
+///
+/// Must run before any threads are started, so before the tokio runtime.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: the process is still single-threaded, so fork() is sound, and
+    // every other call only receives valid constants or a NUL-terminated path.
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for stream in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            libc::dup2(fd, stream);
+        }
+        if fd > libc::STDERR_FILENO {
+            libc::close(fd);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "daemonize is only supported on unix",
+    ))
+}
+
+pub fn write_pidfile(path: &str) -> io::Result<()> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+/// Removes the pidfile, ignoring one that is already gone.
+pub fn remove_pidfile(path: &str) {
+    let _ = fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile_roundtrip() {
+        let path = std::env::temp_dir().join(format!("reredis-test-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_pidfile(path).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        remove_pidfile(path);
+        assert!(fs::metadata(path).is_err());
+        remove_pidfile(path);
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::memory;
+use crate::server::Server;
+use crate::stats::Stats;
+
+/// Keys compacted per database lock, so clients wait for at most a batch.
+const BATCH: usize = 64;
+
+/// Where active defrag is in its pass over the keyspace.
+#[derive(Debug, Default)]
+pub struct ActiveDefrag {
+    /// The database and the position in it the next batch starts at.
+    cursor: Mutex<(usize, usize)>,
+    running: AtomicBool,
+}
+
+impl ActiveDefrag {
+    /// Whether a pass is under way, as `active_defrag_running` in INFO.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether the allocator wastes enough to start a pass: at least
+/// `ignore_bytes`, and `threshold` percent of what's allocated.
+fn fragmented(ignore_bytes: usize, threshold: u64) -> bool {
+    let stats = memory::allocator_stats();
+    let frag_bytes = stats.active.saturating_sub(stats.allocated);
+    frag_bytes > 0
+        && frag_bytes >= ignore_bytes
+        && frag_bytes as u64 * 100 >= stats.allocated as u64 * threshold
+}
+
+/// Runs one slice of active defrag from the cron: reallocates values whose
+/// buffers are mostly unused, in batches, until `budget` is spent. A pass
+/// starts when the allocator is fragmented enough and goes over every key
+/// once, spread over as many ticks as it takes.
+pub fn cycle(server: &Server, budget: Duration) {
+    let defrag = &server.defrag;
+    if !defrag.is_running() {
+        let (enabled, ignore_bytes, threshold) = {
+            let config = server.config.read().unwrap();
+            (
+                config.activedefrag,
+                config.active_defrag_ignore_bytes,
+                config.active_defrag_threshold_lower,
+            )
+        };
+        if !enabled || !fragmented(ignore_bytes, threshold) {
+            return;
+        }
+        tracing::debug!("Starting active defrag");
+        defrag.running.store(true, Ordering::Relaxed);
+    }
+    let start = Instant::now();
+    let mut cursor = defrag.cursor.lock().unwrap();
+    while start.elapsed() < budget {
+        let (db, position) = *cursor;
+        if db >= server.storage.len() {
+            *cursor = (0, 0);
+            defrag.running.store(false, Ordering::Relaxed);
+            tracing::debug!("Active defrag done");
+            return;
+        }
+        let (next, hits, misses) = server.storage.db(db).compact(position, BATCH);
+        Stats::add(&server.stats.active_defrag_hits, hits as u64);
+        Stats::add(&server.stats.active_defrag_misses, misses as u64);
+        *cursor = match next {
+            Some(position) => (db, position),
+            None => (db + 1, 0),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Value;
+
+    #[test]
+    fn test_cycle() {
+        let server = Server::default();
+        let db = server.storage.db(0);
+        for i in 0..200 {
+            let mut value = String::with_capacity(1024);
+            value.push_str("value");
+            db.restore(format!("key:{}", i), Value::String(value), None);
+        }
+        db.set("small".to_string(), "value".to_string());
+
+        // Disabled by default
+        cycle(&server, Duration::from_secs(1));
+        assert!(!server.defrag.is_running());
+        assert_eq!(Stats::get(&server.stats.active_defrag_hits), 0);
+
+        // Started by hand, as whether the allocator is fragmented enough
+        // depends on the rest of the process
+        server.defrag.running.store(true, Ordering::Relaxed);
+        cycle(&server, Duration::from_secs(1));
+        assert!(!server.defrag.is_running());
+        assert_eq!(Stats::get(&server.stats.active_defrag_hits), 200);
+        assert_eq!(Stats::get(&server.stats.active_defrag_misses), 1);
+        assert_eq!(db.get("key:7"), Some("value".to_string()));
+
+        // A zero budget makes no progress
+        server.defrag.running.store(true, Ordering::Relaxed);
+        cycle(&server, Duration::ZERO);
+        assert_eq!(*server.defrag.cursor.lock().unwrap(), (0, 0));
+    }
+}
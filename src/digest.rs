@@ -0,0 +1,187 @@
+use sha1::{Digest, Sha1};
+
+use crate::binary;
+use crate::storage::{Storage, Value};
+use crate::zset;
+
+/// A digest of nothing at all, reported for empty datasets and missing keys.
+pub const EMPTY_DIGEST: [u8; 20] = [0; 20];
+
+/// SHA-1 of `data`.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    Sha1::digest(data).into()
+}
+
+pub fn to_hex(digest: &[u8; 20]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// XORs the SHA-1 of `data` into `digest`, so the order of calls doesn't
+/// matter.
+fn xor_digest(digest: &mut [u8; 20], data: &[u8]) {
+    for (d, h) in digest.iter_mut().zip(sha1(data)) {
+        *d ^= h;
+    }
+}
+
+/// Like `xor_digest` but then rehashes, so the order of calls matters.
+fn mix_digest(digest: &mut [u8; 20], data: &[u8]) {
+    xor_digest(digest, data);
+    *digest = sha1(digest);
+}
+
+/// Digest of a single value, as reported by DEBUG DIGEST-VALUE.
+pub fn value_digest(value: &Value, has_expiry: bool) -> [u8; 20] {
+    let mut digest = EMPTY_DIGEST;
+    add_value(&mut digest, value, has_expiry);
+    digest
+}
+
+/// Folds a value into `digest` the way Redis does: ordered for strings,
+/// lists and streams, order-independent for sets, hashes and sorted sets.
+/// Bloom filters are folded in by their filters' bits.
+fn add_value(digest: &mut [u8; 20], value: &Value, has_expiry: bool) {
+    match value {
+        Value::String(s) => mix_digest(digest, &binary::encode(s)),
+        Value::Int(n) => mix_digest(digest, n.to_string().as_bytes()),
+        Value::List(list) => {
+            for element in list {
+                mix_digest(digest, &binary::encode(element));
+            }
+        }
+        Value::Set(set) => {
+            for member in set {
+                xor_digest(digest, &binary::encode(member));
+            }
+        }
+        Value::Hash(hash) => {
+            for (field, value) in hash {
+                let mut element = EMPTY_DIGEST;
+                mix_digest(&mut element, &binary::encode(field));
+                mix_digest(&mut element, &binary::encode(value));
+                xor_digest(digest, &element);
+            }
+        }
+        Value::ZSet(zset) => {
+            for (member, score) in zset {
+                let mut element = EMPTY_DIGEST;
+                mix_digest(&mut element, &binary::encode(member));
+                mix_digest(&mut element, zset::format_score(score).as_bytes());
+                xor_digest(digest, &element);
+            }
+        }
+        Value::Stream(stream) => {
+            for (id, fields) in stream.iter() {
+                mix_digest(digest, id.to_string().as_bytes());
+                for (field, value) in fields {
+                    mix_digest(digest, &binary::encode(field));
+                    mix_digest(digest, &binary::encode(value));
+                }
+            }
+            // Groups by what they were delivered and who has it pending,
+            // not when
+            for (name, group) in stream.groups() {
+                mix_digest(digest, &binary::encode(name));
+                mix_digest(digest, group.last_id().to_string().as_bytes());
+                for id in group.pending().keys() {
+                    mix_digest(digest, id.to_string().as_bytes());
+                }
+                for (consumer, state) in group.consumers() {
+                    mix_digest(digest, &binary::encode(consumer));
+                    for id in &state.pending {
+                        mix_digest(digest, id.to_string().as_bytes());
+                    }
+                }
+            }
+        }
+        Value::Bloom(bloom) => {
+            for filter in bloom.filters() {
+                mix_digest(digest, filter.size.to_string().as_bytes());
+                mix_digest(digest, &filter.data);
+            }
+        }
+    }
+    if has_expiry {
+        xor_digest(digest, b"!!expire!!");
+    }
+}
+
+/// Digest of every key in every database, independent of iteration order.
+pub fn dataset_digest(storage: &Storage) -> [u8; 20] {
+    let mut digest = EMPTY_DIGEST;
+    for index in 0..storage.len() {
+        let db = storage.db(index);
+        if db.dbsize() == 0 {
+            continue;
+        }
+        mix_digest(&mut digest, &(index as u32).to_be_bytes());
+        db.for_each(|key, value, has_expiry| {
+            let mut key_digest = EMPTY_DIGEST;
+            mix_digest(&mut key_digest, &binary::encode(key));
+            add_value(&mut key_digest, value, has_expiry);
+            xor_digest(&mut digest, &key_digest);
+        });
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            to_hex(&sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            to_hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+
+    #[test]
+    fn test_dataset_digest() {
+        let storage = Storage::new();
+        assert_eq!(dataset_digest(&storage), EMPTY_DIGEST);
+
+        let db = storage.db(0);
+        db.set("a".to_string(), "1".to_string());
+        db.sadd("s", vec!["x".to_string(), "y".to_string()])
+            .unwrap();
+        let first = dataset_digest(&storage);
+        assert_ne!(first, EMPTY_DIGEST);
+
+        // Same contents inserted in another order
+        let other = Storage::new();
+        let db = other.db(0);
+        db.sadd("s", vec!["y".to_string(), "x".to_string()])
+            .unwrap();
+        db.set("a".to_string(), "1".to_string());
+        assert_eq!(dataset_digest(&other), first);
+
+        // The database a key lives in and its expiry both count
+        let moved = Storage::new();
+        moved.db(1).set("a".to_string(), "1".to_string());
+        moved
+            .db(1)
+            .sadd("s", vec!["x".to_string(), "y".to_string()])
+            .unwrap();
+        assert_ne!(dataset_digest(&moved), first);
+        db.expire("a", 60_000);
+        assert_ne!(dataset_digest(&other), first);
+
+        // List order matters
+        let left = Storage::new();
+        left.db(0)
+            .rpush("l", vec!["1".to_string(), "2".to_string()])
+            .unwrap();
+        let right = Storage::new();
+        right
+            .db(0)
+            .rpush("l", vec!["2".to_string(), "1".to_string()])
+            .unwrap();
+        assert_ne!(dataset_digest(&left), dataset_digest(&right));
+    }
+}
@@ -0,0 +1,1883 @@
+use crate::parser::Resp;
+
+/// Documentation for one command, as returned by COMMAND DOCS.
+pub struct CommandDoc {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub since: &'static str,
+    pub group: &'static str,
+    pub complexity: &'static str,
+    pub arguments: &'static [ArgDoc],
+}
+
+/// One argument of a command. `oneof` and `block` arguments group the
+/// arguments nested in them.
+pub struct ArgDoc {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub token: Option<&'static str>,
+    pub optional: bool,
+    pub multiple: bool,
+    pub arguments: &'static [ArgDoc],
+}
+
+impl ArgDoc {
+    const fn new(name: &'static str, kind: &'static str) -> Self {
+        ArgDoc {
+            name,
+            kind,
+            token: None,
+            optional: false,
+            multiple: false,
+            arguments: &[],
+        }
+    }
+
+    const fn token(mut self, token: &'static str) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    const fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    const fn multiple(mut self) -> Self {
+        self.multiple = true;
+        self
+    }
+
+    fn to_resp(&self) -> Resp {
+        let bulk = |s: &str| Resp::Bulk(Some(s.to_string()));
+        let mut fields = vec![
+            (bulk("name"), bulk(self.name)),
+            (bulk("type"), bulk(self.kind)),
+        ];
+        if let Some(token) = self.token {
+            fields.push((bulk("token"), bulk(token)));
+        }
+        let mut flags = Vec::new();
+        if self.optional {
+            flags.push(Resp::Simple("optional".to_string()));
+        }
+        if self.multiple {
+            flags.push(Resp::Simple("multiple".to_string()));
+        }
+        if !flags.is_empty() {
+            fields.push((bulk("flags"), Resp::Array(Some(flags))));
+        }
+        if !self.arguments.is_empty() {
+            let arguments = self.arguments.iter().map(ArgDoc::to_resp).collect();
+            fields.push((bulk("arguments"), Resp::Array(Some(arguments))));
+        }
+        Resp::Map(fields)
+    }
+}
+
+const fn key(name: &'static str) -> ArgDoc {
+    ArgDoc::new(name, "key")
+}
+
+const fn string(name: &'static str) -> ArgDoc {
+    ArgDoc::new(name, "string")
+}
+
+const fn integer(name: &'static str) -> ArgDoc {
+    ArgDoc::new(name, "integer")
+}
+
+const fn double(name: &'static str) -> ArgDoc {
+    ArgDoc::new(name, "double")
+}
+
+const fn pattern(name: &'static str) -> ArgDoc {
+    ArgDoc::new(name, "pattern")
+}
+
+const fn token(name: &'static str, token: &'static str) -> ArgDoc {
+    ArgDoc::new(name, "pure-token").token(token)
+}
+
+const fn oneof(name: &'static str, arguments: &'static [ArgDoc]) -> ArgDoc {
+    let mut arg = ArgDoc::new(name, "oneof");
+    arg.arguments = arguments;
+    arg
+}
+
+const fn block(name: &'static str, arguments: &'static [ArgDoc]) -> ArgDoc {
+    let mut arg = ArgDoc::new(name, "block");
+    arg.arguments = arguments;
+    arg
+}
+
+/// The LEFT | RIGHT end LMPOP and BLMPOP pop from.
+const LIST_WHERE: ArgDoc = oneof("where", &[token("left", "LEFT"), token("right", "RIGHT")]);
+
+/// The NX | XX | GT | LT option of the EXPIRE family.
+const EXPIRE_CONDITION: ArgDoc = oneof(
+    "condition",
+    &[
+        token("nx", "NX"),
+        token("xx", "XX"),
+        token("gt", "GT"),
+        token("lt", "LT"),
+    ],
+)
+.optional();
+
+/// The MIN | MAX end ZMPOP and BZMPOP pop from.
+const ZSET_WHERE: ArgDoc = oneof("where", &[token("min", "MIN"), token("max", "MAX")]);
+
+/// The BYSCORE | BYLEX option of ZRANGE and ZRANGESTORE.
+const ZRANGE_BY: ArgDoc = oneof(
+    "sortby",
+    &[token("byscore", "BYSCORE"), token("bylex", "BYLEX")],
+)
+.optional();
+
+/// The WEIGHTS option of ZUNION and ZINTER and their STORE forms.
+const ZSET_WEIGHTS: ArgDoc = double("weight").token("WEIGHTS").optional().multiple();
+
+/// The AGGREGATE SUM | MIN | MAX option of ZUNION and ZINTER and their
+/// STORE forms.
+const ZSET_AGGREGATE: ArgDoc = oneof(
+    "aggregate",
+    &[
+        token("sum", "SUM"),
+        token("min", "MIN"),
+        token("max", "MAX"),
+    ],
+)
+.token("AGGREGATE")
+.optional();
+
+/// The LIMIT offset count option of the ZRANGE family.
+const ZRANGE_LIMIT: ArgDoc = block("limit", &[integer("offset"), integer("count")])
+    .token("LIMIT")
+    .optional();
+
+/// The M | KM | FT | MI unit of the geo commands' distances.
+const GEO_UNIT: ArgDoc = oneof(
+    "unit",
+    &[
+        token("m", "M"),
+        token("km", "KM"),
+        token("ft", "FT"),
+        token("mi", "MI"),
+    ],
+);
+
+/// The FROMMEMBER | FROMLONLAT origin of GEOSEARCH and GEOSEARCHSTORE.
+const GEO_FROM: ArgDoc = oneof(
+    "from",
+    &[
+        string("member").token("FROMMEMBER"),
+        block("fromlonlat", &[double("longitude"), double("latitude")]).token("FROMLONLAT"),
+    ],
+);
+
+/// The BYRADIUS | BYBOX shape of GEOSEARCH and GEOSEARCHSTORE.
+const GEO_BY: ArgDoc = oneof(
+    "by",
+    &[
+        block("circle", &[double("radius").token("BYRADIUS"), GEO_UNIT]),
+        block(
+            "box",
+            &[double("width").token("BYBOX"), double("height"), GEO_UNIT],
+        ),
+    ],
+);
+
+/// The ASC | DESC order of GEOSEARCH and GEOSEARCHSTORE.
+const GEO_ORDER: ArgDoc = oneof("order", &[token("asc", "ASC"), token("desc", "DESC")]).optional();
+
+/// The COUNT count [ANY] option of GEOSEARCH and GEOSEARCHSTORE.
+const GEO_COUNT: ArgDoc = block(
+    "count-block",
+    &[
+        integer("count").token("COUNT"),
+        token("any", "ANY").optional(),
+    ],
+)
+.optional();
+
+/// The MAXLEN | MINID [= | ~] threshold [LIMIT count] trim of XTRIM, and
+/// of XADD as an option.
+const STREAM_TRIM: ArgDoc = block(
+    "trim",
+    &[
+        oneof(
+            "strategy",
+            &[token("maxlen", "MAXLEN"), token("minid", "MINID")],
+        ),
+        oneof(
+            "operator",
+            &[token("equal", "="), token("approximately", "~")],
+        )
+        .optional(),
+        string("threshold"),
+        integer("count").token("LIMIT").optional(),
+    ],
+);
+
+impl CommandDoc {
+    /// The documentation map entry for this command: its name and the map
+    /// of fields describing it.
+    pub fn to_resp(&self) -> (Resp, Resp) {
+        let bulk = |s: &str| Resp::Bulk(Some(s.to_string()));
+        let mut fields = vec![
+            (bulk("summary"), bulk(self.summary)),
+            (bulk("since"), bulk(self.since)),
+            (bulk("group"), bulk(self.group)),
+            (bulk("complexity"), bulk(self.complexity)),
+        ];
+        if !self.arguments.is_empty() {
+            let arguments = self.arguments.iter().map(ArgDoc::to_resp).collect();
+            fields.push((bulk("arguments"), Resp::Array(Some(arguments))));
+        }
+        (bulk(self.name), Resp::Map(fields))
+    }
+}
+
+const fn doc(
+    name: &'static str,
+    summary: &'static str,
+    since: &'static str,
+    group: &'static str,
+    complexity: &'static str,
+    arguments: &'static [ArgDoc],
+) -> CommandDoc {
+    CommandDoc {
+        name,
+        summary,
+        since,
+        group,
+        complexity,
+        arguments,
+    }
+}
+
+const O1: &str = "O(1)";
+
+/// Documentation for every command in the command table.
+pub static COMMAND_DOCS: &[CommandDoc] = &[
+    // Connection and server
+    doc(
+        "auth",
+        "Authenticates the connection.",
+        "1.0.0",
+        "connection",
+        "O(N) where N is the number of passwords defined for the user",
+        &[string("username").optional(), string("password")],
+    ),
+    doc(
+        "hello",
+        "Handshakes with the Redis server.",
+        "6.0.0",
+        "connection",
+        O1,
+        &[block(
+            "arguments",
+            &[
+                integer("protover"),
+                block(
+                    "username-password",
+                    &[string("username"), string("password")],
+                )
+                .token("AUTH")
+                .optional(),
+                string("clientname").token("SETNAME").optional(),
+            ],
+        )
+        .optional()],
+    ),
+    doc(
+        "ping",
+        "Returns the server's liveliness response.",
+        "1.0.0",
+        "connection",
+        O1,
+        &[string("message").optional()],
+    ),
+    doc(
+        "echo",
+        "Returns the given string.",
+        "1.0.0",
+        "connection",
+        O1,
+        &[string("message")],
+    ),
+    doc(
+        "quit",
+        "Closes the connection.",
+        "1.0.0",
+        "connection",
+        O1,
+        &[],
+    ),
+    doc(
+        "command",
+        "Returns detailed information about all commands.",
+        "2.8.13",
+        "server",
+        "O(N) where N is the total number of Redis commands",
+        &[],
+    ),
+    doc(
+        "config",
+        "A container for server configuration commands.",
+        "2.0.0",
+        "server",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "acl",
+        "A container for Access List Control commands.",
+        "6.0.0",
+        "server",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "client",
+        "A container for client connection commands.",
+        "2.4.0",
+        "connection",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "cluster",
+        "A container for Redis Cluster commands.",
+        "3.0.0",
+        "cluster",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "memory",
+        "A container for memory diagnostics commands.",
+        "4.0.0",
+        "server",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "latency",
+        "A container for latency diagnostics commands.",
+        "2.8.13",
+        "server",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "debug",
+        "A container for debugging commands.",
+        "1.0.0",
+        "server",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "monitor",
+        "Listens for all requests received by the server in real-time.",
+        "1.0.0",
+        "server",
+        "",
+        &[],
+    ),
+    doc(
+        "info",
+        "Returns information and statistics about the server.",
+        "1.0.0",
+        "server",
+        O1,
+        &[string("section").optional()],
+    ),
+    doc(
+        "time",
+        "Returns the server time.",
+        "2.6.0",
+        "server",
+        O1,
+        &[],
+    ),
+    doc(
+        "lastsave",
+        "Returns the Unix timestamp of the last successful save to disk.",
+        "1.0.0",
+        "server",
+        O1,
+        &[],
+    ),
+    doc(
+        "save",
+        "Synchronously saves the database(s) to disk.",
+        "1.0.0",
+        "server",
+        "O(N) where N is the total number of keys in all databases",
+        &[],
+    ),
+    doc(
+        "bgsave",
+        "Asynchronously saves the database(s) to disk.",
+        "1.0.0",
+        "server",
+        O1,
+        &[token("schedule", "SCHEDULE").optional()],
+    ),
+    doc(
+        "replicaof",
+        "Configures a server as replica of another, or promotes it to a master.",
+        "5.0.0",
+        "server",
+        O1,
+        &[oneof(
+            "args",
+            &[
+                block("host-port", &[string("host"), integer("port")]),
+                block("no-one", &[token("no", "NO"), token("one", "ONE")]),
+            ],
+        )],
+    ),
+    doc(
+        "slaveof",
+        "Sets a Redis server as a replica of another, or promotes it to being a master.",
+        "1.0.0",
+        "server",
+        O1,
+        &[oneof(
+            "args",
+            &[
+                block("host-port", &[string("host"), integer("port")]),
+                block("no-one", &[token("no", "NO"), token("one", "ONE")]),
+            ],
+        )],
+    ),
+    doc(
+        "sync",
+        "An internal command used in replication.",
+        "1.0.0",
+        "server",
+        "",
+        &[],
+    ),
+    doc(
+        "psync",
+        "An internal command used in replication.",
+        "2.8.0",
+        "server",
+        "",
+        &[
+            string("replicationid"),
+            integer("offset"),
+            token("failover", "FAILOVER").optional(),
+        ],
+    ),
+    doc(
+        "replconf",
+        "An internal command for configuring the replication stream.",
+        "3.0.0",
+        "server",
+        O1,
+        &[],
+    ),
+    doc(
+        "failover",
+        "Starts a coordinated failover from a server to one of its replicas.",
+        "6.2.0",
+        "server",
+        O1,
+        &[
+            block(
+                "target",
+                &[
+                    string("host"),
+                    integer("port"),
+                    token("force", "FORCE").optional(),
+                ],
+            )
+            .token("TO")
+            .optional(),
+            token("abort", "ABORT").optional(),
+            integer("milliseconds").token("TIMEOUT").optional(),
+        ],
+    ),
+    doc(
+        "role",
+        "Returns the replication role.",
+        "2.8.12",
+        "server",
+        O1,
+        &[],
+    ),
+    doc(
+        "dbsize",
+        "Returns the number of keys in the database.",
+        "1.0.0",
+        "server",
+        O1,
+        &[],
+    ),
+    // Strings
+    doc(
+        "set",
+        "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+        "1.0.0",
+        "string",
+        O1,
+        &[
+            key("key"),
+            string("value"),
+            oneof("condition", &[token("nx", "NX"), token("xx", "XX")]).optional(),
+            token("get", "GET").optional(),
+            oneof(
+                "expiration",
+                &[
+                    integer("seconds").token("EX"),
+                    integer("milliseconds").token("PX"),
+                    token("keepttl", "KEEPTTL"),
+                ],
+            )
+            .optional(),
+        ],
+    ),
+    doc(
+        "get",
+        "Returns the string value of a key.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "setnx",
+        "Set the string value of a key only when the key doesn't exist.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key"), string("value")],
+    ),
+    doc(
+        "setex",
+        "Sets the string value and expiration time of a key. Creates the key if it doesn't exist.",
+        "2.0.0",
+        "string",
+        O1,
+        &[key("key"), integer("seconds"), string("value")],
+    ),
+    doc(
+        "psetex",
+        "Sets both string value and expiration time in milliseconds of a key. The key is created if it doesn't exist.",
+        "2.6.0",
+        "string",
+        O1,
+        &[key("key"), integer("milliseconds"), string("value")],
+    ),
+    doc(
+        "getset",
+        "Returns the previous string value of a key after setting it to a new value.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key"), string("value")],
+    ),
+    doc(
+        "mset",
+        "Atomically creates or modifies the string values of one or more keys.",
+        "1.0.1",
+        "string",
+        "O(N) where N is the number of keys to set.",
+        &[block("data", &[key("key"), string("value")]).multiple()],
+    ),
+    doc(
+        "mget",
+        "Atomically returns the string values of one or more keys.",
+        "1.0.0",
+        "string",
+        "O(N) where N is the number of keys to retrieve.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "incr",
+        "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "incrby",
+        "Increments the integer value of a key by a number. Uses 0 as initial value if the key doesn't exist.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key"), integer("increment")],
+    ),
+    doc(
+        "decr",
+        "Decrements the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "decrby",
+        "Decrements a number from the integer value of a key. Uses 0 as initial value if the key doesn't exist.",
+        "1.0.0",
+        "string",
+        O1,
+        &[key("key"), integer("decrement")],
+    ),
+    doc(
+        "append",
+        "Appends a string to the value of a key. Creates the key if it doesn't exist.",
+        "2.0.0",
+        "string",
+        "O(1). The amortized time complexity is O(1) assuming the appended value is small and the already present value is of any size, since the dynamic string library used by Redis will double the free space available on every reallocation.",
+        &[key("key"), string("value")],
+    ),
+    doc(
+        "strlen",
+        "Returns the length of a string value.",
+        "2.2.0",
+        "string",
+        O1,
+        &[key("key")],
+    ),
+    // Bitmaps
+    doc(
+        "bitcount",
+        "Counts the number of set bits (population counting) in a string.",
+        "2.6.0",
+        "bitmap",
+        "O(N)",
+        &[
+            key("key"),
+            block(
+                "range",
+                &[
+                    integer("start"),
+                    integer("end"),
+                    oneof("unit", &[token("byte", "BYTE"), token("bit", "BIT")]).optional(),
+                ],
+            )
+            .optional(),
+        ],
+    ),
+    // Keyspace
+    doc(
+        "del",
+        "Deletes one or more keys.",
+        "1.0.0",
+        "generic",
+        "O(N) where N is the number of keys that will be removed.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "exists",
+        "Determines whether one or more keys exist.",
+        "1.0.0",
+        "generic",
+        "O(N) where N is the number of keys to check.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "touch",
+        "Returns the number of existing keys out of those specified after updating the time they were last accessed.",
+        "3.2.1",
+        "generic",
+        "O(N) where N is the number of keys that will be touched.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "expire",
+        "Sets the expiration time of a key in seconds.",
+        "1.0.0",
+        "generic",
+        O1,
+        &[key("key"), integer("seconds"), EXPIRE_CONDITION],
+    ),
+    doc(
+        "pexpire",
+        "Sets the expiration time of a key in milliseconds.",
+        "2.6.0",
+        "generic",
+        O1,
+        &[key("key"), integer("milliseconds"), EXPIRE_CONDITION],
+    ),
+    doc(
+        "expireat",
+        "Sets the expiration time of a key to a Unix timestamp.",
+        "1.2.0",
+        "generic",
+        O1,
+        &[
+            key("key"),
+            ArgDoc::new("unix-time-seconds", "unix-time"),
+            EXPIRE_CONDITION,
+        ],
+    ),
+    doc(
+        "pexpireat",
+        "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+        "2.6.0",
+        "generic",
+        O1,
+        &[
+            key("key"),
+            ArgDoc::new("unix-time-milliseconds", "unix-time"),
+            EXPIRE_CONDITION,
+        ],
+    ),
+    doc(
+        "ttl",
+        "Returns the expiration time in seconds of a key.",
+        "1.0.0",
+        "generic",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "pttl",
+        "Returns the expiration time in milliseconds of a key.",
+        "2.6.0",
+        "generic",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "persist",
+        "Removes the expiration time of a key.",
+        "2.2.0",
+        "generic",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "keys",
+        "Returns all key names that match a pattern.",
+        "1.0.0",
+        "generic",
+        "O(N) with N being the number of keys in the database, under the assumption that the key names in the database and the given pattern have limited length.",
+        &[pattern("pattern")],
+    ),
+    doc(
+        "scan",
+        "Iterates over the key names in the database.",
+        "2.8.0",
+        "generic",
+        "O(1) for every call. O(N) for a complete iteration, including enough command calls for the cursor to return back to 0. N is the number of elements inside the collection.",
+        &[
+            integer("cursor"),
+            pattern("pattern").token("MATCH").optional(),
+            integer("count").token("COUNT").optional(),
+            string("type").token("TYPE").optional(),
+        ],
+    ),
+    doc(
+        "dump",
+        "Returns a serialized representation of the value stored at a key.",
+        "2.6.0",
+        "generic",
+        "O(1) to access the key and additional O(N*M) to serialize it, where N is the number of Redis objects composing the value and M their average size. For small string values the time complexity is thus O(1)+O(1*M) where M is small, so simply O(1).",
+        &[key("key")],
+    ),
+    doc(
+        "restore",
+        "Creates a key from the serialized representation of a value.",
+        "2.6.0",
+        "generic",
+        "O(1) to create the new key and additional O(N*M) to reconstruct the serialized value, where N is the number of Redis objects composing the value and M their average size. For small string values the time complexity is thus O(1)+O(1*M) where M is small, so simply O(1). However for sorted set values the complexity is O(N*M*log(N)) because inserting values into sorted sets is O(log(N)).",
+        &[
+            key("key"),
+            integer("ttl"),
+            string("serialized-value"),
+            token("replace", "REPLACE").optional(),
+            token("absttl", "ABSTTL").optional(),
+            integer("seconds").token("IDLETIME").optional(),
+            integer("frequency").token("FREQ").optional(),
+        ],
+    ),
+    doc(
+        "type",
+        "Determines the type of value stored at a key.",
+        "1.0.0",
+        "generic",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "object",
+        "A container for object introspection commands.",
+        "2.2.3",
+        "generic",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "rename",
+        "Renames a key and overwrites the destination.",
+        "1.0.0",
+        "generic",
+        O1,
+        &[key("key"), key("newkey")],
+    ),
+    doc(
+        "copy",
+        "Copies the value of a key to a new key.",
+        "6.2.0",
+        "generic",
+        "O(N) worst case for collections, where N is the number of nested items. O(1) for string values.",
+        &[
+            key("source"),
+            key("destination"),
+            integer("destination-db").token("DB").optional(),
+            token("replace", "REPLACE").optional(),
+        ],
+    ),
+    doc(
+        "move",
+        "Moves a key to another database.",
+        "1.0.0",
+        "generic",
+        O1,
+        &[key("key"), integer("db")],
+    ),
+    doc(
+        "renamenx",
+        "Renames a key only when the target key name doesn't exist.",
+        "1.0.0",
+        "generic",
+        O1,
+        &[key("key"), key("newkey")],
+    ),
+    doc(
+        "flushdb",
+        "Remove all keys from the current database.",
+        "1.0.0",
+        "server",
+        "O(N) where N is the number of keys in the selected database",
+        &[],
+    ),
+    doc(
+        "flushall",
+        "Removes all keys from all databases.",
+        "1.0.0",
+        "server",
+        "O(N) where N is the total number of keys in all databases",
+        &[],
+    ),
+    doc(
+        "select",
+        "Changes the selected database.",
+        "1.0.0",
+        "connection",
+        O1,
+        &[integer("index")],
+    ),
+    doc(
+        "swapdb",
+        "Swaps two Redis databases.",
+        "4.0.0",
+        "server",
+        "O(N) where N is the count of clients watching or blocking on keys from both databases.",
+        &[integer("index1"), integer("index2")],
+    ),
+    // Transactions
+    doc(
+        "multi",
+        "Starts a transaction.",
+        "1.2.0",
+        "transactions",
+        O1,
+        &[],
+    ),
+    doc(
+        "exec",
+        "Executes all commands in a transaction.",
+        "1.2.0",
+        "transactions",
+        "Depends on commands in the transaction",
+        &[],
+    ),
+    doc(
+        "discard",
+        "Discards a transaction.",
+        "2.0.0",
+        "transactions",
+        "O(N), when N is the number of queued commands",
+        &[],
+    ),
+    doc(
+        "watch",
+        "Monitors changes to keys to determine the execution of a transaction.",
+        "2.2.0",
+        "transactions",
+        "O(1) for every key.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "unwatch",
+        "Forgets about watched keys of a transaction.",
+        "2.2.0",
+        "transactions",
+        O1,
+        &[],
+    ),
+    // Lists
+    doc(
+        "lpush",
+        "Prepends one or more elements to a list. Creates the key if it doesn't exist.",
+        "1.0.0",
+        "list",
+        "O(1) for each element added, so O(N) to add N elements when the command is called with multiple arguments.",
+        &[key("key"), string("element").multiple()],
+    ),
+    doc(
+        "rpush",
+        "Appends one or more elements to a list. Creates the key if it doesn't exist.",
+        "1.0.0",
+        "list",
+        "O(1) for each element added, so O(N) to add N elements when the command is called with multiple arguments.",
+        &[key("key"), string("element").multiple()],
+    ),
+    doc(
+        "lpop",
+        "Returns the first elements in a list after removing it. Deletes the list if the last element was popped.",
+        "1.0.0",
+        "list",
+        "O(N) where N is the number of elements returned",
+        &[key("key"), integer("count").optional()],
+    ),
+    doc(
+        "rpop",
+        "Returns and removes the last elements of a list. Deletes the list if the last element was popped.",
+        "1.0.0",
+        "list",
+        "O(N) where N is the number of elements returned",
+        &[key("key"), integer("count").optional()],
+    ),
+    doc(
+        "lmpop",
+        "Returns multiple elements from a list after removing them. Deletes the list if the last element was popped.",
+        "7.0.0",
+        "list",
+        "O(N+M) where N is the number of provided keys and M is the number of elements returned.",
+        &[
+            integer("numkeys"),
+            key("key").multiple(),
+            LIST_WHERE,
+            integer("count").token("COUNT").optional(),
+        ],
+    ),
+    doc(
+        "blpop",
+        "Removes and returns the first element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+        "2.0.0",
+        "list",
+        "O(N) where N is the number of provided keys.",
+        &[key("key").multiple(), double("timeout")],
+    ),
+    doc(
+        "blmpop",
+        "Pops the first element from one of multiple lists. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+        "7.0.0",
+        "list",
+        "O(N+M) where N is the number of provided keys and M is the number of elements returned.",
+        &[
+            double("timeout"),
+            integer("numkeys"),
+            key("key").multiple(),
+            LIST_WHERE,
+            integer("count").token("COUNT").optional(),
+        ],
+    ),
+    doc(
+        "brpop",
+        "Removes and returns the last element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+        "2.0.0",
+        "list",
+        "O(N) where N is the number of provided keys.",
+        &[key("key").multiple(), double("timeout")],
+    ),
+    doc(
+        "llen",
+        "Returns the length of a list.",
+        "1.0.0",
+        "list",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "lrange",
+        "Returns a range of elements from a list.",
+        "1.0.0",
+        "list",
+        "O(S+N) where S is the distance of start offset from HEAD for small lists, from nearest end (HEAD or TAIL) for large lists; and N is the number of elements in the specified range.",
+        &[key("key"), integer("start"), integer("stop")],
+    ),
+    doc(
+        "lindex",
+        "Returns an element from a list by its index.",
+        "1.0.0",
+        "list",
+        "O(N) where N is the number of elements to traverse to get to the element at index. This makes asking for the first or the last element of the list O(1).",
+        &[key("key"), integer("index")],
+    ),
+    doc(
+        "lset",
+        "Sets the value of an element in a list by its index.",
+        "1.0.0",
+        "list",
+        "O(N) where N is the length of the list. Setting either the first or the last element of the list is O(1).",
+        &[key("key"), integer("index"), string("element")],
+    ),
+    doc(
+        "ltrim",
+        "Removes elements from both ends a list. Deletes the list if all elements were trimmed.",
+        "1.0.0",
+        "list",
+        "O(N) where N is the number of elements to be removed by the operation.",
+        &[key("key"), integer("start"), integer("stop")],
+    ),
+    // Sets
+    doc(
+        "sadd",
+        "Adds one or more members to a set. Creates the key if it doesn't exist.",
+        "1.0.0",
+        "set",
+        "O(1) for each element added, so O(N) to add N elements when the command is called with multiple arguments.",
+        &[key("key"), string("member").multiple()],
+    ),
+    doc(
+        "srem",
+        "Removes one or more members from a set. Deletes the set if the last member was removed.",
+        "1.0.0",
+        "set",
+        "O(N) where N is the number of members to be removed.",
+        &[key("key"), string("member").multiple()],
+    ),
+    doc(
+        "smembers",
+        "Returns all members of a set.",
+        "1.0.0",
+        "set",
+        "O(N) where N is the set cardinality.",
+        &[key("key")],
+    ),
+    doc(
+        "sismember",
+        "Determines whether a member belongs to a set.",
+        "1.0.0",
+        "set",
+        O1,
+        &[key("key"), string("member")],
+    ),
+    doc(
+        "scard",
+        "Returns the number of members in a set.",
+        "1.0.0",
+        "set",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "sinter",
+        "Returns the intersect of multiple sets.",
+        "1.0.0",
+        "set",
+        "O(N*M) worst case where N is the cardinality of the smallest set and M is the number of sets.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "sunion",
+        "Returns the union of multiple sets.",
+        "1.0.0",
+        "set",
+        "O(N) where N is the total number of elements in all given sets.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "sdiff",
+        "Returns the difference of multiple sets.",
+        "1.0.0",
+        "set",
+        "O(N) where N is the total number of elements in all given sets.",
+        &[key("key").multiple()],
+    ),
+    doc(
+        "sinterstore",
+        "Stores the intersect of multiple sets in a key.",
+        "1.0.0",
+        "set",
+        "O(N*M) worst case where N is the cardinality of the smallest set and M is the number of sets.",
+        &[key("destination"), key("key").multiple()],
+    ),
+    doc(
+        "sunionstore",
+        "Stores the union of multiple sets in a key.",
+        "1.0.0",
+        "set",
+        "O(N) where N is the total number of elements in all given sets.",
+        &[key("destination"), key("key").multiple()],
+    ),
+    doc(
+        "sdiffstore",
+        "Stores the difference of multiple sets in a key.",
+        "1.0.0",
+        "set",
+        "O(N) where N is the total number of elements in all given sets.",
+        &[key("destination"), key("key").multiple()],
+    ),
+    doc(
+        "srandmember",
+        "Get one or multiple random members from a set",
+        "1.0.0",
+        "set",
+        "Without the count argument O(1), otherwise O(N) where N is the absolute value of the passed count.",
+        &[key("key"), integer("count").optional()],
+    ),
+    // Hashes
+    doc(
+        "hset",
+        "Creates or modifies the value of a field in a hash.",
+        "2.0.0",
+        "hash",
+        "O(1) for each field/value pair added, so O(N) to add N field/value pairs when the command is called with multiple field/value pairs.",
+        &[
+            key("key"),
+            block("data", &[string("field"), string("value")]).multiple(),
+        ],
+    ),
+    doc(
+        "hget",
+        "Returns the value of a field in a hash.",
+        "2.0.0",
+        "hash",
+        O1,
+        &[key("key"), string("field")],
+    ),
+    doc(
+        "hmset",
+        "Sets the values of multiple fields.",
+        "2.0.0",
+        "hash",
+        "O(N) where N is the number of fields being set.",
+        &[
+            key("key"),
+            block("data", &[string("field"), string("value")]).multiple(),
+        ],
+    ),
+    doc(
+        "hmget",
+        "Returns the values of all fields in a hash.",
+        "2.0.0",
+        "hash",
+        "O(N) where N is the number of fields being requested.",
+        &[key("key"), string("field").multiple()],
+    ),
+    doc(
+        "hgetall",
+        "Returns all fields and values in a hash.",
+        "2.0.0",
+        "hash",
+        "O(N) where N is the size of the hash.",
+        &[key("key")],
+    ),
+    doc(
+        "hdel",
+        "Deletes one or more fields and their values from a hash. Deletes the hash if no fields remain.",
+        "2.0.0",
+        "hash",
+        "O(N) where N is the number of fields to be removed.",
+        &[key("key"), string("field").multiple()],
+    ),
+    doc(
+        "hexists",
+        "Determines whether a field exists in a hash.",
+        "2.0.0",
+        "hash",
+        O1,
+        &[key("key"), string("field")],
+    ),
+    doc(
+        "hlen",
+        "Returns the number of fields in a hash.",
+        "2.0.0",
+        "hash",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "hkeys",
+        "Returns all fields in a hash.",
+        "2.0.0",
+        "hash",
+        "O(N) where N is the size of the hash.",
+        &[key("key")],
+    ),
+    doc(
+        "hvals",
+        "Returns all values in a hash.",
+        "2.0.0",
+        "hash",
+        "O(N) where N is the size of the hash.",
+        &[key("key")],
+    ),
+    doc(
+        "hincrby",
+        "Increments the integer value of a field in a hash by a number. Uses 0 as initial value if the field doesn't exist.",
+        "2.0.0",
+        "hash",
+        O1,
+        &[key("key"), string("field"), integer("increment")],
+    ),
+    // Sorted sets
+    doc(
+        "zadd",
+        "Adds one or more members to a sorted set, or updates their scores. Creates the key if it doesn't exist.",
+        "1.2.0",
+        "sorted-set",
+        "O(log(N)) for each item added, where N is the number of elements in the sorted set.",
+        &[
+            key("key"),
+            oneof("condition", &[token("nx", "NX"), token("xx", "XX")]).optional(),
+            oneof("comparison", &[token("gt", "GT"), token("lt", "LT")]).optional(),
+            token("change", "CH").optional(),
+            token("increment", "INCR").optional(),
+            block("data", &[double("score"), string("member")]).multiple(),
+        ],
+    ),
+    doc(
+        "zincrby",
+        "Increments the score of a member in a sorted set.",
+        "1.2.0",
+        "sorted-set",
+        "O(log(N)) where N is the number of elements in the sorted set.",
+        &[key("key"), integer("increment"), string("member")],
+    ),
+    doc(
+        "zscore",
+        "Returns the score of a member in a sorted set.",
+        "1.2.0",
+        "sorted-set",
+        O1,
+        &[key("key"), string("member")],
+    ),
+    doc(
+        "zmscore",
+        "Returns the score of one or more members in a sorted set.",
+        "6.2.0",
+        "sorted-set",
+        "O(N) where N is the number of members being requested.",
+        &[key("key"), string("member").multiple()],
+    ),
+    doc(
+        "zrem",
+        "Removes one or more members from a sorted set. Deletes the sorted set if all members were removed.",
+        "1.2.0",
+        "sorted-set",
+        "O(M*log(N)) with N being the number of elements in the sorted set and M the number of elements to be removed.",
+        &[key("key"), string("member").multiple()],
+    ),
+    doc(
+        "zcard",
+        "Returns the number of members in a sorted set.",
+        "1.2.0",
+        "sorted-set",
+        O1,
+        &[key("key")],
+    ),
+    doc(
+        "zcount",
+        "Returns the count of members in a sorted set that have scores within a range.",
+        "2.0.0",
+        "sorted-set",
+        "O(log(N)) with N being the number of elements in the sorted set.",
+        &[key("key"), double("min"), double("max")],
+    ),
+    doc(
+        "zlexcount",
+        "Returns the number of members in a sorted set within a lexicographical range.",
+        "2.8.9",
+        "sorted-set",
+        "O(log(N)) with N being the number of elements in the sorted set.",
+        &[key("key"), string("min"), string("max")],
+    ),
+    doc(
+        "zpopmin",
+        "Returns the lowest-scoring members from a sorted set after removing them. Deletes the sorted set if the last member was popped.",
+        "5.0.0",
+        "sorted-set",
+        "O(log(N)*M) with N being the number of elements in the sorted set, and M being the number of elements popped.",
+        &[key("key"), integer("count").optional()],
+    ),
+    doc(
+        "zpopmax",
+        "Returns the highest-scoring members from a sorted set after removing them. Deletes the sorted set if the last member was popped.",
+        "5.0.0",
+        "sorted-set",
+        "O(log(N)*M) with N being the number of elements in the sorted set, and M being the number of elements popped.",
+        &[key("key"), integer("count").optional()],
+    ),
+    doc(
+        "zmpop",
+        "Returns the highest- or lowest-scoring members from one or more sorted sets after removing them. Deletes the sorted set if the last member was popped.",
+        "7.0.0",
+        "sorted-set",
+        "O(K) + O(M*log(N)) where K is the number of provided keys, N being the number of elements in the sorted set, and M being the number of elements popped.",
+        &[
+            integer("numkeys"),
+            key("key").multiple(),
+            ZSET_WHERE,
+            integer("count").token("COUNT").optional(),
+        ],
+    ),
+    doc(
+        "bzpopmin",
+        "Removes and returns the member with the lowest score from one or more sorted sets. Blocks until a member is available otherwise. Deletes the sorted set if the last element was popped.",
+        "5.0.0",
+        "sorted-set",
+        "O(log(N)) with N being the number of elements in the sorted set.",
+        &[key("key").multiple(), double("timeout")],
+    ),
+    doc(
+        "bzpopmax",
+        "Removes and returns the member with the highest score from one or more sorted sets. Blocks until a member is available otherwise. Deletes the sorted set if the last element was popped.",
+        "5.0.0",
+        "sorted-set",
+        "O(log(N)) with N being the number of elements in the sorted set.",
+        &[key("key").multiple(), double("timeout")],
+    ),
+    doc(
+        "bzmpop",
+        "Removes and returns a member by score from one or more sorted sets. Blocks until a member is available otherwise. Deletes the sorted set if the last element was popped.",
+        "7.0.0",
+        "sorted-set",
+        "O(K) + O(M*log(N)) where K is the number of provided keys, N being the number of elements in the sorted set, and M being the number of elements popped.",
+        &[
+            double("timeout"),
+            integer("numkeys"),
+            key("key").multiple(),
+            ZSET_WHERE,
+            integer("count").token("COUNT").optional(),
+        ],
+    ),
+    doc(
+        "zrank",
+        "Returns the index of a member in a sorted set ordered by ascending scores.",
+        "2.0.0",
+        "sorted-set",
+        "O(log(N))",
+        &[
+            key("key"),
+            string("member"),
+            token("withscore", "WITHSCORE").optional(),
+        ],
+    ),
+    doc(
+        "zrevrank",
+        "Returns the index of a member in a sorted set ordered by descending scores.",
+        "2.0.0",
+        "sorted-set",
+        "O(log(N))",
+        &[
+            key("key"),
+            string("member"),
+            token("withscore", "WITHSCORE").optional(),
+        ],
+    ),
+    doc(
+        "zrange",
+        "Returns members in a sorted set within a range of indexes, scores or members.",
+        "1.2.0",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements returned.",
+        &[
+            key("key"),
+            string("start"),
+            string("stop"),
+            ZRANGE_BY,
+            token("rev", "REV").optional(),
+            ZRANGE_LIMIT,
+            token("withscores", "WITHSCORES").optional(),
+        ],
+    ),
+    doc(
+        "zrangestore",
+        "Stores a range of members from sorted set in a key.",
+        "6.2.0",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements stored into the destination key.",
+        &[
+            key("dst"),
+            key("src"),
+            string("min"),
+            string("max"),
+            ZRANGE_BY,
+            token("rev", "REV").optional(),
+            ZRANGE_LIMIT,
+        ],
+    ),
+    doc(
+        "zrevrange",
+        "Returns members in a sorted set within a range of indexes in reverse order.",
+        "1.2.0",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements returned.",
+        &[
+            key("key"),
+            integer("start"),
+            integer("stop"),
+            token("withscores", "WITHSCORES").optional(),
+        ],
+    ),
+    doc(
+        "zrangebyscore",
+        "Returns members in a sorted set within a range of scores.",
+        "1.0.5",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements being returned.",
+        &[
+            key("key"),
+            double("min"),
+            double("max"),
+            token("withscores", "WITHSCORES").optional(),
+            ZRANGE_LIMIT,
+        ],
+    ),
+    doc(
+        "zrevrangebyscore",
+        "Returns members in a sorted set within a range of scores in reverse order.",
+        "2.2.0",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements being returned.",
+        &[
+            key("key"),
+            double("max"),
+            double("min"),
+            token("withscores", "WITHSCORES").optional(),
+            ZRANGE_LIMIT,
+        ],
+    ),
+    doc(
+        "zrangebylex",
+        "Returns members in a sorted set within a lexicographical range.",
+        "2.8.9",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements being returned.",
+        &[key("key"), string("min"), string("max"), ZRANGE_LIMIT],
+    ),
+    doc(
+        "zrevrangebylex",
+        "Returns members in a sorted set within a lexicographical range in reverse order.",
+        "2.8.9",
+        "sorted-set",
+        "O(log(N)+M) with N being the number of elements in the sorted set and M the number of elements being returned.",
+        &[key("key"), string("max"), string("min"), ZRANGE_LIMIT],
+    ),
+    doc(
+        "zunion",
+        "Returns the union of multiple sorted sets.",
+        "6.2.0",
+        "sorted-set",
+        "O(N)+O(M*log(M)) with N being the sum of the sizes of the input sorted sets, and M being the number of elements in the resulting sorted set.",
+        &[
+            integer("numkeys"),
+            key("key").multiple(),
+            ZSET_WEIGHTS,
+            ZSET_AGGREGATE,
+            token("withscores", "WITHSCORES").optional(),
+        ],
+    ),
+    doc(
+        "zinter",
+        "Returns the intersect of multiple sorted sets.",
+        "6.2.0",
+        "sorted-set",
+        "O(N*K)+O(M*log(M)) worst case with N being the smallest input sorted set, K being the number of input sorted sets and M being the number of elements in the resulting sorted set.",
+        &[
+            integer("numkeys"),
+            key("key").multiple(),
+            ZSET_WEIGHTS,
+            ZSET_AGGREGATE,
+            token("withscores", "WITHSCORES").optional(),
+        ],
+    ),
+    doc(
+        "zdiff",
+        "Returns the difference between multiple sorted sets.",
+        "6.2.0",
+        "sorted-set",
+        "O(L + (N-K)log(N)) worst case where L is the total number of elements in all the sets, N is the size of the first set, and K is the size of the result set.",
+        &[
+            integer("numkeys"),
+            key("key").multiple(),
+            token("withscores", "WITHSCORES").optional(),
+        ],
+    ),
+    doc(
+        "zunionstore",
+        "Stores the union of multiple sorted sets in a key.",
+        "2.0.0",
+        "sorted-set",
+        "O(N)+O(M log(M)) with N being the sum of the sizes of the input sorted sets, and M being the number of elements in the resulting sorted set.",
+        &[
+            key("destination"),
+            integer("numkeys"),
+            key("key").multiple(),
+            ZSET_WEIGHTS,
+            ZSET_AGGREGATE,
+        ],
+    ),
+    doc(
+        "zinterstore",
+        "Stores the intersect of multiple sorted sets in a key.",
+        "2.0.0",
+        "sorted-set",
+        "O(N*K)+O(M*log(M)) worst case with N being the smallest input sorted set, K being the number of input sorted sets and M being the number of elements in the resulting sorted set.",
+        &[
+            key("destination"),
+            integer("numkeys"),
+            key("key").multiple(),
+            ZSET_WEIGHTS,
+            ZSET_AGGREGATE,
+        ],
+    ),
+    doc(
+        "zdiffstore",
+        "Stores the difference of multiple sorted sets in a key.",
+        "6.2.0",
+        "sorted-set",
+        "O(L + (N-K)log(N)) worst case where L is the total number of elements in all the sets, N is the size of the first set, and K is the size of the result set.",
+        &[
+            key("destination"),
+            integer("numkeys"),
+            key("key").multiple(),
+        ],
+    ),
+    doc(
+        "geoadd",
+        "Adds one or more members to a geospatial index. The key is created if it doesn't exist.",
+        "3.2.0",
+        "geo",
+        "O(log(N)) for each item added, where N is the number of elements in the sorted set.",
+        &[
+            key("key"),
+            oneof("condition", &[token("nx", "NX"), token("xx", "XX")]).optional(),
+            token("change", "CH").optional(),
+            block(
+                "data",
+                &[double("longitude"), double("latitude"), string("member")],
+            )
+            .multiple(),
+        ],
+    ),
+    doc(
+        "geopos",
+        "Returns the longitude and latitude of members from a geospatial index.",
+        "3.2.0",
+        "geo",
+        "O(1) for each member requested.",
+        &[key("key"), string("member").optional().multiple()],
+    ),
+    doc(
+        "geodist",
+        "Returns the distance between two members of a geospatial index.",
+        "3.2.0",
+        "geo",
+        "O(1)",
+        &[
+            key("key"),
+            string("member1"),
+            string("member2"),
+            GEO_UNIT.optional(),
+        ],
+    ),
+    doc(
+        "geosearch",
+        "Queries a geospatial index for members inside an area of a box or a circle.",
+        "6.2.0",
+        "geo",
+        "O(N+log(M)) where N is the number of elements in the grid-aligned bounding box area around the shape provided as the filter and M is the number of items inside the shape",
+        &[
+            key("key"),
+            GEO_FROM,
+            GEO_BY,
+            GEO_ORDER,
+            GEO_COUNT,
+            token("withcoord", "WITHCOORD").optional(),
+            token("withdist", "WITHDIST").optional(),
+            token("withhash", "WITHHASH").optional(),
+        ],
+    ),
+    doc(
+        "geosearchstore",
+        "Queries a geospatial index for members inside an area of a box or a circle, optionally stores the result.",
+        "6.2.0",
+        "geo",
+        "O(N+log(M)) where N is the number of elements in the grid-aligned bounding box area around the shape provided as the filter and M is the number of items inside the shape",
+        &[
+            key("destination"),
+            key("source"),
+            GEO_FROM,
+            GEO_BY,
+            GEO_ORDER,
+            GEO_COUNT,
+            token("storedist", "STOREDIST").optional(),
+        ],
+    ),
+    doc(
+        "xack",
+        "Returns the number of messages that were successfully acknowledged by the consumer group member of a stream.",
+        "5.0.0",
+        "stream",
+        "O(1) for each message ID processed.",
+        &[key("key"), string("group"), string("id").multiple()],
+    ),
+    doc(
+        "xadd",
+        "Appends a new message to a stream. Creates the key if it doesn't exist.",
+        "5.0.0",
+        "stream",
+        "O(1) when adding a new entry.",
+        &[
+            key("key"),
+            token("nomkstream", "NOMKSTREAM").optional(),
+            STREAM_TRIM.optional(),
+            oneof("id-selector", &[token("auto-id", "*"), string("id")]),
+            block("data", &[string("field"), string("value")]).multiple(),
+        ],
+    ),
+    doc(
+        "xautoclaim",
+        "Changes, or acquires, ownership of messages in a consumer group, as if the messages were delivered to as consumer group member.",
+        "6.2.0",
+        "stream",
+        "O(1) if COUNT is small.",
+        &[
+            key("key"),
+            string("group"),
+            string("consumer"),
+            string("min-idle-time"),
+            string("start"),
+            integer("count").token("COUNT").optional(),
+            token("justid", "JUSTID").optional(),
+        ],
+    ),
+    doc(
+        "xclaim",
+        "Changes, or acquires, ownership of a message in a consumer group, as if the message was delivered a consumer group member.",
+        "5.0.0",
+        "stream",
+        "O(log N) with N being the number of messages in the PEL of the consumer group.",
+        &[
+            key("key"),
+            string("group"),
+            string("consumer"),
+            string("min-idle-time"),
+            string("id").multiple(),
+            integer("ms").token("IDLE").optional(),
+            integer("unix-time-milliseconds").token("TIME").optional(),
+            integer("count").token("RETRYCOUNT").optional(),
+            token("force", "FORCE").optional(),
+            token("justid", "JUSTID").optional(),
+            string("lastid").token("LASTID").optional(),
+        ],
+    ),
+    doc(
+        "xgroup",
+        "A container for consumer groups commands.",
+        "5.0.0",
+        "stream",
+        "Depends on subcommand.",
+        &[],
+    ),
+    doc(
+        "xlen",
+        "Return the number of messages in a stream.",
+        "5.0.0",
+        "stream",
+        "O(1)",
+        &[key("key")],
+    ),
+    doc(
+        "xpending",
+        "Returns the information and entries from a stream consumer group's pending entries list.",
+        "5.0.0",
+        "stream",
+        "O(N) with N being the number of elements returned, so asking for a small fixed number of entries per call is O(1). O(M), where M is the total number of entries scanned when used with the IDLE filter. When the command returns just the summary and the list of consumers is small, it runs in O(1) time; otherwise, an additional O(N) time for iterating every consumer.",
+        &[
+            key("key"),
+            string("group"),
+            block(
+                "filters",
+                &[
+                    integer("min-idle-time").token("IDLE").optional(),
+                    string("start"),
+                    string("end"),
+                    integer("count"),
+                    string("consumer").optional(),
+                ],
+            )
+            .optional(),
+        ],
+    ),
+    doc(
+        "xrange",
+        "Returns the messages from a stream within a range of IDs.",
+        "5.0.0",
+        "stream",
+        "O(N) with N being the number of elements being returned. If N is constant (e.g. always asking for the first 10 elements with COUNT), you can consider it O(1).",
+        &[
+            key("key"),
+            string("start"),
+            string("end"),
+            integer("count").token("COUNT").optional(),
+        ],
+    ),
+    doc(
+        "xread",
+        "Returns messages from multiple streams with IDs greater than the ones requested. Blocks until a message is available otherwise.",
+        "5.0.0",
+        "stream",
+        "",
+        &[
+            integer("count").token("COUNT").optional(),
+            integer("milliseconds").token("BLOCK").optional(),
+            block("streams", &[key("key").multiple(), string("id").multiple()]).token("STREAMS"),
+        ],
+    ),
+    doc(
+        "xreadgroup",
+        "Returns new or historical messages from a stream for a consumer in a group. Blocks until a message is available otherwise.",
+        "5.0.0",
+        "stream",
+        "For each stream mentioned: O(M) with M being the number of elements returned. If M is constant (e.g. always asking for the first 10 elements with COUNT), you can consider it O(1). On the other side when XREADGROUP blocks, XADD will pay the O(N) time in order to serve the N clients blocked on the stream getting new data.",
+        &[
+            block("group-block", &[string("group"), string("consumer")]).token("GROUP"),
+            integer("count").token("COUNT").optional(),
+            integer("milliseconds").token("BLOCK").optional(),
+            token("noack", "NOACK").optional(),
+            block("streams", &[key("key").multiple(), string("id").multiple()]).token("STREAMS"),
+        ],
+    ),
+    doc(
+        "xrevrange",
+        "Returns the messages from a stream within a range of IDs in reverse order.",
+        "5.0.0",
+        "stream",
+        "O(N) with N being the number of elements returned. If N is constant (e.g. always asking for the first 10 elements with COUNT), you can consider it O(1).",
+        &[
+            key("key"),
+            string("end"),
+            string("start"),
+            integer("count").token("COUNT").optional(),
+        ],
+    ),
+    doc(
+        "xtrim",
+        "Deletes messages from the beginning of a stream.",
+        "5.0.0",
+        "stream",
+        "O(N), with N being the number of evicted entries. Constant times are very small however, since entries are organized in macro nodes containing multiple entries that can be released with a single deallocation.",
+        &[key("key"), STREAM_TRIM],
+    ),
+    doc(
+        "bf.add",
+        "Adds an item to a Bloom Filter",
+        "1.0.0",
+        "bf",
+        "O(k), where k is the number of hash functions used by the last sub-filter",
+        &[key("key"), string("item")],
+    ),
+    doc(
+        "bf.exists",
+        "Checks whether an item exists in a Bloom Filter",
+        "1.0.0",
+        "bf",
+        "O(k), where k is the number of hash functions used by the last sub-filter",
+        &[key("key"), string("item")],
+    ),
+    doc(
+        "bf.madd",
+        "Adds one or more items to a Bloom Filter. A filter will be created if it does not exist",
+        "1.0.0",
+        "bf",
+        "O(k * n), where k is the number of hash functions and n is the number of items",
+        &[key("key"), string("item").multiple()],
+    ),
+    doc(
+        "bf.mexists",
+        "Checks whether one or more items exist in a Bloom Filter",
+        "1.0.0",
+        "bf",
+        "O(k * n), where k is the number of hash functions and n is the number of items",
+        &[key("key"), string("item").multiple()],
+    ),
+    doc(
+        "bf.reserve",
+        "Creates a new Bloom Filter",
+        "1.0.0",
+        "bf",
+        "O(1)",
+        &[
+            key("key"),
+            double("error_rate"),
+            integer("capacity"),
+            integer("expansion").token("EXPANSION").optional(),
+            token("nonscaling", "NONSCALING").optional(),
+        ],
+    ),
+];
+
+/// Finds a command's documentation by name, in any case.
+pub fn find(name: &str) -> Option<&'static CommandDoc> {
+    COMMAND_DOCS
+        .iter()
+        .find(|doc| doc.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::COMMAND_TABLE;
+
+    #[test]
+    fn test_every_command_is_documented() {
+        for spec in COMMAND_TABLE {
+            assert!(find(spec.name).is_some(), "{} has no docs", spec.name);
+        }
+        assert_eq!(COMMAND_DOCS.len(), COMMAND_TABLE.len());
+    }
+
+    #[test]
+    fn test_argument_rendering() {
+        let bulk = |s: &str| Resp::Bulk(Some(s.to_string()));
+        let (name, fields) = find("set").unwrap().to_resp();
+        assert_eq!(name, bulk("set"));
+        let Resp::Map(fields) = fields else {
+            panic!("expected a map");
+        };
+        assert_eq!(fields[4].0, bulk("arguments"));
+        let Resp::Array(Some(args)) = &fields[4].1 else {
+            panic!("expected an array");
+        };
+        assert_eq!(
+            args[3],
+            Resp::Map(vec![
+                (bulk("name"), bulk("get")),
+                (bulk("type"), bulk("pure-token")),
+                (bulk("token"), bulk("GET")),
+                (
+                    bulk("flags"),
+                    Resp::Array(Some(vec![Resp::Simple("optional".to_string())])),
+                ),
+            ])
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Debug;
+
+use indexmap::IndexMap;
+
+use crate::storage::Entry;
+
+/// Where a database keeps its entries, selected with `storage-engine`.
+///
+/// `Db` implements every command on top of these few primitives, so an
+/// engine only has to store, find and enumerate entries. Expiration, type
+/// checks and locking stay in `Db`.
+///
+/// Eviction samples keys with `get_index`, which by default walks the
+/// entries; engines that can reach one by position should override it.
+/// SCAN walks positions too, from the last down, so an engine should only
+/// move an entry to a lower position: add at the end, and fill a removed
+/// entry's place with the last one or by shifting the rest down.
+pub trait StorageEngine: Send + Sync + Debug {
+    fn get(&self, key: &str) -> Option<&Entry>;
+    fn get_mut(&mut self, key: &str) -> Option<&mut Entry>;
+    fn insert(&mut self, key: String, entry: Entry) -> Option<Entry>;
+    fn remove(&mut self, key: &str) -> Option<Entry>;
+    /// Number of entries, expired ones included.
+    fn len(&self) -> usize;
+    fn clear(&mut self);
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Entry)> + '_>;
+    /// Keeps only the entries for which `keep` returns true.
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, &Entry) -> bool);
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entry at `index` in `0..len()`, in no particular order, so
+    /// random indexes give random entries.
+    fn get_index(&self, index: usize) -> Option<(&String, &Entry)> {
+        self.iter().nth(index)
+    }
+
+    /// The entry under `key`, inserting `default()` first if there is none.
+    fn get_or_insert_with(&mut self, key: &str, default: &mut dyn FnMut() -> Entry) -> &mut Entry {
+        if self.get(key).is_none() {
+            self.insert(key.to_string(), default());
+        }
+        self.get_mut(key).unwrap()
+    }
+}
+
+/// The default engine: a hash map per database, entirely in memory.
+/// Entries are kept densely in an `IndexMap`, whose hash table only holds
+/// their positions, so any of them can be sampled by position without a
+/// second copy of every key.
+#[derive(Debug, Default)]
+pub struct MemoryEngine {
+    entries: IndexMap<String, Entry>,
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.entries.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        self.entries.insert(key, entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        // The last entry takes the removed one's place
+        self.entries.swap_remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Entry)> + '_> {
+        Box::new(self.entries.iter())
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, &Entry) -> bool) {
+        self.entries.retain(|key, entry| keep(key, entry));
+    }
+
+    fn get_index(&self, index: usize) -> Option<(&String, &Entry)> {
+        self.entries.get_index(index)
+    }
+
+    fn get_or_insert_with(&mut self, key: &str, default: &mut dyn FnMut() -> Entry) -> &mut Entry {
+        let position = match self.entries.get_index_of(key) {
+            Some(position) => position,
+            None => self.entries.insert_full(key.to_string(), default()).0,
+        };
+        &mut self.entries[position]
+    }
+}
+
+/// Creates an empty engine for one database.
+pub type EngineFactory = fn() -> Box<dyn StorageEngine>;
+
+/// Every engine `storage-engine` can name.
+pub static ENGINES: &[(&str, EngineFactory)] = &[("memory", || Box::new(MemoryEngine::default()))];
+
+/// The factory of the engine called `name`.
+pub fn find(name: &str) -> Option<EngineFactory> {
+    ENGINES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|&(_, factory)| factory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::{Storage, Value};
+
+    #[test]
+    fn test_engines_are_selected_by_name() {
+        assert!(find("memory").is_some());
+        assert!(find("MEMORY").is_some());
+        assert!(find("rocksdb").is_none());
+
+        let mut config = Config::default();
+        assert!(
+            config
+                .apply("storage-engine", &["sled".to_string()])
+                .is_err()
+        );
+        config
+            .apply("storage-engine", &["Memory".to_string()])
+            .unwrap();
+        assert_eq!(config.storage_engine, "memory");
+
+        let storage = Storage::with_engine(2, find("memory").unwrap());
+        storage.db(1).set("k".to_string(), "v".to_string());
+        assert_eq!(storage.db(1).get("k"), Some("v".to_string()));
+        assert_eq!(storage.db(0).dbsize(), 0);
+    }
+
+    #[test]
+    fn test_memory_engine_positions() {
+        let mut engine = MemoryEngine::default();
+        for key in ["a", "b", "c", "d"] {
+            engine.insert(key.to_string(), Entry::new(Value::String(key.to_string())));
+        }
+        assert!(engine.remove("a").is_some());
+        assert!(engine.remove("a").is_none());
+        engine.retain(&mut |key, _| key != "c");
+        assert_eq!(engine.len(), 2);
+        for key in ["b", "d"] {
+            assert!(engine.get(key).is_some());
+        }
+        let mut sampled: Vec<_> = (0..engine.len())
+            .map(|index| engine.get_index(index).unwrap().0.clone())
+            .collect();
+        sampled.sort();
+        assert_eq!(sampled, ["b", "d"]);
+        assert!(engine.get_index(2).is_none());
+    }
+}
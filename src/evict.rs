@@ -0,0 +1,413 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::aof::AofGuard;
+use crate::commands;
+use crate::memory;
+use crate::notify::{self, KeyspaceEvent};
+use crate::replication::FeedGuard;
+use crate::server::Server;
+use crate::storage::{self, Entry};
+
+/// How keys are picked for eviction once `maxmemory` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// Evict nothing; commands that could add data get -OOM.
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    /// The `volatile-*` policies only evict keys with an expiration.
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    /// Evict the keys closest to expiring first.
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn parse(name: &str) -> Option<MaxmemoryPolicy> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Some(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Some(MaxmemoryPolicy::AllKeysLru),
+            "allkeys-lfu" => Some(MaxmemoryPolicy::AllKeysLfu),
+            "allkeys-random" => Some(MaxmemoryPolicy::AllKeysRandom),
+            "volatile-lru" => Some(MaxmemoryPolicy::VolatileLru),
+            "volatile-lfu" => Some(MaxmemoryPolicy::VolatileLfu),
+            "volatile-random" => Some(MaxmemoryPolicy::VolatileRandom),
+            "volatile-ttl" => Some(MaxmemoryPolicy::VolatileTtl),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn is_lfu(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu
+        )
+    }
+
+    fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+
+    /// How good a candidate for eviction `entry` is: the higher the better.
+    fn score(self, entry: &Entry, decay_time: u64) -> u64 {
+        match self {
+            MaxmemoryPolicy::AllKeysLru | MaxmemoryPolicy::VolatileLru => entry.idle_ms(),
+            MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                255 - u64::from(entry.lfu_counter(decay_time))
+            }
+            MaxmemoryPolicy::VolatileTtl => entry
+                .expires_at()
+                .map_or(0, |at| u64::MAX - at.saturating_sub(storage::now_ms())),
+            _ => 0,
+        }
+    }
+}
+
+/// Candidates the eviction pool keeps between evictions, as Redis'
+/// EVPOOL_SIZE.
+const POOL_SIZE: usize = 16;
+
+/// LFU counter of new keys, so they aren't evicted before they had a chance
+/// to be accessed.
+const LFU_INIT_VAL: u8 = 5;
+
+/// One key the pool considers evicting.
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    db: usize,
+    key: String,
+    score: u64,
+}
+
+/// The best candidates for eviction among the keys sampled so far, across
+/// databases and evictions, as in Redis. Sampling a few keys per eviction
+/// approximates the exact policy, and keeping the best of earlier samples
+/// makes the approximation much closer.
+#[derive(Debug, Default)]
+pub struct EvictionPool {
+    /// Sorted by ascending score, so the best candidate is last.
+    candidates: Vec<Candidate>,
+    /// Database the random policies evict from next.
+    next_db: usize,
+}
+
+impl EvictionPool {
+    fn insert(&mut self, db: usize, key: String, score: u64) {
+        if let Some(index) = self
+            .candidates
+            .iter()
+            .position(|candidate| candidate.db == db && candidate.key == key)
+        {
+            self.candidates.remove(index);
+        } else if self.candidates.len() == POOL_SIZE && score <= self.candidates[0].score {
+            return;
+        }
+        let index = self
+            .candidates
+            .partition_point(|candidate| candidate.score < score);
+        self.candidates.insert(index, Candidate { db, key, score });
+        if self.candidates.len() > POOL_SIZE {
+            self.candidates.remove(0);
+        }
+    }
+
+    fn pop(&mut self) -> Option<Candidate> {
+        self.candidates.pop()
+    }
+}
+
+/// Milliseconds since the server started: the clock key access times are
+/// kept in.
+pub fn lru_clock() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// The LFU field of a key is the minute it was last accessed, in the high
+/// 24 bits, and a logarithmic access counter in the low 8.
+fn lfu_minutes() -> u32 {
+    (lru_clock() / 60_000) as u32 & 0xFF_FFFF
+}
+
+/// The LFU field of a new key.
+pub fn lfu_new() -> u32 {
+    lfu_with(LFU_INIT_VAL)
+}
+
+/// An LFU field with `counter`, accessed just now.
+pub fn lfu_with(counter: u8) -> u32 {
+    (lfu_minutes() << 8) | u32::from(counter)
+}
+
+/// The counter of an LFU field, decremented once per `decay_time` minutes
+/// since the last access.
+pub fn lfu_decayed(packed: u32, decay_time: u64) -> u8 {
+    let counter = (packed & 0xFF) as u8;
+    if decay_time == 0 {
+        return counter;
+    }
+    let elapsed = lfu_minutes().wrapping_sub(packed >> 8) & 0xFF_FFFF;
+    let periods = u64::from(elapsed) / decay_time;
+    counter.saturating_sub(periods.min(255) as u8)
+}
+
+/// The LFU field after an access: decayed, then incremented with a
+/// probability that falls as the counter grows, so 255 takes about a
+/// million accesses with the default `log_factor` of 10.
+pub fn lfu_access(packed: u32, log_factor: u64, decay_time: u64) -> u32 {
+    let mut counter = lfu_decayed(packed, decay_time);
+    if counter < 255 {
+        let base = f64::from(counter.saturating_sub(LFU_INIT_VAL));
+        let probability = 1.0 / (base * log_factor as f64 + 1.0);
+        if random_f64() < probability {
+            counter += 1;
+        }
+    }
+    lfu_with(counter)
+}
+
+/// A cheap random number for sampling, not fit for anything secret.
+/// SplitMix64 over a shared counter.
+pub fn random_u64() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    static SEED: OnceLock<u64> = OnceLock::new();
+    let seed = *SEED.get_or_init(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    });
+    let mut z = seed.wrapping_add(
+        STATE
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15),
+    );
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn random_f64() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Evicts keys until memory use is back under `maxmemory`, propagating a
+/// DEL for each. Returns false when that's impossible: the policy is
+/// noeviction or it has no keys left to evict.
+pub fn perform_evictions(
+    server: &Server,
+    maxmemory: usize,
+    aof: &mut Option<AofGuard<'_>>,
+    replicas: &mut Option<FeedGuard<'_>>,
+) -> bool {
+    evict_while(server, aof, replicas, || {
+        memory::used_memory() > maxmemory
+    })
+}
+
+fn evict_while(
+    server: &Server,
+    aof: &mut Option<AofGuard<'_>>,
+    replicas: &mut Option<FeedGuard<'_>>,
+    mut over: impl FnMut() -> bool,
+) -> bool {
+    let (policy, samples, decay_time) = {
+        let config = server.config.read().unwrap();
+        (
+            config.maxmemory_policy,
+            config.maxmemory_samples,
+            config.lfu_decay_time,
+        )
+    };
+    while over() {
+        let Some((db, key)) = select_victim(server, policy, samples, decay_time) else {
+            return false;
+        };
+        server.storage.db(db).del(std::slice::from_ref(&key));
+        server.storage.touch_key(db, &key);
+        commands::propagate(aof, replicas, db, &["del", &key]);
+        notify::notify(server, KeyspaceEvent::Evicted, db, &key);
+    }
+    true
+}
+
+/// The next key to evict under `policy`, if any.
+fn select_victim(
+    server: &Server,
+    policy: MaxmemoryPolicy,
+    samples: usize,
+    decay_time: u64,
+) -> Option<(usize, String)> {
+    let storage = &server.storage;
+    let volatile = policy.is_volatile();
+    let mut pool = server.eviction_pool.lock().unwrap();
+    match policy {
+        MaxmemoryPolicy::NoEviction => None,
+        MaxmemoryPolicy::AllKeysRandom | MaxmemoryPolicy::VolatileRandom => {
+            // One key from each database in turn
+            for _ in 0..storage.len() {
+                let db = pool.next_db % storage.len();
+                pool.next_db = db + 1;
+                if let Some((key, _)) = storage.db(db).sample(1, volatile, |_| 0).pop() {
+                    return Some((db, key));
+                }
+            }
+            None
+        }
+        _ => {
+            for db in 0..storage.len() {
+                let sampled = storage
+                    .db(db)
+                    .sample(samples, volatile, |entry| policy.score(entry, decay_time));
+                for (key, score) in sampled {
+                    pool.insert(db, key, score);
+                }
+            }
+            // Candidates from earlier samples may be gone by now
+            while let Some(candidate) = pool.pop() {
+                if storage
+                    .db(candidate.db)
+                    .exists(std::slice::from_ref(&candidate.key))
+                    > 0
+                {
+                    return Some((candidate.db, candidate.key));
+                }
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::Pattern;
+    use crate::stats::Stats;
+    use std::time::Duration;
+
+    fn server_with(policy: MaxmemoryPolicy) -> Server {
+        let server = Server::default();
+        let mut config = server.config.write().unwrap();
+        config.maxmemory_policy = policy;
+        // Sampling every key makes the approximation exact
+        config.maxmemory_samples = 64;
+        drop(config);
+        server
+    }
+
+    /// Evicts `count` keys, as if each eviction freed just enough memory
+    /// for the next one to be needed.
+    fn evict_keys(server: &Server, count: usize) -> bool {
+        let mut evicted = 0;
+        evict_while(server, &mut None, &mut None, || {
+            evicted += 1;
+            evicted <= count
+        })
+    }
+
+    fn keys(server: &Server) -> Vec<String> {
+        let mut keys = server.storage.db(0).keys(&Pattern::new("*"));
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_eviction_pool() {
+        let mut pool = EvictionPool::default();
+        for score in 0..20 {
+            pool.insert(0, format!("k{}", score), score);
+        }
+        assert_eq!(pool.candidates.len(), POOL_SIZE);
+        // Too poor to make it in, then an update of a pooled key
+        pool.insert(0, "poor".to_string(), 1);
+        pool.insert(0, "k5".to_string(), 100);
+        assert_eq!(pool.pop().unwrap().key, "k5");
+        assert_eq!(pool.pop().unwrap().key, "k19");
+        assert!(pool.candidates.iter().all(|c| c.key != "poor"));
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let server = server_with(MaxmemoryPolicy::AllKeysLru);
+        let db = server.storage.db(0);
+        for key in ["a", "b", "c"] {
+            db.set(key.to_string(), "1".to_string());
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        db.record_access("a", None);
+        db.record_access("c", None);
+        assert!(evict_keys(&server, 1));
+        assert_eq!(keys(&server), ["a", "c"]);
+        assert_eq!(Stats::get(&server.stats.evicted_keys), 1);
+        // As RESTORE ... IDLETIME does
+        db.set_access("c", Some(100), None);
+        assert!(evict_keys(&server, 1));
+        assert_eq!(keys(&server), ["a"]);
+    }
+
+    #[test]
+    fn test_volatile_policies_only_evict_expiring_keys() {
+        let server = server_with(MaxmemoryPolicy::VolatileTtl);
+        let db = server.storage.db(0);
+        db.set("persistent".to_string(), "1".to_string());
+        db.set_with_expiry("later".to_string(), "1".to_string(), 100_000);
+        db.set_with_expiry("soon".to_string(), "1".to_string(), 1_000);
+        assert!(evict_keys(&server, 1));
+        assert_eq!(keys(&server), ["later", "persistent"]);
+        // Nothing volatile left after the next one
+        assert!(!evict_keys(&server, 2));
+        assert_eq!(keys(&server), ["persistent"]);
+
+        let server = server_with(MaxmemoryPolicy::NoEviction);
+        server.storage.db(0).set("a".to_string(), "1".to_string());
+        assert!(!evict_keys(&server, 1));
+    }
+
+    #[test]
+    fn test_random_eviction_visits_every_database() {
+        let server = server_with(MaxmemoryPolicy::AllKeysRandom);
+        server.storage.db(0).set("a".to_string(), "1".to_string());
+        server.storage.db(3).set("b".to_string(), "1".to_string());
+        assert!(evict_keys(&server, 2));
+        assert_eq!(server.storage.db(0).dbsize() + server.storage.db(3).dbsize(), 0);
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        let mut packed = lfu_new();
+        assert_eq!(lfu_decayed(packed, 1), LFU_INIT_VAL);
+        // Increments are certain below the initial value and get rarer
+        for _ in 0..1000 {
+            packed = lfu_access(packed, 10, 1);
+        }
+        let counter = lfu_decayed(packed, 1);
+        assert!(counter > LFU_INIT_VAL && counter < 50);
+        // Three minutes since the last access take three off
+        let old = packed.wrapping_sub(3 << 8);
+        assert_eq!(lfu_decayed(old, 1), counter - 3);
+        assert_eq!(lfu_decayed(old, 0), counter);
+    }
+}
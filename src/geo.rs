@@ -0,0 +1,394 @@
+use crate::zset::{ScoreBound, ScoreRange, SortedSet};
+
+/// Bits a geohash gives each coordinate, 52 in all, which a sorted set
+/// score holds exactly.
+const STEP_MAX: u32 = 26;
+
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+
+/// The latitudes Web Mercator reaches, the ones Redis accepts.
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+
+/// The Earth's radius in meters, as Redis measures distances with it.
+const EARTH_RADIUS: f64 = 6372797.560856;
+
+/// Half the Earth's circumference in Web Mercator meters.
+const MERCATOR_MAX: f64 = 20037726.37;
+
+/// Whether a longitude and latitude can be stored, as GEOADD checks.
+pub fn valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+/// The 52-bit geohash of a position, the score its member is stored with:
+/// the latitude and longitude each scaled to 26 bits and interleaved, the
+/// latitude's bits in the even positions.
+pub fn encode(lon: f64, lat: f64) -> u64 {
+    let cells = (1u64 << STEP_MAX) as f64;
+    let scale = |value: f64, min: f64, max: f64| {
+        // The maximum itself falls in the last cell
+        (((value - min) / (max - min) * cells) as u64).min((1 << STEP_MAX) - 1)
+    };
+    interleave(scale(lat, LAT_MIN, LAT_MAX), scale(lon, LON_MIN, LON_MAX))
+}
+
+/// The position a geohash stands for: the center of its cell, which is
+/// what GEOPOS replies and distances are measured from.
+pub fn decode(hash: u64) -> (f64, f64) {
+    let (lat_bits, lon_bits) = deinterleave(hash);
+    let cells = (1u64 << STEP_MAX) as f64;
+    let center = |bits: u64, min: f64, max: f64| {
+        let low = min + (bits as f64 / cells) * (max - min);
+        let high = min + ((bits + 1) as f64 / cells) * (max - min);
+        ((low + high) / 2.0).clamp(min, max)
+    };
+    (
+        center(lon_bits, LON_MIN, LON_MAX),
+        center(lat_bits, LAT_MIN, LAT_MAX),
+    )
+}
+
+/// Spreads `even`'s low 26 bits over the even bits of the result and
+/// `odd`'s over the odd ones.
+fn interleave(even: u64, odd: u64) -> u64 {
+    (0..STEP_MAX).fold(0, |hash, bit| {
+        hash | ((even >> bit) & 1) << (2 * bit) | ((odd >> bit) & 1) << (2 * bit + 1)
+    })
+}
+
+/// The even bits of `hash` and its odd bits, each packed back together.
+fn deinterleave(hash: u64) -> (u64, u64) {
+    (0..STEP_MAX).fold((0, 0), |(even, odd), bit| {
+        (
+            even | ((hash >> (2 * bit)) & 1) << bit,
+            odd | ((hash >> (2 * bit + 1)) & 1) << bit,
+        )
+    })
+}
+
+/// The great-circle distance in meters between two positions, by the
+/// haversine formula.
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// The meters in a unit GEODIST and GEOSEARCH take distances in.
+pub fn parse_unit(unit: &str) -> Result<f64, String> {
+    match unit.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err("ERR unsupported unit provided. please use M, KM, FT, MI".to_string()),
+    }
+}
+
+/// Formats a distance as Redis replies with it, to four decimals.
+pub fn format_distance(distance: f64) -> String {
+    format!("{:.4}", distance)
+}
+
+/// Formats a longitude or latitude as Redis replies with it, to 17
+/// decimals without the trailing zeros.
+pub fn format_coordinate(coordinate: f64) -> String {
+    let formatted = format!("{:.17}", coordinate);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Where GEOSEARCH measures from: a member of the sorted set searched, or
+/// a longitude and latitude.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Member(String),
+    Position(f64, f64),
+}
+
+/// The area GEOSEARCH looks in around its origin, in meters: a circle of
+/// a radius, or a box of a width and height, its sides along meridians
+/// and parallels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// The distance from the origin to a position inside the shape, or
+    /// None if the position is outside. A box's sides are measured along
+    /// the meridian and parallel through the position, as Redis does.
+    fn contains(self, lon: f64, lat: f64, other_lon: f64, other_lat: f64) -> Option<f64> {
+        if let Shape::Box { width, height } = self {
+            let lat_distance = EARTH_RADIUS * (other_lat.to_radians() - lat.to_radians()).abs();
+            if lat_distance > height / 2.0
+                || distance(lon, other_lat, other_lon, other_lat) > width / 2.0
+            {
+                return None;
+            }
+        }
+        let distance = distance(lon, lat, other_lon, other_lat);
+        match self {
+            Shape::Radius(radius) if distance > radius => None,
+            _ => Some(distance),
+        }
+    }
+
+    /// How far the shape reaches north or south and east or west of its
+    /// origin, in meters.
+    fn half_extents(self) -> (f64, f64) {
+        match self {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box { width, height } => (height / 2.0, width / 2.0),
+        }
+    }
+}
+
+/// The order GEOSEARCH replies in, by distance from the origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    Asc,
+    Desc,
+}
+
+/// A GEOSEARCH: the members within `shape` of `origin`, their distances
+/// in `unit` (meters to the unit), ordered by `sort` if given and no more
+/// than `count`. With `any` the search stops at the first `count` members
+/// found rather than finding them all and keeping the nearest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoSearch {
+    pub origin: Origin,
+    pub shape: Shape,
+    pub unit: f64,
+    pub sort: Option<Sort>,
+    pub count: Option<usize>,
+    pub any: bool,
+}
+
+/// A member a GEOSEARCH found: its score, the geohash, its position and
+/// its distance from the origin in the search's unit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Found {
+    pub member: String,
+    pub score: f64,
+    pub lon: f64,
+    pub lat: f64,
+    pub distance: f64,
+}
+
+impl GeoSearch {
+    /// The members of `zset` the search finds, or an error if its origin
+    /// is a member that isn't there. Only the members in the geohash
+    /// cells around the origin are looked at.
+    pub fn run(&self, zset: &SortedSet) -> Result<Vec<Found>, String> {
+        let (lon, lat) = match &self.origin {
+            Origin::Member(member) => match zset.score(member) {
+                Some(score) => decode(score as u64),
+                None => return Err("ERR could not decode requested zset member".to_string()),
+            },
+            Origin::Position(lon, lat) => (*lon, *lat),
+        };
+        let limit = if self.any { self.count } else { None };
+
+        let mut found = Vec::new();
+        'cells: for cell in cells(lon, lat, self.shape) {
+            for (member, score) in zset.slice(zset.score_ranks(&cell), false) {
+                let (other_lon, other_lat) = decode(score as u64);
+                let Some(distance) = self.shape.contains(lon, lat, other_lon, other_lat) else {
+                    continue;
+                };
+                found.push(Found {
+                    member: member.to_string(),
+                    score,
+                    lon: other_lon,
+                    lat: other_lat,
+                    distance: distance / self.unit,
+                });
+                if limit == Some(found.len()) {
+                    break 'cells;
+                }
+            }
+        }
+
+        match self.sort {
+            Some(Sort::Asc) => found.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Some(Sort::Desc) => found.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            None => {}
+        }
+        if let Some(count) = self.count {
+            found.truncate(count);
+        }
+        Ok(found)
+    }
+}
+
+/// The finest step, in bits per coordinate, whose cells are about as big
+/// as a search reaching `radius` meters from a latitude, as Redis
+/// estimates it: cells are narrower towards the poles.
+fn estimate_step(radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return STEP_MAX;
+    }
+    let mut range = radius;
+    let mut step: i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    if lat.abs() > 66.0 {
+        step -= 1;
+        if lat.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP_MAX as i32) as u32
+}
+
+/// The score ranges of the geohash cells that can hold members in
+/// `shape` around a position: the cell the position is in and its eight
+/// neighbours, at the finest step where those cover the shape's bounding
+/// box, or every score if no step's do.
+fn cells(lon: f64, lat: f64, shape: Shape) -> Vec<ScoreRange> {
+    let (north_south, east_west) = shape.half_extents();
+    let lat_delta = (north_south / EARTH_RADIUS).to_degrees();
+    // Widest at the latitude nearest a pole the box reaches
+    let nearest_pole = (lat.abs() + lat_delta).min(90.0).to_radians();
+    let sin_delta = (east_west / (2.0 * EARTH_RADIUS)).sin() / nearest_pole.cos();
+    let lon_delta = if sin_delta < 1.0 {
+        2.0 * sin_delta.asin().to_degrees()
+    } else {
+        f64::INFINITY
+    };
+    let south = (lat - lat_delta).max(LAT_MIN);
+    let north = (lat + lat_delta).min(LAT_MAX);
+
+    let mut step = estimate_step(north_south.hypot(east_west), lat);
+    while step > 0 {
+        let cells = 1u64 << step;
+        let lat_size = (LAT_MAX - LAT_MIN) / cells as f64;
+        let lon_size = (LON_MAX - LON_MIN) / cells as f64;
+        let lat_cell = (((lat - LAT_MIN) / lat_size) as u64).min(cells - 1);
+        let lon_cell = (((lon - LON_MIN) / lon_size) as u64).min(cells - 1);
+        let covered = LAT_MIN + (lat_cell as f64 - 1.0) * lat_size <= south
+            && LAT_MIN + (lat_cell as f64 + 2.0) * lat_size >= north
+            && LON_MIN + (lon_cell as f64 - 1.0) * lon_size <= lon - lon_delta
+            && LON_MIN + (lon_cell as f64 + 2.0) * lon_size >= lon + lon_delta;
+        if covered {
+            let shift = 2 * (STEP_MAX - step);
+            let mut hashes = Vec::with_capacity(9);
+            for lat_cell in [lat_cell.wrapping_sub(1), lat_cell, lat_cell + 1] {
+                // Latitudes stop at the poles, longitudes wrap around
+                if lat_cell >= cells {
+                    continue;
+                }
+                for lon_cell in [lon_cell + cells - 1, lon_cell, lon_cell + 1] {
+                    hashes.push(interleave(lat_cell, lon_cell % cells) << shift);
+                }
+            }
+            hashes.sort_unstable();
+            hashes.dedup();
+            return hashes
+                .into_iter()
+                .map(|hash| score_range(hash, hash + (1 << shift)))
+                .collect();
+        }
+        step -= 1;
+    }
+    vec![score_range(0, 1 << (2 * STEP_MAX))]
+}
+
+/// The scores from `min` up to but not including `max`.
+fn score_range(min: u64, max: u64) -> ScoreRange {
+    ScoreRange {
+        min: ScoreBound {
+            score: min as f64,
+            exclusive: false,
+        },
+        max: ScoreBound {
+            score: max as f64,
+            exclusive: true,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        // Palermo, as Redis stores and replies it
+        let hash = encode(13.361389, 38.115556);
+        assert_eq!(hash, 3479099956230698);
+        let (lon, lat) = decode(hash);
+        assert!((lon - 13.361389).abs() < 1e-5);
+        assert!((lat - 38.115556).abs() < 1e-5);
+        assert_eq!(format_coordinate(lon), "13.36138933897018433");
+        assert_eq!(format_coordinate(lat), "38.11555639549629859");
+
+        assert_eq!(encode(LON_MIN, LAT_MIN), 0);
+        assert_eq!(encode(LON_MAX, LAT_MAX), (1 << 52) - 1);
+        assert_eq!(deinterleave(interleave(12345, 67890)), (12345, 67890));
+        assert!(valid(-180.0, 85.0));
+        assert!(!valid(180.5, 0.0));
+        assert!(!valid(0.0, -86.0));
+    }
+
+    #[test]
+    fn test_distance() {
+        let palermo = decode(encode(13.361389, 38.115556));
+        let catania = decode(encode(15.087269, 37.502669));
+        let meters = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert_eq!(format_distance(meters), "166274.1516");
+        assert_eq!(
+            format_distance(meters / parse_unit("KM").unwrap()),
+            "166.2742"
+        );
+        assert!(parse_unit("yd").is_err());
+    }
+
+    #[test]
+    fn test_cells_cover_shape() {
+        // Every member within the radius is found whatever the step the
+        // cells are picked at
+        let mut zset = SortedSet::new();
+        for i in 0..200 {
+            let lon = -179.0 + (i as f64) * 1.79;
+            let lat = -84.0 + (i as f64 * 37.0) % 168.0;
+            zset.insert(&i.to_string(), encode(lon, lat) as f64);
+        }
+        for (lon, lat, radius) in [
+            (0.0, 0.0, 500_000.0),
+            (179.9, 10.0, 2_000_000.0),
+            (-120.0, 84.0, 300_000.0),
+            (30.0, -60.0, 20_000_000.0),
+        ] {
+            let search = GeoSearch {
+                origin: Origin::Position(lon, lat),
+                shape: Shape::Radius(radius),
+                unit: 1.0,
+                sort: Some(Sort::Asc),
+                count: None,
+                any: false,
+            };
+            let found = search.run(&zset).unwrap();
+            let expected = zset
+                .iter()
+                .filter(|(_, score)| {
+                    let (other_lon, other_lat) = decode(*score as u64);
+                    distance(lon, lat, other_lon, other_lat) <= radius
+                })
+                .count();
+            assert_eq!(found.len(), expected);
+            assert!(found.windows(2).all(|w| w[0].distance <= w[1].distance));
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::{HashMap, hash_map};
+
+use crate::listpack::{self, Listpack};
+
+/// `hash-max-listpack-entries` by default.
+pub const DEFAULT_MAX_LISTPACK_ENTRIES: usize = 128;
+
+/// `hash-max-listpack-value` by default.
+pub const DEFAULT_MAX_LISTPACK_VALUE: usize = 64;
+
+/// A hash value, packed into a listpack of fields each followed by its
+/// value while it's small, as most hashes are, and converted to a table
+/// once it has more than `hash-max-listpack-entries` fields or a field or
+/// value longer than `hash-max-listpack-value`. Tables stay tables when
+/// they shrink, as in Redis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hash {
+    Packed(Listpack),
+    Table(HashMap<String, String>),
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::Packed(Listpack::new())
+    }
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How the hash is encoded, as OBJECT ENCODING replies it.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Hash::Packed(_) => "listpack",
+            Hash::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Hash::Packed(listpack) => listpack.len() / 2,
+            Hash::Table(table) => table.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &str) -> Option<&str> {
+        match self {
+            Hash::Packed(listpack) => {
+                let mut items = listpack.iter();
+                while let (Some(name), Some(value)) = (items.next(), items.next()) {
+                    if name == field {
+                        return Some(value);
+                    }
+                }
+                None
+            }
+            Hash::Table(table) => table.get(field).map(String::as_str),
+        }
+    }
+
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+
+    /// Sets `field` to `value`, returning whether the field is new.
+    pub fn insert(&mut self, field: String, value: String) -> bool {
+        match self {
+            Hash::Packed(listpack) => match position(listpack, &field) {
+                Some(index) => {
+                    listpack.set(index + 1, &value);
+                    false
+                }
+                None => {
+                    listpack.push_back(&field);
+                    listpack.push_back(&value);
+                    true
+                }
+            },
+            Hash::Table(table) => table.insert(field, value).is_none(),
+        }
+    }
+
+    /// Removes `field`, returning whether there was one.
+    pub fn remove(&mut self, field: &str) -> bool {
+        match self {
+            Hash::Packed(listpack) => match position(listpack, field) {
+                Some(index) => {
+                    listpack.remove(index, 2);
+                    true
+                }
+                None => false,
+            },
+            Hash::Table(table) => table.remove(field).is_some(),
+        }
+    }
+
+    /// The fields and their values.
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Hash::Packed(listpack) => Iter::Packed(listpack.iter()),
+            Hash::Table(table) => Iter::Table(table.iter()),
+        }
+    }
+
+    /// Converts a listpack grown past `max_entries` fields, or holding a
+    /// field or value longer than `max_value`, to a table, as every write
+    /// that sets a field does.
+    pub fn convert(&mut self, max_entries: usize, max_value: usize) {
+        if let Hash::Packed(listpack) = self
+            && exceeds(listpack, max_entries, max_value)
+        {
+            let mut items = listpack.iter();
+            let mut table = HashMap::with_capacity(listpack.len() / 2);
+            while let (Some(field), Some(value)) = (items.next(), items.next()) {
+                table.insert(field.to_string(), value.to_string());
+            }
+            *self = Hash::Table(table);
+        }
+    }
+
+    /// Packs a table into a listpack if it fits within `max_entries` and
+    /// `max_value`, as a hash loaded or restored is.
+    pub fn pack(&mut self, max_entries: usize, max_value: usize) {
+        let Hash::Table(table) = self else {
+            return;
+        };
+        if table.len() > max_entries {
+            return;
+        }
+        let mut listpack = Listpack::new();
+        for (field, value) in table.iter() {
+            listpack.push_back(field);
+            listpack.push_back(value);
+        }
+        if !exceeds(&listpack, max_entries, max_value) {
+            *self = Hash::Packed(listpack);
+        }
+    }
+}
+
+/// Whether a packed hash is past `hash-max-listpack-entries` or
+/// `hash-max-listpack-value`.
+fn exceeds(listpack: &Listpack, max_entries: usize, max_value: usize) -> bool {
+    listpack.len() / 2 > max_entries || listpack.iter().any(|item| item.len() > max_value)
+}
+
+/// Where `field` is in a packed hash, as the index of the entry holding
+/// its name.
+fn position(listpack: &Listpack, field: &str) -> Option<usize> {
+    listpack
+        .iter()
+        .step_by(2)
+        .position(|name| name == field)
+        .map(|pair| pair * 2)
+}
+
+impl From<HashMap<String, String>> for Hash {
+    fn from(table: HashMap<String, String>) -> Self {
+        Hash::Table(table)
+    }
+}
+
+impl<'a> IntoIterator for &'a Hash {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The fields of a hash and their values, in either encoding.
+#[derive(Debug, Clone)]
+pub enum Iter<'a> {
+    Packed(listpack::Iter<'a>),
+    Table(hash_map::Iter<'a, String, String>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Packed(iter) => Some((iter.next()?, iter.next()?)),
+            Iter::Table(iter) => iter
+                .next()
+                .map(|(field, value)| (field.as_str(), value.as_str())),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Iter::Packed(iter) => (iter.len() / 2, Some(iter.len() / 2)),
+            Iter::Table(iter) => iter.size_hint(),
+        }
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(hash: &Hash) -> Vec<(&str, &str)> {
+        let mut fields: Vec<_> = hash.iter().collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn test_converted_past_limits() {
+        let mut hash = Hash::new();
+        for i in 0..4 {
+            assert!(hash.insert(i.to_string(), "v".to_string()));
+            hash.convert(4, 8);
+        }
+        assert_eq!(hash.encoding(), "listpack");
+        assert!(!hash.insert("0".to_string(), "x".repeat(8)));
+        hash.convert(4, 8);
+        assert_eq!(hash.encoding(), "listpack");
+        hash.insert("4".to_string(), "v".to_string());
+        hash.convert(4, 8);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.get("0"), Some("xxxxxxxx"));
+        assert_eq!(hash.len(), 5);
+
+        // Shrinking keeps the table
+        for i in 1..5 {
+            assert!(hash.remove(&i.to_string()));
+        }
+        hash.convert(4, 8);
+        assert_eq!(hash.encoding(), "hashtable");
+
+        // A long field or value converts it whatever the count
+        let mut hash = Hash::new();
+        hash.insert("f".repeat(9), "v".to_string());
+        hash.convert(4, 8);
+        assert_eq!(hash.encoding(), "hashtable");
+    }
+
+    #[test]
+    fn test_packed_when_loaded() {
+        let table = HashMap::from([
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "2".to_string()),
+        ]);
+        let mut hash = Hash::from(table.clone());
+        hash.pack(DEFAULT_MAX_LISTPACK_ENTRIES, DEFAULT_MAX_LISTPACK_VALUE);
+        assert_eq!(hash.encoding(), "listpack");
+        assert_eq!(fields(&hash), [("a", "1"), ("b", "2")]);
+
+        let mut hash = Hash::from(table.clone());
+        hash.pack(1, DEFAULT_MAX_LISTPACK_VALUE);
+        assert_eq!(hash.encoding(), "hashtable");
+        let mut hash = Hash::from(table);
+        hash.pack(DEFAULT_MAX_LISTPACK_ENTRIES, 0);
+        assert_eq!(hash.encoding(), "hashtable");
+    }
+
+    #[test]
+    fn test_encodings_behave_alike() {
+        let mut packed = Hash::new();
+        let mut table = Hash::from(HashMap::new());
+        for hash in [&mut packed, &mut table] {
+            assert!(hash.insert("a".to_string(), "1".to_string()));
+            assert!(hash.insert("b".to_string(), "2".to_string()));
+            assert!(hash.insert("c".to_string(), "3".to_string()));
+            assert!(!hash.insert("b".to_string(), "two".to_string()));
+            assert!(hash.remove("a"));
+            assert!(!hash.remove("a"));
+            assert!(hash.contains_key("c"));
+            assert_eq!(hash.get("d"), None);
+        }
+        assert_eq!(packed.encoding(), "listpack");
+        assert_eq!(table.encoding(), "hashtable");
+        assert_eq!(fields(&packed), fields(&table));
+        assert_eq!(fields(&packed), [("b", "two"), ("c", "3")]);
+        assert_eq!(packed.iter().len(), 2);
+    }
+}
@@ -0,0 +1,447 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use crate::memory;
+use crate::replication::LinkState;
+use crate::server::Server;
+use crate::stats::{self, Stats};
+
+type Section = fn(&Server, &mut String);
+
+/// Every INFO section in output order, and whether plain `INFO` shows it.
+const SECTIONS: &[(&str, bool, Section)] = &[
+    ("server", true, server_section),
+    ("clients", true, clients_section),
+    ("memory", true, memory_section),
+    ("persistence", true, persistence_section),
+    ("stats", true, stats_section),
+    ("replication", true, replication_section),
+    ("cpu", true, cpu_section),
+    ("latencystats", false, latencystats_section),
+    ("cron", false, cron_section),
+    ("keyspace", true, keyspace_section),
+];
+
+/// Builds the INFO reply for the requested sections. No sections or
+/// `default` selects the default ones; `all` and `everything` select every
+/// section.
+pub fn info(server: &Server, requested: &[String]) -> String {
+    let requested: Vec<String> = requested.iter().map(|s| s.to_lowercase()).collect();
+    let everything = requested.iter().any(|s| s == "all" || s == "everything");
+    let default = requested.is_empty() || requested.iter().any(|s| s == "default");
+
+    let mut out = String::new();
+    for (name, in_default, section) in SECTIONS {
+        if everything || (default && *in_default) || requested.iter().any(|s| s == name) {
+            if !out.is_empty() {
+                out.push_str("\r\n");
+            }
+            section(server, &mut out);
+        }
+    }
+    out
+}
+
+fn server_section(server: &Server, out: &mut String) {
+    let uptime = server.started_at.elapsed().as_secs();
+    let (port, hz) = {
+        let config = server.config.read().unwrap();
+        (config.port, config.hz)
+    };
+    out.push_str("# Server\r\n");
+    out.push_str("redis_version:7.0.0-reredis\r\n");
+    out.push_str("redis_mode:standalone\r\n");
+    let _ = write!(out, "os:{}\r\n", std::env::consts::OS);
+    let _ = write!(out, "arch_bits:{}\r\n", usize::BITS);
+    let _ = write!(out, "process_id:{}\r\n", std::process::id());
+    let _ = write!(out, "run_id:{}\r\n", server.run_id);
+    let _ = write!(out, "tcp_port:{}\r\n", port);
+    let _ = write!(out, "hz:{}\r\n", hz);
+    let _ = write!(out, "configured_hz:{}\r\n", hz);
+    let _ = write!(out, "uptime_in_seconds:{}\r\n", uptime);
+    let _ = write!(out, "uptime_in_days:{}\r\n", uptime / 86400);
+}
+
+fn clients_section(server: &Server, out: &mut String) {
+    let clients = server.clients.list();
+    out.push_str("# Clients\r\n");
+    let _ = write!(out, "connected_clients:{}\r\n", clients.len());
+    let _ = write!(
+        out,
+        "blocked_clients:{}\r\n",
+        clients.iter().filter(|c| c.blocked).count()
+    );
+    let _ = write!(
+        out,
+        "pubsub_clients:{}\r\n",
+        clients.iter().filter(|c| c.sub + c.psub > 0).count()
+    );
+}
+
+fn memory_section(server: &Server, out: &mut String) {
+    let used = memory::used_memory();
+    let rss = memory::rss_bytes();
+    let peak = memory::peak_memory();
+    out.push_str("# Memory\r\n");
+    let _ = write!(out, "used_memory:{}\r\n", used);
+    let _ = write!(
+        out,
+        "used_memory_human:{}\r\n",
+        memory::bytes_to_human(used)
+    );
+    let _ = write!(out, "used_memory_rss:{}\r\n", rss);
+    let _ = write!(
+        out,
+        "used_memory_rss_human:{}\r\n",
+        memory::bytes_to_human(rss)
+    );
+    let _ = write!(out, "used_memory_peak:{}\r\n", peak);
+    let _ = write!(
+        out,
+        "used_memory_peak_human:{}\r\n",
+        memory::bytes_to_human(peak)
+    );
+    if used > 0 {
+        let _ = write!(
+            out,
+            "mem_fragmentation_ratio:{:.2}\r\n",
+            rss as f64 / used as f64
+        );
+    }
+    let _ = write!(out, "mem_clients_normal:{}\r\n", server.clients.memory());
+    let (maxmemory, policy) = {
+        let config = server.config.read().unwrap();
+        (config.maxmemory, config.maxmemory_policy)
+    };
+    let _ = write!(out, "maxmemory:{}\r\n", maxmemory);
+    let _ = write!(
+        out,
+        "maxmemory_human:{}\r\n",
+        memory::bytes_to_human(maxmemory)
+    );
+    let _ = write!(out, "maxmemory_policy:{}\r\n", policy.name());
+    let allocator = memory::allocator_stats();
+    let _ = write!(out, "allocator_allocated:{}\r\n", allocator.allocated);
+    let _ = write!(out, "allocator_active:{}\r\n", allocator.active);
+    let _ = write!(out, "allocator_resident:{}\r\n", allocator.resident);
+    if allocator.allocated > 0 && allocator.active > 0 {
+        let _ = write!(
+            out,
+            "allocator_frag_ratio:{:.2}\r\n",
+            allocator.active as f64 / allocator.allocated as f64
+        );
+        let _ = write!(
+            out,
+            "allocator_frag_bytes:{}\r\n",
+            allocator.active as i64 - allocator.allocated as i64
+        );
+        let _ = write!(
+            out,
+            "allocator_rss_ratio:{:.2}\r\n",
+            allocator.resident as f64 / allocator.active as f64
+        );
+    }
+    let _ = write!(out, "mem_allocator:{}\r\n", memory::allocator_name());
+    let _ = write!(
+        out,
+        "active_defrag_running:{}\r\n",
+        u8::from(server.defrag.is_running())
+    );
+}
+
+fn persistence_section(server: &Server, out: &mut String) {
+    out.push_str("# Persistence\r\n");
+    out.push_str("loading:0\r\n");
+    let _ = write!(
+        out,
+        "rdb_changes_since_last_save:{}\r\n",
+        Stats::get(&server.stats.dirty)
+    );
+    let _ = write!(
+        out,
+        "rdb_bgsave_in_progress:{}\r\n",
+        u8::from(server.rdb.bgsave_in_progress())
+    );
+    let _ = write!(
+        out,
+        "rdb_last_save_time:{}\r\n",
+        server.lastsave.load(Ordering::Relaxed)
+    );
+    let _ = write!(
+        out,
+        "rdb_last_bgsave_status:{}\r\n",
+        if server.rdb.last_bgsave_ok() {
+            "ok"
+        } else {
+            "err"
+        }
+    );
+    let _ = write!(
+        out,
+        "rdb_last_bgsave_time_sec:{}\r\n",
+        server.rdb.last_bgsave_secs()
+    );
+    let _ = write!(
+        out,
+        "rdb_current_bgsave_time_sec:{}\r\n",
+        server.rdb.current_bgsave_secs()
+    );
+    let _ = write!(out, "aof_enabled:{}\r\n", u8::from(server.aof.is_on()));
+    out.push_str("aof_rewrite_in_progress:0\r\n");
+    out.push_str("aof_last_bgrewrite_status:ok\r\n");
+    let _ = write!(
+        out,
+        "aof_last_write_status:{}\r\n",
+        if server.aof.last_write_ok() {
+            "ok"
+        } else {
+            "err"
+        }
+    );
+    if let Some(size) = server.aof.size() {
+        let _ = write!(out, "aof_current_size:{}\r\n", size);
+    }
+}
+
+fn stats_section(server: &Server, out: &mut String) {
+    let stats = &server.stats;
+    let (input_kbps, output_kbps) = stats.instantaneous_kbps();
+    out.push_str("# Stats\r\n");
+    let _ = write!(
+        out,
+        "total_connections_received:{}\r\n",
+        Stats::get(&stats.connections_received)
+    );
+    let _ = write!(
+        out,
+        "total_commands_processed:{}\r\n",
+        Stats::get(&stats.commands_processed)
+    );
+    let _ = write!(
+        out,
+        "instantaneous_ops_per_sec:{}\r\n",
+        stats.instantaneous_ops_per_sec()
+    );
+    let _ = write!(
+        out,
+        "total_net_input_bytes:{}\r\n",
+        Stats::get(&stats.net_input_bytes)
+    );
+    let _ = write!(
+        out,
+        "total_net_output_bytes:{}\r\n",
+        Stats::get(&stats.net_output_bytes)
+    );
+    let _ = write!(out, "instantaneous_input_kbps:{:.2}\r\n", input_kbps);
+    let _ = write!(out, "instantaneous_output_kbps:{:.2}\r\n", output_kbps);
+    let _ = write!(out, "sync_full:{}\r\n", Stats::get(&stats.sync_full));
+    let _ = write!(
+        out,
+        "sync_partial_ok:{}\r\n",
+        Stats::get(&stats.sync_partial_ok)
+    );
+    let _ = write!(
+        out,
+        "sync_partial_err:{}\r\n",
+        Stats::get(&stats.sync_partial_err)
+    );
+    let _ = write!(out, "expired_keys:{}\r\n", Stats::get(&stats.expired_keys));
+    let _ = write!(out, "evicted_keys:{}\r\n", Stats::get(&stats.evicted_keys));
+    let _ = write!(
+        out,
+        "evicted_clients:{}\r\n",
+        Stats::get(&stats.evicted_clients)
+    );
+    let _ = write!(
+        out,
+        "active_defrag_hits:{}\r\n",
+        Stats::get(&stats.active_defrag_hits)
+    );
+    let _ = write!(
+        out,
+        "active_defrag_misses:{}\r\n",
+        Stats::get(&stats.active_defrag_misses)
+    );
+}
+
+fn replication_section(server: &Server, out: &mut String) {
+    let replication = &server.replication;
+    out.push_str("# Replication\r\n");
+    match replication.primary() {
+        None => out.push_str("role:master\r\n"),
+        Some(primary) => {
+            out.push_str("role:slave\r\n");
+            let _ = write!(out, "master_host:{}\r\n", primary.host);
+            let _ = write!(out, "master_port:{}\r\n", primary.port);
+            let up = primary.state == LinkState::Connected;
+            let _ = write!(
+                out,
+                "master_link_status:{}\r\n",
+                if up { "up" } else { "down" }
+            );
+            let _ = write!(
+                out,
+                "master_last_io_seconds_ago:{}\r\n",
+                primary
+                    .last_io
+                    .map_or(-1, |last_io| last_io.elapsed().as_secs() as i64)
+            );
+            let _ = write!(
+                out,
+                "master_sync_in_progress:{}\r\n",
+                u8::from(primary.state == LinkState::Transfer)
+            );
+            if !up {
+                let _ = write!(
+                    out,
+                    "master_link_down_since_seconds:{}\r\n",
+                    primary
+                        .down_since
+                        .map_or(-1, |down_since| down_since.elapsed().as_secs() as i64)
+                );
+            }
+            let _ = write!(out, "slave_repl_offset:{}\r\n", replication.offset());
+        }
+    }
+    let replicas = replication.replicas();
+    let _ = write!(out, "connected_slaves:{}\r\n", replicas.len());
+    for (index, replica) in replicas.iter().enumerate() {
+        let _ = write!(
+            out,
+            "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+            index,
+            replica.ip,
+            replica.port,
+            if replica.online {
+                "online"
+            } else {
+                "wait_bgsave"
+            },
+            replica.ack_offset,
+            replica.lag.as_secs()
+        );
+    }
+    let _ = write!(
+        out,
+        "master_failover_state:{}\r\n",
+        replication.failover_state().name()
+    );
+    let history = replication.history();
+    let _ = write!(out, "master_replid:{}\r\n", history.replid);
+    let _ = write!(out, "master_replid2:{}\r\n", history.replid2);
+    let _ = write!(out, "master_repl_offset:{}\r\n", replication.offset());
+    // Redis reports the offset of the first byte of the new history
+    let _ = write!(
+        out,
+        "second_repl_offset:{}\r\n",
+        history.second_offset.map_or(-1, |offset| offset as i64 + 1)
+    );
+    let backlog = replication.backlog_info();
+    let _ = write!(out, "repl_backlog_active:{}\r\n", u8::from(backlog.active));
+    let _ = write!(out, "repl_backlog_size:{}\r\n", backlog.size);
+    let _ = write!(
+        out,
+        "repl_backlog_first_byte_offset:{}\r\n",
+        backlog.first_byte_offset
+    );
+    let _ = write!(out, "repl_backlog_histlen:{}\r\n", backlog.histlen);
+}
+
+fn cpu_section(_server: &Server, out: &mut String) {
+    let (sys, user, sys_children, user_children) = stats::cpu_usage();
+    out.push_str("# CPU\r\n");
+    let _ = write!(out, "used_cpu_sys:{:.6}\r\n", sys);
+    let _ = write!(out, "used_cpu_user:{:.6}\r\n", user);
+    let _ = write!(out, "used_cpu_sys_children:{:.6}\r\n", sys_children);
+    let _ = write!(out, "used_cpu_user_children:{:.6}\r\n", user_children);
+}
+
+fn latencystats_section(server: &Server, out: &mut String) {
+    let percentiles = server
+        .config
+        .read()
+        .unwrap()
+        .latency_tracking_info_percentiles
+        .clone();
+    out.push_str("# Latencystats\r\n");
+    for (name, histogram) in server.command_latency.called() {
+        let values: Vec<String> = percentiles
+            .iter()
+            .map(|p| format!("p{}={:.3}", p, histogram.percentile(*p) as f64))
+            .collect();
+        let _ = write!(
+            out,
+            "latency_percentiles_usec_{}:{}\r\n",
+            name,
+            values.join(",")
+        );
+    }
+}
+
+fn cron_section(server: &Server, out: &mut String) {
+    out.push_str("# Cron\r\n");
+    out.push_str(&server.cron.info_lines());
+}
+
+fn keyspace_section(server: &Server, out: &mut String) {
+    out.push_str("# Keyspace\r\n");
+    for index in 0..server.storage.len() {
+        let db = server.storage.db(index);
+        let db_size = db.dbsize();
+        if db_size > 0 {
+            let _ = write!(
+                out,
+                "db{}:keys={},expires={},avg_ttl=0\r\n",
+                index,
+                db_size,
+                db.expires_count()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(info: &str) -> Vec<&str> {
+        info.lines().filter(|l| l.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn test_section_selection() {
+        let server = Server::default();
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let default = info(&server, &[]);
+        assert_eq!(
+            headers(&default),
+            [
+                "# Server",
+                "# Clients",
+                "# Memory",
+                "# Persistence",
+                "# Stats",
+                "# Replication",
+                "# CPU",
+                "# Keyspace"
+            ]
+        );
+        assert!(headers(&info(&server, &args(&["all"]))).contains(&"# Latencystats"));
+        assert_eq!(
+            headers(&info(&server, &args(&["CPU", "memory"]))),
+            ["# Memory", "# CPU"]
+        );
+        assert_eq!(info(&server, &args(&["nosuchsection"])), "");
+    }
+
+    #[test]
+    fn test_stats_fields() {
+        let server = Server::default();
+        Stats::add(&server.stats.commands_processed, 3);
+        let stats = info(&server, &["stats".to_string()]);
+        assert!(stats.contains("total_commands_processed:3\r\n"));
+        let memory = info(&server, &["memory".to_string()]);
+        assert!(!memory.contains("used_memory:0\r\n"));
+    }
+}
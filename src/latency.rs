@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::storage::now_ms;
+
+/// Samples kept per event, like Redis' LATENCY_TS_LEN.
+const HISTORY_LEN: usize = 160;
+
+/// A latency spike: when it happened (Unix seconds) and how long it took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub time: u64,
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct EventHistory {
+    samples: VecDeque<Sample>,
+    max_ms: u64,
+}
+
+/// Latest and worst spike of an event, as reported by LATENCY LATEST.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatestSpike {
+    pub event: String,
+    pub time: u64,
+    pub latency_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Records operations slower than `latency-monitor-threshold`, grouped by
+/// event class such as `command` or `expire-cycle`.
+#[derive(Debug, Default)]
+pub struct LatencyMonitor {
+    events: Mutex<BTreeMap<String, EventHistory>>,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `elapsed` for `event` if monitoring is enabled (a non-zero
+    /// threshold) and the operation reached the threshold.
+    pub fn record(&self, event: &str, elapsed: Duration, threshold_ms: u64) {
+        let latency_ms = elapsed.as_millis() as u64;
+        if threshold_ms == 0 || latency_ms < threshold_ms {
+            return;
+        }
+        self.add_sample(event, now_ms() / 1000, latency_ms);
+    }
+
+    fn add_sample(&self, event: &str, time: u64, latency_ms: u64) {
+        let mut events = self.events.lock().unwrap();
+        let history = events.entry(event.to_string()).or_default();
+        history.max_ms = history.max_ms.max(latency_ms);
+
+        // Spikes within the same second are merged, keeping the worst
+        if let Some(last) = history.samples.back_mut()
+            && last.time == time
+        {
+            last.latency_ms = last.latency_ms.max(latency_ms);
+            return;
+        }
+        if history.samples.len() == HISTORY_LEN {
+            history.samples.pop_front();
+        }
+        history.samples.push_back(Sample { time, latency_ms });
+    }
+
+    pub fn latest(&self) -> Vec<LatestSpike> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter_map(|(event, history)| {
+                history.samples.back().map(|sample| LatestSpike {
+                    event: event.clone(),
+                    time: sample.time,
+                    latency_ms: sample.latency_ms,
+                    max_ms: history.max_ms,
+                })
+            })
+            .collect()
+    }
+
+    pub fn history(&self, event: &str) -> Vec<Sample> {
+        let events = self.events.lock().unwrap();
+        events
+            .get(event)
+            .map(|history| history.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forgets the given events, or all of them when `events` is empty.
+    /// Returns how many were reset.
+    pub fn reset(&self, events: &[String]) -> usize {
+        let mut all = self.events.lock().unwrap();
+        if events.is_empty() {
+            let count = all.len();
+            all.clear();
+            return count;
+        }
+        events.iter().filter(|e| all.remove(*e).is_some()).count()
+    }
+
+    /// A human readable analysis of the recorded spikes, for LATENCY DOCTOR.
+    pub fn doctor(&self, threshold_ms: u64) -> String {
+        if threshold_ms == 0 {
+            return "I'm sorry, Dave, I can't do that. Latency monitoring is disabled in this \
+                    server. You may use \"CONFIG SET latency-monitor-threshold <milliseconds>.\" \
+                    in order to enable it.\n"
+                .to_string();
+        }
+
+        let events = self.events.lock().unwrap();
+        if events.is_empty() {
+            return "Dave, no latency spike was observed during the lifetime of this server, \
+                    not in the slightest bit. I honestly think you ought to sleep tonight.\n"
+                .to_string();
+        }
+
+        let mut report = String::from(
+            "Dave, I have observed latency spikes in this server. You don't mind talking \
+             about it, do you Dave?\n\n",
+        );
+        for (i, (event, history)) in events.iter().enumerate() {
+            let samples: Vec<u64> = history.samples.iter().map(|s| s.latency_ms).collect();
+            let avg = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+            let deviation =
+                samples.iter().map(|&s| (s as f64 - avg).abs()).sum::<f64>() / samples.len() as f64;
+            let period = match (history.samples.front(), history.samples.back()) {
+                (Some(first), Some(last)) if samples.len() > 1 => {
+                    (last.time - first.time) as f64 / (samples.len() - 1) as f64
+                }
+                _ => 0.0,
+            };
+            report.push_str(&format!(
+                "{}. {}: {} latency spikes (average {:.0}ms, mean deviation {:.0}ms, period {:.2} sec). Worst all time event {}ms.\n",
+                i + 1,
+                event,
+                samples.len(),
+                avg,
+                deviation,
+                period,
+                history.max_ms
+            ));
+        }
+
+        report.push_str("\nI have a few advices for you:\n\n");
+        if events.contains_key("command") {
+            report.push_str(
+                "- Check your slow commands: O(N) commands such as KEYS, SMEMBERS or HGETALL \
+                 against big values can block the server for a long time.\n",
+            );
+        }
+        if events.contains_key("fast-command") {
+            report.push_str(
+                "- Commands that should run in constant time are slow: the system may be \
+                 swapping or the CPU may be saturated.\n",
+            );
+        }
+        if events.contains_key("expire-cycle") {
+            report.push_str(
+                "- Deleting expired keys is slow: avoid having many keys expire at the same \
+                 time by spreading their TTLs.\n",
+            );
+        }
+        report
+    }
+}
+
+/// Sub-buckets per power of two; 16 keeps values within ~6% of the truth.
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Values up to 2^40 microseconds (about 12 days) are tracked.
+const MAGNITUDES: usize = 40 - SUB_BUCKET_BITS as usize + 1;
+
+/// A lock-free log-linear histogram of durations in microseconds, in the
+/// spirit of HdrHistogram: every power of two is split into equal
+/// sub-buckets, so the relative error is the same at every scale.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            buckets: (0..(MAGNITUDES + 1) * SUB_BUCKETS)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn bucket_index(usec: u64) -> usize {
+        if usec < SUB_BUCKETS as u64 {
+            return usec as usize;
+        }
+        let magnitude = (63 - usec.leading_zeros() - SUB_BUCKET_BITS + 1) as usize;
+        let sub = (usec >> (magnitude - 1)) as usize & (SUB_BUCKETS - 1);
+        (magnitude.min(MAGNITUDES) * SUB_BUCKETS + sub).min((MAGNITUDES + 1) * SUB_BUCKETS - 1)
+    }
+
+    /// The largest value that falls in a bucket.
+    fn bucket_upper_bound(index: usize) -> u64 {
+        let (magnitude, sub) = (index / SUB_BUCKETS, index % SUB_BUCKETS);
+        if magnitude == 0 {
+            return sub as u64;
+        }
+        (((SUB_BUCKETS + sub + 1) as u64) << (magnitude - 1)) - 1
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let usec = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[Self::bucket_index(usec)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+
+    /// The value below which `percentile` percent of samples fall.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let target = ((percentile / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Self::bucket_upper_bound(index);
+            }
+        }
+        Self::bucket_upper_bound(counts.len() - 1)
+    }
+
+    /// Cumulative counts at power-of-two bounds, from the first bound with
+    /// samples up to the one covering the slowest sample.
+    pub fn power_of_two_buckets(&self) -> Vec<(u64, u64)> {
+        let counts = self.counts();
+        let Some(last) = counts.iter().rposition(|&c| c > 0) else {
+            return Vec::new();
+        };
+        let max = Self::bucket_upper_bound(last);
+        let mut buckets = Vec::new();
+        let mut bound = 1u64;
+        loop {
+            let cumulative: u64 = counts
+                .iter()
+                .enumerate()
+                .take_while(|(i, _)| Self::bucket_upper_bound(*i) < bound)
+                .map(|(_, c)| c)
+                .sum();
+            if cumulative > 0 {
+                buckets.push((bound, cumulative));
+            }
+            if bound > max {
+                return buckets;
+            }
+            bound *= 2;
+        }
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One histogram per command, created up front so recording never locks.
+#[derive(Debug, Default)]
+pub struct CommandHistograms {
+    histograms: HashMap<&'static str, Histogram>,
+}
+
+impl CommandHistograms {
+    pub fn new(names: impl IntoIterator<Item = &'static str>) -> Self {
+        CommandHistograms {
+            histograms: names.into_iter().map(|n| (n, Histogram::new())).collect(),
+        }
+    }
+
+    pub fn record(&self, name: &str, elapsed: Duration) {
+        if let Some(histogram) = self.histograms.get(name) {
+            histogram.record(elapsed);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Histogram> {
+        self.histograms.get(name)
+    }
+
+    /// Commands that have been called at least once, sorted by name.
+    pub fn called(&self) -> Vec<(&'static str, &Histogram)> {
+        let mut called: Vec<_> = self
+            .histograms
+            .iter()
+            .filter(|(_, h)| h.total() > 0)
+            .map(|(name, h)| (*name, h))
+            .collect();
+        called.sort_by_key(|(name, _)| *name);
+        called
+    }
+
+    pub fn reset(&self) {
+        for histogram in self.histograms.values() {
+            histogram.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold_and_merging() {
+        let monitor = LatencyMonitor::new();
+        monitor.record("command", Duration::from_millis(50), 0);
+        monitor.record("command", Duration::from_millis(5), 10);
+        assert!(monitor.latest().is_empty());
+
+        monitor.add_sample("command", 100, 20);
+        monitor.add_sample("command", 100, 30);
+        monitor.add_sample("command", 101, 15);
+        assert_eq!(
+            monitor.history("command"),
+            vec![
+                Sample {
+                    time: 100,
+                    latency_ms: 30
+                },
+                Sample {
+                    time: 101,
+                    latency_ms: 15
+                },
+            ]
+        );
+        assert_eq!(
+            monitor.latest(),
+            vec![LatestSpike {
+                event: "command".to_string(),
+                time: 101,
+                latency_ms: 15,
+                max_ms: 30
+            }]
+        );
+        assert!(monitor.doctor(10).contains("1. command: 2 latency spikes"));
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let histogram = Histogram::new();
+        for usec in 1..=1000 {
+            histogram.record(Duration::from_micros(usec));
+        }
+        assert_eq!(histogram.total(), 1000);
+        for (percentile, expected) in [(50.0, 500.0), (99.0, 990.0), (99.9, 999.0)] {
+            let value = histogram.percentile(percentile) as f64;
+            assert!(
+                (value - expected).abs() / expected < 0.07,
+                "p{} = {}",
+                percentile,
+                value
+            );
+        }
+
+        let buckets = histogram.power_of_two_buckets();
+        assert_eq!(buckets.first(), Some(&(2, 1)));
+        assert_eq!(buckets.last().map(|b| b.1), Some(1000));
+    }
+
+    #[test]
+    fn test_bucket_bounds_are_monotonic() {
+        let mut previous = None;
+        for usec in (0..100_000u64).step_by(7) {
+            let index = Histogram::bucket_index(usec);
+            assert!(Histogram::bucket_upper_bound(index) >= usec);
+            if let Some(previous) = previous {
+                assert!(index >= previous);
+            }
+            previous = Some(index);
+        }
+    }
+
+    #[test]
+    fn test_history_is_bounded_and_resettable() {
+        let monitor = LatencyMonitor::new();
+        for time in 0..(HISTORY_LEN as u64 + 10) {
+            monitor.add_sample("expire-cycle", time, 1);
+        }
+        monitor.add_sample("command", 0, 1);
+        assert_eq!(monitor.history("expire-cycle").len(), HISTORY_LEN);
+        assert_eq!(monitor.history("expire-cycle")[0].time, 10);
+
+        assert_eq!(
+            monitor.reset(&["command".to_string(), "nope".to_string()]),
+            1
+        );
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.latest().is_empty());
+    }
+}
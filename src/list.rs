@@ -0,0 +1,308 @@
+use std::collections::{VecDeque, vec_deque};
+
+use crate::listpack::{self, Listpack};
+
+/// `list-max-listpack-size` by default: listpacks of up to 8kb.
+pub const DEFAULT_MAX_LISTPACK_SIZE: i64 = -2;
+
+/// Largest listpack a positive `list-max-listpack-size` allows, whatever
+/// its element count.
+const SIZE_SAFETY_LIMIT: usize = 8192;
+
+/// Whether a listpack of `bytes` holding `count` elements is past
+/// `list-max-listpack-size`: positive, the most elements it holds, and -1
+/// to -5 the most bytes, from 4kb to 64kb.
+fn exceeds(max_size: i64, bytes: usize, count: usize) -> bool {
+    if max_size >= 0 {
+        bytes > SIZE_SAFETY_LIMIT || count > max_size as usize
+    } else {
+        let level = (-max_size - 1).min(4) as u32;
+        bytes > 4096 << level
+    }
+}
+
+/// A list value, packed into a listpack while it's small, as most lists
+/// are, and converted to a deque once it grows past
+/// `list-max-listpack-size`. Deques stay deques when they shrink, as in
+/// Redis before 7.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum List {
+    Packed(Listpack),
+    Deque(VecDeque<String>),
+}
+
+impl Default for List {
+    fn default() -> Self {
+        List::Packed(Listpack::new())
+    }
+}
+
+impl List {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How the list is encoded, as OBJECT ENCODING replies it.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            List::Packed(_) => "listpack",
+            List::Deque(_) => "quicklist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            List::Packed(listpack) => listpack.len(),
+            List::Deque(deque) => deque.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push_front(&mut self, value: String) {
+        match self {
+            List::Packed(listpack) => listpack.push_front(&value),
+            List::Deque(deque) => deque.push_front(value),
+        }
+    }
+
+    pub fn push_back(&mut self, value: String) {
+        match self {
+            List::Packed(listpack) => listpack.push_back(&value),
+            List::Deque(deque) => deque.push_back(value),
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        match self {
+            List::Packed(listpack) => listpack.pop_front(),
+            List::Deque(deque) => deque.pop_front(),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        match self {
+            List::Packed(listpack) => listpack.pop_back(),
+            List::Deque(deque) => deque.pop_back(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        match self {
+            List::Packed(listpack) => listpack.get(index),
+            List::Deque(deque) => deque.get(index).map(String::as_str),
+        }
+    }
+
+    /// Replaces the element at `index`, returning whether there is one.
+    pub fn set(&mut self, index: usize, value: String) -> bool {
+        match self {
+            List::Packed(listpack) => listpack.set(index, &value),
+            List::Deque(deque) => match deque.get_mut(index) {
+                Some(item) => {
+                    *item = value;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            List::Packed(listpack) => listpack.clear(),
+            List::Deque(deque) => deque.clear(),
+        }
+    }
+
+    /// Keeps only the elements from `start` to `stop` inclusive, as far
+    /// as the list goes, or none if `stop` is before `start`.
+    pub fn trim(&mut self, start: usize, stop: usize) {
+        let stop = stop.min(self.len().saturating_sub(1));
+        if start > stop || self.is_empty() {
+            self.clear();
+            return;
+        }
+        match self {
+            List::Packed(listpack) => listpack.trim(start, stop),
+            List::Deque(deque) => {
+                deque.truncate(stop + 1);
+                deque.drain(..start);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            List::Packed(listpack) => Iter::Packed(listpack.iter()),
+            List::Deque(deque) => Iter::Deque(deque.iter()),
+        }
+    }
+
+    /// Converts a listpack grown past `max_size`, the
+    /// `list-max-listpack-size`, to a deque, as every write that adds to
+    /// a list does.
+    pub fn convert(&mut self, max_size: i64) {
+        if let List::Packed(listpack) = self
+            && exceeds(max_size, listpack.bytes(), listpack.len())
+        {
+            *self = List::Deque(listpack.iter().map(str::to_string).collect());
+        }
+    }
+
+    /// Packs a deque into a listpack if it fits within `max_size`, as a
+    /// list loaded or restored is.
+    pub fn pack(&mut self, max_size: i64) {
+        let List::Deque(deque) = self else {
+            return;
+        };
+        let mut listpack = Listpack::new();
+        for item in deque.iter() {
+            listpack.push_back(item);
+            if exceeds(max_size, listpack.bytes(), listpack.len()) {
+                return;
+            }
+        }
+        *self = List::Packed(listpack);
+    }
+}
+
+impl From<VecDeque<String>> for List {
+    fn from(deque: VecDeque<String>) -> Self {
+        List::Deque(deque)
+    }
+}
+
+impl From<Vec<String>> for List {
+    fn from(items: Vec<String>) -> Self {
+        List::Deque(items.into())
+    }
+}
+
+impl<'a> IntoIterator for &'a List {
+    type Item = &'a str;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The elements of a list, in either encoding.
+#[derive(Debug, Clone)]
+pub enum Iter<'a> {
+    Packed(listpack::Iter<'a>),
+    Deque(vec_deque::Iter<'a, String>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        match self {
+            Iter::Packed(iter) => iter.next(),
+            Iter::Deque(iter) => iter.next().map(String::as_str),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Iter::Packed(iter) => iter.size_hint(),
+            Iter::Deque(iter) => iter.size_hint(),
+        }
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Packed(iter) => iter.next_back(),
+            Iter::Deque(iter) => iter.next_back().map(String::as_str),
+        }
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(n: usize) -> Vec<String> {
+        (0..n).map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn test_converted_past_max_size() {
+        let mut list = List::new();
+        for item in items(128) {
+            list.push_back(item);
+            list.convert(128);
+        }
+        assert_eq!(list.encoding(), "listpack");
+        list.push_front("x".to_string());
+        list.convert(128);
+        assert_eq!(list.encoding(), "quicklist");
+        assert_eq!(list.get(0), Some("x"));
+        assert_eq!(list.iter().next_back(), Some("127"));
+
+        // Shrinking keeps the deque
+        list.trim(0, 0);
+        list.convert(128);
+        assert_eq!(list.encoding(), "quicklist");
+
+        // Negative sizes count bytes, 4kb for -1
+        let mut list = List::new();
+        list.push_back("x".repeat(4000));
+        list.convert(-1);
+        assert_eq!(list.encoding(), "listpack");
+        list.push_back("x".repeat(100));
+        list.convert(-1);
+        assert_eq!(list.encoding(), "quicklist");
+        list.convert(-2);
+        assert_eq!(list.encoding(), "quicklist");
+
+        // However few elements, a positive size packs 8kb at most
+        let mut list = List::new();
+        list.push_back("x".repeat(9000));
+        list.convert(128);
+        assert_eq!(list.encoding(), "quicklist");
+    }
+
+    #[test]
+    fn test_packed_when_loaded() {
+        let mut list = List::from(items(3));
+        assert_eq!(list.encoding(), "quicklist");
+        list.pack(DEFAULT_MAX_LISTPACK_SIZE);
+        assert_eq!(list.encoding(), "listpack");
+        assert!(list.iter().eq(["0", "1", "2"]));
+
+        let mut list = List::from(items(3));
+        list.pack(2);
+        assert_eq!(list.encoding(), "quicklist");
+    }
+
+    #[test]
+    fn test_encodings_behave_alike() {
+        let mut packed = List::new();
+        let mut deque = List::from(Vec::new());
+        for list in [&mut packed, &mut deque] {
+            list.push_back("b".to_string());
+            list.push_front("a".to_string());
+            list.push_back("c".to_string());
+            list.push_back("d".to_string());
+            assert!(list.set(2, "see".to_string()));
+            assert!(!list.set(4, "e".to_string()));
+            assert_eq!(list.pop_back().as_deref(), Some("d"));
+            list.trim(1, 5);
+            list.push_back("e".to_string());
+        }
+        assert_eq!(packed.encoding(), "listpack");
+        assert_eq!(deque.encoding(), "quicklist");
+        assert!(packed.iter().eq(deque.iter()));
+        assert!(packed.iter().rev().eq(["e", "see", "b"]));
+        assert_eq!(deque.get(1), Some("see"));
+    }
+}
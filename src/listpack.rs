@@ -0,0 +1,319 @@
+use std::ops::Range;
+
+/// Bytes Redis adds around a listpack's entries: the total size and
+/// element count in front, and an end marker.
+const OVERHEAD: usize = 7;
+
+/// Strings packed one after the other into a single buffer, the way Redis
+/// keeps small collections. An entry is its length as a varint, its bytes,
+/// then the size of those two again, written to be read backwards, so the
+/// buffer can be walked from either end.
+///
+/// Lookups walk the entries and every change but a push or pop at the
+/// back moves the ones after it, which costs little while the buffer is
+/// small and saves a heap allocation and pointer per element.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The size Redis would give the listpack, the entries and the header
+    /// and end marker around them.
+    pub fn bytes(&self) -> usize {
+        self.buf.len() + OVERHEAD
+    }
+
+    /// Bytes reserved for the listpack, counted as `bytes` counts them.
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity() + OVERHEAD
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.buf.shrink_to_fit();
+    }
+
+    pub fn push_front(&mut self, value: &str) {
+        self.buf.splice(0..0, encode(value));
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, value: &str) {
+        self.buf.extend(encode(value));
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let (payload, next) = self.entry_at(0);
+        let value = self.text(payload).to_string();
+        self.buf.drain(..next);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let start = self.entry_before(self.buf.len());
+        let (payload, _) = self.entry_at(start);
+        let value = self.text(payload).to_string();
+        self.buf.truncate(start);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        if index >= self.len {
+            return None;
+        }
+        let (payload, _) = self.entry_at(self.offset_of(index));
+        Some(self.text(payload))
+    }
+
+    /// Replaces the element at `index`, returning whether there is one.
+    pub fn set(&mut self, index: usize, value: &str) -> bool {
+        if index >= self.len {
+            return false;
+        }
+        let start = self.offset_of(index);
+        let (_, next) = self.entry_at(start);
+        self.buf.splice(start..next, encode(value));
+        true
+    }
+
+    /// Removes `count` elements from `index` on, all within the listpack.
+    pub fn remove(&mut self, index: usize, count: usize) {
+        let first = self.offset_of(index);
+        let mut end = first;
+        for _ in 0..count {
+            end = self.entry_at(end).1;
+        }
+        self.buf.drain(first..end);
+        self.len -= count;
+    }
+
+    /// Keeps only the elements from `start` to `stop` inclusive, both
+    /// within the listpack.
+    pub fn trim(&mut self, start: usize, stop: usize) {
+        let first = self.offset_of(start);
+        let mut end = first;
+        for _ in start..=stop {
+            end = self.entry_at(end).1;
+        }
+        self.buf.truncate(end);
+        self.buf.drain(..first);
+        self.len = stop - start + 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            listpack: self,
+            front: 0,
+            back: self.buf.len(),
+            remaining: self.len,
+        }
+    }
+
+    /// Where the entry at `index` starts.
+    fn offset_of(&self, index: usize) -> usize {
+        (0..index).fold(0, |at, _| self.entry_at(at).1)
+    }
+
+    /// The bytes of the entry starting at `at`, and where the next starts.
+    fn entry_at(&self, at: usize) -> (Range<usize>, usize) {
+        let mut len = 0;
+        let mut header = 0;
+        loop {
+            let byte = self.buf[at + header];
+            len |= ((byte & 0x7f) as usize) << (7 * header);
+            header += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let payload = at + header..at + header + len;
+        let next = payload.end + backlen_size(header + len);
+        (payload, next)
+    }
+
+    /// Where the entry ending at `end` starts, read from its backlen.
+    fn entry_before(&self, end: usize) -> usize {
+        let mut size = 0;
+        let mut read = 0;
+        loop {
+            let byte = self.buf[end - 1 - read];
+            size |= ((byte & 0x7f) as usize) << (7 * read);
+            read += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        end - read - size
+    }
+
+    fn text(&self, payload: Range<usize>) -> &str {
+        std::str::from_utf8(&self.buf[payload]).expect("entries are pushed as strings")
+    }
+}
+
+/// Bytes of a backlen holding `size`, seven bits to a byte.
+fn backlen_size(size: usize) -> usize {
+    (usize::BITS - size.leading_zeros()).div_ceil(7).max(1) as usize
+}
+
+/// An entry holding `value`: its length, its bytes, and the size of the
+/// two with the most significant seven bits first, only the first byte
+/// without the high bit set so it ends a backwards read.
+fn encode(value: &str) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(value.len() + 4);
+    let mut len = value.len();
+    while len >= 0x80 {
+        entry.push((len & 0x7f) as u8 | 0x80);
+        len >>= 7;
+    }
+    entry.push(len as u8);
+    entry.extend_from_slice(value.as_bytes());
+    let size = entry.len();
+    let groups = backlen_size(size);
+    for group in (0..groups).rev() {
+        let bits = ((size >> (7 * group)) & 0x7f) as u8;
+        let more = if group + 1 == groups { 0 } else { 0x80 };
+        entry.push(bits | more);
+    }
+    entry
+}
+
+/// The elements of a listpack, from either end.
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    listpack: &'a Listpack,
+    front: usize,
+    back: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let (payload, next) = self.listpack.entry_at(self.front);
+        self.front = next;
+        self.remaining -= 1;
+        Some(self.listpack.text(payload))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.back = self.listpack.entry_before(self.back);
+        self.remaining -= 1;
+        let (payload, _) = self.listpack.entry_at(self.back);
+        Some(self.listpack.text(payload))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listpack(values: &[&str]) -> Listpack {
+        let mut listpack = Listpack::new();
+        for value in values {
+            listpack.push_back(value);
+        }
+        listpack
+    }
+
+    #[test]
+    fn test_push_pop_both_ends() {
+        let long = "x".repeat(300);
+        let mut lp = listpack(&["a", &long]);
+        lp.push_front("");
+        lp.push_back("é");
+        assert_eq!(lp.len(), 4);
+        assert_eq!(lp.iter().collect::<Vec<_>>(), ["", "a", &long, "é"]);
+        assert_eq!(lp.iter().rev().collect::<Vec<_>>(), ["é", &long, "a", ""]);
+
+        assert_eq!(lp.pop_back().as_deref(), Some("é"));
+        assert_eq!(lp.pop_back().as_deref(), Some(long.as_str()));
+        assert_eq!(lp.pop_front().as_deref(), Some(""));
+        assert_eq!(lp.pop_front().as_deref(), Some("a"));
+        assert_eq!(lp.pop_front(), None);
+        assert!(lp.is_empty());
+        assert_eq!(lp.bytes(), OVERHEAD);
+    }
+
+    #[test]
+    fn test_entry_sizes() {
+        // One byte of length and one of backlen up to 126 bytes
+        let entry_size = |value: &str| listpack(&[value]).bytes() - OVERHEAD;
+        assert_eq!(entry_size("abc"), 5);
+        assert_eq!(entry_size(&"x".repeat(126)), 128);
+        assert_eq!(entry_size(&"x".repeat(127)), 130);
+        let values: Vec<String> = [0, 1, 127, 128, 16_383, 16_384, 70_000]
+            .iter()
+            .map(|&n| "v".repeat(n))
+            .collect();
+        let mut lp = Listpack::new();
+        for value in &values {
+            lp.push_back(value);
+        }
+        assert!(lp.iter().eq(values.iter().map(String::as_str)));
+        assert!(lp.iter().rev().eq(values.iter().rev().map(String::as_str)));
+    }
+
+    #[test]
+    fn test_get_set_trim() {
+        let mut lp = listpack(&["a", "b", "c", "d", "e"]);
+        assert_eq!(lp.get(2), Some("c"));
+        assert_eq!(lp.get(5), None);
+        assert!(lp.set(2, "see"));
+        assert!(lp.set(0, ""));
+        assert!(!lp.set(5, "f"));
+        assert_eq!(lp.iter().collect::<Vec<_>>(), ["", "b", "see", "d", "e"]);
+
+        lp.trim(1, 3);
+        assert_eq!(lp.iter().collect::<Vec<_>>(), ["b", "see", "d"]);
+        assert_eq!(lp, listpack(&["b", "see", "d"]));
+        lp.push_back("e");
+        lp.remove(1, 2);
+        assert_eq!(lp, listpack(&["b", "e"]));
+        lp.trim(0, 0);
+        assert_eq!(lp.pop_back().as_deref(), Some("b"));
+        assert!(lp.is_empty());
+    }
+}
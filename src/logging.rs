@@ -0,0 +1,413 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::RwLock;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter;
+use tracing_subscriber::fmt::{self, FmtContext, FormatEvent, FormatFields, format};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::storage::now_ms;
+use crate::trace;
+
+/// Log verbosity, from most to least chatty, as in redis.conf's `loglevel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.to_lowercase().as_str() {
+            "debug" => Some(Level::Debug),
+            "verbose" => Some(Level::Verbose),
+            "notice" => Some(Level::Notice),
+            "warning" => Some(Level::Warning),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+            Level::Notice => "notice",
+            Level::Warning => "warning",
+        }
+    }
+
+    /// The level of a `tracing` event.
+    fn of(level: &tracing::Level) -> Level {
+        match *level {
+            tracing::Level::TRACE => Level::Debug,
+            tracing::Level::DEBUG => Level::Verbose,
+            tracing::Level::INFO => Level::Notice,
+            _ => Level::Warning,
+        }
+    }
+
+    /// The marker Redis puts before each message.
+    fn symbol(self) -> char {
+        match self {
+            Level::Debug => '.',
+            Level::Verbose => '-',
+            Level::Notice => '*',
+            Level::Warning => '#',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Redis' own `pid:role date level message` lines.
+    Plain,
+    /// One JSON object per line, for log aggregation systems.
+    Json,
+}
+
+/// The level and format lines are written with, as `loglevel` and
+/// `log-format` last set them.
+static OPTIONS: RwLock<(Level, LogFormat)> = RwLock::new((Level::Notice, LogFormat::Plain));
+
+/// Where lines go; standard output when None.
+static FILE: RwLock<Option<File>> = RwLock::new(None);
+
+/// Installs the subscriber that writes the server's `tracing` events as
+/// log lines: `warn!` and `error!` at `warning`, `info!` at `notice`,
+/// `debug!` at `verbose` and `trace!` at `debug`. Spans go to
+/// `trace::layer`.
+pub fn init() {
+    let layer = fmt::layer()
+        .event_format(LineFormat)
+        .with_writer(|| LogWriter)
+        .with_filter(filter::dynamic_filter_fn(|metadata, _| {
+            metadata.is_event() && enabled(Level::of(metadata.level()))
+        }));
+    let _ = tracing_subscriber::registry()
+        .with(layer)
+        .with(trace::layer())
+        .try_init();
+}
+
+/// Sets the level and output format.
+pub fn set_options(level: Level, format: LogFormat) {
+    *OPTIONS.write().unwrap() = (level, format);
+}
+
+/// Appends to `path` from now on; an empty path logs to standard output.
+pub fn set_file(path: &str) -> io::Result<()> {
+    let file = if path.is_empty() {
+        None
+    } else {
+        Some(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    *FILE.write().unwrap() = file;
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    level >= OPTIONS.read().unwrap().0
+}
+
+/// Logs a message with extra key/value fields named at runtime, which a
+/// `tracing` event can't have. Plain lines append the fields as
+/// `key=value`; JSON lines make them members of the object.
+pub fn write_fields(level: Level, message: &str, fields: &[(&str, String)]) {
+    let (min_level, format) = *OPTIONS.read().unwrap();
+    if level < min_level {
+        return;
+    }
+    let line = format_line(format, level, now_ms(), message, fields);
+    // Logging must never take the server down
+    let _ = LogWriter.write_all(line.as_bytes());
+}
+
+/// Writes to the log file, or standard output when there is none.
+struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &*FILE.read().unwrap() {
+            Some(file) => (&mut &*file).write(buf),
+            None => io::stdout().lock().write(buf),
+        }
+    }
+
+    /// Writes a whole line while holding the lock, so lines aren't split
+    /// by a `logfile` change.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match &*FILE.read().unwrap() {
+            Some(file) => (&mut &*file).write_all(buf),
+            None => io::stdout().lock().write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &*FILE.read().unwrap() {
+            Some(file) => (&mut &*file).flush(),
+            None => io::stdout().lock().flush(),
+        }
+    }
+}
+
+/// Formats events in the format `log-format` names.
+struct LineFormat;
+
+impl<S, N> FormatEvent<S, N> for LineFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        let format = OPTIONS.read().unwrap().1;
+        let level = Level::of(event.metadata().level());
+        writer.write_str(&format_line(
+            format,
+            level,
+            now_ms(),
+            &fields.message,
+            &fields.fields,
+        ))
+    }
+}
+
+/// An event's message and its other fields.
+#[derive(Default)]
+struct EventFields {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl EventFields {
+    fn push(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.fields.push((field.name(), value));
+        }
+    }
+}
+
+impl Visit for EventFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field, format!("{:?}", value));
+    }
+}
+
+fn format_line(
+    format: LogFormat,
+    level: Level,
+    time_ms: u64,
+    message: &str,
+    fields: &[(&str, String)],
+) -> String {
+    let pid = std::process::id();
+    let mut line = String::new();
+    match format {
+        LogFormat::Plain => {
+            let _ = write!(
+                line,
+                "{}:M {} {} {}",
+                pid,
+                format_time(time_ms),
+                level.symbol(),
+                message
+            );
+            for (name, value) in fields {
+                if value.is_empty() || value.contains([' ', '"', '=']) {
+                    let _ = write!(line, " {}={:?}", name, value);
+                } else {
+                    let _ = write!(line, " {}={}", name, value);
+                }
+            }
+        }
+        LogFormat::Json => {
+            let _ = write!(
+                line,
+                "{{\"time\":\"{}\",\"pid\":{},\"level\":\"{}\",\"msg\":{}",
+                format_iso_time(time_ms),
+                pid,
+                level.name(),
+                json_string(message)
+            );
+            for (name, value) in fields {
+                let _ = write!(line, ",{}:{}", json_string(name), json_string(value));
+            }
+            line.push('}');
+        }
+    }
+    line.push('\n');
+    line
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// UTC `(year, month, day, hour, minute, second, millisecond)`.
+fn civil_time(time_ms: u64) -> (i64, u32, u32, u64, u64, u64, u64) {
+    let secs = time_ms / 1000;
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        time_ms % 1000,
+    )
+}
+
+/// `16 Oct 2026 09:30:00.123`, the timestamp format of Redis logs.
+fn format_time(time_ms: u64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, minute, second, ms) = civil_time(time_ms);
+    format!(
+        "{:02} {} {} {:02}:{:02}:{:02}.{:03}",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second,
+        ms
+    )
+}
+
+/// `2026-10-16T09:30:00.123Z`.
+fn format_iso_time(time_ms: u64) -> String {
+    let (year, month, day, hour, minute, second, ms) = civil_time(time_ms);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, ms
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-10-16 09:30:00.123 UTC
+    const TIME_MS: u64 = 1_792_143_000_123;
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(format_time(TIME_MS), "16 Oct 2026 09:30:00.123");
+        assert_eq!(format_iso_time(TIME_MS), "2026-10-16T09:30:00.123Z");
+        assert_eq!(format_iso_time(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_iso_time(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_line_formats() {
+        let fields = [("client", "3".to_string()), ("name", "a b".to_string())];
+        let plain = format_line(LogFormat::Plain, Level::Warning, TIME_MS, "hi", &fields);
+        assert_eq!(
+            plain,
+            format!(
+                "{}:M 16 Oct 2026 09:30:00.123 # hi client=3 name=\"a b\"\n",
+                std::process::id()
+            )
+        );
+
+        let json = format_line(
+            LogFormat::Json,
+            Level::Notice,
+            TIME_MS,
+            "say \"hi\"",
+            &fields,
+        );
+        assert_eq!(
+            json,
+            format!(
+                "{{\"time\":\"2026-10-16T09:30:00.123Z\",\"pid\":{},\"level\":\"notice\",\"msg\":\"say \\\"hi\\\"\",\"client\":\"3\",\"name\":\"a b\"}}\n",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn test_events_are_lines() {
+        #[derive(Clone, Default)]
+        struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            fmt::layer()
+                .event_format(LineFormat)
+                .with_writer(move || writer.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(client = 3, name = "a b", "hi {}", "there");
+        });
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(line.starts_with(&format!("{}:M ", std::process::id())));
+        assert!(
+            line.ends_with(" # hi there client=3 name=\"a b\"\n"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(Level::parse("VERBOSE"), Some(Level::Verbose));
+        assert_eq!(Level::parse("loud"), None);
+        assert!(Level::Warning > Level::Notice);
+    }
+}
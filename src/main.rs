@@ -1,103 +1,363 @@
+pub mod acl;
+pub mod aof;
+pub mod binary;
+pub mod blocking;
+pub mod bloom;
+pub mod clients;
+pub mod cluster;
 pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod cron;
+pub mod crypto;
+pub mod daemon;
+pub mod defrag;
+pub mod digest;
+pub mod docs;
+pub mod engine;
+pub mod evict;
+pub mod geo;
+pub mod hash;
+pub mod info;
+pub mod latency;
+pub mod list;
+pub mod listpack;
+pub mod logging;
+pub mod memory;
+pub mod notify;
 pub mod parser;
+pub mod pattern;
+pub mod ratelimit;
+pub mod rdb;
+pub mod replication;
+pub mod reply;
+pub mod server;
+pub mod stats;
 pub mod storage;
+pub mod stream;
+pub mod trace;
+pub mod zset;
 
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket};
 
-use crate::commands::{Command, encode_resp, execute};
-use crate::parser::{Resp, parse};
-use crate::storage::Storage;
+use crate::config::Config;
+use crate::connection::handle_client;
+use crate::server::Server;
 
-#[tokio::main]
-async fn main() {
-    let storage = Arc::new(Storage::new());
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("--check-aof") => std::process::exit(aof::check_tool(&args[1..])),
+        Some("--check-rdb") => std::process::exit(rdb::check_tool(&args[1..])),
+        _ => {}
+    }
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("*** FATAL CONFIG FILE ERROR *** {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Forking is only safe while single-threaded, so before the runtime
+    if config.daemonize
+        && let Err(e) = daemon::daemonize()
+    {
+        eprintln!("Can't daemonize: {}", e);
+        std::process::exit(1);
+    }
+
+    logging::init();
+    logging::set_options(config.loglevel, config.log_format);
+    trace::set_log_spans(config.command_tracing);
+    if let Err(e) = logging::set_file(&config.logfile) {
+        eprintln!("Can't open the log file '{}': {}", config.logfile, e);
+        std::process::exit(1);
+    }
+
+    let pidfile = match (config.pidfile.as_str(), config.daemonize) {
+        ("", false) => None,
+        ("", true) => Some(daemon::DEFAULT_PIDFILE.to_string()),
+        (path, _) => Some(path.to_string()),
+    };
+    if let Some(path) = &pidfile
+        && let Err(e) = daemon::write_pidfile(path)
+    {
+        tracing::warn!("Failed to write PID file '{}': {}", path, e);
+    }
 
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
-    println!("ReRedis server listening on 127.0.0.1:6379");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the tokio runtime");
+    runtime.block_on(run(config, pidfile.as_deref()));
+}
 
-    // Spawn a background task to periodically clean up expired keys
-    let cleanup_storage = Arc::clone(&storage);
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
-        loop {
-            interval.tick().await;
-            cleanup_storage.run_expiry_cleanup();
+async fn run(config: Config, pidfile: Option<&str>) {
+    let listeners = match bind_listeners(&config) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            tracing::warn!("Failed to bind {}:{}: {}", config.bind, config.port, e);
+            exit_fatal(pidfile);
         }
-    });
+    };
+    tracing::info!(
+        "ReRedis server listening on {}:{} ({} acceptor{})",
+        config.bind,
+        config.port,
+        listeners.len(),
+        if listeners.len() == 1 { "" } else { "s" }
+    );
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                println!("New connection from: {}", addr);
-                let client_storage = Arc::clone(&storage);
-                tokio::spawn(async move {
-                    handle_client(stream, client_storage).await;
-                });
-            }
-            Err(e) => {
-                eprintln!("Failed to accept connection: {}", e);
+    let keyring = match crypto::Keyring::load(&config.encryption_key_file) {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            tracing::warn!("Can't load the encryption keys: {}", e);
+            exit_fatal(pidfile);
+        }
+    };
+    let mut server = Server::new(config);
+    server.keyring = keyring;
+    let server = Arc::new(server);
+    load_data(&server, pidfile);
+
+    // All periodic background work
+    tokio::spawn(cron::run(Arc::clone(&server)));
+    // The link to the primary, whenever this server is a replica
+    tokio::spawn(replication::run(Arc::clone(&server)));
+
+    let mut acceptors = Vec::new();
+    for listener in listeners {
+        let server = Arc::clone(&server);
+        acceptors.push(tokio::spawn(accept_loop(listener, server)));
+    }
+    let serve = async {
+        for acceptor in acceptors {
+            let _ = acceptor.await;
+        }
+    };
+    tokio::select! {
+        _ = serve => {}
+        signal = shutdown_signal() => {
+            tracing::warn!("Received {}, scheduling shutdown...", signal);
+        }
+    }
+
+    server.aof.stop();
+    trace::shutdown();
+    // With save rules configured the dataset is saved on the way out
+    if !server.config.read().unwrap().save_rules.is_empty() {
+        rdb::wait_bgsave(&server);
+        tracing::info!("Saving the final RDB snapshot before exiting.");
+        if rdb::save(&server).is_err() {
+            tracing::warn!("Error trying to save the DB before exiting.");
+        }
+    }
+
+    if let Some(path) = pidfile {
+        daemon::remove_pidfile(path);
+    }
+    tracing::warn!("ReRedis is now ready to exit, bye bye...");
+}
+
+/// Loads the dataset: from the append-only file when `appendonly` is on,
+/// otherwise from the RDB file. A file that can't be loaded is fatal, so bad
+/// data is never silently replaced by an empty dataset.
+fn load_data(server: &Server, pidfile: Option<&str>) {
+    let (appendonly, aof_path, fsync) = {
+        let config = server.config.read().unwrap();
+        (config.appendonly, config.aof_path(), config.appendfsync)
+    };
+    if !appendonly {
+        load_rdb(server, pidfile);
+    } else if aof_path.exists() {
+        let keyring = server.keyring.as_ref();
+        let (len, encrypted) = load_aof(server, &aof_path, pidfile);
+        // Records can't be appended to a file encrypted differently, so
+        // turning encryption on or off rewrites it
+        let result = if encrypted == keyring.is_some() {
+            server.aof.open(&aof_path, fsync, len, keyring)
+        } else {
+            tracing::info!(
+                "Rewriting the append only file {} encryption",
+                if encrypted { "without" } else { "with" }
+            );
+            server.aof.start(&aof_path, fsync, &server.storage, keyring)
+        };
+        if let Err(e) = result {
+            tracing::warn!("Can't open the append-only file: {}", e);
+            exit_fatal(pidfile);
+        }
+    } else {
+        // Start the new file from whatever the RDB file holds
+        load_rdb(server, pidfile);
+        if let Err(e) = server
+            .aof
+            .start(&aof_path, fsync, &server.storage, server.keyring.as_ref())
+        {
+            tracing::warn!("Can't open the append-only file: {}", e);
+            exit_fatal(pidfile);
+        }
+    }
+    // Replaying counted every command as a change
+    server
+        .stats
+        .dirty
+        .store(0, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Replays the append-only file, returning the length to truncate it to
+/// when its tail had to be left out, and whether it is encrypted.
+fn load_aof(server: &Server, path: &std::path::Path, pidfile: Option<&str>) -> (Option<u64>, bool) {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::warn!("Can't open {}: {}", path.display(), e);
+            exit_fatal(pidfile);
+        }
+    };
+    let load_truncated = server.config.read().unwrap().aof_load_truncated;
+    let start = std::time::Instant::now();
+    match aof::load(&data, server, load_truncated) {
+        Ok(stats) => {
+            tracing::info!(
+                "DB loaded from append only file: {:.3} seconds ({} commands)",
+                start.elapsed().as_secs_f64(),
+                stats.commands
+            );
+            (
+                (stats.valid_len < data.len() as u64).then_some(stats.valid_len),
+                crypto::is_encrypted(&data),
+            )
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Fatal error loading the AOF {}: {}. Exiting.",
+                path.display(),
+                e
+            );
+            exit_fatal(pidfile);
+        }
+    }
+}
+
+fn load_rdb(server: &Server, pidfile: Option<&str>) {
+    let (path, verify_checksum) = {
+        let config = server.config.read().unwrap();
+        (config.rdb_path(), config.rdbchecksum)
+    };
+    let path = path.as_path();
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!("Can't open {}: {}", path.display(), e);
+            exit_fatal(pidfile);
+        }
+    };
+    let start = std::time::Instant::now();
+    let loaded = crypto::open_file(&data, server.keyring.as_ref())
+        .and_then(|data| rdb::load(&data, &server.storage, verify_checksum));
+    match loaded {
+        Ok(stats) => {
+            tracing::info!(
+                "DB loaded from disk: {:.3} seconds ({} keys, {} expired, {} skipped)",
+                start.elapsed().as_secs_f64(),
+                stats.keys,
+                stats.expired,
+                stats.skipped
+            );
+            if stats.skipped > 0 {
+                tracing::warn!(
+                    "{} keys of unsupported types were not loaded",
+                    stats.skipped
+                );
             }
         }
+        Err(e) => {
+            tracing::warn!(
+                "Fatal error loading the DB {}: {}. Exiting.",
+                path.display(),
+                e
+            );
+            exit_fatal(pidfile);
+        }
     }
 }
 
-async fn handle_client(mut stream: tokio::net::TcpStream, storage: Arc<Storage>) {
-    let mut buffer = vec![0u8; 65536];
-    let mut accumulated = Vec::new();
+fn exit_fatal(pidfile: Option<&str>) -> ! {
+    if let Some(path) = pidfile {
+        daemon::remove_pidfile(path);
+    }
+    std::process::exit(1);
+}
 
+/// Resolves with the signal's name once SIGTERM or SIGINT arrives.
+#[cfg(unix)]
+async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+    let (Ok(mut term), Ok(mut int)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+/// Binds one listener per acceptor. With more than one acceptor every socket
+/// sets SO_REUSEPORT so the kernel spreads incoming connections across them.
+fn bind_listeners(config: &Config) -> std::io::Result<Vec<TcpListener>> {
+    let addr: std::net::SocketAddr = format!("{}:{}", config.bind, config.port)
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    let mut acceptors = config.io_acceptors;
+    if acceptors > 1 && cfg!(not(unix)) {
+        tracing::warn!("io-acceptors > 1 requires SO_REUSEPORT, using a single acceptor");
+        acceptors = 1;
+    }
+
+    let mut listeners = Vec::with_capacity(acceptors);
+    for _ in 0..acceptors {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        #[cfg(unix)]
+        if acceptors > 1 {
+            socket.set_reuseport(true)?;
+        }
+        socket.bind(addr)?;
+        listeners.push(socket.listen(1024)?);
+    }
+    Ok(listeners)
+}
+
+async fn accept_loop(listener: TcpListener, server: Arc<Server>) {
     loop {
-        match stream.read(&mut buffer).await {
-            Ok(0) => {
-                // Connection closed
-                break;
-            }
-            Ok(n) => {
-                accumulated.extend_from_slice(&buffer[..n]);
-
-                // Process all complete commands in the buffer
-                loop {
-                    if accumulated.is_empty() {
-                        break;
-                    }
-
-                    match parse(&accumulated) {
-                        Ok((resp, consumed)) => {
-                            // Remove consumed bytes from buffer
-                            accumulated.drain(..consumed);
-
-                            // Execute the command
-                            let response = match Command::from_resp(&resp) {
-                                Ok(cmd) => {
-                                    // Handle QUIT command specially
-                                    if cmd.name == "QUIT" {
-                                        let resp = encode_resp(&Resp::Simple("OK".to_string()));
-                                        let _ = stream.write_all(&resp).await;
-                                        return;
-                                    }
-                                    execute(&cmd, &storage)
-                                }
-                                Err(e) => Resp::Error(e),
-                            };
-
-                            // Encode and send response
-                            let encoded = encode_resp(&response);
-                            if let Err(e) = stream.write_all(&encoded).await {
-                                eprintln!("Failed to write response: {}", e);
-                                return;
-                            }
-                        }
-                        Err(_) => {
-                            // Incomplete data, wait for more
-                            break;
-                        }
-                    }
-                }
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tracing::debug!("Accepted {}", addr);
+                let server = Arc::clone(&server);
+                tokio::spawn(async move {
+                    handle_client(stream, server).await;
+                });
             }
             Err(e) => {
-                eprintln!("Error reading from socket: {}", e);
-                break;
+                tracing::warn!("Failed to accept connection: {}", e);
             }
         }
     }
@@ -0,0 +1,237 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+/// The allocator doing the actual work, chosen with the `jemalloc` and
+/// `mimalloc` features; the system one by default.
+#[cfg(feature = "jemalloc")]
+static INNER: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+#[cfg(feature = "mimalloc")]
+static INNER: mimalloc::MiMalloc = mimalloc::MiMalloc;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+static INNER: std::alloc::System = std::alloc::System;
+
+/// The selected allocator, counting live bytes the way Redis' zmalloc does
+/// so INFO can report `used_memory`.
+pub struct CountingAllocator;
+
+static USED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { INNER.alloc(layout) };
+        if !ptr.is_null() {
+            USED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { INNER.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            USED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { INNER.dealloc(ptr, layout) };
+        USED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { INNER.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            if new_size > layout.size() {
+                USED.fetch_add(new_size - layout.size(), Ordering::Relaxed);
+            } else {
+                USED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new
+    }
+}
+
+/// Bytes currently allocated by the process.
+pub fn used_memory() -> usize {
+    USED.load(Ordering::Relaxed)
+}
+
+/// The highest `used_memory` seen by `update_peak`.
+pub fn peak_memory() -> usize {
+    update_peak();
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Folds the current usage into the peak; called periodically rather than
+/// on every allocation.
+pub fn update_peak() {
+    PEAK.fetch_max(used_memory(), Ordering::Relaxed);
+}
+
+/// Resident set size as reported by the kernel, or 0 where unavailable.
+pub fn rss_bytes() -> usize {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|kb| {
+                    kb.trim()
+                        .trim_end_matches("kB")
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                })
+        })
+        .map(|kb| kb * 1024)
+        .unwrap_or(0)
+}
+
+/// What the allocator itself reports, in bytes: memory handed out to the
+/// program, memory in the pages holding it, and memory mapped and resident.
+/// `active / allocated` is how fragmented the heap is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllocatorStats {
+    pub allocated: usize,
+    pub active: usize,
+    pub resident: usize,
+}
+
+/// The allocator's name and version, as `mem_allocator` in INFO.
+pub fn allocator_name() -> String {
+    #[cfg(feature = "jemalloc")]
+    {
+        let version = tikv_jemalloc_ctl::version::read().unwrap_or("unknown");
+        // Versions read like "5.3.0-0-g54eaed1d8b56b1aa528be3bdd1877e59c56fa90c"
+        format!("jemalloc-{}", version.split('-').next().unwrap_or(version))
+    }
+    #[cfg(feature = "mimalloc")]
+    {
+        // SAFETY: a plain query without arguments.
+        let version = unsafe { libmimalloc_sys::mi_version() };
+        // Encoded as major, then two digits each of minor and patch
+        format!(
+            "mimalloc-{}.{}.{}",
+            version / 10000,
+            version / 100 % 100,
+            version % 100
+        )
+    }
+    #[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+    {
+        "libc".to_string()
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn allocator_stats() -> AllocatorStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // The statistics are cached until the epoch moves on
+    let _ = epoch::advance();
+    AllocatorStats {
+        allocated: stats::allocated::read().unwrap_or(0),
+        active: stats::active::read().unwrap_or(0),
+        resident: stats::resident::read().unwrap_or(0),
+    }
+}
+
+#[cfg(feature = "mimalloc")]
+pub fn allocator_stats() -> AllocatorStats {
+    let (mut rss, mut commit, mut unused) = (0, 0, 0);
+    // SAFETY: every pointer is to a live local.
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut unused,
+            &mut unused,
+            &mut unused,
+            &mut rss,
+            &mut unused,
+            &mut commit,
+            &mut unused,
+            &mut unused,
+        );
+    }
+    // mimalloc doesn't track the bytes it hands out, we do
+    AllocatorStats {
+        allocated: used_memory(),
+        active: commit,
+        resident: rss,
+    }
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn allocator_stats() -> AllocatorStats {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        // SAFETY: mallinfo2 only reads glibc's own bookkeeping.
+        let info = unsafe { libc::mallinfo2() };
+        // Large blocks are mapped on their own, outside the arenas
+        AllocatorStats {
+            allocated: info.uordblks + info.hblkhd,
+            active: info.arena + info.hblkhd,
+            resident: rss_bytes(),
+        }
+    }
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    {
+        let used = used_memory();
+        AllocatorStats {
+            allocated: used,
+            active: used,
+            resident: rss_bytes(),
+        }
+    }
+}
+
+/// Formats a byte count like Redis' `bytesToHuman`: `1.50K`, `12.00M`, ...
+pub fn bytes_to_human(bytes: usize) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_to_human() {
+        assert_eq!(bytes_to_human(512), "512B");
+        assert_eq!(bytes_to_human(1536), "1.50K");
+        assert_eq!(bytes_to_human(3 * 1024 * 1024), "3.00M");
+    }
+
+    #[test]
+    fn test_allocator_stats() {
+        let block = vec![1u8; 1 << 20];
+        let stats = allocator_stats();
+        assert!(stats.allocated >= block.len());
+        assert!(stats.active >= stats.allocated);
+        drop(block);
+        assert!(!allocator_name().is_empty());
+    }
+
+    #[test]
+    fn test_allocations_are_counted() {
+        let block = vec![0u8; 1 << 20];
+        assert!(used_memory() >= block.len());
+        assert!(peak_memory() >= block.len());
+        drop(block);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::server::Server;
+use crate::stats::Stats;
+
+/// Something that happened to a key: written or deleted by a command, or
+/// removed on the server's own initiative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyspaceEvent {
+    /// Written by a command, and still there afterwards.
+    Set,
+    /// Removed by a command.
+    Deleted,
+    /// Removed by the expire cycle, or on access, once its TTL ran out.
+    Expired,
+    /// Removed to stay under maxmemory.
+    Evicted,
+}
+
+impl KeyspaceEvent {
+    /// The event's name, as in Redis keyspace notifications.
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyspaceEvent::Set => "set",
+            KeyspaceEvent::Deleted => "del",
+            KeyspaceEvent::Expired => "expired",
+            KeyspaceEvent::Evicted => "evicted",
+        }
+    }
+}
+
+/// Raises `event` for `key` of database `db`, after the change is made.
+/// Every place keys disappear without a command deleting them goes through
+/// here, as does call() for the keys of write commands. There's no pub/sub
+/// yet, so events are counted, logged and passed to the hooks registered
+/// with `Storage::on_key_change`.
+pub fn notify(server: &Server, event: KeyspaceEvent, db: usize, key: &str) {
+    let counter = match event {
+        KeyspaceEvent::Set | KeyspaceEvent::Deleted => None,
+        KeyspaceEvent::Expired => Some(&server.stats.expired_keys),
+        KeyspaceEvent::Evicted => Some(&server.stats.evicted_keys),
+    };
+    if let Some(counter) = counter {
+        Stats::add(counter, 1);
+        tracing::trace!("Key '{}' of db {} {}", key, db, event.name());
+    }
+    server.storage.key_changed(event, db, key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Command, execute};
+    use crate::connection::ConnectionContext;
+    use crate::cron::CRON_TASKS;
+    use crate::storage::ExpireOptions;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A command as a client sends it, its name first.
+    fn cmd(args: &[&str]) -> Command {
+        Command {
+            name: args[0].to_string(),
+            args: args[1..].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_expired_keys_are_counted() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = |args: &[&str]| execute(&cmd(args), &server, &mut ctx);
+        run(&["SET", "lazy", "v", "PX", "1"]);
+        run(&["SET", "active", "v", "PX", "1"]);
+        run(&["SET", "deleted", "v"]);
+        run(&["DEL", "deleted"]);
+        std::thread::sleep(Duration::from_millis(5));
+
+        run(&["GET", "lazy"]);
+        assert_eq!(Stats::get(&server.stats.expired_keys), 1);
+        let expire_cycle = CRON_TASKS.iter().find(|task| task.name == "expire-cycle");
+        (expire_cycle.unwrap().run)(&server, Duration::from_secs(1));
+        assert_eq!(Stats::get(&server.stats.expired_keys), 2);
+        assert_eq!(Stats::get(&server.stats.evicted_keys), 0);
+        assert_eq!(KeyspaceEvent::Evicted.name(), "evicted");
+    }
+
+    #[test]
+    fn test_key_change_hooks() {
+        let server = Server::default();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        server.storage.on_key_change(move |event, db, key| {
+            seen.lock().unwrap().push((event, db, key.to_string()));
+        });
+        let mut ctx = ConnectionContext::new(1);
+        let mut run = |args: &[&str]| execute(&cmd(args), &server, &mut ctx);
+        let take = || std::mem::take(&mut *events.lock().unwrap());
+        let event = |event, db, key: &str| (event, db, key.to_string());
+
+        run(&["SET", "a", "1"]);
+        run(&["GET", "a"]);
+        run(&["LPUSH", "a", "x"]);
+        run(&["DEL", "missing"]);
+        assert_eq!(take(), vec![event(KeyspaceEvent::Set, 0, "a")]);
+
+        run(&["RENAME", "a", "b"]);
+        run(&["MOVE", "b", "2"]);
+        assert_eq!(
+            take(),
+            vec![
+                event(KeyspaceEvent::Deleted, 0, "a"),
+                event(KeyspaceEvent::Set, 0, "b"),
+                event(KeyspaceEvent::Set, 2, "b"),
+                event(KeyspaceEvent::Deleted, 0, "b"),
+            ]
+        );
+
+        run(&["SET", "c", "v"]);
+        // Its deadline passed, without waiting for it to
+        let db = server.storage.db(0);
+        assert!(db.expire_at("c", 1, ExpireOptions::default()));
+        run(&["GET", "c"]);
+        run(&["SET", "d", "v"]);
+        run(&["FLUSHDB"]);
+        assert_eq!(
+            take(),
+            vec![
+                event(KeyspaceEvent::Set, 0, "c"),
+                event(KeyspaceEvent::Expired, 0, "c"),
+                event(KeyspaceEvent::Set, 0, "d"),
+            ]
+        );
+    }
+}
@@ -4,7 +4,12 @@ pub enum Resp {
     Error(String),
     Integer(i64),
     Bulk(Option<String>),
+    /// A bulk string that needn't be UTF-8, such as a DUMP payload.
+    Bytes(Vec<u8>),
     Array(Option<Vec<Resp>>),
+    /// Field/value pairs, a map frame to RESP3 clients and a flat array of
+    /// the fields and values to RESP2 ones.
+    Map(Vec<(Resp, Resp)>),
 }
 
 pub fn parse(buff: &[u8]) -> Result<(Resp, usize), String> {
@@ -13,7 +18,7 @@ pub fn parse(buff: &[u8]) -> Result<(Resp, usize), String> {
     }
 
     match buff[0] {
-        b'+' => parse_simple(&buff),
+        b'+' => parse_simple(buff),
         b'-' => parse_error(buff),
         b':' => parse_integer(buff),
         b'$' => parse_bulk(buff),
@@ -22,6 +27,85 @@ pub fn parse(buff: &[u8]) -> Result<(Resp, usize), String> {
     }
 }
 
+/// The most arguments a request may have, as Redis allows.
+const MAX_MULTIBULK_LEN: isize = 1024 * 1024;
+
+/// Arguments of one request, borrowed from the read buffer, and the number of
+/// bytes they occupied.
+pub type Request<'a> = (Vec<&'a [u8]>, usize);
+
+/// Parses one client request without copying its arguments: every element
+/// borrows from `buff`. Returns `Ok(None)` when more input is needed.
+///
+/// Besides RESP arrays of bulk strings this accepts inline commands
+/// (`PING\r\n`), which is what telnet-style clients send.
+pub fn parse_request(buff: &[u8]) -> Result<Option<Request<'_>>, String> {
+    if buff.is_empty() {
+        return Ok(None);
+    }
+
+    if buff[0] != b'*' {
+        let Ok((line, consumed)) = read_line(buff) else {
+            return Ok(None);
+        };
+        let parts = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|part| !part.is_empty())
+            .collect();
+        return Ok(Some((parts, consumed)));
+    }
+
+    let Ok((line, mut offset)) = read_line(&buff[1..]) else {
+        return Ok(None);
+    };
+    offset += 1;
+    let count = parse_len(line)?;
+    if count > MAX_MULTIBULK_LEN {
+        return Err("invalid multibulk length".into());
+    }
+
+    // Grown as arguments arrive, not by what the header claims
+    let mut parts = Vec::new();
+    for _ in 0..count {
+        let rest = &buff[offset..];
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        if rest[0] != b'$' {
+            return Err(format!(
+                "expected '$', got '{}'",
+                char::from(rest[0]).escape_default()
+            ));
+        }
+        let Ok((line, header)) = read_line(&rest[1..]) else {
+            return Ok(None);
+        };
+        let len = parse_len(line)?;
+        if len < 0 {
+            return Err("invalid bulk length".into());
+        }
+        let start = offset + 1 + header;
+        let end = start + len as usize;
+        if buff.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buff[end..end + 2] != b"\r\n" {
+            return Err("expected CRLF after bulk data".into());
+        }
+        parts.push(&buff[start..end]);
+        offset = end + 2;
+    }
+
+    Ok(Some((parts, offset)))
+}
+
+fn parse_len(line: &[u8]) -> Result<isize, String> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<isize>().ok())
+        .ok_or_else(|| "invalid length".to_string())
+}
+
 fn read_line(input: &[u8]) -> Result<(&[u8], usize), String> {
     for i in 0..input.len().saturating_sub(1) {
         if input[i] == b'\r' && input[i + 1] == b'\n' {
@@ -65,6 +149,9 @@ fn parse_bulk(input: &[u8]) -> Result<(Resp, usize), String> {
         return Ok((Resp::Bulk(None), offset));
     }
 
+    if len < 0 {
+        return Err("invalid bulk length".into());
+    }
     let len = len as usize;
     let start = offset;
     let end = start + len;
@@ -72,6 +159,9 @@ fn parse_bulk(input: &[u8]) -> Result<(Resp, usize), String> {
     if input.len() < end + 2 {
         return Err("incomplete bulk".into());
     }
+    if &input[end..end + 2] != b"\r\n" {
+        return Err("expected CRLF after bulk data".into());
+    }
 
     let data = String::from_utf8_lossy(&input[start..end]).to_string();
     Ok((Resp::Bulk(Some(data)), end + 2))
@@ -90,7 +180,11 @@ fn parse_array(input: &[u8]) -> Result<(Resp, usize), String> {
         return Ok((Resp::Array(None), offset));
     }
 
-    let mut items = Vec::with_capacity(len as usize);
+    if !(0..=MAX_MULTIBULK_LEN).contains(&len) {
+        return Err("invalid multibulk length".into());
+    }
+
+    let mut items = Vec::new();
     let mut total = offset;
 
     for _ in 0..len {
@@ -101,3 +195,51 @@ fn parse_array(input: &[u8]) -> Result<(Resp, usize), String> {
 
     Ok((Resp::Array(Some(items)), total))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_borrows_arguments() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*1";
+        let (parts, consumed) = parse_request(input).unwrap().unwrap();
+        assert_eq!(parts, vec![&b"SET"[..], b"key", b"value"]);
+        assert_eq!(consumed, input.len() - 2);
+        assert_eq!(parse_request(&input[consumed..]), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_request_incomplete_and_inline() {
+        assert_eq!(parse_request(b"*2\r\n$4\r\nECHO\r\n$5\r\nhel"), Ok(None));
+        assert_eq!(
+            parse_request(b"PING  hello\r\n"),
+            Ok(Some((vec![&b"PING"[..], b"hello"], 13)))
+        );
+        assert!(parse_request(b"*1\r\n:1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_request_multibulk_length() {
+        assert_eq!(
+            parse_request(b"*9223372036854775807\r\n"),
+            Err("invalid multibulk length".to_string())
+        );
+        assert_eq!(
+            parse_request(b"*1048577\r\n$1\r\na\r\n"),
+            Err("invalid multibulk length".to_string())
+        );
+        assert_eq!(parse_request(b"*1048576\r\n$1\r\na\r\n"), Ok(None));
+        assert!(parse(b"*-2\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_request_bulk_crlf() {
+        let error = Err("expected CRLF after bulk data".to_string());
+        assert_eq!(parse_request(b"*1\r\n$4\r\nPINGxx*1\r\n"), error);
+        assert_eq!(parse_request(b"*1\r\n$4\r\nPING\n\r"), error);
+        assert_eq!(parse_request(b"*1\r\n$4\r\nPING\r"), Ok(None));
+        assert!(parse(b"$2\r\nokxx").is_err());
+        assert!(parse(b"$-2\r\nok\r\n").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+/// A glob pattern compiled once and then matched against many strings, as
+/// KEYS and SCAN MATCH do for every key they look at.
+///
+/// `*` is any run of characters, `?` any one, `[abc]`, `[a-c]` and `[^abc]`
+/// one in or not in a set, and `\` makes the next character literal, also
+/// inside a set. An unterminated set ends the pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// `*`, consecutive ones compiled into one.
+    Star,
+    /// `?`
+    Any,
+    Literal(char),
+    /// Inclusive ranges, single characters being ranges of one.
+    Set {
+        ranges: Vec<(char, char)>,
+        negate: bool,
+    },
+}
+
+impl Token {
+    /// Whether this token, any but `Star`, matches `c`.
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Star | Token::Any => true,
+            Token::Literal(literal) => *literal == c,
+            Token::Set { ranges, negate } => {
+                ranges
+                    .iter()
+                    .any(|&(start, end)| (start..=end).contains(&c))
+                    != *negate
+            }
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<_> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = match chars[i] {
+                '*' if tokens.last() == Some(&Token::Star) => {
+                    i += 1;
+                    continue;
+                }
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Token::Literal(chars[i])
+                }
+                '[' => {
+                    let negate = chars.get(i + 1) == Some(&'^');
+                    i += if negate { 2 } else { 1 };
+                    let mut ranges = Vec::new();
+                    while i < chars.len() && chars[i] != ']' {
+                        if chars[i] == '\\' && i + 1 < chars.len() {
+                            ranges.push((chars[i + 1], chars[i + 1]));
+                            i += 2;
+                        } else if i + 2 < chars.len() && chars[i + 1] == '-' {
+                            let (start, end) = (chars[i], chars[i + 2]);
+                            ranges.push((start.min(end), start.max(end)));
+                            i += 3;
+                        } else {
+                            ranges.push((chars[i], chars[i]));
+                            i += 1;
+                        }
+                    }
+                    Token::Set { ranges, negate }
+                }
+                literal => Token::Literal(literal),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        Pattern { tokens }
+    }
+
+    /// Whether the pattern is `*`, matching everything.
+    pub fn matches_all(&self) -> bool {
+        self.tokens == [Token::Star]
+    }
+
+    /// Whether `text` matches the whole pattern. Only the last `*` is
+    /// backtracked to, so this takes at most pattern length times text
+    /// length steps, however many `*` there are.
+    pub fn matches(&self, text: &str) -> bool {
+        if self.matches_all() {
+            return true;
+        }
+        let tokens = &self.tokens;
+        let (mut p, mut t) = (0, 0);
+        // Just past the last `*`, and the byte of the text it has taken up to
+        let mut star = None;
+        while let Some(c) = text[t..].chars().next() {
+            match tokens.get(p) {
+                Some(Token::Star) => {
+                    p += 1;
+                    star = Some((p, t));
+                    continue;
+                }
+                Some(token) if token.matches(c) => {
+                    p += 1;
+                    t += c.len_utf8();
+                    continue;
+                }
+                _ => {}
+            }
+            // Let the last `*` take one more character and retry from there
+            let Some((after_star, taken)) = star else {
+                return false;
+            };
+            let skipped = text[taken..].chars().next().map_or(0, char::len_utf8);
+            p = after_star;
+            t = taken + skipped;
+            star = Some((after_star, t));
+        }
+        tokens[p..].iter().all(|token| *token == Token::Star)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_match(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("user:*", "user:123"));
+        assert!(glob_match("user:*:name", "user:123:name"));
+        assert!(!glob_match("user:*:name", "user:123:age"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("**", ""));
+        assert!(!glob_match("?", ""));
+        assert!(glob_match("caf?", "café"));
+        assert!(glob_match("*é*", "café au lait"));
+
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(glob_match("h[c-a]llo", "hbllo"));
+        assert!(!glob_match("h[a-c]llo", "hdllo"));
+        assert!(glob_match("[\\]]", "]"));
+        assert!(!glob_match("[]", "]"));
+        assert!(glob_match("user:[0-9", "user:7"));
+
+        assert!(glob_match("what\\?", "what?"));
+        assert!(!glob_match("what\\?", "whats"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(glob_match("end\\", "end\\"));
+
+        // Backtracking only to the last star keeps this quick
+        let text = "a".repeat(10_000);
+        assert!(glob_match("*a*a*a*a*a*a*a*a*a*a*", &text));
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*a*a*b", &text));
+    }
+
+    #[test]
+    fn test_compiled_once() {
+        let pattern = Pattern::new("a***[b-d]\\*");
+        assert_eq!(pattern.tokens.len(), 4);
+        assert!(pattern.matches("axyc*"));
+        assert!(!pattern.matches("axye*"));
+        assert!(Pattern::new("***").matches_all());
+        assert!(!Pattern::new("\\*").matches_all());
+    }
+}
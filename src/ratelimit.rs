@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at `rate` tokens per second, holding at most one
+/// second worth of tokens.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Takes `n` tokens if they are available.
+    pub fn try_take(&mut self, n: u64) -> bool {
+        self.refill();
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes `n` tokens unconditionally, going into debt if needed, and
+    /// returns how long the caller should wait for the debt to be repaid.
+    pub fn consume(&mut self, n: u64) -> Duration {
+        self.refill();
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 || self.rate == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Per-connection limiter for commands/sec and bytes/sec. A limit of zero
+/// disables the corresponding bucket.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    commands: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Picks up limit changes made through CONFIG SET.
+    pub fn configure(&mut self, commands_per_sec: u64, bytes_per_sec: u64) {
+        fn update(bucket: &mut Option<TokenBucket>, rate: u64) {
+            match bucket {
+                _ if rate == 0 => *bucket = None,
+                Some(b) if b.rate() == rate => {}
+                _ => *bucket = Some(TokenBucket::new(rate)),
+            }
+        }
+        update(&mut self.commands, commands_per_sec);
+        update(&mut self.bytes, bytes_per_sec);
+    }
+
+    pub fn allow_command(&mut self) -> bool {
+        self.commands.as_mut().is_none_or(|b| b.try_take(1))
+    }
+
+    /// Time to wait until another command is allowed.
+    pub fn command_delay(&mut self) -> Duration {
+        self.commands
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.consume(1))
+    }
+
+    /// Accounts for bytes read and returns how long to pause reading.
+    pub fn read_delay(&mut self, bytes: usize) -> Duration {
+        self.bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.consume(bytes as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refuses_when_empty() {
+        let mut bucket = TokenBucket::new(2);
+        assert!(bucket.try_take(1));
+        assert!(bucket.try_take(1));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn test_consume_reports_debt() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.consume(500), Duration::ZERO);
+        let wait = bucket.consume(1500);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_limiter_disabled_by_default() {
+        let mut limiter = RateLimiter::default();
+        assert!((0..10_000).all(|_| limiter.allow_command()));
+        assert_eq!(limiter.read_delay(1 << 30), Duration::ZERO);
+
+        limiter.configure(1, 0);
+        assert!(limiter.allow_command());
+        assert!(!limiter.allow_command());
+    }
+}
@@ -0,0 +1,2196 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::binary;
+use crate::bloom::{self, Bloom, Filter};
+use crate::crypto::{self, Keyring};
+use crate::memory;
+use crate::server::Server;
+use crate::stats::Stats;
+use crate::storage::{self, DbSnapshot, Storage, Value, now_ms};
+use crate::stream::{Consumer, Fields, Group, NODE_ENTRIES, Pending, Stream, StreamId};
+use crate::zset::{self, SortedSet};
+
+/// Newest RDB format version we understand (Redis 7.4).
+pub const RDB_VERSION: u32 = 12;
+
+/// Version written by SAVE, that of the Redis 7.0 we report in INFO.
+const RDB_SAVE_VERSION: u32 = 10;
+
+/// After a failed BGSAVE, automatic saves wait this long before retrying.
+const BGSAVE_RETRY_DELAY_SECS: u64 = 5;
+
+// Opcodes that can appear where a value type is expected
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xF5;
+const OPCODE_FUNCTION2: u8 = 0xF6;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+// Value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// What each field of a module value starts with
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+/// The characters of module type names, 6 bits each in a module type ID.
+const MODULE_TYPE_CHARSET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// RedisBloom's bloom filter type, and the versions of its encoding we
+/// read: 2 added the chain's options, 4 its expansion.
+const BLOOM_MODULE_TYPE: &str = "MBbloom--";
+const BLOOM_MIN_ENCODING_VERSION: u64 = 2;
+const BLOOM_ENCODING_VERSION: u64 = 4;
+
+// Special string encodings flagged by a length byte starting with 11
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+/// Quicklist 2 node holding a single element as a plain string.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+/// Flags on a stream entry within its listpack node.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+/// What loading a file did.
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadStats {
+    pub keys: usize,
+    /// Keys whose expiration had already passed, which are not loaded.
+    pub expired: usize,
+    /// Keys of types this server doesn't load.
+    pub skipped: usize,
+    /// Database the replication stream had selected when a payload sent to
+    /// a replica was made, from its `repl-stream-db` aux field.
+    pub stream_db: Option<usize>,
+}
+
+/// A decoded length: either a real length or a special string encoding.
+enum Length {
+    Len(u64),
+    Encoded(u64),
+}
+
+/// Cursor over an in-memory RDB payload.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| "Unexpected EOF reading RDB file".to_string())?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length, String> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Length::Len(u64::from(first & 0x3F)),
+            1 => Length::Len((u64::from(first & 0x3F) << 8) | u64::from(self.u8()?)),
+            2 => match first {
+                0x80 => Length::Len(u64::from(u32::from_be_bytes(self.array()?))),
+                0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+                _ => return Err(format!("Unknown length encoding {:#x}", first)),
+            },
+            _ => Length::Encoded(u64::from(first & 0x3F)),
+        })
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => {
+                Err("Unexpected string encoding where a length was expected".to_string())
+            }
+        }
+    }
+
+    /// A length used to size a loop, checked against the bytes left so a
+    /// corrupt file can't make us allocate without bound.
+    fn count(&mut self) -> Result<usize, String> {
+        let len = self.length()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(format!(
+                "Length {} is larger than the rest of the file",
+                len
+            ));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => {
+                let len = usize::try_from(len).map_err(|_| "String too long".to_string())?;
+                Ok(self.bytes(len)?.to_vec())
+            }
+            Length::Encoded(ENC_INT8) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => {
+                Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_INT32) => {
+                Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.count()?;
+                let len = usize::try_from(self.length()?).map_err(|_| "String too long")?;
+                lzf_decompress(self.bytes(compressed_len)?, len)
+            }
+            Length::Encoded(other) => Err(format!("Unknown string encoding {}", other)),
+        }
+    }
+
+    fn text(&mut self) -> Result<String, String> {
+        self.string().map(|bytes| binary::decode(&bytes))
+    }
+
+    /// Reads the opcode a module value's field starts with, which must be
+    /// `opcode`.
+    fn module_opcode(&mut self, opcode: u64) -> Result<(), String> {
+        match self.length()? {
+            found if found == opcode => Ok(()),
+            found => Err(format!(
+                "Module value field is of type {} rather than {}",
+                found, opcode
+            )),
+        }
+    }
+
+    fn module_uint(&mut self) -> Result<u64, String> {
+        self.module_opcode(MODULE_OPCODE_UINT)?;
+        self.length()
+    }
+
+    fn module_double(&mut self) -> Result<f64, String> {
+        self.module_opcode(MODULE_OPCODE_DOUBLE)?;
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn module_string(&mut self) -> Result<Vec<u8>, String> {
+        self.module_opcode(MODULE_OPCODE_STRING)?;
+        self.string()
+    }
+}
+
+/// The ID a module type is stored under: the 9 characters of its name,
+/// then 10 bits of the version of its encoding.
+fn module_type_id(name: &str, version: u64) -> u64 {
+    let name = name.bytes().fold(0, |id, c| {
+        let index = MODULE_TYPE_CHARSET.iter().position(|&known| known == c);
+        (id << 6) | index.expect("module type names use the charset") as u64
+    });
+    (name << 10) | version
+}
+
+/// The name and encoding version of the module type with ID `id`.
+fn module_type(id: u64) -> (String, u64) {
+    let name = (0..9)
+        .rev()
+        .map(|i| MODULE_TYPE_CHARSET[((id >> (10 + 6 * i)) & 63) as usize] as char)
+        .collect();
+    (name, id & 1023)
+}
+
+/// Something found while walking an RDB file.
+enum Item {
+    Aux(String, String),
+    SelectDb(usize),
+    /// A key with its type byte; the value is None for types this server
+    /// can't hold.
+    Key {
+        kind: u8,
+        key: String,
+        value: Option<Value>,
+        expires_at: Option<u64>,
+    },
+}
+
+/// Walks an RDB payload, handing every item to `visit`. Returns the
+/// checksum the file carries, if any, after verifying it when
+/// `verify_checksum` is set. Errors come with the offset they were found at.
+fn walk(
+    data: &[u8],
+    verify_checksum: bool,
+    mut visit: impl FnMut(Item) -> Result<(), String>,
+) -> Result<Option<u64>, (usize, String)> {
+    let mut reader = Reader::new(data);
+    let at = |reader: &Reader, e: String| (reader.pos, e);
+    let header = reader
+        .bytes(9)
+        .map_err(|_| (0, "Wrong signature trying to load DB from file".to_string()))?;
+    if &header[..5] != b"REDIS" {
+        return Err((0, "Wrong signature trying to load DB from file".to_string()));
+    }
+    let version: u32 = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or((0, "Wrong signature trying to load DB from file".to_string()))?;
+    if !(1..=RDB_VERSION).contains(&version) {
+        return Err((0, format!("Can't handle RDB format version {}", version)));
+    }
+
+    let mut expires_at = None;
+    while let Some(item) = next_item(&mut reader, &mut expires_at).map_err(|e| at(&reader, e))? {
+        visit(item).map_err(|e| at(&reader, e))?;
+    }
+
+    // Versions 5 and up end with a CRC64 of everything before it, or zero
+    // when written with rdbchecksum off
+    let mut checksum = None;
+    if version >= 5 {
+        let end = reader.pos;
+        let expected = u64::from_le_bytes(reader.array().map_err(|e| at(&reader, e))?);
+        if verify_checksum && expected != 0 {
+            let actual = crc64(0, &data[..end]);
+            if actual != expected {
+                return Err((
+                    end,
+                    format!(
+                        "Wrong RDB checksum expected: ({:x}) got ({:x})",
+                        expected, actual
+                    ),
+                ));
+            }
+        }
+        checksum = Some(expected).filter(|&crc| crc != 0);
+    }
+    if !reader.is_empty() {
+        return Err((
+            reader.pos,
+            "Unexpected data after the end of the RDB file".to_string(),
+        ));
+    }
+    Ok(checksum)
+}
+
+/// Reads up to the next item, or None at the EOF opcode. Expiration
+/// opcodes are kept in `expires_at` for the key that follows them.
+fn next_item(reader: &mut Reader, expires_at: &mut Option<u64>) -> Result<Option<Item>, String> {
+    loop {
+        let kind = reader.u8()?;
+        match kind {
+            OPCODE_EOF => return Ok(None),
+            OPCODE_SELECTDB => return Ok(Some(Item::SelectDb(reader.length()? as usize))),
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OPCODE_AUX => {
+                let name = reader.text()?;
+                return Ok(Some(Item::Aux(name, reader.text()?)));
+            }
+            OPCODE_EXPIRETIME => {
+                *expires_at = Some(u64::from(u32::from_le_bytes(reader.array()?)) * 1000);
+            }
+            OPCODE_EXPIRETIME_MS => *expires_at = Some(u64::from_le_bytes(reader.array()?)),
+            OPCODE_FREQ => {
+                reader.u8()?;
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FUNCTION2 => {
+                // There is no function engine to load libraries into
+                reader.string()?;
+            }
+            OPCODE_MODULE_AUX | OPCODE_FUNCTION_PRE_GA => {
+                return Err(format!("Unsupported RDB opcode {:#x}", kind));
+            }
+            _ => {
+                let key = reader.text()?;
+                return Ok(Some(Item::Key {
+                    kind,
+                    key,
+                    value: read_value(reader, kind)?,
+                    expires_at: expires_at.take(),
+                }));
+            }
+        }
+    }
+}
+
+/// Loads an RDB payload into `storage`, which should be empty. The trailing
+/// checksum is verified when `verify_checksum` is set and the file has one.
+pub fn load(data: &[u8], storage: &Storage, verify_checksum: bool) -> Result<LoadStats, String> {
+    let mut stats = LoadStats::default();
+    let mut db = 0;
+    let now = storage::now_ms();
+    walk(data, verify_checksum, |item| {
+        match item {
+            Item::Aux(name, value) => {
+                if name == "repl-stream-db" {
+                    stats.stream_db = value.parse().ok();
+                }
+            }
+            Item::SelectDb(index) => {
+                if index >= storage.len() {
+                    return Err(format!(
+                        "Data file was created with a server configured to handle more than {} databases",
+                        storage.len()
+                    ));
+                }
+                db = index;
+            }
+            Item::Key {
+                key,
+                value,
+                expires_at,
+                ..
+            } => match value {
+                None => stats.skipped += 1,
+                Some(_) if expires_at.is_some_and(|at| at <= now) => stats.expired += 1,
+                Some(value) => {
+                    storage.db(db).restore(key, value, expires_at);
+                    stats.keys += 1;
+                }
+            },
+        }
+        Ok(())
+    })
+    .map_err(|(_, e)| e)?;
+    Ok(stats)
+}
+
+fn type_name(kind: u8) -> &'static str {
+    match kind {
+        TYPE_STRING => "string",
+        TYPE_LIST | TYPE_LIST_ZIPLIST | TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => "list",
+        TYPE_SET | TYPE_SET_INTSET | TYPE_SET_LISTPACK => "set",
+        TYPE_ZSET | TYPE_ZSET_2 | TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => "zset",
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => "stream",
+        TYPE_MODULE_2 => "module",
+        _ => "hash",
+    }
+}
+
+/// `reredis --check-rdb <file>`, like redis-check-rdb: walks the file
+/// without starting the server, validating its structure and checksum, and
+/// prints what it holds. Returns the exit code.
+pub fn check_tool(args: &[String]) -> i32 {
+    let [path] = args else {
+        eprintln!("Usage: reredis --check-rdb <dump.rdb>");
+        return 1;
+    };
+    let file = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Cannot open file {}: {}", path, e);
+            return 1;
+        }
+    };
+    println!("[offset 0] Checking RDB file {}", path);
+    if crypto::is_encrypted(&file) {
+        println!(
+            "[info] The file is encrypted, decrypting with {}",
+            crypto::KEY_ENV
+        );
+    }
+    let data =
+        match Keyring::load("").and_then(|keyring| crypto::open_file(&file, keyring.as_ref())) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("--- RDB ERROR DETECTED ---");
+                println!("[offset 0] {}", e);
+                return 1;
+            }
+        };
+
+    let now = storage::now_ms();
+    let mut keys = 0;
+    let mut expires = 0;
+    let mut expired = 0;
+    let mut dbs = HashSet::new();
+    let mut types: Vec<(&str, usize)> = Vec::new();
+    let result = walk(&data, true, |item| {
+        match item {
+            Item::Aux(name, value) => println!("[info] AUX FIELD {} = '{}'", name, value),
+            Item::SelectDb(index) => {
+                dbs.insert(index);
+                println!("[info] Selecting DB ID {}", index);
+            }
+            Item::Key {
+                kind, expires_at, ..
+            } => {
+                keys += 1;
+                if let Some(at) = expires_at {
+                    expires += 1;
+                    if at <= now {
+                        expired += 1;
+                    }
+                }
+                let name = type_name(kind);
+                match types.iter_mut().find(|(t, _)| *t == name) {
+                    Some((_, count)) => *count += 1,
+                    None => types.push((name, 1)),
+                }
+            }
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(checksum) => {
+            match checksum {
+                Some(_) => println!("[offset {}] Checksum OK", data.len()),
+                None => println!(
+                    "[offset {}] RDB file was saved with checksum disabled: no check performed.",
+                    data.len()
+                ),
+            }
+            println!("[offset {}] \\o/ RDB looks OK! \\o/", data.len());
+            println!("[info] {} keys read", keys);
+            println!("[info] {} expires", expires);
+            println!("[info] {} already expired", expired);
+            println!("[info] {} databases", dbs.len());
+            for (name, count) in types {
+                println!("[info] {} {} keys", count, name);
+            }
+            0
+        }
+        Err((offset, e)) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[offset {}] {}", offset, e);
+            println!("[additional info] {} keys read before the error", keys);
+            1
+        }
+    }
+}
+
+/// Reads a value of the given type, or None for a type whose keys are
+/// skipped rather than loaded.
+fn read_value(reader: &mut Reader, kind: u8) -> Result<Option<Value>, String> {
+    let value = match kind {
+        TYPE_STRING => Value::string(reader.text()?),
+        TYPE_LIST => {
+            let len = reader.count()?;
+            let mut list = VecDeque::with_capacity(len);
+            for _ in 0..len {
+                list.push_back(reader.text()?);
+            }
+            Value::List(list.into())
+        }
+        TYPE_SET => {
+            let len = reader.count()?;
+            let mut set = HashSet::with_capacity(len);
+            for _ in 0..len {
+                set.insert(reader.text()?);
+            }
+            Value::Set(set)
+        }
+        TYPE_HASH => {
+            let len = reader.count()?;
+            let mut hash = HashMap::with_capacity(len);
+            for _ in 0..len {
+                let field = reader.text()?;
+                hash.insert(field, reader.text()?);
+            }
+            Value::Hash(hash.into())
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = reader.count()?;
+            let mut zset = SortedSet::new();
+            for _ in 0..len {
+                let member = reader.text()?;
+                let score = if kind == TYPE_ZSET_2 {
+                    f64::from_le_bytes(reader.array()?)
+                } else {
+                    // Length-prefixed text score, or 253-255 for nan/+inf/-inf
+                    match reader.u8()? {
+                        253 => f64::NAN,
+                        254 => f64::INFINITY,
+                        255 => f64::NEG_INFINITY,
+                        len => {
+                            let text = binary::decode(reader.bytes(len as usize)?);
+                            text.parse().unwrap_or(f64::NAN)
+                        }
+                    }
+                };
+                if score.is_nan() {
+                    return Err("Zset with NAN score detected".to_string());
+                }
+                zset.insert(&member, score);
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_ZSET_ZIPLIST => Value::ZSet(scored(ziplist_entries(&reader.string()?)?)?),
+        TYPE_ZSET_LISTPACK => Value::ZSet(scored(listpack_entries(&reader.string()?)?)?),
+        TYPE_HASH_ZIPMAP => Value::Hash(pairs(zipmap_entries(&reader.string()?)?)?.into()),
+        TYPE_LIST_ZIPLIST => Value::List(ziplist_entries(&reader.string()?)?.into()),
+        TYPE_SET_INTSET => Value::Set(intset_entries(&reader.string()?)?.into_iter().collect()),
+        TYPE_SET_LISTPACK => Value::Set(listpack_entries(&reader.string()?)?.into_iter().collect()),
+        TYPE_HASH_ZIPLIST => Value::Hash(pairs(ziplist_entries(&reader.string()?)?)?.into()),
+        TYPE_HASH_LISTPACK => Value::Hash(pairs(listpack_entries(&reader.string()?)?)?.into()),
+        TYPE_LIST_QUICKLIST => {
+            let mut list = VecDeque::new();
+            for _ in 0..reader.count()? {
+                list.extend(ziplist_entries(&reader.string()?)?);
+            }
+            Value::List(list.into())
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let mut list = VecDeque::new();
+            for _ in 0..reader.count()? {
+                let container = reader.length()?;
+                let node = reader.string()?;
+                if container == QUICKLIST_NODE_PLAIN {
+                    list.push_back(binary::decode(&node));
+                } else {
+                    list.extend(listpack_entries(&node)?);
+                }
+            }
+            Value::List(list.into())
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Value::Stream(read_stream(reader, kind)?)
+        }
+        TYPE_MODULE_2 => match module_type(reader.length()?) {
+            (name, version) if name == BLOOM_MODULE_TYPE => {
+                Value::Bloom(read_bloom(reader, version)?)
+            }
+            (name, _) => {
+                return Err(format!(
+                    "The RDB file contains module data I can't load: no matching module type '{}'",
+                    name
+                ));
+            }
+        },
+        _ => return Err(format!("Unknown RDB value type {}", kind)),
+    };
+    Ok(Some(value))
+}
+
+/// Reads a RedisBloom bloom filter: the chain's item count, filter count,
+/// options and expansion, then each filter's sizing, bits and item count,
+/// and the end of the module value.
+fn read_bloom(reader: &mut Reader, version: u64) -> Result<Bloom, String> {
+    if !(BLOOM_MIN_ENCODING_VERSION..=BLOOM_ENCODING_VERSION).contains(&version) {
+        return Err(format!(
+            "Unsupported bloom filter encoding version {}",
+            version
+        ));
+    }
+    reader.module_uint()?; // item count, the sum of the filters'
+    let count = reader.module_uint()?;
+    let options = reader.module_uint()?;
+    if options & bloom::OPT_FORCE64 == 0 {
+        return Err("Bloom filters hashed to 32 bits are not supported".to_string());
+    }
+    let mut expansion = u64::from(bloom::DEFAULT_EXPANSION);
+    if version >= 4 {
+        expansion = reader.module_uint()?;
+    }
+    let invalid = || "Invalid bloom filter".to_string();
+    let mut filters = Vec::new();
+    for _ in 0..count {
+        let capacity = reader.module_uint()?;
+        let error_rate = reader.module_double()?;
+        let hashes = u32::try_from(reader.module_uint()?).map_err(|_| invalid())?;
+        let bpe = reader.module_double()?;
+        let bits = reader.module_uint()?;
+        let n2 = reader.module_uint()?;
+        let data = reader.module_string()?;
+        if bits == 0 || bits > data.len() as u64 * 8 {
+            return Err(invalid());
+        }
+        let size = reader.module_uint()?;
+        filters.push(Filter {
+            capacity,
+            error_rate,
+            hashes,
+            bpe,
+            n2,
+            bits,
+            data,
+            size,
+        });
+    }
+    reader.module_opcode(MODULE_OPCODE_EOF)?;
+    let expansion = u32::try_from(expansion).map_err(|_| invalid())?;
+    if filters.is_empty() || expansion == 0 {
+        return Err(invalid());
+    }
+    Ok(Bloom::from_parts(filters, options, expansion))
+}
+
+/// Reads a stream: listpack nodes keyed by the ID their entries' IDs are
+/// relative to, then the IDs and counters kept alongside the entries and
+/// the consumer groups with their pending entries.
+fn read_stream(reader: &mut Reader, kind: u8) -> Result<Stream, String> {
+    let mut entries = BTreeMap::new();
+    for _ in 0..reader.count()? {
+        let master = raw_stream_id(&reader.string()?)?;
+        let node = listpack_entries(&reader.string()?)?;
+        stream_node_entries(master, &node, &mut entries)?;
+    }
+    reader.length()?; // entry count, known from the entries themselves
+    let last_id = StreamId::new(reader.length()?, reader.length()?);
+    let mut max_deleted_id = StreamId::MIN;
+    let mut entries_added = entries.len() as u64;
+    if kind >= TYPE_STREAM_LISTPACKS_2 {
+        reader.length()?; // first entry's ID, ms and seq
+        reader.length()?;
+        max_deleted_id = StreamId::new(reader.length()?, reader.length()?);
+        entries_added = reader.length()?;
+    }
+    let mut groups = BTreeMap::new();
+    for _ in 0..reader.count()? {
+        let name = reader.text()?;
+        let last_id = StreamId::new(reader.length()?, reader.length()?);
+        let mut entries_read = None;
+        if kind >= TYPE_STREAM_LISTPACKS_2 {
+            // -1 when unknown
+            entries_read = Some(reader.length()?).filter(|&read| read != u64::MAX);
+        }
+        // Whose each entry is comes with the consumers
+        let mut deliveries = BTreeMap::new();
+        for _ in 0..reader.count()? {
+            let id = raw_stream_id(reader.bytes(16)?)?;
+            let delivery_time = u64::from_le_bytes(reader.array()?);
+            deliveries.insert(id, (delivery_time, reader.length()?));
+        }
+        let mut pending = BTreeMap::new();
+        let mut consumers = BTreeMap::new();
+        for _ in 0..reader.count()? {
+            let consumer = reader.text()?;
+            let seen_time = u64::from_le_bytes(reader.array()?);
+            let mut active_time = Some(seen_time);
+            if kind >= TYPE_STREAM_LISTPACKS_3 {
+                active_time = Some(u64::from_le_bytes(reader.array()?)).filter(|&t| t != u64::MAX);
+            }
+            let mut ids = BTreeSet::new();
+            for _ in 0..reader.count()? {
+                let id = raw_stream_id(reader.bytes(16)?)?;
+                let (delivery_time, delivery_count) = deliveries
+                    .remove(&id)
+                    .ok_or("Stream consumer pending entry missing from its group")?;
+                let entry = Pending {
+                    consumer: consumer.clone(),
+                    delivery_time,
+                    delivery_count,
+                };
+                pending.insert(id, entry);
+                ids.insert(id);
+            }
+            let consumer_state = Consumer {
+                seen_time,
+                active_time,
+                pending: ids,
+            };
+            consumers.insert(consumer, consumer_state);
+        }
+        if !deliveries.is_empty() {
+            return Err("Stream group pending entry without a consumer".to_string());
+        }
+        let group = Group::from_parts(last_id, entries_read, pending, consumers);
+        groups.insert(name, group);
+    }
+    Ok(Stream::from_parts(
+        entries,
+        last_id,
+        max_deleted_id,
+        entries_added,
+        groups,
+    ))
+}
+
+/// A stream ID as 16 raw bytes: ms then seq, big-endian.
+fn raw_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// A stream ID stored as 16 raw bytes: ms then seq, big-endian.
+fn raw_stream_id(raw: &[u8]) -> Result<StreamId, String> {
+    let raw: [u8; 16] = raw
+        .try_into()
+        .map_err(|_| "Stream node key is not a 16 byte ID".to_string())?;
+    let (ms, seq) = raw.split_at(8);
+    Ok(StreamId::new(
+        u64::from_be_bytes(ms.try_into().unwrap()),
+        u64::from_be_bytes(seq.try_into().unwrap()),
+    ))
+}
+
+/// Adds the entries of a stream's listpack node: a master entry with the
+/// entry count and the fields shared by entries flagged SAMEFIELDS, then
+/// each entry with its ID as a difference from `master`.
+fn stream_node_entries(
+    master: StreamId,
+    node: &[String],
+    entries: &mut BTreeMap<StreamId, Fields>,
+) -> Result<(), String> {
+    let invalid = || "Invalid stream listpack node".to_string();
+    let mut items = node.iter();
+    let int = |items: &mut std::slice::Iter<String>| -> Result<i64, String> {
+        items
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let count = int(&mut items)?;
+    let deleted = int(&mut items)?;
+    let master_fields: Vec<&String> = (0..int(&mut items)?)
+        .map(|_| items.next().ok_or_else(invalid))
+        .collect::<Result<_, _>>()?;
+    int(&mut items)?; // master entry terminator
+    for _ in 0..count.saturating_add(deleted) {
+        let flags = int(&mut items)?;
+        let ms = master.ms.wrapping_add(int(&mut items)? as u64);
+        let seq = master.seq.wrapping_add(int(&mut items)? as u64);
+        let mut fields = Fields::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                let value = items.next().ok_or_else(invalid)?;
+                fields.push(((*field).clone(), value.clone()));
+            }
+        } else {
+            for _ in 0..int(&mut items)? {
+                let field = items.next().ok_or_else(invalid)?;
+                let value = items.next().ok_or_else(invalid)?;
+                fields.push((field.clone(), value.clone()));
+            }
+        }
+        int(&mut items)?; // item count, for walking the node backwards
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.insert(StreamId::new(ms, seq), fields);
+        }
+    }
+    Ok(())
+}
+
+/// Pairs up a flat member, score, member, score... list into a sorted set.
+fn scored(flat: Vec<String>) -> Result<SortedSet, String> {
+    if !flat.len().is_multiple_of(2) {
+        return Err("Sorted set encoding has an odd number of elements".to_string());
+    }
+    let mut zset = SortedSet::new();
+    for pair in flat.chunks_exact(2) {
+        let score = zset::parse_score(&pair[1])
+            .ok_or_else(|| format!("Invalid sorted set score '{}'", pair[1]))?;
+        zset.insert(&pair[0], score);
+    }
+    Ok(zset)
+}
+
+/// Pairs up a flat field, value, field, value... list.
+fn pairs(flat: Vec<String>) -> Result<HashMap<String, String>, String> {
+    if !flat.len().is_multiple_of(2) {
+        return Err("Hash encoding has an odd number of elements".to_string());
+    }
+    let mut hash = HashMap::with_capacity(flat.len() / 2);
+    let mut items = flat.into_iter();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
+
+/// Elements of a ziplist, the compact list encoding of RDB versions < 10.
+fn ziplist_entries(blob: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = Reader::new(blob);
+    reader.bytes(10)?; // zlbytes, zltail, zllen
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = reader.u8()?;
+        if prevlen == 0xFF {
+            return Ok(entries);
+        }
+        if prevlen == 0xFE {
+            reader.bytes(4)?;
+        }
+        let encoding = reader.u8()?;
+        let entry = match encoding >> 6 {
+            0 => reader.bytes((encoding & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let len = (usize::from(encoding & 0x3F) << 8) | usize::from(reader.u8()?);
+                reader.bytes(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.array()?) as usize;
+                reader.bytes(len)?.to_vec()
+            }
+            _ => {
+                let value: i64 = match encoding {
+                    0xC0 => i16::from_le_bytes(reader.array()?).into(),
+                    0xD0 => i32::from_le_bytes(reader.array()?).into(),
+                    0xE0 => i64::from_le_bytes(reader.array()?),
+                    0xF0 => {
+                        let [a, b, c] = reader.array()?;
+                        (i32::from_le_bytes([0, a, b, c]) >> 8).into()
+                    }
+                    0xFE => (reader.u8()? as i8).into(),
+                    0xF1..=0xFD => i64::from(encoding & 0x0F) - 1,
+                    _ => return Err(format!("Unknown ziplist encoding {:#x}", encoding)),
+                };
+                value.to_string().into_bytes()
+            }
+        };
+        entries.push(binary::decode(&entry));
+    }
+}
+
+/// Elements of a listpack, the compact encoding of RDB versions >= 10.
+fn listpack_entries(blob: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = Reader::new(blob);
+    reader.bytes(6)?; // total bytes, element count
+    let mut entries = Vec::new();
+    loop {
+        let start = reader.pos;
+        let first = reader.u8()?;
+        if first == 0xFF {
+            return Ok(entries);
+        }
+        let entry = if first & 0x80 == 0 {
+            i64::from(first & 0x7F).to_string().into_bytes()
+        } else if first & 0xC0 == 0x80 {
+            reader.bytes((first & 0x3F) as usize)?.to_vec()
+        } else if first & 0xE0 == 0xC0 {
+            let raw = (i64::from(first & 0x1F) << 8) | i64::from(reader.u8()?);
+            let value = if raw >= 1 << 12 { raw - (1 << 13) } else { raw };
+            value.to_string().into_bytes()
+        } else if first & 0xF0 == 0xE0 {
+            let len = (usize::from(first & 0x0F) << 8) | usize::from(reader.u8()?);
+            reader.bytes(len)?.to_vec()
+        } else {
+            let value: i64 = match first {
+                0xF0 => {
+                    let len = u32::from_le_bytes(reader.array()?) as usize;
+                    let bytes = reader.bytes(len)?.to_vec();
+                    skip_backlen(&mut reader, start)?;
+                    entries.push(binary::decode(&bytes));
+                    continue;
+                }
+                0xF1 => i16::from_le_bytes(reader.array()?).into(),
+                0xF2 => {
+                    let [a, b, c] = reader.array()?;
+                    (i32::from_le_bytes([0, a, b, c]) >> 8).into()
+                }
+                0xF3 => i32::from_le_bytes(reader.array()?).into(),
+                0xF4 => i64::from_le_bytes(reader.array()?),
+                _ => return Err(format!("Unknown listpack encoding {:#x}", first)),
+            };
+            value.to_string().into_bytes()
+        };
+        skip_backlen(&mut reader, start)?;
+        entries.push(binary::decode(&entry));
+    }
+}
+
+/// Skips the back-length that follows every listpack entry, given where
+/// the entry started.
+fn skip_backlen(reader: &mut Reader, start: usize) -> Result<(), String> {
+    let size = match reader.pos - start {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    };
+    reader.bytes(size).map(|_| ())
+}
+
+/// Members of an intset, a sorted array of fixed-width integers.
+fn intset_entries(blob: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = Reader::new(blob);
+    let width = u32::from_le_bytes(reader.array()?) as usize;
+    let len = u32::from_le_bytes(reader.array()?) as usize;
+    let mut entries = Vec::with_capacity(len.min(blob.len()));
+    for _ in 0..len {
+        let value: i64 = match width {
+            2 => i16::from_le_bytes(reader.array()?).into(),
+            4 => i32::from_le_bytes(reader.array()?).into(),
+            8 => i64::from_le_bytes(reader.array()?),
+            _ => return Err(format!("Unknown intset encoding {}", width)),
+        };
+        entries.push(value.to_string());
+    }
+    Ok(entries)
+}
+
+/// Fields and values of a zipmap, the small-hash encoding of very old
+/// versions.
+fn zipmap_entries(blob: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = Reader::new(blob);
+    reader.u8()?; // element count, unreliable past 254
+    let mut entries = Vec::new();
+    loop {
+        let len = match reader.u8()? {
+            0xFF => return Ok(entries),
+            0xFE => u32::from_le_bytes(reader.array()?) as usize,
+            len => len as usize,
+        };
+        entries.push(binary::decode(reader.bytes(len)?));
+
+        let len = match reader.u8()? {
+            0xFE => u32::from_le_bytes(reader.array()?) as usize,
+            len => len as usize,
+        };
+        let free = reader.u8()? as usize;
+        entries.push(binary::decode(reader.bytes(len)?));
+        reader.bytes(free)?;
+    }
+}
+
+/// Decompresses an LZF block that must expand to exactly `len` bytes.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let invalid = || "Invalid LZF compressed string".to_string();
+    // A back-reference expands 3 bytes into at most 264
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(88)));
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = usize::from(input[ip]);
+        ip += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = input.get(ip..ip + ctrl + 1).ok_or_else(invalid)?;
+            out.extend_from_slice(run);
+            ip += ctrl + 1;
+        } else {
+            // Back-reference: length and distance into what was decoded so far
+            let mut n = ctrl >> 5;
+            if n == 7 {
+                n += usize::from(*input.get(ip).ok_or_else(invalid)?);
+                ip += 1;
+            }
+            let distance = ((ctrl & 0x1F) << 8) + usize::from(*input.get(ip).ok_or_else(invalid)?);
+            ip += 1;
+            let start = out.len().checked_sub(distance + 1).ok_or_else(invalid)?;
+            // Byte by byte, as the reference may overlap what it produces
+            for i in start..start + n + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return Err(invalid());
+        }
+    }
+    if out.len() != len {
+        return Err(invalid());
+    }
+    Ok(out)
+}
+
+/// LZF-compresses `input`, or returns None when that saves less than four
+/// bytes, the same cut-off Redis uses.
+fn lzf_compress(input: &[u8]) -> Option<Vec<u8>> {
+    const HASH_BITS: u32 = 14;
+    const MAX_DISTANCE: usize = 1 << 13;
+    const MAX_MATCH: usize = 264;
+    const MAX_LITERALS: usize = 32;
+
+    let limit = input.len().checked_sub(4)?;
+    let mut out = Vec::with_capacity(limit);
+    let mut literals: Vec<u8> = Vec::with_capacity(MAX_LITERALS);
+    // Last position + 1 of each 3-byte sequence, by hash; 0 means unseen
+    let mut table = vec![0usize; 1 << HASH_BITS];
+
+    fn flush(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+        if !literals.is_empty() {
+            out.push(literals.len() as u8 - 1);
+            out.append(literals);
+        }
+    }
+
+    let mut ip = 0;
+    while ip < input.len() {
+        if ip + 3 <= input.len() {
+            let seq = u32::from_be_bytes([0, input[ip], input[ip + 1], input[ip + 2]]);
+            let hash = (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+            let candidate = std::mem::replace(&mut table[hash], ip + 1);
+            if let Some(from) = candidate.checked_sub(1)
+                && ip - from <= MAX_DISTANCE
+                && input[from..from + 3] == input[ip..ip + 3]
+            {
+                let max = MAX_MATCH.min(input.len() - ip);
+                let mut n = 3;
+                while n < max && input[from + n] == input[ip + n] {
+                    n += 1;
+                }
+                flush(&mut out, &mut literals);
+                let distance = ip - from - 1;
+                let len = n - 2;
+                if len < 7 {
+                    out.push(((len << 5) | (distance >> 8)) as u8);
+                } else {
+                    out.push(((7 << 5) | (distance >> 8)) as u8);
+                    out.push((len - 7) as u8);
+                }
+                out.push(distance as u8);
+                ip += n;
+                if out.len() > limit {
+                    return None;
+                }
+                continue;
+            }
+        }
+        literals.push(input[ip]);
+        if literals.len() == MAX_LITERALS {
+            flush(&mut out, &mut literals);
+        }
+        ip += 1;
+        if out.len() + literals.len() > limit {
+            return None;
+        }
+    }
+    flush(&mut out, &mut literals);
+    (out.len() <= limit).then_some(out)
+}
+
+/// Builds a listpack as Redis encodes it, for stream nodes: a byte count
+/// and element count, then each element with its length after it so the
+/// list can be walked backwards.
+#[derive(Default)]
+struct Listpack {
+    body: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    fn int(&mut self, n: i64) {
+        let start = self.body.len();
+        if (0..=127).contains(&n) {
+            self.body.push(n as u8);
+        } else if (-4096..=4095).contains(&n) {
+            let n = n as u16 & 0x1FFF;
+            self.body
+                .extend_from_slice(&[0xC0 | (n >> 8) as u8, n as u8]);
+        } else if let Ok(n) = i16::try_from(n) {
+            self.body.push(0xF1);
+            self.body.extend_from_slice(&n.to_le_bytes());
+        } else if (-(1 << 23)..1 << 23).contains(&n) {
+            self.body.push(0xF2);
+            self.body.extend_from_slice(&n.to_le_bytes()[..3]);
+        } else if let Ok(n) = i32::try_from(n) {
+            self.body.push(0xF3);
+            self.body.extend_from_slice(&n.to_le_bytes());
+        } else {
+            self.body.push(0xF4);
+            self.body.extend_from_slice(&n.to_le_bytes());
+        }
+        self.backlen(start);
+    }
+
+    /// Adds a string, as an integer when it is the canonical form of one.
+    fn string(&mut self, s: &str) {
+        if let Some(n) = s.parse::<i64>().ok().filter(|n| n.to_string() == s) {
+            return self.int(n);
+        }
+        let s = binary::encode(s);
+        let start = self.body.len();
+        let len = s.len();
+        if len < 1 << 6 {
+            self.body.push(0x80 | len as u8);
+        } else if len < 1 << 12 {
+            self.body
+                .extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]);
+        } else {
+            self.body.push(0xF0);
+            self.body.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        self.body.extend_from_slice(&s);
+        self.backlen(start);
+    }
+
+    /// Ends the element that started at `start` with its length, in 7-bit
+    /// groups from the most significant, all but that one flagged.
+    fn backlen(&mut self, start: usize) {
+        let len = self.body.len() - start;
+        let groups = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        for i in (0..groups).rev() {
+            let group = ((len >> (7 * i)) & 0x7F) as u8;
+            self.body
+                .push(if i == groups - 1 { group } else { group | 0x80 });
+        }
+        self.len += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.body.len() + 1;
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+        out.extend_from_slice(&(self.len.min(u16::MAX as usize) as u16).to_le_bytes());
+        out.extend_from_slice(&self.body);
+        out.push(0xFF);
+        out
+    }
+}
+
+/// CRC-64/Jones lookup table (reflected polynomial), as used by Redis.
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95AC_9329_AC4B_C9B5
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues a CRC64 over `data`; start from 0.
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        crc = CRC64_TABLE[((crc ^ u64::from(byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// How a snapshot is written, from `rdbcompression` and `rdbchecksum`.
+#[derive(Debug, Clone, Copy)]
+pub struct SaveOptions {
+    pub compression: bool,
+    pub checksum: bool,
+}
+
+/// Builds an RDB payload.
+struct Writer {
+    out: Vec<u8>,
+    compression: bool,
+}
+
+impl Writer {
+    fn length(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.out.push(len as u8);
+        } else if len < 1 << 14 {
+            self.out
+                .extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+        } else if let Ok(len) = u32::try_from(len) {
+            self.out.push(0x80);
+            self.out.extend_from_slice(&len.to_be_bytes());
+        } else {
+            self.out.push(0x81);
+            self.out.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    /// Writes a string, as an integer when it is the canonical form of one
+    /// that fits in 32 bits and LZF-compressed when that pays off.
+    fn string(&mut self, s: &[u8]) {
+        if s.len() <= 11
+            && let Some(n) = std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .filter(|n| n.to_string().as_bytes() == s)
+        {
+            if let Ok(n) = i8::try_from(n) {
+                self.out
+                    .extend_from_slice(&[0xC0 | ENC_INT8 as u8, n as u8]);
+                return;
+            }
+            if let Ok(n) = i16::try_from(n) {
+                self.out.push(0xC0 | ENC_INT16 as u8);
+                self.out.extend_from_slice(&n.to_le_bytes());
+                return;
+            }
+            if let Ok(n) = i32::try_from(n) {
+                self.out.push(0xC0 | ENC_INT32 as u8);
+                self.out.extend_from_slice(&n.to_le_bytes());
+                return;
+            }
+        }
+        if self.compression
+            && s.len() > 20
+            && let Some(compressed) = lzf_compress(s)
+        {
+            self.out.push(0xC0 | ENC_LZF as u8);
+            self.length(compressed.len() as u64);
+            self.length(s.len() as u64);
+            self.out.extend_from_slice(&compressed);
+            return;
+        }
+        self.length(s.len() as u64);
+        self.out.extend_from_slice(s);
+    }
+
+    /// Writes a key: its value's type byte, the name, then the value.
+    fn value(&mut self, key: &str, value: &Value) {
+        self.out.push(value_type(value));
+        self.string(&binary::encode(key));
+        self.object(value);
+    }
+
+    /// Writes a value without its type byte.
+    fn object(&mut self, value: &Value) {
+        match value {
+            Value::String(s) => self.string(&binary::encode(s)),
+            Value::Int(n) => self.string(n.to_string().as_bytes()),
+            Value::List(list) => {
+                self.length(list.len() as u64);
+                for item in list {
+                    self.string(&binary::encode(item));
+                }
+            }
+            Value::Set(set) => {
+                self.length(set.len() as u64);
+                for member in set {
+                    self.string(&binary::encode(member));
+                }
+            }
+            Value::Hash(hash) => {
+                self.length(hash.len() as u64);
+                for (field, value) in hash {
+                    self.string(&binary::encode(field));
+                    self.string(&binary::encode(value));
+                }
+            }
+            Value::ZSet(zset) => {
+                self.length(zset.len() as u64);
+                for (member, score) in zset {
+                    self.string(&binary::encode(member));
+                    self.out.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+            Value::Stream(stream) => self.stream(stream),
+            Value::Bloom(bloom) => self.bloom(bloom),
+        }
+    }
+
+    /// Writes a bloom filter as RedisBloom does, as a module value whose
+    /// fields each start with their type: the chain's item count, filter
+    /// count, options and expansion, then each filter's sizing, bits and
+    /// item count.
+    fn bloom(&mut self, bloom: &Bloom) {
+        self.length(module_type_id(BLOOM_MODULE_TYPE, BLOOM_ENCODING_VERSION));
+        self.module_uint(bloom.size());
+        self.module_uint(bloom.filters().len() as u64);
+        self.module_uint(bloom.options());
+        self.module_uint(u64::from(bloom.expansion()));
+        for filter in bloom.filters() {
+            self.module_uint(filter.capacity);
+            self.module_double(filter.error_rate);
+            self.module_uint(u64::from(filter.hashes));
+            self.module_double(filter.bpe);
+            self.module_uint(filter.bits);
+            self.module_uint(filter.n2);
+            self.length(MODULE_OPCODE_STRING);
+            self.string(&filter.data);
+            self.module_uint(filter.size);
+        }
+        self.length(MODULE_OPCODE_EOF);
+    }
+
+    fn module_uint(&mut self, n: u64) {
+        self.length(MODULE_OPCODE_UINT);
+        self.length(n);
+    }
+
+    fn module_double(&mut self, n: f64) {
+        self.length(MODULE_OPCODE_DOUBLE);
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// Writes a stream as Redis 7.0 does: listpack nodes of up to
+    /// NODE_ENTRIES entries, keyed by their first entry's ID, whose
+    /// fields the entries that share them leave out; then the stream's IDs
+    /// and counters, and its consumer groups.
+    fn stream(&mut self, stream: &Stream) {
+        let entries: Vec<_> = stream.iter().collect();
+        let nodes = entries.chunks(NODE_ENTRIES);
+        self.length(nodes.len() as u64);
+        for node in nodes {
+            let (master, master_fields) = node[0];
+            self.string(&raw_id(master));
+
+            let mut lp = Listpack::default();
+            lp.int(node.len() as i64);
+            lp.int(0); // deleted entries
+            lp.int(master_fields.len() as i64);
+            for (field, _) in master_fields {
+                lp.string(field);
+            }
+            lp.int(0);
+            for &(id, fields) in node {
+                let same_fields = fields.len() == master_fields.len()
+                    && fields.iter().zip(master_fields).all(|(a, b)| a.0 == b.0);
+                lp.int(if same_fields {
+                    STREAM_ITEM_FLAG_SAMEFIELDS
+                } else {
+                    0
+                });
+                lp.int(id.ms.wrapping_sub(master.ms) as i64);
+                lp.int(id.seq.wrapping_sub(master.seq) as i64);
+                if same_fields {
+                    for (_, value) in fields {
+                        lp.string(value);
+                    }
+                    lp.int(fields.len() as i64 + 3);
+                } else {
+                    lp.int(fields.len() as i64);
+                    for (field, value) in fields {
+                        lp.string(field);
+                        lp.string(value);
+                    }
+                    lp.int(fields.len() as i64 * 2 + 4);
+                }
+            }
+            self.string(&lp.finish());
+        }
+
+        let (last, first, max_deleted) =
+            (stream.last_id(), stream.first_id(), stream.max_deleted_id());
+        self.length(stream.len() as u64);
+        for id in [last, first, max_deleted] {
+            self.length(id.ms);
+            self.length(id.seq);
+        }
+        self.length(stream.entries_added());
+        self.length(stream.groups().len() as u64);
+        for (name, group) in stream.groups() {
+            self.string(&binary::encode(name));
+            self.length(group.last_id().ms);
+            self.length(group.last_id().seq);
+            self.length(group.entries_read().unwrap_or(u64::MAX));
+            self.length(group.pending().len() as u64);
+            for (&id, pending) in group.pending() {
+                self.out.extend_from_slice(&raw_id(id));
+                self.out
+                    .extend_from_slice(&pending.delivery_time.to_le_bytes());
+                self.length(pending.delivery_count);
+            }
+            self.length(group.consumers().len() as u64);
+            for (consumer, state) in group.consumers() {
+                self.string(&binary::encode(consumer));
+                self.out.extend_from_slice(&state.seen_time.to_le_bytes());
+                self.length(state.pending.len() as u64);
+                for &id in &state.pending {
+                    self.out.extend_from_slice(&raw_id(id));
+                }
+            }
+        }
+    }
+
+    fn aux(&mut self, name: &str, value: &str) {
+        self.out.push(OPCODE_AUX);
+        self.string(&binary::encode(name));
+        self.string(&binary::encode(value));
+    }
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) | Value::Int(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::Hash(_) => TYPE_HASH,
+        Value::ZSet(_) => TYPE_ZSET_2,
+        Value::Stream(_) => TYPE_STREAM_LISTPACKS_2,
+        Value::Bloom(_) => TYPE_MODULE_2,
+    }
+}
+
+/// Serializes a value for DUMP: its RDB type byte and encoding, followed by
+/// the RDB version as two little-endian bytes and a CRC64 of everything
+/// before it.
+pub fn dump(value: &Value, compression: bool) -> Vec<u8> {
+    let mut writer = Writer {
+        out: vec![value_type(value)],
+        compression,
+    };
+    writer.object(value);
+    writer
+        .out
+        .extend_from_slice(&(RDB_SAVE_VERSION as u16).to_le_bytes());
+    let checksum = crc64(0, &writer.out);
+    writer.out.extend_from_slice(&checksum.to_le_bytes());
+    writer.out
+}
+
+/// Decodes a DUMP payload for RESTORE, after checking its version footer
+/// and checksum. Errors are ready to be sent to the client.
+pub fn undump(payload: &[u8]) -> Result<Value, String> {
+    const WRONG_PAYLOAD: &str = "ERR DUMP payload version or checksum are wrong";
+    let Some(body_len) = payload.len().checked_sub(10) else {
+        return Err(WRONG_PAYLOAD.to_string());
+    };
+    let (body, footer) = payload.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if u32::from(version) > RDB_VERSION || crc64(0, &payload[..body_len + 2]) != checksum {
+        return Err(WRONG_PAYLOAD.to_string());
+    }
+
+    let mut reader = Reader::new(body);
+    let value = reader
+        .u8()
+        .and_then(|kind| read_value(&mut reader, kind))
+        .ok()
+        .flatten()
+        .filter(|_| reader.is_empty());
+    value.ok_or_else(|| "ERR Bad data format".to_string())
+}
+
+/// Encodes a snapshot as an RDB file. Values use the plain (non-compact)
+/// type encodings, which every Redis version can load.
+pub fn encode(dbs: &[DbSnapshot], options: SaveOptions) -> Vec<u8> {
+    encode_with_aux(dbs, options, &[])
+}
+
+/// Encodes a snapshot for a replica's full synchronization, recording the
+/// database the replication stream has selected, if any, so the replica
+/// applies the writes that follow to the right one.
+pub fn encode_for_replica(
+    dbs: &[DbSnapshot],
+    options: SaveOptions,
+    stream_db: Option<usize>,
+) -> Vec<u8> {
+    match stream_db {
+        Some(db) => encode_with_aux(dbs, options, &[("repl-stream-db", db.to_string())]),
+        None => encode(dbs, options),
+    }
+}
+
+fn encode_with_aux(dbs: &[DbSnapshot], options: SaveOptions, aux: &[(&str, String)]) -> Vec<u8> {
+    let mut writer = Writer {
+        out: format!("REDIS{:04}", RDB_SAVE_VERSION).into_bytes(),
+        compression: options.compression,
+    };
+    writer.aux("redis-ver", "7.0.0");
+    writer.aux("redis-bits", &(usize::BITS).to_string());
+    writer.aux("ctime", &(now_ms() / 1000).to_string());
+    writer.aux("used-mem", &memory::used_memory().to_string());
+    for (name, value) in aux {
+        writer.aux(name, value);
+    }
+    for (index, keys) in dbs.iter().enumerate().filter(|(_, keys)| !keys.is_empty()) {
+        writer.out.push(OPCODE_SELECTDB);
+        writer.length(index as u64);
+        writer.out.push(OPCODE_RESIZEDB);
+        writer.length(keys.len() as u64);
+        writer.length(keys.iter().filter(|(_, _, at)| at.is_some()).count() as u64);
+        for (key, value, expires_at) in keys {
+            if let Some(at) = expires_at {
+                writer.out.push(OPCODE_EXPIRETIME_MS);
+                writer.out.extend_from_slice(&at.to_le_bytes());
+            }
+            writer.value(key, value);
+        }
+    }
+    writer.out.push(OPCODE_EOF);
+    let checksum = if options.checksum {
+        crc64(0, &writer.out)
+    } else {
+        0
+    };
+    writer.out.extend_from_slice(&checksum.to_le_bytes());
+    writer.out
+}
+
+/// Encodes a snapshot as the contents of an RDB file, encrypted with the
+/// keyring's current key when there is one.
+fn encode_file(
+    dbs: &[DbSnapshot],
+    options: SaveOptions,
+    keyring: Option<&Keyring>,
+) -> io::Result<Vec<u8>> {
+    let data = encode(dbs, options);
+    match keyring {
+        Some(keyring) => keyring.seal_file(&data),
+        None => Ok(data),
+    }
+}
+
+/// Writes `data` to a temporary file next to `path`, syncs it and renames
+/// it into place, so a crash never leaves a half-written snapshot behind.
+pub fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// A running BGSAVE: the thread writing the file and the number of changes
+/// it will have persisted.
+#[derive(Debug)]
+struct BgSave {
+    handle: JoinHandle<io::Result<()>>,
+    dirty: u64,
+    started: Instant,
+}
+
+/// Snapshotting state reported by `INFO persistence`.
+#[derive(Debug)]
+pub struct SaveState {
+    bgsave: Mutex<Option<BgSave>>,
+    last_bgsave_ok: AtomicBool,
+    /// Duration of the last BGSAVE in seconds; -1 before the first.
+    last_bgsave_secs: AtomicI64,
+    /// Unix time of the last BGSAVE attempt, successful or not.
+    last_bgsave_try: AtomicU64,
+    /// Set by BGSAVE SCHEDULE while another save runs; the cron starts it.
+    scheduled: AtomicBool,
+}
+
+impl SaveState {
+    pub fn new() -> Self {
+        SaveState {
+            bgsave: Mutex::new(None),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_secs: AtomicI64::new(-1),
+            last_bgsave_try: AtomicU64::new(0),
+            scheduled: AtomicBool::new(false),
+        }
+    }
+
+    /// Asks the cron to start a BGSAVE once the running one is done.
+    pub fn schedule_bgsave(&self) {
+        self.scheduled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn bgsave_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Relaxed)
+    }
+
+    /// Whether an automatic BGSAVE may start now: always after a success,
+    /// but only every few seconds while saves keep failing.
+    pub fn may_start_bgsave(&self, now_secs: u64) -> bool {
+        self.last_bgsave_ok()
+            || now_secs.saturating_sub(self.last_bgsave_try.load(Ordering::Relaxed))
+                > BGSAVE_RETRY_DELAY_SECS
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave.lock().unwrap().is_some()
+    }
+
+    /// Seconds the running BGSAVE has taken so far; -1 when none is running.
+    pub fn current_bgsave_secs(&self) -> i64 {
+        self.bgsave
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(-1, |bgsave| bgsave.started.elapsed().as_secs() as i64)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn last_bgsave_secs(&self) -> i64 {
+        self.last_bgsave_secs.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for SaveState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn save_target(server: &Server) -> (PathBuf, SaveOptions) {
+    let config = server.config.read().unwrap();
+    let options = SaveOptions {
+        compression: config.rdbcompression,
+        checksum: config.rdbchecksum,
+    };
+    (config.rdb_path(), options)
+}
+
+/// Records a successful save of a dataset that had `dirty` changes.
+fn saved(server: &Server, dirty: u64) {
+    let _ = server
+        .stats
+        .dirty
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |now| {
+            Some(now.saturating_sub(dirty))
+        });
+    server.lastsave.store(now_ms() / 1000, Ordering::Relaxed);
+}
+
+/// Saves the dataset in the foreground, as SAVE does.
+pub fn save(server: &Server) -> Result<(), String> {
+    if server.rdb.bgsave_in_progress() {
+        return Err("ERR Background save already in progress".to_string());
+    }
+    let (path, options) = save_target(server);
+    // Read before copying, so changes racing with the copy stay counted
+    let dirty = Stats::get(&server.stats.dirty);
+    let data = encode_file(&server.storage.snapshot(), options, server.keyring.as_ref());
+    match data.and_then(|data| write_file(&path, &data)) {
+        Ok(()) => {
+            tracing::info!("DB saved on disk");
+            saved(server, dirty);
+            server.rdb.last_bgsave_ok.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(e) => {
+            tracing::warn!("Failed saving the DB to {}: {}", path.display(), e);
+            Err("ERR".to_string())
+        }
+    }
+}
+
+/// Snapshots the dataset and writes it from a background thread, as BGSAVE
+/// does, while writes go on. The cron notices when the thread is done.
+pub fn bgsave(server: &Server) -> Result<(), String> {
+    let mut bgsave = server.rdb.bgsave.lock().unwrap();
+    if bgsave.is_some() {
+        return Err("ERR Background save already in progress".to_string());
+    }
+    let state = &server.rdb;
+    state.scheduled.store(false, Ordering::Relaxed);
+    state
+        .last_bgsave_try
+        .store(now_ms() / 1000, Ordering::Relaxed);
+    let (path, options) = save_target(server);
+    let dirty = Stats::get(&server.stats.dirty);
+    let dbs = server.storage.snapshot();
+    let keyring = server.keyring.clone();
+    let handle = thread::Builder::new()
+        .name("bgsave".to_string())
+        .spawn(move || {
+            let data = encode_file(&dbs, options, keyring.as_ref())?;
+            write_file(&path, &data)
+        })
+        .map_err(|e| {
+            state.last_bgsave_ok.store(false, Ordering::Relaxed);
+            format!("ERR Can't save in background: {}", e)
+        })?;
+    *bgsave = Some(BgSave {
+        handle,
+        dirty,
+        started: Instant::now(),
+    });
+    tracing::info!("Background saving started");
+    Ok(())
+}
+
+/// Reaps a finished BGSAVE, if any, and records its outcome.
+pub fn check_bgsave_done(server: &Server) {
+    reap_bgsave(server, false);
+}
+
+/// Waits for a running BGSAVE to finish, as shutdown does before its own
+/// final save.
+pub fn wait_bgsave(server: &Server) {
+    reap_bgsave(server, true);
+}
+
+fn reap_bgsave(server: &Server, wait: bool) {
+    let mut bgsave = server.rdb.bgsave.lock().unwrap();
+    if !bgsave
+        .as_ref()
+        .is_some_and(|bgsave| wait || bgsave.handle.is_finished())
+    {
+        return;
+    }
+    let BgSave {
+        handle,
+        dirty,
+        started,
+    } = bgsave.take().unwrap();
+    let state = &server.rdb;
+    state
+        .last_bgsave_secs
+        .store(started.elapsed().as_secs() as i64, Ordering::Relaxed);
+    let ok = match handle.join() {
+        Ok(Ok(())) => {
+            tracing::info!("Background saving terminated with success");
+            saved(server, dirty);
+            true
+        }
+        Ok(Err(e)) => {
+            tracing::warn!("Background saving error: {}", e);
+            false
+        }
+        Err(_) => {
+            tracing::warn!("Background saving terminated by a panic");
+            false
+        }
+    };
+    state.last_bgsave_ok.store(ok, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::GroupRead;
+
+    /// Builds an RDB payload from its body (everything between the header
+    /// and the EOF opcode), with a zero checksum.
+    fn rdb(version: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = format!("REDIS{:04}", version).into_bytes();
+        data.extend_from_slice(body);
+        data.push(OPCODE_EOF);
+        data.extend_from_slice(&[0; 8]);
+        data
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = vec![s.len() as u8];
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    #[test]
+    fn test_load_plain_types() {
+        let mut body = vec![OPCODE_AUX];
+        body.extend(string("redis-ver"));
+        body.extend(string("7.2.4"));
+        body.extend([OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 3, 1]);
+        body.push(TYPE_STRING);
+        body.extend(string("greeting"));
+        body.extend(string("hello"));
+        // An int8-encoded string under a future expiration
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend((now_ms() + 60_000).to_le_bytes());
+        body.push(TYPE_STRING);
+        body.extend(string("counter"));
+        body.extend([0xC0, 0xF6]);
+        // Already expired
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend(1000u64.to_le_bytes());
+        body.push(TYPE_STRING);
+        body.extend(string("old"));
+        body.extend(string("x"));
+        body.extend([OPCODE_SELECTDB, 2, TYPE_LIST]);
+        body.extend(string("list"));
+        body.push(2);
+        body.extend(string("a"));
+        body.extend(string("b"));
+        body.push(TYPE_HASH);
+        body.extend(string("hash"));
+        body.push(1);
+        body.extend(string("f"));
+        body.extend(string("v"));
+        // Scores as text, or 254 for +inf
+        body.push(TYPE_ZSET);
+        body.extend(string("zset"));
+        body.push(2);
+        body.extend(string("a"));
+        body.extend(string("1.5"));
+        body.extend(string("b"));
+        body.push(254);
+
+        let storage = Storage::new();
+        let stats = load(&rdb(11, &body), &storage, true).unwrap();
+        assert_eq!(
+            stats,
+            LoadStats {
+                keys: 5,
+                expired: 1,
+                skipped: 0,
+                stream_db: None,
+            }
+        );
+        assert_eq!(storage.db(0).get("greeting"), Some("hello".to_string()));
+        assert_eq!(storage.db(0).get("counter"), Some("-10".to_string()));
+        assert!(storage.db(0).ttl("counter") > 0);
+        assert_eq!(storage.db(0).get("old"), None);
+        assert_eq!(
+            storage.db(2).lrange("list", 0, -1).unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(
+            storage.db(2).hget("hash", "f").unwrap(),
+            Some("v".to_string())
+        );
+        assert_eq!(storage.db(2).zscore("zset", "a"), Ok(Some(1.5)));
+        assert_eq!(storage.db(2).zscore("zset", "b"), Ok(Some(f64::INFINITY)));
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        // listpack ["a", 5, -2]: 6-bit string, 7-bit uint, 13-bit int
+        let listpack = [0, 0, 0, 0, 3, 0, 0x81, b'a', 2, 5, 1, 0xDF, 0xFE, 2, 0xFF];
+        assert_eq!(listpack_entries(&listpack).unwrap(), ["a", "5", "-2"]);
+
+        // ziplist ["ab", 7, 300]
+        let ziplist = [
+            0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0x02, b'a', b'b', 4, 0xF8, 2, 0xC0, 0x2C, 0x01, 0xFF,
+        ];
+        assert_eq!(ziplist_entries(&ziplist).unwrap(), ["ab", "7", "300"]);
+
+        // intset of int16 [1, -1]
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0xFF, 0xFF];
+        assert_eq!(intset_entries(&intset).unwrap(), ["1", "-1"]);
+
+        // zipmap {"k": "v"}
+        let zipmap = [1, 1, b'k', 1, 0, b'v', 0xFF];
+        assert_eq!(zipmap_entries(&zipmap).unwrap(), ["k", "v"]);
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let storage = Storage::new();
+        assert!(load(b"NOTREDIS", &storage, true).is_err());
+        assert!(load(&rdb(99, &[]), &storage, true).is_err());
+        // Truncated before EOF
+        assert!(load(b"REDIS0011\xfe\x00\x00\x01k", &storage, true).is_err());
+        // Database out of range
+        assert!(load(&rdb(11, &[OPCODE_SELECTDB, 63]), &storage, true).is_err());
+    }
+
+    #[test]
+    fn test_walk_reports_items_and_offsets() {
+        let storage = Storage::new();
+        storage.db(3).set("k".to_string(), "v".to_string());
+        let options = SaveOptions {
+            compression: true,
+            checksum: true,
+        };
+        let data = encode(&storage.snapshot(), options);
+        let mut items = Vec::new();
+        let checksum = walk(&data, true, |item| {
+            items.push(match item {
+                Item::Aux(name, _) => name,
+                Item::SelectDb(index) => format!("db{}", index),
+                Item::Key { kind, key, .. } => format!("{} {}", type_name(kind), key),
+            });
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(checksum, Some(crc64(0, &data[..data.len() - 8])));
+        assert_eq!(
+            items,
+            [
+                "redis-ver",
+                "redis-bits",
+                "ctime",
+                "used-mem",
+                "db3",
+                "string k"
+            ]
+        );
+
+        // Errors point at where the file stops making sense
+        let (offset, _) = walk(&data[..data.len() - 12], true, |_| Ok(())).unwrap_err();
+        assert_eq!(offset, data.len() - 12);
+        // Just past the header and the redis-ver aux field
+        let (offset, e) = walk(&data, true, |_| Err("stop".to_string())).unwrap_err();
+        assert_eq!((offset, e.as_str()), (26, "stop"));
+    }
+
+    #[test]
+    fn test_crc64() {
+        // Check value of CRC-64/Jones, from Redis' crc64.c
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_dump_payloads() {
+        // DUMP of the integer 10 by Redis 2.6, from the DUMP documentation
+        let payload = b"\x00\xc0\n\x06\x00\xf8r?\xc5\xfb\xfb_(";
+        assert!(matches!(undump(payload), Ok(Value::Int(10))));
+
+        let hash = HashMap::from([("f".to_string(), "v".repeat(100))]);
+        let payload = dump(&Value::Hash(hash.clone().into()), true);
+        assert!(payload.len() < 50);
+        assert!(matches!(undump(&payload), Ok(Value::Hash(h)) if h == hash.into()));
+
+        let mut corrupt = payload.clone();
+        corrupt[3] ^= 1;
+        assert!(undump(&corrupt).unwrap_err().contains("checksum"));
+        assert!(undump(&payload[1..]).is_err());
+        assert!(undump(b"short").is_err());
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let mut lp = Listpack::default();
+        let values = [
+            "0",
+            "127",
+            "-1",
+            "-4096",
+            "4095",
+            "-32768",
+            "8388607",
+            "-2147483648",
+        ];
+        for value in values {
+            lp.string(value);
+        }
+        lp.int(i64::MIN);
+        lp.string("007");
+        lp.string(&"x".repeat(5000));
+        let mut expected: Vec<String> = values.iter().map(|s| s.to_string()).collect();
+        expected.extend([i64::MIN.to_string(), "007".to_string(), "x".repeat(5000)]);
+        assert_eq!(listpack_entries(&lp.finish()), Ok(expected));
+
+        // Enough entries for several nodes, the sequence number going down
+        // from a node's first entry, and fields that differ from its own
+        let mut entries = BTreeMap::new();
+        for i in 0..250u64 {
+            let mut fields = vec![("n".to_string(), i.to_string())];
+            if i % 7 == 0 {
+                fields.push(("extra".to_string(), "y".repeat(i as usize)));
+            }
+            entries.insert(StreamId::new(1_700_000_000_000 + i / 3, 5 - i % 3), fields);
+        }
+        let mut stream = Stream::from_parts(
+            entries,
+            StreamId::new(1_800_000_000_000, 0),
+            StreamId::new(3, 1),
+            300,
+            BTreeMap::new(),
+        );
+        // Groups with entries pending and one never read from
+        stream.create_group("g", StreamId::MIN, None);
+        stream.read_group("g", "alice", GroupRead::New, Some(3), false, 1_000);
+        stream.read_group("g", "bob", GroupRead::New, Some(2), false, 2_000);
+        stream.create_group("h", StreamId::MAX, Some(7));
+        assert_eq!(stream.group("g").unwrap().pending().len(), 5);
+        let value = Value::Stream(stream.clone());
+        for compression in [true, false] {
+            assert!(
+                matches!(undump(&dump(&value, compression)), Ok(Value::Stream(s)) if s == stream)
+            );
+        }
+        let empty = Value::Stream(Stream::new());
+        assert!(matches!(undump(&dump(&empty, true)), Ok(Value::Stream(s)) if s.is_empty()));
+    }
+
+    #[test]
+    fn test_bloom_roundtrip() {
+        let id = module_type_id("MBbloom--", 4);
+        assert_eq!(id, 0x3016e5a289bef804);
+        assert_eq!(module_type(id), ("MBbloom--".to_string(), 4));
+
+        // A chain that has grown, and one that can't
+        let mut bloom = Bloom::new(50, 0.001, Some(3)).unwrap();
+        for n in 0..250 {
+            bloom.add(&n.to_string()).unwrap();
+        }
+        assert_eq!(bloom.filters().len(), 3);
+        let fixed = Bloom::new(10, 0.1, None).unwrap();
+        for (bloom, compression) in [(bloom, true), (fixed, false)] {
+            let payload = dump(&Value::Bloom(bloom.clone()), compression);
+            assert!(matches!(undump(&payload), Ok(Value::Bloom(b)) if b == bloom));
+        }
+
+        let mut writer = Writer {
+            out: Vec::new(),
+            compression: false,
+        };
+        writer.length(module_type_id("ReJSON-RL", 3));
+        assert_eq!(
+            read_value(&mut Reader::new(&writer.out), TYPE_MODULE_2).unwrap_err(),
+            "The RDB file contains module data I can't load: no matching module type 'ReJSON-RL'"
+        );
+        // Filters hashed to 32 bits, or fields of the wrong type
+        let mut writer = Writer {
+            out: Vec::new(),
+            compression: false,
+        };
+        writer.length(module_type_id("MBbloom--", 4));
+        writer.module_uint(0);
+        writer.module_uint(1);
+        writer.module_uint(bloom::OPT_NOROUND);
+        assert!(read_value(&mut Reader::new(&writer.out), TYPE_MODULE_2).is_err());
+        writer.out.truncate(writer.out.len() - 2);
+        writer.module_double(4.0);
+        assert!(read_value(&mut Reader::new(&writer.out), TYPE_MODULE_2).is_err());
+    }
+
+    #[test]
+    fn test_lzf_roundtrip() {
+        let repetitive = "abcabcabcabcabcabcabcabcabcabcabcabc"
+            .repeat(20)
+            .into_bytes();
+        let compressed = lzf_compress(&repetitive).unwrap();
+        assert!(compressed.len() < repetitive.len() / 4);
+        assert_eq!(
+            lzf_decompress(&compressed, repetitive.len()).unwrap(),
+            repetitive
+        );
+
+        let mut mixed = Vec::new();
+        for i in 0u32..5000 {
+            mixed.extend_from_slice(&(i.wrapping_mul(2_654_435_761) % 97).to_le_bytes()[..1]);
+            mixed.extend_from_slice(b"field:");
+        }
+        let compressed = lzf_compress(&mixed).unwrap();
+        assert_eq!(lzf_decompress(&compressed, mixed.len()).unwrap(), mixed);
+
+        // Nothing to gain on short or random-looking input
+        assert_eq!(lzf_compress(b"abcdefgh"), None);
+        // Wrong expected length or a reference before the start
+        assert!(lzf_decompress(&compressed, mixed.len() + 1).is_err());
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let storage = Storage::new();
+        let long = "hello world ".repeat(10);
+        storage.db(0).set("s".to_string(), long.clone());
+        storage.db(0).set("n".to_string(), "-40000".to_string());
+        storage.db(0).set("z".to_string(), "007".to_string());
+        storage
+            .db(0)
+            .set_with_expiry("ttl".to_string(), "x".to_string(), 60_000);
+        storage
+            .db(1)
+            .rpush("l", vec!["a".to_string(), long.clone()])
+            .unwrap();
+        storage
+            .db(1)
+            .sadd("set", vec!["1".to_string(), "2".to_string()])
+            .unwrap();
+        storage
+            .db(3)
+            .hset("h", "f".to_string(), "v".to_string())
+            .unwrap();
+        storage
+            .db(3)
+            .zadd(
+                "zs",
+                vec![(-0.25, "m".to_string())],
+                storage::ZAddOptions::default(),
+            )
+            .unwrap();
+
+        for compression in [true, false] {
+            let options = SaveOptions {
+                compression,
+                checksum: true,
+            };
+            let data = encode(&storage.snapshot(), options);
+            let loaded = Storage::new();
+            let stats = load(&data, &loaded, true).unwrap();
+            assert_eq!(stats.keys, 8);
+            assert_eq!(loaded.db(0).get("s"), Some(long.clone()));
+            assert_eq!(loaded.db(0).get("n"), Some("-40000".to_string()));
+            assert_eq!(loaded.db(0).get("z"), Some("007".to_string()));
+            assert!(loaded.db(0).ttl("ttl") > 0);
+            assert_eq!(
+                loaded.db(1).lrange("l", 0, -1).unwrap(),
+                vec!["a".to_string(), long.clone()]
+            );
+            assert_eq!(loaded.db(1).scard("set").unwrap(), 2);
+            assert_eq!(loaded.db(3).hget("h", "f").unwrap(), Some("v".to_string()));
+            assert_eq!(loaded.db(3).zscore("zs", "m"), Ok(Some(-0.25)));
+            assert_eq!(stats.stream_db, None);
+        }
+
+        // Payloads for replicas carry the database their stream is on
+        let options = SaveOptions {
+            compression: true,
+            checksum: true,
+        };
+        let data = encode_for_replica(&storage.snapshot(), options, Some(3));
+        let stats = load(&data, &Storage::new(), true).unwrap();
+        assert_eq!((stats.keys, stats.stream_db), (8, Some(3)));
+    }
+
+    #[test]
+    fn test_checksum_verification() {
+        let storage = Storage::new();
+        storage.db(0).set("k".to_string(), "v".to_string());
+        let options = SaveOptions {
+            compression: true,
+            checksum: true,
+        };
+        let mut data = encode(&storage.snapshot(), options);
+        let value = data.iter().rposition(|&b| b == b'v').unwrap();
+        data[value] = b'w';
+        let err = load(&data, &Storage::new(), true).unwrap_err();
+        assert!(err.starts_with("Wrong RDB checksum"), "{}", err);
+        // Not checked when verification is off
+        assert!(load(&data, &Storage::new(), false).is_ok());
+
+        // A zero checksum means the file was written without one
+        let data = encode(
+            &storage.snapshot(),
+            SaveOptions {
+                checksum: false,
+                ..options
+            },
+        );
+        assert!(data.ends_with(&[0; 8]));
+        assert!(load(&data, &Storage::new(), true).is_ok());
+    }
+}
@@ -0,0 +1,1647 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc};
+
+use crate::acl;
+use crate::aof::encode_command;
+use crate::clients::{ClientKind, PauseMode};
+use crate::commands::{self, Command};
+use crate::connection::{self, ConnectionContext};
+use crate::parser::parse_request;
+use crate::rdb::{self, SaveOptions};
+use crate::server::Server;
+use crate::stats::Stats;
+use crate::storage::{DbSnapshot, Storage};
+
+/// How long a replica waits before connecting to its primary again after
+/// the link failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often replicas acknowledge the offset they reached.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long FAILOVER pauses writes for; the pause is lifted as soon as the
+/// failover ends.
+const FAILOVER_PAUSE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Initial size of the replication link's read buffer.
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// State of a replica's link to its primary, as `INFO replication` reports
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkState {
+    /// Waiting to connect, or to reconnect after a failure.
+    Connect,
+    /// Connecting and going through the handshake.
+    Connecting,
+    /// Receiving the dataset.
+    Transfer,
+    /// Applying the primary's writes as they happen.
+    Connected,
+}
+
+impl LinkState {
+    pub fn name(self) -> &'static str {
+        match self {
+            LinkState::Connect => "connect",
+            LinkState::Connecting => "connecting",
+            LinkState::Transfer => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+/// The primary a replica follows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Primary {
+    pub host: String,
+    pub port: u16,
+    pub state: LinkState,
+    /// When the primary last sent something, while connected.
+    pub last_io: Option<Instant>,
+    /// When the link last went down, or None if it was never up.
+    pub down_since: Option<Instant>,
+}
+
+/// Progress of a FAILOVER, as `INFO replication` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FailoverState {
+    #[default]
+    None,
+    /// Writes are paused until a replica has applied all of them.
+    WaitingForSync,
+    /// Following the new primary, which was asked to take over with PSYNC
+    /// FAILOVER.
+    InProgress,
+}
+
+impl FailoverState {
+    pub fn name(self) -> &'static str {
+        match self {
+            FailoverState::None => "no-failover",
+            FailoverState::WaitingForSync => "waiting-for-sync",
+            FailoverState::InProgress => "failover-in-progress",
+        }
+    }
+}
+
+/// A FAILOVER under way.
+#[derive(Debug, Clone, Default)]
+struct Failover {
+    state: FailoverState,
+    /// The replica to hand over to: given with TO, or the first one to
+    /// catch up.
+    target: Option<(String, u16)>,
+    /// When to stop waiting for the target to catch up, from TIMEOUT.
+    deadline: Option<Instant>,
+    /// Hand over at the deadline even if the target hasn't caught up.
+    force: bool,
+}
+
+/// What a connection asked for with SYNC or PSYNC. The connection is handed
+/// to `serve_replica` once the command returns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncRequest {
+    Sync,
+    /// The history the replica has and the offset of the first byte of it
+    /// the replica is missing.
+    Psync { replid: String, offset: i64 },
+}
+
+/// The histories of writes the dataset belongs to, as INFO replication
+/// reports them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    /// Identifier of the current history.
+    pub replid: String,
+    /// The history this one branched off when the server was promoted, or
+    /// all zeroes. Replicas of the old primary can still continue it.
+    pub replid2: String,
+    /// Offset up to which `replid2` and `replid` are the same history.
+    pub second_offset: Option<u64>,
+}
+
+impl History {
+    fn new(replid: String) -> Self {
+        History {
+            replid,
+            replid2: "0".repeat(40),
+            second_offset: None,
+        }
+    }
+
+    /// Starts history `replid`, continuing the current one from `offset`.
+    fn shift(&mut self, replid: String, offset: u64) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_offset = Some(offset);
+    }
+
+    /// Whether a replica that has history `replid` up to `offset` has a
+    /// part of this one.
+    fn continues(&self, replid: &str, offset: u64) -> bool {
+        replid == self.replid
+            || (replid == self.replid2 && self.second_offset.is_some_and(|second| offset <= second))
+    }
+}
+
+/// The most recent bytes of the replication stream, kept so a replica
+/// whose link failed can continue where it was instead of syncing again.
+#[derive(Debug)]
+struct Backlog {
+    data: VecDeque<u8>,
+    /// Most bytes kept, from repl-backlog-size.
+    size: usize,
+    /// Stream offset the first byte kept follows.
+    start: u64,
+}
+
+impl Backlog {
+    fn new(size: usize, start: u64) -> Self {
+        Backlog {
+            data: VecDeque::new(),
+            size,
+            start,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        let excess = self.data.len().saturating_sub(self.size);
+        self.data.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.size = size;
+        self.push(&[]);
+    }
+
+    /// The bytes after stream offset `offset`, if all of them are kept.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let skip = usize::try_from(offset.checked_sub(self.start)?).ok()?;
+        (skip <= self.data.len()).then(|| self.data.range(skip..).copied().collect())
+    }
+}
+
+/// The backlog as `INFO replication` reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacklogInfo {
+    pub active: bool,
+    pub size: usize,
+    pub first_byte_offset: u64,
+    pub histlen: usize,
+}
+
+/// A replica attached to this server.
+#[derive(Debug)]
+struct Replica {
+    /// Client id of its connection.
+    id: u64,
+    ip: IpAddr,
+    /// The port it listens on, from REPLCONF listening-port.
+    port: u16,
+    /// It received the dataset and is now fed writes as they happen.
+    online: bool,
+    /// Offset it last acknowledged having applied.
+    ack_offset: u64,
+    /// When it attached or last sent REPLCONF ACK.
+    last_ack: Instant,
+    sender: mpsc::UnboundedSender<Bytes>,
+}
+
+/// An attached replica as `INFO replication` reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaInfo {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub online: bool,
+    pub ack_offset: u64,
+    /// Time since it last sent REPLCONF ACK.
+    pub lag: Duration,
+}
+
+/// The stream of writes replicas are fed.
+#[derive(Debug, Default)]
+struct Feed {
+    /// Database the stream has selected, so SELECT is only sent when it
+    /// changes.
+    db: Option<usize>,
+    /// This server is a replica itself: its replicas get the primary's
+    /// stream as it was received rather than its own writes.
+    proxying: bool,
+    replicas: Vec<Replica>,
+    /// Created when the first replica attaches, and kept from then on.
+    backlog: Option<Backlog>,
+    backlog_size: usize,
+    /// When the primary last pinged its replicas through the stream.
+    last_ping: Option<Instant>,
+}
+
+/// Replication state shared by every connection: the replicas this server
+/// feeds and, when it is a replica itself, the primary it follows.
+#[derive(Debug)]
+pub struct Replication {
+    /// Writes must be sent to replicas; mirrors `Feed` so servers that never
+    /// had replicas don't take its lock.
+    feeding: AtomicBool,
+    feed: Mutex<Feed>,
+    /// Mirrors `primary.is_some()` for the checks every command makes.
+    following: AtomicBool,
+    /// Only changed while holding the `feed` lock, so the stream and the
+    /// history it belongs to always agree.
+    history: Mutex<History>,
+    /// Bytes of stream produced as a primary, or received as a replica.
+    offset: AtomicU64,
+    primary: Mutex<Option<Primary>>,
+    /// Woken up when REPLICAOF changes the primary.
+    primary_changed: Notify,
+    failover: Mutex<Failover>,
+}
+
+/// Exclusive access to the replication stream while a write command runs,
+/// so replicas receive writes in the order they happened.
+pub struct FeedGuard<'a> {
+    replication: &'a Replication,
+    feed: MutexGuard<'a, Feed>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Replication {
+            feeding: AtomicBool::new(false),
+            feed: Mutex::new(Feed::default()),
+            following: AtomicBool::new(false),
+            history: Mutex::new(History::new(new_replid())),
+            offset: AtomicU64::new(0),
+            primary: Mutex::new(None),
+            primary_changed: Notify::new(),
+            failover: Mutex::new(Failover::default()),
+        }
+    }
+
+    /// Locks the stream for a write command, or returns None when no
+    /// replica needs it.
+    pub fn lock(&self) -> Option<FeedGuard<'_>> {
+        if !self.feeding.load(Ordering::Relaxed) {
+            return None;
+        }
+        let feed = self.feed.lock().unwrap();
+        is_feeding(&feed).then_some(FeedGuard {
+            replication: self,
+            feed,
+        })
+    }
+
+    pub fn replid(&self) -> String {
+        self.history.lock().unwrap().replid.clone()
+    }
+
+    pub fn history(&self) -> History {
+        self.history.lock().unwrap().clone()
+    }
+
+    pub fn backlog_info(&self) -> BacklogInfo {
+        let feed = self.feed.lock().unwrap();
+        match &feed.backlog {
+            Some(backlog) => BacklogInfo {
+                active: true,
+                size: backlog.size,
+                first_byte_offset: backlog.start + 1,
+                histlen: backlog.data.len(),
+            },
+            None => BacklogInfo {
+                active: false,
+                size: feed.backlog_size,
+                first_byte_offset: 0,
+                histlen: 0,
+            },
+        }
+    }
+
+    pub fn set_backlog_size(&self, size: usize) {
+        let mut feed = self.feed.lock().unwrap();
+        feed.backlog_size = size;
+        if let Some(backlog) = &mut feed.backlog {
+            backlog.resize(size);
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    /// The primary this server follows, if it is a replica.
+    pub fn primary(&self) -> Option<Primary> {
+        self.primary.lock().unwrap().clone()
+    }
+
+    /// The state of the link to the primary, if this server is a replica.
+    pub fn link_state(&self) -> Option<LinkState> {
+        self.primary.lock().unwrap().as_ref().map(|primary| primary.state)
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.following.load(Ordering::Relaxed)
+    }
+
+    pub fn replicas(&self) -> Vec<ReplicaInfo> {
+        self.feed
+            .lock()
+            .unwrap()
+            .replicas
+            .iter()
+            .map(|replica| ReplicaInfo {
+                ip: replica.ip,
+                port: replica.port,
+                online: replica.online,
+                ack_offset: replica.ack_offset,
+                lag: replica.last_ack.elapsed(),
+            })
+            .collect()
+    }
+
+    /// Starts following `host:port`, or with None stops following any
+    /// primary, as REPLICAOF does. Returns false when nothing changes.
+    ///
+    /// The replicas of this server are disconnected either way: their data
+    /// no longer matches what they would be fed, so they have to sync again.
+    pub fn set_primary(&self, target: Option<(String, u16)>) -> bool {
+        let mut primary = self.primary.lock().unwrap();
+        let current = primary.as_ref().map(|p| (p.host.as_str(), p.port));
+        if current == target.as_ref().map(|(host, port)| (host.as_str(), *port)) {
+            return false;
+        }
+        *primary = target.map(|(host, port)| Primary {
+            host,
+            port,
+            state: LinkState::Connect,
+            last_io: None,
+            down_since: None,
+        });
+        self.following.store(primary.is_some(), Ordering::Relaxed);
+        let mut feed = self.feed.lock().unwrap();
+        if primary.is_none() {
+            // A promoted replica starts a history of its own, which the
+            // other replicas of its old primary can still continue
+            let offset = self.offset();
+            self.history.lock().unwrap().shift(new_replid(), offset);
+        }
+        feed.proxying = primary.is_some();
+        feed.replicas.clear();
+        self.feeding.store(is_feeding(&feed), Ordering::Relaxed);
+        self.primary_changed.notify_one();
+        true
+    }
+
+    pub fn failover_state(&self) -> FailoverState {
+        self.failover.lock().unwrap().state
+    }
+
+    /// Records the offset a replica acknowledged with REPLCONF ACK.
+    fn set_ack(&self, id: u64, offset: u64) {
+        let mut feed = self.feed.lock().unwrap();
+        if let Some(replica) = feed.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    /// Asks every replica to acknowledge the offset it has applied, through
+    /// the stream so the answer covers everything sent before.
+    fn request_acks(&self) {
+        let mut feed = self.feed.lock().unwrap();
+        if is_feeding(&feed) {
+            self.send_command(&mut feed, &["REPLCONF", "GETACK", "*"]);
+        }
+    }
+
+    /// Pings the replicas through the stream if `period` passed since the
+    /// last ping, so they can tell a quiet primary from a dead one, and
+    /// disconnects online replicas that acknowledged nothing for `timeout`.
+    fn heartbeat(&self, period: Duration, timeout: Duration) -> Vec<ReplicaInfo> {
+        let mut feed = self.feed.lock().unwrap();
+        let mut timed_out = Vec::new();
+        feed.replicas.retain(|replica| {
+            let lag = replica.last_ack.elapsed();
+            let alive = !replica.online || lag <= timeout;
+            if !alive {
+                timed_out.push(ReplicaInfo {
+                    ip: replica.ip,
+                    port: replica.port,
+                    online: replica.online,
+                    ack_offset: replica.ack_offset,
+                    lag,
+                });
+            }
+            alive
+        });
+        let due = feed
+            .last_ping
+            .is_none_or(|last_ping| last_ping.elapsed() >= period);
+        if is_feeding(&feed) && !feed.replicas.is_empty() && due {
+            feed.last_ping = Some(Instant::now());
+            self.send_command(&mut feed, &["PING"]);
+        }
+        self.feeding.store(is_feeding(&feed), Ordering::Relaxed);
+        timed_out
+    }
+
+    fn send_command(&self, feed: &mut Feed, args: &[&str]) {
+        let mut buf = Vec::new();
+        encode_command(&mut buf, args);
+        self.send(feed, Bytes::from(buf));
+    }
+
+    fn set_link_state(&self, state: LinkState) {
+        if let Some(primary) = self.primary.lock().unwrap().as_mut() {
+            if state == LinkState::Connected {
+                primary.last_io = Some(Instant::now());
+            } else if primary.state == LinkState::Connected {
+                primary.last_io = None;
+                primary.down_since = Some(Instant::now());
+            }
+            primary.state = state;
+        }
+    }
+
+    /// Records that the primary sent something.
+    fn primary_io(&self) {
+        if let Some(primary) = self.primary.lock().unwrap().as_mut() {
+            primary.last_io = Some(Instant::now());
+        }
+    }
+
+    /// Attaches a replica asking for a full synchronization. Returns the
+    /// dataset it starts from, with the database the stream has selected,
+    /// and the replication id and offset of that point; every later write
+    /// is sent through the replica's channel.
+    fn attach(
+        &self,
+        storage: &Storage,
+        replica: Replica,
+    ) -> (Vec<DbSnapshot>, Option<usize>, String, u64) {
+        // With no command running, every write is either in the snapshot
+        // or sent to the replica, never both or neither
+        let _exclusive = storage.exclusive();
+        let mut feed = self.feed.lock().unwrap();
+        self.start_backlog(&mut feed);
+        feed.replicas.push(replica);
+        self.feeding.store(is_feeding(&feed), Ordering::Relaxed);
+        (storage.snapshot(), feed.db, self.replid(), self.offset())
+    }
+
+    /// Attaches a replica that has history `replid` and misses everything
+    /// from `offset` on, as PSYNC gives them. Returns the id of the history
+    /// it continues and the bytes it missed, or the replica back when the
+    /// backlog no longer holds all of them.
+    fn attach_partial(
+        &self,
+        replid: &str,
+        offset: i64,
+        replica: Replica,
+    ) -> Result<(String, Vec<u8>), Replica> {
+        let mut feed = self.feed.lock().unwrap();
+        let Ok(have) = u64::try_from(offset.saturating_sub(1)) else {
+            return Err(replica);
+        };
+        let history = self.history();
+        if !history.continues(replid, have) {
+            return Err(replica);
+        }
+        let Some(missing) = feed.backlog.as_ref().and_then(|backlog| backlog.since(have)) else {
+            return Err(replica);
+        };
+        feed.replicas.push(replica);
+        Ok((history.replid, missing))
+    }
+
+    fn start_backlog(&self, feed: &mut Feed) {
+        if feed.backlog.is_none() {
+            feed.backlog = Some(Backlog::new(feed.backlog_size, self.offset()));
+        }
+    }
+
+    fn set_online(&self, id: u64) {
+        let mut feed = self.feed.lock().unwrap();
+        if let Some(replica) = feed.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.online = true;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    fn detach(&self, id: u64) {
+        let mut feed = self.feed.lock().unwrap();
+        feed.replicas.retain(|replica| replica.id != id);
+    }
+
+    /// Sends `data` to every replica and the backlog, dropping replicas
+    /// that went away.
+    fn send(&self, feed: &mut Feed, data: Bytes) {
+        self.offset.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if let Some(backlog) = &mut feed.backlog {
+            backlog.push(&data);
+        }
+        feed.replicas
+            .retain(|replica| replica.sender.send(data.clone()).is_ok());
+    }
+
+    /// Passes a command received from the primary on to this server's own
+    /// replicas, once it has been applied with `db` selected afterwards.
+    fn proxy(&self, data: &[u8], db: usize) {
+        let mut feed = self.feed.lock().unwrap();
+        feed.db = Some(db);
+        self.send(&mut feed, Bytes::copy_from_slice(data));
+    }
+
+    /// Forgets the history of a dataset a full synchronization is about to
+    /// replace, so it's never continued should loading the new one fail,
+    /// and disconnects the replicas of it.
+    fn discard_history(&self) {
+        let mut feed = self.feed.lock().unwrap();
+        feed.replicas.clear();
+        feed.backlog = None;
+        *self.history.lock().unwrap() = History::new(new_replid());
+    }
+
+    /// Records a completed full synchronization with the primary: this
+    /// server now continues its history from `offset`.
+    fn synced(&self, replid: String, offset: u64, db: usize) {
+        let mut feed = self.feed.lock().unwrap();
+        *self.history.lock().unwrap() = History::new(replid);
+        self.offset.store(offset, Ordering::Relaxed);
+        feed.db = Some(db);
+        feed.backlog = Some(Backlog::new(feed.backlog_size, offset));
+        drop(feed);
+        self.set_link_state(LinkState::Connected);
+    }
+
+    /// Records a partial resynchronization with the primary, taking on the
+    /// id of its history if it changed, and returns the database the stream
+    /// has selected. Replicas are disconnected when the id changes so they
+    /// learn the new one.
+    fn continued(&self, replid: Option<&str>) -> usize {
+        let mut feed = self.feed.lock().unwrap();
+        if let Some(replid) = replid {
+            let mut history = self.history.lock().unwrap();
+            if history.replid != replid {
+                history.shift(replid.to_string(), self.offset());
+                feed.replicas.clear();
+            }
+        }
+        self.start_backlog(&mut feed);
+        let db = feed.db.unwrap_or(0);
+        drop(feed);
+        self.set_link_state(LinkState::Connected);
+        db
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeedGuard<'_> {
+    /// Sends one command run against database `db` to the replicas.
+    pub fn feed(&mut self, db: usize, args: &[&str]) {
+        let mut buf = Vec::new();
+        if self.feed.db != Some(db) {
+            encode_command(&mut buf, &["select", &db.to_string()]);
+            self.feed.db = Some(db);
+        }
+        encode_command(&mut buf, args);
+        self.replication.send(&mut self.feed, Bytes::from(buf));
+    }
+}
+
+fn is_feeding(feed: &Feed) -> bool {
+    !feed.proxying && feed.backlog.is_some()
+}
+
+fn new_replid() -> String {
+    acl::genpass(160).unwrap_or_else(|_| "0".repeat(40))
+}
+
+/// Serves a connection that sent SYNC or PSYNC for the rest of its life:
+/// the writes it missed from the backlog when it can continue its history,
+/// a snapshot of the dataset as an RDB payload otherwise, then every write
+/// from that point on.
+pub async fn serve_replica(
+    stream: &mut TcpStream,
+    server: &Server,
+    ctx: &ConnectionContext,
+    request: SyncRequest,
+) {
+    let Some(client) = server.clients.get(ctx.id) else {
+        return;
+    };
+    let ip = client.addr.ip();
+    let port = ctx.replica_port.unwrap_or(client.addr.port());
+    // Writes are small and latency matters more than packet counts
+    let _ = stream.set_nodelay(true);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let replica = Replica {
+        id: ctx.id,
+        ip,
+        port,
+        online: false,
+        ack_offset: 0,
+        last_ack: Instant::now(),
+        sender,
+    };
+
+    let sent = match request {
+        SyncRequest::Sync => full_sync(stream, server, replica, false).await,
+        SyncRequest::Psync { replid, offset } => {
+            match server.replication.attach_partial(&replid, offset, replica) {
+                Ok((replid, missing)) => {
+                    Stats::add(&server.stats.sync_partial_ok, 1);
+                    tracing::info!(
+                        "Partial resynchronization request from {}:{} accepted, sending {} bytes of backlog starting from offset {}",
+                        ip,
+                        port,
+                        missing.len(),
+                        offset
+                    );
+                    let header = format!("+CONTINUE {}\r\n", replid);
+                    write_sync(stream, server, &header, &missing).await
+                }
+                Err(replica) => {
+                    // "?" asks for a full resync outright, anything else
+                    // is a history we no longer have
+                    if replid != "?" {
+                        Stats::add(&server.stats.sync_partial_err, 1);
+                    }
+                    full_sync(stream, server, replica, true).await
+                }
+            }
+        }
+    };
+    if let Err(e) = sent {
+        tracing::warn!("Failed to synchronize replica {}:{}: {}", ip, port, e);
+        server.replication.detach(ctx.id);
+        return;
+    }
+    server.replication.set_online(ctx.id);
+    tracing::info!("Synchronization with replica {}:{} succeeded", ip, port);
+
+    let mut input = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    loop {
+        tokio::select! {
+            data = receiver.recv() => {
+                // Disconnected by REPLICAOF or a new sync of our own
+                let Some(data) = data else { break };
+                Stats::add(&server.stats.net_output_bytes, data.len() as u64);
+                let timeout = Duration::from_secs(server.config.read().unwrap().repl_timeout);
+                match tokio::time::timeout(timeout, stream.write_all(&data)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => break,
+                    Err(_) => {
+                        tracing::warn!("Disconnecting timedout replica {}:{}", ip, port);
+                        break;
+                    }
+                }
+            }
+            read = stream.read_buf(&mut input) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    Stats::add(&server.stats.net_input_bytes, n as u64);
+                    if read_acks(server, ctx.id, &mut input).is_err() {
+                        break;
+                    }
+                }
+            },
+            _ = client.kill.notified() => break,
+        }
+    }
+    server.replication.detach(ctx.id);
+    tracing::info!("Connection with replica {}:{} lost", ip, port);
+}
+
+/// Handles the commands a replica sent, of which only REPLCONF ACK matters.
+fn read_acks(server: &Server, id: u64, input: &mut BytesMut) -> Result<(), String> {
+    while let Some((parts, used)) = parse_request(input)? {
+        if let [name, option, offset] = parts.as_slice()
+            && name.eq_ignore_ascii_case(b"replconf")
+            && option.eq_ignore_ascii_case(b"ack")
+            && let Some(offset) = std::str::from_utf8(offset)
+                .ok()
+                .and_then(|offset| offset.parse().ok())
+        {
+            server.replication.set_ack(id, offset);
+        }
+        let _ = input.split_to(used);
+    }
+    Ok(())
+}
+
+/// Attaches a replica and sends it a snapshot of the dataset, preceded by
+/// +FULLRESYNC when it asked with PSYNC.
+async fn full_sync(
+    stream: &mut TcpStream,
+    server: &Server,
+    replica: Replica,
+    psync: bool,
+) -> std::io::Result<()> {
+    let (ip, port) = (replica.ip, replica.port);
+    let (dbs, stream_db, replid, offset) = server.replication.attach(&server.storage, replica);
+    Stats::add(&server.stats.sync_full, 1);
+    tracing::info!(
+        "Replica {}:{} asks for synchronization, starting a full resync at offset {}",
+        ip,
+        port,
+        offset
+    );
+
+    let options = {
+        let config = server.config.read().unwrap();
+        SaveOptions {
+            compression: config.rdbcompression,
+            checksum: true,
+        }
+    };
+    let payload =
+        tokio::task::spawn_blocking(move || rdb::encode_for_replica(&dbs, options, stream_db))
+            .await
+            .map_err(std::io::Error::other)?;
+    let mut header = if psync {
+        format!("+FULLRESYNC {} {}\r\n", replid, offset)
+    } else {
+        String::new()
+    };
+    header.push_str(&format!("${}\r\n", payload.len()));
+    write_sync(stream, server, &header, &payload).await
+}
+
+async fn write_sync(
+    stream: &mut TcpStream,
+    server: &Server,
+    header: &str,
+    data: &[u8],
+) -> std::io::Result<()> {
+    Stats::add(
+        &server.stats.net_output_bytes,
+        (header.len() + data.len()) as u64,
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(data).await
+}
+
+/// Follows the primary set with REPLICAOF for as long as the server runs,
+/// reconnecting whenever the link fails.
+pub async fn run(server: Arc<Server>) {
+    let replication = &server.replication;
+    loop {
+        let Some(primary) = replication.primary() else {
+            replication.primary_changed.notified().await;
+            continue;
+        };
+        let result = tokio::select! {
+            result = follow(&server, &primary.host, primary.port) => result,
+            _ = replication.primary_changed.notified() => continue,
+        };
+        if let Err(e) = result {
+            tracing::warn!(
+                "Replication link with primary {}:{} failed: {}",
+                primary.host,
+                primary.port,
+                e
+            );
+            if replication.failover_state() == FailoverState::InProgress {
+                abort_failover(&server, "Failover target rejected psync request");
+                continue;
+            }
+        }
+        replication.set_link_state(LinkState::Connect);
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = replication.primary_changed.notified() => {}
+        }
+    }
+}
+
+/// Connects to the primary, loads its dataset and applies its writes until
+/// the link fails.
+async fn follow(server: &Server, host: &str, port: u16) -> Result<(), String> {
+    let replication = &server.replication;
+    replication.set_link_state(LinkState::Connecting);
+    tracing::info!("Connecting to primary {}:{}", host, port);
+    let timeout = Duration::from_secs(server.config.read().unwrap().repl_timeout);
+    let stream = match tokio::time::timeout(timeout, TcpStream::connect((host, port))).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(format!("can't connect: {}", e)),
+        Err(_) => return Err("timeout connecting".to_string()),
+    };
+    let _ = stream.set_nodelay(true);
+    let client = LinkClient::register(server, &stream)?;
+    let Some(killed) = server.clients.get(client.id).map(|c| c.kill) else {
+        return Err("killed with CLIENT KILL".to_string());
+    };
+    let link = Link {
+        stream,
+        buf: BytesMut::with_capacity(READ_BUFFER_SIZE),
+        timeout,
+    };
+    tokio::select! {
+        result = sync(server, link, client.id, host, port) => result,
+        _ = killed.notified() => Err("killed with CLIENT KILL".to_string()),
+    }
+}
+
+/// The link to the primary as a client of this server, listed by CLIENT
+/// LIST with the `M` flag until the link is dropped.
+struct LinkClient<'a> {
+    server: &'a Server,
+    id: u64,
+}
+
+impl<'a> LinkClient<'a> {
+    fn register(server: &'a Server, stream: &TcpStream) -> Result<Self, String> {
+        let addr = stream
+            .peer_addr()
+            .map_err(|e| format!("can't connect: {}", e))?;
+        let laddr = stream
+            .local_addr()
+            .map_err(|e| format!("can't connect: {}", e))?;
+        let id = server
+            .clients
+            .register(addr, laddr, connection::raw_fd(stream));
+        server.clients.set_kind(id, ClientKind::Primary);
+        Ok(LinkClient { server, id })
+    }
+}
+
+impl Drop for LinkClient<'_> {
+    fn drop(&mut self) {
+        self.server.clients.unregister(self.id);
+    }
+}
+
+/// Synchronizes with the primary over a fresh link and applies its writes
+/// until the link fails.
+async fn sync(
+    server: &Server,
+    mut link: Link,
+    client_id: u64,
+    host: &str,
+    port: u16,
+) -> Result<(), String> {
+    let replication = &server.replication;
+    let (masteruser, masterauth, listening_port) = {
+        let config = server.config.read().unwrap();
+        (
+            config.masteruser.clone(),
+            config.masterauth.clone(),
+            config.port,
+        )
+    };
+    // Without masterauth the primary answers -NOAUTH, reported at PSYNC
+    let reply = link.request(&["PING"]).await?;
+    if reply.starts_with('-') && !reply.starts_with("-NOAUTH") {
+        return Err(format!("error reply to PING: {}", reply));
+    }
+    if !masterauth.is_empty() {
+        let mut auth = vec!["AUTH"];
+        if !masteruser.is_empty() {
+            auth.push(&masteruser);
+        }
+        auth.push(&masterauth);
+        let reply = link.request(&auth).await?;
+        if reply.starts_with('-') {
+            return Err(format!("unable to AUTH: {}", reply));
+        }
+    }
+    // Older primaries reject these, which is fine
+    let listening_port = listening_port.to_string();
+    link.request(&["REPLCONF", "listening-port", &listening_port])
+        .await?;
+    link.request(&["REPLCONF", "capa", "psync2"]).await?;
+
+    // Ask to continue our history when a backlog shows we have one, from
+    // an earlier link or from serving replicas; the primary decides
+    let (replid, next) = if replication.backlog_info().active {
+        let next = replication.offset() + 1;
+        (replication.replid(), next.to_string())
+    } else {
+        ("?".to_string(), "-1".to_string())
+    };
+    let mut psync = vec!["PSYNC", replid.as_str(), next.as_str()];
+    // The target of our FAILOVER takes over when asked this way
+    let failover = replication.failover_state() == FailoverState::InProgress;
+    if failover {
+        psync.push("FAILOVER");
+    }
+    let reply = link.request(&psync).await?;
+    if failover && (reply.starts_with("+CONTINUE") || reply.starts_with("+FULLRESYNC")) {
+        finish_failover(server, host, port);
+    }
+    if let Some(replid) = reply.strip_prefix("+CONTINUE") {
+        let replid = replid.trim();
+        let db = replication.continued((!replid.is_empty()).then_some(replid));
+        tracing::info!(
+            "Successful partial resynchronization with primary {}:{}",
+            host,
+            port
+        );
+        return apply_stream(server, link, client_id, db).await;
+    }
+    let Some((replid, offset)) = reply
+        .strip_prefix("+FULLRESYNC ")
+        .and_then(|rest| rest.split_once(' '))
+    else {
+        return Err(format!("unexpected reply to PSYNC: {}", reply));
+    };
+    let offset: u64 = offset
+        .parse()
+        .map_err(|_| format!("unexpected reply to PSYNC: {}", reply))?;
+    let replid = replid.to_string();
+
+    replication.discard_history();
+    replication.set_link_state(LinkState::Transfer);
+    tracing::info!("Full resync from primary: {}:{}", replid, offset);
+    let payload = link.read_payload().await?;
+    Stats::add(&server.stats.net_input_bytes, payload.len() as u64);
+    tracing::info!(
+        "Loading the {} bytes received from the primary",
+        payload.len()
+    );
+    let db = load_payload(server, &payload)?;
+    replication.synced(replid, offset, db);
+    tracing::info!("Synchronization with primary {}:{} succeeded", host, port);
+
+    apply_stream(server, link, client_id, db).await
+}
+
+/// The connection to a primary. Reads fail once it sent nothing for
+/// repl-timeout.
+struct Link {
+    stream: TcpStream,
+    buf: BytesMut,
+    timeout: Duration,
+}
+
+impl Link {
+    async fn send(&mut self, args: &[&str]) -> Result<(), String> {
+        let mut out = Vec::new();
+        encode_command(&mut out, args);
+        self.stream
+            .write_all(&out)
+            .await
+            .map_err(|e| format!("error writing to primary: {}", e))
+    }
+
+    /// Sends a command to the primary and reads its one-line reply.
+    async fn request(&mut self, args: &[&str]) -> Result<String, String> {
+        self.send(args).await?;
+        self.read_line().await
+    }
+
+    /// Reads one line, without its line ending.
+    async fn read_line(&mut self) -> Result<String, String> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line = self.buf.split_to(end + 1);
+                return Ok(String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string());
+            }
+            self.read_more().await?;
+        }
+    }
+
+    async fn read_more(&mut self) -> Result<(), String> {
+        let read = tokio::time::timeout(self.timeout, self.stream.read_buf(&mut self.buf)).await;
+        match read {
+            Err(_) => Err("timeout, no data received from primary".to_string()),
+            Ok(Ok(0)) => Err("connection lost".to_string()),
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(format!("error reading from primary: {}", e)),
+        }
+    }
+
+    /// Reads the `$<length>` framed RDB payload that follows +FULLRESYNC,
+    /// skipping the empty lines primaries send to keep the link alive while
+    /// they prepare it.
+    async fn read_payload(&mut self) -> Result<Bytes, String> {
+        let header = loop {
+            let line = self.read_line().await?;
+            if !line.is_empty() {
+                break line;
+            }
+        };
+        let len: usize = header
+            .strip_prefix('$')
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| format!("bad payload header from primary: {}", header))?;
+        while self.buf.len() < len {
+            self.read_more().await?;
+        }
+        Ok(self.buf.split_to(len).freeze())
+    }
+}
+
+/// Replaces the dataset with the primary's, returning the database its
+/// stream has selected. The append-only file, if on, is rewritten from the
+/// new dataset.
+fn load_payload(server: &Server, payload: &[u8]) -> Result<usize, String> {
+    let _exclusive = server.storage.exclusive();
+    server.storage.flushall();
+    let stats = rdb::load(payload, &server.storage, true)
+        .map_err(|e| format!("failed to load the dataset: {}", e))?;
+    if server.aof.is_on() {
+        let (path, fsync) = {
+            let config = server.config.read().unwrap();
+            (config.aof_path(), config.appendfsync)
+        };
+        if let Err(e) = server
+            .aof
+            .start(&path, fsync, &server.storage, server.keyring.as_ref())
+        {
+            tracing::warn!("Can't rewrite the append-only file: {}", e);
+        }
+    }
+    Ok(stats.stream_db.unwrap_or(0))
+}
+
+/// Applies the primary's writes until the link fails, passing each on to
+/// this server's own replicas. The offset reached is acknowledged every
+/// second, and the link is given up once the primary sent nothing, not
+/// even a PING, for repl-timeout.
+async fn apply_stream(
+    server: &Server,
+    mut link: Link,
+    client_id: u64,
+    db: usize,
+) -> Result<(), String> {
+    let mut ctx = ConnectionContext::new(client_id);
+    ctx.db = db;
+    ctx.deny_blocking = true;
+    // Commands of an open MULTI block and the bytes they were received as,
+    // applied and passed on together at EXEC
+    let mut multi: Option<(Vec<Command>, Vec<u8>)> = None;
+    let mut heartbeat = tokio::time::interval(ACK_PERIOD);
+    let mut last_io = Instant::now();
+    loop {
+        let mut ack = false;
+        loop {
+            let (cmd, used) = match parse_request(&link.buf) {
+                Ok(Some((parts, used))) if parts.is_empty() => (None, used),
+                Ok(Some((parts, used))) => (Some(Command::from_parts(&parts)?), used),
+                Ok(None) => break,
+                Err(e) => return Err(format!("protocol error from primary: {}", e)),
+            };
+            let raw = link.buf.split_to(used);
+            if let Some(cmd) = cmd {
+                ack |= apply(server, &mut ctx, &mut multi, cmd, &raw);
+            }
+        }
+        if ack {
+            let offset = server.replication.offset().to_string();
+            link.send(&["REPLCONF", "ACK", &offset]).await?;
+        }
+        tokio::select! {
+            read = link.stream.read_buf(&mut link.buf) => match read {
+                Ok(0) => return Err("connection lost".to_string()),
+                Ok(n) => {
+                    Stats::add(&server.stats.net_input_bytes, n as u64);
+                    last_io = Instant::now();
+                    server.replication.primary_io();
+                }
+                Err(e) => return Err(format!("error reading from primary: {}", e)),
+            },
+            _ = heartbeat.tick() => {
+                if last_io.elapsed() > link.timeout {
+                    return Err("timeout, no data nor PING received from primary".to_string());
+                }
+                let offset = server.replication.offset().to_string();
+                link.send(&["REPLCONF", "ACK", &offset]).await?;
+            }
+        }
+    }
+}
+
+/// Applies one command from the primary and passes it on. Returns true
+/// when the primary asked for an acknowledgement of the offset reached.
+fn apply(
+    server: &Server,
+    ctx: &mut ConnectionContext,
+    multi: &mut Option<(Vec<Command>, Vec<u8>)>,
+    cmd: Command,
+    raw: &[u8],
+) -> bool {
+    if cmd.name == "REPLCONF"
+        && cmd
+            .args
+            .first()
+            .is_some_and(|option| option.eq_ignore_ascii_case("getack"))
+    {
+        server.replication.proxy(raw, ctx.db);
+        return true;
+    }
+    // A replica attaching to this server in between would get the write in
+    // both its snapshot and its stream, or in neither
+    match (cmd.name.as_str(), multi.as_mut()) {
+        ("MULTI", None) => *multi = Some((Vec::new(), raw.to_vec())),
+        ("EXEC", Some(_)) => {
+            let (queued, mut data) = multi.take().unwrap();
+            data.extend_from_slice(raw);
+            let _exclusive = server.storage.exclusive();
+            commands::replay_transaction(&queued, server, ctx);
+            server.replication.proxy(&data, ctx.db);
+        }
+        (_, Some((queued, data))) => {
+            queued.push(cmd);
+            data.extend_from_slice(raw);
+        }
+        _ => {
+            let _shared = server.storage.shared();
+            commands::replay(&cmd, server, ctx);
+            server.replication.proxy(raw, ctx.db);
+        }
+    }
+    false
+}
+
+/// Pings replicas every repl-ping-replica-period and disconnects those
+/// that acknowledged nothing for repl-timeout. Run by the server cron.
+pub fn heartbeat(server: &Server) {
+    let (period, timeout) = {
+        let config = server.config.read().unwrap();
+        (
+            Duration::from_secs(config.repl_ping_replica_period),
+            Duration::from_secs(config.repl_timeout),
+        )
+    };
+    for replica in server.replication.heartbeat(period, timeout) {
+        tracing::warn!(
+            "Disconnecting timedout replica {}:{}",
+            replica.ip,
+            replica.port
+        );
+    }
+}
+
+/// Starts a FAILOVER: pauses writes and waits, in `update_failover`, for
+/// `target` or any replica to apply all of them before handing over.
+pub fn start_failover(
+    server: &Server,
+    target: Option<(String, u16)>,
+    timeout: Option<Duration>,
+    force: bool,
+) -> Result<(), String> {
+    let replication = &server.replication;
+    if replication.is_replica() {
+        return Err("ERR FAILOVER is not valid when server is a replica.".to_string());
+    }
+    let replicas = replication.replicas();
+    if replicas.is_empty() {
+        return Err("ERR FAILOVER requires connected replicas.".to_string());
+    }
+    if let Some((host, port)) = &target {
+        let Some(replica) = replicas
+            .iter()
+            .find(|replica| replica.ip.to_string() == *host && replica.port == *port)
+        else {
+            return Err("ERR FAILOVER target HOST and PORT is not a replica.".to_string());
+        };
+        if !replica.online {
+            return Err("ERR FAILOVER target replica is not online.".to_string());
+        }
+    }
+    let mut failover = replication.failover.lock().unwrap();
+    if failover.state != FailoverState::None {
+        return Err("ERR FAILOVER already in progress.".to_string());
+    }
+    *failover = Failover {
+        state: FailoverState::WaitingForSync,
+        target,
+        deadline: timeout.map(|timeout| Instant::now() + timeout),
+        force,
+    };
+    drop(failover);
+    // Lifted when the failover ends one way or the other
+    server.clients.pause(PauseMode::Write, FAILOVER_PAUSE);
+    replication.request_acks();
+    tracing::info!("FAILOVER requested, waiting for a replica to sync");
+    Ok(())
+}
+
+/// Hands over to the failover target once it has caught up, or gives up
+/// at the deadline. Run by the server cron.
+pub fn update_failover(server: &Server) {
+    let replication = &server.replication;
+    let failover = replication.failover.lock().unwrap().clone();
+    if failover.state != FailoverState::WaitingForSync {
+        return;
+    }
+    let offset = replication.offset();
+    let synced = replication.replicas().into_iter().find(|replica| {
+        replica.online
+            && replica.ack_offset == offset
+            && failover
+                .target
+                .as_ref()
+                .is_none_or(|(host, port)| replica.ip.to_string() == *host && replica.port == *port)
+    });
+    let (target, synced) = match synced {
+        Some(replica) => ((replica.ip.to_string(), replica.port), true),
+        None if failover.deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+            match failover.target {
+                Some(target) if failover.force => (target, false),
+                _ => {
+                    abort_failover(server, "Replica never caught up before timeout");
+                    return;
+                }
+            }
+        }
+        None => return,
+    };
+    tracing::info!(
+        "Failing over to {}:{}{}",
+        target.0,
+        target.1,
+        if synced {
+            ""
+        } else {
+            ", forced before it caught up"
+        }
+    );
+    {
+        let mut failover = replication.failover.lock().unwrap();
+        failover.state = FailoverState::InProgress;
+        failover.target = Some(target.clone());
+    }
+    // `follow` asks the target to take over when it connects
+    replication.set_primary(Some(target.clone()));
+    server.config.write().unwrap().replicaof = Some(target);
+}
+
+/// Ends a failover the target accepted: this server is now its replica and
+/// writes go on, to be rejected with -READONLY.
+fn finish_failover(server: &Server, host: &str, port: u16) {
+    *server.replication.failover.lock().unwrap() = Failover::default();
+    server.clients.unpause();
+    tracing::info!(
+        "Failover to {}:{} succeeded, this server is now its replica",
+        host,
+        port
+    );
+}
+
+/// Stops a failover, becoming a primary again if it got as far as
+/// following the target. Returns false when none was under way.
+pub fn abort_failover(server: &Server, reason: &str) -> bool {
+    let replication = &server.replication;
+    let state = std::mem::take(&mut *replication.failover.lock().unwrap()).state;
+    if state == FailoverState::None {
+        return false;
+    }
+    tracing::warn!("FAILOVER aborted: {}", reason);
+    if state == FailoverState::InProgress {
+        replication.set_primary(None);
+        server.config.write().unwrap().replicaof = None;
+    }
+    server.clients.unpause();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attach(server: &Server, id: u64) -> mpsc::UnboundedReceiver<Bytes> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let replica = Replica {
+            id,
+            ip: IpAddr::from([127, 0, 0, 1]),
+            port: 6380,
+            online: false,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+            sender,
+        };
+        server.replication.attach(&server.storage, replica);
+        receiver
+    }
+
+    fn run(server: &Server, ctx: &mut ConnectionContext, args: &[&str]) {
+        let cmd = Command {
+            name: args[0].to_string(),
+            args: args[1..].iter().map(|s| s.to_string()).collect(),
+        };
+        commands::execute(&cmd, server, ctx);
+    }
+
+    fn received(receiver: &mut mpsc::UnboundedReceiver<Bytes>) -> String {
+        let mut out = String::new();
+        while let Ok(data) = receiver.try_recv() {
+            out.push_str(&String::from_utf8_lossy(&data));
+        }
+        out
+    }
+
+    #[test]
+    fn test_writes_are_fed_to_replicas() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        run(&server, &mut ctx, &["SET", "before", "1"]);
+        assert!(server.replication.lock().is_none());
+        assert_eq!(server.replication.offset(), 0);
+
+        let mut receiver = attach(&server, 7);
+        run(&server, &mut ctx, &["SET", "k", "v", "EX", "100"]);
+        run(&server, &mut ctx, &["GET", "k"]);
+        run(&server, &mut ctx, &["SELECT", "2"]);
+        run(&server, &mut ctx, &["MULTI"]);
+        run(&server, &mut ctx, &["INCR", "n"]);
+        run(&server, &mut ctx, &["EXEC"]);
+        let stream = received(&mut receiver);
+        assert!(stream.starts_with(
+            "*2\r\n$6\r\nselect\r\n$1\r\n0\r\n*5\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$3\r\n100\r\n*3\r\n$9\r\npexpireat\r\n"
+        ));
+        assert!(stream.ends_with(
+            "*2\r\n$6\r\nselect\r\n$1\r\n2\r\n*1\r\n$5\r\nmulti\r\n*2\r\n$4\r\nincr\r\n$1\r\nn\r\n*1\r\n$4\r\nexec\r\n"
+        ));
+        assert!(!stream.contains("get"));
+        assert_eq!(server.replication.offset(), stream.len() as u64);
+
+        // Replicas are dropped once they go away, but the backlog goes on
+        drop(receiver);
+        run(&server, &mut ctx, &["DEL", "n"]);
+        assert!(server.replication.replicas().is_empty());
+        let backlog = server.replication.backlog_info();
+        assert!(backlog.active);
+        assert_eq!(backlog.histlen as u64, server.replication.offset());
+    }
+
+    #[test]
+    fn test_expired_keys_are_propagated_as_del() {
+        let expire_cycle = |server: &Server| {
+            let task = crate::cron::CRON_TASKS
+                .iter()
+                .find(|task| task.name == "expire-cycle")
+                .unwrap();
+            (task.run)(server, Duration::from_secs(1));
+        };
+        let primary = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut receiver = attach(&primary, 1);
+        run(&primary, &mut ctx, &["SET", "lazy", "v", "PX", "1"]);
+        run(&primary, &mut ctx, &["SET", "active", "v", "PX", "1"]);
+        std::thread::sleep(Duration::from_millis(5));
+        let stream = received(&mut receiver);
+
+        // Accessing an expired key deletes it, even from a read
+        run(&primary, &mut ctx, &["GET", "lazy"]);
+        let del = received(&mut receiver);
+        assert_eq!(del, "*2\r\n$3\r\ndel\r\n$4\r\nlazy\r\n");
+        expire_cycle(&primary);
+        let active = received(&mut receiver);
+        assert_eq!(active, "*2\r\n$3\r\ndel\r\n$6\r\nactive\r\n");
+
+        // So does an expiration in the past
+        run(&primary, &mut ctx, &["SET", "past", "v"]);
+        received(&mut receiver);
+        run(&primary, &mut ctx, &["EXPIRE", "past", "-1"]);
+        assert_eq!(primary.storage.db(0).dbsize(), 0);
+        assert_eq!(
+            received(&mut receiver),
+            "*2\r\n$3\r\ndel\r\n$4\r\npast\r\n"
+        );
+
+        // Replicas hide expired keys but leave deleting them to the primary
+        let replica = Server::default();
+        replica
+            .replication
+            .set_primary(Some(("127.0.0.1".to_string(), 6379)));
+        let mut link = ConnectionContext::new(0);
+        let mut multi = None;
+        let mut apply_all = |data: &str| {
+            let mut data = data.as_bytes();
+            while let Ok(Some((parts, used))) = parse_request(data) {
+                let cmd = Command::from_parts(&parts).unwrap();
+                apply(&replica, &mut link, &mut multi, cmd, &data[..used]);
+                data = &data[used..];
+            }
+        };
+        apply_all(&stream);
+        std::thread::sleep(Duration::from_millis(5));
+        expire_cycle(&replica);
+        run(&replica, &mut ctx, &["GET", "lazy"]);
+        assert_eq!(replica.storage.db(0).get("lazy"), None);
+        assert!(replica.storage.db(0).is_expired("lazy"));
+        assert!(replica.storage.db(0).is_expired("active"));
+        apply_all(&(del + &active));
+        assert!(!replica.storage.db(0).is_expired("lazy"));
+        assert!(!replica.storage.db(0).is_expired("active"));
+    }
+
+    #[test]
+    fn test_failover() {
+        let server = Server::default();
+        let replication = &server.replication;
+        let target = Some(("127.0.0.1".to_string(), 6380));
+        assert_eq!(
+            start_failover(&server, target.clone(), None, false),
+            Err("ERR FAILOVER requires connected replicas.".to_string())
+        );
+        let mut receiver = attach(&server, 1);
+        let other = Some(("127.0.0.1".to_string(), 6381));
+        assert!(start_failover(&server, other, None, false).is_err());
+        replication.set_online(1);
+
+        // Writes pause until the target acknowledges all of them
+        start_failover(&server, target.clone(), None, false).unwrap();
+        assert!(start_failover(&server, None, None, false).is_err());
+        assert_eq!(replication.failover_state(), FailoverState::WaitingForSync);
+        assert!(server.clients.is_paused(true));
+        assert!(!server.clients.is_paused(false));
+        let getack = received(&mut receiver);
+        assert_eq!(getack, "*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n");
+        update_failover(&server);
+        assert_eq!(replication.failover_state(), FailoverState::WaitingForSync);
+        replication.set_ack(1, replication.offset());
+        update_failover(&server);
+        assert_eq!(replication.failover_state(), FailoverState::InProgress);
+        let primary = replication.primary().unwrap();
+        assert_eq!((primary.host, primary.port), target.clone().unwrap());
+
+        // Aborting makes it a primary again
+        assert!(abort_failover(&server, "test"));
+        assert!(!abort_failover(&server, "test"));
+        assert!(!replication.is_replica());
+        assert!(!server.clients.is_paused(true));
+
+        // Without FORCE a timeout gives up on a target that lags behind
+        let _receiver = attach(&server, 2);
+        replication.set_online(2);
+        let timeout = Some(Duration::from_millis(1));
+        start_failover(&server, None, timeout, false).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        update_failover(&server);
+        assert_eq!(replication.failover_state(), FailoverState::None);
+        assert!(!server.clients.is_paused(true));
+        start_failover(&server, target.clone(), timeout, true).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        update_failover(&server);
+        assert_eq!(replication.failover_state(), FailoverState::InProgress);
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let server = Server::default();
+        let replication = &server.replication;
+        let mut receiver = attach(&server, 1);
+        let _other = attach(&server, 2);
+        replication.set_online(1);
+        replication.set_online(2);
+
+        let period = Duration::from_secs(10);
+        let timeout = Duration::from_secs(60);
+        assert!(replication.heartbeat(period, timeout).is_empty());
+        assert_eq!(received(&mut receiver), "*1\r\n$4\r\nPING\r\n");
+        // Not due again until the period passed
+        assert!(replication.heartbeat(period, timeout).is_empty());
+        assert_eq!(received(&mut receiver), "");
+
+        replication.set_ack(1, replication.offset());
+        std::thread::sleep(Duration::from_millis(20));
+        replication.set_ack(2, replication.offset());
+        let timed_out = replication.heartbeat(period, Duration::from_millis(10));
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].ack_offset, 14);
+        let replicas = replication.replicas();
+        assert_eq!(replicas.len(), 1);
+        assert!(replicas[0].lag < Duration::from_millis(10));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8, 100);
+        backlog.push(b"abcde");
+        assert_eq!(backlog.since(100), Some(b"abcde".to_vec()));
+        assert_eq!(backlog.since(103), Some(b"de".to_vec()));
+        assert_eq!(backlog.since(105), Some(Vec::new()));
+        assert_eq!(backlog.since(106), None);
+        backlog.push(b"fghij");
+        assert_eq!(backlog.start, 102);
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(102), Some(b"cdefghij".to_vec()));
+        backlog.resize(4);
+        assert_eq!(backlog.since(106), Some(b"ghij".to_vec()));
+        assert_eq!(backlog.since(105), None);
+    }
+
+    #[test]
+    fn test_partial_resync() {
+        let server = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let replica = |id| Replica {
+            id,
+            ip: IpAddr::from([127, 0, 0, 1]),
+            port: 6380,
+            online: false,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+            sender: mpsc::unbounded_channel().0,
+        };
+        let replication = &server.replication;
+        let mut receiver = attach(&server, 1);
+        run(&server, &mut ctx, &["SET", "a", "1"]);
+        let seen = received(&mut receiver);
+        let have = replication.offset();
+        run(&server, &mut ctx, &["SET", "b", "2"]);
+        let missed = received(&mut receiver);
+        let replid = replication.replid();
+
+        // PSYNC names the first byte missing
+        let (id, data) = replication
+            .attach_partial(&replid, have as i64 + 1, replica(2))
+            .unwrap();
+        assert_eq!((id, data), (replid.clone(), missed.into_bytes()));
+        assert_eq!(have, seen.len() as u64);
+        assert!(replication.attach_partial("?", -1, replica(3)).is_err());
+        assert!(replication.attach_partial(&"0".repeat(40), 1, replica(3)).is_err());
+        let beyond = replication.offset() as i64 + 2;
+        assert!(replication.attach_partial(&replid, beyond, replica(3)).is_err());
+
+        // After a promotion replicas of the old history can continue it up
+        // to where it branched off
+        replication.set_primary(Some(("10.0.0.1".to_string(), 6379)));
+        replication.set_primary(None);
+        let history = replication.history();
+        assert_eq!(history.replid2, replid);
+        assert_eq!(history.second_offset, Some(replication.offset()));
+        let next = replication.offset() as i64 + 1;
+        let (id, data) = replication
+            .attach_partial(&replid, next, replica(4))
+            .unwrap();
+        assert_eq!((id, data), (history.replid.clone(), Vec::new()));
+        run(&server, &mut ctx, &["SET", "c", "3"]);
+        let next = replication.offset() as i64 + 1;
+        assert!(replication.attach_partial(&replid, next, replica(5)).is_err());
+    }
+
+    #[test]
+    fn test_replica_applies_and_proxies_the_stream() {
+        let primary = Server::default();
+        let mut ctx = ConnectionContext::new(1);
+        let mut from_primary = attach(&primary, 1);
+        run(&primary, &mut ctx, &["RPUSH", "l", "x", "y"]);
+        run(&primary, &mut ctx, &["MULTI"]);
+        run(&primary, &mut ctx, &["INCR", "a"]);
+        run(&primary, &mut ctx, &["DEL", "l"]);
+        run(&primary, &mut ctx, &["EXEC"]);
+        let stream = received(&mut from_primary);
+
+        let replica = Server::default();
+        replica
+            .replication
+            .set_primary(Some(("127.0.0.1".to_string(), 6379)));
+        let mut proxied = attach(&replica, 2);
+        let mut link = ConnectionContext::new(0);
+        let mut multi = None;
+        let mut data = stream.as_bytes();
+        while let Ok(Some((parts, used))) = parse_request(data) {
+            let cmd = Command::from_parts(&parts).unwrap();
+            // Not applied until EXEC arrives
+            if cmd.name == "DEL" {
+                assert_eq!(replica.storage.db(0).llen("l"), Ok(2));
+            }
+            apply(&replica, &mut link, &mut multi, cmd, &data[..used]);
+            data = &data[used..];
+        }
+        assert_eq!(replica.storage.db(0).get("a"), Some("1".to_string()));
+        assert_eq!(replica.storage.db(0).llen("l"), Ok(0));
+        assert_eq!(received(&mut proxied), stream);
+        assert_eq!(replica.replication.offset(), stream.len() as u64);
+
+        // Its own writes are not sent to its replicas
+        run(&replica, &mut ctx, &["SET", "local", "1"]);
+        assert_eq!(received(&mut proxied), "");
+    }
+
+    #[test]
+    fn test_set_primary() {
+        let replication = Replication::new();
+        let replid = replication.replid();
+        assert!(replication.set_primary(Some(("10.0.0.1".to_string(), 6379))));
+        assert!(!replication.set_primary(Some(("10.0.0.1".to_string(), 6379))));
+        assert_eq!(replication.primary().unwrap().state, LinkState::Connect);
+        assert!(replication.is_replica());
+        assert!(replication.set_primary(None));
+        assert!(!replication.is_replica());
+        assert_ne!(replication.replid(), replid);
+        assert!(!replication.set_primary(None));
+    }
+}
@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+use std::io::{self, IoSlice};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::binary;
+use crate::parser::Resp;
+
+/// Bulk payloads at least this large are written straight from the reply
+/// instead of being copied next to their headers.
+const INLINE_PAYLOAD_LIMIT: usize = 1024;
+
+/// The protocol a connection speaks: RESP2 until it switches with HELLO 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+enum Fragment<'a> {
+    Scratch(usize, usize),
+    Payload(&'a [u8]),
+}
+
+/// Encodes replies into a list of I/O slices: protocol headers and small
+/// values are packed into one scratch buffer, while large bulk strings are
+/// borrowed from the replies themselves and handed to `write_vectored`.
+pub struct VectoredEncoder<'a> {
+    scratch: Vec<u8>,
+    sealed: usize,
+    fragments: Vec<Fragment<'a>>,
+}
+
+impl<'a> VectoredEncoder<'a> {
+    pub fn new() -> Self {
+        VectoredEncoder {
+            scratch: Vec::new(),
+            sealed: 0,
+            fragments: Vec::new(),
+        }
+    }
+
+    /// Encodes `resp` as `protocol` has it: RESP3 replies with null and
+    /// map frames where RESP2 has null bulk strings and flat arrays.
+    pub fn push(&mut self, resp: &'a Resp, protocol: Protocol) {
+        match resp {
+            Resp::Bulk(None) | Resp::Array(None) if protocol == Protocol::Resp3 => {
+                self.scratch.extend_from_slice(b"_\r\n")
+            }
+            Resp::Simple(s) => self.line(b'+', s),
+            Resp::Error(e) => self.line(b'-', e),
+            Resp::Integer(i) => self.header(b':', i),
+            Resp::Bulk(None) => self.scratch.extend_from_slice(b"$-1\r\n"),
+            Resp::Bulk(Some(s)) => {
+                let bytes = binary::encode(s);
+                self.header(b'$', bytes.len());
+                match bytes {
+                    Cow::Borrowed(bytes) => self.payload(bytes),
+                    Cow::Owned(bytes) => self.scratch.extend_from_slice(&bytes),
+                }
+                self.scratch.extend_from_slice(b"\r\n");
+            }
+            Resp::Bytes(bytes) => {
+                self.header(b'$', bytes.len());
+                self.payload(bytes);
+                self.scratch.extend_from_slice(b"\r\n");
+            }
+            Resp::Array(None) => self.scratch.extend_from_slice(b"*-1\r\n"),
+            Resp::Array(Some(items)) => {
+                self.header(b'*', items.len());
+                for item in items {
+                    self.push(item, protocol);
+                }
+            }
+            Resp::Map(pairs) => {
+                match protocol {
+                    Protocol::Resp2 => self.header(b'*', pairs.len() * 2),
+                    Protocol::Resp3 => self.header(b'%', pairs.len()),
+                }
+                for (field, value) in pairs {
+                    self.push(field, protocol);
+                    self.push(value, protocol);
+                }
+            }
+        }
+    }
+
+    fn header(&mut self, prefix: u8, value: impl std::fmt::Display) {
+        use std::io::Write;
+        self.scratch.push(prefix);
+        let _ = write!(self.scratch, "{}\r\n", value);
+    }
+
+    /// A simple string or error, in the bytes it was decoded from.
+    fn line(&mut self, prefix: u8, s: &str) {
+        self.scratch.push(prefix);
+        self.scratch.extend_from_slice(&binary::encode(s));
+        self.scratch.extend_from_slice(b"\r\n");
+    }
+
+    fn payload(&mut self, bytes: &'a [u8]) {
+        if bytes.len() < INLINE_PAYLOAD_LIMIT {
+            self.scratch.extend_from_slice(bytes);
+        } else {
+            self.seal();
+            self.fragments.push(Fragment::Payload(bytes));
+        }
+    }
+
+    fn seal(&mut self) {
+        if self.scratch.len() > self.sealed {
+            self.fragments
+                .push(Fragment::Scratch(self.sealed, self.scratch.len()));
+            self.sealed = self.scratch.len();
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(mut self, writer: &mut W) -> io::Result<()> {
+        self.seal();
+        let mut slices: Vec<IoSlice<'_>> = self
+            .fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Scratch(start, end) => IoSlice::new(&self.scratch[*start..*end]),
+                Fragment::Payload(bytes) => IoSlice::new(bytes),
+            })
+            .collect();
+
+        let mut remaining = &mut slices[..];
+        while !remaining.is_empty() {
+            let n = writer.write_vectored(remaining).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            IoSlice::advance_slices(&mut remaining, n);
+        }
+        Ok(())
+    }
+}
+
+impl Default for VectoredEncoder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Size of a reply once encoded, used to decide when to flush.
+pub fn encoded_len(resp: &Resp, protocol: Protocol) -> usize {
+    fn digits(n: usize) -> usize {
+        n.checked_ilog10().unwrap_or(0) as usize + 1
+    }
+
+    match resp {
+        Resp::Bulk(None) | Resp::Array(None) if protocol == Protocol::Resp3 => 3,
+        Resp::Simple(s) | Resp::Error(s) => binary::len(s) + 3,
+        Resp::Integer(i) => i.to_string().len() + 3,
+        Resp::Bulk(None) | Resp::Array(None) => 5,
+        Resp::Bulk(Some(s)) => {
+            let len = binary::len(s);
+            digits(len) + len + 5
+        }
+        Resp::Bytes(bytes) => digits(bytes.len()) + bytes.len() + 5,
+        Resp::Array(Some(items)) => {
+            let size: usize = items.iter().map(|item| encoded_len(item, protocol)).sum();
+            digits(items.len()) + 3 + size
+        }
+        Resp::Map(pairs) => {
+            let len = match protocol {
+                Protocol::Resp2 => pairs.len() * 2,
+                Protocol::Resp3 => pairs.len(),
+            };
+            let size: usize = pairs
+                .iter()
+                .map(|(field, value)| encoded_len(field, protocol) + encoded_len(value, protocol))
+                .sum();
+            digits(len) + 3 + size
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::encode_resp;
+
+    #[test]
+    fn test_vectored_matches_encode_resp() {
+        let replies = vec![
+            Resp::Simple("OK".to_string()),
+            Resp::Integer(-42),
+            Resp::Bulk(None),
+            Resp::Array(Some(vec![
+                Resp::Bulk(Some("small".to_string())),
+                Resp::Bulk(Some(crate::binary::decode(b"\x80\xff binary"))),
+                Resp::Bulk(Some("x".repeat(INLINE_PAYLOAD_LIMIT * 3))),
+                Resp::Error("ERR boom".to_string()),
+            ])),
+            Resp::Map(vec![
+                (
+                    Resp::Bulk(Some("field".to_string())),
+                    Resp::Array(Some(vec![Resp::Integer(1), Resp::Array(None)])),
+                ),
+                (
+                    Resp::Bulk(Some("large".to_string())),
+                    Resp::Bulk(Some("y".repeat(INLINE_PAYLOAD_LIMIT))),
+                ),
+            ]),
+        ];
+
+        for protocol in [Protocol::Resp2, Protocol::Resp3] {
+            let mut expected = Vec::new();
+            let mut encoder = VectoredEncoder::new();
+            for reply in &replies {
+                expected.extend(encode_resp(reply, protocol));
+                encoder.push(reply, protocol);
+                assert_eq!(
+                    encoded_len(reply, protocol),
+                    encode_resp(reply, protocol).len()
+                );
+            }
+
+            let mut written: Vec<u8> = Vec::new();
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            rt.block_on(encoder.write_to(&mut written)).unwrap();
+            assert_eq!(written, expected);
+        }
+    }
+}
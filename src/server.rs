@@ -0,0 +1,99 @@
+use crate::acl;
+use crate::acl::Acl;
+use crate::aof::Aof;
+use crate::blocking::BlockingKeys;
+use crate::clients::ClientRegistry;
+use crate::commands::{COMMAND_TABLE, CommandRenames};
+use crate::config::{Config, SharedConfig};
+use crate::cron::CronStats;
+use crate::crypto::Keyring;
+use crate::defrag::ActiveDefrag;
+use crate::engine;
+use crate::evict::EvictionPool;
+use crate::latency::{CommandHistograms, LatencyMonitor};
+use crate::rdb::SaveState;
+use crate::replication::Replication;
+use crate::stats::Stats;
+use crate::storage::{self, Storage};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// State shared by every connection.
+#[derive(Debug)]
+pub struct Server {
+    pub storage: Storage,
+    pub config: SharedConfig,
+    pub clients: ClientRegistry,
+    pub acl: Acl,
+    pub renames: CommandRenames,
+    pub latency: LatencyMonitor,
+    /// Per-command latency histograms for `INFO latencystats`.
+    pub command_latency: CommandHistograms,
+    /// Unix time of the last successful save; the start time until then.
+    pub lastsave: AtomicU64,
+    pub rdb: SaveState,
+    pub aof: Aof,
+    pub replication: Replication,
+    pub eviction_pool: Mutex<EvictionPool>,
+    /// Clients waiting in BLPOP and BRPOP.
+    pub blocking: BlockingKeys,
+    pub defrag: ActiveDefrag,
+    /// Keys persistence files are encrypted with, loaded at startup.
+    pub keyring: Option<Keyring>,
+    pub stats: Stats,
+    pub started_at: Instant,
+    /// Random identifier of this server process, 40 hex characters.
+    pub run_id: String,
+    pub cron: CronStats,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        let acl = Acl::new();
+        acl.set_requirepass(&config.requirepass);
+        let engine =
+            engine::find(&config.storage_engine).expect("storage-engine is checked when parsed");
+        let replication = Replication::new();
+        replication.set_primary(config.replicaof.clone());
+        replication.set_backlog_size(config.repl_backlog_size);
+        let storage = Storage::with_engine(config.databases, engine);
+        storage.set_list_max_listpack_size(config.list_max_listpack_size);
+        storage.set_hash_max_listpack(
+            config.hash_max_listpack_entries,
+            config.hash_max_listpack_value,
+        );
+        storage.set_bf_defaults(
+            config.bf_error_rate,
+            config.bf_initial_size,
+            config.bf_expansion_factor,
+        );
+        Server {
+            storage,
+            renames: CommandRenames::new(&config.rename_commands),
+            config: Arc::new(RwLock::new(config)),
+            clients: ClientRegistry::new(),
+            acl,
+            latency: LatencyMonitor::new(),
+            command_latency: CommandHistograms::new(COMMAND_TABLE.iter().map(|spec| spec.name)),
+            lastsave: AtomicU64::new(storage::now_ms() / 1000),
+            rdb: SaveState::new(),
+            aof: Aof::new(),
+            replication,
+            eviction_pool: Mutex::new(EvictionPool::default()),
+            blocking: BlockingKeys::default(),
+            defrag: ActiveDefrag::default(),
+            keyring: None,
+            stats: Stats::new(),
+            started_at: Instant::now(),
+            run_id: acl::genpass(160).unwrap_or_else(|_| "0".repeat(40)),
+            cron: CronStats::new(),
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Samples averaged by the `instantaneous_*` metrics, like Redis'
+/// STATS_METRIC_SAMPLES.
+const METRIC_SAMPLES: usize = 16;
+
+/// A per-second rate derived from a monotonically increasing counter.
+#[derive(Debug)]
+struct InstantaneousMetric {
+    last_sample: Instant,
+    last_value: u64,
+    samples: [f64; METRIC_SAMPLES],
+    index: usize,
+}
+
+impl InstantaneousMetric {
+    fn new() -> Self {
+        InstantaneousMetric {
+            last_sample: Instant::now(),
+            last_value: 0,
+            samples: [0.0; METRIC_SAMPLES],
+            index: 0,
+        }
+    }
+
+    fn track(&mut self, value: u64, now: Instant) {
+        let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+        if elapsed > 0.0 {
+            self.samples[self.index] = value.saturating_sub(self.last_value) as f64 / elapsed;
+            self.index = (self.index + 1) % METRIC_SAMPLES;
+        }
+        self.last_sample = now;
+        self.last_value = value;
+    }
+
+    fn average(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / METRIC_SAMPLES as f64
+    }
+}
+
+#[derive(Debug)]
+struct Instantaneous {
+    ops: InstantaneousMetric,
+    net_input: InstantaneousMetric,
+    net_output: InstantaneousMetric,
+}
+
+/// Server-wide counters reported by INFO stats and persistence.
+#[derive(Debug)]
+pub struct Stats {
+    pub connections_received: AtomicU64,
+    pub commands_processed: AtomicU64,
+    pub net_input_bytes: AtomicU64,
+    pub net_output_bytes: AtomicU64,
+    /// Keys modified since the last save.
+    pub dirty: AtomicU64,
+    /// Full and partial resynchronizations served to replicas, and PSYNC
+    /// requests that couldn't continue and got a full one instead.
+    pub sync_full: AtomicU64,
+    pub sync_partial_ok: AtomicU64,
+    pub sync_partial_err: AtomicU64,
+    /// Keys removed because they expired, and evicted to stay under
+    /// maxmemory.
+    pub expired_keys: AtomicU64,
+    pub evicted_keys: AtomicU64,
+    /// Clients disconnected to keep their buffers under maxmemory-clients.
+    pub evicted_clients: AtomicU64,
+    /// Values active defrag reallocated, and those it looked at and left.
+    pub active_defrag_hits: AtomicU64,
+    pub active_defrag_misses: AtomicU64,
+    instantaneous: Mutex<Instantaneous>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            connections_received: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            dirty: AtomicU64::new(0),
+            sync_full: AtomicU64::new(0),
+            sync_partial_ok: AtomicU64::new(0),
+            sync_partial_err: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            evicted_clients: AtomicU64::new(0),
+            active_defrag_hits: AtomicU64::new(0),
+            active_defrag_misses: AtomicU64::new(0),
+            instantaneous: Mutex::new(Instantaneous {
+                ops: InstantaneousMetric::new(),
+                net_input: InstantaneousMetric::new(),
+                net_output: InstantaneousMetric::new(),
+            }),
+        }
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Zeroes the counters, as CONFIG RESETSTAT does. `dirty` is left alone
+    /// since it tracks unsaved changes rather than statistics.
+    pub fn reset(&self) {
+        for counter in [
+            &self.connections_received,
+            &self.commands_processed,
+            &self.net_input_bytes,
+            &self.net_output_bytes,
+            &self.sync_full,
+            &self.sync_partial_ok,
+            &self.sync_partial_err,
+            &self.expired_keys,
+            &self.evicted_keys,
+            &self.evicted_clients,
+            &self.active_defrag_hits,
+            &self.active_defrag_misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Takes one sample of every instantaneous metric; meant to be called
+    /// about ten times a second.
+    pub fn track_instantaneous_metrics(&self) {
+        let now = Instant::now();
+        let mut metrics = self.instantaneous.lock().unwrap();
+        metrics.ops.track(Self::get(&self.commands_processed), now);
+        metrics
+            .net_input
+            .track(Self::get(&self.net_input_bytes), now);
+        metrics
+            .net_output
+            .track(Self::get(&self.net_output_bytes), now);
+    }
+
+    pub fn instantaneous_ops_per_sec(&self) -> u64 {
+        self.instantaneous.lock().unwrap().ops.average().round() as u64
+    }
+
+    /// Network input and output rates in KB/s.
+    pub fn instantaneous_kbps(&self) -> (f64, f64) {
+        let metrics = self.instantaneous.lock().unwrap();
+        (
+            metrics.net_input.average() / 1024.0,
+            metrics.net_output.average() / 1024.0,
+        )
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CPU seconds used by the process and its waited-for children, as
+/// `(sys, user, sys_children, user_children)`. Zero where /proc is missing.
+pub fn cpu_usage() -> (f64, f64, f64, f64) {
+    // Values in /proc are in USER_HZ, which the kernel fixes at 100
+    const TICKS_PER_SEC: f64 = 100.0;
+    let Ok(stat) = std::fs::read_to_string("/proc/self/stat") else {
+        return (0.0, 0.0, 0.0, 0.0);
+    };
+    // The command name may contain spaces, so count fields after its ')'
+    let fields: Vec<f64> = stat
+        .rsplit_once(')')
+        .map(|(_, rest)| rest)
+        .unwrap_or_default()
+        .split_whitespace()
+        .skip(11)
+        .take(4)
+        .map(|field| field.parse::<f64>().unwrap_or(0.0) / TICKS_PER_SEC)
+        .collect();
+    match fields.as_slice() {
+        [user, sys, user_children, sys_children] => (*sys, *user, *sys_children, *user_children),
+        _ => (0.0, 0.0, 0.0, 0.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_instantaneous_metric() {
+        let mut metric = InstantaneousMetric::new();
+        let start = metric.last_sample;
+        for i in 1..=METRIC_SAMPLES as u64 {
+            metric.track(i * 100, start + Duration::from_millis(i * 100));
+        }
+        assert!((metric.average() - 1000.0).abs() < 1e-6);
+    }
+}
@@ -1,39 +1,356 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::binary;
+use crate::bloom::{self, Bloom};
+use crate::engine::{EngineFactory, MemoryEngine, StorageEngine};
+use crate::evict;
+use crate::geo::{Found, GeoSearch};
+use crate::hash::{self, Hash};
+use crate::list::{self, List};
+use crate::notify::KeyspaceEvent;
+use crate::pattern::Pattern;
+use crate::stream::{Fields, GroupChanges, GroupEntries, GroupRead, NewId, Stream, StreamId, Trim};
+use crate::zset::{RangeBy, RangeQuery, SortedSet};
+
+/// Expired keys the expire cycle removes per database lock.
+const EXPIRE_KEYS_PER_LOOP: usize = 20;
+
+/// The current Unix time in milliseconds, the clock expirations are kept
+/// on. It's read off the monotonic clock from a wall-clock reading taken
+/// the first time, so comparing deadlines needs no system call and isn't
+/// thrown off by the wall clock being set back.
+pub fn now_ms() -> u64 {
+    static START: OnceLock<(Instant, u64)> = OnceLock::new();
+    let (instant, unix_ms) = START.get_or_init(|| {
+        let unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        (Instant::now(), unix_ms)
+    });
+    unix_ms + instant.elapsed().as_millis() as u64
+}
 
 #[derive(Debug, Clone)]
 pub enum Value {
     String(String),
-    List(VecDeque<String>),
+    /// A string holding an integer in its canonical form, kept as one so
+    /// counters needn't be parsed and formatted on every INCR. It reads
+    /// as its digits wherever a string is expected.
+    Int(i64),
+    List(List),
     Set(HashSet<String>),
-    Hash(HashMap<String, String>),
+    Hash(Hash),
+    ZSet(SortedSet),
+    Stream(Stream),
+    Bloom(Bloom),
 }
 
-#[derive(Debug, Clone)]
-struct Entry {
-    value: Value,
-    expires_at: Option<Instant>,
+impl Value {
+    /// A string value, kept as `Int` if it's an integer that formats back
+    /// to exactly the same string, so no string reads differently.
+    pub fn string(s: String) -> Value {
+        match s.parse::<i64>() {
+            Ok(n) if s.len() <= 20 && n.to_string() == s => Value::Int(n),
+            _ => Value::String(s),
+        }
+    }
+
+    /// The value as a string, if it's one.
+    pub fn as_string(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Value::String(s) => Some(Cow::Borrowed(s)),
+            Value::Int(n) => Some(Cow::Owned(n.to_string())),
+            _ => None,
+        }
+    }
+
+    /// How the value is encoded, as OBJECT ENCODING replies it. Strings
+    /// short enough for Redis to embed in their object are "embstr".
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::String(s) if s.len() <= 44 => "embstr",
+            Value::String(_) => "raw",
+            Value::List(list) => list.encoding(),
+            Value::Set(_) => "hashtable",
+            Value::Hash(hash) => hash.encoding(),
+            Value::ZSet(_) => "skiplist",
+            Value::Stream(_) => "stream",
+            Value::Bloom(_) => "raw",
+        }
+    }
+
+    /// Whether the value is a list, set or hash with nothing in it, which
+    /// no key is left holding. Streams are kept when empty, as in Redis,
+    /// for the last ID they gave out.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) | Value::Int(_) | Value::Stream(_) | Value::Bloom(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+        }
+    }
+
+    /// The type's name, as TYPE replies it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) | Value::Int(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::Hash(_) => "hash",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+            Value::Bloom(_) => "MBbloom--",
+        }
+    }
+
+    /// Bytes the value takes on the heap: its payload and the container
+    /// around it. The elements of collections are estimated from the first
+    /// `samples` of them, or all with 0, as MEMORY USAGE does.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Value::String(s) => s.capacity(),
+            Value::Int(_) => 0,
+            Value::List(List::Packed(listpack)) => listpack.capacity(),
+            Value::List(List::Deque(list)) => {
+                list.capacity() * size_of::<String>()
+                    + sampled_size(list.iter(), list.len(), samples, |item| item.capacity())
+            }
+            Value::Set(set) => {
+                set.capacity() * (size_of::<String>() + 1)
+                    + sampled_size(set.iter(), set.len(), samples, |member| member.capacity())
+            }
+            Value::Hash(Hash::Packed(listpack)) => listpack.capacity(),
+            Value::Hash(Hash::Table(hash)) => {
+                hash.capacity() * (2 * size_of::<String>() + 1)
+                    + sampled_size(hash.iter(), hash.len(), samples, |(field, value)| {
+                        field.capacity() + value.capacity()
+                    })
+            }
+            Value::ZSet(zset) => {
+                zset.overhead()
+                    + sampled_size(zset.iter(), zset.len(), samples, |(member, _)| member.len())
+            }
+            Value::Stream(stream) => {
+                stream.overhead()
+                    + sampled_size(stream.iter(), stream.len(), samples, |(_, fields)| {
+                        fields
+                            .iter()
+                            .map(|(field, value)| field.capacity() + value.capacity())
+                            .sum()
+                    })
+            }
+            Value::Bloom(bloom) => size_of_val(bloom.filters()) + bloom.bytes(),
+        }
+    }
+}
+
+impl Value {
+    /// Reallocates the value, and its elements, where most of the memory
+    /// reserved for them is unused, as active defrag does. Returns whether
+    /// anything was reallocated.
+    pub fn compact(&mut self) -> bool {
+        match self {
+            Value::String(s) => compact_string(s),
+            Value::Int(_) => false,
+            Value::List(List::Packed(listpack)) => {
+                let oversized = oversized(listpack.capacity(), listpack.bytes());
+                if oversized {
+                    listpack.shrink_to_fit();
+                }
+                oversized
+            }
+            Value::List(List::Deque(list)) => {
+                let mut compacted = false;
+                for item in list.iter_mut() {
+                    compacted |= compact_string(item);
+                }
+                if oversized(list.capacity(), list.len()) {
+                    list.shrink_to_fit();
+                    compacted = true;
+                }
+                compacted
+            }
+            // Set members can't be changed in place, only the table shrunk
+            Value::Set(set) => {
+                let oversized = oversized(set.capacity(), set.len());
+                if oversized {
+                    set.shrink_to_fit();
+                }
+                oversized
+            }
+            Value::Hash(Hash::Packed(listpack)) => {
+                let oversized = oversized(listpack.capacity(), listpack.bytes());
+                if oversized {
+                    listpack.shrink_to_fit();
+                }
+                oversized
+            }
+            Value::Hash(Hash::Table(hash)) => {
+                let mut compacted = false;
+                for value in hash.values_mut() {
+                    compacted |= compact_string(value);
+                }
+                if oversized(hash.capacity(), hash.len()) {
+                    hash.shrink_to_fit();
+                    compacted = true;
+                }
+                compacted
+            }
+            // Members are shared between the table and the skiplist
+            Value::ZSet(zset) => zset.shrink(oversized),
+            // Entries are never changed once added
+            Value::Stream(_) => false,
+            // The bits are allocated whole when a filter is created
+            Value::Bloom(_) => false,
+        }
+    }
+}
+
+/// Set bits in `bytes`, eight bytes at a time.
+fn popcount(bytes: &[u8]) -> u64 {
+    let words = bytes.chunks_exact(8);
+    let rest: u64 = words
+        .remainder()
+        .iter()
+        .map(|b| b.count_ones() as u64)
+        .sum();
+    let whole: u64 = words
+        .map(|word| u64::from_ne_bytes(word.try_into().unwrap()).count_ones() as u64)
+        .sum();
+    whole + rest
+}
+
+/// The positions `start` to `end` of something `len` long, negative ones
+/// counting from the end, clamped to it; None if that leaves nothing.
+fn clamp_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let from_end = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+    let (start, end) = (from_end(start), from_end(end).min(len - 1));
+    (start <= end).then_some((start as usize, end as usize))
+}
+
+/// Whether a buffer of `capacity` holding `len` wastes enough to be worth
+/// reallocating: at least as much as it uses, and 64 bytes or slots.
+fn oversized(capacity: usize, len: usize) -> bool {
+    capacity - len >= len.max(64)
+}
+
+fn compact_string(s: &mut String) -> bool {
+    let oversized = oversized(s.capacity(), s.len());
+    if oversized {
+        s.shrink_to_fit();
+    }
+    oversized
+}
+
+/// The total of `size` over `len` items, extrapolated from the first
+/// `samples` (all with 0). Hash tables spend a control byte per slot,
+/// which the callers count with the slots.
+fn sampled_size<T>(
+    items: impl Iterator<Item = T>,
+    len: usize,
+    samples: usize,
+    size: impl Fn(T) -> usize,
+) -> usize {
+    let taken = if samples == 0 { len } else { samples.min(len) };
+    if taken == 0 {
+        return 0;
+    }
+    let total: usize = items.take(taken).map(size).sum();
+    (total as f64 / taken as f64 * len as f64) as usize
+}
+
+/// A key's value and expiration. Values are shared with any snapshot that
+/// holds them and copied on write, so taking a snapshot copies no values.
+#[derive(Debug)]
+pub struct Entry {
+    value: Arc<Value>,
+    /// Unix time in milliseconds the key expires at, see `now_ms`.
+    expires_at: Option<u64>,
+    /// When the key was last accessed, on `evict::lru_clock`. Updated
+    /// under the read lock, hence atomic.
+    access: AtomicU64,
+    /// Access frequency for the LFU policies, see `evict::lfu_access`.
+    lfu: AtomicU32,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Entry {
+            value: Arc::clone(&self.value),
+            expires_at: self.expires_at,
+            access: AtomicU64::new(self.access.load(Ordering::Relaxed)),
+            lfu: AtomicU32::new(self.lfu.load(Ordering::Relaxed)),
+        }
+    }
 }
 
 impl Entry {
-    fn new(value: Value) -> Self {
+    pub fn new(value: Value) -> Self {
         Entry {
-            value,
+            value: Arc::new(value),
             expires_at: None,
+            access: AtomicU64::new(evict::lru_clock()),
+            lfu: AtomicU32::new(evict::lfu_new()),
         }
     }
 
     fn with_expiry(value: Value, duration: Duration) -> Self {
         Entry {
-            value,
-            expires_at: Some(Instant::now() + duration),
+            expires_at: Some(now_ms().saturating_add(duration.as_millis() as u64)),
+            ..Entry::new(value)
+        }
+    }
+
+    /// A new key holding the same value and expiration, sharing the value
+    /// until either is written to.
+    fn copied(&self) -> Self {
+        Entry {
+            value: Arc::clone(&self.value),
+            expires_at: self.expires_at,
+            access: AtomicU64::new(evict::lru_clock()),
+            lfu: AtomicU32::new(evict::lfu_new()),
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Milliseconds since the key was last accessed.
+    pub fn idle_ms(&self) -> u64 {
+        evict::lru_clock().saturating_sub(self.access.load(Ordering::Relaxed))
+    }
+
+    /// The LFU counter, decayed for the time since the last access.
+    pub fn lfu_counter(&self, decay_time: u64) -> u8 {
+        evict::lfu_decayed(self.lfu.load(Ordering::Relaxed), decay_time)
+    }
+
+    /// Records an access; `lfu` holds the lfu-log-factor and lfu-decay-time
+    /// when an LFU policy is in use.
+    fn touch(&self, lfu: Option<(u64, u64)>) {
+        self.access.store(evict::lru_clock(), Ordering::Relaxed);
+        if let Some((log_factor, decay_time)) = lfu {
+            let packed = self.lfu.load(Ordering::Relaxed);
+            self.lfu.store(
+                evict::lfu_access(packed, log_factor, decay_time),
+                Ordering::Relaxed,
+            );
         }
     }
 
     fn is_expired(&self) -> bool {
         match self.expires_at {
-            Some(exp) => Instant::now() >= exp,
+            Some(exp) => now_ms() >= exp,
             None => false,
         }
     }
@@ -41,11 +358,11 @@ impl Entry {
     fn ttl_ms(&self) -> Option<i64> {
         match self.expires_at {
             Some(exp) => {
-                let now = Instant::now();
+                let now = now_ms();
                 if now >= exp {
                     Some(-2)
                 } else {
-                    Some((exp - now).as_millis() as i64)
+                    Some((exp - now) as i64)
                 }
             }
             None => None,
@@ -53,176 +370,1487 @@ impl Entry {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Every live key of one database, with its value and its expiration as a
+/// Unix time in milliseconds.
+pub type DbSnapshot = Vec<(String, Arc<Value>, Option<u64>)>;
+
+/// Keys watched by clients with WATCH, and the clients whose watched keys
+/// have been modified since.
+#[derive(Debug, Default)]
+struct Watches {
+    keys: HashMap<(usize, String), HashSet<u64>>,
+    dirty: HashSet<u64>,
+}
+
+type KeyHook = Box<dyn Fn(KeyspaceEvent, usize, &str) + Send + Sync>;
+
+/// Callbacks registered with `Storage::on_key_change`.
+#[derive(Default)]
+struct KeyHooks {
+    hooks: RwLock<Vec<KeyHook>>,
+    /// Whether any is registered, so changes can skip the lock when not.
+    any: AtomicBool,
+}
+
+impl std::fmt::Debug for KeyHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyHooks")
+            .field("count", &self.hooks.read().unwrap().len())
+            .finish()
+    }
+}
+
+/// The numbered logical databases selected with SELECT.
+#[derive(Debug)]
 pub struct Storage {
-    data: Arc<RwLock<HashMap<String, Entry>>>,
+    dbs: Vec<Db>,
+    /// Held shared by every command and exclusively by EXEC, so a
+    /// transaction never interleaves with commands from other clients.
+    exec_lock: RwLock<()>,
+    watches: Mutex<Watches>,
+    /// Number of watched keys, so writes can skip the lock when it's zero.
+    watched_keys: AtomicUsize,
+    /// Database the next expiry cycle starts from.
+    expire_cursor: AtomicUsize,
+    key_hooks: KeyHooks,
 }
 
 impl Storage {
     pub fn new() -> Self {
+        Self::with_databases(16)
+    }
+
+    pub fn with_databases(count: usize) -> Self {
+        Self::with_engine(count, || Box::new(MemoryEngine::default()))
+    }
+
+    /// `count` databases, each kept by an engine made by `engine`.
+    pub fn with_engine(count: usize, engine: EngineFactory) -> Self {
         Storage {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            dbs: (0..count).map(|_| Db::with_engine(engine())).collect(),
+            exec_lock: RwLock::new(()),
+            watches: Mutex::new(Watches::default()),
+            watched_keys: AtomicUsize::new(0),
+            expire_cursor: AtomicUsize::new(0),
+            key_hooks: KeyHooks::default(),
         }
     }
 
-    fn cleanup_expired(&self) {
-        let mut data = self.data.write().unwrap();
-        data.retain(|_, entry| !entry.is_expired());
+    /// Number of databases; valid indexes are `0..len()`.
+    pub fn len(&self) -> usize {
+        self.dbs.len()
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        let data = self.data.read().unwrap();
-        match data.get(key) {
-            Some(entry) if !entry.is_expired() => {
-                if let Value::String(s) = &entry.value {
-                    Some(s.clone())
-                } else {
-                    None
-                }
-            }
-            _ => None,
+    pub fn is_empty(&self) -> bool {
+        self.dbs.is_empty()
+    }
+
+    pub fn db(&self, index: usize) -> &Db {
+        &self.dbs[index]
+    }
+
+    /// Sets `list-max-listpack-size`, past which lists are converted from
+    /// listpacks to deques, for lists written from now on.
+    pub fn set_list_max_listpack_size(&self, max_size: i64) {
+        for db in &self.dbs {
+            db.list_max_listpack_size.store(max_size, Ordering::Relaxed);
         }
     }
 
-    pub fn get_type(&self, key: &str) -> Option<&'static str> {
-        let data = self.data.read().unwrap();
-        match data.get(key) {
-            Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::String(_) => Some("string"),
-                Value::List(_) => Some("list"),
-                Value::Set(_) => Some("set"),
-                Value::Hash(_) => Some("hash"),
-            },
-            _ => None,
+    /// Sets `hash-max-listpack-entries` and `hash-max-listpack-value`,
+    /// past which hashes are converted from listpacks to tables, for
+    /// hashes written from now on.
+    pub fn set_hash_max_listpack(&self, max_entries: usize, max_value: usize) {
+        for db in &self.dbs {
+            db.hash_max_listpack_entries
+                .store(max_entries, Ordering::Relaxed);
+            db.hash_max_listpack_value
+                .store(max_value, Ordering::Relaxed);
         }
     }
 
-    pub fn set(&self, key: String, value: String) {
-        let mut data = self.data.write().unwrap();
-        data.insert(key, Entry::new(Value::String(value)));
+    /// Sets `bf-error-rate`, `bf-initial-size` and `bf-expansion-factor`,
+    /// what the bloom filters BF.ADD and BF.MADD create from now on start
+    /// with, the last also for BF.RESERVE.
+    pub fn set_bf_defaults(&self, error_rate: f64, initial_size: u64, expansion: u32) {
+        for db in &self.dbs {
+            db.bf_error_rate
+                .store(error_rate.to_bits(), Ordering::Relaxed);
+            db.bf_initial_size.store(initial_size, Ordering::Relaxed);
+            db.bf_expansion_factor.store(expansion, Ordering::Relaxed);
+        }
     }
 
-    pub fn set_with_expiry(&self, key: String, value: String, expiry_ms: u64) {
-        let mut data = self.data.write().unwrap();
-        let entry = Entry::with_expiry(Value::String(value), Duration::from_millis(expiry_ms));
-        data.insert(key, entry);
+    /// Exchanges the contents of two databases, as SWAPDB does. Connections
+    /// that selected either index see the other dataset from now on.
+    pub fn swap_db(&self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        let (mut first, mut second) = self.write_pair(a, b);
+        std::mem::swap(&mut *first, &mut *second);
+        drop((first, second));
+        self.touch_db(a);
+        self.touch_db(b);
+    }
+
+    /// Write locks on two different databases, `a`'s first. They're taken
+    /// in index order so concurrent callers can't deadlock.
+    fn write_pair(
+        &self,
+        a: usize,
+        b: usize,
+    ) -> (
+        RwLockWriteGuard<'_, Keyspace>,
+        RwLockWriteGuard<'_, Keyspace>,
+    ) {
+        if a < b {
+            let first = self.dbs[a].data.write().unwrap();
+            (first, self.dbs[b].data.write().unwrap())
+        } else {
+            let second = self.dbs[b].data.write().unwrap();
+            (self.dbs[a].data.write().unwrap(), second)
+        }
     }
 
-    pub fn expire(&self, key: &str, expiry_ms: u64) -> bool {
-        let mut data = self.data.write().unwrap();
-        if let Some(entry) = data.get_mut(key) {
-            if !entry.is_expired() {
-                entry.expires_at = Some(Instant::now() + Duration::from_millis(expiry_ms));
-                return true;
+    /// Copies `src` of database `src_db` to `dst` of `dst_db` along with its
+    /// expiration, as COPY does. The value is shared until either key is
+    /// written to. Returns false, copying nothing, if `src` doesn't exist or
+    /// `dst` does and `replace` isn't set.
+    pub fn copy(&self, src_db: usize, src: &str, dst_db: usize, dst: &str, replace: bool) -> bool {
+        let place = |target: &mut Keyspace, entry: Entry| {
+            if !replace && target.get(dst).is_some_and(|e| !e.is_expired()) {
+                return false;
             }
+            target.insert(dst.to_string(), entry);
+            true
+        };
+        let copied = |source: &Keyspace| {
+            source
+                .get(src)
+                .filter(|entry| !entry.is_expired())
+                .map(Entry::copied)
+        };
+        if src_db == dst_db {
+            let mut data = self.dbs[src_db].data.write().unwrap();
+            return copied(&data).is_some_and(|entry| place(&mut data, entry));
         }
-        false
+        let (source, mut target) = self.write_pair(src_db, dst_db);
+        copied(&source).is_some_and(|entry| place(&mut target, entry))
+    }
+
+    /// Moves `key` from database `src_db` to `dst_db`, which must differ,
+    /// with its expiration, as MOVE does. Returns false, moving nothing, if
+    /// the key doesn't exist in `src_db` or already does in `dst_db`.
+    pub fn move_key(&self, key: &str, src_db: usize, dst_db: usize) -> bool {
+        let (mut source, mut target) = self.write_pair(src_db, dst_db);
+        let live = |data: &Keyspace| data.get(key).is_some_and(|entry| !entry.is_expired());
+        if !live(&source) || live(&target) {
+            return false;
+        }
+        let entry = source.remove(key).unwrap();
+        target.insert(key.to_string(), entry);
+        true
+    }
+
+    /// A point-in-time copy of every database, for saving while writes go
+    /// on. The databases are locked together, in index order as in
+    /// `swap_db`, but only for as long as it takes to copy the keys: values
+    /// are shared with the live dataset and only copied when written to.
+    pub fn snapshot(&self) -> Vec<DbSnapshot> {
+        let guards: Vec<_> = self.dbs.iter().map(|db| db.data.read().unwrap()).collect();
+        guards
+            .iter()
+            .map(|data| {
+                data.iter()
+                    .filter(|(_, e)| !e.is_expired())
+                    .map(|(key, entry)| {
+                        (key.clone(), Arc::clone(&entry.value), entry.expires_at)
+                    })
+                    .collect()
+            })
+            .collect()
     }
 
-    pub fn persist(&self, key: &str) -> bool {
-        let mut data = self.data.write().unwrap();
-        if let Some(entry) = data.get_mut(key) {
-            if !entry.is_expired() && entry.expires_at.is_some() {
-                entry.expires_at = None;
-                return true;
-            }
+    pub fn flushdb(&self, index: usize) {
+        self.dbs[index].flushdb();
+        self.touch_db(index);
+    }
+
+    pub fn flushall(&self) {
+        for index in 0..self.dbs.len() {
+            self.flushdb(index);
         }
-        false
     }
 
-    pub fn ttl(&self, key: &str) -> i64 {
-        let data = self.data.read().unwrap();
-        match data.get(key) {
-            Some(entry) if !entry.is_expired() => entry.ttl_ms().unwrap_or(-1),
-            _ => -2,
+    /// Starts watching a key on behalf of a client.
+    pub fn watch(&self, client: u64, db: usize, key: &str) {
+        let mut watches = self.watches.lock().unwrap();
+        let clients = watches.keys.entry((db, key.to_string())).or_default();
+        if clients.is_empty() {
+            self.watched_keys.fetch_add(1, Ordering::Relaxed);
         }
+        clients.insert(client);
     }
 
-    pub fn del(&self, keys: &[String]) -> usize {
-        let mut data = self.data.write().unwrap();
-        let mut count = 0;
+    /// Drops every watch of a client, returning whether any watched key was
+    /// modified in the meantime.
+    pub fn unwatch(&self, client: u64, keys: &[(usize, String)]) -> bool {
+        let mut watches = self.watches.lock().unwrap();
         for key in keys {
-            if data.remove(key).is_some() {
-                count += 1;
+            if let Some(clients) = watches.keys.get_mut(key) {
+                clients.remove(&client);
+                if clients.is_empty() {
+                    watches.keys.remove(key);
+                    self.watched_keys.fetch_sub(1, Ordering::Relaxed);
+                }
             }
         }
-        count
+        watches.dirty.remove(&client)
     }
 
-    pub fn exists(&self, keys: &[String]) -> usize {
-        let data = self.data.read().unwrap();
-        keys.iter()
-            .filter(|key| data.get(*key).map(|e| !e.is_expired()).unwrap_or(false))
-            .count()
+    /// Marks the clients watching a key as dirty.
+    pub fn touch_key(&self, db: usize, key: &str) {
+        if self.watched_keys.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut watches = self.watches.lock().unwrap();
+        let Watches { keys, dirty } = &mut *watches;
+        if let Some(clients) = keys.get(&(db, key.to_string())) {
+            dirty.extend(clients);
+        }
     }
 
-    pub fn incr(&self, key: &str) -> Result<i64, String> {
-        self.incr_by(key, 1)
+    /// Marks every client watching a key of a database as dirty.
+    pub fn touch_db(&self, db: usize) {
+        if self.watched_keys.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut watches = self.watches.lock().unwrap();
+        let Watches { keys, dirty } = &mut *watches;
+        for ((watched_db, _), clients) in keys.iter() {
+            if *watched_db == db {
+                dirty.extend(clients);
+            }
+        }
     }
 
-    pub fn decr(&self, key: &str) -> Result<i64, String> {
-        self.incr_by(key, -1)
+    /// Registers `hook` to be called with every change to a key: set or
+    /// deleted by a write command, expired or evicted, with the database and
+    /// the key. This lets applications embedding reredis keep their own
+    /// index or write through to another store without a client connection.
+    ///
+    /// Hooks run in registration order, on the thread that made the change,
+    /// once it's made but while the command still holds the locks ordering
+    /// it in the append-only file and replication stream. They may read the
+    /// keyspace but should return quickly, and mustn't register hooks. Keys
+    /// a write command names but leaves as they were, as SETNX on an
+    /// existing key does, get `Set` too; FLUSHDB, FLUSHALL and SWAPDB raise
+    /// nothing per key.
+    pub fn on_key_change(&self, hook: impl Fn(KeyspaceEvent, usize, &str) + Send + Sync + 'static) {
+        self.key_hooks.hooks.write().unwrap().push(Box::new(hook));
+        self.key_hooks.any.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether any hook is registered with `on_key_change`.
+    pub fn has_key_hooks(&self) -> bool {
+        self.key_hooks.any.load(Ordering::Relaxed)
+    }
+
+    /// Calls the hooks registered with `on_key_change`. Changes are raised
+    /// through `notify::notify`, which also keeps the statistics.
+    pub fn key_changed(&self, event: KeyspaceEvent, db: usize, key: &str) {
+        if !self.has_key_hooks() {
+            return;
+        }
+        for hook in self.key_hooks.hooks.read().unwrap().iter() {
+            hook(event, db, key);
+        }
     }
 
-    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64, String> {
-        let mut data = self.data.write().unwrap();
-        let entry = data.get(key);
+    /// Guard for running a single command.
+    pub fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.exec_lock.read().unwrap()
+    }
+
+    /// Guard for running several commands as one atomic unit.
+    pub fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.exec_lock.write().unwrap()
+    }
+
+    /// Drops expired keys database by database until `budget` is used up,
+    /// returning them with their database index; the next cycle carries on
+    /// where this one stopped. The caller holds the `shared` guard.
+    ///
+    /// Expired keys are found in each database's expiration index, oldest
+    /// deadline first, so keys without an expiration are never visited.
+    pub fn run_expiry_cleanup(&self, budget: Duration) -> Vec<(usize, String)> {
+        let start = Instant::now();
+        let first = self.expire_cursor.load(Ordering::Relaxed);
+        let mut expired = Vec::new();
+        for offset in 0..self.dbs.len() {
+            let index = (first + offset) % self.dbs.len();
+            loop {
+                let keys = self.dbs[index].remove_expired_batch(EXPIRE_KEYS_PER_LOOP);
+                let more = keys.len() == EXPIRE_KEYS_PER_LOOP;
+                expired.extend(keys.into_iter().map(|key| (index, key)));
+                if !more {
+                    break;
+                }
+                // Carry on with this database next cycle
+                if start.elapsed() >= budget {
+                    return expired;
+                }
+            }
+            self.expire_cursor
+                .store((index + 1) % self.dbs.len(), Ordering::Relaxed);
+            if start.elapsed() >= budget {
+                break;
+            }
+        }
+        expired
+    }
 
-        let current = match entry {
-            Some(e) if !e.is_expired() => {
-                if let Value::String(s) = &e.value {
-                    s.parse::<i64>()
-                        .map_err(|_| "ERR value is not an integer or out of range".to_string())?
-                } else {
+    /// The nearest expiration deadline in any database, expired or not.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.dbs.iter().filter_map(Db::next_expiry).min()
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How SET writes a string: NX only if the key doesn't exist, XX only if
+/// it does, expiring at `expires_at` (Unix milliseconds) or, with
+/// `keep_ttl`, when the key already did, and with `get` returning the value
+/// it replaces.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub get: bool,
+    pub keep_ttl: bool,
+    pub expires_at: Option<u64>,
+}
+
+/// The end of a list elements are popped from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    /// The pop command for this end, which blocking pops are propagated
+    /// as so they replay without blocking.
+    pub fn pop_command(self) -> &'static str {
+        match self {
+            End::Left => "lpop",
+            End::Right => "rpop",
+        }
+    }
+}
+
+/// How ZADD updates a sorted set: NX only adding new members, XX only
+/// updating existing ones, GT and LT only to a greater or lesser score,
+/// and CH counting the members whose scores changed as well as those
+/// added. INCR adds to a member's score as ZINCRBY does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+impl ZAddOptions {
+    /// Whether a member scored `current`, or not in the set, may be
+    /// scored `score`. New members aren't held to GT or LT.
+    fn allows(self, current: Option<f64>, score: f64) -> bool {
+        match current {
+            None => !self.xx,
+            Some(current) => {
+                !self.nx && (!self.gt || score > current) && (!self.lt || score < current)
+            }
+        }
+    }
+}
+
+/// The end of a sorted set members are popped from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreEnd {
+    Min,
+    Max,
+}
+
+impl ScoreEnd {
+    /// The pop command for this end, which ZMPOP is propagated as.
+    pub fn pop_command(self) -> &'static str {
+        match self {
+            ScoreEnd::Min => "zpopmin",
+            ScoreEnd::Max => "zpopmax",
+        }
+    }
+}
+
+/// How SINTER, SUNION and SDIFF combine their sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    /// The members of the first set in none of the others.
+    Diff,
+}
+
+/// The sets at `keys` combined by `op`, missing keys counting as empty
+/// sets, or a WRONGTYPE error if any holds something else.
+fn combine_sets(data: &Keyspace, op: SetOp, keys: &[String]) -> Result<HashSet<String>, String> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => match &*entry.value {
+                Value::Set(set) => sets.push(Some(set)),
+                _ => {
                     return Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
                             .to_string(),
                     );
                 }
+            },
+            _ => sets.push(None),
+        }
+    }
+    let combined = match op {
+        SetOp::Union => sets.iter().flatten().flat_map(|set| set.iter()).cloned().collect(),
+        SetOp::Diff => match sets.split_first() {
+            Some((Some(first), others)) => first
+                .iter()
+                .filter(|member| !others.iter().flatten().any(|set| set.contains(*member)))
+                .cloned()
+                .collect(),
+            _ => HashSet::new(),
+        },
+        // Only members of the smallest set can be in all of them, so it's
+        // the one walked, checking the others smallest first
+        SetOp::Inter => match sets.into_iter().collect::<Option<Vec<_>>>() {
+            Some(mut sets) if !sets.is_empty() => {
+                sets.sort_by_key(|set| set.len());
+                let (smallest, others) = sets.split_first().unwrap();
+                smallest
+                    .iter()
+                    .filter(|member| others.iter().all(|set| set.contains(*member)))
+                    .cloned()
+                    .collect()
             }
-            _ => 0,
-        };
+            _ => HashSet::new(),
+        },
+    };
+    Ok(combined)
+}
 
-        let new_value = current
-            .checked_add(delta)
-            .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
+/// How ZUNION and ZINTER combine the scores a member has in the sets it's
+/// in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
 
-        data.insert(
-            key.to_string(),
-            Entry::new(Value::String(new_value.to_string())),
-        );
-        Ok(new_value)
+impl Aggregate {
+    /// Combines two scores, a sum of opposite infinities being 0 as it is
+    /// to Redis.
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
     }
+}
 
-    pub fn append(&self, key: &str, value: &str) -> Result<usize, String> {
-        let mut data = self.data.write().unwrap();
-        let entry = data.get(key);
+fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() { 0.0 } else { score }
+}
 
-        let new_value = match entry {
-            Some(e) if !e.is_expired() => {
-                if let Value::String(s) = &e.value {
-                    format!("{}{}", s, value)
-                } else {
+/// An input of ZUNION, ZINTER and ZDIFF: a sorted set, or a set whose
+/// members all score 1.
+#[derive(Clone, Copy)]
+enum Scored<'a> {
+    ZSet(&'a SortedSet),
+    Set(&'a HashSet<String>),
+}
+
+impl<'a> Scored<'a> {
+    fn len(self) -> usize {
+        match self {
+            Scored::ZSet(zset) => zset.len(),
+            Scored::Set(set) => set.len(),
+        }
+    }
+
+    fn score(self, member: &str) -> Option<f64> {
+        match self {
+            Scored::ZSet(zset) => zset.score(member),
+            Scored::Set(set) => set.contains(member).then_some(1.0),
+        }
+    }
+
+    fn iter(self) -> Box<dyn Iterator<Item = (&'a str, f64)> + 'a> {
+        match self {
+            Scored::ZSet(zset) => Box::new(zset.iter()),
+            Scored::Set(set) => Box::new(set.iter().map(|member| (member.as_str(), 1.0))),
+        }
+    }
+}
+
+/// The sorted sets or sets at `keys` combined by `op`, missing keys
+/// counting as empty, each one's scores multiplied by its weight in
+/// `weights` and a member's scores in several combined by `aggregate`.
+/// Differences keep the first set's scores as they are.
+fn combine_zsets(
+    data: &Keyspace,
+    op: SetOp,
+    keys: &[String],
+    weights: &[f64],
+    aggregate: Aggregate,
+) -> Result<SortedSet, String> {
+    let mut inputs = Vec::with_capacity(keys.len());
+    for key in keys {
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => match &*entry.value {
+                Value::ZSet(zset) => inputs.push(Some(Scored::ZSet(zset))),
+                Value::Set(set) => inputs.push(Some(Scored::Set(set))),
+                _ => {
                     return Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
                             .to_string(),
                     );
                 }
+            },
+            _ => inputs.push(None),
+        }
+    }
+    // Infinity times 0 is 0 too
+    let weighted = |score: f64, i: usize| zero_if_nan(score * weights[i]);
+
+    let mut combined = SortedSet::new();
+    match op {
+        SetOp::Union => {
+            let mut scores: HashMap<&str, f64> = HashMap::new();
+            for (i, input) in inputs.iter().enumerate() {
+                for (member, score) in input.iter().flat_map(|input| input.iter()) {
+                    let score = weighted(score, i);
+                    scores
+                        .entry(member)
+                        .and_modify(|total| *total = aggregate.apply(*total, score))
+                        .or_insert(score);
+                }
             }
-            _ => value.to_string(),
-        };
+            for (member, score) in scores {
+                combined.insert(member, score);
+            }
+        }
+        SetOp::Diff => {
+            if let Some((Some(first), others)) = inputs.split_first() {
+                let others: Vec<_> = others.iter().flatten().collect();
+                for (member, score) in first.iter() {
+                    if others.iter().all(|set| set.score(member).is_none()) {
+                        combined.insert(member, score);
+                    }
+                }
+            }
+        }
+        // Only members of the smallest set can be in all of them, so it's
+        // the one walked
+        SetOp::Inter => {
+            if let Some(inputs) = inputs.into_iter().collect::<Option<Vec<_>>>()
+                && let Some(smallest) = inputs.iter().min_by_key(|input| input.len())
+            {
+                'members: for (member, _) in smallest.iter() {
+                    let mut total = None;
+                    for (i, input) in inputs.iter().enumerate() {
+                        let Some(score) = input.score(member) else {
+                            continue 'members;
+                        };
+                        let score = weighted(score, i);
+                        total = Some(total.map_or(score, |total| aggregate.apply(total, score)));
+                    }
+                    if let Some(total) = total {
+                        combined.insert(member, total);
+                    }
+                }
+            }
+        }
+    }
+    Ok(combined)
+}
+
+/// Conditions on a key's current expiration for EXPIRE and its variants to
+/// set a new one: NX only if there's none, XX only if there's one, and GT
+/// and LT only if the new one is later or earlier. No expiration counts as
+/// later than any.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExpireOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+}
+
+impl ExpireOptions {
+    /// Whether a key expiring at `current`, or never, may expire at `at`.
+    fn allows(self, current: Option<u64>, at: u64) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => !self.nx && (!self.gt || at > current) && (!self.lt || at < current),
+        }
+    }
+}
+
+/// A database's entries, kept by its engine, and an index of the keys
+/// with an expiration ordered by deadline. Entries are only added, removed
+/// or given an expiration through here, so the index stays in step.
+#[derive(Debug)]
+struct Keyspace {
+    engine: Box<dyn StorageEngine>,
+    expires: BTreeSet<(u64, String)>,
+}
+
+impl Keyspace {
+    fn new(engine: Box<dyn StorageEngine>) -> Self {
+        Keyspace {
+            engine,
+            expires: BTreeSet::new(),
+        }
+    }
 
-        let len = new_value.len();
-        data.insert(key.to_string(), Entry::new(Value::String(new_value)));
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.engine.get(key)
+    }
+
+    /// The entry under `key`, to change its value; its expiration is
+    /// changed with `set_expiry`.
+    fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.engine.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        if let Some(old) = self.engine.get(&key).and_then(|old| old.expires_at) {
+            self.expires.remove(&(old, key.clone()));
+        }
+        if let Some(at) = entry.expires_at {
+            self.expires.insert((at, key.clone()));
+        }
+        self.engine.insert(key, entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.engine.remove(key)?;
+        if let Some(at) = entry.expires_at {
+            self.expires.remove(&(at, key.to_string()));
+        }
+        Some(entry)
+    }
+
+    /// Removes `key` if it holds an empty collection, as it does once its
+    /// last element is popped or removed.
+    fn remove_if_empty(&mut self, key: &str) {
+        if self
+            .engine
+            .get(key)
+            .is_some_and(|entry| entry.value.is_empty_collection())
+        {
+            self.remove(key);
+        }
+    }
+
+    /// The live entry under `key`, replacing an expired one and inserting
+    /// `default()`, which has no expiration, when there's none.
+    fn get_or_insert_with(&mut self, key: &str, default: &mut dyn FnMut() -> Entry) -> &mut Entry {
+        if self.engine.get(key).is_some_and(|entry| entry.is_expired()) {
+            self.remove(key);
+        }
+        self.engine.get_or_insert_with(key, default)
+    }
+
+    /// Sets or clears the expiration of `key`, returning whether it exists.
+    fn set_expiry(&mut self, key: &str, at: Option<u64>) -> bool {
+        let Some(entry) = self.engine.get_mut(key) else {
+            return false;
+        };
+        if let Some(old) = std::mem::replace(&mut entry.expires_at, at) {
+            self.expires.remove(&(old, key.to_string()));
+        }
+        if let Some(at) = at {
+            self.expires.insert((at, key.to_string()));
+        }
+        true
+    }
+
+    /// Removes up to `count` keys whose deadline is at or before `now`.
+    fn pop_expired(&mut self, now: u64, count: usize) -> Vec<String> {
+        let mut expired = Vec::new();
+        while expired.len() < count {
+            match self.expires.first() {
+                Some((at, _)) if *at <= now => {}
+                _ => break,
+            }
+            let (_, key) = self.expires.pop_first().unwrap();
+            self.engine.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+
+    fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    fn clear(&mut self) {
+        self.engine.clear();
+        self.expires.clear();
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Entry)> + '_> {
+        self.engine.iter()
+    }
+
+    fn get_index(&self, index: usize) -> Option<(&String, &Entry)> {
+        self.engine.get_index(index)
+    }
+}
+
+/// A single keyspace, kept by a `StorageEngine`.
+#[derive(Debug)]
+pub struct Db {
+    data: RwLock<Keyspace>,
+    /// `list-max-listpack-size`, for the lists written here.
+    list_max_listpack_size: AtomicI64,
+    /// `hash-max-listpack-entries` and `hash-max-listpack-value`, for the
+    /// hashes written here.
+    hash_max_listpack_entries: AtomicUsize,
+    hash_max_listpack_value: AtomicUsize,
+    /// `bf-error-rate`, as its bits, `bf-initial-size` and
+    /// `bf-expansion-factor`, for the bloom filters created here.
+    bf_error_rate: AtomicU64,
+    bf_initial_size: AtomicU64,
+    bf_expansion_factor: AtomicU32,
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Self::with_engine(Box::new(MemoryEngine::default()))
+    }
+
+    pub fn with_engine(engine: Box<dyn StorageEngine>) -> Self {
+        Db {
+            data: RwLock::new(Keyspace::new(engine)),
+            list_max_listpack_size: AtomicI64::new(list::DEFAULT_MAX_LISTPACK_SIZE),
+            hash_max_listpack_entries: AtomicUsize::new(hash::DEFAULT_MAX_LISTPACK_ENTRIES),
+            hash_max_listpack_value: AtomicUsize::new(hash::DEFAULT_MAX_LISTPACK_VALUE),
+            bf_error_rate: AtomicU64::new(bloom::DEFAULT_ERROR_RATE.to_bits()),
+            bf_initial_size: AtomicU64::new(bloom::DEFAULT_CAPACITY),
+            bf_expansion_factor: AtomicU32::new(bloom::DEFAULT_EXPANSION),
+        }
+    }
+
+    fn list_max_listpack_size(&self) -> i64 {
+        self.list_max_listpack_size.load(Ordering::Relaxed)
+    }
+
+    /// `hash-max-listpack-entries` and `hash-max-listpack-value`.
+    fn hash_max_listpack(&self) -> (usize, usize) {
+        (
+            self.hash_max_listpack_entries.load(Ordering::Relaxed),
+            self.hash_max_listpack_value.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Whether `key` exists but has expired, waiting to be removed.
+    pub fn is_expired(&self, key: &str) -> bool {
+        let data = self.data.read().unwrap();
+        data.get(key).is_some_and(|entry| entry.is_expired())
+    }
+
+    /// Removes `key` if it has expired, returning whether it did.
+    pub fn remove_expired(&self, key: &str) -> bool {
+        let mut data = self.data.write().unwrap();
+        if data.get(key).is_some_and(|entry| entry.is_expired()) {
+            data.remove(key);
+            return true;
+        }
+        false
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => entry.value.as_string().map(Cow::into_owned),
+            _ => None,
+        }
+    }
+
+    /// The encoding of `key`'s value, as OBJECT ENCODING replies it.
+    pub fn encoding(&self, key: &str) -> Option<&'static str> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(entry.value.encoding()),
+            _ => None,
+        }
+    }
+
+    pub fn get_type(&self, key: &str) -> Option<&'static str> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(entry.value.type_name()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) {
+        let mut data = self.data.write().unwrap();
+        data.insert(key, Entry::new(Value::string(value)));
+    }
+
+    /// Sets `key` to the string `value` as `options` say, checking the
+    /// conditions and reading the old value under the same lock as the
+    /// write. Returns whether the key was written and, with `get`, its old
+    /// value; `get` on a key that isn't a string fails without writing.
+    pub fn set_with_options(
+        &self,
+        key: String,
+        value: String,
+        options: SetOptions,
+    ) -> Result<(bool, Option<String>), String> {
+        let mut data = self.data.write().unwrap();
+        let current = data.get(&key).filter(|entry| !entry.is_expired());
+        let old = match current.map(|entry| entry.value.as_string()) {
+            Some(Some(s)) if options.get => Some(s.into_owned()),
+            Some(None) if options.get => {
+                return Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                );
+            }
+            _ => None,
+        };
+        let exists = current.is_some();
+        if options.nx && exists || options.xx && !exists {
+            return Ok((false, old));
+        }
+        let expires_at = if options.keep_ttl {
+            current.and_then(|entry| entry.expires_at)
+        } else {
+            options.expires_at
+        };
+        let entry = Entry {
+            expires_at,
+            ..Entry::new(Value::string(value))
+        };
+        data.insert(key, entry);
+        Ok((true, old))
+    }
+
+    pub fn set_with_expiry(&self, key: String, value: String, expiry_ms: u64) {
+        let mut data = self.data.write().unwrap();
+        let entry = Entry::with_expiry(Value::string(value), Duration::from_millis(expiry_ms));
+        data.insert(key, entry);
+    }
+
+    pub fn expire(&self, key: &str, expiry_ms: u64) -> bool {
+        let mut data = self.data.write().unwrap();
+        if data.get(key).is_some_and(|entry| !entry.is_expired()) {
+            return data.set_expiry(key, Some(now_ms().saturating_add(expiry_ms)));
+        }
+        false
+    }
+
+    /// Expires a key at a Unix time in milliseconds, if `options` allow;
+    /// a time in the past leaves it expired, for the caller or the expire
+    /// cycle to remove. Returns whether the expiration was set.
+    pub fn expire_at(&self, key: &str, at_ms: u64, options: ExpireOptions) -> bool {
+        let mut data = self.data.write().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() && options.allows(entry.expires_at, at_ms) => {
+                data.set_expiry(key, Some(at_ms))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn persist(&self, key: &str) -> bool {
+        let mut data = self.data.write().unwrap();
+        if data
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired() && entry.expires_at.is_some())
+        {
+            return data.set_expiry(key, None);
+        }
+        false
+    }
+
+    pub fn ttl(&self, key: &str) -> i64 {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => entry.ttl_ms().unwrap_or(-1),
+            _ => -2,
+        }
+    }
+
+    pub fn del(&self, keys: &[String]) -> usize {
+        let mut data = self.data.write().unwrap();
+        let mut count = 0;
+        for key in keys {
+            if data.remove(key).is_some() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn exists(&self, keys: &[String]) -> usize {
+        let data = self.data.read().unwrap();
+        keys.iter()
+            .filter(|key| data.get(key).map(|e| !e.is_expired()).unwrap_or(false))
+            .count()
+    }
+
+    pub fn incr(&self, key: &str) -> Result<i64, String> {
+        self.incr_by(key, 1)
+    }
+
+    pub fn decr(&self, key: &str) -> Result<i64, String> {
+        self.incr_by(key, -1)
+    }
+
+    /// Adds `delta` to the integer at `key`, a missing key counting as 0.
+    /// The value is changed in place, so the key keeps its expiration.
+    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64, String> {
+        let mut data = self.data.write().unwrap();
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::Int(0)));
+
+        let current = match &*entry.value {
+            Value::Int(n) => *n,
+            Value::String(s) => s
+                .parse::<i64>()
+                .map_err(|_| "ERR value is not an integer or out of range".to_string())?,
+            _ => {
+                return Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                );
+            }
+        };
+
+        let new_value = current
+            .checked_add(delta)
+            .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
+
+        entry.value = Arc::new(Value::Int(new_value));
+        Ok(new_value)
+    }
+
+    /// Appends `value` to the string at `key`, creating it if missing.
+    /// The value is changed in place, so the key keeps its expiration.
+    pub fn append(&self, key: &str, value: &str) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::String(String::new())));
+
+        match Arc::make_mut(&mut entry.value) {
+            Value::String(s) => {
+                s.push_str(value);
+                Ok(binary::len(s))
+            }
+            Value::Int(n) => {
+                let s = format!("{}{}", n, value);
+                let len = binary::len(&s);
+                entry.value = Arc::new(Value::String(s));
+                Ok(len)
+            }
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            }
+        }
+    }
+
+    pub fn strlen(&self, key: &str) -> Result<usize, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Some(s) = entry.value.as_string() {
+                    Ok(binary::len(&s))
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Set bits in the string at `key`, or in its part from `start` to
+    /// `end` inclusive, positions being bytes or, with `bits`, bits and
+    /// negative ones counting from the end, as BITCOUNT does.
+    pub fn bitcount(
+        &self,
+        key: &str,
+        range: Option<(i64, i64)>,
+        bits: bool,
+    ) -> Result<u64, String> {
+        let data = self.data.read().unwrap();
+        let value = match data.get(key) {
+            Some(entry) if !entry.is_expired() => match entry.value.as_string() {
+                Some(s) => s,
+                None => {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                }
+            },
+            _ => return Ok(0),
+        };
+        let value = binary::encode(&value);
+        let bytes = &*value;
+        let Some((start, end)) = range else {
+            return Ok(popcount(bytes));
+        };
+        let len = if bits { bytes.len() * 8 } else { bytes.len() };
+        let Some((start, end)) = clamp_range(start, end, len) else {
+            return Ok(0);
+        };
+        if !bits {
+            return Ok(popcount(&bytes[start..=end]));
+        }
+        // Whole bytes, less the bits of the first and last ones outside
+        // the range; bit 0 is the most significant of the first byte
+        let (first, last) = (bytes[start / 8], bytes[end / 8]);
+        let before = (first & !(0xff >> (start % 8))).count_ones();
+        let after = (last & 0xffu8.checked_shr(end as u32 % 8 + 1).unwrap_or(0)).count_ones();
+        Ok(popcount(&bytes[start / 8..=end / 8]) - before as u64 - after as u64)
+    }
+
+    pub fn mset(&self, pairs: Vec<(String, String)>) {
+        let mut data = self.data.write().unwrap();
+        for (key, value) in pairs {
+            data.insert(key, Entry::new(Value::string(value)));
+        }
+    }
+
+    pub fn mget(&self, keys: &[String]) -> Vec<Option<String>> {
+        let data = self.data.read().unwrap();
+        keys.iter()
+            .map(|key| {
+                data.get(key).and_then(|e| {
+                    if !e.is_expired() {
+                        e.value.as_string().map(Cow::into_owned)
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect()
+    }
+
+    pub fn lpush(&self, key: &str, values: Vec<String>) -> Result<usize, String> {
+        let max_size = self.list_max_listpack_size();
+        let mut data = self.data.write().unwrap();
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::List(List::new())));
+
+        if let Value::List(list) = Arc::make_mut(&mut entry.value) {
+            for v in values {
+                list.push_front(v);
+            }
+            list.convert(max_size);
+            Ok(list.len())
+        } else {
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+        }
+    }
+
+    pub fn rpush(&self, key: &str, values: Vec<String>) -> Result<usize, String> {
+        let max_size = self.list_max_listpack_size();
+        let mut data = self.data.write().unwrap();
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::List(List::new())));
+
+        if let Value::List(list) = Arc::make_mut(&mut entry.value) {
+            for v in values {
+                list.push_back(v);
+            }
+            list.convert(max_size);
+            Ok(list.len())
+        } else {
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+        }
+    }
+
+    pub fn lpop(&self, key: &str) -> Result<Option<String>, String> {
+        let mut data = self.data.write().unwrap();
+        let popped = match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = Arc::make_mut(&mut entry.value) {
+                    Ok(list.pop_front())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(None),
+        };
+        data.remove_if_empty(key);
+        popped
+    }
+
+    pub fn rpop(&self, key: &str) -> Result<Option<String>, String> {
+        let mut data = self.data.write().unwrap();
+        let popped = match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = Arc::make_mut(&mut entry.value) {
+                    Ok(list.pop_back())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(None),
+        };
+        data.remove_if_empty(key);
+        popped
+    }
+
+    /// Pops up to `count` elements from `end` of the list at `key`, in the
+    /// order they're popped.
+    pub fn pop(&self, key: &str, end: End, count: usize) -> Result<Vec<String>, String> {
+        let mut data = self.data.write().unwrap();
+        let popped = match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = Arc::make_mut(&mut entry.value) {
+                    let count = count.min(list.len());
+                    Ok(match end {
+                        End::Left => (0..count).filter_map(|_| list.pop_front()).collect(),
+                        End::Right => (0..count).filter_map(|_| list.pop_back()).collect(),
+                    })
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(Vec::new()),
+        };
+        data.remove_if_empty(key);
+        popped
+    }
+
+    pub fn llen(&self, key: &str) -> Result<usize, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = &*entry.value {
+                    Ok(list.len())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(0),
+        }
+    }
+
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = &*entry.value {
+                    let len = list.len() as i64;
+                    if len == 0 {
+                        return Ok(vec![]);
+                    }
+
+                    let start = if start < 0 {
+                        (len + start).max(0) as usize
+                    } else {
+                        start.min(len) as usize
+                    };
+
+                    let stop = if stop < 0 {
+                        (len + stop).max(0) as usize
+                    } else {
+                        stop.min(len - 1) as usize
+                    };
+
+                    if start > stop || start >= len as usize {
+                        return Ok(vec![]);
+                    }
+
+                    Ok(list
+                        .iter()
+                        .skip(start)
+                        .take(stop - start + 1)
+                        .map(str::to_string)
+                        .collect())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    pub fn lindex(&self, key: &str, index: i64) -> Result<Option<String>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = &*entry.value {
+                    let len = list.len() as i64;
+                    let idx = if index < 0 { len + index } else { index };
+                    if idx < 0 || idx >= len {
+                        Ok(None)
+                    } else {
+                        Ok(list.get(idx as usize).map(str::to_string))
+                    }
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn lset(&self, key: &str, index: i64, value: String) -> Result<(), String> {
+        let max_size = self.list_max_listpack_size();
+        let mut data = self.data.write().unwrap();
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::List(list) = Arc::make_mut(&mut entry.value) {
+                    let len = list.len() as i64;
+                    let idx = if index < 0 { len + index } else { index };
+                    if idx < 0 || idx >= len {
+                        Err("ERR index out of range".to_string())
+                    } else {
+                        list.set(idx as usize, value);
+                        list.convert(max_size);
+                        Ok(())
+                    }
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Err("ERR no such key".to_string()),
+        }
+    }
+
+    /// Keeps only the elements of the list at `key` from `start` to `stop`
+    /// inclusive, negative indexes counting from the end, and removes the
+    /// key if none are left.
+    pub fn ltrim(&self, key: &str, start: i64, stop: i64) -> Result<(), String> {
+        let mut data = self.data.write().unwrap();
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                let Value::List(list) = Arc::make_mut(&mut entry.value) else {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                };
+                let len = list.len() as i64;
+                let start = if start < 0 {
+                    (len + start).max(0)
+                } else {
+                    start
+                };
+                let stop = if stop < 0 {
+                    len + stop
+                } else {
+                    stop.min(len - 1)
+                };
+                if start > stop {
+                    list.clear();
+                } else {
+                    list.trim(start as usize, stop as usize);
+                }
+            }
+            _ => return Ok(()),
+        }
+        data.remove_if_empty(key);
+        Ok(())
+    }
+
+    pub fn sadd(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::Set(HashSet::new())));
+
+        if let Value::Set(set) = Arc::make_mut(&mut entry.value) {
+            let mut added = 0;
+            for member in members {
+                if set.insert(member) {
+                    added += 1;
+                }
+            }
+            Ok(added)
+        } else {
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+        }
+    }
+
+    pub fn srem(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let removed = match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::Set(set) = Arc::make_mut(&mut entry.value) {
+                    let mut removed = 0;
+                    for member in members {
+                        if set.remove(&member) {
+                            removed += 1;
+                        }
+                    }
+                    Ok(removed)
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(0),
+        };
+        data.remove_if_empty(key);
+        removed
+    }
+
+    pub fn smembers(&self, key: &str) -> Result<Vec<String>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::Set(set) = &*entry.value {
+                    Ok(set.iter().cloned().collect())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    pub fn sismember(&self, key: &str, member: &str) -> Result<bool, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::Set(set) = &*entry.value {
+                    Ok(set.contains(member))
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// `count` random members of the set at `key`, each picked anew when
+    /// `repeat` is set, so the same member may come up more than once, or
+    /// else distinct and as many as the set has at most.
+    pub fn srandmember(
+        &self,
+        key: &str,
+        count: usize,
+        repeat: bool,
+    ) -> Result<Vec<String>, String> {
+        let data = self.data.read().unwrap();
+        let set = match data.get(key) {
+            Some(entry) if !entry.is_expired() => match &*entry.value {
+                Value::Set(set) => set,
+                _ => {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                }
+            },
+            _ => return Ok(vec![]),
+        };
+        if set.is_empty() || count == 0 {
+            return Ok(vec![]);
+        }
+        let random_index = |len: usize| (evict::random_u64() % len as u64) as usize;
+        if repeat {
+            let members: Vec<&String> = set.iter().collect();
+            return Ok((0..count)
+                .map(|_| members[random_index(members.len())].clone())
+                .collect());
+        }
+        if count >= set.len() {
+            return Ok(set.iter().cloned().collect());
+        }
+        // The first `count` steps of a Fisher-Yates shuffle
+        let mut members: Vec<&String> = set.iter().collect();
+        for i in 0..count {
+            let j = i + random_index(members.len() - i);
+            members.swap(i, j);
+        }
+        members.truncate(count);
+        Ok(members.into_iter().cloned().collect())
+    }
+
+    /// The members of the sets at `keys` combined by `op`.
+    pub fn combine_sets(&self, op: SetOp, keys: &[String]) -> Result<Vec<String>, String> {
+        let data = self.data.read().unwrap();
+        combine_sets(&data, op, keys).map(|set| set.into_iter().collect())
+    }
+
+    /// Stores the sets at `keys` combined by `op` in `destination`,
+    /// replacing whatever it held, or removing it if the result is empty.
+    /// Returns the number of members stored.
+    pub fn combine_sets_store(
+        &self,
+        op: SetOp,
+        destination: &str,
+        keys: &[String],
+    ) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let combined = combine_sets(&data, op, keys)?;
+        let len = combined.len();
+        if combined.is_empty() {
+            data.remove(destination);
+        } else {
+            data.insert(destination.to_string(), Entry::new(Value::Set(combined)));
+        }
         Ok(len)
     }
 
-    pub fn strlen(&self, key: &str) -> Result<usize, String> {
+    pub fn scard(&self, key: &str) -> Result<usize, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::String(s) = &entry.value {
-                    Ok(s.len())
+                if let Value::Set(set) = &*entry.value {
+                    Ok(set.len())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -234,108 +1862,82 @@ impl Storage {
         }
     }
 
-    pub fn setnx(&self, key: String, value: String) -> bool {
+    pub fn hset(&self, key: &str, field: String, value: String) -> Result<bool, String> {
+        let (max_entries, max_value) = self.hash_max_listpack();
         let mut data = self.data.write().unwrap();
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::Hash(Hash::new())));
 
-        let exists = data.get(&key).map(|e| !e.is_expired()).unwrap_or(false);
-
-        if !exists {
-            data.insert(key, Entry::new(Value::String(value)));
-            true
+        if let Value::Hash(hash) = Arc::make_mut(&mut entry.value) {
+            let is_new = hash.insert(field, value);
+            hash.convert(max_entries, max_value);
+            Ok(is_new)
         } else {
-            false
-        }
-    }
-
-    pub fn getset(&self, key: String, value: String) -> Option<String> {
-        let mut data = self.data.write().unwrap();
-        let old = data.get(&key).and_then(|e| {
-            if !e.is_expired() {
-                if let Value::String(s) = &e.value {
-                    Some(s.clone())
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        });
-        data.insert(key, Entry::new(Value::String(value)));
-        old
-    }
-
-    pub fn mset(&self, pairs: Vec<(String, String)>) {
-        let mut data = self.data.write().unwrap();
-        for (key, value) in pairs {
-            data.insert(key, Entry::new(Value::String(value)));
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
         }
     }
 
-    pub fn mget(&self, keys: &[String]) -> Vec<Option<String>> {
-        let data = self.data.read().unwrap();
-        keys.iter()
-            .map(|key| {
-                data.get(key).and_then(|e| {
-                    if !e.is_expired() {
-                        if let Value::String(s) = &e.value {
-                            Some(s.clone())
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-            })
-            .collect()
-    }
-
-    pub fn lpush(&self, key: &str, values: Vec<String>) -> Result<usize, String> {
+    pub fn hmset(&self, key: &str, pairs: Vec<(String, String)>) -> Result<(), String> {
+        let (max_entries, max_value) = self.hash_max_listpack();
         let mut data = self.data.write().unwrap();
-        let entry = data
-            .entry(key.to_string())
-            .or_insert_with(|| Entry::new(Value::List(VecDeque::new())));
-
-        if entry.is_expired() {
-            *entry = Entry::new(Value::List(VecDeque::new()));
-        }
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::Hash(Hash::new())));
 
-        if let Value::List(list) = &mut entry.value {
-            for v in values {
-                list.push_front(v);
+        if let Value::Hash(hash) = Arc::make_mut(&mut entry.value) {
+            for (field, value) in pairs {
+                hash.insert(field, value);
+                hash.convert(max_entries, max_value);
             }
-            Ok(list.len())
+            Ok(())
         } else {
             Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
         }
     }
 
-    pub fn rpush(&self, key: &str, values: Vec<String>) -> Result<usize, String> {
-        let mut data = self.data.write().unwrap();
-        let entry = data
-            .entry(key.to_string())
-            .or_insert_with(|| Entry::new(Value::List(VecDeque::new())));
-
-        if entry.is_expired() {
-            *entry = Entry::new(Value::List(VecDeque::new()));
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(hash.get(field).map(str::to_string))
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(None),
         }
+    }
 
-        if let Value::List(list) = &mut entry.value {
-            for v in values {
-                list.push_back(v);
+    pub fn hmget(&self, key: &str, fields: &[String]) -> Result<Vec<Option<String>>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(fields
+                        .iter()
+                        .map(|f| hash.get(f).map(str::to_string))
+                        .collect())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
             }
-            Ok(list.len())
-        } else {
-            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            _ => Ok(fields.iter().map(|_| None).collect()),
         }
     }
 
-    pub fn lpop(&self, key: &str) -> Result<Option<String>, String> {
-        let mut data = self.data.write().unwrap();
-        match data.get_mut(key) {
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::List(list) = &mut entry.value {
-                    Ok(list.pop_front())
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(hash
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -343,16 +1945,22 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(None),
+            _ => Ok(vec![]),
         }
     }
 
-    pub fn rpop(&self, key: &str) -> Result<Option<String>, String> {
+    pub fn hdel(&self, key: &str, fields: Vec<String>) -> Result<usize, String> {
         let mut data = self.data.write().unwrap();
-        match data.get_mut(key) {
+        let removed = match data.get_mut(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::List(list) = &mut entry.value {
-                    Ok(list.pop_back())
+                if let Value::Hash(hash) = Arc::make_mut(&mut entry.value) {
+                    let mut removed = 0;
+                    for field in fields {
+                        if hash.remove(&field) {
+                            removed += 1;
+                        }
+                    }
+                    Ok(removed)
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -360,16 +1968,18 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(None),
-        }
+            _ => Ok(0),
+        };
+        data.remove_if_empty(key);
+        removed
     }
 
-    pub fn llen(&self, key: &str) -> Result<usize, String> {
+    pub fn hexists(&self, key: &str, field: &str) -> Result<bool, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::List(list) = &entry.value {
-                    Ok(list.len())
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(hash.contains_key(field))
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -377,42 +1987,16 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(0),
+            _ => Ok(false),
         }
     }
 
-    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, String> {
+    pub fn hlen(&self, key: &str) -> Result<usize, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::List(list) = &entry.value {
-                    let len = list.len() as i64;
-                    if len == 0 {
-                        return Ok(vec![]);
-                    }
-
-                    let start = if start < 0 {
-                        (len + start).max(0) as usize
-                    } else {
-                        start.min(len) as usize
-                    };
-
-                    let stop = if stop < 0 {
-                        (len + stop).max(0) as usize
-                    } else {
-                        stop.min(len - 1) as usize
-                    };
-
-                    if start > stop || start >= len as usize {
-                        return Ok(vec![]);
-                    }
-
-                    Ok(list
-                        .iter()
-                        .skip(start)
-                        .take(stop - start + 1)
-                        .cloned()
-                        .collect())
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(hash.len())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -420,22 +2004,16 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(vec![]),
+            _ => Ok(0),
         }
     }
 
-    pub fn lindex(&self, key: &str, index: i64) -> Result<Option<String>, String> {
+    pub fn hkeys(&self, key: &str) -> Result<Vec<String>, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::List(list) = &entry.value {
-                    let len = list.len() as i64;
-                    let idx = if index < 0 { len + index } else { index };
-                    if idx < 0 || idx >= len {
-                        Ok(None)
-                    } else {
-                        Ok(list.get(idx as usize).cloned())
-                    }
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(hash.iter().map(|(field, _)| field.to_string()).collect())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -443,23 +2021,16 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(None),
+            _ => Ok(vec![]),
         }
     }
 
-    pub fn lset(&self, key: &str, index: i64, value: String) -> Result<(), String> {
-        let mut data = self.data.write().unwrap();
-        match data.get_mut(key) {
+    pub fn hvals(&self, key: &str) -> Result<Vec<String>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::List(list) = &mut entry.value {
-                    let len = list.len() as i64;
-                    let idx = if index < 0 { len + index } else { index };
-                    if idx < 0 || idx >= len {
-                        Err("ERR index out of range".to_string())
-                    } else {
-                        list[idx as usize] = value;
-                        Ok(())
-                    }
+                if let Value::Hash(hash) = &*entry.value {
+                    Ok(hash.iter().map(|(_, value)| value.to_string()).collect())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -467,45 +2038,107 @@ impl Storage {
                     )
                 }
             }
-            _ => Err("ERR no such key".to_string()),
+            _ => Ok(vec![]),
         }
     }
 
-    pub fn sadd(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
+    pub fn hincrby(&self, key: &str, field: &str, delta: i64) -> Result<i64, String> {
+        let (max_entries, max_value) = self.hash_max_listpack();
         let mut data = self.data.write().unwrap();
-        let entry = data
-            .entry(key.to_string())
-            .or_insert_with(|| Entry::new(Value::Set(HashSet::new())));
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::Hash(Hash::new())));
+
+        if let Value::Hash(hash) = Arc::make_mut(&mut entry.value) {
+            let current = hash
+                .get(field)
+                .map(|v| v.parse::<i64>())
+                .transpose()
+                .map_err(|_| "ERR hash value is not an integer".to_string())?
+                .unwrap_or(0);
+
+            let new_value = current
+                .checked_add(delta)
+                .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
 
-        if entry.is_expired() {
-            *entry = Entry::new(Value::Set(HashSet::new()));
+            hash.insert(field.to_string(), new_value.to_string());
+            hash.convert(max_entries, max_value);
+            Ok(new_value)
+        } else {
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
         }
+    }
 
-        if let Value::Set(set) = &mut entry.value {
-            let mut added = 0;
-            for member in members {
-                if set.insert(member) {
-                    added += 1;
+    /// Adds `members` with their scores, or updates the scores of those
+    /// already there, as `options` allow. Returns the number added, or
+    /// with CH the number added or changed.
+    pub fn zadd(
+        &self,
+        key: &str,
+        members: Vec<(f64, String)>,
+        options: ZAddOptions,
+    ) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        // XX adds nothing, so it mustn't leave an empty sorted set either
+        if options.xx && data.get(key).is_none_or(|entry| entry.is_expired()) {
+            return Ok(0);
+        }
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::ZSet(SortedSet::new())));
+
+        if let Value::ZSet(zset) = Arc::make_mut(&mut entry.value) {
+            let mut changed = 0;
+            for (score, member) in members {
+                let current = zset.score(&member);
+                if !options.allows(current, score) {
+                    continue;
                 }
+                if current.is_none() || (options.ch && current != Some(score)) {
+                    changed += 1;
+                }
+                zset.insert(&member, score);
             }
-            Ok(added)
+            Ok(changed)
         } else {
             Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
         }
     }
 
-    pub fn srem(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
+    /// Adds `delta` to `member`'s score, adding the member with `delta`
+    /// as its score if it isn't there, and returns the new score, or
+    /// `None` if `options` don't allow it.
+    pub fn zincrby(
+        &self,
+        key: &str,
+        member: &str,
+        delta: f64,
+        options: ZAddOptions,
+    ) -> Result<Option<f64>, String> {
         let mut data = self.data.write().unwrap();
-        match data.get_mut(key) {
+        if options.xx && data.get(key).is_none_or(|entry| entry.is_expired()) {
+            return Ok(None);
+        }
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::ZSet(SortedSet::new())));
+
+        if let Value::ZSet(zset) = Arc::make_mut(&mut entry.value) {
+            let current = zset.score(member);
+            let score = current.unwrap_or(0.0) + delta;
+            if score.is_nan() {
+                return Err("ERR resulting score is not a number (NaN)".to_string());
+            }
+            if !options.allows(current, score) {
+                return Ok(None);
+            }
+            zset.insert(member, score);
+            Ok(Some(score))
+        } else {
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+        }
+    }
+
+    pub fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Set(set) = &mut entry.value {
-                    let mut removed = 0;
-                    for member in members {
-                        if set.remove(&member) {
-                            removed += 1;
-                        }
-                    }
-                    Ok(removed)
+                if let Value::ZSet(zset) = &*entry.value {
+                    Ok(zset.score(member))
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -513,16 +2146,17 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(0),
+            _ => Ok(None),
         }
     }
 
-    pub fn smembers(&self, key: &str) -> Result<Vec<String>, String> {
+    /// The scores of `members`, `None` for those not in the sorted set.
+    pub fn zmscore(&self, key: &str, members: &[String]) -> Result<Vec<Option<f64>>, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Set(set) = &entry.value {
-                    Ok(set.iter().cloned().collect())
+                if let Value::ZSet(zset) = &*entry.value {
+                    Ok(members.iter().map(|member| zset.score(member)).collect())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -530,16 +2164,93 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(vec![]),
+            _ => Ok(members.iter().map(|_| None).collect()),
         }
     }
 
-    pub fn sismember(&self, key: &str, member: &str) -> Result<bool, String> {
+    pub fn zrem(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let removed = match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::ZSet(zset) = Arc::make_mut(&mut entry.value) {
+                    Ok(members.iter().filter(|member| zset.remove(member)).count())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(0),
+        };
+        data.remove_if_empty(key);
+        removed
+    }
+
+    pub fn zcard(&self, key: &str) -> Result<usize, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::ZSet(zset) = &*entry.value {
+                    Ok(zset.len())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Pops up to `count` members from `end` of the sorted set at `key`,
+    /// with their scores, the first popped first.
+    pub fn zpop(
+        &self,
+        key: &str,
+        end: ScoreEnd,
+        count: usize,
+    ) -> Result<Vec<(String, f64)>, String> {
+        let mut data = self.data.write().unwrap();
+        let popped = match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::ZSet(zset) = Arc::make_mut(&mut entry.value) {
+                    let count = count.min(zset.len());
+                    Ok(match end {
+                        ScoreEnd::Min => (0..count).filter_map(|_| zset.pop_min()).collect(),
+                        ScoreEnd::Max => (0..count).filter_map(|_| zset.pop_max()).collect(),
+                    })
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(Vec::new()),
+        };
+        data.remove_if_empty(key);
+        popped
+    }
+
+    /// Where `member` ranks in the sorted set at `key`, 0 being the lowest
+    /// score or with `rev` the highest, and its score.
+    pub fn zrank(
+        &self,
+        key: &str,
+        member: &str,
+        rev: bool,
+    ) -> Result<Option<(usize, f64)>, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Set(set) = &entry.value {
-                    Ok(set.contains(member))
+                if let Value::ZSet(zset) = &*entry.value {
+                    let ranked = zset.rank(member).zip(zset.score(member));
+                    Ok(ranked.map(|(rank, score)| {
+                        let rank = if rev { zset.len() - 1 - rank } else { rank };
+                        (rank, score)
+                    }))
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -547,16 +2258,23 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(false),
+            _ => Ok(None),
         }
     }
 
-    pub fn scard(&self, key: &str) -> Result<usize, String> {
+    /// How many members of the sorted set at `key` are within `by`,
+    /// counted from the ranks at its two ends.
+    pub fn zcount(&self, key: &str, by: RangeBy) -> Result<usize, String> {
+        let query = RangeQuery {
+            by,
+            rev: false,
+            limit: None,
+        };
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Set(set) = &entry.value {
-                    Ok(set.len())
+                if let Value::ZSet(zset) = &*entry.value {
+                    Ok(zset.range(&query).len())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -568,51 +2286,109 @@ impl Storage {
         }
     }
 
-    pub fn hset(&self, key: &str, field: String, value: String) -> Result<bool, String> {
+    /// The sorted sets or sets at `keys` combined by `op`, with their
+    /// scores, lowest first.
+    pub fn combine_zsets(
+        &self,
+        op: SetOp,
+        keys: &[String],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> Result<Vec<(String, f64)>, String> {
+        let data = self.data.read().unwrap();
+        let combined = combine_zsets(&data, op, keys, weights, aggregate)?;
+        Ok(combined
+            .iter()
+            .map(|(member, score)| (member.to_string(), score))
+            .collect())
+    }
+
+    /// Stores the sorted sets or sets at `keys` combined by `op` in
+    /// `destination`, replacing whatever it held, or removing it if the
+    /// result is empty. Returns the number of members stored.
+    pub fn combine_zsets_store(
+        &self,
+        op: SetOp,
+        destination: &str,
+        keys: &[String],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> Result<usize, String> {
         let mut data = self.data.write().unwrap();
-        let entry = data
-            .entry(key.to_string())
-            .or_insert_with(|| Entry::new(Value::Hash(HashMap::new())));
-
-        if entry.is_expired() {
-            *entry = Entry::new(Value::Hash(HashMap::new()));
-        }
-
-        if let Value::Hash(hash) = &mut entry.value {
-            let is_new = !hash.contains_key(&field);
-            hash.insert(field, value);
-            Ok(is_new)
+        let combined = combine_zsets(&data, op, keys, weights, aggregate)?;
+        let len = combined.len();
+        if combined.is_empty() {
+            data.remove(destination);
         } else {
-            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            data.insert(destination.to_string(), Entry::new(Value::ZSet(combined)));
         }
+        Ok(len)
     }
 
-    pub fn hmset(&self, key: &str, pairs: Vec<(String, String)>) -> Result<(), String> {
-        let mut data = self.data.write().unwrap();
-        let entry = data
-            .entry(key.to_string())
-            .or_insert_with(|| Entry::new(Value::Hash(HashMap::new())));
-
-        if entry.is_expired() {
-            *entry = Entry::new(Value::Hash(HashMap::new()));
+    /// The members of the sorted set at `key` that `query` picks, with
+    /// their scores, in the order it asks for.
+    pub fn zrange(&self, key: &str, query: &RangeQuery) -> Result<Vec<(String, f64)>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::ZSet(zset) = &*entry.value {
+                    Ok(zset
+                        .range(query)
+                        .map(|(member, score)| (member.to_string(), score))
+                        .collect())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(vec![]),
         }
+    }
 
-        if let Value::Hash(hash) = &mut entry.value {
-            for (field, value) in pairs {
-                hash.insert(field, value);
+    /// Stores the members of the sorted set at `key` that `query` picks in
+    /// `destination`, replacing whatever it held, or removing it if none
+    /// are. Returns the number of members stored.
+    pub fn zrangestore(
+        &self,
+        destination: &str,
+        key: &str,
+        query: &RangeQuery,
+    ) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let mut picked = SortedSet::new();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::ZSet(zset) = &*entry.value {
+                    for (member, score) in zset.range(query) {
+                        picked.insert(member, score);
+                    }
+                } else {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                }
             }
-            Ok(())
+            _ => {}
+        }
+        let len = picked.len();
+        if picked.is_empty() {
+            data.remove(destination);
         } else {
-            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            data.insert(destination.to_string(), Entry::new(Value::ZSet(picked)));
         }
+        Ok(len)
     }
 
-    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>, String> {
+    /// The members of the sorted set at `key` that `search` finds.
+    pub fn geosearch(&self, key: &str, search: &GeoSearch) -> Result<Vec<Found>, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(hash.get(field).cloned())
+                if let Value::ZSet(zset) = &*entry.value {
+                    search.run(zset)
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -620,56 +2396,96 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(None),
+            _ => Ok(Vec::new()),
         }
     }
 
-    pub fn hmget(&self, key: &str, fields: &[String]) -> Result<Vec<Option<String>>, String> {
-        let data = self.data.read().unwrap();
+    /// Stores the members of the sorted set at `key` that `search` finds
+    /// in `destination`, scored by their geohash or, with `store_dist`,
+    /// their distance, replacing whatever it held or removing it if none
+    /// were found. Returns the number stored.
+    pub fn geosearch_store(
+        &self,
+        destination: &str,
+        key: &str,
+        search: &GeoSearch,
+        store_dist: bool,
+    ) -> Result<usize, String> {
+        let mut data = self.data.write().unwrap();
+        let mut found = SortedSet::new();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(fields.iter().map(|f| hash.get(f).cloned()).collect())
+                if let Value::ZSet(zset) = &*entry.value {
+                    for place in search.run(zset)? {
+                        let score = if store_dist {
+                            place.distance
+                        } else {
+                            place.score
+                        };
+                        found.insert(&place.member, score);
+                    }
                 } else {
-                    Err(
+                    return Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
                             .to_string(),
-                    )
+                    );
                 }
             }
-            _ => Ok(fields.iter().map(|_| None).collect()),
+            _ => {}
+        }
+        let len = found.len();
+        if found.is_empty() {
+            data.remove(destination);
+        } else {
+            data.insert(destination.to_string(), Entry::new(Value::ZSet(found)));
         }
+        Ok(len)
     }
 
-    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>, String> {
-        let data = self.data.read().unwrap();
-        match data.get(key) {
+    /// Appends an entry to the stream at `key`, creating the stream unless
+    /// `nomkstream` is set, then trims it if `trim` is given, and returns
+    /// the ID the entry got with the exact trim Stream::trim says it did,
+    /// or `None` if there was no stream to add to.
+    pub fn xadd(
+        &self,
+        key: &str,
+        id: NewId,
+        fields: Fields,
+        nomkstream: bool,
+        trim: Option<Trim>,
+    ) -> Result<Option<(StreamId, Option<Trim>)>, String> {
+        let mut data = self.data.write().unwrap();
+        // The ID is settled first so a rejected one doesn't leave an empty
+        // stream behind
+        let id = match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(hash.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                if let Value::Stream(stream) = &*entry.value {
+                    stream.next_id(id, now_ms())?
                 } else {
-                    Err(
+                    return Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
                             .to_string(),
-                    )
+                    );
                 }
             }
-            _ => Ok(vec![]),
+            _ if nomkstream => return Ok(None),
+            _ => Stream::new().next_id(id, now_ms())?,
+        };
+        let entry = data.get_or_insert_with(key, &mut || Entry::new(Value::Stream(Stream::new())));
+        let mut trimmed = None;
+        if let Value::Stream(stream) = Arc::make_mut(&mut entry.value) {
+            stream.add(id, fields);
+            trimmed = trim.map(|trim| stream.trim(trim).1);
         }
+        Ok(Some((id, trimmed)))
     }
 
-    pub fn hdel(&self, key: &str, fields: Vec<String>) -> Result<usize, String> {
-        let mut data = self.data.write().unwrap();
-        match data.get_mut(key) {
+    pub fn xlen(&self, key: &str) -> Result<usize, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &mut entry.value {
-                    let mut removed = 0;
-                    for field in fields {
-                        if hash.remove(&field).is_some() {
-                            removed += 1;
-                        }
-                    }
-                    Ok(removed)
+                if let Value::Stream(stream) = &*entry.value {
+                    Ok(stream.len())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -681,12 +2497,13 @@ impl Storage {
         }
     }
 
-    pub fn hexists(&self, key: &str, field: &str) -> Result<bool, String> {
+    /// The last ID the stream at `key` gave out, 0-0 if there's no stream.
+    pub fn xlast_id(&self, key: &str) -> Result<StreamId, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(hash.contains_key(field))
+                if let Value::Stream(stream) = &*entry.value {
+                    Ok(stream.last_id())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -694,16 +2511,29 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(false),
+            _ => Ok(StreamId::MIN),
         }
     }
 
-    pub fn hlen(&self, key: &str) -> Result<usize, String> {
+    /// Up to `count` entries of the stream at `key` from `start` to `end`
+    /// inclusive, or from `end` down to `start` with `rev`.
+    pub fn xrange(
+        &self,
+        key: &str,
+        start: StreamId,
+        end: StreamId,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Result<Vec<(StreamId, Fields)>, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(hash.len())
+                if let Value::Stream(stream) = &*entry.value {
+                    Ok(stream
+                        .range(start, end, rev)
+                        .take(count.unwrap_or(usize::MAX))
+                        .map(|(id, fields)| (id, fields.clone()))
+                        .collect())
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -711,16 +2541,21 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(0),
+            _ => Ok(Vec::new()),
         }
     }
 
-    pub fn hkeys(&self, key: &str) -> Result<Vec<String>, String> {
+    /// Applies `f` to the stream at `key`, or returns None if there's none.
+    pub fn with_stream<R>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&Stream) -> R,
+    ) -> Result<Option<R>, String> {
         let data = self.data.read().unwrap();
         match data.get(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(hash.keys().cloned().collect())
+                if let Value::Stream(stream) = &*entry.value {
+                    Ok(Some(f(stream)))
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -728,16 +2563,22 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(vec![]),
+            _ => Ok(None),
         }
     }
 
-    pub fn hvals(&self, key: &str) -> Result<Vec<String>, String> {
-        let data = self.data.read().unwrap();
-        match data.get(key) {
+    /// Applies `f` to the stream at `key` to change it, or returns None if
+    /// there's none.
+    pub fn with_stream_mut<R>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut Stream) -> R,
+    ) -> Result<Option<R>, String> {
+        let mut data = self.data.write().unwrap();
+        match data.get_mut(key) {
             Some(entry) if !entry.is_expired() => {
-                if let Value::Hash(hash) = &entry.value {
-                    Ok(hash.values().cloned().collect())
+                if let Value::Stream(stream) = Arc::make_mut(&mut entry.value) {
+                    Ok(Some(f(stream)))
                 } else {
                     Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -745,80 +2586,170 @@ impl Storage {
                     )
                 }
             }
-            _ => Ok(vec![]),
+            _ => Ok(None),
         }
     }
 
-    pub fn hincrby(&self, key: &str, field: &str, delta: i64) -> Result<i64, String> {
+    /// Reads for `consumer` of group `group` of the stream at `key`, as
+    /// Stream::read_group does, and returns the entries read, without
+    /// fields those gone from the stream, and what changed. None if there's
+    /// no such stream or group.
+    pub fn xreadgroup(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        from: GroupRead,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Result<Option<(GroupEntries, GroupChanges)>, String> {
+        let read = self.with_stream_mut(key, |stream| {
+            let (ids, changes) =
+                stream.read_group(group, consumer, from, count, noack, now_ms())?;
+            let entries = ids
+                .into_iter()
+                .map(|id| (id, stream.get(id).cloned()))
+                .collect();
+            Some((entries, changes))
+        })?;
+        Ok(read.flatten())
+    }
+
+    /// Creates consumer group `group` of the stream at `key`, delivered up
+    /// to `id` or, if None, the stream's last ID, and returns whether it's
+    /// new, or None if there's no stream and `mkstream` isn't set to
+    /// create an empty one.
+    pub fn xgroup_create(
+        &self,
+        key: &str,
+        group: &str,
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+        mkstream: bool,
+    ) -> Result<Option<bool>, String> {
         let mut data = self.data.write().unwrap();
-        let entry = data
-            .entry(key.to_string())
-            .or_insert_with(|| Entry::new(Value::Hash(HashMap::new())));
-
-        if entry.is_expired() {
-            *entry = Entry::new(Value::Hash(HashMap::new()));
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {}
+            _ if mkstream => {
+                data.insert(key.to_string(), Entry::new(Value::Stream(Stream::new())));
+            }
+            _ => return Ok(None),
         }
+        let entry = data.get_mut(key).expect("the stream was just looked up");
+        if let Value::Stream(stream) = Arc::make_mut(&mut entry.value) {
+            let id = id.unwrap_or(stream.last_id());
+            Ok(Some(stream.create_group(group, id, entries_read)))
+        } else {
+            Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+        }
+    }
 
-        if let Value::Hash(hash) = &mut entry.value {
-            let current = hash
-                .get(field)
-                .map(|v| v.parse::<i64>())
-                .transpose()
-                .map_err(|_| "ERR hash value is not an integer".to_string())?
-                .unwrap_or(0);
-
-            let new_value = current
-                .checked_add(delta)
-                .ok_or_else(|| "ERR increment or decrement would overflow".to_string())?;
+    /// Creates an empty bloom filter at `key` for `capacity` items with
+    /// `error_rate` false positives, growing by `expansion` or else by
+    /// `bf-expansion-factor` unless `scaling` is false, and returns
+    /// whether it did, false if the key holds anything already.
+    pub fn bf_reserve(
+        &self,
+        key: &str,
+        capacity: u64,
+        error_rate: f64,
+        expansion: Option<u32>,
+        scaling: bool,
+    ) -> Result<bool, String> {
+        let mut data = self.data.write().unwrap();
+        if data.get(key).is_some_and(|entry| !entry.is_expired()) {
+            return Ok(false);
+        }
+        let expansion = expansion.unwrap_or(self.bf_expansion_factor.load(Ordering::Relaxed));
+        let bloom = Bloom::new(capacity, error_rate, scaling.then_some(expansion))
+            .ok_or("ERR could not create filter")?;
+        data.insert(key.to_string(), Entry::new(Value::Bloom(bloom)));
+        Ok(true)
+    }
 
-            hash.insert(field.to_string(), new_value.to_string());
-            Ok(new_value)
+    /// Adds `items` to the bloom filter at `key`, created as the `bf-*`
+    /// parameters say if there's none, and returns whether each was new, or
+    /// the error Bloom::add gave for it.
+    pub fn bf_add(&self, key: &str, items: &[String]) -> Result<Vec<Result<bool, String>>, String> {
+        let mut data = self.data.write().unwrap();
+        if data.get(key).is_none_or(|entry| entry.is_expired()) {
+            let bloom = Bloom::new(
+                self.bf_initial_size.load(Ordering::Relaxed),
+                f64::from_bits(self.bf_error_rate.load(Ordering::Relaxed)),
+                Some(self.bf_expansion_factor.load(Ordering::Relaxed)),
+            )
+            .ok_or("ERR could not create filter")?;
+            data.insert(key.to_string(), Entry::new(Value::Bloom(bloom)));
+        }
+        let entry = data.get_mut(key).expect("the filter was just looked up");
+        if let Value::Bloom(bloom) = Arc::make_mut(&mut entry.value) {
+            Ok(items.iter().map(|item| bloom.add(item)).collect())
         } else {
             Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
         }
     }
 
-    pub fn keys(&self, pattern: &str) -> Vec<String> {
+    /// Whether each of `items` may have been added to the bloom filter at
+    /// `key`, none if there's no filter.
+    pub fn bf_exists(&self, key: &str, items: &[String]) -> Result<Vec<bool>, String> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                if let Value::Bloom(bloom) = &*entry.value {
+                    Ok(items.iter().map(|item| bloom.contains(item)).collect())
+                } else {
+                    Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    )
+                }
+            }
+            _ => Ok(vec![false; items.len()]),
+        }
+    }
+
+    pub fn keys(&self, pattern: &Pattern) -> Vec<String> {
         let data = self.data.read().unwrap();
         data.iter()
             .filter(|(_, entry)| !entry.is_expired())
-            .filter(|(key, _)| Self::glob_match(pattern, key))
+            .filter(|(key, _)| pattern.matches(key))
             .map(|(key, _)| key.clone())
             .collect()
     }
 
-    fn glob_match(pattern: &str, text: &str) -> bool {
-        if pattern == "*" {
-            return true;
-        }
-
-        let pattern_chars: Vec<_> = pattern.chars().collect();
-        let text_chars: Vec<_> = text.chars().collect();
-
-        Self::glob_match_recursive(&pattern_chars, &text_chars)
-    }
-
-    fn glob_match_recursive(pattern: &[char], text: &[char]) -> bool {
-        if pattern.is_empty() {
-            return text.is_empty();
-        }
-
-        match pattern[0] {
-            '*' => {
-                for i in 0..=text.len() {
-                    if Self::glob_match_recursive(&pattern[1..], &text[i..]) {
-                        return true;
-                    }
-                }
-                false
-            }
-            '?' => !text.is_empty() && Self::glob_match_recursive(&pattern[1..], &text[1..]),
-            c => {
-                !text.is_empty()
-                    && text[0] == c
-                    && Self::glob_match_recursive(&pattern[1..], &text[1..])
-            }
-        }
+    /// One step of SCAN: visits up to `count` positions of the engine
+    /// below `cursor` (0 starting from the top) and returns the live keys
+    /// there matching `pattern` and, if given, `type_name`, with the cursor
+    /// to continue from, 0 once every position was visited.
+    ///
+    /// Positions are walked downwards because the engine appends new keys
+    /// and fills a removed key's place with its last one: a key present for
+    /// the whole scan can only move to a position not yet visited, or be
+    /// moved from a visited one and returned twice, never be skipped.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: &Pattern,
+        type_name: Option<&str>,
+    ) -> (u64, Vec<String>) {
+        let data = self.data.read().unwrap();
+        let top = match cursor {
+            0 => data.len(),
+            cursor => usize::try_from(cursor)
+                .unwrap_or(usize::MAX)
+                .min(data.len()),
+        };
+        let bottom = top.saturating_sub(count.max(1));
+        let keys = (bottom..top)
+            .rev()
+            .filter_map(|index| data.get_index(index))
+            .filter(|(_, entry)| !entry.is_expired())
+            .filter(|(_, entry)| type_name.is_none_or(|name| entry.value.type_name() == name))
+            .filter(|(key, _)| pattern.matches(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        (bottom as u64, keys)
     }
 
     pub fn rename(&self, old_key: &str, new_key: &str) -> Result<(), String> {
@@ -849,22 +2780,181 @@ impl Storage {
         }
     }
 
+    /// Stores a value under `key`, replacing any existing one, optionally
+    /// expiring at a Unix time in milliseconds. An empty list, set or hash
+    /// removes the key instead.
+    pub fn restore(&self, key: String, mut value: Value, expires_at_ms: Option<u64>) {
+        match &mut value {
+            Value::List(list) => list.pack(self.list_max_listpack_size()),
+            Value::Hash(hash) => {
+                let (max_entries, max_value) = self.hash_max_listpack();
+                hash.pack(max_entries, max_value);
+            }
+            _ => {}
+        }
+        let entry = Entry {
+            expires_at: expires_at_ms,
+            ..Entry::new(value)
+        };
+        let mut data = self.data.write().unwrap();
+        if entry.value.is_empty_collection() {
+            data.remove(&key);
+        } else {
+            data.insert(key, entry);
+        }
+    }
+
+    /// Calls `f` with every live key, its value and whether it has an expiry.
+    pub fn for_each(&self, mut f: impl FnMut(&str, &Value, bool)) {
+        let data = self.data.read().unwrap();
+        for (key, entry) in data.iter().filter(|(_, e)| !e.is_expired()) {
+            f(key, &entry.value, entry.expires_at.is_some());
+        }
+    }
+
+    /// The Unix time in milliseconds a live key expires at, if it does.
+    pub fn expire_time(&self, key: &str) -> Option<u64> {
+        let data = self.data.read().unwrap();
+        data.get(key)
+            .filter(|entry| !entry.is_expired())
+            .and_then(|entry| entry.expires_at)
+    }
+
+    /// Applies `f` to a live key's value and whether it has an expiry.
+    pub fn with_value<R>(&self, key: &str, f: impl FnOnce(&Value, bool) -> R) -> Option<R> {
+        let data = self.data.read().unwrap();
+        match data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(f(&entry.value, entry.expires_at.is_some())),
+            _ => None,
+        }
+    }
+
     pub fn dbsize(&self) -> usize {
         let data = self.data.read().unwrap();
         data.iter().filter(|(_, e)| !e.is_expired()).count()
     }
 
+    /// Number of live keys with an expiration set.
+    pub fn expires_count(&self) -> usize {
+        let data = self.data.read().unwrap();
+        let now = now_ms();
+        data.expires.iter().filter(|(at, _)| *at > now).count()
+    }
+
     pub fn flushdb(&self) {
         let mut data = self.data.write().unwrap();
         data.clear();
     }
 
-    pub fn run_expiry_cleanup(&self) {
-        self.cleanup_expired();
+    /// Removes up to `count` expired keys, earliest deadline first,
+    /// returning them.
+    pub fn remove_expired_batch(&self, count: usize) -> Vec<String> {
+        let mut data = self.data.write().unwrap();
+        data.pop_expired(now_ms(), count)
+    }
+
+    /// The nearest expiration deadline of a key in this database.
+    pub fn next_expiry(&self) -> Option<u64> {
+        let data = self.data.read().unwrap();
+        data.expires.first().map(|(at, _)| *at)
+    }
+
+    /// Records that a command accessed `key`, for the LRU and LFU eviction
+    /// policies. See `Entry::touch` for `lfu`.
+    pub fn record_access(&self, key: &str, lfu: Option<(u64, u64)>) {
+        let data = self.data.read().unwrap();
+        if let Some(entry) = data.get(key) {
+            entry.touch(lfu);
+        }
+    }
+
+    /// Bytes `key` and its value take, counting the entry, the shared value
+    /// and `Value::memory_usage` with `samples`, or None if there's no such
+    /// key.
+    pub fn memory_usage(&self, key: &str, samples: usize) -> Option<usize> {
+        let data = self.data.read().unwrap();
+        let entry = data.get(key).filter(|entry| !entry.is_expired())?;
+        // The Arc allocation holds two reference counts next to the value
+        let overhead = size_of::<String>() + size_of::<Entry>() + 2 * size_of::<usize>();
+        Some(overhead + key.len() + size_of::<Value>() + entry.value.memory_usage(samples))
+    }
+
+    /// Compacts up to `count` values from position `start` of the engine
+    /// on, skipping values shared with a snapshot. Returns where the next
+    /// call should start, None once the end is reached, and how many values
+    /// were and weren't compacted.
+    pub fn compact(&self, start: usize, count: usize) -> (Option<usize>, usize, usize) {
+        let mut data = self.data.write().unwrap();
+        let end = start.saturating_add(count).min(data.len());
+        let (mut hits, mut misses) = (0, 0);
+        for index in start..end {
+            let Some(key) = data.get_index(index).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            let compacted = data
+                .get_mut(&key)
+                .and_then(|entry| Arc::get_mut(&mut entry.value))
+                .is_some_and(|value| value.compact());
+            if compacted {
+                hits += 1;
+            } else {
+                misses += 1;
+            }
+        }
+        let next = (end < data.len()).then_some(end);
+        (next, hits, misses)
+    }
+
+    /// Backdates the last access of `key` by `idle` seconds and sets its
+    /// LFU counter to `freq`, as RESTORE's IDLETIME and FREQ do.
+    pub fn set_access(&self, key: &str, idle: Option<u64>, freq: Option<u8>) {
+        let data = self.data.read().unwrap();
+        let Some(entry) = data.get(key) else {
+            return;
+        };
+        if let Some(idle) = idle {
+            let access = evict::lru_clock().saturating_sub(idle.saturating_mul(1000));
+            entry.access.store(access, Ordering::Relaxed);
+        }
+        if let Some(freq) = freq {
+            entry.lfu.store(evict::lfu_with(freq), Ordering::Relaxed);
+        }
+    }
+
+    /// Up to `count` randomly picked keys, with expirations only when
+    /// `volatile`, each with its `score`. The same key may come up twice.
+    pub fn sample(
+        &self,
+        count: usize,
+        volatile: bool,
+        score: impl Fn(&Entry) -> u64,
+    ) -> Vec<(String, u64)> {
+        let data = self.data.read().unwrap();
+        let len = data.len();
+        let mut sampled = Vec::new();
+        if len == 0 || volatile && data.expires.is_empty() {
+            return sampled;
+        }
+        // Without an index of volatile keys they're found by trial, so
+        // give up after a few misses per key wanted
+        let draws = if volatile { count * 10 } else { count };
+        for _ in 0..draws {
+            if sampled.len() == count {
+                break;
+            }
+            let index = (evict::random_u64() % len as u64) as usize;
+            let Some((key, entry)) = data.get_index(index) else {
+                continue;
+            };
+            if !volatile || entry.expires_at.is_some() {
+                sampled.push((key.clone(), score(entry)));
+            }
+        }
+        sampled
     }
 }
 
-impl Default for Storage {
+impl Default for Db {
     fn default() -> Self {
         Self::new()
     }
@@ -873,17 +2963,18 @@ impl Default for Storage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::VecDeque;
 
     #[test]
     fn test_set_get() {
-        let storage = Storage::new();
+        let storage = Db::new();
         storage.set("key".to_string(), "value".to_string());
         assert_eq!(storage.get("key"), Some("value".to_string()));
     }
 
     #[test]
     fn test_del() {
-        let storage = Storage::new();
+        let storage = Db::new();
         storage.set("key".to_string(), "value".to_string());
         assert_eq!(storage.del(&["key".to_string()]), 1);
         assert_eq!(storage.get("key"), None);
@@ -891,7 +2982,7 @@ mod tests {
 
     #[test]
     fn test_incr() {
-        let storage = Storage::new();
+        let storage = Db::new();
         storage.set("counter".to_string(), "10".to_string());
         assert_eq!(storage.incr("counter"), Ok(11));
         assert_eq!(storage.incr("counter"), Ok(12));
@@ -899,7 +2990,7 @@ mod tests {
 
     #[test]
     fn test_list_operations() {
-        let storage = Storage::new();
+        let storage = Db::new();
         assert_eq!(
             storage.rpush("list", vec!["a".to_string(), "b".to_string()]),
             Ok(2)
@@ -909,22 +3000,101 @@ mod tests {
             storage.lrange("list", 0, -1),
             Ok(vec!["c".to_string(), "a".to_string(), "b".to_string()])
         );
+
+        // Popping the last element removes the list
+        assert_eq!(storage.lpop("list"), Ok(Some("c".to_string())));
+        assert_eq!(storage.pop("list", End::Right, 5).unwrap().len(), 2);
+        assert_eq!(storage.get_type("list"), None);
+        assert_eq!(storage.exists(&["list".to_string()]), 0);
+        assert_eq!(storage.rpop("list"), Ok(None));
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let storage = Db::new();
+        let items = |n: usize| (0..n).map(|i| i.to_string()).collect::<Vec<_>>();
+        let trimmed = |start, stop| {
+            storage.del(&["list".to_string()]);
+            storage.rpush("list", items(5)).unwrap();
+            storage.ltrim("list", start, stop).unwrap();
+            storage.lrange("list", 0, -1).unwrap()
+        };
+        assert_eq!(trimmed(0, 2), items(3));
+        assert_eq!(trimmed(-2, -1), vec!["3", "4"]);
+        assert_eq!(trimmed(-100, 100), items(5));
+        assert_eq!(trimmed(1, -2), vec!["1", "2", "3"]);
+        assert!(trimmed(3, 1).is_empty());
+        assert!(trimmed(5, 10).is_empty());
+        assert!(trimmed(0, -6).is_empty());
+
+        // A list trimmed to nothing is removed
+        assert_eq!(storage.get_type("list"), None);
+        assert_eq!(storage.ltrim("missing", 0, 1), Ok(()));
+        storage.set("string".to_string(), "v".to_string());
+        assert!(storage.ltrim("string", 0, 1).is_err());
     }
 
     #[test]
     fn test_set_operations() {
-        let storage = Storage::new();
+        let storage = Db::new();
         assert_eq!(
             storage.sadd("myset", vec!["a".to_string(), "b".to_string()]),
             Ok(2)
         );
         assert_eq!(storage.sadd("myset", vec!["a".to_string()]), Ok(0));
         assert_eq!(storage.scard("myset"), Ok(2));
+
+        let mut picked = storage.srandmember("myset", 5, false).unwrap();
+        picked.sort();
+        assert_eq!(picked, ["a", "b"]);
+        assert_eq!(storage.srandmember("myset", 1, false).unwrap().len(), 1);
+        let repeated = storage.srandmember("myset", 50, true).unwrap();
+        assert_eq!(repeated.len(), 50);
+        assert!(repeated.iter().all(|m| m == "a" || m == "b"));
+        assert_eq!(storage.srandmember("missing", 5, true), Ok(vec![]));
+
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        let combined = |op, names: &[&str]| {
+            let mut members = storage.combine_sets(op, &keys(names)).unwrap();
+            members.sort();
+            members
+        };
+        storage.sadd("other", keys(&["b", "c"])).unwrap();
+        assert_eq!(combined(SetOp::Inter, &["myset", "other"]), ["b"]);
+        assert_eq!(combined(SetOp::Union, &["myset", "other"]), ["a", "b", "c"]);
+        assert_eq!(combined(SetOp::Diff, &["myset", "other"]), ["a"]);
+        assert_eq!(combined(SetOp::Diff, &["myset", "missing"]), ["a", "b"]);
+        assert!(combined(SetOp::Inter, &["myset", "missing"]).is_empty());
+        assert!(combined(SetOp::Diff, &["missing", "myset"]).is_empty());
+
+        // Stored over any type, and deleted when empty
+        storage.set("dest".to_string(), "string".to_string());
+        assert_eq!(
+            storage.combine_sets_store(SetOp::Union, "dest", &keys(&["myset", "other"])),
+            Ok(3)
+        );
+        assert_eq!(storage.scard("dest"), Ok(3));
+        assert_eq!(
+            storage.combine_sets_store(SetOp::Inter, "dest", &keys(&["dest", "missing"])),
+            Ok(0)
+        );
+        assert_eq!(storage.get_type("dest"), None);
+        storage.set("s".to_string(), "v".to_string());
+        assert!(storage.combine_sets(SetOp::Diff, &keys(&["missing", "s"])).is_err());
+
+        // Removing the last member removes the set
+        assert_eq!(storage.srem("myset", vec!["a".to_string()]), Ok(1));
+        assert_eq!(storage.get_type("myset"), Some("set"));
+        assert_eq!(
+            storage.srem("myset", vec!["b".to_string(), "c".to_string()]),
+            Ok(1)
+        );
+        assert_eq!(storage.get_type("myset"), None);
     }
 
     #[test]
     fn test_hash_operations() {
-        let storage = Storage::new();
+        let storage = Db::new();
         assert_eq!(
             storage.hset("hash", "field1".to_string(), "value1".to_string()),
             Ok(true)
@@ -934,16 +3104,199 @@ mod tests {
             Ok(Some("value1".to_string()))
         );
         assert_eq!(storage.hlen("hash"), Ok(1));
+
+        // Deleting the last field removes the hash
+        assert_eq!(storage.hdel("hash", vec!["field1".to_string()]), Ok(1));
+        assert_eq!(storage.get_type("hash"), None);
+        storage.restore("hash".to_string(), Value::Hash(Hash::new()), None);
+        assert_eq!(storage.dbsize(), 0);
+    }
+
+    #[test]
+    fn test_databases_are_isolated_and_swappable() {
+        let storage = Storage::with_databases(4);
+        storage.db(0).set("key".to_string(), "zero".to_string());
+        storage.db(3).set("other".to_string(), "three".to_string());
+        assert_eq!(storage.db(1).get("key"), None);
+
+        storage.swap_db(3, 0);
+        assert_eq!(storage.db(3).get("key"), Some("zero".to_string()));
+        assert_eq!(storage.db(0).get("other"), Some("three".to_string()));
+
+        storage.flushall();
+        assert_eq!(storage.db(0).dbsize() + storage.db(3).dbsize(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_is_copy_on_write() {
+        let storage = Storage::with_databases(2);
+        let db = storage.db(1);
+        db.rpush("list", vec!["a".to_string()]).unwrap();
+        db.set("untouched".to_string(), "v".to_string());
+        db.expire("untouched", 60_000);
+
+        let snapshot = storage.snapshot();
+        db.rpush("list", vec!["b".to_string()]).unwrap();
+        db.del(&["untouched".to_string()]);
+
+        let mut keys = snapshot[1].clone();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(matches!(&*keys[0].1, Value::List(list) if list.len() == 1));
+        assert!(keys[1].2.is_some());
+        assert_eq!(db.llen("list"), Ok(2));
+
+        // Values nobody writes to are shared, not copied
+        db.set("big".to_string(), "x".repeat(1000));
+        let snapshot = storage.snapshot();
+        let (_, big, _) = snapshot[1].iter().find(|(key, _, _)| key == "big").unwrap();
+        assert_eq!(Arc::strong_count(big), 2);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let db = Db::new();
+        assert_eq!(db.memory_usage("missing", 5), None);
+        db.set("a".to_string(), "v".to_string());
+        db.set("b".to_string(), "v".repeat(10_000));
+        let short = db.memory_usage("a", 5).unwrap();
+        let long = db.memory_usage("b", 5).unwrap();
+        assert_eq!(long - short, 9_999);
+
+        // A listpack is its buffer, however many elements are sampled
+        db.rpush("small", vec!["x".repeat(100); 10]).unwrap();
+        let packed = db.memory_usage("small", 5).unwrap();
+        assert_eq!(db.memory_usage("small", 0), Some(packed));
+        assert!((1027..2048).contains(&packed));
+
+        db.list_max_listpack_size.store(0, Ordering::Relaxed);
+        let items = (0..100).map(|i| if i < 5 { "x".repeat(1000) } else { "x".to_string() });
+        db.rpush("list", items.collect()).unwrap();
+        // Extrapolated from the five long ones, or exact
+        let sampled = db.memory_usage("list", 5).unwrap();
+        let exact = db.memory_usage("list", 0).unwrap();
+        assert!(sampled >= 100_000);
+        assert!(exact < 10_000);
+    }
+
+    #[test]
+    fn test_compact() {
+        let db = Db::new();
+        let mut oversized = String::with_capacity(4096);
+        oversized.push_str("value");
+        db.restore("a".to_string(), Value::String(oversized), None);
+        db.set("b".to_string(), "value".to_string());
+        // Kept a deque, with capacity to give back
+        db.list_max_listpack_size.store(0, Ordering::Relaxed);
+        let mut list = VecDeque::with_capacity(1024);
+        list.push_back("item".to_string());
+        db.restore("list".to_string(), Value::List(list.into()), None);
+        let before = db.memory_usage("a", 0).unwrap();
+
+        let (next, hits, misses) = db.compact(0, 2);
+        assert_eq!(next, Some(2));
+        let (next, more_hits, more_misses) = db.compact(2, 2);
+        assert_eq!(next, None);
+        assert_eq!((hits + more_hits, misses + more_misses), (2, 1));
+        assert!(db.memory_usage("a", 0).unwrap() < before - 4000);
+        assert_eq!(db.get("a"), Some("value".to_string()));
+        assert_eq!(db.lrange("list", 0, -1).unwrap(), ["item"]);
+    }
+
+    #[test]
+    fn test_expirations_are_absolute() {
+        let storage = Storage::with_databases(1);
+        let db = storage.db(0);
+        let at = now_ms() + 60_000;
+        db.restore("k".to_string(), Value::String("v".to_string()), Some(at));
+        assert_eq!(db.expire_time("k"), Some(at));
+        // Saved and restored exactly, however long it takes
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(storage.snapshot()[0][0].2, Some(at));
+        assert!(db.expire_at("k", at + 1, ExpireOptions::default()));
+        assert_eq!(db.expire_time("k"), Some(at + 1));
+        assert!((59_990..=60_001).contains(&db.ttl("k")));
+
+        // A deadline in the past leaves the key expired
+        assert!(db.expire_at("k", now_ms() - 1, ExpireOptions::default()));
+        assert_eq!(db.get("k"), None);
+        assert_eq!(db.expire_time("k"), None);
+    }
+
+    #[test]
+    fn test_scan_returns_every_remaining_key() {
+        let db = Db::new();
+        for i in 0..100 {
+            db.set(format!("key:{}", i), "v".to_string());
+        }
+
+        // Delete keys behind and ahead of the cursor as the scan goes
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut deleted = 0;
+        loop {
+            let (next, keys) = db.scan(cursor, 7, &Pattern::new("*"), None);
+            seen.extend(keys);
+            for _ in 0..3 {
+                db.del(&[format!("key:{}", deleted)]);
+                deleted += 1;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in deleted..100 {
+            assert!(seen.contains(&format!("key:{}", i)), "key:{} skipped", i);
+        }
+
+        // New keys don't stop the scan from ending
+        let (next, keys) = db.scan(0, 1000, &Pattern::new("key:9?"), Some("string"));
+        assert_eq!(next, 0);
+        assert_eq!(keys.len(), 10);
+        assert_eq!(db.scan(0, 1000, &Pattern::new("*"), Some("hash")).1.len(), 0);
     }
 
     #[test]
-    fn test_glob_match() {
-        assert!(Storage::glob_match("*", "anything"));
-        assert!(Storage::glob_match("user:*", "user:123"));
-        assert!(Storage::glob_match("user:*:name", "user:123:name"));
-        assert!(!Storage::glob_match("user:*:name", "user:123:age"));
-        assert!(Storage::glob_match("h?llo", "hello"));
-        assert!(Storage::glob_match("h?llo", "hallo"));
-        assert!(!Storage::glob_match("h?llo", "hllo"));
+    fn test_expiration_index() {
+        let storage = Storage::with_databases(1);
+        let db = storage.db(0);
+        for i in 0..50 {
+            db.set_with_expiry(format!("stale:{}", i), "v".to_string(), 20);
+        }
+        db.set_with_expiry("live".to_string(), "v".to_string(), 60_000);
+        db.set_with_expiry("persisted".to_string(), "v".to_string(), 20);
+        assert!(db.persist("persisted"));
+        db.set_with_expiry("overwritten".to_string(), "v".to_string(), 20);
+        db.set("overwritten".to_string(), "v".to_string());
+        db.set_with_expiry("deleted".to_string(), "v".to_string(), 20);
+        db.del(&["deleted".to_string()]);
+        db.set("extended".to_string(), "v".to_string());
+        db.expire("extended", 20);
+        db.expire("extended", 60_000);
+        assert_eq!(db.expires_count(), 52);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(storage.next_expiry().unwrap() <= now_ms());
+
+        // Pushing to an expired key replaces it, expiration included
+        db.rpush("stale:0", vec!["a".to_string()]).unwrap();
+
+        let mut expired = storage.run_expiry_cleanup(Duration::from_secs(10));
+        expired.sort();
+        assert_eq!(expired.len(), 49);
+        assert!(expired.iter().all(|(index, key)| *index == 0 && key.starts_with("stale:")));
+        assert_eq!(db.dbsize(), 5);
+        assert_eq!(db.expires_count(), 2);
+        assert!(storage.next_expiry().unwrap() > now_ms());
+        assert!(storage.run_expiry_cleanup(Duration::from_secs(10)).is_empty());
+
+        // Out of budget after the first batch
+        for i in 0..50 {
+            db.set_with_expiry(format!("stale:{}", i), "v".to_string(), 20);
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        let expired = storage.run_expiry_cleanup(Duration::ZERO);
+        assert_eq!(expired.len(), EXPIRE_KEYS_PER_LOOP);
+        db.flushdb();
+        assert_eq!(storage.next_expiry(), None);
     }
 }
@@ -0,0 +1,953 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Bound;
+
+/// A stream entry's fields and their values, in the order they were added.
+pub type Fields = Vec<(String, String)>;
+
+/// Entries written to each listpack node of a stream, Redis' default
+/// `stream-node-max-entries`. Approximate trimming removes whole nodes.
+pub const NODE_ENTRIES: usize = 100;
+
+/// Entries read by a consumer of a group, without fields those gone from
+/// the stream since they were delivered.
+pub type GroupEntries = Vec<(StreamId, Option<Fields>)>;
+
+/// A stream entry ID: the Unix time in milliseconds it was added at, and
+/// a sequence number telling apart the entries added in the same
+/// millisecond. IDs order by both, as `ms-seq`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    /// Parses `ms-seq`, or `ms` alone with `missing_seq` as its sequence
+    /// number.
+    pub fn parse(s: &str, missing_seq: u64) -> Option<StreamId> {
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, parse_u64(seq)?),
+            None => (s, missing_seq),
+        };
+        Some(StreamId::new(parse_u64(ms)?, seq))
+    }
+
+    /// The ID right after this one, or None after the last.
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_add(1).map(|ms| StreamId::new(ms, 0)),
+        }
+    }
+
+    /// The ID right before this one, or None before the first.
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_sub(1).map(|ms| StreamId::new(ms, u64::MAX)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Parses an unsigned number written only in digits, as Redis' string2ull
+/// takes it.
+fn parse_u64(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// The ID XADD is given: `*` for one made from the clock, `ms-*` for the
+/// next sequence number in a millisecond, or an ID to use as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewId {
+    Auto,
+    Ms(u64),
+    Explicit(StreamId),
+}
+
+impl NewId {
+    pub fn parse(s: &str) -> Option<NewId> {
+        if s == "*" {
+            return Some(NewId::Auto);
+        }
+        match s.strip_suffix("-*") {
+            Some(ms) => parse_u64(ms).map(NewId::Ms),
+            None => StreamId::parse(s, 0).map(NewId::Explicit),
+        }
+    }
+}
+
+/// An entry delivered to a consumer of a group and not yet acknowledged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub consumer: String,
+    /// The Unix time in milliseconds it was last delivered at.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+/// A consumer of a group.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Consumer {
+    /// The Unix time in milliseconds it last read or claimed at.
+    pub seen_time: u64,
+    /// The Unix time in milliseconds it was last given entries at, if ever.
+    pub active_time: Option<u64>,
+    /// The IDs of the entries pending for it.
+    pub pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    fn new(now: u64) -> Self {
+        Consumer {
+            seen_time: now,
+            ..Consumer::default()
+        }
+    }
+}
+
+/// A consumer group: the last entry delivered to it, how many entries
+/// came up to that one if known, and the entries its consumers were given
+/// and haven't acknowledged, each listed both in the group's pending
+/// entries and in its consumer's.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Group {
+    last_id: StreamId,
+    entries_read: Option<u64>,
+    pending: BTreeMap<StreamId, Pending>,
+    consumers: BTreeMap<String, Consumer>,
+}
+
+impl Group {
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> Self {
+        Group {
+            last_id,
+            entries_read,
+            ..Group::default()
+        }
+    }
+
+    /// A group loaded with its pending entries and consumers, which must
+    /// agree on who each entry is pending for.
+    pub fn from_parts(
+        last_id: StreamId,
+        entries_read: Option<u64>,
+        pending: BTreeMap<StreamId, Pending>,
+        consumers: BTreeMap<String, Consumer>,
+    ) -> Self {
+        Group {
+            last_id,
+            entries_read,
+            pending,
+            consumers,
+        }
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn entries_read(&self) -> Option<u64> {
+        self.entries_read
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, Pending> {
+        &self.pending
+    }
+
+    pub fn consumers(&self) -> &BTreeMap<String, Consumer> {
+        &self.consumers
+    }
+
+    /// The consumer called `name`, created if it's new, seen at `now`.
+    fn seen(&mut self, name: &str, now: u64, changes: &mut GroupChanges) -> &mut Consumer {
+        if !self.consumers.contains_key(name) {
+            self.consumers.insert(name.to_string(), Consumer::new(now));
+            changes.created_consumer = true;
+        }
+        let consumer = self
+            .consumers
+            .get_mut(name)
+            .expect("the consumer was just added");
+        consumer.seen_time = now;
+        consumer
+    }
+
+    /// Makes entry `id` pending for `consumer`, delivered at `time` for the
+    /// `count`th time, taking it from whoever it was pending for.
+    fn assign(
+        &mut self,
+        id: StreamId,
+        consumer: &str,
+        time: u64,
+        count: u64,
+        changes: &mut GroupChanges,
+    ) {
+        let pending = Pending {
+            consumer: consumer.to_string(),
+            delivery_time: time,
+            delivery_count: count,
+        };
+        if let Some(previous) = self.pending.insert(id, pending.clone())
+            && let Some(owner) = self.consumers.get_mut(&previous.consumer)
+        {
+            owner.pending.remove(&id);
+        }
+        self.consumers
+            .get_mut(consumer)
+            .expect("consumers are seen before they're given entries")
+            .pending
+            .insert(id);
+        changes.claimed.push((id, pending));
+    }
+
+    /// Drops entry `id` from the pending entries, true if it was there.
+    fn drop_pending(&mut self, id: StreamId) -> bool {
+        let Some(pending) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(owner) = self.consumers.get_mut(&pending.consumer) {
+            owner.pending.remove(&id);
+        }
+        true
+    }
+}
+
+/// Where XREADGROUP reads from: the entries never delivered to the group,
+/// or those pending for the consumer after an ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupRead {
+    New,
+    Pending(StreamId),
+}
+
+/// What a consumer group command changed besides what it replies, for it
+/// to be propagated as the commands making the same changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupChanges {
+    pub created_consumer: bool,
+    /// The entries delivered or claimed, as they're pending now.
+    pub claimed: Vec<(StreamId, Pending)>,
+    /// The entries dropped from the pending ones, gone from the stream.
+    pub dropped: Vec<StreamId>,
+    /// The group's last delivered ID and entries read, if they moved.
+    pub last_id: Option<(StreamId, Option<u64>)>,
+}
+
+/// What a stream is trimmed down to: a number of entries, or the entries
+/// from an ID on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimTo {
+    MaxLen(u64),
+    MinId(StreamId),
+}
+
+/// How XTRIM and XADD trim a stream: exactly, or with `approx` only whole
+/// nodes of NODE_ENTRIES, as RDB files store them from the first entry,
+/// and at most `limit` entries if given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trim {
+    pub to: TrimTo,
+    pub approx: bool,
+    pub limit: Option<usize>,
+}
+
+/// How XCLAIM claims.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClaimOptions {
+    /// Milliseconds an entry must have gone undelivered to be claimed.
+    pub min_idle: u64,
+    /// The Unix time in milliseconds to deliver at, if not now.
+    pub delivery_time: Option<u64>,
+    /// The delivery count to set, instead of adding one.
+    pub retry_count: Option<u64>,
+    /// Claim entries of the stream pending for no one too.
+    pub force: bool,
+    /// Leave delivery counts as they are.
+    pub justid: bool,
+    /// Moves the group's last delivered ID up to this one.
+    pub last_id: Option<StreamId>,
+}
+
+/// A stream: entries ordered by ID, each a list of fields and values,
+/// and the last ID it gave out, which new IDs must be greater than even
+/// once the entry holding it is gone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    /// The greatest ID of an entry deleted, 0-0 if none has been.
+    max_deleted_id: StreamId,
+    /// Entries ever added, those since deleted included.
+    entries_added: u64,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A stream loaded with its entries, the IDs and count kept alongside
+    /// them, and its consumer groups.
+    pub fn from_parts(
+        entries: BTreeMap<StreamId, Fields>,
+        last_id: StreamId,
+        max_deleted_id: StreamId,
+        entries_added: u64,
+        groups: BTreeMap<String, Group>,
+    ) -> Self {
+        Stream {
+            entries,
+            last_id,
+            max_deleted_id,
+            entries_added,
+            groups,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// The ID of the first entry, or 0-0 if there's none.
+    pub fn first_id(&self) -> StreamId {
+        self.entries.keys().next().copied().unwrap_or(StreamId::MIN)
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    /// The ID an entry added with `id` at `now_ms` gets, or an error if it
+    /// isn't greater than the last one given out.
+    pub fn next_id(&self, id: NewId, now_ms: u64) -> Result<StreamId, String> {
+        let too_small =
+            || "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+        let id = match id {
+            NewId::Auto => {
+                let ms = now_ms.max(self.last_id.ms);
+                if ms == self.last_id.ms {
+                    self.last_id.next().ok_or_else(too_small)?
+                } else {
+                    StreamId::new(ms, 0)
+                }
+            }
+            NewId::Ms(ms) if ms == self.last_id.ms => {
+                let seq = self.last_id.seq.checked_add(1).ok_or_else(too_small)?;
+                StreamId::new(ms, seq)
+            }
+            NewId::Ms(ms) => StreamId::new(ms, 0),
+            NewId::Explicit(id) => id,
+        };
+        if id <= self.last_id {
+            return Err(too_small().to_string());
+        }
+        Ok(id)
+    }
+
+    /// Appends an entry, its ID greater than any given out before.
+    pub fn add(&mut self, id: StreamId, fields: Fields) {
+        debug_assert!(id > self.last_id);
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// The entries from `start` to `end` inclusive, in order or, with
+    /// `rev`, from `end` down.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (StreamId, &Fields)> + '_> {
+        if start > end {
+            return Box::new(std::iter::empty());
+        }
+        let range = self
+            .entries
+            .range((Bound::Included(start), Bound::Included(end)))
+            .map(|(id, fields)| (*id, fields));
+        if rev {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        }
+    }
+
+    /// Every entry, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (StreamId, &Fields)> {
+        self.entries.iter().map(|(id, fields)| (*id, fields))
+    }
+
+    /// Removes the first entries as `trim` says, returning how many it
+    /// removed and the exact trim that removes just as many, which the
+    /// command is propagated with. Groups keep their pending entries.
+    pub fn trim(&mut self, trim: Trim) -> (usize, Trim) {
+        let mut removed = match trim.to {
+            TrimTo::MaxLen(len) => (self.entries.len() as u64).saturating_sub(len) as usize,
+            TrimTo::MinId(id) => self.entries.range(..id).count(),
+        };
+        if let Some(limit) = trim.limit {
+            removed = removed.min(limit);
+        }
+        if trim.approx {
+            removed -= removed % NODE_ENTRIES;
+        }
+        for _ in 0..removed {
+            self.entries.pop_first();
+        }
+        let to = match trim.to {
+            TrimTo::MaxLen(_) => TrimTo::MaxLen(self.entries.len() as u64),
+            TrimTo::MinId(id) if self.entries.is_empty() => TrimTo::MinId(id),
+            TrimTo::MinId(_) => TrimTo::MinId(self.first_id()),
+        };
+        let exact = if trim.approx {
+            Trim {
+                to,
+                approx: false,
+                limit: None,
+            }
+        } else {
+            trim
+        };
+        (removed, exact)
+    }
+
+    /// The fields of entry `id`, if the stream has it.
+    pub fn get(&self, id: StreamId) -> Option<&Fields> {
+        self.entries.get(&id)
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Group> {
+        &self.groups
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.get(name)
+    }
+
+    /// Creates group `name` having been delivered up to `id`, false if
+    /// there's one already.
+    pub fn create_group(&mut self, name: &str, id: StreamId, entries_read: Option<u64>) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        self.groups
+            .insert(name.to_string(), Group::new(id, entries_read));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Sets the last ID delivered to group `name`, false if there's none.
+    pub fn set_group_id(&mut self, name: &str, id: StreamId, entries_read: Option<u64>) -> bool {
+        let Some(group) = self.groups.get_mut(name) else {
+            return false;
+        };
+        group.last_id = id;
+        group.entries_read = entries_read;
+        true
+    }
+
+    /// Creates `consumer` in group `name`, replying whether it's new, or
+    /// None if there's no such group.
+    pub fn create_consumer(&mut self, name: &str, consumer: &str, now: u64) -> Option<bool> {
+        let group = self.groups.get_mut(name)?;
+        let mut changes = GroupChanges::default();
+        group.seen(consumer, now, &mut changes);
+        Some(changes.created_consumer)
+    }
+
+    /// Reads for `consumer` of group `name`, created if it's new, up to
+    /// `count` entries: those never delivered to the group, made pending
+    /// for it unless `noack`, or those pending for it already, delivered
+    /// once more unless gone from the stream. Returns their IDs and what
+    /// changed, or None if there's no such group.
+    pub fn read_group(
+        &mut self,
+        name: &str,
+        consumer: &str,
+        from: GroupRead,
+        count: Option<usize>,
+        noack: bool,
+        now: u64,
+    ) -> Option<(Vec<StreamId>, GroupChanges)> {
+        let group = self.groups.get_mut(name)?;
+        let mut changes = GroupChanges::default();
+        group.seen(consumer, now, &mut changes);
+        let count = count.unwrap_or(usize::MAX);
+        let ids: Vec<StreamId> = match from {
+            GroupRead::New => {
+                let ids: Vec<StreamId> = match group.last_id.next() {
+                    Some(start) => self
+                        .entries
+                        .range(start..)
+                        .map(|(id, _)| *id)
+                        .take(count)
+                        .collect(),
+                    None => Vec::new(),
+                };
+                if let Some(&last) = ids.last() {
+                    // Counted on from what was read unless entries past it
+                    // were deleted, else from the entries still after
+                    group.entries_read = match group.entries_read {
+                        Some(read) if self.max_deleted_id <= group.last_id => {
+                            Some(read + ids.len() as u64)
+                        }
+                        _ if self.max_deleted_id < last => {
+                            let after = self
+                                .entries
+                                .range((Bound::Excluded(last), Bound::Unbounded))
+                                .count();
+                            Some(self.entries_added - after as u64)
+                        }
+                        _ => None,
+                    };
+                    group.last_id = last;
+                    changes.last_id = Some((last, group.entries_read));
+                    if !noack {
+                        for &id in &ids {
+                            group.assign(id, consumer, now, 1, &mut changes);
+                        }
+                    }
+                }
+                ids
+            }
+            GroupRead::Pending(after) => {
+                let ids: Vec<StreamId> = group.consumers[consumer]
+                    .pending
+                    .range((Bound::Excluded(after), Bound::Unbounded))
+                    .copied()
+                    .take(count)
+                    .collect();
+                for &id in &ids {
+                    if self.entries.contains_key(&id) {
+                        let count = group.pending[&id].delivery_count + 1;
+                        group.assign(id, consumer, now, count, &mut changes);
+                    }
+                }
+                ids
+            }
+        };
+        if !ids.is_empty() {
+            group.seen(consumer, now, &mut changes).active_time = Some(now);
+        }
+        Some((ids, changes))
+    }
+
+    /// Acknowledges entries `ids` in group `name`, replying how many were
+    /// pending, or None if there's no such group.
+    pub fn ack(&mut self, name: &str, ids: &[StreamId]) -> Option<usize> {
+        let group = self.groups.get_mut(name)?;
+        Some(ids.iter().filter(|&&id| group.drop_pending(id)).count())
+    }
+
+    /// Makes the entries `ids` pending in group `name` for `consumer`, as
+    /// `options` say. Entries gone from the stream are dropped instead.
+    /// Returns the IDs claimed and what changed, or None if there's no
+    /// such group.
+    pub fn claim(
+        &mut self,
+        name: &str,
+        consumer: &str,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Option<(Vec<StreamId>, GroupChanges)> {
+        let group = self.groups.get_mut(name)?;
+        let mut changes = GroupChanges::default();
+        if let Some(last_id) = options.last_id
+            && last_id > group.last_id
+        {
+            group.last_id = last_id;
+            changes.last_id = Some((last_id, group.entries_read));
+        }
+        group.seen(consumer, now, &mut changes);
+        let mut claimed = Vec::new();
+        for &id in ids {
+            let exists = self.entries.contains_key(&id);
+            let (delivery_time, delivery_count) = match group.pending.get(&id) {
+                Some(_) if !exists => {
+                    group.drop_pending(id);
+                    changes.dropped.push(id);
+                    continue;
+                }
+                Some(pending) => (pending.delivery_time, pending.delivery_count),
+                // Forced in as if just delivered, as Redis does
+                None if options.force && exists => (now, 1),
+                None => continue,
+            };
+            if now.saturating_sub(delivery_time) < options.min_idle {
+                continue;
+            }
+            let count = match options.retry_count {
+                Some(count) => count,
+                None => delivery_count + u64::from(!options.justid),
+            };
+            let time = options.delivery_time.unwrap_or(now);
+            group.assign(id, consumer, time, count, &mut changes);
+            claimed.push(id);
+        }
+        if !claimed.is_empty() {
+            group.seen(consumer, now, &mut changes).active_time = Some(now);
+        }
+        Some((claimed, changes))
+    }
+
+    /// Claims for `consumer` of group `name` up to `count` entries pending
+    /// from `start` on, as XAUTOCLAIM does with the `min_idle` and `justid`
+    /// of `options`, looking at ten times as many at most. Entries gone
+    /// from the stream are dropped instead. Returns the ID to go on from,
+    /// 0-0 once there are no more, the IDs claimed and what changed, or
+    /// None if there's no such group.
+    pub fn autoclaim(
+        &mut self,
+        name: &str,
+        consumer: &str,
+        start: StreamId,
+        count: usize,
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Option<(StreamId, Vec<StreamId>, GroupChanges)> {
+        let group = self.groups.get_mut(name)?;
+        let mut changes = GroupChanges::default();
+        group.seen(consumer, now, &mut changes);
+        let mut claimed = Vec::new();
+        let mut attempts = count.saturating_mul(10);
+        let mut next = group.pending.range(start..).next().map(|(id, _)| *id);
+        while let Some(id) = next
+            && attempts > 0
+            && claimed.len() < count
+        {
+            attempts -= 1;
+            next = group
+                .pending
+                .range((Bound::Excluded(id), Bound::Unbounded))
+                .next()
+                .map(|(id, _)| *id);
+            if !self.entries.contains_key(&id) {
+                group.drop_pending(id);
+                changes.dropped.push(id);
+                continue;
+            }
+            let pending = &group.pending[&id];
+            if now.saturating_sub(pending.delivery_time) < options.min_idle {
+                continue;
+            }
+            let count = pending.delivery_count + u64::from(!options.justid);
+            group.assign(id, consumer, now, count, &mut changes);
+            claimed.push(id);
+        }
+        if !claimed.is_empty() {
+            group.seen(consumer, now, &mut changes).active_time = Some(now);
+        }
+        Some((next.unwrap_or(StreamId::MIN), claimed, changes))
+    }
+
+    /// Bytes the entries take beyond their strings, estimated: the tree's
+    /// node per entry, its ID and its vector of fields. Groups add their
+    /// names, consumers and pending entries, listed twice.
+    pub fn overhead(&self) -> usize {
+        let entries: usize = self
+            .entries
+            .values()
+            .map(|fields| {
+                size_of::<StreamId>()
+                    + size_of::<Fields>()
+                    + fields.capacity() * size_of::<(String, String)>()
+            })
+            .sum();
+        let groups: usize = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let pending: usize = group
+                    .pending
+                    .values()
+                    .map(|pending| {
+                        2 * size_of::<StreamId>()
+                            + size_of::<Pending>()
+                            + pending.consumer.capacity()
+                    })
+                    .sum();
+                let consumers: usize = group
+                    .consumers
+                    .keys()
+                    .map(|name| name.capacity() + size_of::<Consumer>())
+                    .sum();
+                name.capacity() + size_of::<Group>() + pending + consumers
+            })
+            .sum();
+        entries + groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(
+            StreamId::parse("5", u64::MAX),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        assert_eq!(StreamId::parse("-5", 0), None);
+        assert_eq!(StreamId::parse("5-", 0), None);
+        assert_eq!(StreamId::parse("+1", 0), None);
+        assert_eq!(StreamId::parse("18446744073709551616", 0), None);
+        assert_eq!(NewId::parse("*"), Some(NewId::Auto));
+        assert_eq!(NewId::parse("7-*"), Some(NewId::Ms(7)));
+        assert_eq!(
+            NewId::parse("7"),
+            Some(NewId::Explicit(StreamId::new(7, 0)))
+        );
+        assert_eq!(NewId::parse("*-1"), None);
+
+        assert_eq!(StreamId::new(1, u64::MAX).next(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::new(2, 0).prev(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(StreamId::new(3, 4).to_string(), "3-4");
+    }
+
+    #[test]
+    fn test_next_id() {
+        let mut stream = Stream::new();
+        let fields = || vec![("f".to_string(), "v".to_string())];
+        assert_eq!(stream.next_id(NewId::Auto, 100), Ok(StreamId::new(100, 0)));
+        assert_eq!(stream.next_id(NewId::Ms(0), 100), Ok(StreamId::new(0, 1)));
+        stream.add(StreamId::new(100, 5), fields());
+
+        // The clock going back reuses the last millisecond
+        assert_eq!(stream.next_id(NewId::Auto, 50), Ok(StreamId::new(100, 6)));
+        assert_eq!(stream.next_id(NewId::Ms(100), 0), Ok(StreamId::new(100, 6)));
+        assert_eq!(stream.next_id(NewId::Ms(101), 0), Ok(StreamId::new(101, 0)));
+        assert!(stream.next_id(NewId::Ms(99), 0).is_err());
+        assert!(
+            stream
+                .next_id(NewId::Explicit(StreamId::new(100, 5)), 0)
+                .is_err()
+        );
+
+        stream.add(StreamId::new(100, u64::MAX), fields());
+        assert!(stream.next_id(NewId::Ms(100), 0).is_err());
+        assert_eq!(stream.next_id(NewId::Auto, 0), Ok(StreamId::new(101, 0)));
+        assert_eq!(stream.len(), 2);
+        assert_eq!(stream.entries_added(), 2);
+        assert_eq!(stream.first_id(), StreamId::new(100, 5));
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut stream = Stream::new();
+        for ms in 1..=4 {
+            stream.add(
+                StreamId::new(ms, 0),
+                vec![("n".to_string(), ms.to_string())],
+            );
+        }
+        let id = |ms| StreamId::new(ms, 0);
+        assert!(stream.create_group("g", StreamId::MIN, None));
+        assert!(!stream.create_group("g", StreamId::MIN, None));
+        assert_eq!(
+            stream.read_group("missing", "c", GroupRead::New, None, false, 0),
+            None
+        );
+
+        // New entries are delivered once, and pending until acknowledged
+        let (ids, changes) = stream
+            .read_group("g", "alice", GroupRead::New, Some(3), false, 100)
+            .unwrap();
+        assert_eq!(ids, [id(1), id(2), id(3)]);
+        assert!(changes.created_consumer);
+        assert_eq!(changes.last_id, Some((id(3), Some(3))));
+        let (ids, _) = stream
+            .read_group("g", "bob", GroupRead::New, None, true, 100)
+            .unwrap();
+        assert_eq!(ids, [id(4)]);
+        let group = stream.group("g").unwrap();
+        assert_eq!(group.entries_read(), Some(4));
+        assert_eq!(group.pending().len(), 3);
+        assert!(group.consumers()["bob"].pending.is_empty());
+        assert_eq!(stream.ack("g", &[id(2), id(2), id(9)]), Some(1));
+
+        // History is delivered again, counted
+        let (ids, changes) = stream
+            .read_group("g", "alice", GroupRead::Pending(id(1)), None, false, 200)
+            .unwrap();
+        assert_eq!(ids, [id(3)]);
+        assert_eq!(changes.claimed[0].1.delivery_count, 2);
+
+        // Claimed once idle for long enough, FORCE taking unowned entries
+        let options = ClaimOptions {
+            min_idle: 150,
+            ..ClaimOptions::default()
+        };
+        let (ids, _) = stream
+            .claim("g", "bob", &[id(1), id(3)], &options, 300)
+            .unwrap();
+        assert_eq!(ids, [id(1)]);
+        let forced = ClaimOptions {
+            force: true,
+            justid: true,
+            retry_count: Some(7),
+            ..ClaimOptions::default()
+        };
+        let (ids, _) = stream
+            .claim("g", "bob", &[id(2), id(9)], &forced, 300)
+            .unwrap();
+        assert_eq!(ids, [id(2)]);
+        let group = stream.group("g").unwrap();
+        assert_eq!(group.pending()[&id(1)].consumer, "bob");
+        assert_eq!(group.pending()[&id(1)].delivery_count, 2);
+        assert_eq!(group.pending()[&id(2)].delivery_count, 7);
+        assert_eq!(group.consumers()["alice"].pending.len(), 1);
+        assert_eq!(group.consumers()["bob"].pending.len(), 2);
+
+        // Entries gone from the stream are dropped, not claimed
+        stream.entries.remove(&id(1));
+        let (next, ids, changes) = stream
+            .autoclaim(
+                "g",
+                "carol",
+                StreamId::MIN,
+                1,
+                &ClaimOptions::default(),
+                400,
+            )
+            .unwrap();
+        assert_eq!((next, ids), (id(3), vec![id(2)]));
+        assert_eq!(changes.dropped, [id(1)]);
+        let (next, ids, _) = stream
+            .autoclaim("g", "carol", next, 10, &ClaimOptions::default(), 400)
+            .unwrap();
+        assert_eq!((next, ids), (StreamId::MIN, vec![id(3)]));
+        assert_eq!(
+            stream.group("g").unwrap().consumers()["carol"]
+                .pending
+                .len(),
+            2
+        );
+
+        assert_eq!(stream.create_consumer("g", "dave", 0), Some(true));
+        assert_eq!(stream.create_consumer("g", "dave", 0), Some(false));
+        assert!(stream.set_group_id("g", StreamId::MIN, None));
+        assert!(stream.destroy_group("g"));
+        assert!(stream.groups().is_empty());
+    }
+
+    #[test]
+    fn test_range() {
+        let mut stream = Stream::new();
+        for ms in 1..=5 {
+            stream.add(
+                StreamId::new(ms, 0),
+                vec![("n".to_string(), ms.to_string())],
+            );
+        }
+        let ids = |start, end, rev| {
+            stream
+                .range(start, end, rev)
+                .map(|(id, _)| id.ms)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(StreamId::new(2, 0), StreamId::new(4, 0), false),
+            [2, 3, 4]
+        );
+        assert_eq!(ids(StreamId::new(2, 1), StreamId::MAX, true), [5, 4, 3]);
+        assert_eq!(
+            ids(StreamId::new(4, 0), StreamId::new(2, 0), false),
+            [] as [u64; 0]
+        );
+    }
+
+    #[test]
+    fn test_trim() {
+        let filled = |n| {
+            let mut stream = Stream::new();
+            for ms in 1..=n {
+                stream.add(
+                    StreamId::new(ms, 0),
+                    vec![("n".to_string(), ms.to_string())],
+                );
+            }
+            stream
+        };
+        let trim = |to, approx, limit| Trim { to, approx, limit };
+
+        let mut stream = filled(10);
+        assert_eq!(
+            stream.trim(trim(TrimTo::MaxLen(4), false, None)),
+            (6, trim(TrimTo::MaxLen(4), false, None))
+        );
+        assert_eq!(stream.first_id(), StreamId::new(7, 0));
+        assert_eq!(
+            stream.trim(trim(TrimTo::MinId(StreamId::new(9, 0)), false, None)),
+            (2, trim(TrimTo::MinId(StreamId::new(9, 0)), false, None))
+        );
+        assert_eq!(stream.len(), 2);
+        assert_eq!(stream.last_id(), StreamId::new(10, 0));
+
+        // Approximately only whole nodes go, and as the exact trim done
+        let mut stream = filled(250);
+        assert_eq!(
+            stream.trim(trim(TrimTo::MaxLen(10), true, None)),
+            (200, trim(TrimTo::MaxLen(50), false, None))
+        );
+        assert_eq!(stream.trim(trim(TrimTo::MaxLen(10), true, None)).0, 0);
+        let mut stream = filled(250);
+        assert_eq!(
+            stream.trim(trim(TrimTo::MinId(StreamId::new(240, 0)), true, Some(150))),
+            (100, trim(TrimTo::MinId(StreamId::new(101, 0)), false, None))
+        );
+        assert_eq!(
+            stream.trim(trim(TrimTo::MaxLen(0), false, Some(20))),
+            (20, trim(TrimTo::MaxLen(0), false, Some(20)))
+        );
+        assert_eq!(stream.len(), 130);
+
+        let mut stream = filled(3);
+        let min_id = trim(TrimTo::MinId(StreamId::new(7, 0)), false, None);
+        assert_eq!(stream.trim(min_id), (3, min_id));
+        assert!(stream.is_empty());
+        assert_eq!(stream.last_id(), StreamId::new(3, 0));
+    }
+}
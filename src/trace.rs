@@ -0,0 +1,191 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use tracing::Subscriber;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_subscriber::filter;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::logging::{self, Level};
+
+/// `command-tracing`: spans are written to the log as they close.
+static LOG_SPANS: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "otel")]
+static PROVIDER: std::sync::OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> =
+    std::sync::OnceLock::new();
+
+pub fn set_log_spans(on: bool) {
+    LOG_SPANS.store(on, Ordering::Relaxed);
+}
+
+/// What handles the `connection` and `command` spans: the log while
+/// `command-tracing` is on and, built with the `otel` feature, an OTLP
+/// exporter when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Spans nothing wants
+/// aren't created at all.
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let log = SpanLog.with_filter(filter::dynamic_filter_fn(|metadata, _| {
+        metadata.is_span() && LOG_SPANS.load(Ordering::Relaxed)
+    }));
+    #[cfg(feature = "otel")]
+    let log = log.and_then(otlp_layer());
+    log
+}
+
+/// Exports spans over OTLP/HTTP, configured by the standard `OTEL_*`
+/// environment variables.
+#[cfg(feature = "otel")]
+fn otlp_layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            // Nothing is logged through tracing until this layer is in
+            eprintln!("Can't export spans over OTLP: {}", e);
+            return None;
+        }
+    };
+    let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "reredis".to_string());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service).build())
+        .build();
+    let tracer = provider.tracer("reredis");
+    let _ = PROVIDER.set(provider);
+    Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter::filter_fn(|metadata| metadata.is_span())),
+    )
+}
+
+/// Exports the spans still queued, before the server exits.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::warn!("Error exporting the last spans: {}", e);
+    }
+}
+
+/// Writes each span to the log when it closes, with its fields and how
+/// long it was open.
+struct SpanLog;
+
+/// A span's fields as they're recorded, kept with it until it closes.
+struct Recorded {
+    start: Instant,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for Recorded {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.fields.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+impl<S> Layer<S> for SpanLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut recorded = Recorded {
+            start: Instant::now(),
+            fields: Vec::new(),
+        };
+        attrs.record(&mut recorded);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(recorded);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(recorded) = span.extensions_mut().get_mut::<Recorded>()
+        {
+            values.record(recorded);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(recorded) = span.extensions_mut().remove::<Recorded>() else {
+            return;
+        };
+        let mut fields = vec![
+            ("span", span.name().to_string()),
+            (
+                "duration_us",
+                recorded.start.elapsed().as_micros().to_string(),
+            ),
+        ];
+        fields.extend(recorded.fields);
+        logging::write_fields(Level::Notice, "span", &fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_span_fields() {
+        struct Fields(std::sync::Arc<std::sync::Mutex<Vec<(&'static str, String)>>>);
+        impl<S> Layer<S> for Fields
+        where
+            S: Subscriber + for<'a> LookupSpan<'a>,
+        {
+            fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+                let span = ctx.span(&id).unwrap();
+                let extensions = span.extensions();
+                let recorded = extensions.get::<Recorded>().unwrap();
+                *self.0.lock().unwrap() = recorded.fields.clone();
+            }
+        }
+
+        // Added first, it sees the span close before SpanLog takes them
+        let fields = Fields(Default::default());
+        let closed = fields.0.clone();
+        let subscriber = tracing_subscriber::registry().with(fields).with(SpanLog);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "command",
+                client = 7,
+                command = "get",
+                status = tracing::field::Empty
+            );
+            span.record("status", "ok");
+        });
+        assert_eq!(
+            *closed.lock().unwrap(),
+            vec![
+                ("client", "7".to_string()),
+                ("command", "get".to_string()),
+                ("status", "ok".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,838 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::binary;
+use crate::evict;
+
+/// Levels a skiplist node can have, as in Redis.
+const MAX_LEVEL: usize = 32;
+
+/// Index of the skiplist's head, which holds no member.
+const HEAD: usize = 0;
+
+/// A missing link.
+const NIL: usize = usize::MAX;
+
+/// Orders two members by score, then by member for equal scores. Scores
+/// are never NaN, and 0 and -0 are equal, as they are to Redis. Members
+/// compare byte by byte.
+fn compare(score: f64, member: &str, other_score: f64, other_member: &str) -> Ordering {
+    score
+        .partial_cmp(&other_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| binary::cmp(member, other_member))
+}
+
+/// Formats a score the way Redis replies with it, as `%.17g` lays it out
+/// but with the shortest digits that read back as the same double: with
+/// an exponent below 1e-4 and from 1e17 on, and `inf` and `-inf` for the
+/// infinities.
+pub fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        return if score > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let magnitude = score.abs();
+    if magnitude == 0.0 || (1e-4..1e17).contains(&magnitude) {
+        return score.to_string();
+    }
+    let formatted = format!("{:e}", score);
+    let (mantissa, exponent) = formatted.split_once('e').expect("formatted with {:e}");
+    let (sign, digits) = match exponent.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', exponent),
+    };
+    format!("{}e{}{:0>2}", mantissa, sign, digits)
+}
+
+/// Parses a score as ZADD takes it, `inf`, `+inf` and `-inf` included,
+/// or None if it isn't a number or overflows, as `1e400` does.
+pub fn parse_score(s: &str) -> Option<f64> {
+    let score = s.parse::<f64>().ok()?;
+    let infinite = s
+        .strip_prefix(['+', '-'])
+        .unwrap_or(s)
+        .eq_ignore_ascii_case("inf");
+    (score.is_finite() || infinite).then_some(score)
+}
+
+/// One end of a score range, as ZRANGEBYSCORE takes it: a score, left out
+/// of the range when written after a `(`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Parses a bound, or None if it isn't a score.
+    pub fn parse(s: &str) -> Option<ScoreBound> {
+        let (score, exclusive) = match s.strip_prefix('(') {
+            Some(score) => (score, true),
+            None => (s, false),
+        };
+        parse_score(score).map(|score| ScoreBound { score, exclusive })
+    }
+}
+
+/// The scores from `min` to `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreRange {
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+impl ScoreRange {
+    /// Whether `score` comes before the range.
+    fn below(&self, score: f64) -> bool {
+        score < self.min.score || (self.min.exclusive && score == self.min.score)
+    }
+
+    /// Whether `score` comes after the range.
+    fn above(&self, score: f64) -> bool {
+        score > self.max.score || (self.max.exclusive && score == self.max.score)
+    }
+}
+
+/// One end of a range of members, as ZRANGEBYLEX takes it: `-` and `+`
+/// for before and after every member, or a member after `[` to include it
+/// or `(` to leave it out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    /// Parses a bound, or None if it isn't one.
+    pub fn parse(s: &str) -> Option<LexBound> {
+        match s {
+            "-" => Some(LexBound::Min),
+            "+" => Some(LexBound::Max),
+            _ => {
+                if let Some(member) = s.strip_prefix('[') {
+                    Some(LexBound::Inclusive(member.to_string()))
+                } else {
+                    s.strip_prefix('(')
+                        .map(|member| LexBound::Exclusive(member.to_string()))
+                }
+            }
+        }
+    }
+}
+
+/// The members from `min` to `max`, compared byte by byte. Like Redis,
+/// lexicographical ranges assume every member has the same score.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    /// Whether `member` comes before the range.
+    fn below(&self, member: &str) -> bool {
+        match &self.min {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(min) => binary::cmp(member, min).is_lt(),
+            LexBound::Exclusive(min) => binary::cmp(member, min).is_le(),
+        }
+    }
+
+    /// Whether `member` comes after the range.
+    fn above(&self, member: &str) -> bool {
+        match &self.max {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(max) => binary::cmp(member, max).is_gt(),
+            LexBound::Exclusive(max) => binary::cmp(member, max).is_ge(),
+        }
+    }
+}
+
+/// The members a ZRANGE picks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeBy {
+    /// Positions `start` to `stop`, both included, negative ones counting
+    /// back from the end.
+    Index(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// A ZRANGE: the members it picks, whether from the highest score down,
+/// positions then counting from the highest too, and the LIMIT offset and
+/// count applied to a range of scores or members, a negative count taking
+/// all that are left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeQuery {
+    pub by: RangeBy,
+    pub rev: bool,
+    pub limit: Option<(i64, i64)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    forward: usize,
+    /// How many members the link skips over, the one it leads to included.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Arc<str>,
+    score: f64,
+    backward: usize,
+    levels: Vec<Link>,
+}
+
+/// Members ordered by score, in nodes kept in one vector and linked by
+/// index. Each link counts the members it skips, so finding a member's
+/// rank or the member at a rank takes O(log n), as in Redis' zskiplist.
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    /// Slots of removed nodes, reused by the next inserts.
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    tail: usize,
+}
+
+impl SkipList {
+    fn new() -> Self {
+        let head = Node {
+            member: Arc::from(""),
+            score: 0.0,
+            backward: NIL,
+            levels: vec![
+                Link {
+                    forward: NIL,
+                    span: 0
+                };
+                MAX_LEVEL
+            ],
+        };
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            tail: NIL,
+        }
+    }
+
+    /// A level for a new node: each one up with a chance of 1 in 4.
+    fn random_level() -> usize {
+        let mut level = 1;
+        let mut bits = evict::random_u64();
+        while level < MAX_LEVEL && bits & 3 == 0 {
+            level += 1;
+            bits >>= 2;
+        }
+        level
+    }
+
+    /// The nodes each level was last followed from to reach the place of
+    /// `score` and `member`, and the rank of each, as inserting and
+    /// removing need them.
+    fn path(&self, score: f64, member: &str) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            loop {
+                let link = self.nodes[x].levels[i];
+                if link.forward == NIL {
+                    break;
+                }
+                let next = &self.nodes[link.forward];
+                if compare(next.score, &next.member, score, member) != Ordering::Less {
+                    break;
+                }
+                rank[i] += link.span;
+                x = link.forward;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    /// Inserts a member that isn't in the list yet.
+    fn insert(&mut self, score: f64, member: Arc<str>) {
+        let (mut update, mut rank) = self.path(score, &member);
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: if update[0] == HEAD { NIL } else { update[0] },
+            levels: vec![
+                Link {
+                    forward: NIL,
+                    span: 0
+                };
+                level
+            ],
+        };
+        let x = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let before = self.nodes[update[i]].levels[i];
+            self.nodes[x].levels[i] = Link {
+                forward: before.forward,
+                span: before.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = Link {
+                forward: x,
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &node) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[node].levels[i].span += 1;
+        }
+
+        match self.nodes[x].levels[0].forward {
+            NIL => self.tail = x,
+            next => self.nodes[next].backward = x,
+        }
+        self.len += 1;
+    }
+
+    /// Removes the member with `score`, which must be in the list.
+    fn remove(&mut self, score: f64, member: &str) {
+        let (update, _) = self.path(score, member);
+        let x = self.nodes[update[0]].levels[0].forward;
+        debug_assert!(x != NIL && &*self.nodes[x].member == member);
+
+        for (i, &before) in update.iter().enumerate().take(self.level) {
+            let removed = self.nodes[x].levels.get(i).copied();
+            let link = &mut self.nodes[before].levels[i];
+            match removed {
+                Some(removed) if link.forward == x => {
+                    link.span += removed.span;
+                    link.span -= 1;
+                    link.forward = removed.forward;
+                }
+                _ => link.span -= 1,
+            }
+        }
+        let backward = self.nodes[x].backward;
+        match self.nodes[x].levels[0].forward {
+            NIL => self.tail = backward,
+            next => self.nodes[next].backward = backward,
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward == NIL {
+            self.level -= 1;
+        }
+        self.len -= 1;
+
+        let node = &mut self.nodes[x];
+        node.member = Arc::from("");
+        node.levels = Vec::new();
+        self.free.push(x);
+    }
+
+    /// How many members `before` holds for, it holding for the first ones
+    /// in the list and none after, found in a walk down the levels.
+    fn count_while(&self, before: impl Fn(f64, &str) -> bool) -> usize {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].levels[i];
+                if link.forward == NIL {
+                    break;
+                }
+                let next = &self.nodes[link.forward];
+                if !before(next.score, &next.member) {
+                    break;
+                }
+                rank += link.span;
+                x = link.forward;
+            }
+        }
+        rank
+    }
+
+    /// The node at `rank`, 0 being the first, which must be in the list.
+    fn node_at(&self, rank: usize) -> usize {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].levels[i];
+                if link.forward == NIL || traversed + link.span > target {
+                    break;
+                }
+                traversed += link.span;
+                x = link.forward;
+            }
+            if traversed == target {
+                return x;
+            }
+        }
+        unreachable!("rank {} is past the end of the list", rank)
+    }
+}
+
+/// A sorted set: members ordered by score, then by member where scores
+/// are equal, in a skiplist, and each member's score in a table for
+/// lookups by member. The two share the member strings.
+#[derive(Debug, Clone)]
+pub struct SortedSet {
+    scores: HashMap<Arc<str>, f64>,
+    list: SkipList,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet {
+            scores: HashMap::new(),
+            list: SkipList::new(),
+        }
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets `member`'s score, moving it if it changes, and returns whether
+    /// the member is new.
+    pub fn insert(&mut self, member: &str, score: f64) -> bool {
+        match self.scores.get_mut(member) {
+            Some(current) if *current == score => false,
+            Some(current) => {
+                let old = std::mem::replace(current, score);
+                self.list.remove(old, member);
+                let (member, _) = self.scores.get_key_value(member).expect("just updated");
+                self.list.insert(score, member.clone());
+                false
+            }
+            None => {
+                let member: Arc<str> = Arc::from(member);
+                self.scores.insert(member.clone(), score);
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    /// Removes the member with the lowest score and returns it with its
+    /// score, if the set isn't empty.
+    pub fn pop_min(&mut self) -> Option<(String, f64)> {
+        self.pop_node(self.list.nodes[HEAD].levels[0].forward)
+    }
+
+    /// Removes the member with the highest score and returns it with its
+    /// score, if the set isn't empty.
+    pub fn pop_max(&mut self) -> Option<(String, f64)> {
+        self.pop_node(self.list.tail)
+    }
+
+    fn pop_node(&mut self, x: usize) -> Option<(String, f64)> {
+        if x == NIL {
+            return None;
+        }
+        let member = self.list.nodes[x].member.to_string();
+        let score = self.scores.remove(member.as_str())?;
+        self.list.remove(score, &member);
+        Some((member, score))
+    }
+
+    /// Where `member` ranks, 0 being the lowest score, if it's in the set.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.list.count_while(|other_score, other| {
+            compare(other_score, other, score, member) == Ordering::Less
+        }))
+    }
+
+    /// Removes `member`, returning whether there was one.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The members and their scores, lowest score first.
+    pub fn iter(&self) -> Iter<'_> {
+        self.slice(0..self.len(), false)
+    }
+
+    /// The members ranked within `ranks`, 0 being the lowest, from the
+    /// lowest up or, with `rev`, from the highest down.
+    pub fn slice(&self, ranks: Range<usize>, rev: bool) -> Iter<'_> {
+        let next = match (ranks.is_empty(), rev) {
+            (true, _) => NIL,
+            (false, false) => self.list.node_at(ranks.start),
+            (false, true) => self.list.node_at(ranks.end - 1),
+        };
+        Iter {
+            list: &self.list,
+            next,
+            remaining: ranks.len(),
+            rev,
+        }
+    }
+
+    /// The ranks of the members with scores within `range`.
+    pub fn score_ranks(&self, range: &ScoreRange) -> Range<usize> {
+        let start = self.list.count_while(|score, _| range.below(score));
+        let end = self.list.count_while(|score, _| !range.above(score));
+        start..end.max(start)
+    }
+
+    /// The ranks of the members within `range`.
+    pub fn lex_ranks(&self, range: &LexRange) -> Range<usize> {
+        let start = self.list.count_while(|_, member| range.below(member));
+        let end = self.list.count_while(|_, member| !range.above(member));
+        start..end.max(start)
+    }
+
+    /// The members `query` picks, in the order it asks for.
+    pub fn range(&self, query: &RangeQuery) -> Iter<'_> {
+        let mut ranks = match &query.by {
+            RangeBy::Index(start, stop) => {
+                let len = self.len() as i64;
+                let start = if *start < 0 {
+                    (len + start).max(0)
+                } else {
+                    *start
+                };
+                let stop = if *stop < 0 {
+                    len + stop
+                } else {
+                    (*stop).min(len - 1)
+                };
+                if start > stop {
+                    0..0
+                } else if query.rev {
+                    (len - 1 - stop) as usize..(len - start) as usize
+                } else {
+                    start as usize..stop as usize + 1
+                }
+            }
+            RangeBy::Score(range) => self.score_ranks(range),
+            RangeBy::Lex(range) => self.lex_ranks(range),
+        };
+        if let Some((offset, count)) = query.limit {
+            ranks = if offset < 0 {
+                0..0
+            } else {
+                let offset = (offset as usize).min(ranks.len());
+                let left = ranks.len() - offset;
+                let count = if count < 0 {
+                    left
+                } else {
+                    (count as usize).min(left)
+                };
+                if query.rev {
+                    ranks.end - offset - count..ranks.end - offset
+                } else {
+                    ranks.start + offset..ranks.start + offset + count
+                }
+            };
+        }
+        self.slice(ranks, query.rev)
+    }
+
+    /// Bytes the set takes beyond its members' text: the table, and the
+    /// nodes and their links.
+    pub fn overhead(&self) -> usize {
+        self.scores.capacity() * (size_of::<Arc<str>>() + size_of::<f64>() + 1)
+            + self.list.nodes.capacity() * size_of::<Node>()
+            + self
+                .list
+                .nodes
+                .iter()
+                .map(|node| node.levels.capacity() * size_of::<Link>())
+                .sum::<usize>()
+    }
+
+    /// Shrinks the table and the node vector where most of what they
+    /// reserve is unused, returning whether either was.
+    pub fn shrink(&mut self, oversized: impl Fn(usize, usize) -> bool) -> bool {
+        let mut shrunk = false;
+        if oversized(self.scores.capacity(), self.scores.len()) {
+            self.scores.shrink_to_fit();
+            shrunk = true;
+        }
+        if oversized(self.list.nodes.capacity(), self.list.nodes.len()) {
+            self.list.nodes.shrink_to_fit();
+            shrunk = true;
+        }
+        shrunk
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedSet {
+    type Item = (&'a str, f64);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The members of a sorted set and their scores, in order.
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: usize,
+    remaining: usize,
+    rev: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.next];
+        self.next = if self.rev {
+            node.backward
+        } else {
+            node.levels[0].forward
+        };
+        self.remaining -= 1;
+        Some((&node.member, node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks every link's span against the ranks of the nodes it joins.
+    fn check_spans(zset: &SortedSet) {
+        let list = &zset.list;
+        let mut rank = HashMap::from([(HEAD, 0)]);
+        let mut x = list.nodes[HEAD].levels[0].forward;
+        let mut previous = NIL;
+        let mut i = 1;
+        while x != NIL {
+            assert_eq!(list.nodes[x].backward, previous);
+            rank.insert(x, i);
+            previous = x;
+            x = list.nodes[x].levels[0].forward;
+            i += 1;
+        }
+        assert_eq!(list.tail, previous);
+        for level in 0..list.level {
+            let mut x = HEAD;
+            while x != NIL {
+                let link = list.nodes[x].levels[level];
+                if link.forward != NIL {
+                    assert_eq!(rank[&link.forward] - rank[&x], link.span);
+                }
+                x = link.forward;
+            }
+        }
+    }
+
+    #[test]
+    fn test_ordered_by_score_then_member() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert("b", 2.0));
+        assert!(zset.insert("a", 2.0));
+        assert!(zset.insert("c", 1.0));
+        assert!(zset.insert("d", f64::NEG_INFINITY));
+        assert!(!zset.insert("c", 3.0));
+        assert!(!zset.insert("a", 2.0));
+        assert_eq!(
+            zset.iter().collect::<Vec<_>>(),
+            [("d", f64::NEG_INFINITY), ("a", 2.0), ("b", 2.0), ("c", 3.0)]
+        );
+        assert_eq!(zset.score("c"), Some(3.0));
+        assert_eq!(zset.score("e"), None);
+
+        assert_eq!(zset.rank("d"), Some(0));
+        assert_eq!(zset.rank("b"), Some(2));
+        assert_eq!(zset.rank("e"), None);
+
+        assert!(zset.remove("a"));
+        assert!(!zset.remove("a"));
+        assert_eq!(zset.len(), 3);
+        check_spans(&zset);
+
+        assert_eq!(zset.pop_max(), Some(("c".to_string(), 3.0)));
+        assert_eq!(zset.pop_min(), Some(("d".to_string(), f64::NEG_INFINITY)));
+        assert_eq!(zset.pop_min(), Some(("b".to_string(), 2.0)));
+        assert_eq!(zset.pop_max(), None);
+        assert!(zset.is_empty());
+        check_spans(&zset);
+    }
+
+    #[test]
+    fn test_links_stay_consistent() {
+        let mut zset = SortedSet::new();
+        for i in 0..500u64 {
+            let member = format!("m{}", i * 7919 % 500);
+            zset.insert(&member, (i % 37) as f64);
+        }
+        check_spans(&zset);
+        for i in (0..500).step_by(3) {
+            assert!(zset.remove(&format!("m{}", i)));
+        }
+        for i in 0..100 {
+            zset.insert(&format!("m{}", i), -(i as f64));
+        }
+        check_spans(&zset);
+        let scores: Vec<f64> = zset.iter().map(|(_, score)| score).collect();
+        assert!(scores.is_sorted());
+        assert_eq!(scores.len(), zset.len());
+
+        for i in 0..500 {
+            zset.remove(&format!("m{}", i));
+        }
+        assert!(zset.is_empty());
+        assert_eq!(zset.list.level, 1);
+        assert_eq!(zset.iter().next(), None);
+    }
+
+    #[test]
+    fn test_format_and_parse_score() {
+        assert_eq!(format_score(1.0), "1");
+        assert_eq!(format_score(-2.5), "-2.5");
+        assert_eq!(format_score(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_score(1e20), "1e+20");
+        assert_eq!(format_score(1.5e-7), "1.5e-07");
+        assert_eq!(format_score(0.0001), "0.0001");
+        assert_eq!(format_score(-1.25e300), "-1.25e+300");
+        assert_eq!(format_score(f64::INFINITY), "inf");
+        assert_eq!(format_score(f64::NEG_INFINITY), "-inf");
+
+        assert_eq!(parse_score("+inf"), Some(f64::INFINITY));
+        assert_eq!(parse_score("-inf"), Some(f64::NEG_INFINITY));
+        assert_eq!(parse_score("1e3"), Some(1000.0));
+        assert_eq!(parse_score("nan"), None);
+        assert_eq!(parse_score("1e400"), None);
+        assert_eq!(parse_score("-1e400"), None);
+        assert_eq!(parse_score("infinity"), None);
+        assert_eq!(parse_score("INF"), Some(f64::INFINITY));
+        assert_eq!(parse_score("one"), None);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut zset = SortedSet::new();
+        for (i, member) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            zset.insert(member, i as f64);
+        }
+        let members = |query: RangeQuery| -> Vec<String> {
+            zset.range(&query)
+                .map(|(member, _)| member.to_string())
+                .collect()
+        };
+        let query = |by, rev, limit| RangeQuery { by, rev, limit };
+        let score = |s: &str| ScoreBound::parse(s).unwrap();
+        let lex = |s: &str| LexBound::parse(s).unwrap();
+
+        assert_eq!(
+            members(query(RangeBy::Index(1, -2), false, None)),
+            ["b", "c", "d"]
+        );
+        assert_eq!(members(query(RangeBy::Index(0, 1), true, None)), ["e", "d"]);
+        assert_eq!(
+            members(query(RangeBy::Index(-100, 100), false, None)).len(),
+            5
+        );
+        assert!(members(query(RangeBy::Index(3, 1), false, None)).is_empty());
+        assert!(members(query(RangeBy::Index(5, 10), false, None)).is_empty());
+
+        let scores = ScoreRange {
+            min: score("(1"),
+            max: score("3"),
+        };
+        assert_eq!(zset.score_ranks(&scores), 2..4);
+        assert_eq!(
+            members(query(RangeBy::Score(scores), true, None)),
+            ["d", "c"]
+        );
+        let all = ScoreRange {
+            min: score("-inf"),
+            max: score("+inf"),
+        };
+        assert_eq!(
+            members(query(RangeBy::Score(all), false, Some((1, 2)))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(query(RangeBy::Score(all), true, Some((1, -1)))),
+            ["d", "c", "b", "a"]
+        );
+        assert!(members(query(RangeBy::Score(all), false, Some((-1, 2)))).is_empty());
+        assert!(members(query(RangeBy::Score(all), false, Some((9, 2)))).is_empty());
+        let empty = ScoreRange {
+            min: score("3"),
+            max: score("(3"),
+        };
+        assert_eq!(zset.score_ranks(&empty).len(), 0);
+
+        // Lexicographical ranges among equal scores
+        let mut zset = SortedSet::new();
+        for member in ["apple", "banana", "cherry", "date"] {
+            zset.insert(member, 0.0);
+        }
+        let range = |min: &str, max: &str| LexRange {
+            min: lex(min),
+            max: lex(max),
+        };
+        assert_eq!(zset.lex_ranks(&range("-", "+")), 0..4);
+        assert_eq!(zset.lex_ranks(&range("[banana", "(date")), 1..3);
+        assert_eq!(zset.lex_ranks(&range("(banana", "[c")), 2..2);
+        assert_eq!(zset.lex_ranks(&range("+", "-")).len(), 0);
+        assert_eq!(LexBound::parse("banana"), None);
+        assert_eq!(ScoreBound::parse("(x"), None);
+    }
+}